@@ -0,0 +1,249 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::config::{Config, HttpConfig};
+use crate::gmail::GmailServer;
+use crate::oauth::OAuthManager;
+
+/// The account stored in token.json, used when no account is named
+pub const DEFAULT_ACCOUNT: &str = "default";
+
+/// Longest accepted account name
+const MAX_NAME_LEN: usize = 64;
+
+/// A Gmail account with its own token
+#[derive(Clone)]
+pub struct Account {
+    pub oauth_manager: Arc<OAuthManager>,
+    pub gmail_server: Arc<GmailServer>,
+}
+
+/// The default account plus the named accounts from `--accounts`
+pub struct Accounts {
+    accounts: BTreeMap<String, Account>,
+    /// Account used when a tool call doesn't name one, from `--default-account`
+    fallback: String,
+}
+
+impl Accounts {
+    /// Register the default account and create one for each configured name.
+    ///
+    /// Named accounts share the default server's HTTP client and audit log.
+    pub fn new(default: Account, config: &Config, http_config: &HttpConfig) -> Result<Self> {
+        let fallback = resolve(config, None)?;
+        let mut accounts = BTreeMap::new();
+        for name in configured_accounts(config)? {
+            if name == DEFAULT_ACCOUNT {
+                continue;
+            }
+            let oauth_manager = Arc::new(OAuthManager::for_account(
+                config.clone(),
+                http_config.clone(),
+                &name,
+            )?);
+            let gmail_server = Arc::new(
+                default
+                    .gmail_server
+                    .for_account(oauth_manager.clone())
+                    .named(&name),
+            );
+            accounts.insert(
+                name,
+                Account {
+                    oauth_manager,
+                    gmail_server,
+                },
+            );
+        }
+        accounts.insert(DEFAULT_ACCOUNT.to_string(), default);
+        Ok(Self { accounts, fallback })
+    }
+
+    /// Look up an account by name, or the `--default-account` when none is given
+    pub fn get(&self, name: Option<&str>) -> Result<&Account> {
+        let name = self.name(name);
+        self.accounts.get(name).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Unknown account '{name}', configured accounts: {}",
+                self.names().collect::<Vec<_>>().join(", ")
+            )
+        })
+    }
+
+    /// The account a tool call means: the one it names, or the `--default-account`
+    pub fn name<'a>(&'a self, name: Option<&'a str>) -> &'a str {
+        name.unwrap_or(&self.fallback)
+    }
+
+    /// The `--default-account`, used by tool calls that don't name one
+    pub fn default_account(&self) -> &Account {
+        &self.accounts[&self.fallback]
+    }
+
+    /// Account names in sorted order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.accounts.keys().map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Account)> {
+        self.accounts
+            .iter()
+            .map(|(name, account)| (name.as_str(), account))
+    }
+}
+
+/// The default account followed by the validated names from `--accounts`
+pub fn configured_accounts(config: &Config) -> Result<Vec<String>> {
+    let mut names = vec![DEFAULT_ACCOUNT.to_string()];
+    for name in &config.accounts {
+        let name = name.trim();
+        validate_name(name)?;
+        if names.iter().any(|existing| existing == name) {
+            anyhow::bail!("Account '{name}' is configured more than once");
+        }
+        names.push(name.to_string());
+    }
+    Ok(names)
+}
+
+/// Fail unless `name` is the default account or one listed in `--accounts`
+pub fn ensure_configured(config: &Config, name: &str) -> Result<()> {
+    let names = configured_accounts(config)?;
+    if !names.iter().any(|configured| configured == name) {
+        anyhow::bail!(
+            "Unknown account '{name}', configured accounts: {}",
+            names.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// The account a tool call should use: the one it names, else `--default-account`, else the
+/// token.json account
+pub fn resolve(config: &Config, account: Option<&str>) -> Result<String> {
+    let name = account
+        .or(config.default_account.as_deref())
+        .unwrap_or(DEFAULT_ACCOUNT);
+    ensure_configured(config, name)?;
+    Ok(name.to_string())
+}
+
+/// Token file name for an account, relative to the app data directory
+pub fn token_file_name(account: &str) -> String {
+    if account == DEFAULT_ACCOUNT {
+        "token.json".to_string()
+    } else {
+        format!("token-{account}.json")
+    }
+}
+
+/// Names end up in file names and URLs, so only allow a safe subset of characters
+fn validate_name(name: &str) -> Result<()> {
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if name.is_empty() || name.len() > MAX_NAME_LEN || !valid_chars {
+        anyhow::bail!(
+            "Invalid account name '{name}': use up to {MAX_NAME_LEN} letters, digits, '-' or '_'"
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_with_accounts(accounts: &[&str]) -> Config {
+        Config {
+            accounts: accounts.iter().map(|name| name.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_configured_accounts() {
+        assert_eq!(
+            configured_accounts(&config_with_accounts(&["work", " personal"])).unwrap(),
+            ["default", "work", "personal"]
+        );
+        assert!(configured_accounts(&config_with_accounts(&["work", "work"])).is_err());
+        assert!(configured_accounts(&config_with_accounts(&["default"])).is_err());
+        assert!(configured_accounts(&config_with_accounts(&["../work"])).is_err());
+        assert!(configured_accounts(&config_with_accounts(&[""])).is_err());
+
+        let config = config_with_accounts(&["work"]);
+        assert!(ensure_configured(&config, "work").is_ok());
+        assert!(ensure_configured(&config, DEFAULT_ACCOUNT).is_ok());
+        assert!(ensure_configured(&config, "personal").is_err());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut config = config_with_accounts(&["work", "personal"]);
+        assert_eq!(resolve(&config, None).unwrap(), DEFAULT_ACCOUNT);
+        assert_eq!(resolve(&config, Some("personal")).unwrap(), "personal");
+
+        config.default_account = Some("work".to_string());
+        assert_eq!(resolve(&config, None).unwrap(), "work");
+        assert_eq!(resolve(&config, Some("default")).unwrap(), DEFAULT_ACCOUNT);
+
+        config.default_account = Some("other".to_string());
+        assert!(resolve(&config, None).is_err());
+    }
+
+    #[test]
+    fn test_token_file_name() {
+        assert_eq!(token_file_name(DEFAULT_ACCOUNT), "token.json");
+        assert_eq!(token_file_name("work"), "token-work.json");
+    }
+
+    #[tokio::test]
+    async fn test_accounts_have_separate_tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            gmail_client_id: Some("test_client_id".to_string()),
+            gmail_client_secret: Some("test_client_secret".to_string()),
+            app_data_dir: Some(dir.path().to_path_buf()),
+            ..config_with_accounts(&["work"])
+        };
+        let http_config = HttpConfig::default();
+        let oauth_manager =
+            Arc::new(OAuthManager::new(config.clone(), http_config.clone()).unwrap());
+        let default = Account {
+            gmail_server: Arc::new(GmailServer::new(oauth_manager.clone(), &config).unwrap()),
+            oauth_manager,
+        };
+        let config = Config {
+            default_account: Some("work".to_string()),
+            ..config
+        };
+        let accounts = Accounts::new(default, &config, &http_config).unwrap();
+
+        assert_eq!(accounts.names().collect::<Vec<_>>(), ["default", "work"]);
+        let work = accounts.get(Some("work")).unwrap();
+        assert_eq!(
+            work.oauth_manager.token_location(),
+            dir.path().join("token-work.json").display().to_string()
+        );
+        assert_eq!(
+            accounts
+                .get(Some(DEFAULT_ACCOUNT))
+                .unwrap()
+                .oauth_manager
+                .token_location(),
+            dir.path().join("token.json").display().to_string()
+        );
+        // Calls that don't name an account use --default-account
+        assert_eq!(
+            accounts.get(None).unwrap().oauth_manager.token_location(),
+            work.oauth_manager.token_location()
+        );
+        let error = accounts.get(Some("personal")).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Unknown account 'personal', configured accounts: default, work"
+        );
+    }
+}
@@ -0,0 +1,199 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+/// Argument names whose values are message content and never written to the audit log
+const CONTENT_ARGUMENTS: [&str; 1] = ["body"];
+
+/// Longest string argument kept verbatim in an audit entry
+const MAX_ARGUMENT_CHARS: usize = 200;
+
+/// Append-only JSONL log of tool invocations.
+///
+/// Each line is a self-contained JSON object, flushed as soon as it is written,
+/// so the file can be tailed or shipped without a reader ever seeing a partial entry.
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+/// A single tool invocation as written to the audit log
+#[derive(Debug, Serialize)]
+pub struct AuditEntry<'a> {
+    /// RFC 3339 time the call started
+    pub timestamp: String,
+    pub tool: &'a str,
+    /// How the call arrived: mcp, http or cli
+    pub transport: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<&'a str>,
+    pub arguments: Value,
+    /// "ok" or "error"
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+impl<'a> AuditEntry<'a> {
+    /// Describe a call that started at `started` and took `elapsed`, summarizing its arguments
+    pub fn new(
+        tool: &'a str,
+        transport: &'a str,
+        arguments: &Value,
+        started: SystemTime,
+        elapsed: Duration,
+        error: Option<String>,
+    ) -> Self {
+        Self {
+            timestamp: chrono::DateTime::<chrono::Utc>::from(started)
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            tool,
+            transport,
+            session_id: None,
+            request_id: None,
+            arguments: summarize_arguments(arguments),
+            status: if error.is_some() { "error" } else { "ok" },
+            error,
+            duration_ms: elapsed.as_millis() as u64,
+        }
+    }
+
+    /// The entry as the fields of a `tool_invoked` webhook event, which has its own timestamp
+    pub fn event_fields(&self) -> Value {
+        let mut fields = serde_json::to_value(self).unwrap_or_default();
+        if let Value::Object(fields) = &mut fields {
+            fields.remove("timestamp");
+        }
+        fields
+    }
+}
+
+impl AuditLog {
+    /// Open (or create) the audit log at the given path for appending
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open audit log at {}", path.display()))?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Append an entry; failures are logged rather than failing the tool call
+    pub fn record(&self, entry: &AuditEntry) {
+        if let Err(e) = self.write_entry(entry) {
+            warn!("Failed to write audit log entry for {}: {}", entry.tool, e);
+        }
+    }
+
+    fn write_entry(&self, entry: &AuditEntry) -> Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// Reduce tool arguments to what an auditor needs: message content becomes a
+/// character count and long strings are truncated
+fn summarize_arguments(arguments: &Value) -> Value {
+    match arguments {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(name, value)| {
+                    let summary = match value.as_str() {
+                        Some(text) if CONTENT_ARGUMENTS.contains(&name.as_str()) => {
+                            Value::String(format!("<{} chars>", text.chars().count()))
+                        }
+                        _ => summarize_arguments(value),
+                    };
+                    (name.clone(), summary)
+                })
+                .collect::<Map<_, _>>(),
+        ),
+        Value::Array(items) => Value::Array(items.iter().map(summarize_arguments).collect()),
+        Value::String(text) if text.chars().count() > MAX_ARGUMENT_CHARS => {
+            let truncated: String = text.chars().take(MAX_ARGUMENT_CHARS).collect();
+            Value::String(format!("{truncated}…"))
+        }
+        other => other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_summarize_arguments_hides_body_and_truncates() {
+        let long_subject = "s".repeat(MAX_ARGUMENT_CHARS + 10);
+        let summary = summarize_arguments(&json!({
+            "to": "alice@example.com",
+            "subject": long_subject,
+            "body": "Hello, Alice",
+            "thread_ids": ["t1", "t2"]
+        }));
+        assert_eq!(summary["to"], "alice@example.com");
+        assert_eq!(summary["body"], "<12 chars>");
+        assert_eq!(summary["thread_ids"], json!(["t1", "t2"]));
+        let subject = summary["subject"].as_str().unwrap();
+        assert_eq!(subject.chars().count(), MAX_ARGUMENT_CHARS + 1);
+        assert!(subject.ends_with('…'));
+    }
+
+    #[test]
+    fn test_record_appends_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let arguments = json!({"draft_id": "d1"});
+
+        let audit_log = AuditLog::open(&path).unwrap();
+        let mut entry = AuditEntry::new(
+            "send_draft",
+            "mcp",
+            &arguments,
+            SystemTime::UNIX_EPOCH,
+            Duration::from_millis(42),
+            None,
+        );
+        entry.session_id = Some("session-1");
+        audit_log.record(&entry);
+        // Reopening appends rather than truncating
+        AuditLog::open(&path).unwrap().record(&AuditEntry::new(
+            "send_draft",
+            "http",
+            &arguments,
+            SystemTime::UNIX_EPOCH,
+            Duration::ZERO,
+            Some("Not authenticated".to_string()),
+        ));
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["timestamp"], "1970-01-01T00:00:00.000Z");
+        assert_eq!(lines[0]["session_id"], "session-1");
+        assert_eq!(lines[0]["status"], "ok");
+        assert_eq!(lines[0]["duration_ms"], 42);
+        assert!(lines[0].get("error").is_none());
+        assert_eq!(lines[1]["transport"], "http");
+        assert_eq!(lines[1]["status"], "error");
+        assert_eq!(lines[1]["error"], "Not authenticated");
+    }
+}
@@ -1,42 +1,196 @@
+use crate::accounts::{Account, DEFAULT_ACCOUNT};
+use crate::config::{Config, HttpConfig};
+use crate::gmail::GmailServer;
+use crate::oauth::{OAuthManager, OAuthToken};
 use crate::AppState;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use axum::{
     extract::{Query, State},
     http::StatusCode,
-    response::{Html, Redirect},
-    routing::get,
+    response::{Html, IntoResponse, Redirect},
+    routing::{get, post},
     Json, Router,
 };
-use serde::Deserialize;
-use serde_json::Value;
-use tracing::error;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
 
 #[derive(Deserialize)]
 struct CallbackQuery {
     code: Option<String>,
+    state: Option<String>,
     error: Option<String>,
 }
 
-pub fn auth_router() -> Router<AppState> {
+#[derive(Deserialize)]
+struct AccountQuery {
+    account: Option<String>,
+}
+
+impl AccountQuery {
+    fn name(&self) -> &str {
+        self.account.as_deref().unwrap_or(DEFAULT_ACCOUNT)
+    }
+}
+
+#[derive(Deserialize)]
+struct LoginQuery {
+    #[serde(flatten)]
+    account: AccountQuery,
+    /// MCP session to bind the token to, in multi-user mode
+    session: Option<String>,
+    /// Space-separated Gmail scopes to ask for on top of the initial ones
+    scope: Option<String>,
+}
+
+/// Where a user logs in with the given query parameters, absolute when the redirect URL allows it
+pub fn login_url(http_config: &HttpConfig, params: &[(&str, &str)]) -> String {
+    let login_route = http_config.login_route();
+    match reqwest::Url::parse(&http_config.oauth_redirect_url())
+        .and_then(|redirect_url| redirect_url.join(login_route))
+    {
+        Ok(mut login_url) => {
+            login_url.query_pairs_mut().extend_pairs(params);
+            login_url.to_string()
+        }
+        Err(_) => {
+            let query = url::form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(params)
+                .finish();
+            format!("{login_route}?{query}")
+        }
+    }
+}
+
+/// Scopes a login asks for on top of the initial ones, rejecting any the server has no use for
+fn requested_scopes<'a>(scopes: impl IntoIterator<Item = &'a str>) -> Result<Vec<String>> {
+    scopes
+        .into_iter()
+        .map(|scope| {
+            if crate::oauth::GMAIL_SCOPES.contains(&scope) {
+                Ok(scope.to_string())
+            } else {
+                Err(anyhow::anyhow!("Unsupported scope '{scope}'"))
+            }
+        })
+        .collect()
+}
+
+/// Where a completed login's token goes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LoginTarget {
+    /// One of the server's configured accounts, by name
+    Account(String),
+    /// An MCP session in multi-user mode, by session ID
+    Session(String),
+}
+
+/// OAuth login, refresh, status and accounts routes at their configured paths. They start
+/// logins that replace an account's token or report on its state, so they belong behind the
+/// same checks as the MCP routes
+pub fn auth_router(http_config: &HttpConfig) -> Router<AppState> {
     Router::new()
-        .route("/login", get(login_handler))
-        .route("/callback", get(callback_handler))
-        .route("/refresh", get(refresh_handler))
+        .route(http_config.login_route(), get(login_handler))
+        .route(http_config.refresh_route(), post(refresh_handler))
+        .route(http_config.auth_status_route(), get(status_handler))
+        .route(http_config.auth_accounts_route(), get(accounts_handler))
+}
+
+/// OAuth callback route, which Google sends the browser back to. It only completes logins
+/// started through [`auth_router`], checked by their state parameter
+pub fn callback_router(http_config: &HttpConfig) -> Router<AppState> {
+    Router::new().route(http_config.callback_route(), get(callback_handler))
+}
+
+/// Whether the request starts a multi-user session's login, which a browser opens from the link
+/// handed to that session and which only binds a token to it
+pub fn is_session_login(state: &AppState, uri: &axum::http::Uri) -> bool {
+    state.sessions.is_some()
+        && uri.path() == state.http_config.login_route()
+        && url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+            .any(|(name, _)| name == "session")
+}
+
+/// Resolve the `account` query parameter, answering 404 for accounts that aren't configured
+fn requested_account<'a>(
+    state: &'a AppState,
+    query: &AccountQuery,
+) -> Result<&'a Account, (StatusCode, Json<Value>)> {
+    state.accounts.get(Some(query.name())).map_err(|e| {
+        (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "status": "error", "message": e.to_string() })),
+        )
+    })
 }
 
-async fn login_handler(State(state): State<AppState>) -> Result<Redirect, StatusCode> {
-    let (auth_url, csrf_token) = state
+async fn login_handler(
+    State(state): State<AppState>,
+    Query(query): Query<LoginQuery>,
+) -> Result<Redirect, (StatusCode, Json<Value>)> {
+    let requested = query.scope.as_deref().unwrap_or_default();
+    let scopes = requested_scopes(requested.split_whitespace()).map_err(|e| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "status": "error", "message": e.to_string() })),
+        )
+    })?;
+    let (account, target) = match (query.session, &state.sessions) {
+        (Some(session_id), Some(sessions)) => {
+            let account = sessions.login_account(&session_id).await.map_err(|e| {
+                error!("Failed to start session login: {}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(json!({ "status": "error", "message": "Failed to start login" })),
+                )
+            })?;
+            (account, LoginTarget::Session(session_id))
+        }
+        (Some(_), None) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "status": "error",
+                    "message": "Session logins are only available in multi-user mode",
+                })),
+            ))
+        }
+        (None, _) => {
+            let account = requested_account(&state, &query.account)?.clone();
+            (
+                account,
+                LoginTarget::Account(query.account.name().to_string()),
+            )
+        }
+    };
+    let (auth_url, csrf_token) = account
         .oauth_manager
-        .get_authorization_url()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .get_authorization_url(&scopes)
+        .map_err(|e| {
+            error!("Failed to build authorization URL: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "status": "error", "message": "Failed to start login" })),
+            )
+        })?;
 
-    // Store CSRF token
+    // Remember the state parameter so the callback can verify it came from this login
     state
         .csrf_tokens
-        .write()
+        .insert(&csrf_token, &target)
         .await
-        .insert(csrf_token.clone(), csrf_token);
+        .map_err(|e| {
+            error!("Failed to store CSRF token: {:#}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "status": "error", "message": "Failed to start login" })),
+            )
+        })?;
 
     Ok(Redirect::to(auth_url.as_str()))
 }
@@ -44,47 +198,100 @@ async fn login_handler(State(state): State<AppState>) -> Result<Redirect, Status
 async fn callback_handler(
     State(state): State<AppState>,
     Query(params): Query<CallbackQuery>,
-) -> Result<Html<String>, StatusCode> {
+) -> Result<Html<String>, (StatusCode, Html<String>)> {
     if let Some(error) = params.error {
-        let template = include_str!("../templates/error.html");
-        let html = crate::render_template(
-            template,
-            &[
-                ("{error_message}", &error),
-                (
-                    "{login_route}",
-                    state.http_config.auth_config.login_route.as_str(),
-                ),
-            ],
-        );
-        return Ok(Html(html));
+        return Ok(error_page(&state, &error));
     }
 
-    let code = params.code.ok_or(StatusCode::BAD_REQUEST)?;
+    // Reject callbacks that don't answer a login this server started
+    let target = match &params.state {
+        Some(csrf_token) => state.csrf_tokens.take(csrf_token).await.map_err(|e| {
+            error!("Failed to read CSRF token: {:#}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                error_page(&state, "Failed to verify login state"),
+            )
+        })?,
+        None => None,
+    };
+    let Some(target) = target else {
+        warn!("Rejected OAuth callback with a missing, unknown or expired state");
+        return Err((
+            StatusCode::BAD_REQUEST,
+            error_page(&state, "Invalid or expired login state"),
+        ));
+    };
+    let account = match &target {
+        LoginTarget::Account(name) => state.accounts.get(Some(name)).cloned(),
+        LoginTarget::Session(session_id) => match &state.sessions {
+            Some(sessions) => sessions.login_account(session_id).await,
+            None => Err(anyhow::anyhow!("Multi-user mode is off")),
+        },
+    }
+    .map_err(|e| {
+        error!("OAuth callback for an unavailable login target: {}", e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            error_page(&state, "Unknown account"),
+        )
+    })?;
+
+    let code = params.code.ok_or_else(|| {
+        (
+            StatusCode::BAD_REQUEST,
+            error_page(&state, "Missing authorization code"),
+        )
+    })?;
 
-    match state.oauth_manager.exchange_code(&code).await {
+    match account.oauth_manager.exchange_code(&code).await {
         Ok(token) => {
-            state.gmail_server.set_authenticated(true).await;
-            // Update metrics with the new token
-            state.metrics.update_token_metrics(Some(&token));
+            account.gmail_server.forget_email_address();
+            match account.gmail_server.email_address().await {
+                Ok(address) => info!("🔑 Logged in to {}", address),
+                Err(e) => warn!("Failed to look up the logged-in address: {:#}", e),
+            }
+            // Token metrics describe the default account
+            if target == LoginTarget::Account(DEFAULT_ACCOUNT.to_string()) {
+                state.metrics.update_token_metrics(Some(&token));
+            }
             let template = include_str!("../templates/success.html");
             Ok(Html(template.to_string()))
         }
         Err(e) => {
             error!("Failed to exchange authorization code: {}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                error_page(&state, "Failed to exchange authorization code"),
+            ))
         }
     }
 }
 
-async fn refresh_handler(State(state): State<AppState>) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
-    match state.oauth_manager.refresh_token().await {
+fn error_page(state: &AppState, message: &str) -> Html<String> {
+    let template = include_str!("../templates/error.html");
+    Html(crate::render_template(
+        template,
+        &[
+            ("{error_message}", message),
+            ("{login_route}", state.http_config.login_route()),
+        ],
+    ))
+}
+
+/// Refresh an account's token, reporting only when it expires so the token stays on the server
+async fn refresh_handler(
+    State(state): State<AppState>,
+    Query(query): Query<AccountQuery>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let account = requested_account(&state, &query)?;
+    match account.oauth_manager.refresh_token().await {
         Ok(token) => {
-            state.metrics.update_token_metrics(Some(&token));
+            if query.name() == DEFAULT_ACCOUNT {
+                state.metrics.update_token_metrics(Some(&token));
+            }
             Ok(Json(serde_json::json!({
                 "status": "success",
                 "message": "Token refreshed successfully",
-                "access_token": token.access_token,
                 "expires_in": token.expires_in,
             })))
         }
@@ -100,3 +307,277 @@ async fn refresh_handler(State(state): State<AppState>) -> Result<Json<Value>, (
         }
     }
 }
+
+/// Log in through the browser with a temporary loopback listener and save the token.
+///
+/// Google accepts any port on a loopback redirect for desktop clients, so an ephemeral port is
+/// used and the listener is shut down as soon as the callback arrives.
+pub async fn loopback_login(
+    config: &Config,
+    account: &str,
+    scopes: &[String],
+    open_browser: bool,
+    timeout: Duration,
+) -> anyhow::Result<OAuthToken> {
+    let scopes = requested_scopes(scopes.iter().map(String::as_str))?;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .context("Failed to bind loopback listener")?;
+    let port = listener.local_addr()?.port();
+    let http_config = HttpConfig {
+        oauth_redirect_url: Some(format!("http://127.0.0.1:{port}/callback")),
+        ..Default::default()
+    };
+    let oauth_manager = Arc::new(OAuthManager::for_account(
+        config.clone(),
+        http_config,
+        account,
+    )?);
+    let (auth_url, csrf_token) = oauth_manager.get_authorization_url(&scopes)?;
+
+    let (result_tx, mut result_rx) = mpsc::channel(1);
+    let callback = move |Query(params): Query<CallbackQuery>| async move {
+        let result = complete_login(&oauth_manager, &csrf_token, params).await;
+        let page = match &result {
+            Ok(_) => Html(include_str!("../templates/success.html").to_string()).into_response(),
+            Err(e) => (
+                StatusCode::BAD_REQUEST,
+                format!("Login failed: {e:#}\n\nReturn to the terminal and try again."),
+            )
+                .into_response(),
+        };
+        let _ = result_tx.send(result).await;
+        page
+    };
+    let app = Router::new().route("/callback", get(callback));
+
+    let shutdown = CancellationToken::new();
+    let server = tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await
+        }
+    });
+
+    println!("Open this URL to log in:\n\n{auth_url}\n");
+    if open_browser {
+        if let Err(e) = open::that(&auth_url) {
+            warn!("Failed to open a browser: {}", e);
+        }
+    }
+
+    let result = tokio::time::timeout(timeout, result_rx.recv()).await;
+    shutdown.cancel();
+    let _ = server.await;
+    match result {
+        Ok(Some(result)) => result,
+        Ok(None) => Err(anyhow::anyhow!(
+            "Login listener stopped before the callback arrived"
+        )),
+        Err(_) => Err(anyhow::anyhow!(
+            "Timed out after {}s waiting for the login to complete",
+            timeout.as_secs()
+        )),
+    }
+}
+
+/// Check a loopback callback against the login that was started and exchange its code
+async fn complete_login(
+    oauth_manager: &OAuthManager,
+    expected_state: &str,
+    params: CallbackQuery,
+) -> anyhow::Result<OAuthToken> {
+    if let Some(error) = params.error {
+        anyhow::bail!("Authorization was not granted: {error}");
+    }
+    if params.state.as_deref() != Some(expected_state) {
+        anyhow::bail!("Invalid login state");
+    }
+    let code = params
+        .code
+        .ok_or_else(|| anyhow::anyhow!("Missing authorization code"))?;
+    oauth_manager.exchange_code(&code).await
+}
+
+/// Report whether the server can call Gmail, and with which account and token
+async fn status_handler(
+    State(state): State<AppState>,
+    Query(query): Query<AccountQuery>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let account = requested_account(&state, &query)?;
+    let mut status = auth_status(&account.oauth_manager, &account.gmail_server).await;
+    status["account"] = json!(query.name());
+    Ok(Json(status))
+}
+
+/// List the configured accounts with where to log in to each
+async fn accounts_handler(State(state): State<AppState>) -> Json<Value> {
+    let mut accounts = Vec::new();
+    for (name, account) in state.accounts.iter() {
+        accounts.push(json!({
+            "name": name,
+            "token_present": account.oauth_manager.get_token().await.is_some(),
+            "authenticated": account.gmail_server.is_authenticated().await,
+            "login_url": format!("{}?account={name}", state.http_config.login_route()),
+        }));
+    }
+    Json(json!({ "accounts": accounts }))
+}
+
+/// Authentication state reported by the status route and `auth status`
+pub async fn auth_status(oauth_manager: &OAuthManager, gmail_server: &GmailServer) -> Value {
+    let token = oauth_manager.get_token().await;
+    let authenticated = gmail_server.is_authenticated().await;
+
+    // Only ask Gmail for the address when a call can succeed
+    let email = if authenticated && token.is_some() {
+        match gmail_server.email_address().await {
+            Ok(address) => Some(address),
+            Err(e) => {
+                debug!("Failed to get profile for auth status: {:#}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut status = token_status(token.as_ref());
+    status["authenticated"] = json!(authenticated);
+    status["email"] = json!(email);
+    status
+}
+
+/// Token fields of the auth status response, without exposing the token itself
+pub fn token_status(token: Option<&OAuthToken>) -> Value {
+    match token {
+        Some(token) => json!({
+            "token_present": true,
+            "scopes": token.scopes(),
+            "expires_at": chrono::DateTime::from_timestamp(token.expires_at() as i64, 0)
+                .map(|expires_at| expires_at.to_rfc3339()),
+            "expired": token.is_expired(),
+            "has_refresh_token": token.refresh_token.is_some(),
+        }),
+        None => json!({
+            "token_present": false,
+            "scopes": [],
+            "expires_at": null,
+            "expired": null,
+            "has_refresh_token": false,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_complete_login_rejects_bad_callbacks() {
+        let config = Config {
+            gmail_client_id: Some("test_client_id".to_string()),
+            gmail_client_secret: Some("test_client_secret".to_string()),
+            ..Default::default()
+        };
+        let oauth_manager = OAuthManager::new(config, HttpConfig::default()).unwrap();
+        let login_error = |code: Option<&str>, state: Option<&str>, error: Option<&str>| {
+            let params = CallbackQuery {
+                code: code.map(str::to_string),
+                state: state.map(str::to_string),
+                error: error.map(str::to_string),
+            };
+            let oauth_manager = &oauth_manager;
+            async move {
+                let result = complete_login(oauth_manager, "s1", params).await;
+                result.unwrap_err().to_string()
+            }
+        };
+
+        assert_eq!(
+            login_error(None, Some("s1"), Some("access_denied")).await,
+            "Authorization was not granted: access_denied"
+        );
+        assert_eq!(
+            login_error(Some("code"), Some("s2"), None).await,
+            "Invalid login state"
+        );
+        assert_eq!(
+            login_error(Some("code"), None, None).await,
+            "Invalid login state"
+        );
+        assert_eq!(
+            login_error(None, Some("s1"), None).await,
+            "Missing authorization code"
+        );
+    }
+
+    #[test]
+    fn test_requested_scopes() {
+        let scope = "https://www.googleapis.com/auth/gmail.send https://mail.google.com/";
+        assert_eq!(
+            requested_scopes(scope.split_whitespace()).unwrap(),
+            vec![
+                "https://www.googleapis.com/auth/gmail.send",
+                "https://mail.google.com/"
+            ]
+        );
+        assert!(requested_scopes([]).unwrap().is_empty());
+        assert!(requested_scopes(["https://www.googleapis.com/auth/drive"]).is_err());
+    }
+
+    #[test]
+    fn test_login_url() {
+        let scope = "https://www.googleapis.com/auth/gmail.send";
+        assert_eq!(
+            login_url(
+                &HttpConfig::default(),
+                &[("account", "work"), ("scope", scope)]
+            ),
+            "http://localhost:8080/auth/login?account=work&scope=https%3A%2F%2Fwww.googleapis.com%2Fauth%2Fgmail.send"
+        );
+        let http_config = HttpConfig {
+            oauth_redirect_url: Some("not a url".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            login_url(&http_config, &[("session", "abc 123")]),
+            "/auth/login?session=abc+123"
+        );
+    }
+
+    #[test]
+    fn test_token_status_without_token() {
+        let status = token_status(None);
+        assert_eq!(status["token_present"], false);
+        assert_eq!(status["has_refresh_token"], false);
+        assert!(status["expires_at"].is_null());
+    }
+
+    #[test]
+    fn test_token_status_with_token() {
+        let token = OAuthToken {
+            access_token: "secret".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: 3600,
+            refresh_token: Some("refresh".to_string()),
+            scope: "https://www.googleapis.com/auth/gmail.readonly https://www.googleapis.com/auth/gmail.compose".to_string(),
+            created_at: 1_700_000_000,
+        };
+        let status = token_status(Some(&token));
+        assert_eq!(status["token_present"], true);
+        assert_eq!(
+            status["scopes"],
+            json!([
+                "https://www.googleapis.com/auth/gmail.readonly",
+                "https://www.googleapis.com/auth/gmail.compose"
+            ])
+        );
+        assert_eq!(status["expires_at"], "2023-11-14T23:13:20+00:00");
+        assert_eq!(status["expired"], true);
+        assert_eq!(status["has_refresh_token"], true);
+        assert!(!status.to_string().contains("secret"));
+    }
+}
@@ -0,0 +1,175 @@
+use anyhow::{Context, Result};
+use reqwest::StatusCode;
+
+/// Maximum number of calls Gmail accepts in one batch request
+pub const MAX_BATCH_SIZE: usize = 100;
+
+/// A single response extracted from a multipart/mixed batch response
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchPart {
+    pub index: usize,
+    pub status: StatusCode,
+    pub body: String,
+}
+
+/// Build a multipart/mixed batch body of GET requests for the given request paths
+pub fn build_batch_body(boundary: &str, paths: &[String]) -> String {
+    let mut body = String::new();
+    for (index, path) in paths.iter().enumerate() {
+        body.push_str(&format!("--{boundary}\r\n"));
+        body.push_str("Content-Type: application/http\r\n");
+        body.push_str(&format!("Content-ID: <item-{index}>\r\n"));
+        body.push_str("\r\n");
+        body.push_str(&format!("GET {path}\r\n"));
+        body.push_str("\r\n");
+    }
+    body.push_str(&format!("--{boundary}--\r\n"));
+    body
+}
+
+/// Extract the boundary parameter from a multipart Content-Type header value
+pub fn boundary_from_content_type(content_type: &str) -> Option<String> {
+    content_type.split(';').find_map(|param| {
+        let (name, value) = param.trim().split_once('=')?;
+        name.eq_ignore_ascii_case("boundary")
+            .then(|| value.trim_matches('"').to_string())
+    })
+}
+
+/// Parse a multipart/mixed batch response into its individual HTTP responses
+pub fn parse_batch_response(boundary: &str, body: &str) -> Result<Vec<BatchPart>> {
+    let body = body.replace("\r\n", "\n");
+    let delimiter = format!("--{boundary}");
+    let mut parts = Vec::new();
+
+    for (position, section) in body.split(&delimiter).skip(1).enumerate() {
+        if section.starts_with("--") {
+            break;
+        }
+
+        // Outer MIME headers, then the embedded HTTP response
+        let section = section.strip_prefix('\n').unwrap_or(section);
+        let (outer_headers, http_response) = match section.strip_prefix('\n') {
+            Some(rest) => ("", rest),
+            None => section
+                .split_once("\n\n")
+                .context("Malformed batch part: missing headers")?,
+        };
+
+        let index = outer_headers
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.trim()
+                    .eq_ignore_ascii_case("content-id")
+                    .then(|| content_id_index(value.trim()))
+                    .flatten()
+            })
+            .unwrap_or(position);
+
+        let (head, response_body) = http_response
+            .split_once("\n\n")
+            .unwrap_or((http_response, ""));
+        let status_line = head.lines().next().unwrap_or_default();
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse::<u16>().ok())
+            .and_then(|code| StatusCode::from_u16(code).ok())
+            .with_context(|| format!("Malformed batch part status line: {status_line}"))?;
+
+        parts.push(BatchPart {
+            index,
+            status,
+            body: response_body.trim().to_string(),
+        });
+    }
+
+    Ok(parts)
+}
+
+/// Parse the request index out of a Content-ID such as `<response-item-3>`
+fn content_id_index(content_id: &str) -> Option<usize> {
+    content_id
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .rsplit_once("item-")?
+        .1
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_batch_body() {
+        let body = build_batch_body(
+            "batch_test",
+            &[
+                "/gmail/v1/users/me/messages/a".to_string(),
+                "/gmail/v1/users/me/messages/b".to_string(),
+            ],
+        );
+        assert!(body.starts_with("--batch_test\r\n"));
+        assert!(body.contains("Content-ID: <item-0>\r\n\r\nGET /gmail/v1/users/me/messages/a\r\n"));
+        assert!(body.contains("Content-ID: <item-1>\r\n\r\nGET /gmail/v1/users/me/messages/b\r\n"));
+        assert!(body.ends_with("--batch_test--\r\n"));
+    }
+
+    #[test]
+    fn test_boundary_from_content_type() {
+        assert_eq!(
+            boundary_from_content_type("multipart/mixed; boundary=batch_abc"),
+            Some("batch_abc".to_string())
+        );
+        assert_eq!(
+            boundary_from_content_type("multipart/mixed; charset=utf-8; boundary=\"quoted\""),
+            Some("quoted".to_string())
+        );
+        assert_eq!(boundary_from_content_type("application/json"), None);
+    }
+
+    #[test]
+    fn test_parse_batch_response() {
+        let body = "--batch_abc\r\n\
+            Content-Type: application/http\r\n\
+            Content-ID: <response-item-1>\r\n\
+            \r\n\
+            HTTP/1.1 404 Not Found\r\n\
+            Content-Type: application/json\r\n\
+            \r\n\
+            {\"error\": \"missing\"}\r\n\
+            --batch_abc\r\n\
+            Content-Type: application/http\r\n\
+            Content-ID: <response-item-0>\r\n\
+            \r\n\
+            HTTP/1.1 200 OK\r\n\
+            Content-Type: application/json\r\n\
+            \r\n\
+            {\"id\": \"a\"}\r\n\
+            --batch_abc--\r\n";
+        let parts = parse_batch_response("batch_abc", body).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].index, 1);
+        assert_eq!(parts[0].status, StatusCode::NOT_FOUND);
+        assert_eq!(parts[1].index, 0);
+        assert_eq!(parts[1].status, StatusCode::OK);
+        assert_eq!(parts[1].body, "{\"id\": \"a\"}");
+    }
+
+    #[test]
+    fn test_parse_batch_response_without_content_id() {
+        let body = "--b\n\nHTTP/1.1 200 OK\n\n{}\n--b--\n";
+        let parts = parse_batch_response("b", body).unwrap();
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].index, 0);
+    }
+
+    #[test]
+    fn test_parse_batch_response_malformed_status() {
+        let body = "--b\nContent-Type: application/http\n\nnot a status line\n\n{}\n--b--\n";
+        assert!(parse_batch_response("b", body).is_err());
+    }
+}
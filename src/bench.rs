@@ -0,0 +1,320 @@
+use anyhow::{Context, Result};
+use axum::{
+    extract::OriginalUri,
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::post,
+    Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use futures::{stream, StreamExt};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::batch::boundary_from_content_type;
+use crate::config::{BenchConfig, BenchTool, Config, HttpConfig};
+use crate::gmail::GmailServer;
+use crate::oauth::{OAuthManager, OAuthToken, GMAIL_SCOPES};
+use crate::tools::{self, MessageFormat};
+
+/// Messages in each thread of the fake Gmail API
+const MOCK_MESSAGES_PER_THREAD: usize = 3;
+
+/// Call a tool `requests` times, `concurrency` at a time, and print its latency percentiles
+pub async fn run(mut config: Config, bench_config: &BenchConfig) -> Result<()> {
+    let gmail_server = if bench_config.mock {
+        let latency = Duration::from_millis(bench_config.mock_latency_ms);
+        let url = spawn_mock_gmail(latency).await?;
+        eprintln!("Benchmarking against a fake Gmail API at {url}");
+        config.gmail_api.gmail_api_url = Some(url);
+        mock_gmail_server(config).await?
+    } else {
+        crate::authenticated_gmail_server(&config, bench_config.account.as_deref(), true).await?
+    };
+
+    let tool = bench_config.tool;
+    let (query, max_results) = (bench_config.query.as_str(), bench_config.max_results);
+    let thread_ids = match tool {
+        BenchTool::SearchThreads => Vec::new(),
+        BenchTool::FetchEmailBodies => {
+            let threads = tools::search_threads(
+                &gmail_server,
+                query,
+                max_results,
+                None,
+                &tools::SearchFilter::default(),
+            )
+            .await?;
+            let thread_ids: Vec<String> = threads["threads"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|thread| thread["id"].as_str().map(str::to_string))
+                .collect();
+            if thread_ids.is_empty() {
+                anyhow::bail!("No threads match '{query}' to fetch");
+            }
+            thread_ids
+        }
+    };
+
+    let started = Instant::now();
+    let results: Vec<(Duration, Result<Value>)> = stream::iter(0..bench_config.requests)
+        .map(|_| {
+            let (gmail_server, thread_ids) = (&gmail_server, &thread_ids);
+            async move {
+                let timer = Instant::now();
+                let result = match tool {
+                    BenchTool::SearchThreads => {
+                        tools::search_threads(
+                            gmail_server,
+                            query,
+                            max_results,
+                            None,
+                            &tools::SearchFilter::default(),
+                        )
+                        .await
+                    }
+                    BenchTool::FetchEmailBodies => {
+                        let format = MessageFormat::Full;
+                        tools::fetch_email_bodies(gmail_server, thread_ids, format, None).await
+                    }
+                };
+                (timer.elapsed(), result)
+            }
+        })
+        .buffer_unordered(bench_config.concurrency as usize)
+        .collect()
+        .await;
+    let elapsed = started.elapsed();
+
+    let mut errors = 0;
+    let mut latencies = Vec::with_capacity(results.len());
+    for (latency, result) in results {
+        match result {
+            Ok(_) => latencies.push(latency),
+            Err(e) => {
+                // Only the first is shown, since a failing backend usually fails every call alike
+                if errors == 0 {
+                    eprintln!("First error: {e:#}");
+                }
+                errors += 1;
+            }
+        }
+    }
+    print!(
+        "{}",
+        report(tool, bench_config, elapsed, errors, &mut latencies)
+    );
+    Ok(())
+}
+
+fn report(
+    tool: BenchTool,
+    bench_config: &BenchConfig,
+    elapsed: Duration,
+    errors: usize,
+    latencies: &mut [Duration],
+) -> String {
+    latencies.sort();
+    let name = match tool {
+        BenchTool::SearchThreads => "search_threads",
+        BenchTool::FetchEmailBodies => "fetch_email_bodies",
+    };
+    let mut report = format!(
+        "{name}: {} calls, {} at a time, {errors} failed, in {:.2}s ({:.1} calls/s)\n",
+        bench_config.requests,
+        bench_config.concurrency,
+        elapsed.as_secs_f64(),
+        f64::from(bench_config.requests) / elapsed.as_secs_f64().max(f64::EPSILON),
+    );
+    if !latencies.is_empty() {
+        let ms = |latency: Duration| latency.as_secs_f64() * 1000.0;
+        report.push_str(&format!(
+            "latency ms: min {:.1}  p50 {:.1}  p90 {:.1}  p99 {:.1}  max {:.1}\n",
+            ms(latencies[0]),
+            ms(percentile(latencies, 50.0)),
+            ms(percentile(latencies, 90.0)),
+            ms(percentile(latencies, 99.0)),
+            ms(latencies[latencies.len() - 1]),
+        ));
+    }
+    report
+}
+
+/// Nearest-rank percentile of sorted, non-empty latencies
+fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// A Gmail server for the fake API, holding a token for every Gmail scope in memory
+async fn mock_gmail_server(mut config: Config) -> Result<Arc<GmailServer>> {
+    // Fake messages must not land in the real message cache or audit log
+    config.message_cache = false;
+    config.audit_log = false;
+    config
+        .gmail_client_id
+        .get_or_insert_with(|| "bench".to_string());
+    config
+        .gmail_client_secret
+        .get_or_insert_with(|| "bench".to_string());
+
+    let oauth_manager = Arc::new(OAuthManager::in_memory(
+        config.clone(),
+        HttpConfig::default(),
+    )?);
+    oauth_manager
+        .set_token(OAuthToken {
+            access_token: "bench".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: u32::MAX.into(),
+            refresh_token: None,
+            scope: GMAIL_SCOPES.join(" "),
+            created_at: chrono::Utc::now()
+                .timestamp()
+                .try_into()
+                .unwrap_or_default(),
+        })
+        .await;
+    Ok(Arc::new(GmailServer::new(oauth_manager, &config)?))
+}
+
+/// Serve a fake Gmail API on a local port, returning its root URL
+async fn spawn_mock_gmail(latency: Duration) -> Result<String> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .context("Failed to bind the fake Gmail API")?;
+    let url = format!("http://{}", listener.local_addr()?);
+    let app = Router::new()
+        .route(
+            "/batch/gmail/v1",
+            post(move |headers: HeaderMap, body: String| async move {
+                tokio::time::sleep(latency).await;
+                mock_batch(&headers, &body)
+            }),
+        )
+        .fallback(move |OriginalUri(uri): OriginalUri| async move {
+            tokio::time::sleep(latency).await;
+            let path = uri
+                .path_and_query()
+                .map_or(uri.path(), |path| path.as_str());
+            match mock_response(path) {
+                Some(body) => (StatusCode::OK, Json(body)).into_response(),
+                None => StatusCode::NOT_FOUND.into_response(),
+            }
+        });
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    Ok(url)
+}
+
+/// Answer each GET in a batch request, in a multipart response like Gmail's
+fn mock_batch(headers: &HeaderMap, body: &str) -> axum::response::Response {
+    let Some(boundary) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(boundary_from_content_type)
+    else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let mut response = String::new();
+    let requests = body.lines().filter_map(|line| line.strip_prefix("GET "));
+    for (index, path) in requests.enumerate() {
+        let (status, body) = match mock_response(path.trim()) {
+            Some(body) => ("200 OK", body.to_string()),
+            None => ("404 Not Found", "{}".to_string()),
+        };
+        response.push_str(&format!(
+            "--{boundary}\r\nContent-Type: application/http\r\nContent-ID: <response-item-{index}>\r\n\r\n\
+             HTTP/1.1 {status}\r\nContent-Type: application/json\r\n\r\n{body}\r\n"
+        ));
+    }
+    response.push_str(&format!("--{boundary}--\r\n"));
+    let content_type = format!("multipart/mixed; boundary={boundary}");
+    ([(header::CONTENT_TYPE, content_type)], response).into_response()
+}
+
+/// The fake Gmail API's answer to a GET of `path`, or None for a 404: any search finds
+/// `maxResults` threads, each with a few plain text messages
+fn mock_response(path: &str) -> Option<Value> {
+    let url = url::Url::parse(&format!("http://mock{path}")).ok()?;
+    let segments: Vec<&str> = url.path_segments()?.collect();
+    match segments.as_slice() {
+        ["gmail", "v1", "users", _, "threads"] => {
+            let max_results = url
+                .query_pairs()
+                .find(|(name, _)| name == "maxResults")
+                .and_then(|(_, value)| value.parse().ok())
+                .unwrap_or(100_usize);
+            let threads: Vec<Value> = (0..max_results)
+                .map(|i| {
+                    json!({ "id": format!("thread-{i}"), "snippet": "Benchmark message", "historyId": "1" })
+                })
+                .collect();
+            Some(json!({ "threads": threads, "resultSizeEstimate": max_results }))
+        }
+        ["gmail", "v1", "users", _, "threads", thread_id] => {
+            let messages: Vec<Value> = (0..MOCK_MESSAGES_PER_THREAD)
+                .map(|i| json!({ "id": format!("{thread_id}-m{i}"), "historyId": "1" }))
+                .collect();
+            Some(json!({ "id": thread_id, "historyId": "1", "messages": messages }))
+        }
+        ["gmail", "v1", "users", _, "messages", message_id] => Some(json!({
+            "id": message_id,
+            "historyId": "1",
+            "snippet": "Benchmark message",
+            "payload": {
+                "mimeType": "text/plain",
+                "headers": [
+                    { "name": "From", "value": "Bench <bench@example.com>" },
+                    { "name": "Subject", "value": format!("Benchmark message {message_id}") },
+                    { "name": "Date", "value": "Tue, 1 Oct 2024 09:00:00 +0000" },
+                ],
+                "body": { "data": URL_SAFE.encode("Benchmark message body.\n".repeat(40)) },
+            },
+        })),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let latencies: Vec<Duration> = (1..=10).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 50.0), Duration::from_millis(5));
+        assert_eq!(percentile(&latencies, 90.0), Duration::from_millis(9));
+        assert_eq!(percentile(&latencies, 99.0), Duration::from_millis(10));
+        assert_eq!(percentile(&latencies[..1], 0.0), Duration::from_millis(1));
+    }
+
+    #[tokio::test]
+    async fn test_tools_against_mock_gmail() {
+        let url = spawn_mock_gmail(Duration::ZERO).await.unwrap();
+        let mut config = Config::default();
+        config.gmail_api.gmail_api_url = Some(url);
+        let gmail_server = mock_gmail_server(config).await.unwrap();
+
+        let threads = tools::search_threads(
+            &gmail_server,
+            "in:inbox",
+            2,
+            None,
+            &tools::SearchFilter::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(threads["threads"].as_array().unwrap().len(), 2);
+
+        // Two threads of three messages each go through one batch request
+        let thread_ids = ["thread-0".to_string(), "thread-1".to_string()];
+        let bodies =
+            tools::fetch_email_bodies(&gmail_server, &thread_ids, MessageFormat::Full, None)
+                .await
+                .unwrap();
+        assert!(bodies.to_string().contains("Benchmark message thread-1-m2"));
+    }
+}
@@ -0,0 +1,596 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde_json::{json, Value};
+use std::sync::LazyLock;
+use tracing::{error, instrument};
+
+use crate::email::decode_email_content;
+use crate::gmail::{GmailClient, GmailServer, GMAIL_API_BASE};
+use crate::oauth::SCOPE_READONLY;
+use crate::tools::{message_url, MessageFormat};
+
+/// Messages that look like bounces: from a mail system, or with the subjects MTAs give them
+const BOUNCE_QUERY: &str =
+    "{from:mailer-daemon from:postmaster subject:\"delivery status notification\" \
+     subject:undeliverable subject:\"mail delivery failed\" subject:\"returned mail\" \
+     subject:\"delivery has failed\"}";
+
+/// An RFC 3463 enhanced status code such as 5.1.1, not part of an IP address
+static STATUS_CODE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?:^|[^\d.])([245]\.\d{1,3}\.\d{1,3})\b").unwrap());
+
+/// The Message-ID header of the original message, quoted in a bounce
+static ORIGINAL_MESSAGE_ID: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?im)^message-id:\s*(<[^>\s]+>)").unwrap());
+
+/// The message a bounce is looked for, as found in the sent mail
+struct SentMessage {
+    thread_id: Option<String>,
+    rfc822_id: Option<String>,
+    /// Seconds since the epoch, as Gmail's `after:` takes them
+    sent_at: Option<i64>,
+}
+
+/// What a delivery status notification reports for one recipient
+#[derive(Debug, Default, PartialEq)]
+struct RecipientStatus {
+    recipient: Option<String>,
+    action: Option<String>,
+    status: Option<String>,
+    diagnostic: Option<String>,
+}
+
+/// A bounce message with the outcome it reports for each recipient
+#[derive(Debug, Default)]
+struct Bounce {
+    reporting_mta: Option<String>,
+    original_message_id: Option<String>,
+    recipients: Vec<RecipientStatus>,
+    /// Every decoded text part and header value, for matching the bounce to a sent message
+    text: String,
+}
+
+/// Find bounces and delivery status notifications, for a sent message, a recipient or both
+#[instrument(name = "tool", skip_all, fields(tool = "find_bounces"))]
+pub async fn find_bounces(
+    gmail_server: &GmailServer,
+    message_id: Option<&str>,
+    recipient: Option<&str>,
+    max_results: u32,
+) -> Result<Value> {
+    gmail_server.check_authentication(&[SCOPE_READONLY]).await?;
+
+    let client = gmail_server.authenticated_client().await?;
+    let user_id = gmail_server.user_id();
+
+    let sent = match message_id {
+        Some(message_id) => Some(sent_message(&client, user_id, message_id).await?),
+        None => None,
+    };
+    let query = bounce_query(recipient, sent.as_ref().and_then(|sent| sent.sent_at));
+    let url = format!(
+        "{GMAIL_API_BASE}/users/{user_id}/messages?q={}&maxResults={max_results}",
+        urlencoding::encode(&query)
+    );
+    let listed = client
+        .get_json(&url)
+        .await
+        .context("Failed to search for bounces")?;
+    let message_ids: Vec<&str> = listed["messages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|message| message["id"].as_str())
+        .collect();
+
+    let message_urls: Vec<String> = message_ids
+        .iter()
+        .map(|message_id| message_url(user_id, message_id, MessageFormat::Full, None))
+        .collect();
+    let messages = client
+        .batch_get(&message_urls)
+        .await
+        .context("Failed to get messages")?;
+
+    let mut bounces = Vec::new();
+    for (message_id, message) in message_ids.into_iter().zip(messages) {
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Error fetching message {}: {}", message_id, e);
+                continue;
+            }
+        };
+        let delivery_status = delivery_status(&client, user_id, message_id, &message).await;
+        let bounce = parse_bounce(&message, delivery_status.as_deref());
+        if let Some(sent) = &sent {
+            if !bounce.refers_to(&message, sent) {
+                continue;
+            }
+        }
+
+        for status in &bounce.recipients {
+            if let Some(recipient) = recipient {
+                let matches = match &status.recipient {
+                    Some(address) => address.eq_ignore_ascii_case(recipient.trim()),
+                    None => contains_ignore_case(&bounce.text, recipient.trim()),
+                };
+                if !matches {
+                    continue;
+                }
+            }
+            bounces.push(json!({
+                "message_id": message_id,
+                "thread_id": message["threadId"],
+                "date": header(&message["payload"], "Date"),
+                "recipient": status.recipient,
+                "action": status.action,
+                "status": status.status,
+                "reason": status.status.as_deref().and_then(describe_status),
+                "diagnostic": status.diagnostic,
+                "reporting_mta": bounce.reporting_mta,
+                "original_message_id": bounce.original_message_id,
+            }));
+        }
+    }
+
+    Ok(json!({ "bounces": bounces }))
+}
+
+/// The thread, Message-ID and send time of a sent message
+async fn sent_message(
+    client: &GmailClient,
+    user_id: &str,
+    message_id: &str,
+) -> Result<SentMessage> {
+    let url = format!(
+        "{GMAIL_API_BASE}/users/{user_id}/messages/{message_id}?format=metadata&metadataHeaders=Message-ID"
+    );
+    let message = client
+        .get_json(&url)
+        .await
+        .context("Failed to get sent message")?;
+    Ok(SentMessage {
+        thread_id: message["threadId"].as_str().map(str::to_string),
+        rfc822_id: header(&message["payload"], "Message-ID"),
+        sent_at: message["internalDate"]
+            .as_str()
+            .and_then(|millis| millis.parse::<i64>().ok())
+            .map(|millis| millis / 1000),
+    })
+}
+
+/// The Gmail search for bounces, narrowed to a recipient and to mail arriving after a send
+fn bounce_query(recipient: Option<&str>, after: Option<i64>) -> String {
+    let mut query = BOUNCE_QUERY.to_string();
+    if let Some(recipient) = recipient {
+        query.push_str(&format!(" \"{}\"", recipient.trim().replace('"', "")));
+    }
+    if let Some(after) = after {
+        query.push_str(&format!(" after:{after}"));
+    }
+    query
+}
+
+/// The body of a bounce's `message/delivery-status` part, downloaded when Gmail stores it as an
+/// attachment
+async fn delivery_status(
+    client: &GmailClient,
+    user_id: &str,
+    message_id: &str,
+    message: &Value,
+) -> Option<String> {
+    let part = parts(&message["payload"])
+        .into_iter()
+        .find(|part| is_delivery_status(part))?;
+    if let Some(text) = part_text(part) {
+        return Some(text);
+    }
+    let attachment_id = part["body"]["attachmentId"].as_str()?;
+    let url = format!(
+        "{GMAIL_API_BASE}/users/{user_id}/messages/{message_id}/attachments/{attachment_id}"
+    );
+    match client.get_json(&url).await {
+        Ok(attachment) => attachment["data"]
+            .as_str()
+            .and_then(|data| decode_email_content(data).ok()),
+        Err(e) => {
+            error!(
+                "Error fetching the delivery status of {}: {}",
+                message_id, e
+            );
+            None
+        }
+    }
+}
+
+/// Parse a bounce message, using the `message/delivery-status` body when it has one and
+/// falling back to the status codes in its text for bounces that don't follow RFC 3464
+fn parse_bounce(message: &Value, delivery_status: Option<&str>) -> Bounce {
+    let payload = &message["payload"];
+    let mut headers = String::new();
+    let mut body = String::new();
+    let mut original_message_id = None;
+    for part in parts(payload) {
+        for header in part["headers"].as_array().into_iter().flatten() {
+            if let Some(value) = header["value"].as_str() {
+                headers.push_str(value);
+                headers.push('\n');
+            }
+        }
+        if is_delivery_status(part) {
+            continue;
+        }
+        let Some(text) = part_text(part) else {
+            continue;
+        };
+        let mime_type = part["mimeType"].as_str().unwrap_or_default();
+        if original_message_id.is_none()
+            && (mime_type.eq_ignore_ascii_case("text/rfc822-headers")
+                || mime_type.eq_ignore_ascii_case("message/rfc822"))
+        {
+            original_message_id = ORIGINAL_MESSAGE_ID
+                .captures(&text)
+                .map(|captures| captures[1].to_string());
+        }
+        body.push_str(&text);
+        body.push('\n');
+    }
+
+    let (reporting_mta, mut recipients) = match delivery_status {
+        Some(delivery_status) => parse_delivery_status(delivery_status),
+        None => (None, Vec::new()),
+    };
+    if recipients.is_empty() {
+        recipients = text_statuses(payload, &body, message["snippet"].as_str());
+    }
+    for status in &mut recipients {
+        if status.status.is_none() {
+            status.status = status
+                .diagnostic
+                .as_deref()
+                .and_then(status_code)
+                .map(|code| code.as_str().to_string());
+        }
+        if status.action.is_none() {
+            status.action = status
+                .status
+                .as_deref()
+                .and_then(status_action)
+                .map(str::to_string);
+        }
+    }
+
+    Bounce {
+        reporting_mta,
+        original_message_id,
+        recipients,
+        text: headers + &body,
+    }
+}
+
+impl Bounce {
+    /// Whether the bounce is about the sent message: Gmail threads bounces with the message
+    /// that bounced, and most MTAs quote its Message-ID
+    fn refers_to(&self, message: &Value, sent: &SentMessage) -> bool {
+        if sent.thread_id.is_some() && message["threadId"].as_str() == sent.thread_id.as_deref() {
+            return true;
+        }
+        match &sent.rfc822_id {
+            Some(rfc822_id) => {
+                self.original_message_id.as_deref() == Some(rfc822_id.as_str())
+                    || self.text.contains(rfc822_id.as_str())
+            }
+            None => false,
+        }
+    }
+}
+
+/// The reporting MTA and per-recipient fields of an RFC 3464 `message/delivery-status` body
+fn parse_delivery_status(body: &str) -> (Option<String>, Vec<RecipientStatus>) {
+    let mut reporting_mta = None;
+    let mut recipients = Vec::new();
+    for block in field_blocks(body) {
+        let field = |name: &str| {
+            block
+                .iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value.as_str())
+        };
+        if let Some(mta) = field("reporting-mta") {
+            reporting_mta = Some(typed_value(mta).to_string());
+        }
+        let Some(recipient) = field("final-recipient").or_else(|| field("original-recipient"))
+        else {
+            continue;
+        };
+        recipients.push(RecipientStatus {
+            recipient: Some(typed_value(recipient).to_string()),
+            action: field("action").map(str::to_ascii_lowercase),
+            status: field("status")
+                .and_then(|status| status.split_whitespace().next())
+                .map(str::to_string),
+            diagnostic: field("diagnostic-code").map(|code| typed_value(code).to_string()),
+        });
+    }
+    (reporting_mta, recipients)
+}
+
+/// Statuses read from the text of a bounce without a delivery-status part, one for each
+/// address in its `X-Failed-Recipients` header
+fn text_statuses(payload: &Value, text: &str, snippet: Option<&str>) -> Vec<RecipientStatus> {
+    let status = status_code(text).map(|code| code.as_str().to_string());
+    let diagnostic = match status_code(text) {
+        Some(code) => {
+            let start = text[..code.start()].rfind('\n').map_or(0, |i| i + 1);
+            let end = text[code.end()..]
+                .find('\n')
+                .map_or(text.len(), |i| code.end() + i);
+            Some(text[start..end].trim().to_string())
+        }
+        None => snippet.map(str::to_string),
+    };
+    let failed = header(payload, "X-Failed-Recipients");
+    let recipients: Vec<Option<String>> = match &failed {
+        Some(failed) => failed
+            .split(',')
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .map(|address| Some(address.to_string()))
+            .collect(),
+        None => vec![None],
+    };
+    recipients
+        .into_iter()
+        .map(|recipient| RecipientStatus {
+            recipient,
+            action: None,
+            status: status.clone(),
+            diagnostic: diagnostic.clone(),
+        })
+        .collect()
+}
+
+/// The first enhanced status code in a text
+fn status_code(text: &str) -> Option<regex::Match<'_>> {
+    STATUS_CODE
+        .captures(text)
+        .and_then(|captures| captures.get(1))
+}
+
+/// Groups of `name: value` fields separated by blank lines, with folded lines unfolded and
+/// names lowercased
+fn field_blocks(body: &str) -> Vec<Vec<(String, String)>> {
+    let mut blocks = Vec::new();
+    let mut block: Vec<(String, String)> = Vec::new();
+    for line in body.lines() {
+        if line.trim().is_empty() {
+            if !block.is_empty() {
+                blocks.push(std::mem::take(&mut block));
+            }
+        } else if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = block.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            block.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    if !block.is_empty() {
+        blocks.push(block);
+    }
+    blocks
+}
+
+/// The value of a field typed like `rfc822; bob@example.com` or `smtp; 550 ...`
+fn typed_value(value: &str) -> &str {
+    value
+        .split_once(';')
+        .map_or(value, |(_, value)| value)
+        .trim()
+}
+
+/// The RFC 3464 action an enhanced status code implies
+fn status_action(status: &str) -> Option<&'static str> {
+    match status.split('.').next()? {
+        "2" => Some("delivered"),
+        "4" => Some("delayed"),
+        "5" => Some("failed"),
+        _ => None,
+    }
+}
+
+/// A readable explanation of an enhanced status code, for the common RFC 3463 codes
+fn describe_status(status: &str) -> Option<String> {
+    let (class, detail) = status.split_once('.')?;
+    let class = match class {
+        "2" => "Delivered",
+        "4" => "Temporary failure, delivery may still be retried",
+        "5" => "Permanent failure",
+        _ => return None,
+    };
+    let detail = match detail {
+        "1.1" => "the mailbox doesn't exist",
+        "1.2" => "the recipient's domain doesn't exist or doesn't accept mail",
+        "1.3" => "the address is malformed",
+        "1.10" => "the recipient's domain doesn't accept mail",
+        "2.1" => "the mailbox is disabled",
+        "2.2" => "the mailbox is full",
+        "2.3" | "3.4" => "the message is too large",
+        "4.1" | "4.2" => "the recipient's server didn't answer",
+        "4.4" => "no route to the recipient's server",
+        "4.7" => "delivery timed out",
+        "5.3" => "too many recipients",
+        "7.0" | "7.1" => "the recipient's server refused the message",
+        "7.26" | "7.27" => "the message failed sender authentication checks",
+        _ => return Some(class.to_string()),
+    };
+    Some(format!("{class}: {detail}"))
+}
+
+/// A part and every part nested in it, depth first
+fn parts(part: &Value) -> Vec<&Value> {
+    let mut parts = vec![part];
+    for nested in part["parts"].as_array().into_iter().flatten() {
+        parts.extend(self::parts(nested));
+    }
+    parts
+}
+
+fn is_delivery_status(part: &Value) -> bool {
+    let mime_type = part["mimeType"].as_str().unwrap_or_default();
+    mime_type.eq_ignore_ascii_case("message/delivery-status")
+        || mime_type.eq_ignore_ascii_case("message/global-delivery-status")
+}
+
+/// A part's body decoded as text, when Gmail includes it inline
+fn part_text(part: &Value) -> Option<String> {
+    part["body"]["data"]
+        .as_str()
+        .and_then(|data| decode_email_content(data).ok())
+}
+
+/// The value of a payload header, matching its name case-insensitively
+fn header(payload: &Value, name: &str) -> Option<String> {
+    payload["headers"]
+        .as_array()?
+        .iter()
+        .find(|header| {
+            header["name"]
+                .as_str()
+                .is_some_and(|header| header.eq_ignore_ascii_case(name))
+        })
+        .and_then(|header| header["value"].as_str())
+        .map(str::to_string)
+}
+
+fn contains_ignore_case(text: &str, needle: &str) -> bool {
+    text.to_lowercase().contains(&needle.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockGmail;
+    use base64::{engine::general_purpose::URL_SAFE, Engine};
+
+    const DELIVERY_STATUS: &str = "Reporting-MTA: dns; mx.google.com\r\n\
+        Arrival-Date: Mon, 30 Sep 2024 11:00:00 -0700\r\n\
+        \r\n\
+        Final-Recipient: rfc822; carol@example.org\r\n\
+        Action: failed\r\n\
+        Status: 5.1.1\r\n\
+        Remote-MTA: dns; mx.example.org\r\n\
+        Diagnostic-Code: smtp; 550-5.1.1 The email account that you tried to reach\r\n \
+        does not exist.\r\n\
+        \r\n\
+        Final-Recipient: rfc822; dave@example.org\r\n\
+        Action: delayed\r\n\
+        Status: 4.4.1\r\n";
+
+    #[test]
+    fn test_parse_delivery_status() {
+        let (reporting_mta, recipients) = parse_delivery_status(DELIVERY_STATUS);
+        assert_eq!(reporting_mta.as_deref(), Some("mx.google.com"));
+        assert_eq!(
+            recipients,
+            vec![
+                RecipientStatus {
+                    recipient: Some("carol@example.org".to_string()),
+                    action: Some("failed".to_string()),
+                    status: Some("5.1.1".to_string()),
+                    diagnostic: Some(
+                        "550-5.1.1 The email account that you tried to reach does not exist."
+                            .to_string()
+                    ),
+                },
+                RecipientStatus {
+                    recipient: Some("dave@example.org".to_string()),
+                    action: Some("delayed".to_string()),
+                    status: Some("4.4.1".to_string()),
+                    diagnostic: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_bounce_without_delivery_status() {
+        let text = "Delivery has failed to these recipients:\r\n\r\n\
+            erin@example.net\r\n\
+            Remote server returned '554 5.7.1 Recipient address rejected: Access denied'\r\n";
+        let message = json!({
+            "snippet": "Delivery has failed",
+            "payload": {
+                "mimeType": "text/plain",
+                "headers": [{ "name": "X-Failed-Recipients", "value": "erin@example.net" }],
+                "body": { "data": URL_SAFE.encode(text) },
+            },
+        });
+        let bounce = parse_bounce(&message, None);
+        assert_eq!(
+            bounce.recipients,
+            vec![RecipientStatus {
+                recipient: Some("erin@example.net".to_string()),
+                action: Some("failed".to_string()),
+                status: Some("5.7.1".to_string()),
+                diagnostic: Some(
+                    "Remote server returned '554 5.7.1 Recipient address rejected: Access denied'"
+                        .to_string()
+                ),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_describe_status() {
+        assert_eq!(
+            describe_status("5.1.1").as_deref(),
+            Some("Permanent failure: the mailbox doesn't exist")
+        );
+        assert_eq!(
+            describe_status("4.9.9").as_deref(),
+            Some("Temporary failure, delivery may still be retried")
+        );
+        assert_eq!(describe_status("smtp"), None);
+    }
+
+    #[test]
+    fn test_bounce_query() {
+        assert_eq!(
+            bounce_query(Some(" \"carol@example.org\" "), Some(1727719200)),
+            format!("{BOUNCE_QUERY} \"carol@example.org\" after:1727719200")
+        );
+        assert_eq!(bounce_query(None, None), BOUNCE_QUERY);
+    }
+
+    #[tokio::test]
+    async fn test_find_bounces_against_mock_gmail() {
+        let gmail = MockGmail::start().await;
+        let config = gmail.config();
+        let gmail_server = gmail.gmail_server(&config).await;
+
+        let found = find_bounces(&gmail_server, Some("msg-1"), None, 10)
+            .await
+            .unwrap();
+        let bounces = found["bounces"].as_array().unwrap();
+        assert_eq!(bounces.len(), 1);
+        assert_eq!(bounces[0]["message_id"], "msg-bounce");
+        assert_eq!(bounces[0]["recipient"], "carol@example.org");
+        assert_eq!(bounces[0]["action"], "failed");
+        assert_eq!(bounces[0]["status"], "5.1.1");
+        assert_eq!(bounces[0]["original_message_id"], "<msg-1@example.com>");
+
+        // The bounce is in another thread and quotes another Message-ID
+        let found = find_bounces(&gmail_server, Some("msg-3"), None, 10)
+            .await
+            .unwrap();
+        assert_eq!(found["bounces"], json!([]));
+
+        let found = find_bounces(&gmail_server, None, Some("dave@example.org"), 10)
+            .await
+            .unwrap();
+        assert_eq!(found["bounces"], json!([]));
+    }
+}
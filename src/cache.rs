@@ -0,0 +1,382 @@
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::{Map, Value};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::tools::text_size;
+
+/// Persistent cache of message summaries keyed by Gmail message ID.
+///
+/// Entries record the message's historyId when cached; a lookup with a different
+/// historyId is treated as a miss so label or content changes are refetched.
+/// Threads are cached as their historyId and message list, so an unchanged
+/// thread can be validated with a single cheap historyId probe.
+pub struct MessageCache {
+    conn: Mutex<Connection>,
+}
+
+impl MessageCache {
+    /// Open (or create) the cache database at the given path
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open message cache at {}", path.display()))?;
+        Self::init(conn)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                message_id TEXT PRIMARY KEY,
+                thread_id TEXT NOT NULL,
+                history_id TEXT NOT NULL,
+                metadata TEXT NOT NULL,
+                body TEXT,
+                cached_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS messages_thread_id ON messages (thread_id);
+            CREATE TABLE IF NOT EXISTS threads (
+                thread_id TEXT PRIMARY KEY,
+                history_id TEXT NOT NULL,
+                message_ids TEXT NOT NULL,
+                cached_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS sync_state (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            );",
+        )
+        .context("Failed to initialize message cache schema")?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Get the cached summary for a message if it is still at the given historyId
+    pub fn get(&self, message_id: &str, history_id: &str) -> Result<Option<Value>> {
+        let conn = self.conn.lock().unwrap();
+        let row: Option<(String, Option<String>)> = conn
+            .query_row(
+                "SELECT metadata, body FROM messages WHERE message_id = ?1 AND history_id = ?2",
+                params![message_id, history_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .context("Failed to read message cache")?;
+
+        row.map(|(metadata, body)| summary_from_row(&metadata, body))
+            .transpose()
+    }
+
+    /// Whether a thread has been cached at any historyId
+    pub fn contains_thread(&self, thread_id: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM threads WHERE thread_id = ?1",
+                params![thread_id],
+                |row| row.get(0),
+            )
+            .context("Failed to read thread cache")?;
+        Ok(count > 0)
+    }
+
+    /// Get the cached message summaries of a thread if it is still at the given historyId
+    ///
+    /// Returns `None` if the thread changed or any of its messages is no longer cached.
+    pub fn get_thread(&self, thread_id: &str, history_id: &str) -> Result<Option<Vec<Value>>> {
+        let conn = self.conn.lock().unwrap();
+        let message_ids: Option<String> = conn
+            .query_row(
+                "SELECT message_ids FROM threads WHERE thread_id = ?1 AND history_id = ?2",
+                params![thread_id, history_id],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("Failed to read thread cache")?;
+        let Some(message_ids) = message_ids else {
+            return Ok(None);
+        };
+        let message_ids: Vec<String> =
+            serde_json::from_str(&message_ids).context("Failed to parse cached thread")?;
+
+        let mut summaries = Vec::with_capacity(message_ids.len());
+        for message_id in message_ids {
+            let row: Option<(String, Option<String>)> = conn
+                .query_row(
+                    "SELECT metadata, body FROM messages WHERE message_id = ?1",
+                    params![message_id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()
+                .context("Failed to read message cache")?;
+            match row {
+                Some((metadata, body)) => summaries.push(summary_from_row(&metadata, body)?),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(summaries))
+    }
+
+    /// Record a thread's historyId and message list
+    pub fn put_thread(
+        &self,
+        thread_id: &str,
+        history_id: &str,
+        message_ids: &[String],
+    ) -> Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO threads (thread_id, history_id, message_ids, cached_at)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    thread_id,
+                    history_id,
+                    serde_json::to_string(message_ids)?,
+                    now_secs()
+                ],
+            )
+            .context("Failed to write thread cache")?;
+        Ok(())
+    }
+
+    /// Store a message summary, splitting the decoded body from the rest of the metadata
+    pub fn put(
+        &self,
+        message_id: &str,
+        thread_id: &str,
+        history_id: &str,
+        summary: &Value,
+    ) -> Result<()> {
+        let mut metadata = summary
+            .as_object()
+            .cloned()
+            .context("Message summary must be a JSON object")?;
+        // The body's size is worked out again from the body when it is read back
+        metadata.remove("body_size");
+        let body = metadata
+            .remove("body")
+            .and_then(|body| body.as_str().map(str::to_string));
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO messages
+                    (message_id, thread_id, history_id, metadata, body, cached_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    message_id,
+                    thread_id,
+                    history_id,
+                    Value::Object(metadata).to_string(),
+                    body,
+                    now_secs()
+                ],
+            )
+            .context("Failed to write message cache")?;
+        Ok(())
+    }
+
+    /// Drop a changed message and the cached listing of its thread
+    pub fn invalidate(&self, message_id: &str, thread_id: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM messages WHERE message_id = ?1",
+            params![message_id],
+        )
+        .context("Failed to invalidate cached message")?;
+        conn.execute(
+            "DELETE FROM threads WHERE thread_id = ?1",
+            params![thread_id],
+        )
+        .context("Failed to invalidate cached thread")?;
+        Ok(())
+    }
+
+    /// Remove every cached message, thread and the history sync position
+    pub fn clear(&self) -> Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute_batch("DELETE FROM messages; DELETE FROM threads; DELETE FROM sync_state;")
+            .context("Failed to clear message cache")
+    }
+
+    /// Numbers of cached messages and threads
+    pub fn entry_counts(&self) -> Result<(u64, u64)> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT (SELECT COUNT(*) FROM messages), (SELECT COUNT(*) FROM threads)",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .context("Failed to count cache entries")
+    }
+
+    /// The mailbox historyId the cache was last synchronized to
+    pub fn last_history_id(&self) -> Result<Option<String>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT value FROM sync_state WHERE key = 'last_history_id'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("Failed to read history sync state")
+    }
+
+    /// Record the mailbox historyId the cache is synchronized to
+    pub fn set_last_history_id(&self, history_id: &str) -> Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO sync_state (key, value) VALUES ('last_history_id', ?1)",
+                params![history_id],
+            )
+            .context("Failed to write history sync state")?;
+        Ok(())
+    }
+}
+
+/// Rebuild a message summary from its cached metadata and body columns
+fn summary_from_row(metadata: &str, body: Option<String>) -> Result<Value> {
+    let mut summary: Map<String, Value> =
+        serde_json::from_str(metadata).context("Failed to parse cached metadata")?;
+    if let Some(body) = &body {
+        summary.insert("body_size".to_string(), text_size(body));
+    }
+    summary.insert("body".to_string(), body.map_or(Value::Null, Value::String));
+    Ok(Value::Object(summary))
+}
+
+fn now_secs() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn summary() -> Value {
+        json!({
+            "message_id": "m1",
+            "from": "alice@example.com",
+            "subject": "Hello",
+            "date": null,
+            "body_size": { "bytes": 9, "estimated_tokens": 3 },
+            "body": "Body text"
+        })
+    }
+
+    #[test]
+    fn test_put_and_get_round_trip() {
+        let cache = MessageCache::open_in_memory().unwrap();
+        cache.put("m1", "t1", "100", &summary()).unwrap();
+        assert_eq!(cache.get("m1", "100").unwrap(), Some(summary()));
+    }
+
+    #[test]
+    fn test_get_with_different_history_id_misses() {
+        let cache = MessageCache::open_in_memory().unwrap();
+        cache.put("m1", "t1", "100", &summary()).unwrap();
+        assert_eq!(cache.get("m1", "101").unwrap(), None);
+        assert_eq!(cache.get("m2", "100").unwrap(), None);
+    }
+
+    #[test]
+    fn test_put_replaces_existing_entry() {
+        let cache = MessageCache::open_in_memory().unwrap();
+        cache.put("m1", "t1", "100", &summary()).unwrap();
+        let mut updated = summary();
+        updated["subject"] = json!("Updated");
+        cache.put("m1", "t1", "200", &updated).unwrap();
+        assert_eq!(cache.get("m1", "100").unwrap(), None);
+        assert_eq!(cache.get("m1", "200").unwrap(), Some(updated));
+    }
+
+    #[test]
+    fn test_thread_round_trip() {
+        let cache = MessageCache::open_in_memory().unwrap();
+        assert!(!cache.contains_thread("t1").unwrap());
+        cache.put("m1", "t1", "100", &summary()).unwrap();
+        cache.put_thread("t1", "150", &["m1".to_string()]).unwrap();
+        assert!(cache.contains_thread("t1").unwrap());
+        assert_eq!(
+            cache.get_thread("t1", "150").unwrap(),
+            Some(vec![summary()])
+        );
+    }
+
+    #[test]
+    fn test_get_thread_with_changed_history_misses() {
+        let cache = MessageCache::open_in_memory().unwrap();
+        cache.put("m1", "t1", "100", &summary()).unwrap();
+        cache.put_thread("t1", "150", &["m1".to_string()]).unwrap();
+        assert_eq!(cache.get_thread("t1", "151").unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_thread_with_missing_message_misses() {
+        let cache = MessageCache::open_in_memory().unwrap();
+        cache.put("m1", "t1", "100", &summary()).unwrap();
+        cache
+            .put_thread("t1", "150", &["m1".to_string(), "m2".to_string()])
+            .unwrap();
+        assert_eq!(cache.get_thread("t1", "150").unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalidate_drops_message_and_thread() {
+        let cache = MessageCache::open_in_memory().unwrap();
+        cache.put("m1", "t1", "100", &summary()).unwrap();
+        cache.put_thread("t1", "150", &["m1".to_string()]).unwrap();
+        cache.invalidate("m1", "t1").unwrap();
+        assert_eq!(cache.get("m1", "100").unwrap(), None);
+        assert!(!cache.contains_thread("t1").unwrap());
+    }
+
+    #[test]
+    fn test_history_id_and_clear() {
+        let cache = MessageCache::open_in_memory().unwrap();
+        assert_eq!(cache.last_history_id().unwrap(), None);
+        cache.set_last_history_id("500").unwrap();
+        cache.set_last_history_id("600").unwrap();
+        assert_eq!(cache.last_history_id().unwrap(), Some("600".to_string()));
+
+        cache.put("m1", "t1", "100", &summary()).unwrap();
+        cache.put_thread("t1", "150", &["m1".to_string()]).unwrap();
+        assert_eq!(cache.entry_counts().unwrap(), (1, 1));
+        cache.clear().unwrap();
+        assert_eq!(cache.entry_counts().unwrap(), (0, 0));
+        assert_eq!(cache.get("m1", "100").unwrap(), None);
+        assert_eq!(cache.last_history_id().unwrap(), None);
+    }
+
+    #[test]
+    fn test_open_persists_to_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.sqlite3");
+        MessageCache::open(&path)
+            .unwrap()
+            .put("m1", "t1", "100", &summary())
+            .unwrap();
+        let reopened = MessageCache::open(&path).unwrap();
+        assert_eq!(reopened.get("m1", "100").unwrap(), Some(summary()));
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::Result;
+use axum::http::{header, HeaderMap};
+use std::sync::Arc;
+
+use crate::config::{Config, HttpConfig};
+use crate::gmail::GmailServer;
+use crate::oauth::{OAuthManager, OAuthToken};
+
+/// Google access tokens are issued for an hour; the caller's real expiry is unknown
+const ASSUMED_LIFETIME_SECS: u64 = 3600;
+
+/// Google access tokens supplied by callers in an `Authorization: Bearer` header.
+///
+/// Only enabled with `--trust-caller-tokens`, for deployments where an upstream gateway already
+/// handles Google auth. Each request gets a throwaway server that never stores or refreshes the
+/// token.
+pub struct CallerTokens {
+    config: Config,
+    http_config: HttpConfig,
+    /// Server whose HTTP client and audit log the per-request servers share
+    template: Arc<GmailServer>,
+}
+
+impl CallerTokens {
+    pub fn new(template: Arc<GmailServer>, config: &Config, http_config: &HttpConfig) -> Self {
+        Self {
+            config: config.clone(),
+            http_config: http_config.clone(),
+            template,
+        }
+    }
+
+    /// A server that calls Gmail with the caller's access token
+    pub async fn gmail_server(&self, access_token: &str) -> Result<Arc<GmailServer>> {
+        let oauth_manager = Arc::new(OAuthManager::in_memory(
+            self.config.clone(),
+            self.http_config.clone(),
+        )?);
+        oauth_manager.set_token(caller_token(access_token)).await;
+        Ok(Arc::new(self.template.for_account(oauth_manager)))
+    }
+}
+
+/// Wrap a caller's access token; without a refresh token a rejected one is reported, not refreshed
+fn caller_token(access_token: &str) -> OAuthToken {
+    OAuthToken {
+        access_token: access_token.to_string(),
+        token_type: "Bearer".to_string(),
+        expires_in: ASSUMED_LIFETIME_SECS,
+        refresh_token: None,
+        scope: String::new(),
+        created_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    }
+}
+
+/// The token from a request's `Authorization: Bearer` header, if any
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let authorization = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = authorization.split_once(' ')?;
+    let token = token.trim();
+    (scheme.eq_ignore_ascii_case("bearer") && !token.is_empty()).then_some(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(authorization).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        assert_eq!(bearer_token(&headers("Bearer ya29.abc")), Some("ya29.abc"));
+        assert_eq!(
+            bearer_token(&headers("bearer  ya29.abc ")),
+            Some("ya29.abc")
+        );
+        assert_eq!(bearer_token(&headers("Basic dXNlcjpwdw==")), None);
+        assert_eq!(bearer_token(&headers("Bearer ")), None);
+        assert_eq!(bearer_token(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn test_caller_token_server() {
+        let config = Config {
+            gmail_client_id: Some("test_client_id".to_string()),
+            gmail_client_secret: Some("test_client_secret".to_string()),
+            ..Default::default()
+        };
+        let http_config = HttpConfig::default();
+        let oauth_manager =
+            Arc::new(OAuthManager::in_memory(config.clone(), http_config.clone()).unwrap());
+        let template = Arc::new(GmailServer::new(oauth_manager.clone(), &config).unwrap());
+        let caller_tokens = CallerTokens::new(template.clone(), &config, &http_config);
+
+        let gmail_server = caller_tokens.gmail_server("ya29.caller").await.unwrap();
+        assert!(gmail_server.is_authenticated().await);
+        let token = caller_token("ya29.caller");
+        assert_eq!(token.access_token, "ya29.caller");
+        assert!(token.refresh_token.is_none());
+        assert!(!token.is_expired());
+        // The server's own token is untouched
+        assert!(oauth_manager.get_token().await.is_none());
+        assert!(!template.is_authenticated().await);
+    }
+}
@@ -0,0 +1,245 @@
+use anyhow::{Context, Result};
+use axum::http::{header, HeaderName};
+use std::fs;
+use std::time::Duration;
+
+use crate::config::{Config, HttpConfig};
+use crate::{csrf_store, mcp_sessions, tls, utils};
+
+/// Validate the `http` command's configuration without starting the server, printing a line per
+/// check and failing when any did
+pub fn run(config: &Config, http_config: &HttpConfig) -> Result<()> {
+    let checks = [
+        ("Client credentials", credentials(config)),
+        ("API key", api_key(http_config)),
+        ("Routes", routes(http_config)),
+        ("OAuth redirect URL", redirect_url(http_config)),
+        ("App data directory", app_data_dir(config)),
+        ("MCP session store", session_store(config, http_config)),
+        ("OAuth login state store", csrf_store(config, http_config)),
+        (
+            "TLS",
+            tls::load(&http_config.tls_config).map(|tls| tls_summary(tls.is_some())),
+        ),
+    ];
+    let mut failed = 0;
+    for (name, result) in &checks {
+        match result {
+            Ok(summary) => println!("✅ {name}: {summary}"),
+            Err(e) => {
+                failed += 1;
+                println!("❌ {name}: {e:#}");
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{} of {} configuration checks failed", failed, checks.len());
+    }
+    println!("Configuration is valid");
+    Ok(())
+}
+
+/// The OAuth client ID and secret are set, directly or from a secret manager
+pub fn credentials(config: &Config) -> Result<String> {
+    if config.gmail_client_id.is_none() {
+        anyhow::bail!("GMAIL_CLIENT_ID environment variable not set");
+    }
+    if config.gmail_client_secret.is_none() {
+        anyhow::bail!("GMAIL_CLIENT_SECRET environment variable not set");
+    }
+    Ok("client ID and secret are set".to_string())
+}
+
+/// `--api-key-header` is a valid header that doesn't clash with `--trust-caller-tokens`
+pub fn api_key(http_config: &HttpConfig) -> Result<String> {
+    if http_config.api_key.is_none() {
+        return Ok("not required".to_string());
+    }
+    HeaderName::from_bytes(http_config.api_key_header.as_bytes())
+        .with_context(|| format!("Invalid API key header: {}", http_config.api_key_header))?;
+    if http_config.trust_caller_tokens
+        && http_config
+            .api_key_header
+            .eq_ignore_ascii_case(header::AUTHORIZATION.as_str())
+    {
+        anyhow::bail!(
+            "--trust-caller-tokens reads Google tokens from the Authorization header, so \
+             --api-key needs another --api-key-header"
+        );
+    }
+    Ok(format!(
+        "required in the {} header",
+        http_config.api_key_header
+    ))
+}
+
+/// Every route is an absolute path that no other route or nested router also serves
+pub fn routes(http_config: &HttpConfig) -> Result<String> {
+    let routes = [
+        ("--root-route", http_config.root_route()),
+        ("--health-route", http_config.health_route()),
+        ("--ready-route", http_config.ready_route()),
+        ("--server-status-route", http_config.server_status_route()),
+        ("--metrics-route", http_config.metrics_route()),
+        ("--openapi-route", http_config.openapi_route()),
+        ("--docs-route", http_config.docs_route()),
+        ("--login-route", http_config.login_route()),
+        ("--callback-route", http_config.callback_route()),
+        ("--refresh-route", http_config.refresh_route()),
+        ("--status-route", http_config.auth_status_route()),
+        ("--accounts-route", http_config.auth_accounts_route()),
+    ];
+    // Routers nested under a prefix serve every path below it
+    let nested = [
+        ("--sse-prefix", http_config.sse_prefix()),
+        ("--http-stream-route", http_config.http_stream_route()),
+        ("--tools-route", http_config.tools_route()),
+    ];
+
+    let all = routes.iter().chain(&nested).collect::<Vec<_>>();
+    for (flag, path) in &all {
+        if !path.starts_with('/') {
+            anyhow::bail!("{} must start with /, got {}", flag, path);
+        }
+    }
+    for (i, (flag, path)) in all.iter().enumerate() {
+        for (other_flag, other_path) in &all[i + 1..] {
+            if path == other_path {
+                anyhow::bail!("{} and {} both use {}", flag, other_flag, path);
+            }
+        }
+    }
+    for (prefix_flag, prefix) in &nested {
+        for (flag, path) in &all {
+            if flag != prefix_flag && is_under(path, prefix) {
+                anyhow::bail!("{} ({}) is inside {} ({})", flag, path, prefix_flag, prefix);
+            }
+        }
+    }
+    Ok(format!("{} paths, none overlapping", all.len()))
+}
+
+fn is_under(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    prefix.is_empty()
+        || path
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// The redirect URL Google sends the login back to is an absolute http(s) URL
+pub fn redirect_url(http_config: &HttpConfig) -> Result<String> {
+    let redirect_url = http_config.oauth_redirect_url();
+    let url = url::Url::parse(&redirect_url)
+        .with_context(|| format!("Invalid OAuth redirect URL {redirect_url}"))?;
+    if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+        anyhow::bail!(
+            "OAuth redirect URL {} must be an http or https URL",
+            redirect_url
+        );
+    }
+    Ok(redirect_url)
+}
+
+/// The app data directory exists, or can be created, and files can be written to it
+pub fn app_data_dir(config: &Config) -> Result<String> {
+    let app_data_dir = utils::get_app_data_dir(config)?;
+    let probe = app_data_dir.join(".check-config");
+    fs::write(&probe, b"").with_context(|| format!("Can't write to {}", app_data_dir.display()))?;
+    fs::remove_file(&probe).with_context(|| format!("Can't remove {}", probe.display()))?;
+    Ok(format!("{} is writable", app_data_dir.display()))
+}
+
+/// `--session-store` names a Redis URL this build can use
+pub fn session_store(config: &Config, http_config: &HttpConfig) -> Result<String> {
+    let store = mcp_sessions::open(
+        http_config.session_store.as_deref(),
+        &config.token_storage.redis_key_prefix,
+        http_config.session_store_ttl_secs,
+    )?;
+    Ok(match store {
+        Some(store) => store.location(),
+        None => "in memory, lost on restart".to_string(),
+    })
+}
+
+/// `--csrf-store` names a Redis URL this build can use
+pub fn csrf_store(config: &Config, http_config: &HttpConfig) -> Result<String> {
+    let store = csrf_store::open(
+        http_config.auth_config.csrf_store.as_deref(),
+        &config.token_storage.redis_key_prefix,
+        Duration::from_secs(http_config.auth_config.csrf_token_ttl_secs.max(1)),
+    )?;
+    Ok(store.location())
+}
+
+fn tls_summary(enabled: bool) -> String {
+    if enabled {
+        "certificate and key load".to_string()
+    } else {
+        "not configured, serving plain HTTP".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_routes() {
+        let mut http_config = HttpConfig::default();
+        assert!(routes(&http_config).is_ok());
+
+        http_config.ready_route = "/healthz".to_string();
+        let error = routes(&http_config).unwrap_err().to_string();
+        assert_eq!(error, "--health-route and --ready-route both use /healthz");
+
+        http_config.ready_route = "/tools/ready".to_string();
+        let error = routes(&http_config).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "--ready-route (/tools/ready) is inside --tools-route (/tools)"
+        );
+
+        // A shared prefix isn't nesting
+        http_config.ready_route = "/toolsready".to_string();
+        assert!(routes(&http_config).is_ok());
+
+        http_config.metrics_route = "metrics".to_string();
+        assert!(routes(&http_config).is_err());
+    }
+
+    #[test]
+    fn test_redirect_url() {
+        let mut http_config = HttpConfig::default();
+        assert_eq!(
+            redirect_url(&http_config).unwrap(),
+            "http://localhost:8080/auth/callback"
+        );
+        http_config.oauth_redirect_url = Some("/auth/callback".to_string());
+        assert!(redirect_url(&http_config).is_err());
+        http_config.oauth_redirect_url = Some("mailto:me@example.com".to_string());
+        assert!(redirect_url(&http_config).is_err());
+    }
+
+    #[test]
+    fn test_credentials_and_app_data_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            app_data_dir: Some(dir.path().join("data")),
+            ..Default::default()
+        };
+        assert!(credentials(&config).is_err());
+        assert!(app_data_dir(&config).is_ok());
+        assert!(!dir.path().join("data/.check-config").exists());
+
+        config.gmail_client_id = Some("id".to_string());
+        config.gmail_client_secret = Some("secret".to_string());
+        assert!(credentials(&config).is_ok());
+
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        config.app_data_dir = Some(file);
+        assert!(app_data_dir(&config).is_err());
+    }
+}
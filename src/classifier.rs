@@ -0,0 +1,269 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tracing::instrument;
+
+use crate::config::Config;
+use crate::gmail::GmailServer;
+use crate::oauth::SCOPE_READONLY;
+use crate::tools::{message_url, summarize_message, MessageFormat};
+use crate::utils::run_shell;
+
+/// Headers sent to the classifier besides the ones in a message summary
+const EXTRA_HEADERS: [&str; 4] = ["To", "Cc", "Reply-To", "List-Id"];
+
+/// A hook classifying messages, from --classifier-url or --classifier-command
+#[derive(Debug)]
+pub enum Classifier {
+    /// POST the message as JSON and read the classification from the response
+    Http {
+        url: String,
+        client: reqwest::Client,
+    },
+    /// Run a shell command with the message as JSON on stdin and read the classification from
+    /// its stdout
+    Command {
+        command: String,
+        timeout: Option<Duration>,
+    },
+}
+
+/// Labels to apply and named scores, as the classifier returns them
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Classification {
+    #[serde(default)]
+    pub labels: Vec<String>,
+    #[serde(default)]
+    pub scores: BTreeMap<String, f64>,
+}
+
+impl Classifier {
+    /// The configured classifier, if any
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        let timeout = (config.classifier_timeout_secs > 0)
+            .then(|| Duration::from_secs(config.classifier_timeout_secs));
+        if let Some(url) = &config.classifier_url {
+            reqwest::Url::parse(url).with_context(|| format!("Invalid classifier URL: {url}"))?;
+            let mut builder = reqwest::Client::builder();
+            if let Some(timeout) = timeout {
+                builder = builder.timeout(timeout);
+            }
+            let client = builder
+                .build()
+                .context("Failed to build classifier HTTP client")?;
+            return Ok(Some(Self::Http {
+                url: url.clone(),
+                client,
+            }));
+        }
+        Ok(config
+            .classifier_command
+            .as_ref()
+            .map(|command| Self::Command {
+                command: command.clone(),
+                timeout,
+            }))
+    }
+
+    /// Classify a message, given as the JSON the classifier receives
+    pub async fn classify(&self, message: &Value) -> Result<Classification> {
+        match self {
+            Self::Http { url, client } => {
+                let response = client
+                    .post(url)
+                    .json(message)
+                    .send()
+                    .await
+                    .context("Failed to call the classifier")?;
+                let status = response.status();
+                if !status.is_success() {
+                    let error_text = response.text().await.unwrap_or_default();
+                    anyhow::bail!("Classifier returned {status}: {error_text}");
+                }
+                response
+                    .json()
+                    .await
+                    .context("Classifier returned invalid JSON")
+            }
+            Self::Command {
+                command,
+                timeout: Some(timeout),
+            } => tokio::time::timeout(*timeout, run_command(command, message))
+                .await
+                .with_context(|| {
+                    format!("Classifier command timed out after {}s", timeout.as_secs())
+                })?,
+            Self::Command {
+                command,
+                timeout: None,
+            } => run_command(command, message).await,
+        }
+    }
+}
+
+async fn run_command(command: &str, message: &Value) -> Result<Classification> {
+    let output = run_shell(command, message.to_string().as_bytes())
+        .await
+        .context("Failed to run the classifier command")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "Classifier command failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    serde_json::from_slice(&output.stdout).context("Classifier command printed invalid JSON")
+}
+
+/// What the classifier receives: the message's IDs, labels, headers, snippet and body
+fn classifier_input(message_id: &str, message: &Value) -> Result<Value> {
+    // Messages with no text part are classified on their headers and snippet
+    let mut input = summarize_message(message_id, message, MessageFormat::Full)
+        .or_else(|_| summarize_message(message_id, message, MessageFormat::Metadata))?;
+    input["thread_id"] = message["threadId"].clone();
+    input["label_ids"] = message["labelIds"].clone();
+    input["snippet"] = message["snippet"].clone();
+    for header in message["payload"]["headers"]
+        .as_array()
+        .into_iter()
+        .flatten()
+    {
+        if let Some(name) = header["name"].as_str() {
+            if let Some(wanted) = EXTRA_HEADERS.iter().find(|h| h.eq_ignore_ascii_case(name)) {
+                input[wanted.to_lowercase().replace('-', "_")] = header["value"].clone();
+            }
+        }
+    }
+    Ok(input)
+}
+
+/// Fetch a message and run the configured classifier on it
+pub async fn classify(gmail_server: &GmailServer, message_id: &str) -> Result<Classification> {
+    let Some(classifier) = gmail_server.classifier() else {
+        anyhow::bail!("No classifier is configured: set --classifier-url or --classifier-command");
+    };
+    gmail_server.check_authentication(&[SCOPE_READONLY]).await?;
+    let client = gmail_server.authenticated_client().await?;
+    let message = client
+        .get_json(&message_url(
+            gmail_server.user_id(),
+            message_id,
+            MessageFormat::Full,
+            None,
+        ))
+        .await
+        .context("Failed to get message")?;
+    let input = classifier_input(message_id, &message)?;
+    classifier.classify(&input).await
+}
+
+/// Run the configured classifier on a message, without applying its labels
+#[instrument(name = "tool", skip_all, fields(tool = "classify_message", message_id = %message_id))]
+pub async fn classify_message(gmail_server: &GmailServer, message_id: &str) -> Result<Value> {
+    let classification = classify(gmail_server, message_id).await?;
+    Ok(json!({
+        "message_id": message_id,
+        "labels": classification.labels,
+        "scores": classification.scores,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockGmail;
+    use wiremock::matchers::{body_partial_json, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_classifier_input() {
+        let message = json!({
+            "threadId": "t1",
+            "labelIds": ["INBOX"],
+            "snippet": "Win a prize",
+            "payload": {
+                "mimeType": "text/plain",
+                "headers": [
+                    {"name": "From", "value": "promo@example.com"},
+                    {"name": "to", "value": "me@example.com"},
+                    {"name": "List-Id", "value": "<deals.example.com>"},
+                ],
+                "body": {"data": "V2luIGEgcHJpemU="},
+            },
+        });
+        let input = classifier_input("m1", &message).unwrap();
+        assert_eq!(input["message_id"], "m1");
+        assert_eq!(input["thread_id"], "t1");
+        assert_eq!(input["label_ids"], json!(["INBOX"]));
+        assert_eq!(input["from"], "promo@example.com");
+        assert_eq!(input["to"], "me@example.com");
+        assert_eq!(input["list_id"], "<deals.example.com>");
+        assert_eq!(input["body"], "Win a prize");
+
+        // Without a body it still has the headers and snippet
+        let mut attachment_only = message.clone();
+        attachment_only["payload"]["body"] = json!({});
+        let input = classifier_input("m1", &attachment_only).unwrap();
+        assert!(input["body"].is_null());
+        assert_eq!(input["snippet"], "Win a prize");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_classifier() {
+        let classifier = Classifier::Command {
+            command: r#"grep -q prize && echo '{"labels": ["Spam"], "scores": {"spam": 0.97}}'"#
+                .to_string(),
+            timeout: Some(Duration::from_secs(10)),
+        };
+        let classification = classifier
+            .classify(&json!({ "body": "Win a prize" }))
+            .await
+            .unwrap();
+        assert_eq!(classification.labels, vec!["Spam"]);
+        assert_eq!(classification.scores["spam"], 0.97);
+
+        // grep finds nothing and fails, so the command does
+        assert!(classifier
+            .classify(&json!({ "body": "Lunch?" }))
+            .await
+            .is_err());
+
+        let slow = Classifier::Command {
+            command: "sleep 5".to_string(),
+            timeout: Some(Duration::from_millis(100)),
+        };
+        let error = slow.classify(&json!({})).await.unwrap_err();
+        assert!(error.to_string().contains("timed out"));
+    }
+
+    #[tokio::test]
+    async fn test_classify_message_with_http_classifier() {
+        let hook = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({
+                "message_id": "msg-1",
+                "thread_id": "thread-1",
+            })))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "scores": { "priority": 0.4 } })),
+            )
+            .mount(&hook)
+            .await;
+
+        let gmail = MockGmail::start().await;
+        let mut config = gmail.config();
+        config.classifier_url = Some(hook.uri());
+        let gmail_server = gmail.gmail_server(&config).await;
+        let result = classify_message(&gmail_server, "msg-1").await.unwrap();
+        assert_eq!(
+            result,
+            json!({ "message_id": "msg-1", "labels": [], "scores": { "priority": 0.4 } })
+        );
+
+        let unconfigured = gmail.gmail_server(&gmail.config()).await;
+        assert!(classify_message(&unconfigured, "msg-1").await.is_err());
+    }
+}
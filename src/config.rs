@@ -1,5 +1,10 @@
 use clap::{Args, Parser};
 use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::export::ExportFormat;
+use crate::secrets::ClientCredentials;
+use crate::tools::{Category, MessageFormat};
 
 #[derive(Parser, Debug, Clone)]
 #[command(name = "gmail-mcp-server")]
@@ -25,6 +30,23 @@ pub struct AuthConfig {
     /// OAuth callback route path (defaults to /auth/callback)
     #[arg(long, env = "CALLBACK_ROUTE", default_value = "/auth/callback")]
     pub callback_route: String,
+
+    /// Authentication status route path (defaults to /auth/status)
+    #[arg(long, env = "AUTH_STATUS_ROUTE", default_value = "/auth/status")]
+    pub status_route: String,
+
+    /// Configured accounts route path (defaults to /auth/accounts)
+    #[arg(long, env = "AUTH_ACCOUNTS_ROUTE", default_value = "/auth/accounts")]
+    pub accounts_route: String,
+
+    /// Seconds a login's CSRF state stays valid for the OAuth callback (defaults to 600)
+    #[arg(long, env = "CSRF_TOKEN_TTL_SECS", default_value = "600")]
+    pub csrf_token_ttl_secs: u64,
+
+    /// Redis URL where logins' CSRF state is kept so any replica can serve the OAuth callback,
+    /// e.g. redis://localhost:6379 (requires the `redis` feature; defaults to this process's memory)
+    #[arg(long, env = "CSRF_STORE")]
+    pub csrf_store: Option<String>,
 }
 
 impl Default for AuthConfig {
@@ -33,19 +55,64 @@ impl Default for AuthConfig {
             login_route: "/auth/login".to_string(),
             refresh_route: "/auth/refresh".to_string(),
             callback_route: "/auth/callback".to_string(),
+            status_route: "/auth/status".to_string(),
+            accounts_route: "/auth/accounts".to_string(),
+            csrf_token_ttl_secs: 600,
+            csrf_store: None,
         }
     }
 }
 
+// Parsed once at startup, so the size of the Http variant doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Parser, Debug, Clone)]
 pub enum Commands {
     /// Run the HTTP server
     Http(HttpConfig),
+    /// Check the http command's configuration and exit non-zero if any of it is invalid
+    CheckConfig(HttpConfig),
     /// Access tools
     Tools {
+        /// Account to call the tool as, from --accounts (defaults to --default-account)
+        #[arg(long, global = true)]
+        account: Option<String>,
         #[command(subcommand)]
         tool: ToolsCmd,
     },
+    /// Measure tool latency by calling a tool repeatedly, against Gmail or a built-in fake
+    Bench(BenchConfig),
+    /// Manage the stored OAuth token
+    Auth {
+        /// Account whose token to manage, from --accounts (defaults to the token.json account)
+        #[arg(long, global = true, default_value = crate::accounts::DEFAULT_ACCOUNT)]
+        account: String,
+        #[command(subcommand)]
+        command: AuthCmd,
+    },
+}
+
+#[derive(Parser, Debug, Clone)]
+pub enum AuthCmd {
+    /// Log in through the browser and save the token, without running the HTTP server
+    Login {
+        /// Seconds to wait for the login to complete in the browser
+        #[arg(long, default_value = "300")]
+        timeout_secs: u64,
+        /// Only print the login URL instead of also opening it in a browser
+        #[arg(long)]
+        no_browser: bool,
+        /// Gmail or Drive scope to grant on top of the initial ones, e.g. with --incremental-auth (repeatable)
+        #[arg(long)]
+        scope: Vec<String>,
+    },
+    /// Print the stored token's state and account as JSON
+    Status,
+    /// Refresh the access token and save it
+    Refresh,
+    /// Revoke the token with Google and delete it
+    Revoke,
+    /// List the configured accounts and whether each has a token
+    Accounts,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -54,6 +121,10 @@ pub struct HttpConfig {
     #[arg(long, env = "PORT", default_value = "8080")]
     pub port: u16,
 
+    /// Listen on this Unix socket path instead of the TCP port
+    #[arg(long, env = "UNIX_SOCKET", conflicts_with = "tls_cert")]
+    pub unix_socket: Option<PathBuf>,
+
     /// OAuth redirect URL (defaults to http://localhost:{port}/callback)
     #[arg(long, env = "OAUTH_REDIRECT_URL")]
     pub oauth_redirect_url: Option<String>,
@@ -62,6 +133,38 @@ pub struct HttpConfig {
     #[arg(long, env = "METRICS_ROUTE", default_value = "/metrics")]
     pub metrics_route: String,
 
+    /// Bearer token required to scrape the metrics route
+    #[arg(long, env = "METRICS_BEARER_TOKEN")]
+    pub metrics_bearer_token: Option<String>,
+
+    /// Basic auth credentials required to scrape the metrics route, as user:password
+    #[arg(long, env = "METRICS_BASIC_AUTH")]
+    pub metrics_basic_auth: Option<String>,
+
+    /// Shared secret required on the MCP stream, SSE and tools endpoints
+    #[arg(long, env = "API_KEY")]
+    pub api_key: Option<String>,
+
+    /// Header carrying the API key; `Authorization` expects `Bearer <key>` (defaults to Authorization)
+    #[arg(long, env = "API_KEY_HEADER", default_value = "Authorization")]
+    pub api_key_header: String,
+
+    /// Largest request body accepted, in bytes (defaults to 4194304)
+    #[arg(long, env = "MAX_BODY_BYTES", default_value = "4194304")]
+    pub max_body_bytes: usize,
+
+    /// MCP and tools requests handled at once before new ones get 503, 0 is unlimited (defaults to 0)
+    #[arg(long, env = "MAX_IN_FLIGHT_REQUESTS", default_value = "0")]
+    pub max_in_flight_requests: usize,
+
+    /// Seconds to wait on shutdown for requests in flight to finish (defaults to 25)
+    #[arg(long, env = "SHUTDOWN_DRAIN_SECS", default_value = "25")]
+    pub shutdown_drain_secs: u64,
+
+    /// Seconds between checks of --config-file for changes to apply, 0 disables (defaults to 5)
+    #[arg(long, env = "CONFIG_RELOAD_INTERVAL_SECS", default_value = "5")]
+    pub config_reload_interval_secs: u64,
+
     /// HTTP stream route path (defaults to /stream)
     #[arg(long, env = "HTTP_STREAM_ROUTE", default_value = "/stream")]
     pub http_stream_route: String,
@@ -70,25 +173,78 @@ pub struct HttpConfig {
     #[arg(long, env = "TOOLS_ROUTE", default_value = "/tools")]
     pub tools_route: String,
 
+    /// Route serving the OpenAPI document of the tools endpoints (defaults to /openapi.json)
+    #[arg(long, env = "OPENAPI_ROUTE", default_value = "/openapi.json")]
+    pub openapi_route: String,
+
+    /// Route serving Swagger UI for the tools endpoints (defaults to /docs)
+    #[arg(long, env = "DOCS_ROUTE", default_value = "/docs")]
+    pub docs_route: String,
+
     /// SSE configuration
     #[command(flatten)]
     pub sse_config: SseConfig,
 
+    /// HTTPS configuration
+    #[command(flatten)]
+    pub tls_config: TlsConfig,
+
+    /// Per-client rate limiting of the MCP and tools endpoints
+    #[command(flatten)]
+    pub rate_limit: RateLimitConfig,
+
     /// Auth configuration
     #[command(flatten)]
     pub auth_config: AuthConfig,
 
-    /// Health check route path (defaults to /health)
-    #[arg(long, env = "HEALTH_ROUTE", default_value = "/health")]
+    /// Liveness check route path (defaults to /healthz)
+    #[arg(long, env = "HEALTH_ROUTE", default_value = "/healthz")]
     pub health_route: String,
 
+    /// Readiness check route path (defaults to /readyz)
+    #[arg(long, env = "READY_ROUTE", default_value = "/readyz")]
+    pub ready_route: String,
+
+    /// Server status route path (defaults to /status)
+    #[arg(long, env = "SERVER_STATUS_ROUTE", default_value = "/status")]
+    pub server_status_route: String,
+
     /// Root route path (defaults to /)
     #[arg(long, env = "ROOT_ROUTE", default_value = "/")]
     pub root_route: String,
+
+    /// Bind each MCP session to its own OAuth token instead of using the server's accounts
+    #[arg(long, env = "MULTI_USER")]
+    pub multi_user: bool,
+
+    /// Seconds a session's token is kept after its last tool call in multi-user mode (defaults to 3600)
+    #[arg(long, env = "SESSION_IDLE_TIMEOUT_SECS", default_value = "3600")]
+    pub session_idle_timeout_secs: u64,
+
+    /// Redis URL where MCP sessions are kept so every replica and restart can serve them, e.g.
+    /// redis://localhost:6379 (requires the `redis` feature; defaults to this process's memory)
+    #[arg(long, env = "SESSION_STORE")]
+    pub session_store: Option<String>,
+
+    /// Seconds an MCP session is kept in --session-store after its last request (defaults to 86400)
+    #[arg(long, env = "SESSION_STORE_TTL_SECS", default_value = "86400")]
+    pub session_store_ttl_secs: u64,
+
+    /// Call Gmail with the Google access token in a request's `Authorization: Bearer` header
+    #[arg(long, env = "TRUST_CALLER_TOKENS")]
+    pub trust_caller_tokens: bool,
 }
 
 #[derive(Args, Debug, Clone, Default)]
 pub struct Config {
+    /// TOML file setting any of these flags, by name; flags and environment variables take precedence
+    #[arg(long, env = "CONFIG_FILE")]
+    pub config_file: Option<PathBuf>,
+
+    /// Config file profile, under [profiles.<name>], whose settings override the rest of the file
+    #[arg(long, env = "CONFIG_PROFILE", requires = "config_file")]
+    pub profile: Option<String>,
+
     /// Gmail OAuth Client ID
     #[arg(long, env = "GMAIL_CLIENT_ID")]
     pub gmail_client_id: Option<String>,
@@ -100,15 +256,488 @@ pub struct Config {
     /// Application data directory (defaults to platform-specific location)
     #[arg(long, env = "APP_DATA_DIR")]
     pub app_data_dir: Option<PathBuf>,
+
+    /// Cache message metadata and bodies in a SQLite database in the app data directory
+    #[arg(long, env = "MESSAGE_CACHE")]
+    pub message_cache: bool,
+
+    /// Seconds between history syncs that invalidate changed cache entries, 0 disables (defaults to 300)
+    #[arg(long, env = "CACHE_SYNC_INTERVAL_SECS", default_value = "300")]
+    pub cache_sync_interval_secs: u64,
+
+    /// File of triage rules, TOML or JSON by extension (defaults to rules.toml in the app data
+    /// directory)
+    #[arg(long, env = "RULES_FILE")]
+    pub rules_file: Option<PathBuf>,
+
+    /// Seconds between polls of each triage rule's query, 0 disables (defaults to 60)
+    #[arg(long, env = "RULES_INTERVAL_SECS", default_value = "60")]
+    pub rules_interval_secs: u64,
+
+    /// File of scheduled jobs, TOML or JSON by extension (defaults to jobs.toml in the app data
+    /// directory)
+    #[arg(long, env = "JOBS_FILE")]
+    pub jobs_file: Option<PathBuf>,
+
+    /// URL the classifier hook POSTs each message to as JSON, answering with the labels and
+    /// scores to apply
+    #[arg(long, env = "CLASSIFIER_URL", conflicts_with = "classifier_command")]
+    pub classifier_url: Option<String>,
+
+    /// Shell command the classifier hook runs with each message as JSON on stdin, printing the
+    /// labels and scores to apply
+    #[arg(long, env = "CLASSIFIER_COMMAND")]
+    pub classifier_command: Option<String>,
+
+    /// Seconds the classifier hook may take per message, 0 disables (defaults to 10)
+    #[arg(long, env = "CLASSIFIER_TIMEOUT_SECS", default_value = "10")]
+    pub classifier_timeout_secs: u64,
+
+    /// clamd to scan attachments with before they're saved or their text returned: a Unix
+    /// socket path or host:port
+    #[arg(
+        long,
+        env = "CLAMD_ADDRESS",
+        conflicts_with = "attachment_scan_command"
+    )]
+    pub clamd_address: Option<String>,
+
+    /// Shell command scanning each attachment on stdin: exit 0 when clean, 1 when infected
+    /// (printing what was found), anything else on error
+    #[arg(long, env = "ATTACHMENT_SCAN_COMMAND")]
+    pub attachment_scan_command: Option<String>,
+
+    /// What to do with attachments the scan flags, or when it fails (defaults to block)
+    #[arg(
+        long,
+        env = "ATTACHMENT_SCAN_ACTION",
+        value_enum,
+        default_value = "block"
+    )]
+    pub attachment_scan_action: ScanAction,
+
+    /// Seconds an attachment scan may take, 0 disables (defaults to 30)
+    #[arg(long, env = "ATTACHMENT_SCAN_TIMEOUT_SECS", default_value = "30")]
+    pub attachment_scan_timeout_secs: u64,
+
+    /// Decrypt PGP/MIME messages and their attachments with the local gpg keyring and
+    /// gpg-agent (requires the `pgp` feature)
+    #[arg(long, env = "PGP_DECRYPT")]
+    pub pgp_decrypt: bool,
+
+    /// GnuPG home directory holding the decryption keys (defaults to gpg's own, ~/.gnupg)
+    #[arg(long, env = "GPG_HOMEDIR", requires = "pgp_decrypt")]
+    pub gpg_homedir: Option<PathBuf>,
+
+    /// Additional named Gmail accounts, each with its own token-<name>.json (comma-separated)
+    #[arg(long, env = "GMAIL_ACCOUNTS", value_delimiter = ',')]
+    pub accounts: Vec<String>,
+
+    /// Account used by tool calls that don't pass `account` (defaults to the token.json account)
+    #[arg(long, env = "GMAIL_DEFAULT_ACCOUNT")]
+    pub default_account: Option<String>,
+
+    /// Seconds before expiry to refresh the access token in the background, 0 disables (defaults to 300)
+    #[arg(long, env = "TOKEN_REFRESH_LEAD_SECS", default_value = "300")]
+    pub token_refresh_lead_secs: u64,
+
+    /// Append a JSONL audit entry for every tool call to audit.jsonl in the app data directory
+    #[arg(long, env = "AUDIT_LOG")]
+    pub audit_log: bool,
+
+    /// Refuse the tools that change the mailbox: create_draft, forward_email, send_draft and
+    /// snooze_thread
+    #[arg(long, env = "READ_ONLY")]
+    pub read_only: bool,
+
+    /// Have create_draft, forward_email and send_draft return the message they would send instead
+    #[arg(long, env = "DRY_RUN")]
+    pub dry_run: bool,
+
+    /// Queue forward_email and send_draft in outbox.sqlite3 in the app data directory, and retry
+    /// sends that fail with a transient error in the background
+    #[arg(long, env = "OUTBOX")]
+    pub outbox: bool,
+
+    /// Background retries of a queued send before it's marked failed (defaults to 10)
+    #[arg(long, env = "OUTBOX_MAX_RETRIES", default_value = "10")]
+    pub outbox_max_retries: u32,
+
+    /// Largest tool response in bytes; longer ones are cut down by their truncation strategy
+    /// (defaults to unlimited)
+    #[arg(long, env = "MAX_TOOL_RESPONSE_BYTES")]
+    pub max_tool_response_bytes: Option<usize>,
+
+    /// How responses over --max-tool-response-bytes are cut down: oldest-first, drop-html or
+    /// headers-only, for every tool or as <tool>=<strategy> (comma-separated, defaults to
+    /// oldest-first)
+    #[arg(
+        long,
+        env = "TRUNCATION_STRATEGY",
+        value_delimiter = ',',
+        requires = "max_tool_response_bytes"
+    )]
+    pub truncation_strategy: Vec<String>,
+
+    /// Serve a bundled synthetic mailbox through every tool instead of Gmail, with no Google
+    /// credentials or login, to try the server and client configs out
+    #[arg(long, env = "DEMO", conflicts_with_all = ["gmail_api_url", "gmail_fixtures"])]
+    pub demo: bool,
+
+    /// Proxy URL for outbound Google traffic (defaults to HTTP_PROXY/HTTPS_PROXY, honoring NO_PROXY)
+    #[arg(long, env = "GMAIL_PROXY")]
+    pub proxy: Option<String>,
+
+    /// Webhook URL notified with a JSON payload when token refresh keeps failing
+    #[arg(long, env = "REFRESH_FAILURE_WEBHOOK_URL")]
+    pub refresh_failure_webhook_url: Option<String>,
+
+    /// Consecutive token refresh failures before the webhook fires (defaults to 3)
+    #[arg(long, env = "REFRESH_FAILURE_ALERT_THRESHOLD", default_value = "3")]
+    pub refresh_failure_alert_threshold: u32,
+
+    /// URLs POSTed a JSON payload for each webhook event (comma-separated)
+    #[arg(long, env = "WEBHOOK_URLS", value_delimiter = ',')]
+    pub webhook_urls: Vec<String>,
+
+    /// Secret webhook payloads are signed with, as an HMAC-SHA256 in the X-Webhook-Signature header
+    #[arg(long, env = "WEBHOOK_SECRET", requires = "webhook_urls")]
+    pub webhook_secret: Option<String>,
+
+    /// Webhook events to send, comma-separated (defaults to all)
+    #[arg(long, env = "WEBHOOK_EVENTS", value_enum, value_delimiter = ',')]
+    pub webhook_events: Vec<crate::webhooks::WebhookEvent>,
+
+    /// Seconds between inbox polls for new_mail webhook events, 0 disables (defaults to 60)
+    #[arg(long, env = "WEBHOOK_POLL_INTERVAL_SECS", default_value = "60")]
+    pub webhook_poll_interval_secs: u64,
+
+    /// Log in with read-only access and ask for compose/send access when a write tool first needs it
+    #[arg(long, env = "INCREMENTAL_AUTH")]
+    pub incremental_auth: bool,
+
+    /// OAuth token persistence configuration
+    #[command(flatten)]
+    pub token_storage: TokenStoreConfig,
+
+    /// Secret manager references for the OAuth client credentials
+    #[command(flatten)]
+    pub secret_manager: SecretManagerConfig,
+
+    /// Client credentials loaded from the secret manager, shared by every clone of the config
+    #[arg(skip)]
+    pub client_credentials: Arc<ClientCredentials>,
+
+    /// Gmail API client configuration
+    #[command(flatten)]
+    pub gmail_api: GmailApiConfig,
+
+    /// Logging and trace export configuration
+    #[command(flatten)]
+    pub telemetry: TelemetryConfig,
 }
 
-#[derive(Parser, Debug, Clone)]
+#[derive(Args, Debug, Clone)]
+pub struct GmailApiConfig {
+    /// Maximum retries for rate-limited or failed Gmail API calls (defaults to 3)
+    #[arg(long, env = "GMAIL_MAX_RETRIES", default_value = "3")]
+    pub gmail_max_retries: u32,
+
+    /// Initial retry backoff in milliseconds (defaults to 500)
+    #[arg(long, env = "GMAIL_RETRY_BASE_DELAY_MS", default_value = "500")]
+    pub gmail_retry_base_delay_ms: u64,
+
+    /// Maximum retry backoff in milliseconds, also caps Retry-After (defaults to 30000)
+    #[arg(long, env = "GMAIL_RETRY_MAX_DELAY_MS", default_value = "30000")]
+    pub gmail_retry_max_delay_ms: u64,
+
+    /// Calls packed into one Gmail batch request, 1 disables batching (defaults to 50, max 100)
+    #[arg(long, env = "GMAIL_BATCH_SIZE", default_value = "50")]
+    pub gmail_batch_size: usize,
+
+    /// Maximum concurrent Gmail API requests per tool call (defaults to 8)
+    #[arg(long, env = "GMAIL_FETCH_CONCURRENCY", default_value = "8")]
+    pub gmail_fetch_concurrency: usize,
+
+    /// Total timeout in seconds for each Gmail API request, 0 disables (defaults to 60)
+    #[arg(long, env = "GMAIL_TIMEOUT_SECS", default_value = "60")]
+    pub gmail_timeout_secs: u64,
+
+    /// Timeout in seconds for establishing a connection to Gmail, 0 disables (defaults to 10)
+    #[arg(long, env = "GMAIL_CONNECT_TIMEOUT_SECS", default_value = "10")]
+    pub gmail_connect_timeout_secs: u64,
+
+    /// Root URL Gmail API calls are sent to, e.g. a proxy or a fake server for testing, with
+    /// Drive API calls under its /drive path (defaults to https://gmail.googleapis.com)
+    #[arg(long, env = "GMAIL_API_URL")]
+    pub gmail_api_url: Option<String>,
+
+    /// Mailbox Gmail API calls act on: `me` for the token's own, or a user's address for a
+    /// service account with domain-wide delegation (defaults to me)
+    #[arg(long, env = "GMAIL_USER_ID", default_value = "me")]
+    pub gmail_user_id: String,
+
+    /// Record sanitized Gmail API responses to fixture files, or replay them instead of calling
+    /// Gmail, e.g. to reproduce a bug report or run offline
+    #[arg(long, env = "GMAIL_FIXTURES", value_enum)]
+    pub gmail_fixtures: Option<FixtureMode>,
+
+    /// Directory of fixture files for --gmail-fixtures (defaults to `fixtures` in the app data
+    /// directory)
+    #[arg(long, env = "GMAIL_FIXTURES_DIR")]
+    pub gmail_fixtures_dir: Option<PathBuf>,
+}
+
+/// What --gmail-fixtures does with Gmail API responses
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixtureMode {
+    /// Call Gmail and save each response
+    Record,
+    /// Answer each call from its saved response, without calling Gmail
+    Replay,
+}
+
+/// What --attachment-scan-action does with a flagged attachment
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ScanAction {
+    /// Refuse the attachment, and any attachment the scan fails on
+    #[default]
+    Block,
+    /// Return the attachment with the scan's findings
+    Warn,
+}
+
+impl Default for GmailApiConfig {
+    fn default() -> Self {
+        Self {
+            gmail_max_retries: 3,
+            gmail_retry_base_delay_ms: 500,
+            gmail_retry_max_delay_ms: 30000,
+            gmail_batch_size: 50,
+            gmail_fetch_concurrency: 8,
+            gmail_timeout_secs: 60,
+            gmail_connect_timeout_secs: 10,
+            gmail_api_url: None,
+            gmail_user_id: "me".to_string(),
+            gmail_fixtures: None,
+            gmail_fixtures_dir: None,
+        }
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct SecretManagerConfig {
+    /// Load the OAuth client ID from gcp-sm://projects/<p>/secrets/<s>[/versions/<v>] or
+    /// vault://<mount>/<path>#<key> (requires the `secret-manager` feature)
+    #[arg(long, env = "GMAIL_CLIENT_ID_FROM")]
+    pub gmail_client_id_from: Option<String>,
+
+    /// Load the OAuth client secret from a secret reference, like --gmail-client-id-from
+    #[arg(long, env = "GMAIL_CLIENT_SECRET_FROM")]
+    pub gmail_client_secret_from: Option<String>,
+
+    /// Access token for GCP Secret Manager (defaults to the GCP metadata server's service account)
+    #[arg(long, env = "GCP_ACCESS_TOKEN")]
+    pub gcp_access_token: Option<String>,
+
+    /// Vault server address (defaults to http://127.0.0.1:8200)
+    #[arg(long, env = "VAULT_ADDR", default_value = "http://127.0.0.1:8200")]
+    pub vault_addr: String,
+
+    /// Vault token used to read secrets
+    #[arg(long, env = "VAULT_TOKEN")]
+    pub vault_token: Option<String>,
+
+    /// Seconds between checks for rotated client credentials, 0 disables (defaults to 3600)
+    #[arg(long, env = "SECRET_REFRESH_INTERVAL_SECS", default_value = "3600")]
+    pub secret_refresh_interval_secs: u64,
+}
+
+impl Default for SecretManagerConfig {
+    fn default() -> Self {
+        Self {
+            gmail_client_id_from: None,
+            gmail_client_secret_from: None,
+            gcp_access_token: None,
+            vault_addr: "http://127.0.0.1:8200".to_string(),
+            vault_token: None,
+            secret_refresh_interval_secs: 3600,
+        }
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TokenStoreConfig {
+    /// Where OAuth tokens are persisted (defaults to file)
+    #[arg(long, env = "TOKEN_STORE", value_enum, default_value = "file")]
+    pub token_store: TokenStoreKind,
+
+    /// Redis URL for `--token-store redis`, e.g. redis://localhost:6379
+    #[arg(long, env = "REDIS_URL")]
+    pub redis_url: Option<String>,
+
+    /// Prefix for the server's Redis keys (defaults to gmail-mcp-server:)
+    #[arg(long, env = "REDIS_KEY_PREFIX", default_value = "gmail-mcp-server:")]
+    pub redis_key_prefix: String,
+
+    /// Environment variable holding the token JSON for `--token-store env` (defaults to GMAIL_TOKEN)
+    #[arg(long, env = "TOKEN_ENV_VAR", default_value = "GMAIL_TOKEN")]
+    pub token_env_var: String,
+
+    /// Load token files other users can read instead of refusing them (Unix only)
+    #[arg(long, env = "ALLOW_INSECURE_TOKEN_FILE")]
+    pub allow_insecure_token_file: bool,
+
+    /// Service name of the keychain entries for `--token-store keyring` (defaults to gmail-mcp-server)
+    #[arg(long, env = "KEYRING_SERVICE", default_value = "gmail-mcp-server")]
+    pub keyring_service: String,
+}
+
+impl Default for TokenStoreConfig {
+    fn default() -> Self {
+        Self {
+            token_store: TokenStoreKind::File,
+            redis_url: None,
+            redis_key_prefix: "gmail-mcp-server:".to_string(),
+            token_env_var: "GMAIL_TOKEN".to_string(),
+            allow_insecure_token_file: false,
+            keyring_service: "gmail-mcp-server".to_string(),
+        }
+    }
+}
+
+/// Backend that persists OAuth tokens
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TokenStoreKind {
+    /// token.json and token-<name>.json in the app data directory
+    #[default]
+    File,
+    /// Kept in memory only, so every restart needs a new login
+    Memory,
+    /// A Redis key per account, shared by every replica (requires the `redis` feature)
+    Redis,
+    /// Read-only token JSON from an environment variable per account
+    Env,
+    /// The OS keychain: macOS Keychain, Windows Credential Manager or Secret Service (requires
+    /// the `keyring` feature)
+    Keyring,
+}
+
+/// Output format for log lines
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable text
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// When the log file is rotated
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogRotation {
+    /// A new file every hour
+    Hourly,
+    /// A new file every day
+    #[default]
+    Daily,
+    /// A new file once the current one reaches --log-max-size-mb
+    Size,
+    /// Never rotate
+    Never,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TelemetryConfig {
+    /// Log filter such as debug or gmail_mcp_server=trace (defaults to RUST_LOG, then info)
+    #[arg(long, env = "LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Log output format
+    #[arg(long, env = "LOG_FORMAT", value_enum, default_value = "text")]
+    pub log_format: LogFormat,
+
+    /// Also write logs to this file
+    #[arg(long, env = "LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// Log file rotation policy
+    #[arg(long, env = "LOG_ROTATION", value_enum, default_value = "daily")]
+    pub log_rotation: LogRotation,
+
+    /// Log file size in megabytes that triggers rotation with --log-rotation size (defaults to 100)
+    #[arg(long, env = "LOG_MAX_SIZE_MB", default_value = "100")]
+    pub log_max_size_mb: u64,
+
+    /// Rotated log files to keep (defaults to 7)
+    #[arg(long, env = "LOG_MAX_FILES", default_value = "7")]
+    pub log_max_files: usize,
+
+    /// Log email addresses, credentials and message bodies without masking them
+    #[arg(long, env = "LOG_UNREDACTED")]
+    pub log_unredacted: bool,
+
+    /// OTLP/HTTP collector URL for trace export, e.g. http://localhost:4318 (requires the `otel` feature)
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Service name reported with exported traces (defaults to gmail-mcp-server)
+    #[arg(long, env = "OTEL_SERVICE_NAME", default_value = "gmail-mcp-server")]
+    pub otel_service_name: String,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            log_level: None,
+            log_format: LogFormat::Text,
+            log_file: None,
+            log_rotation: LogRotation::Daily,
+            log_max_size_mb: 100,
+            log_max_files: 7,
+            log_unredacted: false,
+            otlp_endpoint: None,
+            otel_service_name: "gmail-mcp-server".to_string(),
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone, serde::Serialize)]
+#[serde(tag = "tool", rename_all = "snake_case")]
 pub enum ToolsCmd {
     /// Search Gmail threads
     SearchThreads {
         query: String,
         #[arg(long, default_value = "10")]
         max_results: i64,
+        /// Partial response selector (e.g., "threads(id,snippet)")
+        #[arg(long)]
+        fields: Option<String>,
+        /// Only messages from the last this many days
+        #[arg(long, conflicts_with = "start_date")]
+        last_n_days: Option<u32>,
+        /// Only messages from this day on, as YYYY-MM-DD or an RFC 3339 time
+        #[arg(long)]
+        start_date: Option<String>,
+        /// Only messages up to and including this day, as YYYY-MM-DD or an RFC 3339 time
+        #[arg(long)]
+        end_date: Option<String>,
+        /// UTC offset such as +02:00 whose midnights the dates mean (defaults to local time)
+        #[arg(long, allow_hyphen_values = true)]
+        utc_offset: Option<String>,
+        /// Only messages in this inbox tab
+        #[arg(long, value_enum)]
+        category: Option<Category>,
+        /// Only messages Gmail marked important (true), or only those it didn't (false)
+        #[arg(long)]
+        is_important: Option<bool>,
+        /// Only threads with a message whose text body matches this regular expression
+        #[arg(long)]
+        body_regex: Option<String>,
+        /// Only threads with a message having a "Name: value" header line matching this regular
+        /// expression
+        #[arg(long)]
+        header_regex: Option<String>,
     },
     /// Create a Gmail draft
     CreateDraft {
@@ -117,14 +746,29 @@ pub enum ToolsCmd {
         body: String,
         #[arg(long)]
         thread_id: Option<String>,
+        /// Leave the mailbox's own address out of the recipients, as reply-all does
+        #[arg(long)]
+        exclude_own_address: bool,
     },
     /// Extract attachment text by filename
+    #[serde(rename = "extract_attachment_by_filename")]
     ExtractAttachment {
         message_id: String,
         filename: String,
+        /// Return only the first this many characters of the text
+        #[arg(long)]
+        preview_chars: Option<usize>,
     },
     /// Fetch email bodies for threads
-    FetchEmailBodies { thread_ids: Vec<String> },
+    FetchEmailBodies {
+        thread_ids: Vec<String>,
+        /// Message format to request
+        #[arg(long, value_enum, default_value = "full")]
+        format: MessageFormat,
+        /// Partial response selector applied to each message
+        #[arg(long)]
+        fields: Option<String>,
+    },
     /// Download attachment
     DownloadAttachment {
         message_id: String,
@@ -138,9 +782,271 @@ pub enum ToolsCmd {
         to: String,
         subject: String,
         body: String,
+        /// Queue the forward in the outbox until the next login when the account needs one
+        #[arg(long)]
+        enqueue_if_unauthenticated: bool,
     },
     /// Send draft
-    SendDraft { draft_id: String },
+    SendDraft {
+        draft_id: String,
+        /// Queue the send in the outbox until the next login when the account needs one
+        #[arg(long)]
+        enqueue_if_unauthenticated: bool,
+    },
+    /// Find bounces for a sent message or recipient, with each failure's status and reason
+    FindBounces {
+        /// Gmail message ID of the sent message
+        #[arg(long)]
+        message_id: Option<String>,
+        /// Recipient address the bounces are for
+        #[arg(long)]
+        recipient: Option<String>,
+        /// Bounce messages to examine
+        #[arg(long, default_value = "10")]
+        max_results: u32,
+    },
+    /// Get the whole conversation a message belongs to, across the threads Gmail split it into
+    GetConversation {
+        message_id: String,
+        /// Message format to request
+        #[arg(long, value_enum, default_value = "full")]
+        format: MessageFormat,
+        /// Threads to gather the conversation from
+        #[arg(long, default_value = "10")]
+        max_threads: usize,
+    },
+    /// Save a thread as a self-contained HTML file or PDF
+    ExportThread {
+        thread_id: String,
+        /// File type to write
+        #[arg(long, value_enum, default_value = "html")]
+        format: ExportFormat,
+        #[arg(long)]
+        download_dir: Option<String>,
+    },
+    /// Get a message as a markdown document
+    EmailToMarkdown { message_id: String },
+    /// List a thread's attachments with duplicates collapsed
+    ListThreadAttachments {
+        thread_id: String,
+        /// Extract the text of each unique PDF, DOCX or TXT file
+        #[arg(long)]
+        extract_text: bool,
+        /// Return only the first this many characters of each text
+        #[arg(long, requires = "extract_text")]
+        preview_chars: Option<usize>,
+    },
+    /// Extract the text of the Google Docs, Sheets and Slides linked from a message
+    ExtractDriveAttachments {
+        message_id: String,
+        /// Return only the first this many characters of each text
+        #[arg(long)]
+        preview_chars: Option<usize>,
+    },
+    /// List new messages, deleted messages and label changes in a thread since a historyId
+    GetThreadChanges {
+        thread_id: String,
+        /// historyId the thread was last seen at
+        history_id: String,
+        /// Format of new messages
+        #[arg(long, value_enum, default_value = "full")]
+        format: MessageFormat,
+    },
+    /// Run the classifier hook on a message and print its labels and scores
+    ClassifyMessage { message_id: String },
+    /// Score how likely a message is phishing or spam, with the reasons
+    ScorePhishingRisk { message_id: String },
+    /// Take a thread out of the inbox until a given time
+    SnoozeThread {
+        thread_id: String,
+        /// RFC 3339 time or duration from now (e.g., 90m, 4h, 2d, 1w) to put it back
+        until: String,
+        /// Mark the thread unread when it comes back
+        #[arg(long)]
+        mark_unread: bool,
+    },
+    /// List snoozed threads, soonest to wake first
+    ListSnoozed,
+    /// List the triage rules
+    ListRules,
+    /// Add a triage rule acting on new mail matching a search, or replace the one of its name
+    AddRule {
+        name: String,
+        /// Gmail search query new messages must match
+        query: String,
+        /// Label to apply, created if missing (repeatable)
+        #[arg(long)]
+        label: Vec<String>,
+        /// Take matching messages out of the inbox
+        #[arg(long)]
+        archive: bool,
+        /// Forward matching messages to these comma-separated recipients
+        #[arg(long)]
+        forward: Option<String>,
+        /// POST a JSON summary of matching messages to this URL
+        #[arg(long)]
+        notify: Option<String>,
+        /// Apply the labels the classifier hook returns, before notifying
+        #[arg(long)]
+        classify: bool,
+    },
+    /// Remove a triage rule
+    RemoveRule { name: String },
+    /// List the latest runs of the scheduled jobs, newest first
+    ListJobRuns {
+        /// Only this job's runs
+        #[arg(long)]
+        job: Option<String>,
+        /// Maximum number of runs
+        #[arg(long, default_value = "20")]
+        limit: u32,
+    },
+    /// List the sends queued in the outbox, oldest first
+    ListOutbox,
+    /// Cancel a queued, failed or unconfirmed send in the outbox
+    CancelOutboxMessage { outbox_id: i64 },
+    /// List the saved searches
+    ListSavedSearches,
+    /// Save a Gmail query under a name
+    SaveSearch {
+        name: String,
+        query: String,
+        /// What the search is for
+        #[arg(long)]
+        description: Option<String>,
+    },
+    /// Change the query or description of a saved search
+    UpdateSavedSearch {
+        name: String,
+        /// New Gmail search query
+        #[arg(long)]
+        query: Option<String>,
+        /// New description, or "" to remove it
+        #[arg(long)]
+        description: Option<String>,
+    },
+    /// Delete a saved search
+    DeleteSavedSearch { name: String },
+    /// Print a line for each new message matching a search as it arrives, until Ctrl+C
+    Watch {
+        /// Gmail search query to watch
+        #[arg(long, default_value = "is:unread")]
+        query: String,
+        /// Seconds between polls
+        #[arg(long, default_value = "30", value_parser = clap::value_parser!(u64).range(1..))]
+        interval_secs: u64,
+        /// Newest matching messages compared on each poll
+        #[arg(long, default_value = "100", value_parser = clap::value_parser!(u32).range(1..=500))]
+        max_results: u32,
+        /// Print each message as a JSON object instead of tab-separated fields
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct BenchConfig {
+    /// Tool to call
+    #[arg(long, value_enum, default_value = "search-threads")]
+    pub tool: BenchTool,
+
+    /// Search query, also choosing the threads fetch-email-bodies fetches
+    #[arg(long, default_value = "in:inbox")]
+    pub query: String,
+
+    /// Threads each call searches for or fetches
+    #[arg(long, default_value = "10")]
+    pub max_results: i64,
+
+    /// Total calls to make
+    #[arg(long, default_value = "100", value_parser = clap::value_parser!(u32).range(1..))]
+    pub requests: u32,
+
+    /// Calls in flight at once
+    #[arg(long, default_value = "8", value_parser = clap::value_parser!(u32).range(1..))]
+    pub concurrency: u32,
+
+    /// Call a fake Gmail API started in this process instead of Gmail, needing no login
+    #[arg(long)]
+    pub mock: bool,
+
+    /// Milliseconds the fake Gmail API waits before each response
+    #[arg(long, default_value = "0", requires = "mock")]
+    pub mock_latency_ms: u64,
+
+    /// Account to call the tool as, from --accounts (defaults to --default-account)
+    #[arg(long, conflicts_with = "mock")]
+    pub account: Option<String>,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            tool: BenchTool::SearchThreads,
+            query: "in:inbox".to_string(),
+            max_results: 10,
+            requests: 100,
+            concurrency: 8,
+            mock: false,
+            mock_latency_ms: 0,
+            account: None,
+        }
+    }
+}
+
+/// Tools the bench command can call
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BenchTool {
+    SearchThreads,
+    FetchEmailBodies,
+}
+
+#[derive(Args, Debug, Clone, Default)]
+pub struct TlsConfig {
+    /// PEM certificate chain to serve HTTPS with (requires the `tls` feature)
+    #[arg(long, env = "TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, env = "TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM CA bundle; MCP and tools requests must then present a client certificate it signed
+    #[arg(long, env = "TLS_CLIENT_CA", requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    /// Whether the listener serves HTTPS
+    pub fn enabled(&self) -> bool {
+        self.tls_cert.is_some()
+    }
+}
+
+#[derive(Args, Debug, Clone, Default, PartialEq)]
+pub struct RateLimitConfig {
+    /// Requests per minute each client may make to the MCP and tools endpoints, 0 disables (defaults to 0)
+    #[arg(long, env = "RATE_LIMIT_PER_MINUTE", default_value = "0")]
+    pub rate_limit_per_minute: u32,
+
+    /// Requests a client may make at once before the rate applies (defaults to the per-minute limit)
+    #[arg(long, env = "RATE_LIMIT_BURST")]
+    pub rate_limit_burst: Option<u32>,
+
+    /// What identifies a client for rate limiting (defaults to ip)
+    #[arg(long, env = "RATE_LIMIT_BY", value_enum, default_value = "ip")]
+    pub rate_limit_by: RateLimitKey,
+}
+
+/// What the rate limiter tells clients apart by
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RateLimitKey {
+    /// The client's IP address
+    #[default]
+    Ip,
+    /// The value of the --api-key-header when it carries the --api-key, falling back to the IP
+    /// address otherwise
+    ApiKey,
 }
 
 #[derive(Args, Debug, Clone)]
@@ -179,26 +1085,53 @@ impl Default for HttpConfig {
     fn default() -> Self {
         Self {
             port: 8080,
+            unix_socket: None,
             oauth_redirect_url: None,
             metrics_route: "/metrics".to_string(),
+            metrics_bearer_token: None,
+            metrics_basic_auth: None,
+            api_key: None,
+            api_key_header: "Authorization".to_string(),
+            max_body_bytes: 4 * 1024 * 1024,
+            max_in_flight_requests: 0,
+            shutdown_drain_secs: 25,
+            config_reload_interval_secs: 5,
             http_stream_route: "/stream".to_string(),
             tools_route: "/tools".to_string(),
+            openapi_route: "/openapi.json".to_string(),
+            docs_route: "/docs".to_string(),
             sse_config: SseConfig::default(),
+            tls_config: TlsConfig::default(),
+            rate_limit: RateLimitConfig::default(),
             auth_config: AuthConfig::default(),
-            health_route: "/health".to_string(),
+            health_route: "/healthz".to_string(),
+            ready_route: "/readyz".to_string(),
+            server_status_route: "/status".to_string(),
             root_route: "/".to_string(),
+            multi_user: false,
+            session_idle_timeout_secs: 3600,
+            session_store: None,
+            session_store_ttl_secs: 86400,
+            trust_caller_tokens: false,
         }
     }
 }
 
 impl HttpConfig {
     pub fn oauth_redirect_url(&self) -> String {
-        self.oauth_redirect_url.clone().unwrap_or_else(|| {
-            format!(
-                "http://localhost:{}{}",
-                self.port, self.auth_config.callback_route
-            )
-        })
+        self.oauth_redirect_url
+            .clone()
+            .unwrap_or_else(|| format!("{}{}", self.local_url(), self.auth_config.callback_route))
+    }
+
+    /// The server's address on this machine, e.g. `http://localhost:8080`
+    pub fn local_url(&self) -> String {
+        let scheme = if self.tls_config.enabled() {
+            "https"
+        } else {
+            "http"
+        };
+        format!("{scheme}://localhost:{}", self.port)
     }
 
     pub fn metrics_route(&self) -> &str {
@@ -213,6 +1146,14 @@ impl HttpConfig {
         &self.tools_route
     }
 
+    pub fn openapi_route(&self) -> &str {
+        &self.openapi_route
+    }
+
+    pub fn docs_route(&self) -> &str {
+        &self.docs_route
+    }
+
     pub fn sse_route(&self) -> &str {
         self.sse_config.sse_route()
     }
@@ -234,6 +1175,14 @@ impl HttpConfig {
         &self.auth_config.refresh_route
     }
 
+    pub fn auth_status_route(&self) -> &str {
+        &self.auth_config.status_route
+    }
+
+    pub fn auth_accounts_route(&self) -> &str {
+        &self.auth_config.accounts_route
+    }
+
     pub fn callback_route(&self) -> &str {
         &self.auth_config.callback_route
     }
@@ -242,6 +1191,14 @@ impl HttpConfig {
         &self.health_route
     }
 
+    pub fn ready_route(&self) -> &str {
+        &self.ready_route
+    }
+
+    pub fn server_status_route(&self) -> &str {
+        &self.server_status_route
+    }
+
     pub fn root_route(&self) -> &str {
         &self.root_route
     }
@@ -265,6 +1222,20 @@ impl Config {
                 .unwrap_or_else(|_| PathBuf::from(".").join("gmail-mcp-server-data"))
         }
     }
+
+    /// The triage rules file: `--rules-file`, or rules.toml in the app data directory
+    pub fn rules_file(&self) -> PathBuf {
+        self.rules_file
+            .clone()
+            .unwrap_or_else(|| self.app_data_dir().join(crate::rules::RULES_FILE))
+    }
+
+    /// The scheduled jobs file: `--jobs-file`, or jobs.toml in the app data directory
+    pub fn jobs_file(&self) -> PathBuf {
+        self.jobs_file
+            .clone()
+            .unwrap_or_else(|| self.app_data_dir().join(crate::jobs::JOBS_FILE))
+    }
 }
 
 #[cfg(test)]
@@ -307,6 +1278,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_oauth_redirect_url_default_with_tls() {
+        let http_config = HttpConfig {
+            port: 8443,
+            tls_config: TlsConfig {
+                tls_cert: Some(PathBuf::from("cert.pem")),
+                tls_key: Some(PathBuf::from("key.pem")),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(http_config.local_url(), "https://localhost:8443");
+        assert_eq!(
+            http_config.oauth_redirect_url(),
+            "https://localhost:8443/auth/callback"
+        );
+    }
+
     #[test]
     fn test_metrics_route_uses_configured_value() {
         let http_config = HttpConfig {
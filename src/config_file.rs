@@ -0,0 +1,269 @@
+use anyhow::{Context, Result};
+use clap::{Command, CommandFactory, FromArgMatches};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+use crate::config::Cli;
+
+/// Subcommands whose flags can also be set in the config file
+const HTTP_COMMANDS: &[&str] = &["http", "check-config"];
+
+/// Table of named profiles, each a table of settings
+const PROFILES: &str = "profiles";
+
+/// Parse the command line, taking defaults for flags it leaves unset from `--config-file`.
+/// Flags win over environment variables, which win over the file
+pub fn parse_cli() -> Result<Cli> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let settings = match config_file_path(&args) {
+        Some(path) => with_profile(read(&path)?, profile(&args).as_deref())
+            .with_context(|| format!("Invalid config file {}", path.display()))?,
+        None => Table::new(),
+    };
+    let matches = command(&settings)?.get_matches_from(args);
+    Ok(Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()))
+}
+
+/// `--config-file` from the arguments or `CONFIG_FILE`, needed before the rest are parsed
+fn config_file_path(args: &[OsString]) -> Option<PathBuf> {
+    flag_value(args, "--config-file", "CONFIG_FILE").map(PathBuf::from)
+}
+
+/// `--profile` from the arguments or `CONFIG_PROFILE`, needed to pick the file's settings
+pub fn profile(args: &[OsString]) -> Option<String> {
+    flag_value(args, "--profile", "CONFIG_PROFILE").map(|name| name.to_string_lossy().into_owned())
+}
+
+fn flag_value(args: &[OsString], flag: &str, env: &str) -> Option<OsString> {
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            break;
+        }
+        if arg == flag {
+            return args.next().cloned();
+        }
+        if let Some(value) = arg
+            .strip_prefix(flag)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            return Some(value.into());
+        }
+    }
+    std::env::var_os(env)
+}
+
+/// The settings in a TOML config file
+pub fn read(path: &Path) -> Result<Table> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    contents
+        .parse()
+        .with_context(|| format!("Invalid config file {}", path.display()))
+}
+
+/// The file's settings with those of `profile` in place of the ones it overrides
+pub fn with_profile(mut settings: Table, profile: Option<&str>) -> Result<Table> {
+    let profiles = match settings.remove(PROFILES) {
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => anyhow::bail!("'{}' must be a table of profiles", PROFILES),
+        None => Table::new(),
+    };
+    let Some(name) = profile else {
+        return Ok(settings);
+    };
+    let Some(Value::Table(overrides)) = profiles.get(name) else {
+        anyhow::bail!("No profile '{}' under [{}]", name, PROFILES);
+    };
+    for (key, value) in overrides {
+        // The profile may spell a key differently from the top level
+        let id = key.replace('-', "_");
+        settings.retain(|other, _| other.replace('-', "_") != id);
+        settings.insert(key.clone(), value.clone());
+    }
+    Ok(settings)
+}
+
+/// The CLI with each setting as the default of the flag it names
+pub fn command(settings: &Table) -> Result<Command> {
+    let mut command = Cli::command();
+    for (key, value) in settings {
+        let id = key.replace('-', "_");
+        let values = setting_values(key, value)?;
+        if settable(&command, &id) {
+            command = command.mut_arg(&id, |arg| arg.default_values(values));
+        } else if command
+            .find_subcommand(HTTP_COMMANDS[0])
+            .is_some_and(|http| settable(http, &id))
+        {
+            for name in HTTP_COMMANDS {
+                command = command.mut_subcommand(name, |http| {
+                    http.mut_arg(&id, |arg| arg.default_values(values.clone()))
+                });
+            }
+        } else {
+            anyhow::bail!("Unknown setting '{}' in config file", key);
+        }
+    }
+    Ok(command)
+}
+
+/// Flags that also read an environment variable are the server's configuration, as opposed to
+/// a subcommand's arguments
+fn settable(command: &Command, id: &str) -> bool {
+    id != "config_file"
+        && id != "profile"
+        && command
+            .get_arguments()
+            .any(|arg| arg.get_id() == id && arg.get_env().is_some())
+}
+
+fn setting_values(key: &str, value: &Value) -> Result<Vec<String>> {
+    match value {
+        Value::String(value) => Ok(vec![value.clone()]),
+        Value::Integer(value) => Ok(vec![value.to_string()]),
+        Value::Float(value) => Ok(vec![value.to_string()]),
+        Value::Boolean(value) => Ok(vec![value.to_string()]),
+        Value::Datetime(value) => Ok(vec![value.to_string()]),
+        Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                Value::Array(_) | Value::Table(_) => {
+                    anyhow::bail!("Setting '{}' must be a list of values", key)
+                }
+                value => Ok(setting_values(key, value)?.remove(0)),
+            })
+            .collect(),
+        Value::Table(_) => anyhow::bail!("Setting '{}' must be a value, not a table", key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Commands;
+
+    fn parse(settings: &str, args: &[&str]) -> Result<Cli> {
+        let settings: Table = settings.parse()?;
+        let matches = command(&settings)?.try_get_matches_from(args)?;
+        Ok(Cli::from_arg_matches(&matches)?)
+    }
+
+    #[test]
+    fn test_settings_become_defaults() {
+        let settings = r#"
+            app-data-dir = "/var/lib/gmail-mcp"
+            accounts = ["work", "personal"]
+            audit_log = true
+            port = 9000
+            rate_limit_per_minute = 120
+            login_route = "/login"
+        "#;
+        let cli = parse(settings, &["gmail-mcp-server", "http"]).unwrap();
+        assert_eq!(
+            cli.config.app_data_dir,
+            Some(PathBuf::from("/var/lib/gmail-mcp"))
+        );
+        assert_eq!(cli.config.accounts, ["work", "personal"]);
+        assert!(cli.config.audit_log);
+        let Commands::Http(http_config) = cli.command else {
+            panic!("expected the http command");
+        };
+        assert_eq!(http_config.port, 9000);
+        assert_eq!(http_config.rate_limit.rate_limit_per_minute, 120);
+        assert_eq!(http_config.auth_config.login_route, "/login");
+
+        // Flags override the file
+        let cli = parse(settings, &["gmail-mcp-server", "http", "--port", "9100"]).unwrap();
+        let Commands::Http(http_config) = cli.command else {
+            panic!("expected the http command");
+        };
+        assert_eq!(http_config.port, 9100);
+
+        // check-config sees the same settings as the server
+        let cli = parse(settings, &["gmail-mcp-server", "check-config"]).unwrap();
+        let Commands::CheckConfig(http_config) = cli.command else {
+            panic!("expected the check-config command");
+        };
+        assert_eq!(http_config.port, 9000);
+
+        // HTTP settings don't get in the way of other commands
+        assert!(parse(settings, &["gmail-mcp-server", "auth", "status"]).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_unknown_settings() {
+        let args = ["gmail-mcp-server", "http"];
+        assert!(parse("prot = 9000", &args).is_err());
+        // Subcommand arguments and the config file itself aren't settings
+        assert!(parse("timeout_secs = 10", &args).is_err());
+        assert!(parse("config_file = \"other.toml\"", &args).is_err());
+        assert!(parse("[port]\nvalue = 9000", &args).is_err());
+        // Values are checked like flags
+        assert!(parse("port = \"eighty\"", &args).is_err());
+    }
+
+    #[test]
+    fn test_profiles() {
+        let settings: Table = r#"
+            port = 8080
+            app-data-dir = "/var/lib/gmail-mcp"
+
+            [profiles.staging]
+            app_data_dir = "/var/lib/gmail-mcp-staging"
+            gmail-client-id = "staging-id"
+        "#
+        .parse()
+        .unwrap();
+
+        // Without a profile, the profiles are ignored
+        let base = with_profile(settings.clone(), None).unwrap();
+        assert_eq!(base.len(), 2);
+
+        let staging = with_profile(settings.clone(), Some("staging")).unwrap();
+        let cli = Cli::from_arg_matches(
+            &command(&staging)
+                .unwrap()
+                .try_get_matches_from(["gmail-mcp-server", "http"])
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            cli.config.app_data_dir,
+            Some(PathBuf::from("/var/lib/gmail-mcp-staging"))
+        );
+        assert_eq!(cli.config.gmail_client_id.as_deref(), Some("staging-id"));
+        let Commands::Http(http_config) = cli.command else {
+            panic!("expected the http command");
+        };
+        assert_eq!(http_config.port, 8080);
+
+        assert!(with_profile(settings, Some("production")).is_err());
+        assert!(with_profile("profiles = 1".parse().unwrap(), None).is_err());
+        assert!(parse("profile = \"staging\"", &["gmail-mcp-server", "http"]).is_err());
+    }
+
+    #[test]
+    fn test_config_file_path() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            config_file_path(&args(&[
+                "gmail-mcp-server",
+                "--config-file",
+                "a.toml",
+                "http"
+            ])),
+            Some(PathBuf::from("a.toml"))
+        );
+        assert_eq!(
+            config_file_path(&args(&["gmail-mcp-server", "--config-file=b.toml", "http"])),
+            Some(PathBuf::from("b.toml"))
+        );
+        assert_eq!(
+            profile(&args(&["gmail-mcp-server", "--profile", "staging", "http"])).as_deref(),
+            Some("staging")
+        );
+    }
+}
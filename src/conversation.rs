@@ -0,0 +1,457 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::LazyLock;
+use tracing::{error, instrument};
+
+use crate::gmail::{GmailServer, GMAIL_API_BASE};
+use crate::oauth::SCOPE_READONLY;
+use crate::pgp::decrypt_message;
+use crate::tools::{message_url, summarize_message, with_response_size, MessageFormat};
+use crate::truncate::fit_messages;
+
+/// Headers requested with each thread: the ones linking messages, and the ones finding and
+/// ordering them
+const LINK_HEADERS: [&str; 5] = ["Message-ID", "In-Reply-To", "References", "Subject", "Date"];
+
+/// A message ID in angle brackets, as the linking headers list them
+static MESSAGE_ID: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^<>\s]+>").unwrap());
+
+/// Reply and forward prefixes, in the languages mail clients commonly use
+static SUBJECT_PREFIX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)^\s*(?:(?:re|fwd?|aw|wg|sv|vs|antw|rif|tr)(?:\[\d+\])?\s*:\s*)+").unwrap()
+});
+
+/// A message of the conversation, with the headers linking it to the others
+#[derive(Debug)]
+struct Linked {
+    message_id: String,
+    thread_id: String,
+    rfc822_id: Option<String>,
+    in_reply_to: Option<String>,
+    /// Every message ID in its References and In-Reply-To headers
+    references: Vec<String>,
+    subject: Option<String>,
+    /// Milliseconds since the epoch
+    received: i64,
+}
+
+/// The messages found so far, and the threads they came from
+#[derive(Debug, Default)]
+struct Conversation {
+    messages: Vec<Linked>,
+    threads: Vec<String>,
+}
+
+/// Reconstruct the conversation a message belongs to, following its Message-ID, In-Reply-To and
+/// References headers into every thread Gmail split it across
+#[instrument(name = "tool", skip_all, fields(tool = "get_conversation", message_id = %message_id))]
+pub async fn get_conversation(
+    gmail_server: &GmailServer,
+    message_id: &str,
+    format: MessageFormat,
+    max_threads: usize,
+) -> Result<Value> {
+    gmail_server.check_authentication(&[SCOPE_READONLY]).await?;
+
+    let client = gmail_server.authenticated_client().await?;
+    let user_id = gmail_server.user_id();
+
+    let message = client
+        .get_json(&message_url(
+            user_id,
+            message_id,
+            MessageFormat::Minimal,
+            None,
+        ))
+        .await
+        .context("Failed to get message")?;
+    let thread_id = message["threadId"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("Message missing thread ID"))?;
+    let thread = client
+        .get_json(&thread_url(user_id, thread_id))
+        .await
+        .context("Failed to get thread")?;
+    let mut conversation = Conversation::default();
+    conversation.add_thread(&thread);
+
+    // Each round looks up the messages the conversation refers to, and later replies Gmail put
+    // in threads of their own under the same subject, until a round adds no thread
+    let mut examined: HashSet<String> = conversation.threads.iter().cloned().collect();
+    let mut searched_ids = HashSet::new();
+    let mut searched_subjects = HashSet::new();
+    while conversation.threads.len() < max_threads {
+        let mut searches = Vec::new();
+        for rfc822_id in conversation.missing_references() {
+            if searched_ids.insert(rfc822_id.clone()) {
+                let query = format!("rfc822msgid:{}", rfc822_id.trim_matches(['<', '>']));
+                searches.push(format!(
+                    "{GMAIL_API_BASE}/users/{user_id}/messages?q={}",
+                    urlencoding::encode(&query)
+                ));
+            }
+        }
+        for subject in conversation.subjects() {
+            if searched_subjects.insert(subject.clone()) {
+                let query = format!("subject:\"{}\"", subject.replace('"', ""));
+                searches.push(format!(
+                    "{GMAIL_API_BASE}/users/{user_id}/threads?q={}&maxResults={max_threads}",
+                    urlencoding::encode(&query)
+                ));
+            }
+        }
+        let results = client
+            .batch_get(&searches)
+            .await
+            .context("Failed to search for related messages")?;
+
+        let mut candidates = Vec::new();
+        for result in results {
+            let result = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    error!("Error searching for related messages: {}", e);
+                    continue;
+                }
+            };
+            let found = result["messages"]
+                .as_array()
+                .into_iter()
+                .chain(result["threads"].as_array())
+                .flatten();
+            for item in found {
+                let thread_id = item["threadId"].as_str().or_else(|| item["id"].as_str());
+                if let Some(thread_id) = thread_id {
+                    if examined.insert(thread_id.to_string()) {
+                        candidates.push(thread_id.to_string());
+                    }
+                }
+            }
+        }
+        if candidates.is_empty() {
+            break;
+        }
+
+        let thread_urls: Vec<String> = candidates
+            .iter()
+            .map(|thread_id| thread_url(user_id, thread_id))
+            .collect();
+        let threads = client
+            .batch_get(&thread_urls)
+            .await
+            .context("Failed to get threads")?;
+        let mut added = false;
+        for (thread_id, thread) in candidates.iter().zip(threads) {
+            match thread {
+                Ok(thread) if conversation.threads.len() < max_threads => {
+                    if conversation.is_linked(&thread) {
+                        conversation.add_thread(&thread);
+                        added = true;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => error!("Error fetching thread {}: {}", thread_id, e),
+            }
+        }
+        if !added {
+            break;
+        }
+    }
+
+    let ordered = conversation.ordered();
+    let urls: Vec<String> = ordered
+        .iter()
+        .map(|linked| message_url(user_id, &linked.message_id, format, None))
+        .collect();
+    let fetched = client
+        .batch_get(&urls)
+        .await
+        .context("Failed to get messages")?;
+    let mut messages = Vec::new();
+    for (linked, message) in ordered.into_iter().zip(fetched) {
+        let mut message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Error fetching message {}: {}", linked.message_id, e);
+                continue;
+            }
+        };
+        decrypt_message(gmail_server, &client, &linked.message_id, &mut message).await;
+        let mut summary = summarize_message(&linked.message_id, &message, format)?;
+        summary["thread_id"] = json!(linked.thread_id);
+        summary["rfc822_message_id"] = json!(linked.rfc822_id);
+        summary["in_reply_to"] = json!(linked.in_reply_to);
+        messages.push(summary);
+    }
+
+    let result = with_response_size(json!({
+        "message_id": message_id,
+        "thread_ids": conversation.threads,
+        "messages": messages,
+    }));
+    Ok(fit_messages(gmail_server, "get_conversation", result))
+}
+
+/// A threads.get URL returning the headers that link and order its messages
+fn thread_url(user_id: &str, thread_id: &str) -> String {
+    let mut url = format!("{GMAIL_API_BASE}/users/{user_id}/threads/{thread_id}?format=metadata");
+    for header in LINK_HEADERS {
+        url.push_str(&format!("&metadataHeaders={header}"));
+    }
+    url
+}
+
+impl Conversation {
+    fn add_thread(&mut self, thread: &Value) {
+        let Some(thread_id) = thread["id"].as_str() else {
+            return;
+        };
+        if self.threads.iter().any(|known| known == thread_id) {
+            return;
+        }
+        self.threads.push(thread_id.to_string());
+        for message in thread["messages"].as_array().into_iter().flatten() {
+            if let Some(linked) = Linked::parse(thread_id, message) {
+                self.messages.push(linked);
+            }
+        }
+    }
+
+    /// Message IDs the conversation refers to that none of its messages have
+    fn missing_references(&self) -> Vec<String> {
+        let known: HashSet<&str> = self
+            .messages
+            .iter()
+            .filter_map(|linked| linked.rfc822_id.as_deref())
+            .collect();
+        let mut missing = Vec::new();
+        for reference in self.messages.iter().flat_map(|linked| &linked.references) {
+            if !known.contains(reference.as_str()) && !missing.contains(reference) {
+                missing.push(reference.clone());
+            }
+        }
+        missing
+    }
+
+    /// The distinct subjects of its messages, without reply and forward prefixes
+    fn subjects(&self) -> Vec<String> {
+        let mut subjects = Vec::new();
+        for subject in self
+            .messages
+            .iter()
+            .filter_map(|linked| linked.subject.as_deref())
+        {
+            let subject = SUBJECT_PREFIX.replace(subject, "").trim().to_string();
+            if !subject.is_empty() && !subjects.contains(&subject) {
+                subjects.push(subject);
+            }
+        }
+        subjects
+    }
+
+    /// Whether a thread belongs to the conversation: one of its messages refers to a message
+    /// of the conversation, or is referred to by one
+    fn is_linked(&self, thread: &Value) -> bool {
+        let known: HashSet<&str> = self
+            .messages
+            .iter()
+            .filter_map(|linked| linked.rfc822_id.as_deref())
+            .collect();
+        let referenced: HashSet<&str> = self
+            .messages
+            .iter()
+            .flat_map(|linked| linked.references.iter().map(String::as_str))
+            .collect();
+        let thread_id = thread["id"].as_str().unwrap_or_default();
+        thread["messages"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|message| Linked::parse(thread_id, message))
+            .any(|linked| {
+                linked
+                    .rfc822_id
+                    .as_deref()
+                    .is_some_and(|rfc822_id| referenced.contains(rfc822_id))
+                    || linked
+                        .references
+                        .iter()
+                        .any(|reference| known.contains(reference.as_str()))
+            })
+    }
+
+    /// Its messages oldest first, keeping one copy of a message that is in several threads,
+    /// as a message sent to yourself is
+    fn ordered(&self) -> Vec<&Linked> {
+        let mut ordered: Vec<&Linked> = self.messages.iter().collect();
+        ordered.sort_by_key(|linked| linked.received);
+        let mut seen = HashSet::new();
+        ordered.retain(|linked| match &linked.rfc822_id {
+            Some(rfc822_id) => seen.insert(rfc822_id.as_str()),
+            None => true,
+        });
+        ordered
+    }
+}
+
+impl Linked {
+    fn parse(thread_id: &str, message: &Value) -> Option<Self> {
+        let header = |name: &str| {
+            message["payload"]["headers"]
+                .as_array()?
+                .iter()
+                .find(|header| {
+                    header["name"]
+                        .as_str()
+                        .is_some_and(|header| header.eq_ignore_ascii_case(name))
+                })
+                .and_then(|header| header["value"].as_str())
+        };
+        let message_ids = |name: &str| -> Vec<String> {
+            header(name)
+                .map(|value| {
+                    MESSAGE_ID
+                        .find_iter(value)
+                        .map(|id| id.as_str().to_string())
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        let in_reply_to = message_ids("In-Reply-To").into_iter().next();
+        let mut references = message_ids("References");
+        if let Some(in_reply_to) = &in_reply_to {
+            if !references.contains(in_reply_to) {
+                references.push(in_reply_to.clone());
+            }
+        }
+        // Gmail's internalDate is when the message arrived, which the Date header only claims
+        let received = message["internalDate"]
+            .as_str()
+            .and_then(|millis| millis.parse().ok())
+            .or_else(|| {
+                header("Date")
+                    .and_then(|date| chrono::DateTime::parse_from_rfc2822(date).ok())
+                    .map(|date| date.timestamp_millis())
+            })
+            .unwrap_or_default();
+        Some(Linked {
+            message_id: message["id"].as_str()?.to_string(),
+            thread_id: thread_id.to_string(),
+            rfc822_id: message_ids("Message-ID").into_iter().next(),
+            in_reply_to,
+            references,
+            subject: header("Subject").map(str::to_string),
+            received,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockGmail;
+
+    fn message(id: &str, internal_date: &str, headers: &[(&str, &str)]) -> Value {
+        let headers: Vec<Value> = headers
+            .iter()
+            .map(|(name, value)| json!({ "name": name, "value": value }))
+            .collect();
+        json!({ "id": id, "internalDate": internal_date, "payload": { "headers": headers } })
+    }
+
+    #[test]
+    fn test_conversation_links_threads() {
+        let mut conversation = Conversation::default();
+        let reply = message(
+            "m3",
+            "3000",
+            &[
+                ("Message-ID", "<c@example.com>"),
+                ("In-Reply-To", "<b@example.com>"),
+                ("References", "<a@example.com>\r\n <b@example.com>"),
+                ("Subject", "RE: Fwd: Budget"),
+            ],
+        );
+        conversation.add_thread(&json!({ "id": "t2", "messages": [reply] }));
+        assert_eq!(
+            conversation.missing_references(),
+            vec!["<a@example.com>", "<b@example.com>"]
+        );
+        assert_eq!(conversation.subjects(), vec!["Budget"]);
+
+        let original = json!({
+            "id": "t1",
+            "messages": [
+                message("m1", "1000", &[("Message-ID", "<a@example.com>"), ("Subject", "Budget")]),
+                message(
+                    "m2",
+                    "2000",
+                    &[
+                        ("Message-ID", "<b@example.com>"),
+                        ("In-Reply-To", "<a@example.com>"),
+                        ("Subject", "Re: Budget"),
+                    ],
+                ),
+            ],
+        });
+        let same_subject = message(
+            "m4",
+            "4000",
+            &[("Message-ID", "<d@example.com>"), ("Subject", "Budget")],
+        );
+        let unrelated = json!({ "id": "t3", "messages": [same_subject] });
+        assert!(conversation.is_linked(&original));
+        assert!(!conversation.is_linked(&unrelated));
+
+        conversation.add_thread(&original);
+        assert!(conversation.missing_references().is_empty());
+        let ordered: Vec<&str> = conversation
+            .ordered()
+            .iter()
+            .map(|linked| linked.message_id.as_str())
+            .collect();
+        assert_eq!(ordered, vec!["m1", "m2", "m3"]);
+        assert_eq!(conversation.threads, vec!["t2", "t1"]);
+    }
+
+    #[test]
+    fn test_ordered_keeps_one_copy_of_each_message() {
+        let mut conversation = Conversation::default();
+        let sent = [("Message-ID", "<a@example.com>")];
+        conversation.add_thread(&json!({
+            "id": "t1",
+            "messages": [message("m1", "1000", &sent), message("m2", "1001", &sent)],
+        }));
+        assert_eq!(conversation.ordered().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_conversation_against_mock_gmail() {
+        let gmail = MockGmail::start().await;
+        let config = gmail.config();
+        let gmail_server = gmail.gmail_server(&config).await;
+
+        // Carol's reply is in a thread of its own, but refers to both messages of thread-1
+        let conversation = get_conversation(&gmail_server, "msg-4", MessageFormat::Full, 10)
+            .await
+            .unwrap();
+        assert_eq!(conversation["thread_ids"], json!(["thread-4", "thread-1"]));
+        let messages = conversation["messages"].as_array().unwrap();
+        let ids: Vec<&str> = messages
+            .iter()
+            .map(|message| message["message_id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, vec!["msg-1", "msg-2", "msg-4"]);
+        assert_eq!(messages[2]["in_reply_to"], "<msg-2@example.com>");
+        assert_eq!(messages[2]["thread_id"], "thread-4");
+        assert_eq!(messages[0]["rfc822_message_id"], "<msg-1@example.com>");
+
+        let conversation = get_conversation(&gmail_server, "msg-4", MessageFormat::Full, 1)
+            .await
+            .unwrap();
+        assert_eq!(conversation["thread_ids"], json!(["thread-4"]));
+    }
+}
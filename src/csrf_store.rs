@@ -0,0 +1,240 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error};
+
+use crate::auth::LoginTarget;
+
+/// OAuth `state` values issued by the login route and not yet returned to the callback.
+///
+/// Each value can complete one login, and only within the TTL.
+#[async_trait]
+pub trait CsrfStore: Send + Sync {
+    /// Remember a login started with `token` as its state
+    async fn insert(&self, token: &str, target: &LoginTarget) -> Result<()>;
+
+    /// Consume a token, returning what is being logged in to if it was issued and has not expired
+    async fn take(&self, token: &str) -> Result<Option<LoginTarget>>;
+
+    /// Drop tokens whose login was never completed, returning how many were removed
+    async fn remove_expired(&self) -> Result<usize> {
+        Ok(0)
+    }
+
+    /// Human-readable location of the tokens for logs, never including credentials
+    fn location(&self) -> String;
+}
+
+/// Open the `--csrf-store` backend, or this process's memory when there is none
+pub fn open(url: Option<&str>, key_prefix: &str, ttl: Duration) -> Result<Arc<dyn CsrfStore>> {
+    let Some(url) = url else {
+        return Ok(Arc::new(CsrfTokens::new(ttl)));
+    };
+    if !(url.starts_with("redis://") || url.starts_with("rediss://")) {
+        anyhow::bail!("--csrf-store must be a redis:// or rediss:// URL");
+    }
+    #[cfg(feature = "redis")]
+    return Ok(Arc::new(RedisCsrfStore::new(
+        url,
+        format!("{key_prefix}csrf:"),
+        ttl,
+    )?));
+    #[cfg(not(feature = "redis"))]
+    {
+        let _ = key_prefix;
+        anyhow::bail!("--csrf-store requires building with the `redis` feature")
+    }
+}
+
+/// Periodically drop expired CSRF tokens until cancelled
+pub fn spawn_csrf_cleanup(
+    csrf_store: Arc<dyn CsrfStore>,
+    interval: Duration,
+    ct: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = ct.cancelled() => break,
+                _ = ticker.tick() => {}
+            }
+            match csrf_store.remove_expired().await {
+                Ok(0) => {}
+                Ok(removed) => debug!("Removed {} expired CSRF token(s)", removed),
+                Err(e) => error!("Failed to remove expired CSRF tokens: {:#}", e),
+            }
+        }
+    })
+}
+
+/// Tokens kept in this process, so the callback must reach the replica that served the login
+pub struct CsrfTokens {
+    ttl: Duration,
+    issued: RwLock<HashMap<String, PendingLogin>>,
+}
+
+/// A login started by the login route
+struct PendingLogin {
+    issued_at: Instant,
+    target: LoginTarget,
+}
+
+impl CsrfTokens {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            issued: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl CsrfStore for CsrfTokens {
+    async fn insert(&self, token: &str, target: &LoginTarget) -> Result<()> {
+        let login = PendingLogin {
+            issued_at: Instant::now(),
+            target: target.clone(),
+        };
+        self.issued.write().await.insert(token.to_string(), login);
+        Ok(())
+    }
+
+    async fn take(&self, token: &str) -> Result<Option<LoginTarget>> {
+        Ok(self
+            .issued
+            .write()
+            .await
+            .remove(token)
+            .filter(|login| login.issued_at.elapsed() <= self.ttl)
+            .map(|login| login.target))
+    }
+
+    async fn remove_expired(&self) -> Result<usize> {
+        let mut issued = self.issued.write().await;
+        let before = issued.len();
+        issued.retain(|_, login| login.issued_at.elapsed() <= self.ttl);
+        Ok(before - issued.len())
+    }
+
+    fn location(&self) -> String {
+        "in memory, so callbacks must reach the replica that started the login".to_string()
+    }
+}
+
+/// Tokens shared by every replica through Redis keys that expire with the TTL
+#[cfg(feature = "redis")]
+pub struct RedisCsrfStore {
+    client: redis::Client,
+    prefix: String,
+    ttl: Duration,
+}
+
+#[cfg(feature = "redis")]
+impl RedisCsrfStore {
+    pub fn new(url: &str, prefix: String, ttl: Duration) -> Result<Self> {
+        use anyhow::Context;
+        let client = redis::Client::open(url).context("Invalid Redis URL")?;
+        Ok(Self {
+            client,
+            prefix,
+            ttl,
+        })
+    }
+
+    async fn connection(&self) -> Result<redis::aio::MultiplexedConnection> {
+        use anyhow::Context;
+        self.client
+            .get_multiplexed_async_connection()
+            .await
+            .context("Failed to connect to Redis")
+    }
+
+    fn key(&self, token: &str) -> String {
+        format!("{}{token}", self.prefix)
+    }
+}
+
+#[cfg(feature = "redis")]
+#[async_trait]
+impl CsrfStore for RedisCsrfStore {
+    async fn insert(&self, token: &str, target: &LoginTarget) -> Result<()> {
+        use anyhow::Context;
+        use redis::AsyncCommands;
+        let target = serde_json::to_string(target)?;
+        self.connection()
+            .await?
+            .pset_ex::<_, _, ()>(self.key(token), target, self.ttl.as_millis() as u64)
+            .await
+            .context("Failed to write CSRF token to Redis")
+    }
+
+    async fn take(&self, token: &str) -> Result<Option<LoginTarget>> {
+        use anyhow::Context;
+        use redis::AsyncCommands;
+        // GETDEL, so two callbacks with the same state can't both complete a login
+        let target: Option<String> = self
+            .connection()
+            .await?
+            .get_del(self.key(token))
+            .await
+            .context("Failed to read CSRF token from Redis")?;
+        target
+            .map(|target| serde_json::from_str(&target).context("Invalid CSRF token in Redis"))
+            .transpose()
+    }
+
+    fn location(&self) -> String {
+        format!("Redis keys {}*", self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_csrf_tokens_are_single_use() {
+        let csrf_tokens = CsrfTokens::new(Duration::from_secs(600));
+        let work = LoginTarget::Account("work".to_string());
+        csrf_tokens.insert("state-1", &work).await.unwrap();
+        assert_eq!(csrf_tokens.take("state-2").await.unwrap(), None);
+        assert_eq!(csrf_tokens.take("state-1").await.unwrap(), Some(work));
+        assert_eq!(csrf_tokens.take("state-1").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_csrf_tokens_expire() {
+        let csrf_tokens = CsrfTokens::new(Duration::from_millis(20));
+        let session = || LoginTarget::Session("session-1".to_string());
+        csrf_tokens.insert("state-1", &session()).await.unwrap();
+        csrf_tokens.insert("state-2", &session()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        csrf_tokens.insert("state-3", &session()).await.unwrap();
+
+        assert_eq!(csrf_tokens.take("state-1").await.unwrap(), None);
+        assert_eq!(csrf_tokens.remove_expired().await.unwrap(), 1);
+        assert_eq!(csrf_tokens.take("state-3").await.unwrap(), Some(session()));
+    }
+
+    #[test]
+    fn test_open() {
+        let ttl = Duration::from_secs(600);
+        let memory = open(None, "gmail-mcp-server:", ttl).unwrap();
+        assert!(memory.location().starts_with("in memory"));
+        assert!(open(Some("localhost:6379"), "gmail-mcp-server:", ttl).is_err());
+
+        let redis = open(Some("redis://localhost:6379"), "gmail-mcp-server:", ttl);
+        #[cfg(feature = "redis")]
+        assert_eq!(
+            redis.unwrap().location(),
+            "Redis keys gmail-mcp-server:csrf:*"
+        );
+        #[cfg(not(feature = "redis"))]
+        assert!(redis.is_err());
+    }
+}
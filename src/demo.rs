@@ -0,0 +1,620 @@
+use anyhow::{Context, Result};
+use axum::{
+    extract::{OriginalUri, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    response::IntoResponse,
+    routing::post,
+    Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::info;
+
+use crate::batch::boundary_from_content_type;
+use crate::config::{Config, HttpConfig};
+use crate::oauth::{OAuthManager, OAuthToken, GMAIL_SCOPES};
+
+/// The synthetic mailbox `--demo` serves
+const MAILBOX: &str = include_str!("demo_mailbox.json");
+
+#[derive(Deserialize)]
+struct Mailbox {
+    email: String,
+    threads: Vec<Thread>,
+}
+
+#[derive(Deserialize)]
+struct Thread {
+    id: String,
+    messages: Vec<Message>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    id: String,
+    labels: Vec<String>,
+    from: String,
+    to: String,
+    cc: Option<String>,
+    subject: String,
+    date: String,
+    text: String,
+    html: Option<String>,
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+}
+
+#[derive(Deserialize)]
+struct Attachment {
+    id: String,
+    filename: String,
+    mime_type: String,
+    text: String,
+}
+
+/// A fake Gmail API answering from the bundled mailbox. Drafts and sent messages are
+/// acknowledged and kept in memory, but never change the mailbox or leave the process
+struct DemoGmail {
+    mailbox: Mailbox,
+    /// Drafts by ID, as Gmail returns them with `format=raw`
+    drafts: Mutex<HashMap<String, Value>>,
+    next_id: AtomicU64,
+}
+
+/// Serve the bundled mailbox on a local port and point `config` at it instead of Gmail. Client
+/// credentials are filled in if missing, since no OAuth flow takes place
+pub async fn start(config: &mut Config) -> Result<()> {
+    let demo = Arc::new(DemoGmail::new()?);
+    let email = demo.mailbox.email.clone();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .context("Failed to bind the demo Gmail API")?;
+    let url = format!("http://{}", listener.local_addr()?);
+    let app = Router::new()
+        .route("/batch/gmail/v1", post(batch))
+        .fallback(call)
+        .with_state(demo);
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    config.gmail_api.gmail_api_url = Some(url);
+    // Demo messages must not land in the real message cache or audit log
+    config.message_cache = false;
+    config.audit_log = false;
+    config
+        .gmail_client_id
+        .get_or_insert_with(|| "demo".to_string());
+    config
+        .gmail_client_secret
+        .get_or_insert_with(|| "demo".to_string());
+    info!("🎭 Demo mode: serving a synthetic mailbox for {email}, Gmail is not called");
+    Ok(())
+}
+
+/// An OAuth manager holding a token for the demo API in memory, leaving stored tokens alone
+pub async fn oauth_manager(config: &Config, http_config: HttpConfig) -> Result<OAuthManager> {
+    let oauth_manager = OAuthManager::in_memory(config.clone(), http_config)?;
+    oauth_manager
+        .save_token(&OAuthToken {
+            access_token: "demo".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: u32::MAX.into(),
+            refresh_token: None,
+            scope: GMAIL_SCOPES.join(" "),
+            created_at: chrono::Utc::now()
+                .timestamp()
+                .try_into()
+                .unwrap_or_default(),
+        })
+        .await?;
+    Ok(oauth_manager)
+}
+
+async fn call(
+    State(demo): State<Arc<DemoGmail>>,
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    body: String,
+) -> axum::response::Response {
+    let path = uri
+        .path_and_query()
+        .map_or(uri.path(), |path| path.as_str());
+    let (status, body) = demo.respond(&method, path, &body);
+    (status, Json(body)).into_response()
+}
+
+/// Answer each GET in a batch request, in a multipart response like Gmail's
+async fn batch(
+    State(demo): State<Arc<DemoGmail>>,
+    headers: HeaderMap,
+    body: String,
+) -> axum::response::Response {
+    let Some(boundary) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(boundary_from_content_type)
+    else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let mut response = String::new();
+    let requests = body.lines().filter_map(|line| line.strip_prefix("GET "));
+    for (index, path) in requests.enumerate() {
+        let (status, body) = demo.respond(&Method::GET, path.trim(), "");
+        response.push_str(&format!(
+            "--{boundary}\r\nContent-Type: application/http\r\nContent-ID: <response-item-{index}>\r\n\r\n\
+             HTTP/1.1 {status}\r\nContent-Type: application/json\r\n\r\n{body}\r\n"
+        ));
+    }
+    response.push_str(&format!("--{boundary}--\r\n"));
+    let content_type = format!("multipart/mixed; boundary={boundary}");
+    ([(header::CONTENT_TYPE, content_type)], response).into_response()
+}
+
+impl DemoGmail {
+    fn new() -> Result<Self> {
+        let mailbox = serde_json::from_str(MAILBOX).context("Invalid demo mailbox")?;
+        Ok(Self {
+            mailbox,
+            drafts: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+        })
+    }
+
+    /// Status and JSON body of the answer to one call
+    fn respond(&self, method: &Method, path: &str, body: &str) -> (StatusCode, Value) {
+        let Ok(url) = url::Url::parse(&format!("http://demo{path}")) else {
+            return not_found();
+        };
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+        let query = param("q").unwrap_or_default();
+        let max_results = param("maxResults")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(100);
+        let segments: Vec<&str> = url.path_segments().into_iter().flatten().collect();
+        let Some(["users", _, resource @ ..]) = segments.strip_prefix(&["gmail", "v1"]) else {
+            return not_found();
+        };
+
+        let found = match (method.as_str(), resource) {
+            ("GET", ["profile"]) => Some(self.profile()),
+            ("GET", ["threads"]) => Some(self.list_threads(&query, max_results)),
+            ("GET", ["threads", thread_id]) => self.thread(thread_id),
+            ("GET", ["messages"]) => Some(self.list_messages(&query, max_results)),
+            ("GET", ["messages", message_id]) => self
+                .find_message(message_id)
+                .map(|(thread, message)| message_resource(&thread.id, message)),
+            ("GET", ["messages", message_id, "attachments", attachment_id]) => {
+                self.attachment(message_id, attachment_id)
+            }
+            ("POST", ["drafts"]) => Some(self.create_draft(body)),
+            ("GET", ["drafts", draft_id]) => self.drafts.lock().unwrap().get(*draft_id).cloned(),
+            ("POST", ["drafts", draft_id, "send"]) => {
+                let draft = self.drafts.lock().unwrap().remove(*draft_id);
+                draft.map(|draft| self.sent(draft["message"]["threadId"].as_str()))
+            }
+            ("POST", ["messages", "send"]) => {
+                let message: Value = serde_json::from_str(body).unwrap_or_default();
+                Some(self.sent(message["threadId"].as_str()))
+            }
+            _ => None,
+        };
+        match found {
+            Some(body) => (StatusCode::OK, body),
+            None => not_found(),
+        }
+    }
+
+    fn profile(&self) -> Value {
+        let threads = &self.mailbox.threads;
+        json!({
+            "emailAddress": self.mailbox.email,
+            "messagesTotal": threads.iter().map(|thread| thread.messages.len()).sum::<usize>(),
+            "threadsTotal": threads.len(),
+            "historyId": "1",
+        })
+    }
+
+    /// Threads with a message matching `query`, latest activity first
+    fn list_threads(&self, query: &str, max_results: usize) -> Value {
+        let mut threads: Vec<&Thread> = self
+            .mailbox
+            .threads
+            .iter()
+            .filter(|thread| {
+                thread
+                    .messages
+                    .iter()
+                    .any(|message| matches(message, query))
+            })
+            .collect();
+        threads.sort_by_key(|thread| {
+            std::cmp::Reverse(thread.messages.iter().map(internal_date).max())
+        });
+        let threads: Vec<Value> = threads
+            .into_iter()
+            .take(max_results)
+            .map(|thread| {
+                let latest = thread
+                    .messages
+                    .iter()
+                    .max_by_key(|message| internal_date(message));
+                json!({
+                    "id": thread.id,
+                    "snippet": latest.map(snippet),
+                    "historyId": "1",
+                })
+            })
+            .collect();
+        list("threads", threads)
+    }
+
+    /// Messages matching `query`, newest first
+    fn list_messages(&self, query: &str, max_results: usize) -> Value {
+        let mut messages: Vec<(&Thread, &Message)> = self
+            .mailbox
+            .threads
+            .iter()
+            .flat_map(|thread| thread.messages.iter().map(move |message| (thread, message)))
+            .filter(|(_, message)| matches(message, query))
+            .collect();
+        messages.sort_by_key(|(_, message)| std::cmp::Reverse(internal_date(message)));
+        let messages: Vec<Value> = messages
+            .into_iter()
+            .take(max_results)
+            .map(|(thread, message)| json!({ "id": message.id, "threadId": thread.id }))
+            .collect();
+        list("messages", messages)
+    }
+
+    fn thread(&self, thread_id: &str) -> Option<Value> {
+        let thread = self
+            .mailbox
+            .threads
+            .iter()
+            .find(|thread| thread.id == thread_id)?;
+        let messages: Vec<Value> = thread
+            .messages
+            .iter()
+            .map(|message| message_resource(&thread.id, message))
+            .collect();
+        Some(json!({ "id": thread.id, "historyId": "1", "messages": messages }))
+    }
+
+    fn find_message(&self, message_id: &str) -> Option<(&Thread, &Message)> {
+        self.mailbox.threads.iter().find_map(|thread| {
+            let message = thread
+                .messages
+                .iter()
+                .find(|message| message.id == message_id)?;
+            Some((thread, message))
+        })
+    }
+
+    fn attachment(&self, message_id: &str, attachment_id: &str) -> Option<Value> {
+        let (_, message) = self.find_message(message_id)?;
+        let attachment = message
+            .attachments
+            .iter()
+            .find(|attachment| attachment.id == attachment_id)?;
+        Some(json!({
+            "size": attachment.text.len(),
+            "data": URL_SAFE.encode(&attachment.text),
+        }))
+    }
+
+    fn create_draft(&self, body: &str) -> Value {
+        let request: Value = serde_json::from_str(body).unwrap_or_default();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let thread_id = request["message"]["threadId"]
+            .as_str()
+            .map_or_else(|| format!("demo-thread-draft-{id}"), str::to_string);
+        let message = json!({
+            "id": format!("demo-msg-draft-{id}"),
+            "threadId": thread_id,
+            "labelIds": ["DRAFT"],
+        });
+        let draft_id = format!("demo-draft-{id}");
+        let mut stored = json!({ "id": draft_id, "message": message });
+        stored["message"]["raw"] = request["message"]["raw"].clone();
+        self.drafts.lock().unwrap().insert(draft_id.clone(), stored);
+        json!({ "id": draft_id, "message": message })
+    }
+
+    fn sent(&self, thread_id: Option<&str>) -> Value {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        json!({
+            "id": format!("demo-msg-sent-{id}"),
+            "threadId": thread_id.map_or_else(|| format!("demo-thread-sent-{id}"), str::to_string),
+            "labelIds": ["SENT"],
+        })
+    }
+}
+
+fn not_found() -> (StatusCode, Value) {
+    let error =
+        json!({ "code": 404, "message": "Requested entity was not found.", "status": "NOT_FOUND" });
+    (StatusCode::NOT_FOUND, json!({ "error": error }))
+}
+
+/// A list response, which like Gmail's leaves out the items when there are none
+fn list(name: &str, items: Vec<Value>) -> Value {
+    let mut list = json!({ "resultSizeEstimate": items.len() });
+    if !items.is_empty() {
+        list[name] = Value::Array(items);
+    }
+    list
+}
+
+/// Milliseconds since the epoch the message was received, as Gmail's `internalDate`
+fn internal_date(message: &Message) -> i64 {
+    chrono::DateTime::parse_from_rfc2822(&message.date)
+        .map(|date| date.timestamp_millis())
+        .unwrap_or_default()
+}
+
+fn snippet(message: &Message) -> String {
+    let text = message
+        .text
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    text.chars().take(100).collect()
+}
+
+/// A message as Gmail returns it with `format=full`: a text part, with an HTML alternative
+/// if it has one, followed by its attachments
+fn message_resource(thread_id: &str, message: &Message) -> Value {
+    let part = |mime_type: &str, body: &str| {
+        json!({
+            "mimeType": mime_type,
+            "filename": "",
+            "body": { "size": body.len(), "data": URL_SAFE.encode(body) },
+        })
+    };
+    let multipart = |mime_type: &str, parts: Vec<Value>| json!({ "mimeType": mime_type, "filename": "", "body": { "size": 0 }, "parts": parts });
+
+    let mut payload = match &message.html {
+        Some(html) => multipart(
+            "multipart/alternative",
+            vec![part("text/plain", &message.text), part("text/html", html)],
+        ),
+        None => part("text/plain", &message.text),
+    };
+    if !message.attachments.is_empty() {
+        let attachments = message.attachments.iter().map(|attachment| {
+            json!({
+                "mimeType": attachment.mime_type,
+                "filename": attachment.filename,
+                "body": { "attachmentId": attachment.id, "size": attachment.text.len() },
+            })
+        });
+        payload = multipart(
+            "multipart/mixed",
+            std::iter::once(payload).chain(attachments).collect(),
+        );
+    }
+
+    let mut headers = vec![("From", message.from.as_str()), ("To", message.to.as_str())];
+    if let Some(cc) = &message.cc {
+        headers.push(("Cc", cc));
+    }
+    headers.push(("Subject", &message.subject));
+    headers.push(("Date", &message.date));
+    let message_id = format!("<{}@demo.example>", message.id);
+    headers.push(("Message-ID", &message_id));
+    payload["headers"] = headers
+        .into_iter()
+        .map(|(name, value)| json!({ "name": name, "value": value }))
+        .collect();
+
+    json!({
+        "id": message.id,
+        "threadId": thread_id,
+        "labelIds": message.labels,
+        "snippet": snippet(message),
+        "historyId": "1",
+        "internalDate": internal_date(message).to_string(),
+        "sizeEstimate": message.text.len(),
+        "payload": payload,
+    })
+}
+
+/// Whether a message matches a Gmail search query, for the operators a demo is likely to try:
+/// `from:`, `to:`, `subject:`, `in:`, `is:`, `label:`, `category:`, `has:attachment`,
+/// `filename:`, free text and `-` to negate, with parentheses ignored. Others, such as dates,
+/// match everything
+fn matches(message: &Message, query: &str) -> bool {
+    terms(query).iter().all(|term| {
+        let (negated, term) = match term.strip_prefix('-') {
+            Some(term) if !term.is_empty() => (true, term),
+            _ => (false, term.as_str()),
+        };
+        matches_term(message, &term.to_lowercase()) != negated
+    })
+}
+
+fn matches_term(message: &Message, term: &str) -> bool {
+    let contains = |text: &str, value: &str| text.to_lowercase().contains(value);
+    let has_label = |label: &str| {
+        message.labels.iter().any(|id| {
+            id.eq_ignore_ascii_case(label)
+                || id
+                    .strip_prefix("Label_")
+                    .is_some_and(|name| name.eq_ignore_ascii_case(label))
+        })
+    };
+    match term.split_once(':') {
+        Some(("from", value)) => contains(&message.from, value),
+        Some(("to", value)) => {
+            contains(&message.to, value)
+                || message.cc.as_deref().is_some_and(|cc| contains(cc, value))
+        }
+        Some(("subject", value)) => contains(&message.subject, value),
+        Some(("is", "read")) => !has_label("unread"),
+        Some(("in", "anywhere" | "all")) => true,
+        Some(("in" | "is" | "label", value)) => has_label(value),
+        // The Primary tab's label is named for personal mail
+        Some(("category", "primary")) => has_label("category_personal"),
+        Some(("category", value)) => has_label(&format!("category_{value}")),
+        Some(("has", "attachment")) => !message.attachments.is_empty(),
+        Some(("filename", value)) => message
+            .attachments
+            .iter()
+            .any(|attachment| contains(&attachment.filename, value)),
+        Some((operator, _))
+            if operator
+                .chars()
+                .all(|c| c.is_ascii_alphabetic() || c == '_') =>
+        {
+            true
+        }
+        _ => [
+            message.from.as_str(),
+            message.to.as_str(),
+            message.cc.as_deref().unwrap_or_default(),
+            message.subject.as_str(),
+            message.text.as_str(),
+        ]
+        .iter()
+        .any(|text| contains(text, term)),
+    }
+}
+
+/// Split a query into terms at whitespace, keeping "quoted phrases" together
+fn terms(query: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    let mut term = String::new();
+    let mut quoted = false;
+    for c in query.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if (c.is_whitespace() || c == '(' || c == ')') && !quoted => {
+                terms.push(std::mem::take(&mut term))
+            }
+            c => term.push(c),
+        }
+    }
+    terms.push(term);
+    terms.retain(|term| !term.is_empty());
+    terms
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gmail::GmailServer;
+    use crate::tools::{self, MessageFormat};
+
+    fn thread_ids(threads: &Value) -> Vec<&str> {
+        threads["threads"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|thread| thread["id"].as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_search() {
+        let demo = DemoGmail::new().unwrap();
+        let search = |query: &str| demo.list_threads(query, 100);
+
+        assert_eq!(thread_ids(&search("")).len(), demo.mailbox.threads.len());
+        assert_eq!(
+            thread_ids(&search("is:unread has:attachment")),
+            ["demo-thread-offsite", "demo-thread-invoice"]
+        );
+        assert_eq!(
+            thread_ids(&search("label:finance")),
+            ["demo-thread-invoice"]
+        );
+        assert_eq!(
+            thread_ids(&search("\"security review\" from:marco")),
+            ["demo-thread-launch"]
+        );
+        assert_eq!(
+            thread_ids(&search("category:updates -rust")),
+            Vec::<&str>::new()
+        );
+        // As search_threads adds a category filter to a query
+        assert_eq!(
+            thread_ids(&search(
+                "(in:inbox -is:unread) category:updates -is:important"
+            ))
+            .len(),
+            1
+        );
+        assert_eq!(search("nothing matches this")["resultSizeEstimate"], 0);
+        assert_eq!(
+            terms(r#"subject:"launch plan" -is:read"#),
+            ["subject:launch plan", "-is:read"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tools_against_demo_mailbox() {
+        let mut config = Config::default();
+        start(&mut config).await.unwrap();
+        let oauth_manager = Arc::new(oauth_manager(&config, HttpConfig::default()).await.unwrap());
+        let token = oauth_manager.load_token().await.unwrap().unwrap();
+        oauth_manager.set_token(token).await;
+        let gmail_server = GmailServer::new(oauth_manager, &config).unwrap();
+
+        let threads = tools::search_threads(
+            &gmail_server,
+            "filename:agenda",
+            10,
+            None,
+            &tools::SearchFilter::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(thread_ids(&threads), ["demo-thread-offsite"]);
+
+        let thread_ids = ["demo-thread-launch".to_string()];
+        let bodies =
+            tools::fetch_email_bodies(&gmail_server, &thread_ids, MessageFormat::Full, None)
+                .await
+                .unwrap();
+        let bodies = bodies.to_string();
+        assert!(bodies.contains("Beta opens to 500 customers"));
+        assert!(bodies.contains("Support rota is confirmed"));
+
+        let attachment = tools::extract_attachment_by_filename(
+            &gmail_server,
+            "demo-msg-invoice-1",
+            "INV-2024-0917.txt",
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(attachment.to_string().contains("$1,284.50"));
+
+        let draft = tools::create_draft(
+            &gmail_server,
+            "priya@northwind.example",
+            "Re: Launch plan for the mobile app",
+            "Sounds good.",
+            Some("demo-thread-launch"),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+        let draft_id = draft["id"].as_str().unwrap();
+        let sent = tools::send_draft(&gmail_server, draft_id, false, false)
+            .await
+            .unwrap();
+        assert_eq!(sent["threadId"], "demo-thread-launch");
+        assert!(tools::send_draft(&gmail_server, draft_id, false, false)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde_json::{json, Value};
+use std::sync::LazyLock;
+use tracing::instrument;
+
+use crate::email::decode_email_content;
+use crate::extract::extract_text_from_bytes;
+use crate::gmail::{GmailClient, GmailServer, DRIVE_API_BASE};
+use crate::mime::leaf_parts;
+use crate::oauth::{SCOPE_DRIVE_READONLY, SCOPE_READONLY};
+use crate::pgp::decrypt_message;
+use crate::tools::{message_url, preview_text, text_size, with_response_size, MessageFormat};
+
+/// Links to a Google Doc, Sheet or Slides deck, or to any Drive file by ID, capturing the file ID
+static DRIVE_LINK: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"https://(?:docs\.google\.com/(?:document|spreadsheets|presentation)/(?:u/\d+/)?d/|drive\.google\.com/open\?id=)([A-Za-z0-9_-]{10,})",
+    )
+    .unwrap()
+});
+
+/// The format a Drive-native file is exported in, as its MIME type and file extension, or `None`
+/// for files that are stored as themselves and can't be exported
+fn export_format(mime_type: &str) -> Option<(&'static str, &'static str)> {
+    match mime_type {
+        "application/vnd.google-apps.document" | "application/vnd.google-apps.presentation" => {
+            Some(("text/plain", "txt"))
+        }
+        "application/vnd.google-apps.spreadsheet" => Some(("text/csv", "csv")),
+        _ => None,
+    }
+}
+
+/// IDs of the Drive files linked from a message's text and HTML bodies, each once, in the order
+/// they're first linked
+fn linked_file_ids(message: &Value) -> Vec<String> {
+    let mut leaves = Vec::new();
+    leaf_parts(&message["payload"], &mut leaves);
+    let mut file_ids: Vec<String> = Vec::new();
+    for part in leaves {
+        let is_body = matches!(part["mimeType"].as_str(), Some("text/plain" | "text/html"))
+            && part["filename"].as_str().unwrap_or_default().is_empty();
+        let Some(body) = is_body
+            .then(|| part["body"]["data"].as_str())
+            .flatten()
+            .and_then(|data| decode_email_content(data).ok())
+        else {
+            continue;
+        };
+        for captures in DRIVE_LINK.captures_iter(&body) {
+            let file_id = &captures[1];
+            if !file_ids.iter().any(|id| id == file_id) {
+                file_ids.push(file_id.to_string());
+            }
+        }
+    }
+    file_ids
+}
+
+/// Extract the text of the Google Docs, Sheets and Slides decks linked from a message, which
+/// Gmail sends as links rather than attachments. Docs and Slides are exported as plain text and
+/// Sheets as CSV, which Drive limits to the first sheet
+#[instrument(name = "tool", skip_all, fields(tool = "extract_drive_attachments", message_id = %message_id))]
+pub async fn extract_drive_attachments(
+    gmail_server: &GmailServer,
+    message_id: &str,
+    preview_chars: Option<usize>,
+) -> Result<Value> {
+    gmail_server
+        .check_authentication(&[SCOPE_READONLY, SCOPE_DRIVE_READONLY])
+        .await?;
+
+    let client = gmail_server.authenticated_client().await?;
+    let mut message = client
+        .get_json(&message_url(
+            gmail_server.user_id(),
+            message_id,
+            MessageFormat::Full,
+            None,
+        ))
+        .await
+        .context("Failed to get message")?;
+    decrypt_message(gmail_server, &client, message_id, &mut message).await;
+
+    let mut files = Vec::new();
+    for file_id in linked_file_ids(&message) {
+        // A file that isn't shared with the account doesn't spoil the rest
+        let file = match export_file(gmail_server, &client, &file_id).await {
+            Ok(mut file) => {
+                if let Some(chars) = preview_chars {
+                    preview_text(&mut file, chars);
+                }
+                file
+            }
+            Err(e) => json!({
+                "file_id": file_id,
+                "extracted_text": null,
+                "error": format!("{e:#}"),
+            }),
+        };
+        files.push(file);
+    }
+    Ok(with_response_size(json!({
+        "message_id": message_id,
+        "files": files,
+    })))
+}
+
+/// A Drive file's name, type and exported text
+async fn export_file(
+    gmail_server: &GmailServer,
+    client: &GmailClient,
+    file_id: &str,
+) -> Result<Value> {
+    let file_url = format!("{DRIVE_API_BASE}/files/{}", urlencoding::encode(file_id));
+    let metadata = client
+        .get_json(&format!("{file_url}?fields=id,name,mimeType,webViewLink"))
+        .await
+        .context("Failed to get Drive file")?;
+    let name = metadata["name"].as_str().unwrap_or(file_id);
+    let mime_type = metadata["mimeType"].as_str().unwrap_or_default();
+    let (export_mime_type, extension) = export_format(mime_type).ok_or_else(|| {
+        anyhow::anyhow!("'{name}' is a {mime_type} file, not a Google Doc, Sheet or Slides deck")
+    })?;
+
+    let response = client
+        .get(&format!(
+            "{file_url}/export?mimeType={}",
+            urlencoding::encode(export_mime_type)
+        ))
+        .send()
+        .await
+        .context("Failed to export Drive file")?;
+    let status = response.status();
+    if !status.is_success() {
+        let error_text = response.text().await.unwrap_or_default();
+        return Err(anyhow::anyhow!("Drive API error: {status} - {error_text}"));
+    }
+    let data = response
+        .bytes()
+        .await
+        .context("Failed to read exported file")?;
+
+    let filename = format!("{name}.{extension}");
+    if let Some(scanner) = gmail_server.attachment_scanner() {
+        scanner.check(&filename, &data).await?;
+    }
+    let extracted_text = extract_text_from_bytes(&data, export_mime_type, &filename)
+        .context("Failed to extract text from exported file")?;
+    Ok(json!({
+        "file_id": file_id,
+        "name": name,
+        "mime_type": mime_type,
+        "export_mime_type": export_mime_type,
+        "url": metadata["webViewLink"],
+        "text_size": text_size(&extracted_text),
+        "extracted_text": extracted_text,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockGmail, MOCK_DOC_TEXT, MOCK_SHEET_CSV};
+    use base64::{engine::general_purpose::URL_SAFE, Engine};
+
+    #[test]
+    fn test_linked_file_ids() {
+        let body = |mime_type: &str, text: &str| json!({ "mimeType": mime_type, "filename": "", "body": { "data": URL_SAFE.encode(text) } });
+        let message = json!({
+            "payload": {
+                "mimeType": "multipart/alternative",
+                "parts": [
+                    body("text/plain", "Plan: https://docs.google.com/document/d/1AbCdEfGhIjK/edit?usp=drive_web"),
+                    body(
+                        "text/html",
+                        "<a href=\"https://docs.google.com/document/d/1AbCdEfGhIjK/edit\">Plan</a> \
+                         <a href=\"https://docs.google.com/spreadsheets/u/1/d/2XyZ_-0123456/view\">Budget</a> \
+                         <a href=\"https://drive.google.com/open?id=3QrStUvWxYz&amp;authuser=0\">Deck</a> \
+                         <a href=\"https://docs.google.com/forms/d/4NotAFile000/edit\">Survey</a>",
+                    ),
+                ],
+            },
+        });
+        assert_eq!(
+            linked_file_ids(&message),
+            ["1AbCdEfGhIjK", "2XyZ_-0123456", "3QrStUvWxYz"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_extract_drive_attachments_against_mock_gmail() {
+        let gmail = MockGmail::start().await;
+        let gmail_server = gmail.gmail_server(&gmail.config()).await;
+
+        let result = extract_drive_attachments(&gmail_server, "msg-7", None)
+            .await
+            .unwrap();
+        let files = result["files"].as_array().unwrap();
+        assert_eq!(files.len(), 4);
+        assert_eq!(files[0]["name"], "Launch plan");
+        assert_eq!(files[0]["export_mime_type"], "text/plain");
+        assert_eq!(files[0]["extracted_text"], MOCK_DOC_TEXT);
+        assert_eq!(files[1]["name"], "Budget");
+        assert_eq!(files[1]["extracted_text"], MOCK_SHEET_CSV);
+        // An uploaded file can't be exported, and an unshared one can't be read
+        assert!(files[2]["error"]
+            .as_str()
+            .unwrap()
+            .contains("not a Google Doc"));
+        assert_eq!(files[3]["file_id"], "doc-private00");
+        assert!(files[3]["error"].as_str().unwrap().contains("404"));
+
+        let result = extract_drive_attachments(&gmail_server, "msg-7", Some(6))
+            .await
+            .unwrap();
+        assert_eq!(result["files"][0]["extracted_text"], "Launch");
+        assert_eq!(result["files"][0]["preview_truncated"], true);
+
+        let result = extract_drive_attachments(&gmail_server, "msg-1", None)
+            .await
+            .unwrap();
+        assert_eq!(result["files"], json!([]));
+    }
+}
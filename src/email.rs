@@ -1,5 +1,73 @@
 use anyhow::Result;
 use base64::{engine::general_purpose::URL_SAFE, Engine};
+use regex::Regex;
+use std::borrow::Cow;
+use std::sync::LazyLock;
+
+/// `@domain` in an address where a label of the domain is punycode
+static PUNYCODE_DOMAIN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)@((?:[a-z0-9-]+\.)*xn--[a-z0-9-]+(?:\.[a-z0-9-]+)*)").unwrap()
+});
+
+/// Elements whose content is never shown, and comments
+static HIDDEN_HTML: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?is)<(script|style|head|title)\b.*?</(?:script|style|head|title)\s*>|<!--.*?-->")
+        .unwrap()
+});
+
+/// Tags that start a new line of text
+static BLOCK_TAG: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)<(?:br|/?(?:p|div|tr|table|h[1-6]|ul|ol|blockquote|pre|hr))\b[^>]*>").unwrap()
+});
+
+static LIST_ITEM: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)<li\b[^>]*>").unwrap());
+
+static WHITESPACE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\s+").unwrap());
+
+static TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>").unwrap());
+
+static PRE_BLOCK: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)<pre\b[^>]*>(.*?)</pre\s*>").unwrap());
+
+static HEADING: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)<h([1-6])\b[^>]*>").unwrap());
+
+static HEADING_END: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)</h[1-6]\s*>").unwrap());
+
+static STRONG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)</?(?:b|strong)\b[^>]*>").unwrap());
+
+static EMPHASIS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)</?(?:i|em)\b[^>]*>").unwrap());
+
+static CODE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)</?code\b[^>]*>").unwrap());
+
+static LINK: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)<a\b([^>]*)>(.*?)</a\s*>").unwrap());
+
+static IMAGE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)<img\b([^>]*)>").unwrap());
+
+static ATTRIBUTE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i)\b([a-z-]+)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#).unwrap()
+});
+
+static BLOCKQUOTE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)<blockquote\b[^>]*>").unwrap());
+
+static BLOCKQUOTE_END: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)</blockquote\s*>").unwrap());
+
+static RULE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?i)<hr\b[^>]*>").unwrap());
+
+/// Tags that separate paragraphs
+static PARAGRAPH_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)</?(?:p|table|ul|ol)\b[^>]*>").unwrap());
+
+/// Stand-ins for where a blockquote starts and ends, until its lines are prefixed with `>`
+const QUOTE_START: &str = "\u{2}";
+const QUOTE_END: &str = "\u{3}";
+
+static ENTITY: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);").unwrap());
 
 /// Decode base64url-encoded email content
 pub fn decode_email_content(data: &str) -> Result<String> {
@@ -7,6 +75,34 @@ pub fn decode_email_content(data: &str) -> Result<String> {
     Ok(String::from_utf8(decoded)?)
 }
 
+/// An address with an internationalized domain converted to punycode, or None if it isn't a
+/// valid address. UTF-8 local parts (RFC 6531) are kept, since they have no ASCII form and
+/// Gmail delivers them over SMTPUTF8
+pub fn ascii_domain_address(address: &str) -> Option<String> {
+    let (local, domain) = address.split_once('@')?;
+    if local.is_empty()
+        || domain.is_empty()
+        || domain.contains('@')
+        || address.contains(char::is_whitespace)
+    {
+        return None;
+    }
+    let domain = idna::domain_to_ascii(domain).ok()?;
+    Some(format!("{local}@{domain}"))
+}
+
+/// A header value with the punycode domains of its addresses shown in Unicode, as mail clients
+/// display them
+pub fn unicode_domains(value: &str) -> Cow<'_, str> {
+    PUNYCODE_DOMAIN.replace_all(
+        value,
+        |captures: &regex::Captures| match idna::domain_to_unicode(&captures[1]) {
+            (domain, Ok(())) => format!("@{domain}"),
+            (_, Err(_)) => captures[0].to_string(),
+        },
+    )
+}
+
 /// Check if content contains HTML tags
 #[allow(dead_code)]
 pub fn is_html_content(content: &str) -> bool {
@@ -25,6 +121,193 @@ pub fn is_html_content(content: &str) -> bool {
     html_tags.iter().any(|tag| content_lower.contains(tag))
 }
 
+/// The text of an HTML body, with a line break for each paragraph, row or line break and a
+/// bullet for each list item
+pub fn html_to_text(html: &str) -> String {
+    let html = HIDDEN_HTML.replace_all(html, "");
+    // Whitespace in the markup is insignificant, so only the tags break lines
+    let html = WHITESPACE.replace_all(&html, " ");
+    let html = LIST_ITEM.replace_all(&html, "\n- ");
+    let html = BLOCK_TAG.replace_all(&html, "\n");
+    let html = TAG.replace_all(&html, "");
+    let html = decode_entities(&html).replace('\u{a0}', " ");
+
+    let mut text = String::new();
+    let mut blank = true;
+    for line in html.lines().map(str::trim) {
+        // Runs of empty blocks leave at most one blank line
+        if line.is_empty() {
+            if !blank {
+                text.push('\n');
+                blank = true;
+            }
+            continue;
+        }
+        text.push_str(line);
+        text.push('\n');
+        blank = false;
+    }
+    text.trim_end().to_string()
+}
+
+/// An HTML body as markdown: headings, emphasis, links, images, lists, quotes, rules and
+/// preformatted blocks are kept, and the rest reduced to text as by `html_to_text`
+pub fn html_to_markdown(html: &str) -> String {
+    let html = HIDDEN_HTML.replace_all(html, "");
+    let mut converted = String::new();
+    let mut last = 0;
+    for captures in PRE_BLOCK.captures_iter(&html) {
+        let block = captures.get(0).expect("a match has a whole group");
+        converted.push_str(&inline_markdown(&html[last..block.start()]));
+        let code = TAG.replace_all(&captures[1], "");
+        let code = decode_entities(&code).replace('\u{a0}', " ");
+        converted.push_str(&format!("\n\n```\n{}\n```\n\n", code.trim_matches('\n')));
+        last = block.end();
+    }
+    converted.push_str(&inline_markdown(&html[last..]));
+
+    let quote = |depth: usize| "> ".repeat(depth);
+    let mut markdown = String::new();
+    let mut depth = 0;
+    let mut in_code = false;
+    // Where the blank line last written starts, until a line of text follows it
+    let mut blank = Some(0);
+    for line in converted.lines() {
+        if !in_code && (line == QUOTE_START || line == QUOTE_END) {
+            // Quotes are set off by a blank line at the shallower depth, so leaving one replaces
+            // the blank line already inside it
+            if line == QUOTE_END {
+                depth = usize::saturating_sub(depth, 1);
+                if let Some(start) = blank {
+                    markdown.truncate(start);
+                }
+            }
+            if line == QUOTE_END || blank.is_none() {
+                blank = Some(markdown.len());
+                markdown.push_str(quote(depth).trim_end());
+                markdown.push('\n');
+            }
+            if line == QUOTE_START {
+                depth += 1;
+            }
+            continue;
+        }
+        let line = if in_code {
+            line.trim_end()
+        } else {
+            line.trim()
+        };
+        if line == "```" {
+            in_code = !in_code;
+        }
+        if line.is_empty() && !in_code {
+            if blank.is_none() {
+                blank = Some(markdown.len());
+                markdown.push_str(quote(depth).trim_end());
+                markdown.push('\n');
+            }
+            continue;
+        }
+        markdown.push_str(&quote(depth));
+        markdown.push_str(line);
+        markdown.push('\n');
+        blank = None;
+    }
+    markdown.trim().to_string()
+}
+
+/// Markdown for HTML outside preformatted blocks, one block per line, with blockquotes on lines
+/// of their own between `QUOTE_START` and `QUOTE_END`
+fn inline_markdown(html: &str) -> String {
+    let html = WHITESPACE.replace_all(html, " ");
+    let html = IMAGE.replace_all(&html, |captures: &regex::Captures| {
+        let (src, alt) = (
+            attribute(&captures[1], "src"),
+            attribute(&captures[1], "alt"),
+        );
+        let alt = alt.as_deref().map(str::trim).unwrap_or_default();
+        match src {
+            // Images only a mail client can show, such as cid: references, are named at most
+            Some(src) if src.starts_with("http://") || src.starts_with("https://") => {
+                format!("![{alt}]({})", src.replace(' ', "%20").replace(')', "%29"))
+            }
+            _ if !alt.is_empty() => format!("[image: {alt}]"),
+            _ => String::new(),
+        }
+    });
+    let html = LINK.replace_all(&html, |captures: &regex::Captures| {
+        let text = TAG.replace_all(&captures[2], "");
+        let text = text.trim();
+        match attribute(&captures[1], "href") {
+            _ if text.is_empty() => String::new(),
+            Some(href) if !href.starts_with('#') && !href.starts_with("javascript:") => {
+                format!("[{text}]({})", href.replace(' ', "%20").replace(')', "%29"))
+            }
+            _ => text.to_string(),
+        }
+    });
+    let html = HEADING.replace_all(&html, |captures: &regex::Captures| {
+        let level: usize = captures[1].parse().unwrap_or(1);
+        format!("\n\n{} ", "#".repeat(level))
+    });
+    let html = HEADING_END.replace_all(&html, "\n\n");
+    let html = STRONG.replace_all(&html, "**");
+    let html = EMPHASIS.replace_all(&html, "_");
+    let html = CODE.replace_all(&html, "`");
+    let html = BLOCKQUOTE.replace_all(&html, format!("\n{QUOTE_START}\n"));
+    let html = BLOCKQUOTE_END.replace_all(&html, format!("\n{QUOTE_END}\n"));
+    let html = RULE.replace_all(&html, "\n\n---\n\n");
+    let html = LIST_ITEM.replace_all(&html, "\n- ");
+    let html = PARAGRAPH_TAG.replace_all(&html, "\n\n");
+    let html = BLOCK_TAG.replace_all(&html, "\n");
+    let html = TAG.replace_all(&html, "");
+    decode_entities(&html).replace('\u{a0}', " ")
+}
+
+/// The value of an HTML tag's attribute, from the text after the tag name
+fn attribute(attributes: &str, name: &str) -> Option<String> {
+    ATTRIBUTE.captures_iter(attributes).find_map(|captures| {
+        captures[1].eq_ignore_ascii_case(name).then(|| {
+            let value = captures.get(2).or(captures.get(3)).or(captures.get(4));
+            decode_entities(value.map_or("", |value| value.as_str())).into_owned()
+        })
+    })
+}
+
+/// HTML with its character references, such as `&amp;` and `&#8217;`, decoded
+pub fn decode_entities(html: &str) -> Cow<'_, str> {
+    ENTITY.replace_all(html, |captures: &regex::Captures| {
+        let entity = &captures[1];
+        let decoded = match entity.strip_prefix('#') {
+            Some(number) => {
+                let code = match number.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => number.parse().ok(),
+                };
+                code.and_then(char::from_u32)
+            }
+            None => match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some('\u{a0}'),
+                "ndash" => Some('–'),
+                "mdash" => Some('—'),
+                "hellip" => Some('…'),
+                "lsquo" => Some('‘'),
+                "rsquo" => Some('’'),
+                "ldquo" => Some('“'),
+                "rdquo" => Some('”'),
+                "copy" => Some('©'),
+                _ => None,
+            },
+        };
+        decoded.map_or_else(|| captures[0].to_string(), String::from)
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -36,4 +319,70 @@ mod tests {
         assert!(!is_html_content("Plain text"));
         assert!(!is_html_content(""));
     }
+
+    #[test]
+    fn test_html_to_text() {
+        let html = "<html><head><style>p { color: red }</style></head><body>\n\
+                    <p>Hi&nbsp;Jane,</p><p>The <b>plan</b>\n   is:<br>\
+                    <ul><li>ship &amp; test</li><li>celebrate &#127881;</li></ul></p>\
+                    <!-- tracking --><div></div><div></div><p>Bob</p></body></html>";
+        assert_eq!(
+            html_to_text(html),
+            "Hi Jane,\n\nThe plan is:\n\n- ship & test\n- celebrate 🎉\n\nBob"
+        );
+    }
+
+    #[test]
+    fn test_html_to_markdown() {
+        let html = "<html><body><h2>Launch&nbsp;plan</h2>\
+                    <p>Hi <b>team</b>, see <a href=\"https://example.com/plan?a=1&amp;b=2\">the \
+                    <i>plan</i></a> or mail <a href=\"mailto:ops@example.com\">ops@example.com</a>.\
+                    <img src=\"cid:logo\" alt=\"logo\"><img src=\"https://t.example.com/p.gif\"></p>\
+                    <ol><li>ship</li><li>test</li></ol><hr>\
+                    <pre>fn main() {\n    run();\n}</pre>\
+                    <div class=\"gmail_quote\">On Monday Bob wrote:<blockquote><p>Ready?</p>\
+                    <blockquote>Soon</blockquote></blockquote></div><p>Thanks</p></body></html>";
+        assert_eq!(
+            html_to_markdown(html),
+            "## Launch plan\n\n\
+             Hi **team**, see [the plan](https://example.com/plan?a=1&b=2) or mail \
+             [ops@example.com](mailto:ops@example.com).[image: logo]![](https://t.example.com/p.gif)\n\n\
+             - ship\n- test\n\n---\n\n\
+             ```\nfn main() {\n    run();\n}\n```\n\n\
+             On Monday Bob wrote:\n\n> Ready?\n>\n> > Soon\n\nThanks"
+        );
+    }
+
+    #[test]
+    fn test_ascii_domain_address() {
+        assert_eq!(
+            ascii_domain_address("jane@example.com").as_deref(),
+            Some("jane@example.com")
+        );
+        assert_eq!(
+            ascii_domain_address("jörg@münchen.de").as_deref(),
+            Some("jörg@xn--mnchen-3ya.de")
+        );
+        assert_eq!(
+            ascii_domain_address("用户@例子.广告").as_deref(),
+            Some("用户@xn--fsqu00a.xn--4rr70v")
+        );
+        assert_eq!(ascii_domain_address("jane"), None);
+        assert_eq!(ascii_domain_address("@example.com"), None);
+        assert_eq!(ascii_domain_address("jane@"), None);
+        assert_eq!(ascii_domain_address("jane@a@example.com"), None);
+        assert_eq!(ascii_domain_address("ja ne@example.com"), None);
+    }
+
+    #[test]
+    fn test_unicode_domains() {
+        assert_eq!(
+            unicode_domains("Jörg <jörg@xn--mnchen-3ya.de>, bob@example.com"),
+            "Jörg <jörg@münchen.de>, bob@example.com"
+        );
+        assert!(matches!(
+            unicode_domains("Jane <jane@example.com>"),
+            Cow::Borrowed(_)
+        ));
+    }
 }
@@ -0,0 +1,743 @@
+use anyhow::{Context, Result};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE},
+    Engine,
+};
+use chrono::Utc;
+use lopdf::content::{Content, Operation};
+use lopdf::{dictionary, Dictionary, Document, Object, Stream};
+use rmcp::schemars;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{instrument, warn};
+
+use crate::email::html_to_text;
+use crate::gmail::{GmailClient, GmailServer, GMAIL_API_BASE};
+use crate::mime::{leaf_parts, part_header};
+use crate::oauth::SCOPE_READONLY;
+use crate::pgp::decrypt_message;
+use crate::preview::{decode_image, image_format};
+use crate::tools::{download_path, part_data};
+use crate::utils::format_size;
+
+/// US Letter, in points
+const PAGE_WIDTH: f32 = 612.0;
+const PAGE_HEIGHT: f32 = 792.0;
+const MARGIN: f32 = 54.0;
+/// Characters per line of body text; Helvetica averages about half its size per character
+const WRAP_CHARS: usize = 90;
+/// Tallest an inline image is drawn in a PDF, in points
+const MAX_IMAGE_HEIGHT: f32 = 320.0;
+/// Most pixels across an image embedded in a PDF is kept at, about 150 dpi on the page
+const MAX_IMAGE_PIXELS: u32 = 1000;
+
+/// File type of an exported thread
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    schemars::JsonSchema,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// A single HTML file with the images embedded
+    #[default]
+    Html,
+    /// A PDF of the messages' text and images
+    Pdf,
+}
+
+impl ExportFormat {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Html => "html",
+            Self::Pdf => "pdf",
+        }
+    }
+}
+
+/// A message as it's laid out in an export
+#[derive(Debug, Default)]
+struct ExportedMessage {
+    /// From, To, Cc and Date, in that order, where the message has them
+    headers: Vec<(&'static str, String)>,
+    subject: String,
+    text: Option<String>,
+    html: Option<String>,
+    inline_images: Vec<InlineImage>,
+    attachments: Vec<AttachmentEntry>,
+}
+
+/// An image shown in a message's body, such as a logo or a pasted screenshot
+#[derive(Debug)]
+struct InlineImage {
+    content_id: Option<String>,
+    filename: String,
+    mime_type: String,
+    data: Vec<u8>,
+}
+
+impl InlineImage {
+    fn data_uri(&self) -> String {
+        format!(
+            "data:{};base64,{}",
+            self.mime_type,
+            STANDARD.encode(&self.data)
+        )
+    }
+}
+
+#[derive(Debug)]
+struct AttachmentEntry {
+    filename: String,
+    mime_type: String,
+    size: u64,
+}
+
+fn decode_text(data: &str) -> Option<String> {
+    let bytes = URL_SAFE.decode(data).ok()?;
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Sort a full-format message's parts into its bodies, inline images and attachments,
+/// downloading the inline images
+async fn export_message(
+    gmail_server: &GmailServer,
+    client: &GmailClient,
+    message: &Value,
+) -> Result<ExportedMessage> {
+    let message_id = message["id"].as_str().unwrap_or_default();
+    let payload = &message["payload"];
+    let mut exported = ExportedMessage {
+        subject: part_header(payload, "Subject")
+            .unwrap_or_default()
+            .to_string(),
+        ..Default::default()
+    };
+    for name in ["From", "To", "Cc", "Date"] {
+        if let Some(value) = part_header(payload, name) {
+            exported.headers.push((name, value.to_string()));
+        }
+    }
+
+    let mut leaves = Vec::new();
+    leaf_parts(payload, &mut leaves);
+    for part in leaves {
+        let mime_type = part["mimeType"].as_str().unwrap_or_default();
+        let filename = part["filename"].as_str().unwrap_or_default();
+        let content_id = part_header(part, "Content-ID")
+            .map(|id| id.trim().trim_start_matches('<').trim_end_matches('>'));
+        let is_attachment = part_header(part, "Content-Disposition")
+            .is_some_and(|value| value.trim().to_ascii_lowercase().starts_with("attachment"));
+
+        if mime_type.starts_with("image/") && content_id.is_some() && !is_attachment {
+            let data = match part_data(client, gmail_server.user_id(), message_id, part).await {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Leaving out an inline image of {}: {:#}", message_id, e);
+                    continue;
+                }
+            };
+            if let Some(scanner) = gmail_server.attachment_scanner() {
+                if let Err(e) = scanner.check(filename, &data).await {
+                    warn!("Leaving out an inline image of {}: {:#}", message_id, e);
+                    continue;
+                }
+            }
+            exported.inline_images.push(InlineImage {
+                content_id: content_id.map(str::to_string),
+                filename: filename.to_string(),
+                mime_type: mime_type.to_string(),
+                data,
+            });
+        } else if !filename.is_empty() {
+            exported.attachments.push(AttachmentEntry {
+                filename: filename.to_string(),
+                mime_type: mime_type.to_string(),
+                size: part["body"]["size"].as_u64().unwrap_or_default(),
+            });
+        } else if let Some(data) = part["body"]["data"].as_str() {
+            match mime_type {
+                "text/plain" if exported.text.is_none() => exported.text = decode_text(data),
+                "text/html" if exported.html.is_none() => exported.html = decode_text(data),
+                _ => {}
+            }
+        }
+    }
+    Ok(exported)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+const HTML_STYLE: &str = "body { font-family: -apple-system, 'Segoe UI', Helvetica, Arial, \
+    sans-serif; max-width: 860px; margin: 2em auto; padding: 0 1em; color: #202124; }
+article { border-top: 1px solid #dadce0; padding: 1em 0; }
+dl { display: grid; grid-template-columns: max-content auto; gap: 0.2em 1em; margin: 0 0 1em; }
+dt { font-weight: 600; color: #5f6368; }
+dd { margin: 0; }
+pre { white-space: pre-wrap; font-family: inherit; }
+iframe { width: 100%; height: 70vh; border: 1px solid #dadce0; }
+img { max-width: 100%; }
+.meta, .attachments { color: #5f6368; }";
+
+/// A self-contained HTML page of a thread. Message HTML is shown in sandboxed frames, and the
+/// page's content security policy keeps it from loading anything, such as tracking pixels
+fn render_html(thread_id: &str, messages: &[ExportedMessage]) -> String {
+    let subject = messages
+        .first()
+        .map(|message| message.subject.as_str())
+        .filter(|subject| !subject.is_empty())
+        .unwrap_or("(no subject)");
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta http-equiv=\"Content-Security-Policy\" content=\"default-src 'none'; \
+         img-src data:; style-src 'unsafe-inline'\">\n\
+         <title>{title}</title>\n<style>\n{HTML_STYLE}\n</style>\n</head>\n<body>\n\
+         <h1>{title}</h1>\n<p class=\"meta\">Thread {thread_id}, {count} message{plural}, \
+         exported {exported}</p>\n",
+        title = escape_html(subject),
+        thread_id = escape_html(thread_id),
+        count = messages.len(),
+        plural = if messages.len() == 1 { "" } else { "s" },
+        exported = Utc::now().format("%Y-%m-%d %H:%M UTC"),
+    );
+
+    for message in messages {
+        html.push_str("<article>\n<dl>\n");
+        for (name, value) in &message.headers {
+            html.push_str(&format!("<dt>{name}</dt><dd>{}</dd>\n", escape_html(value)));
+        }
+        html.push_str("</dl>\n");
+
+        let mut shown_images = Vec::new();
+        match (&message.html, &message.text) {
+            (Some(body), _) => {
+                // Images the HTML refers to by Content-ID go into it as data URIs
+                let mut body = body.clone();
+                for (index, image) in message.inline_images.iter().enumerate() {
+                    let Some(content_id) = &image.content_id else {
+                        continue;
+                    };
+                    let reference = format!("cid:{content_id}");
+                    if body.contains(&reference) {
+                        body = body.replace(&reference, &image.data_uri());
+                        shown_images.push(index);
+                    }
+                }
+                html.push_str(&format!(
+                    "<iframe sandbox srcdoc=\"{}\"></iframe>\n",
+                    escape_html(&body)
+                ));
+            }
+            (None, Some(text)) => {
+                html.push_str(&format!("<pre>{}</pre>\n", escape_html(text)));
+            }
+            (None, None) => {}
+        }
+        for (index, image) in message.inline_images.iter().enumerate() {
+            if !shown_images.contains(&index) {
+                html.push_str(&format!(
+                    "<p><img src=\"{}\" alt=\"{}\"></p>\n",
+                    image.data_uri(),
+                    escape_html(&image.filename)
+                ));
+            }
+        }
+
+        if !message.attachments.is_empty() {
+            html.push_str("<ul class=\"attachments\">\n");
+            for attachment in &message.attachments {
+                html.push_str(&format!(
+                    "<li>{} ({}, {})</li>\n",
+                    escape_html(&attachment.filename),
+                    escape_html(&attachment.mime_type),
+                    format_size(attachment.size)
+                ));
+            }
+            html.push_str("</ul>\n");
+        }
+        html.push_str("</article>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/// Text in the WinAnsi encoding of the PDF standard fonts, with `?` for what it can't show
+fn win_ansi(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match c {
+            ' '..='~' => c as u8,
+            '\u{a0}'..='\u{ff}' => c as u32 as u8,
+            '€' => 0x80,
+            '‚' => 0x82,
+            '„' => 0x84,
+            '…' => 0x85,
+            '‘' => 0x91,
+            '’' => 0x92,
+            '“' => 0x93,
+            '”' => 0x94,
+            '•' => 0x95,
+            '–' => 0x96,
+            '—' => 0x97,
+            '™' => 0x99,
+            '\t' => b' ',
+            _ => b'?',
+        })
+        .collect()
+}
+
+/// Lines of at most `width` characters, broken between words where possible
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for word in paragraph.split(' ') {
+            let mut word = word;
+            let fits = line.chars().count() + 1 + word.chars().count() <= width;
+            if !line.is_empty() && !fits {
+                lines.push(std::mem::take(&mut line));
+            }
+            // Words longer than a line are split across lines
+            while word.chars().count() > width {
+                let split = word
+                    .char_indices()
+                    .nth(width)
+                    .map_or(word.len(), |(i, _)| i);
+                lines.push(word[..split].to_string());
+                word = &word[split..];
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(word);
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Lays out lines of text and images top to bottom over as many pages as they take
+struct PdfWriter {
+    document: Document,
+    pages: Vec<Vec<Operation>>,
+    images: Dictionary,
+    y: f32,
+}
+
+impl PdfWriter {
+    fn new() -> Self {
+        Self {
+            document: Document::with_version("1.5"),
+            pages: vec![Vec::new()],
+            images: Dictionary::new(),
+            y: PAGE_HEIGHT - MARGIN,
+        }
+    }
+
+    /// Move down by `height`, starting a new page if it doesn't fit on this one
+    fn advance(&mut self, height: f32) {
+        if self.y - height < MARGIN {
+            self.pages.push(Vec::new());
+            self.y = PAGE_HEIGHT - MARGIN;
+        }
+        self.y -= height;
+    }
+
+    fn gap(&mut self, height: f32) {
+        self.y -= height;
+    }
+
+    fn line(&mut self, text: &str, size: f32, bold: bool) {
+        self.advance(size * 1.3);
+        let font = if bold { "F2" } else { "F1" };
+        let page = self.pages.last_mut().expect("there is always a page");
+        page.extend([
+            Operation::new("BT", vec![]),
+            Operation::new("Tf", vec![Object::Name(font.into()), size.into()]),
+            Operation::new("Td", vec![MARGIN.into(), self.y.into()]),
+            Operation::new("Tj", vec![Object::string_literal(win_ansi(text))]),
+            Operation::new("ET", vec![]),
+        ]);
+    }
+
+    fn paragraph(&mut self, text: &str, size: f32) {
+        for line in wrap(text, WRAP_CHARS) {
+            self.line(&line, size, false);
+        }
+    }
+
+    /// Draw an image at its natural size, shrunk to fit the page width and `MAX_IMAGE_HEIGHT`
+    fn image(&mut self, image: &InlineImage) -> Result<()> {
+        let format = image_format(&image.mime_type, &image.filename)
+            .context("Unsupported inline image type")?;
+        let decoded = decode_image(&image.data, format)?;
+        let decoded = if decoded.width().max(decoded.height()) > MAX_IMAGE_PIXELS {
+            decoded.thumbnail(MAX_IMAGE_PIXELS, MAX_IMAGE_PIXELS)
+        } else {
+            decoded
+        };
+        let rgb = decoded.to_rgb8();
+        let (pixels_wide, pixels_high) = rgb.dimensions();
+        let mut stream = Stream::new(
+            dictionary! {
+                "Type" => "XObject",
+                "Subtype" => "Image",
+                "Width" => pixels_wide,
+                "Height" => pixels_high,
+                "ColorSpace" => "DeviceRGB",
+                "BitsPerComponent" => 8,
+            },
+            rgb.into_raw(),
+        );
+        stream.compress()?;
+        let image_id = self.document.add_object(stream);
+        let name = format!("Im{}", self.images.len());
+        self.images.set(name.clone(), image_id);
+
+        let scale = ((PAGE_WIDTH - 2.0 * MARGIN) / pixels_wide as f32)
+            .min(MAX_IMAGE_HEIGHT / pixels_high as f32)
+            .min(1.0);
+        let (width, height) = (pixels_wide as f32 * scale, pixels_high as f32 * scale);
+        self.advance(height);
+        let page = self.pages.last_mut().expect("there is always a page");
+        page.extend([
+            Operation::new("q", vec![]),
+            Operation::new(
+                "cm",
+                vec![
+                    width.into(),
+                    0.into(),
+                    0.into(),
+                    height.into(),
+                    MARGIN.into(),
+                    self.y.into(),
+                ],
+            ),
+            Operation::new("Do", vec![Object::Name(name.into_bytes())]),
+            Operation::new("Q", vec![]),
+        ]);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>> {
+        let font = |name: &str| {
+            dictionary! {
+                "Type" => "Font",
+                "Subtype" => "Type1",
+                "BaseFont" => Object::Name(name.into()),
+                "Encoding" => "WinAnsiEncoding",
+            }
+        };
+        let regular = self.document.add_object(font("Helvetica"));
+        let bold = self.document.add_object(font("Helvetica-Bold"));
+        let resources = self.document.add_object(dictionary! {
+            "Font" => dictionary! { "F1" => regular, "F2" => bold },
+            "XObject" => self.images,
+        });
+
+        let pages_id = self.document.new_object_id();
+        let mut kids = Vec::new();
+        for operations in self.pages {
+            let mut contents = Stream::new(Dictionary::new(), Content { operations }.encode()?);
+            contents.compress()?;
+            let contents_id = self.document.add_object(contents);
+            let page_id = self.document.add_object(dictionary! {
+                "Type" => "Page",
+                "Parent" => pages_id,
+                "Contents" => contents_id,
+            });
+            kids.push(page_id.into());
+        }
+        let count = kids.len() as i64;
+        self.document.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => kids,
+                "Count" => count,
+                "Resources" => resources,
+                "MediaBox" => vec![0.into(), 0.into(), PAGE_WIDTH.into(), PAGE_HEIGHT.into()],
+            }),
+        );
+        let catalog_id = self.document.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        self.document.trailer.set("Root", catalog_id);
+
+        let mut pdf = Vec::new();
+        self.document
+            .save_to(&mut pdf)
+            .context("Failed to write PDF")?;
+        Ok(pdf)
+    }
+}
+
+/// A PDF of a thread's headers, body text, inline images and attachment list. HTML bodies are
+/// reduced to their text
+fn render_pdf(thread_id: &str, messages: &[ExportedMessage]) -> Result<Vec<u8>> {
+    let subject = messages
+        .first()
+        .map(|message| message.subject.as_str())
+        .filter(|subject| !subject.is_empty())
+        .unwrap_or("(no subject)");
+    let mut pdf = PdfWriter::new();
+    for line in wrap(subject, WRAP_CHARS * 10 / 16) {
+        pdf.line(&line, 16.0, true);
+    }
+    pdf.line(
+        &format!(
+            "Thread {thread_id}, {} message{}, exported {}",
+            messages.len(),
+            if messages.len() == 1 { "" } else { "s" },
+            Utc::now().format("%Y-%m-%d %H:%M UTC")
+        ),
+        9.0,
+        false,
+    );
+
+    for message in messages {
+        pdf.gap(14.0);
+        for (name, value) in &message.headers {
+            for (index, line) in wrap(&format!("{name}: {value}"), WRAP_CHARS)
+                .iter()
+                .enumerate()
+            {
+                pdf.line(line, 10.0, index == 0 && *name == "From");
+            }
+        }
+        pdf.gap(6.0);
+        let body = match (&message.text, &message.html) {
+            (Some(text), _) => Some(text.clone()),
+            (None, Some(html)) => Some(html_to_text(html)),
+            (None, None) => None,
+        };
+        if let Some(body) = body {
+            pdf.paragraph(body.trim_end(), 10.0);
+        }
+        for image in &message.inline_images {
+            pdf.gap(6.0);
+            if let Err(e) = pdf.image(image) {
+                warn!("Leaving an inline image out of the PDF: {:#}", e);
+                pdf.line(&format!("[image: {}]", image.filename), 9.0, false);
+            }
+        }
+        if !message.attachments.is_empty() {
+            pdf.gap(6.0);
+            pdf.line("Attachments", 10.0, true);
+            for attachment in &message.attachments {
+                pdf.paragraph(
+                    &format!(
+                        "- {} ({}, {})",
+                        attachment.filename,
+                        attachment.mime_type,
+                        format_size(attachment.size)
+                    ),
+                    10.0,
+                );
+            }
+        }
+    }
+    pdf.finish()
+}
+
+/// Render a thread as one self-contained HTML file or PDF in the download directory, for sharing
+/// or archiving outside Gmail
+#[instrument(name = "tool", skip_all, fields(tool = "export_thread", thread_id = %thread_id))]
+pub async fn export_thread(
+    gmail_server: &GmailServer,
+    thread_id: &str,
+    format: ExportFormat,
+    download_dir: Option<&str>,
+) -> Result<Value> {
+    gmail_server.check_authentication(&[SCOPE_READONLY]).await?;
+
+    let client = gmail_server.authenticated_client().await?;
+    let user_id = gmail_server.user_id();
+    let url = format!(
+        "{GMAIL_API_BASE}/users/{user_id}/threads/{}?format=full",
+        urlencoding::encode(thread_id)
+    );
+    let thread = client
+        .get_json(&url)
+        .await
+        .context("Failed to get thread")?;
+
+    let mut messages = Vec::new();
+    for message in thread["messages"].as_array().into_iter().flatten() {
+        let mut message = message.clone();
+        let message_id = message["id"].as_str().unwrap_or_default().to_string();
+        decrypt_message(gmail_server, &client, &message_id, &mut message).await;
+        messages.push(export_message(gmail_server, &client, &message).await?);
+    }
+    if messages.is_empty() {
+        anyhow::bail!("Thread {thread_id} has no messages");
+    }
+
+    let contents = match format {
+        ExportFormat::Html => render_html(thread_id, &messages).into_bytes(),
+        ExportFormat::Pdf => render_pdf(thread_id, &messages)?,
+    };
+
+    let directory = download_path(download_dir);
+    std::fs::create_dir_all(&directory).context("Failed to create download directory")?;
+    // Thread IDs are hex, but keep anything else out of the file name
+    let safe_id: String = thread_id
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect();
+    let path = directory.join(format!("thread-{safe_id}.{}", format.as_str()));
+    std::fs::write(&path, &contents).context("Failed to write export file")?;
+
+    Ok(json!({
+        "thread_id": thread_id,
+        "format": format.as_str(),
+        "path": path.to_string_lossy(),
+        "size": contents.len(),
+        "messages": messages.len(),
+        "inline_images": messages.iter().map(|m| m.inline_images.len()).sum::<usize>(),
+        "attachments": messages.iter().map(|m| m.attachments.len()).sum::<usize>(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockGmail;
+
+    fn message_with_inline_image() -> ExportedMessage {
+        ExportedMessage {
+            headers: vec![("From", "Alice <alice@example.com>".to_string())],
+            subject: "Logo <draft>".to_string(),
+            html: Some(r#"<p>Our new logo:</p><img src="cid:logo@example.com">"#.to_string()),
+            inline_images: vec![InlineImage {
+                content_id: Some("logo@example.com".to_string()),
+                filename: "logo.png".to_string(),
+                mime_type: "image/png".to_string(),
+                data: b"png".to_vec(),
+            }],
+            attachments: vec![AttachmentEntry {
+                filename: "brief.pdf".to_string(),
+                mime_type: "application/pdf".to_string(),
+                size: 1_234_567,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_render_html() {
+        let html = render_html("t1", &[message_with_inline_image()]);
+        assert!(html.contains("<title>Logo &lt;draft&gt;</title>"));
+        assert!(html.contains("<dt>From</dt><dd>Alice &lt;alice@example.com&gt;</dd>"));
+        // The body is escaped into a sandboxed frame, with the image inlined where it's used
+        assert!(html.contains("<iframe sandbox srcdoc=\"&lt;p&gt;Our new logo:"));
+        assert!(html.contains("src=&quot;data:image/png;base64,cG5n&quot;"));
+        assert!(!html.contains("cid:"));
+        assert!(!html.contains("<img src="));
+        assert!(html.contains("<li>brief.pdf (application/pdf, 1.2 MB)</li>"));
+    }
+
+    #[test]
+    fn test_render_pdf_with_image() {
+        let mut png = Vec::new();
+        image::RgbImage::new(2000, 100)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let mut message = message_with_inline_image();
+        message.inline_images[0].data = png;
+        let broken = InlineImage {
+            content_id: None,
+            filename: "broken.png".to_string(),
+            mime_type: "image/png".to_string(),
+            data: b"not a png".to_vec(),
+        };
+        message.inline_images.push(broken);
+
+        let document = Document::load_mem(&render_pdf("t1", &[message]).unwrap()).unwrap();
+        let text = document.extract_text(&[1]).unwrap();
+        assert!(text.contains("Our new logo:"));
+        assert!(text.contains("[image: broken.png]"));
+        assert!(text.contains("brief.pdf (application/pdf, 1.2 MB)"));
+        // The wide image is scaled down before it's embedded
+        let image = document
+            .objects
+            .values()
+            .filter_map(|object| object.as_stream().ok())
+            .find(|stream| stream.dict.has_type(b"XObject"))
+            .unwrap();
+        assert_eq!(image.dict.get(b"Width").unwrap().as_i64().unwrap(), 1000);
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(wrap("one two three", 7), ["one two", "three"]);
+        assert_eq!(wrap("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+        assert_eq!(wrap("a\n\nb", 4), ["a", "", "b"]);
+    }
+
+    #[test]
+    fn test_win_ansi() {
+        assert_eq!(win_ansi("Café – “ok” 😀"), b"Caf\xe9 \x96 \x93ok\x94 ?");
+    }
+
+    #[tokio::test]
+    async fn test_export_thread_against_mock_gmail() {
+        let gmail = MockGmail::start().await;
+        let gmail_server = gmail.gmail_server(&gmail.config()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let download_dir = dir.path().to_str().unwrap();
+
+        let result = export_thread(
+            &gmail_server,
+            "thread-1",
+            ExportFormat::Html,
+            Some(download_dir),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result["messages"], 2);
+        assert_eq!(result["attachments"], 1);
+        let html = std::fs::read_to_string(dir.path().join("thread-thread-1.html")).unwrap();
+        assert!(html.contains("<h1>Quarterly report</h1>"));
+        assert!(html.contains("<pre>Notes from today&#39;s meeting are attached.</pre>"));
+        assert!(html.contains("<li>notes.txt (text/plain, 31 bytes)</li>"));
+
+        let result = export_thread(
+            &gmail_server,
+            "thread-1",
+            ExportFormat::Pdf,
+            Some(download_dir),
+        )
+        .await
+        .unwrap();
+        let pdf = std::fs::read(dir.path().join("thread-thread-1.pdf")).unwrap();
+        assert_eq!(result["size"], pdf.len());
+        let document = Document::load_mem(&pdf).unwrap();
+        assert_eq!(document.get_pages().len(), 1);
+        let text = document.extract_text(&[1]).unwrap();
+        assert!(text.contains("Quarterly report"));
+        assert!(text.contains("Sounds good, see you Friday."));
+
+        assert!(export_thread(
+            &gmail_server,
+            "missing",
+            ExportFormat::Html,
+            Some(download_dir)
+        )
+        .await
+        .is_err());
+    }
+}
@@ -7,7 +7,7 @@ pub fn extract_text_from_bytes(data: &[u8], mime_type: &str, filename: &str) ->
         "application/vnd.openxmlformats-officedocument.wordprocessingml.document" => {
             extract_docx_text(data)
         }
-        "text/plain" => Ok(String::from_utf8(data.to_vec())?),
+        "text/plain" | "text/csv" => Ok(String::from_utf8(data.to_vec())?),
         _ => {
             // Try to infer from filename
             let lower_filename = filename.to_lowercase();
@@ -15,7 +15,7 @@ pub fn extract_text_from_bytes(data: &[u8], mime_type: &str, filename: &str) ->
                 extract_pdf_text(data)
             } else if lower_filename.ends_with(".docx") {
                 extract_docx_text(data)
-            } else if lower_filename.ends_with(".txt") {
+            } else if lower_filename.ends_with(".txt") || lower_filename.ends_with(".csv") {
                 Ok(String::from_utf8(data.to_vec())?)
             } else {
                 Err(anyhow::anyhow!("Unsupported file type: {mime_type}"))
@@ -63,12 +63,13 @@ pub fn is_extractable_document(mime_type: &str, filename: &str) -> bool {
     match mime_type {
         "application/pdf" => true,
         "application/vnd.openxmlformats-officedocument.wordprocessingml.document" => true,
-        "text/plain" => true,
+        "text/plain" | "text/csv" => true,
         _ => {
             let lower_filename = filename.to_lowercase();
             lower_filename.ends_with(".pdf")
                 || lower_filename.ends_with(".docx")
                 || lower_filename.ends_with(".txt")
+                || lower_filename.ends_with(".csv")
         }
     }
 }
@@ -0,0 +1,318 @@
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Method, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::PathBuf;
+use tracing::{debug, info, warn};
+
+use crate::config::{Config, FixtureMode};
+use crate::redact::pseudonymize_emails;
+
+/// Gmail API responses saved to files by `--gmail-fixtures record`, and served from them by
+/// `--gmail-fixtures replay` instead of calling Gmail
+pub struct Fixtures {
+    mode: FixtureMode,
+    dir: PathBuf,
+}
+
+/// One recorded response
+#[derive(Serialize, Deserialize)]
+struct Fixture {
+    method: String,
+    /// Path and query of the call, with email addresses pseudonymized
+    path: String,
+    status: u16,
+    content_type: Option<String>,
+    /// JSON bodies are kept as JSON so the files can be read and edited
+    body: Value,
+}
+
+impl Fixtures {
+    /// The `--gmail-fixtures` mode and directory, or None to call Gmail as usual
+    pub fn open(config: &Config) -> Result<Option<Self>> {
+        let Some(mode) = config.gmail_api.gmail_fixtures else {
+            return Ok(None);
+        };
+        let dir = match &config.gmail_api.gmail_fixtures_dir {
+            Some(dir) => dir.clone(),
+            None => crate::utils::get_app_data_dir(config)?.join("fixtures"),
+        };
+        match mode {
+            FixtureMode::Record => {
+                std::fs::create_dir_all(&dir).with_context(|| {
+                    format!("Failed to create fixtures directory {}", dir.display())
+                })?;
+                info!("🎞️ Recording Gmail API responses to {}", dir.display());
+            }
+            FixtureMode::Replay => {
+                if !dir.is_dir() {
+                    anyhow::bail!("No fixtures to replay at {}", dir.display());
+                }
+                info!(
+                    "🎞️ Replaying Gmail API responses from {} instead of calling Gmail",
+                    dir.display()
+                );
+            }
+        }
+        Ok(Some(Self { mode, dir }))
+    }
+
+    pub fn replaying(&self) -> bool {
+        self.mode == FixtureMode::Replay
+    }
+
+    /// The recorded response to a call, by method, path and query
+    pub fn replay(&self, method: &Method, path: &str) -> Result<Response> {
+        let file = self.dir.join(fixture_name(method, path));
+        let fixture = std::fs::read_to_string(&file).with_context(|| {
+            format!(
+                "No recorded response to {method} {} at {}",
+                pseudonymize_emails(path),
+                file.display()
+            )
+        })?;
+        let fixture: Fixture = serde_json::from_str(&fixture)
+            .with_context(|| format!("Invalid fixture {}", file.display()))?;
+        debug!(file = %file.display(), "Replaying Gmail API response");
+
+        let body = match fixture.body {
+            Value::String(text) if !is_json(fixture.content_type.as_deref()) => text.into_bytes(),
+            body => serde_json::to_vec(&body)?,
+        };
+        let mut response = axum::http::Response::builder().status(fixture.status);
+        if let Some(content_type) = fixture.content_type {
+            response = response.header(CONTENT_TYPE, content_type);
+        }
+        Ok(Response::from(response.body(body)?))
+    }
+
+    /// Save a sanitized copy of the response to a call, returning the response to use in its
+    /// place since its body has been read. A failure to save is logged and doesn't fail the call
+    pub async fn record(
+        &self,
+        method: &Method,
+        path: &str,
+        response: Response,
+    ) -> Result<Response> {
+        let status = response.status();
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let bytes = response
+            .bytes()
+            .await
+            .context("Failed to read Gmail API response")?;
+
+        let body = match serde_json::from_slice::<Value>(&bytes) {
+            Ok(json) if is_json(content_type.as_deref()) => json,
+            _ => Value::String(String::from_utf8_lossy(&bytes).into_owned()),
+        };
+        let fixture = Fixture {
+            method: method.to_string(),
+            path: pseudonymize_emails(path).into_owned(),
+            status: status.as_u16(),
+            content_type: content_type.clone(),
+            body: sanitize(body),
+        };
+        let file = self.dir.join(fixture_name(method, path));
+        let saved = serde_json::to_string_pretty(&fixture)
+            .map_err(anyhow::Error::from)
+            .and_then(|fixture| Ok(std::fs::write(&file, fixture)?));
+        if let Err(e) = saved {
+            warn!("Failed to record fixture {}: {:#}", file.display(), e);
+        }
+
+        let mut response = axum::http::Response::builder().status(status);
+        if let Some(content_type) = content_type {
+            response = response.header(CONTENT_TYPE, content_type);
+        }
+        Ok(Response::from(response.body(bytes)?))
+    }
+}
+
+fn is_json(content_type: Option<&str>) -> bool {
+    content_type.is_some_and(|content_type| content_type.contains("json"))
+}
+
+/// File a call's response is kept in: readable, and unique to the method, path and query
+fn fixture_name(method: &Method, path: &str) -> String {
+    let key = format!("{method} {path}");
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    let resource: String = path
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches("/gmail/v1/users/")
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .take(60)
+        .collect();
+    format!(
+        "{}-{resource}-{hash:016x}.json",
+        method.as_str().to_lowercase()
+    )
+}
+
+/// Pseudonymize email addresses in every string, including text bodies and attachments that
+/// Gmail sends base64url encoded. Binary attachments are kept as they are
+fn sanitize(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        Value::String(data) if key == "data" || key == "raw" => {
+                            Value::String(sanitize_base64(data))
+                        }
+                        value => sanitize(value),
+                    };
+                    (key, value)
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(sanitize).collect()),
+        Value::String(text) => Value::String(pseudonymize_emails(&text).into_owned()),
+        other => other,
+    }
+}
+
+fn sanitize_base64(data: String) -> String {
+    let text = URL_SAFE
+        .decode(&data)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok());
+    match text {
+        Some(text) => URL_SAFE.encode(pseudonymize_emails(&text).as_bytes()),
+        None => data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HttpConfig;
+    use crate::gmail::GmailServer;
+    use crate::oauth::OAuthManager;
+    use crate::test_support::MockGmail;
+    use crate::tools::{self, MessageFormat};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_fixture_name() {
+        let name = fixture_name(&Method::GET, "/gmail/v1/users/me/threads?q=in%3Ainbox");
+        assert!(name.starts_with("get-me_threads-"));
+        assert!(name.ends_with(".json"));
+        assert_eq!(
+            name,
+            fixture_name(&Method::GET, "/gmail/v1/users/me/threads?q=in%3Ainbox")
+        );
+        assert_ne!(
+            name,
+            fixture_name(&Method::GET, "/gmail/v1/users/me/threads?q=is%3Aunread")
+        );
+        assert_ne!(
+            name,
+            fixture_name(&Method::POST, "/gmail/v1/users/me/threads?q=in%3Ainbox")
+        );
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let text = URL_SAFE.encode("Write to alice@example.org");
+        let binary = URL_SAFE.encode([0xff, 0xfe, 0x00]);
+        let sanitized = sanitize(json!({
+            "payload": {
+                "headers": [{ "name": "From", "value": "Alice <alice@example.org>" }],
+                "parts": [
+                    { "body": { "data": text } },
+                    { "body": { "data": binary } },
+                ],
+            },
+        }));
+        let from = sanitized["payload"]["headers"][0]["value"]
+            .as_str()
+            .unwrap();
+        let address = from.trim_start_matches("Alice <").trim_end_matches('>');
+        assert!(address.ends_with("@example.com"));
+        let body = URL_SAFE
+            .decode(
+                sanitized["payload"]["parts"][0]["body"]["data"]
+                    .as_str()
+                    .unwrap(),
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            format!("Write to {address}")
+        );
+        assert_eq!(sanitized["payload"]["parts"][1]["body"]["data"], binary);
+    }
+
+    #[tokio::test]
+    async fn test_record_then_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let gmail = MockGmail::start().await;
+        let mut config = gmail.config();
+        config.app_data_dir = Some(dir.path().to_path_buf());
+        config.gmail_api.gmail_fixtures = Some(FixtureMode::Record);
+        let recording = gmail.gmail_server(&config).await;
+
+        let thread_ids = ["thread-1".to_string()];
+        let search = |server: Arc<GmailServer>| async move {
+            tools::search_threads(
+                &server,
+                "in:inbox",
+                10,
+                None,
+                &tools::SearchFilter::default(),
+            )
+            .await
+        };
+        let fetch = |server: Arc<GmailServer>| {
+            let thread_ids = thread_ids.clone();
+            async move {
+                tools::fetch_email_bodies(&server, &thread_ids, MessageFormat::Full, None).await
+            }
+        };
+        let searched = search(recording.clone()).await.unwrap();
+        let fetched = fetch(recording).await.unwrap();
+        assert!(dir.path().join("fixtures").read_dir().unwrap().count() >= 4);
+
+        // Replays need neither the API nor a token
+        config.gmail_api.gmail_api_url = Some("http://127.0.0.1:9".to_string());
+        config.gmail_api.gmail_fixtures = Some(FixtureMode::Replay);
+        let oauth_manager =
+            Arc::new(OAuthManager::in_memory(config.clone(), HttpConfig::default()).unwrap());
+        let replaying = Arc::new(GmailServer::new(oauth_manager, &config).unwrap());
+        let replayed = search(replaying.clone()).await.unwrap();
+        assert_eq!(replayed, searched);
+        let replayed = fetch(replaying.clone()).await.unwrap();
+        assert_eq!(
+            replayed["threads"][0]["messages"][0]["body"],
+            fetched["threads"][0]["messages"][0]["body"]
+        );
+        // Addresses were replaced when recording
+        let from = replayed["threads"][0]["messages"][0]["from"]
+            .as_str()
+            .unwrap();
+        assert!(from.starts_with("Alice <user-") && from.ends_with("@example.com>"));
+
+        let error = tools::search_threads(
+            &replaying,
+            "is:unread",
+            10,
+            None,
+            &tools::SearchFilter::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(format!("{error:#}").contains("No recorded response to GET"));
+    }
+}
@@ -1,61 +1,397 @@
 use anyhow::{Context, Result};
-use reqwest::Client;
+use futures::{stream, StreamExt, TryStreamExt};
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, RETRY_AFTER};
+use reqwest::{Body, Client, Method, RequestBuilder, Response, StatusCode};
+use serde::Serialize;
+use serde_json::Value;
+use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tracing::{field, info, info_span, warn, Instrument};
 
+use crate::audit::AuditLog;
+use crate::batch::{
+    boundary_from_content_type, build_batch_body, parse_batch_response, MAX_BATCH_SIZE,
+};
+use crate::cache::MessageCache;
+use crate::classifier::Classifier;
+use crate::config::{Config, GmailApiConfig};
+use crate::fixtures::Fixtures;
+use crate::metrics::{record_gmail_api_error, GmailEndpoint};
 use crate::oauth;
+use crate::pgp::PgpDecryptor;
+use crate::scan::AttachmentScanner;
+use crate::truncate::ResponseLimit;
+use crate::webhooks::Webhooks;
 
+/// Where Gmail API calls go unless `--gmail-api-url` names another root
+pub const GMAIL_API_ROOT: &str = "https://gmail.googleapis.com";
 pub const GMAIL_API_BASE: &str = "https://gmail.googleapis.com/gmail/v1";
+/// Drive's API lives under another host, but is swapped for the `--gmail-api-url` root too, so
+/// one fake server can stand in for both
+pub const DRIVE_API_ROOT: &str = "https://www.googleapis.com/drive";
+pub const DRIVE_API_BASE: &str = "https://www.googleapis.com/drive/v3";
+pub const GMAIL_BATCH_URL: &str = "https://gmail.googleapis.com/batch/gmail/v1";
+
+/// Errors raised by the Gmail client layer
+#[derive(Debug, thiserror::Error)]
+pub enum GmailError {
+    #[error("Not authenticated: {0}")]
+    NotAuthenticated(AuthFailure),
+    #[error("Re-authentication required: {0}")]
+    ReauthenticationRequired(String),
+    #[error("Missing OAuth scope: {}", .0.join(", "))]
+    InsufficientScope(Vec<String>),
+    /// An error response from the Gmail or Drive API
+    #[error("Gmail API error: {status} - {body}")]
+    Api {
+        status: StatusCode,
+        reason: String,
+        retry_after: Option<Duration>,
+        body: String,
+    },
+}
+
+impl GmailError {
+    /// The error of a Gmail API response that wasn't successful
+    pub async fn from_response(response: Response) -> Self {
+        let status = response.status();
+        let retry_after = retry_after(response.headers());
+        let body = response.text().await.unwrap_or_default();
+        Self::api(status, retry_after, body)
+    }
+
+    fn api(status: StatusCode, retry_after: Option<Duration>, body: String) -> Self {
+        Self::Api {
+            status,
+            reason: error_reason(body.as_bytes()),
+            retry_after,
+            body,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::NotAuthenticated(_) | Self::ReauthenticationRequired(_) => {
+                ErrorKind::AuthRequired
+            }
+            Self::InsufficientScope(_) => ErrorKind::InsufficientScope,
+            Self::Api { status, reason, .. } => match (*status, reason.as_str()) {
+                (StatusCode::UNAUTHORIZED, _) => ErrorKind::AuthRequired,
+                (StatusCode::NOT_FOUND, _) => ErrorKind::NotFound,
+                (StatusCode::TOO_MANY_REQUESTS, _)
+                | (
+                    StatusCode::FORBIDDEN,
+                    "rateLimitExceeded" | "userRateLimitExceeded" | "dailyLimitExceeded",
+                ) => ErrorKind::RateLimited,
+                (StatusCode::BAD_REQUEST, _) => ErrorKind::InvalidArgument,
+                _ => ErrorKind::Other,
+            },
+        }
+    }
+}
+
+/// Why a server has no token it can call Gmail with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailure {
+    /// No token was ever loaded, or it was revoked
+    NoToken,
+    /// The access token expired and there's no refresh token to renew it
+    Expired,
+}
+
+impl AuthFailure {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::NoToken => "no_token",
+            Self::Expired => "token_expired",
+        }
+    }
+}
+
+impl std::fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::NoToken => "no token available",
+            Self::Expired => "the access token expired and there is no refresh token",
+        })
+    }
+}
+
+/// What kind of failure a Gmail call hit, so clients can tell whether to log in again, fix
+/// their arguments, back off or give up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    AuthRequired,
+    InsufficientScope,
+    NotFound,
+    RateLimited,
+    InvalidArgument,
+    Other,
+}
+
+impl ErrorKind {
+    /// The kind of a tool's error, `Other` unless it came from the Gmail client layer
+    pub fn of(error: &anyhow::Error) -> Self {
+        error
+            .downcast_ref::<GmailError>()
+            .map_or(Self::Other, GmailError::kind)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::AuthRequired => "auth_required",
+            Self::InsufficientScope => "insufficient_scope",
+            Self::NotFound => "not_found",
+            Self::RateLimited => "rate_limited",
+            Self::InvalidArgument => "invalid_argument",
+            Self::Other => "internal_error",
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct GmailServer {
     user_id: String,
-    authenticated: Arc<Mutex<bool>>,
     oauth_manager: Arc<oauth::OAuthManager>,
+    /// The mailbox's address, from `getProfile`, until the next login
+    email_address: Arc<std::sync::Mutex<Option<String>>>,
+    http_client: Client,
+    retry_policy: RetryPolicy,
+    batch_size: usize,
+    fetch_concurrency: usize,
+    /// Replaces `GMAIL_API_ROOT` in request URLs, from `--gmail-api-url`
+    api_root: Option<Arc<str>>,
+    /// Records or replays Gmail API responses, with `--gmail-fixtures`
+    fixtures: Option<Arc<Fixtures>>,
+    cache: Option<Arc<MessageCache>>,
+    audit_log: Option<Arc<AuditLog>>,
+    /// Sending tools return the message instead of calling Gmail, with `--dry-run`
+    dry_run: bool,
+    /// Cuts down long tool responses, with `--max-tool-response-bytes`
+    response_limit: Option<Arc<ResponseLimit>>,
+    /// Labels and scores messages, with `--classifier-url` or `--classifier-command`
+    classifier: Option<Arc<Classifier>>,
+    /// Scans attachments before they're saved or returned, with `--clamd-address` or
+    /// `--attachment-scan-command`
+    attachment_scanner: Option<Arc<AttachmentScanner>>,
+    /// Decrypts PGP/MIME messages, with `--pgp-decrypt`
+    pgp_decryptor: Option<Arc<PgpDecryptor>>,
+    /// Tells external URLs about tool calls, sends and new mail, with `--webhook-urls`
+    webhooks: Option<Webhooks>,
+    /// The configured account whose token this is, which snoozes are filed under. None for
+    /// tokens the server doesn't keep
+    account: Option<Arc<str>>,
+    /// Where snoozed threads are kept, except in demo mode
+    snooze_file: Option<Arc<Path>>,
+    /// Where triage rules are kept, except in demo mode
+    rules_file: Option<Arc<Path>>,
+    /// Where the history of scheduled job runs is kept, except in demo mode
+    job_runs_file: Option<Arc<Path>>,
+    /// Where sends are queued, with `--outbox` and except in demo mode
+    outbox_file: Option<Arc<Path>>,
+    /// Where saved searches are kept, except in demo mode
+    saved_searches_file: Option<Arc<Path>>,
 }
 
 impl GmailServer {
-    pub fn new(oauth_manager: Arc<oauth::OAuthManager>) -> Result<Self> {
+    pub fn new(oauth_manager: Arc<oauth::OAuthManager>, config: &Config) -> Result<Self> {
+        let api_config = &config.gmail_api;
+
+        // Build the HTTP client once so all Gmail calls share its connection pool
+        let mut builder = Client::builder();
+        if api_config.gmail_timeout_secs > 0 {
+            builder = builder.timeout(Duration::from_secs(api_config.gmail_timeout_secs));
+        }
+        if api_config.gmail_connect_timeout_secs > 0 {
+            builder =
+                builder.connect_timeout(Duration::from_secs(api_config.gmail_connect_timeout_secs));
+        }
+        let http_client = crate::utils::apply_proxy(builder, config)?
+            .build()
+            .context("Failed to build HTTP client")?;
+
+        let fixtures = Fixtures::open(config)?.map(Arc::new);
+        // Cached messages would skip the calls fixtures record and replay
+        let cache = if config.message_cache && fixtures.is_none() {
+            let cache_file = crate::utils::get_app_file_path(config, "message_cache.sqlite3")?;
+            info!("🗄️ Message cache: {}", cache_file.display());
+            Some(Arc::new(MessageCache::open(&cache_file)?))
+        } else {
+            None
+        };
+
+        let audit_log = if config.audit_log {
+            let audit_file = crate::utils::get_app_file_path(config, "audit.jsonl")?;
+            info!("📝 Audit log: {}", audit_file.display());
+            Some(Arc::new(AuditLog::open(&audit_file)?))
+        } else {
+            None
+        };
+
+        // Fixtures are kept per call, since a batch's boundary differs every time it's sent
+        let batch_size = match fixtures {
+            Some(_) => 1,
+            None => api_config.gmail_batch_size.clamp(1, MAX_BATCH_SIZE),
+        };
+
+        validate_user_id(&api_config.gmail_user_id)?;
+
         Ok(Self {
-            user_id: "me".to_string(),
-            authenticated: Arc::new(Mutex::new(false)),
+            user_id: api_config.gmail_user_id.clone(),
             oauth_manager,
+            email_address: Default::default(),
+            http_client,
+            retry_policy: RetryPolicy::from_config(api_config),
+            batch_size,
+            fetch_concurrency: api_config.gmail_fetch_concurrency.max(1),
+            api_root: api_config
+                .gmail_api_url
+                .as_deref()
+                .map(|url| url.trim_end_matches('/').into()),
+            fixtures,
+            cache,
+            audit_log,
+            dry_run: config.dry_run,
+            response_limit: ResponseLimit::from_config(config)?.map(Arc::new),
+            classifier: Classifier::from_config(config)?.map(Arc::new),
+            attachment_scanner: AttachmentScanner::from_config(config).map(Arc::new),
+            pgp_decryptor: PgpDecryptor::from_config(config)?.map(Arc::new),
+            webhooks: Webhooks::from_config(config)?,
+            account: Some(crate::accounts::DEFAULT_ACCOUNT.into()),
+            snooze_file: (!config.demo).then(|| {
+                config
+                    .app_data_dir()
+                    .join(crate::snooze::SNOOZE_FILE)
+                    .into()
+            }),
+            rules_file: (!config.demo).then(|| config.rules_file().into()),
+            job_runs_file: (!config.demo).then(|| {
+                config
+                    .app_data_dir()
+                    .join(crate::jobs::JOB_RUNS_FILE)
+                    .into()
+            }),
+            outbox_file: (config.outbox && !config.demo).then(|| {
+                config
+                    .app_data_dir()
+                    .join(crate::outbox::OUTBOX_FILE)
+                    .into()
+            }),
+            saved_searches_file: (!config.demo).then(|| {
+                config
+                    .app_data_dir()
+                    .join(crate::saved_searches::SAVED_SEARCHES_FILE)
+                    .into()
+            }),
         })
     }
 
-    #[allow(dead_code)]
-    pub async fn is_authenticated(&self) -> bool {
-        *self.authenticated.lock().await
+    /// A server for another account's token, sharing this one's HTTP client and audit log.
+    ///
+    /// The message cache is left out, since history sync only tracks the default mailbox, and
+    /// the server has no account name until [`GmailServer::named`] gives it one.
+    pub fn for_account(&self, oauth_manager: Arc<oauth::OAuthManager>) -> Self {
+        Self {
+            oauth_manager,
+            email_address: Default::default(),
+            cache: None,
+            account: None,
+            ..self.clone()
+        }
     }
 
-    pub async fn set_authenticated(&self, auth: bool) {
-        *self.authenticated.lock().await = auth;
+    /// A server acting on the mailbox `user_id` with this one's token, which a service account
+    /// with domain-wide delegation can do for any user in its domain
+    pub fn for_user(&self, user_id: &str) -> Result<Self> {
+        validate_user_id(user_id)?;
+        if user_id == self.user_id {
+            return Ok(self.clone());
+        }
+        Ok(Self {
+            user_id: user_id.to_string(),
+            email_address: Default::default(),
+            // Cached messages and history belong to the token's own mailbox
+            cache: None,
+            ..self.clone()
+        })
     }
 
-    pub async fn authenticated_client(&self) -> Result<Client> {
-        self.check_authentication().await?;
-        let token = self
-            .oauth_manager
-            .get_token()
-            .await
-            .ok_or_else(|| anyhow::anyhow!("Not authenticated: no token available"))?;
+    /// The server of the configured account `account`
+    pub fn named(self, account: &str) -> Self {
+        Self {
+            account: Some(account.into()),
+            ..self
+        }
+    }
 
-        let mut headers = reqwest::header::HeaderMap::new();
-        headers.insert(
-            "Authorization",
-            format!("Bearer {}", token.access_token).parse().unwrap(),
-        );
+    /// The configured account whose token this is
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_deref()
+    }
 
-        let client = Client::builder()
-            .default_headers(headers)
-            .build()
-            .context("Failed to build authenticated client")?;
-        Ok(client)
+    /// The database of snoozed threads, unless in demo mode
+    pub fn snooze_file(&self) -> Option<&Path> {
+        self.snooze_file.as_deref()
+    }
+
+    /// The file of triage rules, unless in demo mode
+    pub fn rules_file(&self) -> Option<&Path> {
+        self.rules_file.as_deref()
     }
 
-    pub async fn check_authentication(&self) -> Result<()> {
-        if !*self.authenticated.lock().await {
-            return Err(anyhow::anyhow!("Not authenticated"));
+    /// The database of scheduled job runs, unless in demo mode
+    pub fn job_runs_file(&self) -> Option<&Path> {
+        self.job_runs_file.as_deref()
+    }
+
+    /// The database of queued sends, with `--outbox` and unless in demo mode
+    pub fn outbox_file(&self) -> Option<&Path> {
+        self.outbox_file.as_deref()
+    }
+
+    /// The file of saved searches, unless in demo mode
+    pub fn saved_searches_file(&self) -> Option<&Path> {
+        self.saved_searches_file.as_deref()
+    }
+
+    /// Whether the server holds a token it can call Gmail with
+    pub async fn is_authenticated(&self) -> bool {
+        self.check_authentication(&[]).await.is_ok()
+    }
+
+    /// Get a Gmail API client backed by the shared connection pool
+    pub async fn authenticated_client(&self) -> Result<GmailClient> {
+        self.check_authentication(&[]).await?;
+
+        Ok(GmailClient {
+            http_client: self.http_client.clone(),
+            oauth_manager: self.oauth_manager.clone(),
+            retry_policy: self.retry_policy.clone(),
+            batch_size: self.batch_size,
+            fetch_concurrency: self.fetch_concurrency,
+            api_root: self.api_root.clone(),
+            fixtures: self.fixtures.clone(),
+        })
+    }
+
+    /// Fail unless the token can call Gmail, because it hasn't expired or can be refreshed, and
+    /// was granted `required_scopes` or broader ones, so a tool reports what's wrong up front
+    /// instead of Gmail rejecting it mid-call. Replayed fixtures need no token.
+    pub async fn check_authentication(&self, required_scopes: &[&str]) -> Result<()> {
+        let replaying = replaying(&self.fixtures);
+        let token = match self.oauth_manager.get_token().await {
+            Some(token) => token,
+            None if replaying => return Ok(()),
+            None => return Err(GmailError::NotAuthenticated(AuthFailure::NoToken).into()),
+        };
+        if !replaying && !token.is_usable() {
+            return Err(GmailError::NotAuthenticated(AuthFailure::Expired).into());
+        }
+        let missing = token.missing_scopes(required_scopes);
+        if !missing.is_empty() {
+            return Err(GmailError::InsufficientScope(missing).into());
         }
         Ok(())
     }
@@ -63,74 +399,1004 @@ impl GmailServer {
     pub fn user_id(&self) -> &str {
         &self.user_id
     }
+
+    /// Get the mailbox profile (email address, message and thread totals, historyId)
+    pub async fn get_profile(&self) -> Result<Value> {
+        let client = self.authenticated_client().await?;
+        client
+            .get_json(&format!("{GMAIL_API_BASE}/users/{}/profile", self.user_id))
+            .await
+    }
+
+    /// The mailbox's email address, looked up with `getProfile` the first time and remembered
+    /// until [`GmailServer::forget_email_address`]
+    pub async fn email_address(&self) -> Result<String> {
+        if let Some(address) = self.known_email_address() {
+            return Ok(address);
+        }
+        let profile = self.get_profile().await?;
+        let address = profile["emailAddress"]
+            .as_str()
+            .context("Profile has no email address")?
+            .to_string();
+        *self.email_address.lock().unwrap() = Some(address.clone());
+        Ok(address)
+    }
+
+    /// The mailbox's email address if it was already looked up, without calling Gmail
+    pub fn known_email_address(&self) -> Option<String> {
+        self.email_address.lock().unwrap().clone()
+    }
+
+    /// Forget the email address after a login, which may be to another mailbox
+    pub fn forget_email_address(&self) {
+        *self.email_address.lock().unwrap() = None;
+    }
+
+    /// Verify the server can serve tool calls: a token is loaded and Gmail accepts it
+    pub async fn check_ready(&self) -> Result<()> {
+        if self.oauth_manager.get_token().await.is_none() {
+            return Err(anyhow::anyhow!("No OAuth token loaded"));
+        }
+
+        // getProfile costs a single quota unit; an expired access token is refreshed on its 401
+        self.get_profile()
+            .await
+            .context("Gmail getProfile probe failed")?;
+
+        match self.oauth_manager.get_token().await {
+            Some(token) if !token.is_expired() => Ok(()),
+            _ => Err(anyhow::anyhow!("OAuth token expired")),
+        }
+    }
+
+    /// The local message cache, if enabled
+    pub fn cache(&self) -> Option<&MessageCache> {
+        self.cache.as_deref()
+    }
+
+    /// The tool invocation audit log, if enabled
+    pub fn audit_log(&self) -> Option<&AuditLog> {
+        self.audit_log.as_deref()
+    }
+
+    /// Whether every call of a sending tool is a dry run
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// The limit long tool responses are cut down to, if set
+    pub fn response_limit(&self) -> Option<&ResponseLimit> {
+        self.response_limit.as_deref()
+    }
+
+    /// The event webhooks, if configured
+    pub fn webhooks(&self) -> Option<&Webhooks> {
+        self.webhooks.as_ref()
+    }
+
+    /// The classifier hook, if configured
+    pub fn classifier(&self) -> Option<&Classifier> {
+        self.classifier.as_deref()
+    }
+
+    /// The attachment virus scanner, if configured
+    pub fn attachment_scanner(&self) -> Option<&AttachmentScanner> {
+        self.attachment_scanner.as_deref()
+    }
+
+    /// The PGP/MIME decryptor, if configured
+    pub fn pgp_decryptor(&self) -> Option<&PgpDecryptor> {
+        self.pgp_decryptor.as_deref()
+    }
+}
+
+/// Retry policy for rate-limited and transiently failing Gmail API calls
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &GmailApiConfig) -> Self {
+        Self {
+            max_retries: config.gmail_max_retries,
+            base_delay: Duration::from_millis(config.gmail_retry_base_delay_ms),
+            max_delay: Duration::from_millis(config.gmail_retry_max_delay_ms),
+        }
+    }
+
+    /// Exponential backoff with full jitter for the given zero-based attempt
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        let millis = ceiling.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(0..=millis))
+    }
+
+    /// Delay before the next attempt, preferring the server's Retry-After hint
+    fn delay(&self, attempt: u32, headers: Option<&HeaderMap>) -> Duration {
+        headers
+            .and_then(retry_after)
+            .map(|delay| delay.min(self.max_delay))
+            .unwrap_or_else(|| self.backoff(attempt))
+    }
+}
+
+/// Whether a Gmail API status code is worth retrying
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// The reason Google gives for an API error, e.g. `rateLimitExceeded` or `notFound`, falling
+/// back to the error's status such as `PERMISSION_DENIED` when it lists no reasons
+pub fn error_reason(body: &[u8]) -> String {
+    let error = serde_json::from_slice::<Value>(body).unwrap_or_default()["error"].take();
+    error["errors"][0]["reason"]
+        .as_str()
+        .or_else(|| error["status"].as_str())
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+/// Check a mailbox to act on is `me` or an email address, since it's put into request paths
+pub fn validate_user_id(user_id: &str) -> Result<()> {
+    let is_address = user_id.split_once('@').is_some_and(|(local, domain)| {
+        !local.is_empty()
+            && !domain.is_empty()
+            && !user_id.contains(|c: char| c.is_whitespace() || "/?#%".contains(c))
+    });
+    if user_id == "me" || is_address {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Invalid Gmail user ID {user_id:?}: expected `me` or an email address"
+        ))
+    }
+}
+
+/// Count an error response in the Gmail API error metrics, then hand it on with its body intact
+async fn record_error_response(response: Response) -> Result<Response> {
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = response
+        .bytes()
+        .await
+        .context("Failed to read Gmail API response")?;
+    record_gmail_api_error(status.as_u16(), &error_reason(&body));
+
+    let mut rebuilt = axum::http::Response::new(body);
+    *rebuilt.status_mut() = status;
+    *rebuilt.headers_mut() = headers;
+    Ok(Response::from(rebuilt))
+}
+
+/// Parse a Retry-After header given either as delay seconds or an HTTP date
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Gmail API client that attaches the current access token to each request
+#[derive(Clone)]
+pub struct GmailClient {
+    http_client: Client,
+    oauth_manager: Arc<oauth::OAuthManager>,
+    retry_policy: RetryPolicy,
+    batch_size: usize,
+    fetch_concurrency: usize,
+    api_root: Option<Arc<str>>,
+    fixtures: Option<Arc<Fixtures>>,
+}
+
+impl GmailClient {
+    pub fn get(&self, url: &str) -> GmailRequest {
+        self.request(Method::GET, url)
+    }
+
+    pub fn post(&self, url: &str) -> GmailRequest {
+        self.request(Method::POST, url)
+    }
+
+    fn request(&self, method: Method, url: &str) -> GmailRequest {
+        let url = self.resolve(url);
+        let url = url.as_str();
+        let endpoint = request_path(url)
+            .ok()
+            .and_then(|path| GmailEndpoint::from_request(&method, &path));
+        GmailRequest {
+            client: self.clone(),
+            builder: self.http_client.request(method.clone(), url),
+            endpoint,
+            method,
+            url: url.to_string(),
+        }
+    }
+
+    /// The URL with `GMAIL_API_ROOT` swapped for the `--gmail-api-url` root, if one is set, and
+    /// `DRIVE_API_ROOT` for its `/drive` path
+    fn resolve(&self, url: &str) -> String {
+        let Some(root) = &self.api_root else {
+            return url.to_string();
+        };
+        if let Some(path) = url.strip_prefix(GMAIL_API_ROOT) {
+            format!("{root}{path}")
+        } else if let Some(path) = url.strip_prefix(DRIVE_API_ROOT) {
+            format!("{root}/drive{path}")
+        } else {
+            url.to_string()
+        }
+    }
+
+    /// GET a Gmail API URL and parse the JSON response
+    pub async fn get_json(&self, url: &str) -> Result<Value> {
+        let response = self.get(url).send().await?;
+        if !response.status().is_success() {
+            return Err(GmailError::from_response(response).await.into());
+        }
+        response.json().await.context("Failed to parse response")
+    }
+
+    /// POST a JSON body to a Gmail API URL and parse the JSON response
+    pub async fn post_json<T: Serialize + ?Sized>(&self, url: &str, body: &T) -> Result<Value> {
+        let response = self.post(url).json(body).send().await?;
+        if !response.status().is_success() {
+            return Err(GmailError::from_response(response).await.into());
+        }
+        response.json().await.context("Failed to parse response")
+    }
+
+    /// GET several Gmail API URLs using as few batch requests as possible.
+    ///
+    /// Batches are sent concurrently up to the configured fetch concurrency and
+    /// results are returned in the same order as `urls`. Calls that Gmail
+    /// rate-limited inside a batch are retried individually.
+    pub async fn batch_get(&self, urls: &[String]) -> Result<Vec<Result<Value>>> {
+        let chunks: Vec<Vec<String>> = urls
+            .chunks(self.batch_size)
+            .map(<[String]>::to_vec)
+            .collect();
+        let chunks: Vec<Vec<Result<Value>>> = stream::iter(chunks)
+            .map(|chunk| {
+                let client = self.clone();
+                async move { client.batch_get_chunk(&chunk).await }
+            })
+            .buffered(self.fetch_concurrency)
+            .try_collect()
+            .await?;
+        Ok(chunks.into_iter().flatten().collect())
+    }
+
+    async fn batch_get_chunk(&self, urls: &[String]) -> Result<Vec<Result<Value>>> {
+        if let [url] = urls {
+            return Ok(vec![self.get_json(url).await]);
+        }
+
+        let paths = urls
+            .iter()
+            .map(|url| request_path(url))
+            .collect::<Result<Vec<_>>>()?;
+        // Each call inside a batch is charged as if sent individually
+        for path in &paths {
+            if let Some(endpoint) = GmailEndpoint::from_request(&Method::GET, path) {
+                endpoint.record();
+            }
+        }
+        let boundary: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(24)
+            .map(char::from)
+            .collect();
+        let boundary = format!("batch_{boundary}");
+
+        let response = self
+            .post(GMAIL_BATCH_URL)
+            .header(
+                CONTENT_TYPE,
+                HeaderValue::from_str(&format!("multipart/mixed; boundary={boundary}"))?,
+            )
+            .body(build_batch_body(&boundary, &paths))
+            .send()
+            .await
+            .context("Failed to send batch request")?;
+
+        if !response.status().is_success() {
+            return Err(GmailError::from_response(response).await.into());
+        }
+
+        let response_boundary = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(boundary_from_content_type)
+            .ok_or_else(|| anyhow::anyhow!("Batch response is missing a multipart boundary"))?;
+        let text = response
+            .text()
+            .await
+            .context("Failed to read batch response")?;
+
+        let mut parsed: Vec<Option<Result<Value>>> = urls.iter().map(|_| None).collect();
+        for part in parse_batch_response(&response_boundary, &text)? {
+            let Some(slot) = parsed.get_mut(part.index) else {
+                continue;
+            };
+            *slot = if part.status.is_success() {
+                Some(serde_json::from_str(&part.body).context("Failed to parse batch response"))
+            } else if is_retryable_status(part.status) {
+                None
+            } else {
+                Some(Err(GmailError::api(part.status, None, part.body).into()))
+            };
+        }
+
+        // Parts that were rate-limited or missing fall back to individual requests with retries
+        let results = stream::iter(urls.iter().cloned().zip(parsed))
+            .map(|(url, result)| {
+                let client = self.clone();
+                async move {
+                    match result {
+                        Some(result) => result,
+                        None => client.get_json(&url).await,
+                    }
+                }
+            })
+            .buffered(self.fetch_concurrency)
+            .collect()
+            .await;
+        Ok(results)
+    }
+}
+
+fn replaying(fixtures: &Option<Arc<Fixtures>>) -> bool {
+    fixtures
+        .as_ref()
+        .is_some_and(|fixtures| fixtures.replaying())
+}
+
+/// Path and query of a Gmail API URL, as used for requests inside a batch
+fn request_path(url: &str) -> Result<String> {
+    let url = url::Url::parse(url).with_context(|| format!("Invalid Gmail API URL: {url}"))?;
+    Ok(match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    })
+}
+
+/// A pending Gmail API request
+pub struct GmailRequest {
+    client: GmailClient,
+    builder: RequestBuilder,
+    endpoint: Option<GmailEndpoint>,
+    method: Method,
+    url: String,
+}
+
+impl GmailRequest {
+    pub fn json<T: Serialize + ?Sized>(mut self, json: &T) -> Self {
+        self.builder = self.builder.json(json);
+        self
+    }
+
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.builder = self.builder.header(name, value);
+        self
+    }
+
+    pub fn body<T: Into<Body>>(mut self, body: T) -> Self {
+        self.builder = self.builder.body(body);
+        self
+    }
+
+    /// Send the request, authorizing it with the token current at send time.
+    ///
+    /// A 401 response triggers a single token refresh and replay of the request.
+    /// 429 and 5xx responses, as well as connection failures, are retried with
+    /// jittered exponential backoff according to the client's retry policy.
+    pub async fn send(self) -> Result<Response> {
+        let span = info_span!(
+            "gmail_api",
+            endpoint = self.endpoint.map_or("other", |endpoint| endpoint.name),
+            status = field::Empty,
+        );
+        let result = match self.client.fixtures.clone() {
+            Some(fixtures) => {
+                let (method, path) = (self.method.clone(), request_path(&self.url)?);
+                if fixtures.replaying() {
+                    fixtures.replay(&method, &path)
+                } else {
+                    match self.send_with_retries().instrument(span.clone()).await {
+                        Ok(response) => fixtures.record(&method, &path, response).await,
+                        Err(e) => Err(e),
+                    }
+                }
+            }
+            None => self.send_with_retries().instrument(span.clone()).await,
+        };
+        if let Ok(response) = &result {
+            span.record("status", response.status().as_u16());
+        }
+        result
+    }
+
+    async fn send_with_retries(self) -> Result<Response> {
+        let GmailRequest {
+            client,
+            builder,
+            endpoint,
+            ..
+        } = self;
+        let token = client
+            .oauth_manager
+            .get_token()
+            .await
+            .ok_or(GmailError::NotAuthenticated(AuthFailure::NoToken))?;
+        let mut access_token = token.access_token;
+        let mut refreshed = false;
+        let mut attempt = 0;
+        // Gmail may have sent the message before a 5xx or timeout came back, so a send is only
+        // repeated when it can't have gone out: it was throttled or never connected
+        let sends_mail = endpoint.is_some_and(|endpoint| endpoint.sends_mail());
+
+        loop {
+            // Every attempt counts against the quota, including retries
+            if let Some(endpoint) = &endpoint {
+                endpoint.record();
+            }
+
+            let Some(request) = builder.try_clone() else {
+                // Streaming bodies cannot be replayed, so send them exactly once
+                let response = builder.bearer_auth(&access_token).send().await?;
+                if response.status().is_success() {
+                    return Ok(response);
+                }
+                return record_error_response(response).await;
+            };
+
+            // Errors that are retried count too, so throttling shows even when retries succeed
+            let sent = match request.bearer_auth(&access_token).send().await {
+                Ok(response) if !response.status().is_success() => {
+                    Ok(record_error_response(response).await?)
+                }
+                sent => sent,
+            };
+            match sent {
+                Ok(response) if response.status() == StatusCode::UNAUTHORIZED && !refreshed => {
+                    refreshed = true;
+                    access_token = client.refreshed_access_token(&access_token).await?;
+                }
+                Ok(response)
+                    if is_retryable_status(response.status())
+                        && (!sends_mail || response.status() == StatusCode::TOO_MANY_REQUESTS)
+                        && attempt < client.retry_policy.max_retries =>
+                {
+                    let delay = client.retry_policy.delay(attempt, Some(response.headers()));
+                    warn!(
+                        "Gmail API returned {}, retrying in {:?} (attempt {}/{})",
+                        response.status(),
+                        delay,
+                        attempt + 1,
+                        client.retry_policy.max_retries
+                    );
+                    attempt += 1;
+                    tokio::time::sleep(delay).await;
+                }
+                Ok(response) => return Ok(response),
+                Err(e)
+                    if (e.is_connect() || (e.is_timeout() && !sends_mail))
+                        && attempt < client.retry_policy.max_retries =>
+                {
+                    let delay = client.retry_policy.delay(attempt, None);
+                    warn!(
+                        "Gmail API request failed: {}, retrying in {:?} (attempt {}/{})",
+                        e,
+                        delay,
+                        attempt + 1,
+                        client.retry_policy.max_retries
+                    );
+                    attempt += 1;
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+impl GmailClient {
+    /// Get a fresh access token after the given one was rejected with a 401
+    async fn refreshed_access_token(&self, rejected: &str) -> Result<String> {
+        // Another request may already have refreshed the token while this one was in flight
+        if let Some(current) = self.oauth_manager.get_token().await {
+            if current.access_token != rejected {
+                return Ok(current.access_token);
+            }
+            // Caller-supplied tokens can't be refreshed, so don't count them as refresh failures
+            if current.refresh_token.is_none() {
+                return Err(GmailError::ReauthenticationRequired(
+                    "Access token was rejected and there is no refresh token".to_string(),
+                )
+                .into());
+            }
+        }
+
+        info!("Gmail API returned 401, refreshing access token");
+        let refreshed = self.oauth_manager.refresh_token().await.map_err(|e| {
+            warn!("Token refresh after 401 failed: {}", e);
+            GmailError::ReauthenticationRequired(e.to_string())
+        })?;
+        Ok(refreshed.access_token)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::{Config, HttpConfig};
+    use crate::config::{Config, GmailApiConfig, HttpConfig};
 
     fn create_test_config() -> Config {
         Config {
             gmail_client_id: Some("test_client_id".to_string()),
             gmail_client_secret: Some("test_client_secret".to_string()),
             app_data_dir: None,
+            ..Default::default()
+        }
+    }
+
+    fn test_token() -> oauth::OAuthToken {
+        oauth::OAuthToken {
+            access_token: "test_access_token".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: 3600,
+            refresh_token: None,
+            scope: "test_scope".to_string(),
+            created_at: SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
         }
     }
 
     #[tokio::test]
     async fn test_gmail_server_new() {
         let config = create_test_config();
-        let oauth_manager = Arc::new(
-            oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap(),
-        );
-        let server = GmailServer::new(oauth_manager).unwrap();
+        let oauth_manager =
+            Arc::new(oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap());
+        let server = GmailServer::new(oauth_manager, &config).unwrap();
         assert_eq!(server.user_id(), "me");
         assert!(!server.is_authenticated().await);
     }
 
     #[tokio::test]
-    async fn test_set_authenticated() {
+    async fn test_authenticated_follows_the_token() {
         let config = create_test_config();
-        let oauth_manager = Arc::new(
-            oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap(),
-        );
-        let server = GmailServer::new(oauth_manager).unwrap();
-        server.set_authenticated(true).await;
+        let oauth_manager =
+            Arc::new(oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap());
+        let server = GmailServer::new(oauth_manager.clone(), &config).unwrap();
+        let failure = |error: anyhow::Error| match error.downcast_ref::<GmailError>() {
+            Some(GmailError::NotAuthenticated(failure)) => *failure,
+            other => panic!("unexpected error: {other:?}"),
+        };
+        let error = server.check_authentication(&[]).await.unwrap_err();
+        assert_eq!(failure(error), AuthFailure::NoToken);
+
+        oauth_manager.set_token(test_token()).await;
+        assert!(server.is_authenticated().await);
+
+        let expired = oauth::OAuthToken {
+            created_at: 0,
+            ..test_token()
+        };
+        oauth_manager.set_token(expired.clone()).await;
+        let error = server.check_authentication(&[]).await.unwrap_err();
+        assert_eq!(failure(error), AuthFailure::Expired);
+
+        // The next call refreshes it
+        oauth_manager
+            .set_token(oauth::OAuthToken {
+                refresh_token: Some("refresh".to_string()),
+                ..expired
+            })
+            .await;
         assert!(server.is_authenticated().await);
     }
 
     #[tokio::test]
     async fn test_authenticated_client_not_authenticated() {
         let config = create_test_config();
-        let oauth_manager = Arc::new(
-            oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap(),
-        );
-        let server = GmailServer::new(oauth_manager).unwrap();
+        let oauth_manager =
+            Arc::new(oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap());
+        let server = GmailServer::new(oauth_manager, &config).unwrap();
         let result = server.authenticated_client().await;
         assert!(result.is_err());
     }
 
     #[tokio::test]
-    async fn test_authenticated_client_authenticated_no_token() {
+    async fn test_authenticated_client_with_token() {
         let config = create_test_config();
-        let oauth_manager = Arc::new(
-            oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap(),
+        let oauth_manager =
+            Arc::new(oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap());
+        oauth_manager.set_token(test_token()).await;
+        let server = GmailServer::new(oauth_manager, &config).unwrap();
+        assert!(server.authenticated_client().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_check_ready_without_token_or_authentication() {
+        let config = create_test_config();
+        let oauth_manager =
+            Arc::new(oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap());
+        let server = GmailServer::new(oauth_manager.clone(), &config).unwrap();
+        let error = server.check_ready().await.unwrap_err();
+        assert_eq!(error.to_string(), "No OAuth token loaded");
+
+        oauth_manager
+            .set_token(oauth::OAuthToken {
+                created_at: 0,
+                ..test_token()
+            })
+            .await;
+        let error = server.check_ready().await.unwrap_err();
+        assert!(format!("{error:#}").ends_with(
+            "Not authenticated: the access token expired and there is no refresh token"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_send_passes_through_non_401_responses() {
+        let mut mock_server = mockito::Server::new_async().await;
+        let mock = mock_server
+            .mock("GET", "/resource")
+            .match_header("authorization", "Bearer test_access_token")
+            .with_status(200)
+            .with_body("{}")
+            .create_async()
+            .await;
+
+        let config = create_test_config();
+        let oauth_manager =
+            Arc::new(oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap());
+        oauth_manager.set_token(test_token()).await;
+        let server = GmailServer::new(oauth_manager, &config).unwrap();
+
+        let client = server.authenticated_client().await.unwrap();
+        let response = client
+            .get(&format!("{}/resource", mock_server.url()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_gmail_api_url_replaces_the_api_root() {
+        let mut mock_server = mockito::Server::new_async().await;
+        let mock = mock_server
+            .mock("GET", "/gmail/v1/users/me/profile")
+            .with_status(200)
+            .with_body("{}")
+            .create_async()
+            .await;
+
+        let mut config = create_test_config();
+        config.gmail_api.gmail_api_url = Some(format!("{}/", mock_server.url()));
+        let oauth_manager =
+            Arc::new(oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap());
+        oauth_manager.set_token(test_token()).await;
+        let server = GmailServer::new(oauth_manager, &config).unwrap();
+
+        let client = server.authenticated_client().await.unwrap();
+        let profile = client
+            .get_json(&format!("{GMAIL_API_BASE}/users/me/profile"))
+            .await
+            .unwrap();
+        assert_eq!(profile, serde_json::json!({}));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_send_times_out_on_unresponsive_server() {
+        // Accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut config = create_test_config();
+        config.gmail_api.gmail_timeout_secs = 1;
+        config.gmail_api.gmail_max_retries = 0;
+        let oauth_manager =
+            Arc::new(oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap());
+        oauth_manager.set_token(test_token()).await;
+        let server = GmailServer::new(oauth_manager, &config).unwrap();
+
+        let client = server.authenticated_client().await.unwrap();
+        let err = client
+            .get(&format!("http://{addr}/resource"))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(err
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(reqwest::Error::is_timeout));
+    }
+
+    #[tokio::test]
+    async fn test_send_401_without_refresh_token_requires_reauthentication() {
+        let mut mock_server = mockito::Server::new_async().await;
+        let mock = mock_server
+            .mock("GET", "/resource")
+            .with_status(401)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let config = create_test_config();
+        let oauth_manager =
+            Arc::new(oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap());
+        oauth_manager.set_token(test_token()).await;
+        let server = GmailServer::new(oauth_manager, &config).unwrap();
+
+        let client = server.authenticated_client().await.unwrap();
+        let err = client
+            .get(&format!("{}/resource", mock_server.url()))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GmailError>(),
+            Some(GmailError::ReauthenticationRequired(_))
+        ));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_send_retries_rate_limited_requests() {
+        let mut mock_server = mockito::Server::new_async().await;
+        let mock = mock_server
+            .mock("GET", "/resource")
+            .with_status(429)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"error": {"code": 429, "errors": [{"reason": "rateLimitExceeded"}]}}"#)
+            .expect(3)
+            .create_async()
+            .await;
+
+        let config = Config {
+            gmail_api: GmailApiConfig {
+                gmail_max_retries: 2,
+                gmail_retry_base_delay_ms: 1,
+                gmail_retry_max_delay_ms: 5,
+                ..Default::default()
+            },
+            ..create_test_config()
+        };
+        let oauth_manager =
+            Arc::new(oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap());
+        oauth_manager.set_token(test_token()).await;
+        let server = GmailServer::new(oauth_manager, &config).unwrap();
+
+        let client = server.authenticated_client().await.unwrap();
+        let response = client
+            .get(&format!("{}/resource", mock_server.url()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        // Counting the error doesn't swallow the response it came in
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            HeaderValue::from_static("application/json")
         );
-        let server = GmailServer::new(oauth_manager).unwrap();
-        server.set_authenticated(true).await;
-        let result = server.authenticated_client().await;
-        assert!(result.is_err());
+        assert!(response.text().await.unwrap().contains("rateLimitExceeded"));
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_sends_are_not_repeated_after_server_errors() {
+        let mut mock_server = mockito::Server::new_async().await;
+        let send = mock_server
+            .mock("POST", "/gmail/v1/users/me/drafts/send")
+            .with_status(503)
+            .expect(1)
+            .create_async()
+            .await;
+        let throttled = mock_server
+            .mock("POST", "/gmail/v1/users/me/messages/send")
+            .with_status(429)
+            .expect(3)
+            .create_async()
+            .await;
+
+        let config = Config {
+            gmail_api: GmailApiConfig {
+                gmail_max_retries: 2,
+                gmail_retry_base_delay_ms: 1,
+                gmail_retry_max_delay_ms: 5,
+                ..Default::default()
+            },
+            ..create_test_config()
+        };
+        let oauth_manager =
+            Arc::new(oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap());
+        oauth_manager.set_token(test_token()).await;
+        let server = GmailServer::new(oauth_manager, &config).unwrap();
+        let client = server.authenticated_client().await.unwrap();
+
+        // Gmail may have sent the draft before failing, so it isn't sent again
+        let response = client
+            .post(&format!(
+                "{}/gmail/v1/users/me/drafts/send",
+                mock_server.url()
+            ))
+            .json(&serde_json::json!({ "id": "d1" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        send.assert_async().await;
+
+        // A throttled send was refused, so it's retried
+        let response = client
+            .post(&format!(
+                "{}/gmail/v1/users/me/messages/send",
+                mock_server.url()
+            ))
+            .json(&serde_json::json!({ "raw": "" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        throttled.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_batch_get_without_batching_preserves_order() {
+        let mut mock_server = mockito::Server::new_async().await;
+        let mut mocks = Vec::new();
+        for id in ["a", "b", "c"] {
+            mocks.push(
+                mock_server
+                    .mock("GET", format!("/messages/{id}").as_str())
+                    .with_status(200)
+                    .with_body(format!("{{\"id\": \"{id}\"}}"))
+                    .create_async()
+                    .await,
+            );
+        }
+        let missing = mock_server
+            .mock("GET", "/messages/missing")
+            .with_status(404)
+            .create_async()
+            .await;
+
+        let config = Config {
+            gmail_api: GmailApiConfig {
+                gmail_batch_size: 1,
+                gmail_fetch_concurrency: 2,
+                ..Default::default()
+            },
+            ..create_test_config()
+        };
+        let oauth_manager =
+            Arc::new(oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap());
+        oauth_manager.set_token(test_token()).await;
+        let server = GmailServer::new(oauth_manager, &config).unwrap();
+
+        let client = server.authenticated_client().await.unwrap();
+        let urls: Vec<String> = ["a", "missing", "b", "c"]
+            .iter()
+            .map(|id| format!("{}/messages/{id}", mock_server.url()))
+            .collect();
+        let results = client.batch_get(&urls).await.unwrap();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().unwrap()["id"], "a");
+        assert!(results[1].is_err());
+        assert_eq!(results[2].as_ref().unwrap()["id"], "b");
+        assert_eq!(results[3].as_ref().unwrap()["id"], "c");
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+        missing.assert_async().await;
+    }
+
+    #[test]
+    fn test_request_path() {
+        assert_eq!(
+            request_path("https://gmail.googleapis.com/gmail/v1/users/me/messages/abc").unwrap(),
+            "/gmail/v1/users/me/messages/abc"
+        );
+        assert_eq!(
+            request_path("https://gmail.googleapis.com/gmail/v1/users/me/threads/t?format=minimal")
+                .unwrap(),
+            "/gmail/v1/users/me/threads/t?format=minimal"
+        );
+        assert!(request_path("not a url").is_err());
+    }
+
+    #[test]
+    fn test_backoff_is_bounded_by_max_delay() {
+        let policy = RetryPolicy::from_config(&GmailApiConfig {
+            gmail_max_retries: 5,
+            gmail_retry_base_delay_ms: 100,
+            gmail_retry_max_delay_ms: 1000,
+            ..Default::default()
+        });
+        assert!(policy.backoff(0) <= Duration::from_millis(100));
+        assert!(policy.backoff(2) <= Duration::from_millis(400));
+        assert!(policy.backoff(20) <= Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_retry_after_seconds() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, "7".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn test_retry_after_http_date() {
+        let mut headers = HeaderMap::new();
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(120));
+        headers.insert(RETRY_AFTER, date.parse().unwrap());
+        let delay = retry_after(&headers).unwrap();
+        assert!(delay > Duration::from_secs(100) && delay <= Duration::from_secs(120));
+    }
+
+    #[test]
+    fn test_retry_after_missing_or_invalid() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, "soon".parse().unwrap());
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    fn test_delay_caps_retry_after() {
+        let policy = RetryPolicy::from_config(&GmailApiConfig {
+            gmail_max_retries: 3,
+            gmail_retry_base_delay_ms: 100,
+            gmail_retry_max_delay_ms: 2000,
+            ..Default::default()
+        });
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, "60".parse().unwrap());
+        assert_eq!(policy.delay(0, Some(&headers)), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_error_reason() {
+        let body = br#"{"error": {"code": 404, "errors": [{"reason": "notFound"}], "status": "NOT_FOUND"}}"#;
+        assert_eq!(error_reason(body), "notFound");
+        let body = br#"{"error": {"code": 403, "status": "PERMISSION_DENIED"}}"#;
+        assert_eq!(error_reason(body), "PERMISSION_DENIED");
+        assert_eq!(error_reason(b"Service Unavailable"), "unknown");
+    }
+
+    #[test]
+    fn test_is_retryable_status() {
+        assert!(is_retryable_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_retryable_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!is_retryable_status(StatusCode::NOT_FOUND));
+        assert!(!is_retryable_status(StatusCode::UNAUTHORIZED));
     }
 
     #[tokio::test]
     async fn test_check_authentication_not_authenticated() {
         let config = create_test_config();
-        let oauth_manager = Arc::new(
-            oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap(),
-        );
-        let server = GmailServer::new(oauth_manager).unwrap();
-        let result = server.check_authentication().await;
+        let oauth_manager =
+            Arc::new(oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap());
+        let server = GmailServer::new(oauth_manager, &config).unwrap();
+        let result = server.check_authentication(&[]).await;
         assert!(result.is_err());
     }
 
@@ -139,21 +1405,138 @@ mod tests {
         let config = create_test_config();
         let oauth_manager =
             oauth::OAuthManager::new(config.clone(), HttpConfig::default()).unwrap();
-        let token = oauth::OAuthToken {
-            access_token: "test_access_token".to_string(),
-            token_type: "Bearer".to_string(),
-            expires_in: 3600,
-            refresh_token: None,
-            scope: "test_scope".to_string(),
-            created_at: 0,
-        };
-        oauth_manager.set_token(token).await;
+        oauth_manager.set_token(test_token()).await;
         let server_with_token = GmailServer {
             user_id: "me".to_string(),
-            authenticated: Arc::new(Mutex::new(true)),
             oauth_manager: Arc::new(oauth_manager),
+            email_address: Default::default(),
+            http_client: Client::new(),
+            retry_policy: RetryPolicy::from_config(&config.gmail_api),
+            batch_size: config.gmail_api.gmail_batch_size,
+            fetch_concurrency: config.gmail_api.gmail_fetch_concurrency,
+            api_root: None,
+            fixtures: None,
+            cache: None,
+            audit_log: None,
+            dry_run: false,
+            response_limit: None,
+            classifier: None,
+            attachment_scanner: None,
+            pgp_decryptor: None,
+            webhooks: None,
+            account: None,
+            snooze_file: None,
+            rules_file: None,
+            job_runs_file: None,
+            outbox_file: None,
+            saved_searches_file: None,
         };
-        let result = server_with_token.check_authentication().await;
+        let result = server_with_token.check_authentication(&[]).await;
         assert!(result.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_check_authentication_insufficient_scope() {
+        let config = create_test_config();
+        let oauth_manager = Arc::new(
+            oauth::OAuthManager::in_memory(config.clone(), HttpConfig::default()).unwrap(),
+        );
+        let server = GmailServer::new(oauth_manager.clone(), &config).unwrap();
+        oauth_manager
+            .set_token(oauth::OAuthToken {
+                scope: oauth::SCOPE_READONLY.to_string(),
+                ..test_token()
+            })
+            .await;
+        server
+            .check_authentication(&[oauth::SCOPE_READONLY])
+            .await
+            .unwrap();
+        let err = server
+            .check_authentication(&[oauth::SCOPE_READONLY, oauth::SCOPE_SEND])
+            .await
+            .unwrap_err();
+        match err.downcast_ref::<GmailError>() {
+            Some(GmailError::InsufficientScope(missing)) => {
+                assert_eq!(missing, &vec![oauth::SCOPE_SEND.to_string()])
+            }
+            other => panic!("unexpected error: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_email_address_is_looked_up_once_per_login() {
+        use crate::test_support::{MockGmail, MOCK_EMAIL};
+
+        let gmail = MockGmail::start().await;
+        let gmail_server = gmail.gmail_server(&gmail.config()).await;
+        assert_eq!(gmail_server.known_email_address(), None);
+        assert_eq!(gmail_server.email_address().await.unwrap(), MOCK_EMAIL);
+        assert_eq!(gmail_server.email_address().await.unwrap(), MOCK_EMAIL);
+        assert_eq!(gmail.requests().await.len(), 1);
+
+        gmail_server.forget_email_address();
+        assert_eq!(gmail_server.known_email_address(), None);
+        assert_eq!(gmail_server.email_address().await.unwrap(), MOCK_EMAIL);
+        assert_eq!(gmail.requests().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_for_user_acts_on_a_delegated_mailbox() {
+        use crate::test_support::MockGmail;
+
+        assert!(validate_user_id("me").is_ok());
+        assert!(validate_user_id("alice@example.com").is_ok());
+        let invalid_ids = [
+            "",
+            "alice",
+            "@example.com",
+            "alice@",
+            "a/b@example.com",
+            "a@b?x",
+        ];
+        for invalid in invalid_ids {
+            assert!(validate_user_id(invalid).is_err(), "{invalid}");
+        }
+
+        let gmail = MockGmail::start().await;
+        let gmail_server = gmail.gmail_server(&gmail.config()).await;
+        assert!(gmail_server.for_user("../me").is_err());
+        let delegated = gmail_server.for_user("alice@example.com").unwrap();
+        assert_eq!(delegated.user_id(), "alice@example.com");
+        assert_eq!(gmail_server.user_id(), "me");
+        delegated.get_profile().await.unwrap();
+        assert_eq!(
+            gmail.requests().await[0].url.path(),
+            "/gmail/v1/users/alice@example.com/profile"
+        );
+    }
+
+    #[test]
+    fn test_error_kind() {
+        let api = |status: u16, body: &str| -> anyhow::Error {
+            let status = StatusCode::from_u16(status).unwrap();
+            GmailError::api(status, None, body.to_string()).into()
+        };
+        let rate_limited = r#"{"error": {"errors": [{"reason": "userRateLimitExceeded"}]}}"#;
+        let forbidden = r#"{"error": {"errors": [{"reason": "forbidden"}]}}"#;
+        assert_eq!(ErrorKind::of(&api(404, "")), ErrorKind::NotFound);
+        assert_eq!(ErrorKind::of(&api(429, "")), ErrorKind::RateLimited);
+        assert_eq!(
+            ErrorKind::of(&api(403, rate_limited)),
+            ErrorKind::RateLimited
+        );
+        assert_eq!(ErrorKind::of(&api(403, forbidden)), ErrorKind::Other);
+        assert_eq!(ErrorKind::of(&api(400, "")), ErrorKind::InvalidArgument);
+        assert_eq!(ErrorKind::of(&api(500, "")), ErrorKind::Other);
+        assert_eq!(
+            ErrorKind::of(&api(404, "").context("Failed to fetch thread")),
+            ErrorKind::NotFound
+        );
+        assert_eq!(
+            ErrorKind::of(&GmailError::ReauthenticationRequired(String::new()).into()),
+            ErrorKind::AuthRequired
+        );
+        assert_eq!(ErrorKind::of(&anyhow::anyhow!("Invalid")), ErrorKind::Other);
+    }
 }
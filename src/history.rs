@@ -0,0 +1,192 @@
+use anyhow::{Context, Result};
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::cache::MessageCache;
+use crate::gmail::{GmailClient, GmailError, GmailServer, GMAIL_API_BASE};
+use crate::metrics::{record_watcher_poll, record_watcher_started};
+
+/// Name the history sync reports its polls under on `/status` and in metrics
+const WATCHER: &str = "history_sync";
+
+/// History record fields whose entries wrap a `message` object
+const HISTORY_CHANGE_FIELDS: [&str; 4] = [
+    "messagesAdded",
+    "messagesDeleted",
+    "labelsAdded",
+    "labelsRemoved",
+];
+
+/// Periodically replay mailbox history into the message cache until cancelled, at an interval
+/// that can change while the task runs
+pub fn spawn_history_sync(
+    gmail_server: Arc<GmailServer>,
+    mut interval: watch::Receiver<Duration>,
+    ct: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    record_watcher_started(WATCHER);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(*interval.borrow_and_update());
+        loop {
+            tokio::select! {
+                _ = ct.cancelled() => break,
+                _ = ticker.tick() => {}
+                Ok(()) = interval.changed() => {
+                    let period = *interval.borrow_and_update();
+                    ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+                    continue;
+                }
+            }
+
+            let Some(cache) = gmail_server.cache() else {
+                break;
+            };
+            if !gmail_server.is_authenticated().await {
+                debug!("Skipping cache history sync: not authenticated");
+                continue;
+            }
+            match sync_history(&gmail_server, cache).await {
+                Ok(()) => record_watcher_poll(WATCHER),
+                Err(e) => warn!("Cache history sync failed: {}", e),
+            }
+        }
+    })
+}
+
+/// Invalidate cached messages and threads changed since the last synchronized historyId
+pub async fn sync_history(gmail_server: &GmailServer, cache: &MessageCache) -> Result<()> {
+    let client = gmail_server.authenticated_client().await?;
+    let user_id = gmail_server.user_id();
+
+    // Nothing to replay yet; start from the mailbox's current position
+    let Some(start_history_id) = cache.last_history_id()? else {
+        return reset_history_id(&client, cache, user_id).await;
+    };
+
+    let mut latest_history_id = start_history_id.clone();
+    let mut page_token: Option<String> = None;
+    let mut invalidated = 0;
+    loop {
+        let mut url = format!(
+            "{GMAIL_API_BASE}/users/{user_id}/history?startHistoryId={}",
+            urlencoding::encode(&start_history_id)
+        );
+        if let Some(token) = &page_token {
+            url.push_str(&format!("&pageToken={}", urlencoding::encode(token)));
+        }
+
+        let response = client
+            .get(&url)
+            .send()
+            .await
+            .context("Failed to list history")?;
+
+        // Gmail only keeps about a week of history; past that the cache cannot be caught up
+        if response.status() == StatusCode::NOT_FOUND {
+            warn!(
+                "History {} has expired, clearing message cache",
+                start_history_id
+            );
+            cache.clear()?;
+            return reset_history_id(&client, cache, user_id).await;
+        }
+
+        if !response.status().is_success() {
+            return Err(GmailError::from_response(response).await.into());
+        }
+
+        let page: Value = response.json().await.context("Failed to parse history")?;
+        for record in page["history"].as_array().into_iter().flatten() {
+            for (message_id, thread_id) in changed_messages(record) {
+                cache.invalidate(&message_id, &thread_id)?;
+                invalidated += 1;
+            }
+        }
+        if let Some(history_id) = page["historyId"].as_str() {
+            latest_history_id = history_id.to_string();
+        }
+
+        match page["nextPageToken"].as_str() {
+            Some(token) => page_token = Some(token.to_string()),
+            None => break,
+        }
+    }
+
+    cache.set_last_history_id(&latest_history_id)?;
+    if invalidated > 0 {
+        info!(
+            "🗄️ Cache history sync invalidated {} message(s) up to history {}",
+            invalidated, latest_history_id
+        );
+    }
+    Ok(())
+}
+
+/// Record the mailbox's current historyId as the cache's sync position
+async fn reset_history_id(client: &GmailClient, cache: &MessageCache, user_id: &str) -> Result<()> {
+    let profile = client
+        .get_json(&format!("{GMAIL_API_BASE}/users/{user_id}/profile"))
+        .await
+        .context("Failed to get profile")?;
+    let history_id = profile["historyId"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("Profile missing historyId"))?;
+    cache.set_last_history_id(history_id)
+}
+
+/// (message ID, thread ID) pairs touched by a single history record
+fn changed_messages(record: &Value) -> Vec<(String, String)> {
+    let direct = record["messages"].as_array().into_iter().flatten();
+    let wrapped = HISTORY_CHANGE_FIELDS
+        .iter()
+        .flat_map(|field| record[*field].as_array().into_iter().flatten())
+        .map(|change| &change["message"]);
+
+    direct
+        .chain(wrapped)
+        .filter_map(|message| {
+            let message_id = message["id"].as_str()?;
+            let thread_id = message["threadId"].as_str()?;
+            Some((message_id.to_string(), thread_id.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_changed_messages_collects_every_change_kind() {
+        let record = json!({
+            "id": "900",
+            "messages": [{"id": "m1", "threadId": "t1"}],
+            "messagesAdded": [{"message": {"id": "m2", "threadId": "t2"}}],
+            "messagesDeleted": [{"message": {"id": "m3", "threadId": "t3"}}],
+            "labelsAdded": [{"message": {"id": "m4", "threadId": "t4"}, "labelIds": ["STARRED"]}],
+            "labelsRemoved": [{"message": {"id": "m5", "threadId": "t5"}, "labelIds": ["UNREAD"]}]
+        });
+        let changed = changed_messages(&record);
+        assert_eq!(
+            changed,
+            (1..=5)
+                .map(|n| (format!("m{n}"), format!("t{n}")))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_changed_messages_skips_incomplete_entries() {
+        let record = json!({
+            "messages": [{"id": "m1"}],
+            "labelsAdded": [{"labelIds": ["STARRED"]}]
+        });
+        assert!(changed_messages(&record).is_empty());
+    }
+}
@@ -0,0 +1,679 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, DurationRound, TimeDelta, Timelike, Utc};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, instrument, warn};
+
+use crate::accounts::Accounts;
+use crate::gmail::{GmailError, GmailServer, GMAIL_API_BASE};
+use crate::oauth::{SCOPE_MODIFY, SCOPE_READONLY};
+use crate::rules::Action;
+use crate::saved_searches::expand_query;
+
+/// Jobs file in the app data directory, unless `--jobs-file` names another
+pub const JOBS_FILE: &str = "jobs.toml";
+
+/// Run history database in the app data directory
+pub const JOB_RUNS_FILE: &str = "job_runs.sqlite3";
+
+/// How often the scheduler looks for jobs that are due, well inside a minute so none is missed
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Messages a job acts on in one run unless it sets `max_messages`
+const DEFAULT_MAX_MESSAGES: u32 = 1000;
+
+/// IDs Gmail accepts in one batchModify request
+const BATCH_MODIFY_LIMIT: usize = 1000;
+
+/// Runs kept per job, the oldest dropped first
+const RUNS_KEPT: u32 = 100;
+
+/// Label and archive actions run on a schedule against the messages matching a search
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    pub name: String,
+    /// Five-field cron expression in UTC, or `@hourly`, `@daily`, `@weekly`, `@monthly`
+    pub schedule: String,
+    pub query: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    pub actions: Vec<Action>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_messages: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct JobsFile {
+    #[serde(default)]
+    jobs: Vec<Job>,
+}
+
+/// The jobs in a TOML file, or a JSON one when its name ends in `.json`. A missing file has no
+/// jobs
+pub fn load(path: &Path) -> Result<Vec<Job>> {
+    let file: JobsFile = crate::rules::read_file(path)?;
+    validate(&file.jobs).with_context(|| format!("Invalid jobs file {}", path.display()))?;
+    Ok(file.jobs)
+}
+
+fn validate(jobs: &[Job]) -> Result<()> {
+    let mut names = HashSet::new();
+    for job in jobs {
+        if job.name.trim().is_empty() {
+            anyhow::bail!("Jobs need a name");
+        }
+        if !names.insert(job.name.as_str()) {
+            anyhow::bail!("More than one job is named '{}'", job.name);
+        }
+        if job.query.trim().is_empty() {
+            anyhow::bail!("Job '{}' has no query", job.name);
+        }
+        job.schedule
+            .parse::<Schedule>()
+            .with_context(|| format!("Job '{}' has an invalid schedule", job.name))?;
+        if job.actions.is_empty() {
+            anyhow::bail!("Job '{}' has no actions", job.name);
+        }
+        for action in &job.actions {
+            match action {
+                Action::Label { label } if label.trim().is_empty() => {
+                    anyhow::bail!("Job '{}' has a label action without a label", job.name)
+                }
+                Action::Label { .. } | Action::Archive => {}
+                // Matching messages are found again on every run, so only idempotent actions
+                _ => anyhow::bail!(
+                    "Job '{}' can only label and archive, as it runs again on the same messages",
+                    job.name
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// When a job runs: the minutes, hours, days of the month, months and weekdays it may run at
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Cron runs on either a listed day of the month or weekday when both are restricted
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(expression: &str) -> Result<Self> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            anyhow::bail!(
+                "'{expression}' should have five fields: minute, hour, day of month, month and \
+                 day of week"
+            );
+        };
+        // Sunday is 0 or 7
+        let weekdays = cron_field(weekday, 0, 7)?;
+        Ok(Self {
+            minutes: cron_field(minute, 0, 59)?,
+            hours: cron_field(hour, 0, 23)?,
+            days: cron_field(day, 1, 31)?,
+            months: cron_field(month, 1, 12)?,
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+}
+
+impl Schedule {
+    /// Whether the schedule fires in the minute starting at `time`
+    fn fires_at(&self, time: DateTime<Utc>) -> bool {
+        let day = bit(self.days, time.day());
+        let weekday = bit(self.weekdays, time.weekday().num_days_from_sunday());
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        bit(self.minutes, time.minute())
+            && bit(self.hours, time.hour())
+            && bit(self.months, time.month())
+            && day_matches
+    }
+
+    /// Whether the schedule fires in any minute after `after`, up to and including `until`
+    fn fires_between(&self, after: DateTime<Utc>, until: DateTime<Utc>) -> bool {
+        let mut minute = after + TimeDelta::minutes(1);
+        while minute <= until {
+            if self.fires_at(minute) {
+                return true;
+            }
+            minute += TimeDelta::minutes(1);
+        }
+        false
+    }
+}
+
+fn bit(set: u64, value: u32) -> bool {
+    set & (1 << value) != 0
+}
+
+/// The values a comma-separated cron field allows as a bit set: `*`, `5`, `1-5`, `*/15`, `0-30/10`
+fn cron_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid step in '{part}'"))?,
+            ),
+            None => (part, 1),
+        };
+        let value = |text: &str| {
+            text.parse::<u32>()
+                .ok()
+                .filter(|value| (min..=max).contains(value))
+                .ok_or_else(|| anyhow::anyhow!("'{part}' is outside {min}-{max}"))
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            // A single value with a step runs from it to the end of the range
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if start > end {
+            anyhow::bail!("'{part}' is an empty range");
+        }
+        for value in (start..=end).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+fn start_of_minute(time: DateTime<Utc>) -> DateTime<Utc> {
+    time.duration_trunc(TimeDelta::minutes(1)).unwrap_or(time)
+}
+
+/// The outcome of one run of a job
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct JobRun {
+    pub job: String,
+    pub account: String,
+    /// Seconds since the epoch
+    pub started_at: i64,
+    pub finished_at: i64,
+    /// Messages the job's query matched and its actions were applied to
+    pub messages: u32,
+    pub dry_run: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// History of job runs, kept in SQLite so it outlives the server
+pub struct JobRuns {
+    conn: Mutex<Connection>,
+}
+
+impl JobRuns {
+    /// Open (or create) the run history database at the given path
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory at {}", dir.display()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open job run database at {}", path.display()))?;
+        // The scheduler and tool calls each open their own connection
+        conn.busy_timeout(Duration::from_secs(5))?;
+        Self::init(conn)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS job_runs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                job TEXT NOT NULL,
+                account TEXT NOT NULL,
+                started_at INTEGER NOT NULL,
+                finished_at INTEGER NOT NULL,
+                messages INTEGER NOT NULL,
+                dry_run INTEGER NOT NULL,
+                error TEXT
+            );
+            CREATE INDEX IF NOT EXISTS job_runs_job ON job_runs (job, id);",
+        )
+        .context("Failed to initialize job run database schema")?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Record a run, dropping the job's oldest beyond the last `RUNS_KEPT`
+    pub fn record(&self, run: &JobRun) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO job_runs (job, account, started_at, finished_at, messages, dry_run, error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                run.job,
+                run.account,
+                run.started_at,
+                run.finished_at,
+                run.messages,
+                run.dry_run,
+                run.error,
+            ],
+        )?;
+        conn.execute(
+            "DELETE FROM job_runs WHERE job = ?1 AND id NOT IN
+             (SELECT id FROM job_runs WHERE job = ?1 ORDER BY id DESC LIMIT ?2)",
+            params![run.job, RUNS_KEPT],
+        )?;
+        Ok(())
+    }
+
+    /// The latest runs, newest first, of one job or all of them
+    pub fn latest(&self, job: Option<&str>, limit: u32) -> Result<Vec<JobRun>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT job, account, started_at, finished_at, messages, dry_run, error FROM job_runs
+             WHERE ?1 IS NULL OR job = ?1 ORDER BY id DESC LIMIT ?2",
+        )?;
+        let runs = statement
+            .query_map(params![job, limit], |row| {
+                Ok(JobRun {
+                    job: row.get(0)?,
+                    account: row.get(1)?,
+                    started_at: row.get(2)?,
+                    finished_at: row.get(3)?,
+                    messages: row.get(4)?,
+                    dry_run: row.get(5)?,
+                    error: row.get(6)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(runs)
+    }
+}
+
+/// The latest runs of the scheduled jobs, newest first
+#[instrument(name = "tool", skip_all, fields(tool = "list_job_runs"))]
+pub async fn list_job_runs(
+    gmail_server: &GmailServer,
+    job: Option<&str>,
+    limit: u32,
+) -> Result<Value> {
+    let (Some(path), Some(_)) = (gmail_server.job_runs_file(), gmail_server.account()) else {
+        anyhow::bail!(
+            "Jobs belong to the server's own accounts, so their runs aren't available with \
+             caller-supplied tokens, in multi-user mode or in demo mode"
+        );
+    };
+    let runs: Vec<Value> = JobRuns::open(path)?
+        .latest(job, limit)?
+        .into_iter()
+        .map(|run| {
+            json!({
+                "job": run.job,
+                "account": run.account,
+                "started_at": rfc3339(run.started_at),
+                "finished_at": rfc3339(run.finished_at),
+                "messages": run.messages,
+                "dry_run": run.dry_run,
+                "error": run.error,
+            })
+        })
+        .collect();
+    Ok(json!({ "runs": runs }))
+}
+
+/// Run each job in minutes its schedule fires, until cancelled. The file is read on every
+/// check, so edits apply without a restart.
+///
+/// Minutes the scheduler sleeps through count, so a job runs once after a stall that skipped
+/// its time; jobs due while the server was down don't run
+pub fn spawn_job_scheduler(
+    accounts: Arc<Accounts>,
+    path: PathBuf,
+    runs: JobRuns,
+    ct: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut checked = start_of_minute(Utc::now());
+        let mut ticker = tokio::time::interval(SCHEDULER_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = ct.cancelled() => break,
+                _ = ticker.tick() => {}
+            }
+            let now = start_of_minute(Utc::now());
+            if now <= checked {
+                continue;
+            }
+            let jobs = match load(&path) {
+                Ok(jobs) => jobs,
+                Err(e) => {
+                    warn!("Failed to load jobs: {:#}", e);
+                    checked = now;
+                    continue;
+                }
+            };
+            for job in &jobs {
+                // Validated by load
+                let Ok(schedule) = job.schedule.parse::<Schedule>() else {
+                    continue;
+                };
+                if !schedule.fires_between(checked, now) {
+                    continue;
+                }
+                let gmail_server = match accounts.get(job.account.as_deref()) {
+                    Ok(account) => account.gmail_server.clone(),
+                    Err(e) => {
+                        warn!("Skipping job '{}': {}", job.name, e);
+                        continue;
+                    }
+                };
+                if !gmail_server.is_authenticated().await {
+                    debug!("Skipping job '{}': not authenticated", job.name);
+                    continue;
+                }
+                let run = run_job(&gmail_server, accounts.name(job.account.as_deref()), job).await;
+                match &run.error {
+                    None => info!("🗓️ Job '{}' ran on {} message(s)", job.name, run.messages),
+                    Some(e) => warn!("Job '{}' failed: {}", job.name, e),
+                }
+                if let Err(e) = runs.record(&run) {
+                    warn!("Failed to record run of job '{}': {:#}", job.name, e);
+                }
+            }
+            checked = now;
+        }
+    })
+}
+
+/// Apply a job's actions to the messages matching its query, with `--dry-run` only counting them
+async fn run_job(gmail_server: &GmailServer, account: &str, job: &Job) -> JobRun {
+    let started_at = Utc::now().timestamp();
+    let dry_run = gmail_server.dry_run();
+    let result = async {
+        let message_ids = matching_messages(
+            gmail_server,
+            &job.query,
+            job.max_messages.unwrap_or(DEFAULT_MAX_MESSAGES),
+        )
+        .await?;
+        if !dry_run {
+            modify_messages(gmail_server, &job.actions, &message_ids).await?;
+        }
+        Ok::<_, anyhow::Error>(message_ids.len() as u32)
+    }
+    .await;
+    JobRun {
+        job: job.name.clone(),
+        account: account.to_string(),
+        started_at,
+        finished_at: Utc::now().timestamp(),
+        messages: *result.as_ref().unwrap_or(&0),
+        dry_run,
+        error: result.err().map(|e| format!("{e:#}")),
+    }
+}
+
+/// IDs of up to `max_messages` messages matching the query, newest first
+async fn matching_messages(
+    gmail_server: &GmailServer,
+    query: &str,
+    max_messages: u32,
+) -> Result<Vec<String>> {
+    gmail_server.check_authentication(&[SCOPE_READONLY]).await?;
+    let query = &expand_query(gmail_server, query)?;
+    let client = gmail_server.authenticated_client().await?;
+    let mut message_ids = Vec::new();
+    let mut page_token: Option<String> = None;
+    while message_ids.len() < max_messages as usize {
+        let mut url = format!(
+            "{GMAIL_API_BASE}/users/{}/messages?q={}&maxResults={}",
+            gmail_server.user_id(),
+            urlencoding::encode(query),
+            (max_messages as usize - message_ids.len()).min(500),
+        );
+        if let Some(token) = &page_token {
+            url.push_str(&format!("&pageToken={}", urlencoding::encode(token)));
+        }
+        let page = client
+            .get_json(&url)
+            .await
+            .context("Failed to list messages")?;
+        message_ids.extend(
+            page["messages"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|message| message["id"].as_str().map(str::to_string)),
+        );
+        match page["nextPageToken"].as_str() {
+            Some(token) => page_token = Some(token.to_string()),
+            None => break,
+        }
+    }
+    message_ids.truncate(max_messages as usize);
+    Ok(message_ids)
+}
+
+/// Apply label and archive actions to messages with as few batchModify requests as possible
+async fn modify_messages(
+    gmail_server: &GmailServer,
+    actions: &[Action],
+    message_ids: &[String],
+) -> Result<()> {
+    if message_ids.is_empty() {
+        return Ok(());
+    }
+    gmail_server.check_authentication(&[SCOPE_MODIFY]).await?;
+    let client = gmail_server.authenticated_client().await?;
+    let user_id = gmail_server.user_id();
+    let mut add = Vec::new();
+    for action in actions {
+        if let Action::Label { label } = action {
+            add.push(crate::labels::find_or_create_label(&client, user_id, label).await?);
+        }
+    }
+    let remove: &[&str] = if actions.contains(&Action::Archive) {
+        &["INBOX"]
+    } else {
+        &[]
+    };
+    for ids in message_ids.chunks(BATCH_MODIFY_LIMIT) {
+        let response = client
+            .post(&format!(
+                "{GMAIL_API_BASE}/users/{user_id}/messages/batchModify"
+            ))
+            .json(&json!({ "ids": ids, "addLabelIds": add, "removeLabelIds": remove }))
+            .send()
+            .await
+            .context("Failed to modify messages")?;
+        // batchModify answers with an empty body
+        if !response.status().is_success() {
+            return Err(GmailError::from_response(response).await.into());
+        }
+    }
+    Ok(())
+}
+
+fn rfc3339(secs: i64) -> String {
+    DateTime::from_timestamp(secs, 0)
+        .unwrap_or_default()
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockGmail;
+
+    fn time(text: &str) -> DateTime<Utc> {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_schedule_fields() {
+        let nightly: Schedule = "0 2 * * *".parse().unwrap();
+        assert!(nightly.fires_at(time("2024-10-21T02:00:00Z")));
+        assert!(!nightly.fires_at(time("2024-10-21T02:01:00Z")));
+        assert!(!nightly.fires_at(time("2024-10-21T03:00:00Z")));
+
+        let weekdays: Schedule = "*/15 9-17 * * 1-5".parse().unwrap();
+        // Monday, then Sunday
+        assert!(weekdays.fires_at(time("2024-10-21T09:45:00Z")));
+        assert!(!weekdays.fires_at(time("2024-10-21T09:50:00Z")));
+        assert!(!weekdays.fires_at(time("2024-10-20T09:45:00Z")));
+
+        // Sunday can be 7, and the first of the month or a Sunday either matches
+        let either: Schedule = "30 6 1 * 7".parse().unwrap();
+        assert!(either.fires_at(time("2024-10-20T06:30:00Z")));
+        assert!(either.fires_at(time("2024-10-01T06:30:00Z")));
+        assert!(!either.fires_at(time("2024-10-02T06:30:00Z")));
+
+        assert_eq!(
+            "@daily".parse::<Schedule>().unwrap(),
+            "0 0 * * *".parse().unwrap()
+        );
+        for invalid in [
+            "",
+            "0 2 * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+        ] {
+            assert!(invalid.parse::<Schedule>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_schedule_fires_between() {
+        let nightly: Schedule = "0 2 * * *".parse().unwrap();
+        assert!(nightly.fires_between(time("2024-10-21T01:55:00Z"), time("2024-10-21T02:03:00Z")));
+        assert!(!nightly.fires_between(time("2024-10-21T02:00:00Z"), time("2024-10-21T02:03:00Z")));
+    }
+
+    #[test]
+    fn test_job_runs_keep_the_latest() {
+        let runs = JobRuns::open_in_memory().unwrap();
+        let run = |job: &str, started_at: i64| JobRun {
+            job: job.to_string(),
+            account: "default".to_string(),
+            started_at,
+            finished_at: started_at + 1,
+            messages: 3,
+            dry_run: false,
+            error: None,
+        };
+        for started_at in 0..RUNS_KEPT as i64 + 5 {
+            runs.record(&run("receipts", started_at)).unwrap();
+        }
+        runs.record(&run("newsletters", 1000)).unwrap();
+
+        let latest = runs.latest(Some("receipts"), 500).unwrap();
+        assert_eq!(latest.len(), RUNS_KEPT as usize);
+        assert_eq!(latest[0], run("receipts", RUNS_KEPT as i64 + 4));
+        assert_eq!(
+            runs.latest(None, 1).unwrap(),
+            vec![run("newsletters", 1000)]
+        );
+    }
+
+    #[test]
+    fn test_load_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(JOBS_FILE);
+        std::fs::write(
+            &path,
+            r#"
+[[jobs]]
+name = "receipts"
+schedule = "0 2 * * *"
+query = "subject:receipt older_than:7d"
+actions = [{ action = "label", label = "Receipts" }, { action = "archive" }]
+"#,
+        )
+        .unwrap();
+        let jobs = load(&path).unwrap();
+        assert_eq!(jobs[0].actions.len(), 2);
+        assert_eq!(jobs[0].max_messages, None);
+
+        std::fs::write(
+            &path,
+            r#"
+[[jobs]]
+name = "forward"
+schedule = "@daily"
+query = "subject:receipt"
+actions = [{ action = "forward", to = "books@example.com" }]
+"#,
+        )
+        .unwrap();
+        assert!(load(&path).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_job_against_mock_gmail() {
+        let gmail = MockGmail::start().await;
+        let gmail_server = gmail.gmail_server(&gmail.config()).await;
+        let job = Job {
+            name: "tidy".to_string(),
+            schedule: "@daily".to_string(),
+            query: "subject:receipt".to_string(),
+            account: None,
+            actions: vec![
+                Action::Label {
+                    label: "Snoozed".to_string(),
+                },
+                Action::Archive,
+            ],
+            max_messages: None,
+        };
+        let run = run_job(&gmail_server, "default", &job).await;
+        assert_eq!(run.error, None);
+        assert_eq!(run.messages, 1);
+
+        let requests = gmail.requests().await;
+        let body: Value = serde_json::from_slice(&requests.last().unwrap().body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "ids": ["msg-bounce"],
+                "addLabelIds": ["Label_snoozed"],
+                "removeLabelIds": ["INBOX"],
+            })
+        );
+    }
+}
@@ -0,0 +1,64 @@
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::collections::HashMap;
+
+use crate::gmail::{GmailClient, GMAIL_API_BASE};
+
+/// The ID of the label named `name`, if the mailbox has one
+pub async fn find_label(client: &GmailClient, user_id: &str, name: &str) -> Result<Option<String>> {
+    let labels = client
+        .get_json(&format!("{GMAIL_API_BASE}/users/{user_id}/labels"))
+        .await
+        .context("Failed to list labels")?;
+    Ok(labels["labels"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|label| label["name"] == name)
+        .and_then(|label| label["id"].as_str())
+        .map(str::to_string))
+}
+
+/// The ID of the label named `name`, creating a visible one if the mailbox has none
+pub async fn find_or_create_label(
+    client: &GmailClient,
+    user_id: &str,
+    name: &str,
+) -> Result<String> {
+    if let Some(label_id) = find_label(client, user_id, name).await? {
+        return Ok(label_id);
+    }
+    let label = client
+        .post_json(
+            &format!("{GMAIL_API_BASE}/users/{user_id}/labels"),
+            &json!({
+                "name": name,
+                "labelListVisibility": "labelShow",
+                "messageListVisibility": "show",
+            }),
+        )
+        .await
+        .with_context(|| format!("Failed to create the {name} label"))?;
+    label["id"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| anyhow::anyhow!("Created label has no ID"))
+}
+
+/// Names of the mailbox's labels, keyed by label ID
+pub async fn label_names(client: &GmailClient, user_id: &str) -> Result<HashMap<String, String>> {
+    let labels = client
+        .get_json(&format!("{GMAIL_API_BASE}/users/{user_id}/labels"))
+        .await
+        .context("Failed to list labels")?;
+    Ok(labels["labels"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|label| {
+            let id = label["id"].as_str()?;
+            let name = label["name"].as_str()?;
+            Some((id.to_string(), name.to_string()))
+        })
+        .collect())
+}
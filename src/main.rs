@@ -1,17 +1,69 @@
+mod accounts;
+mod audit;
 mod auth;
+mod batch;
+mod bench;
+mod bounces;
+mod cache;
+mod caller_tokens;
+mod check_config;
+mod classifier;
 mod config;
+mod config_file;
+mod conversation;
+mod csrf_store;
+mod demo;
+mod drive;
 mod email;
+mod export;
 mod extract;
+mod fixtures;
 mod gmail;
+mod history;
+mod jobs;
+mod labels;
+mod markdown;
+mod mcp_sessions;
 mod metrics;
+mod mime;
 mod oauth;
+mod openapi;
+mod outbox;
+mod pgp;
+mod phishing;
+mod preview;
+mod prompts;
+mod rate_limit;
+mod redact;
+mod refresh;
+mod reload;
+mod rules;
+mod saved_searches;
+mod scan;
+mod secrets;
 mod server;
+mod sessions;
+mod shutdown;
+mod snooze;
+mod telemetry;
+// A fake Gmail API for end-to-end tests of the tools, also built with the `test-support` feature
+#[cfg(any(test, feature = "test-support"))]
+#[cfg_attr(not(test), allow(dead_code))]
+mod test_support;
+mod thread_attachments;
+mod thread_changes;
+mod tls;
+mod token_store;
 mod tools;
+mod truncate;
+mod unix_socket;
 mod utils;
+mod watch_inbox;
+mod webhooks;
 
 use anyhow::{Context, Result};
 use axum::{
-    extract::{Query, State},
+    extract::{ConnectInfo, FromRequest, FromRequestParts, Path, Query, RawPathParams, State},
     http::{header, StatusCode},
     response::{Html, IntoResponse, Response},
     routing::get,
@@ -22,59 +74,59 @@ use axum_prometheus::PrometheusMetricLayer;
 use axum::body::Body;
 use axum::extract::Request;
 use axum::middleware::Next;
-use bytes::Bytes;
-use clap::Parser;
-use config::{Cli, Commands, Config, HttpConfig, ToolsCmd};
+use config::{AuthCmd, Commands, Config, HttpConfig, ToolsCmd};
 use dotenv::dotenv;
+use futures::StreamExt;
 use http_body_util::BodyExt;
-use rmcp::transport::streamable_http_server::{
-    session::local::LocalSessionManager, StreamableHttpService,
-};
+use rmcp::transport::streamable_http_server::StreamableHttpService;
 use rmcp::transport::{sse_server::SseServerConfig, SseServer};
-use serde::Deserialize;
 use serde_json::Value;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::RwLock;
+use tokio::sync::{watch, Semaphore};
 use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 use tower::ServiceBuilder;
+use tower_http::limit::RequestBodyLimitLayer;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
 use tower_http::trace::TraceLayer;
-use tracing::{debug, error, info, trace, Level};
+use tracing::{debug, error, info, trace, warn, Level};
 
 use crate::server::{
-    CreateDraftArgs, DownloadAttachmentArgs, ExtractAttachmentArgs, FetchEmailBodiesArgs,
-    ForwardEmailArgs, SearchThreadsArgs, SendDraftArgs,
+    AddRuleArgs, CancelOutboxMessageArgs, ClassifyMessageArgs, CreateDraftArgs,
+    DeleteSavedSearchArgs, DownloadAttachmentArgs, EmailToMarkdownArgs, ExportThreadArgs,
+    ExtractAttachmentArgs, ExtractDriveAttachmentsArgs, FetchEmailBodiesArgs, FindBouncesArgs,
+    ForwardEmailArgs, GetConversationArgs, GetThreadChangesArgs, ListJobRunsArgs, ListOutboxArgs,
+    ListRulesArgs, ListSavedSearchesArgs, ListSnoozedArgs, ListThreadAttachmentsArgs,
+    RemoveRuleArgs, SaveSearchArgs, ScorePhishingRiskArgs, SearchThreadsArgs, SendDraftArgs,
+    SnoozeThreadArgs, UpdateSavedSearchArgs,
 };
 
-#[derive(Deserialize)]
-#[allow(dead_code)]
-struct CallbackQuery {
-    code: Option<String>,
-    error: Option<String>,
-}
-
-/// Middleware to log request bodies at trace level
+/// Middleware to log request bodies at trace level, redacted unless --log-unredacted is set
 async fn log_request_body(request: Request, next: Next) -> axum::response::Response {
+    // Buffering the body is only worth it when it will be logged
+    if !tracing::enabled!(Level::TRACE) {
+        return next.run(request).await;
+    }
     let (parts, body) = request.into_parts();
 
-    // Try to collect the body for logging (limit to 1MB to avoid memory issues)
-    let body_bytes = match body.collect().await {
-        Ok(collected) => {
-            let bytes = collected.to_bytes();
-            // Only log if body is reasonable size (1MB limit)
-            if bytes.len() <= 1_048_576 {
-                trace!("request body: {:?}", String::from_utf8_lossy(&bytes));
-            } else {
-                trace!("request body: <too large to log ({} bytes)>", bytes.len());
-            }
-            bytes
-        }
-        Err(e) => {
-            trace!("failed to read request body: {}", e);
-            Bytes::new()
-        }
+    let body_bytes = match buffer_body(body).await {
+        Ok(bytes) => bytes,
+        Err(response) => return response,
     };
+    // Only log if body is reasonable size (1MB limit)
+    if body_bytes.len() <= 1_048_576 {
+        trace!(
+            "request body: {:?}",
+            redact::redact(&String::from_utf8_lossy(&body_bytes))
+        );
+    } else {
+        trace!(
+            "request body: <too large to log ({} bytes)>",
+            body_bytes.len()
+        );
+    }
 
     // Reconstruct the request with the buffered body
     let body = Body::from(body_bytes);
@@ -83,58 +135,548 @@ async fn log_request_body(request: Request, next: Next) -> axum::response::Respo
     next.run(request).await
 }
 
+/// Collect a request body, answering 413 past `--max-body-bytes` and 400 when it can't be read
+async fn buffer_body(body: Body) -> Result<bytes::Bytes, axum::response::Response> {
+    body.collect()
+        .await
+        .map(|collected| collected.to_bytes())
+        .map_err(|e| {
+            trace!("failed to read request body: {}", e);
+            let too_large = e
+                .into_inner()
+                .downcast_ref::<http_body_util::LengthLimitError>()
+                .is_some();
+            if too_large {
+                StatusCode::PAYLOAD_TOO_LARGE.into_response()
+            } else {
+                (StatusCode::BAD_REQUEST, "Failed to read request body").into_response()
+            }
+        })
+}
+
+/// Middleware rejecting MCP, `/tools` and auth requests that don't carry the `--api-key`, when
+/// set. Session logins are opened in a browser, which can't send the key
+async fn require_api_key(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> axum::response::Response {
+    let http_config = &state.http_config;
+    let Some(api_key) = &http_config.api_key else {
+        return next.run(request).await;
+    };
+    if auth::is_session_login(&state, request.uri()) {
+        return next.run(request).await;
+    }
+    if utils::has_api_key(request.headers(), &http_config.api_key_header, api_key) {
+        return next.run(request).await;
+    }
+    if http_config
+        .api_key_header
+        .eq_ignore_ascii_case(header::AUTHORIZATION.as_str())
+    {
+        (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+        )
+            .into_response()
+    } else {
+        StatusCode::UNAUTHORIZED.into_response()
+    }
+}
+
+/// Middleware answering 429 to clients over `--rate-limit-per-minute`, when set
+async fn rate_limit(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    request: Request,
+    next: Next,
+) -> axum::response::Response {
+    let rate_limiter = &state.rate_limiter;
+    if !rate_limiter.enabled() {
+        return next.run(request).await;
+    }
+    let http_config = &state.http_config;
+    let ip = connect_info.map_or_else(|| "unknown".to_string(), |info| info.0.ip().to_string());
+    // Only a key the server accepts names the client, or sending a new value each time would
+    // get a fresh bucket
+    let api_key = match (rate_limiter.key(), &http_config.api_key) {
+        (config::RateLimitKey::ApiKey, Some(api_key))
+            if utils::has_api_key(request.headers(), &http_config.api_key_header, api_key) =>
+        {
+            request
+                .headers()
+                .get(&http_config.api_key_header)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| format!("key:{}", value.trim()))
+        }
+        _ => None,
+    };
+    match rate_limiter.check(api_key.as_deref().unwrap_or(&ip)).await {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => {
+            metrics::record_rate_limited();
+            debug!("Rate limited request from {}", ip);
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(
+                    header::RETRY_AFTER,
+                    retry_after.as_secs_f64().ceil().max(1.0).to_string(),
+                )],
+                "Rate limit exceeded",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Put `routes` behind the API key, client certificate and rate limit checks
+fn protect(routes: Router<AppState>, state: &AppState) -> Router<AppState> {
+    routes
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            require_api_key,
+        ))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            require_client_certificate,
+        ))
+        // Outermost, so rejected and unauthenticated requests count against the client too
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit,
+        ))
+}
+
+/// Run the request, keeping `guard` alive until the response body ends, since MCP results
+/// stream back in it, except for the long-lived event streams clients open with GET to listen
+/// for server messages
+async fn hold_while_in_flight<G: Send + Sync + 'static>(
+    guard: G,
+    request: Request,
+    next: Next,
+) -> axum::response::Response {
+    let listening = request.method() == axum::http::Method::GET;
+    let response = next.run(request).await;
+    let event_stream = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"text/event-stream"));
+    if listening && event_stream {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = body.into_data_stream().map(move |chunk| {
+        let _guard = &guard;
+        chunk
+    });
+    axum::response::Response::from_parts(parts, Body::from_stream(body))
+}
+
+/// Middleware answering 503 once `--max-in-flight-requests` are already being handled
+async fn limit_in_flight(
+    State(in_flight): State<Arc<Semaphore>>,
+    request: Request,
+    next: Next,
+) -> axum::response::Response {
+    let Ok(permit) = in_flight.try_acquire_owned() else {
+        metrics::record_shed();
+        debug!("Shedding request, too many in flight");
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, "1")],
+            "Server is busy",
+        )
+            .into_response();
+    };
+    hold_while_in_flight(permit, request, next).await
+}
+
+/// Middleware tracking MCP and `/tools` requests so shutdown can wait for them. Once the
+/// tracker is closed for shutdown, requests outside an existing MCP session get 503
+async fn track_in_flight(
+    State(tracker): State<TaskTracker>,
+    request: Request,
+    next: Next,
+) -> axum::response::Response {
+    if tracker.is_closed() && telemetry::session_id(request.headers(), request.uri()).is_none() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::CONNECTION, "close")],
+            "Server is shutting down",
+        )
+            .into_response();
+    }
+    hold_while_in_flight(tracker.token(), request, next).await
+}
+
+/// Middleware rejecting MCP, `/tools` and auth requests whose connection presented no client
+/// certificate signed by `--tls-client-ca`, when set. Session logins are let through like the
+/// API key lets them through
+async fn require_client_certificate(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> axum::response::Response {
+    if state.http_config.tls_config.tls_client_ca.is_none()
+        || auth::is_session_login(&state, request.uri())
+    {
+        return next.run(request).await;
+    }
+    let verified = request
+        .extensions()
+        .get::<tls::ClientCertificate>()
+        .is_some_and(|certificate| certificate.verified);
+    if verified {
+        next.run(request).await
+    } else {
+        (
+            StatusCode::FORBIDDEN,
+            "A client certificate signed by the configured CA is required",
+        )
+            .into_response()
+    }
+}
+
+/// Middleware recording each `/tools` call in the audit log and sending it to `tool_invoked`
+/// webhooks, when enabled
+async fn audit_tool_request(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> axum::response::Response {
+    let (audit_log, webhooks) = (
+        state.gmail_server.audit_log(),
+        state.gmail_server.webhooks(),
+    );
+    if audit_log.is_none() && webhooks.is_none() {
+        return next.run(request).await;
+    }
+
+    let tool = tool_name(&request).to_string();
+    // POSTed arguments are in the body, which is buffered to read them
+    let (arguments, request) = if request.method() == axum::http::Method::POST {
+        let (parts, body) = request.into_parts();
+        let bytes = match buffer_body(body).await {
+            Ok(bytes) => bytes,
+            Err(response) => return response,
+        };
+        let arguments = serde_json::from_slice::<serde_json::Map<String, Value>>(&bytes)
+            .map(Value::Object)
+            .unwrap_or_default();
+        (arguments, Request::from_parts(parts, Body::from(bytes)))
+    } else {
+        let mut arguments = Query::<serde_json::Map<String, Value>>::try_from_uri(request.uri())
+            .map(|Query(arguments)| arguments)
+            .unwrap_or_default();
+        // The attachment route takes its arguments in the path
+        let (mut parts, body) = request.into_parts();
+        if let Ok(params) = RawPathParams::from_request_parts(&mut parts, &state).await {
+            for (name, value) in &params {
+                arguments.insert(name.to_string(), Value::from(value));
+            }
+        }
+        (Value::Object(arguments), Request::from_parts(parts, body))
+    };
+    let request_id = telemetry::request_id(request.headers()).map(str::to_string);
+    let session_id = telemetry::session_id(request.headers(), request.uri()).map(str::to_string);
+    let started = std::time::SystemTime::now();
+    let timer = std::time::Instant::now();
+
+    let response = next.run(request).await;
+
+    // Handlers report failures as a plain-text body; keep it for the entry and the client
+    let (response, error) = if response.status().is_success() {
+        (response, None)
+    } else {
+        let (parts, body) = response.into_parts();
+        let bytes = body
+            .collect()
+            .await
+            .map(|collected| collected.to_bytes())
+            .unwrap_or_default();
+        let error = format!("{}: {}", parts.status, String::from_utf8_lossy(&bytes));
+        (Response::from_parts(parts, Body::from(bytes)), Some(error))
+    };
+
+    let mut entry =
+        audit::AuditEntry::new(&tool, "http", &arguments, started, timer.elapsed(), error);
+    entry.request_id = request_id.as_deref();
+    entry.session_id = session_id.as_deref();
+    if let Some(audit_log) = audit_log {
+        audit_log.record(&entry);
+    }
+    if let Some(webhooks) = webhooks {
+        webhooks.send(webhooks::WebhookEvent::ToolInvoked, entry.event_fields());
+    }
+
+    response
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize tracing subscriber with default log level if RUST_LOG is not set
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
-        )
-        .init();
+    // Load environment variables from .env file if it exists, before parsing flags that read them
+    let dotenv_loaded = dotenv().is_ok();
+
+    let cli = config_file::parse_cli()?;
+    let mut config = cli.config;
 
-    // Load environment variables from .env file if it exists
-    if dotenv().is_ok() {
+    // Initialize tracing; the guard flushes exported traces on exit
+    let _telemetry = telemetry::init(&config.telemetry)?;
+    if dotenv_loaded {
         info!("Loaded .env file");
     }
-
-    let cli = Cli::parse();
-    let config = cli.config;
+    if let Some(path) = &config.config_file {
+        match &config.profile {
+            Some(profile) => info!(
+                "Loaded config file {} (profile {})",
+                path.display(),
+                profile
+            ),
+            None => info!("Loaded config file {}", path.display()),
+        }
+    }
+    secrets::resolve_client_credentials(&mut config).await?;
+    if config.demo {
+        demo::start(&mut config).await?;
+    }
 
     match cli.command {
-        Commands::Http(http_config) => run_http_server(config, http_config).await,
-        Commands::Tools { tool } => run_tools(config, tool).await,
+        Commands::Http(http_config) => {
+            run_http_server(config, http_config, _telemetry.log_filter()).await
+        }
+        Commands::CheckConfig(http_config) => check_config::run(&config, &http_config),
+        Commands::Tools { account, tool } => run_tools(config, account.as_deref(), tool).await,
+        Commands::Bench(bench_config) => bench::run(config, &bench_config).await,
+        Commands::Auth { account, command } => run_auth(config, &account, command).await,
     }
 }
 
-async fn run_tools(config: Config, tool: ToolsCmd) -> Result<()> {
-    let oauth_manager = Arc::new(oauth::OAuthManager::new(config.clone(), HttpConfig::default())?);
-    if let Ok(Some(token)) = oauth_manager.load_token().await {
+async fn run_auth(config: Config, account: &str, command: AuthCmd) -> Result<()> {
+    accounts::ensure_configured(&config, account)?;
+    let token_location = token_store::open(&config, account)?.location();
+    match command {
+        AuthCmd::Login {
+            timeout_secs,
+            no_browser,
+            scope,
+        } => {
+            let timeout = Duration::from_secs(timeout_secs);
+            auth::loopback_login(&config, account, &scope, !no_browser, timeout).await?;
+            println!("✅ Logged in, token saved to {token_location}");
+        }
+        AuthCmd::Status => {
+            let oauth_manager = load_account_token(&config, account).await?;
+            let gmail_server = gmail::GmailServer::new(oauth_manager.clone(), &config)?;
+            let mut status = auth::auth_status(&oauth_manager, &gmail_server).await;
+            status["account"] = Value::String(account.to_string());
+            status["token_store"] = Value::String(token_location);
+            println!("{}", serde_json::to_string_pretty(&status)?);
+        }
+        AuthCmd::Refresh => {
+            let oauth_manager = load_account_token(&config, account).await?;
+            let token = oauth_manager.refresh_token().await?;
+            println!(
+                "✅ Token refreshed, expires in {}s and saved to {}",
+                token.expires_in, token_location
+            );
+        }
+        AuthCmd::Revoke => {
+            let oauth_manager = load_account_token(&config, account).await?;
+            oauth_manager.revoke_token().await?;
+            println!("✅ Token revoked and removed from {token_location}");
+        }
+        AuthCmd::Accounts => {
+            for name in accounts::configured_accounts(&config)? {
+                let store = token_store::open(&config, &name)?;
+                let token = if matches!(store.load().await, Ok(Some(_))) {
+                    "token present"
+                } else {
+                    "no token"
+                };
+                println!("{name}\t{token}\t{}", store.location());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Load an account's stored token at startup. A token that is still valid, or whose refresh
+/// token the background refresh can renew, serves tool calls straight away instead of after a
+/// new login.
+async fn restore_token(
+    name: &str,
+    account: &accounts::Account,
+) -> Result<Option<oauth::OAuthToken>> {
+    let Some(token) = account.oauth_manager.load_token().await? else {
+        return Ok(None);
+    };
+    account.oauth_manager.set_token(token.clone()).await;
+    if token.is_usable() {
+        if token.is_expired() {
+            info!("🔑 Stored token for account {name} expired, refreshing it");
+        }
+        // Tells agents whose mailbox they act on, in the MCP instructions. Looked up in the
+        // background so a slow Gmail doesn't hold up the listener
+        let (name, gmail_server) = (name.to_string(), account.gmail_server.clone());
+        tokio::spawn(async move {
+            match gmail_server.email_address().await {
+                Ok(address) => info!("🔑 Account {name} is {address}"),
+                Err(e) => warn!("Failed to look up the address of account {name}: {e:#}"),
+            }
+        });
+    } else {
+        warn!("🔑 Stored token for account {name} expired and can't be refreshed, log in again");
+    }
+    Ok(Some(token))
+}
+
+/// An OAuth manager for the account with its stored token, if any, loaded
+async fn load_account_token(config: &Config, account: &str) -> Result<Arc<oauth::OAuthManager>> {
+    let oauth_manager = Arc::new(oauth::OAuthManager::for_account(
+        config.clone(),
+        HttpConfig::default(),
+        account,
+    )?);
+    if let Some(token) = oauth_manager.load_token().await? {
         oauth_manager.set_token(token).await;
+    }
+    Ok(oauth_manager)
+}
+
+/// A Gmail server for a command line call, using the account's saved token. Without one it fails,
+/// unless `require_token` is false for a send that waits in the outbox for a login
+async fn authenticated_gmail_server(
+    config: &Config,
+    account: Option<&str>,
+    require_token: bool,
+) -> Result<Arc<gmail::GmailServer>> {
+    let account_name = accounts::resolve(config, account)?;
+    let oauth_manager = Arc::new(if config.demo {
+        demo::oauth_manager(config, HttpConfig::default()).await?
     } else {
-        return Err(anyhow::anyhow!("Not authenticated. Please run the http command and login first."));
+        oauth::OAuthManager::for_account(config.clone(), HttpConfig::default(), &account_name)?
+    });
+    if let Ok(Some(token)) = oauth_manager.load_token().await {
+        oauth_manager.set_token(token).await;
+    } else if require_token && config.gmail_api.gmail_fixtures != Some(config::FixtureMode::Replay)
+    {
+        return Err(anyhow::anyhow!(
+            "Not authenticated as account '{account_name}'. Please run `auth login --account {account_name}` or the http command and login first."
+        ));
+    }
+
+    Ok(Arc::new(
+        gmail::GmailServer::new(oauth_manager, config)?.named(&account_name),
+    ))
+}
+
+async fn run_tools(config: Config, account: Option<&str>, tool: ToolsCmd) -> Result<()> {
+    let waits_for_login = matches!(
+        tool,
+        ToolsCmd::ForwardEmail {
+            enqueue_if_unauthenticated: true,
+            ..
+        } | ToolsCmd::SendDraft {
+            enqueue_if_unauthenticated: true,
+            ..
+        }
+    );
+    let gmail_server = authenticated_gmail_server(&config, account, !waits_for_login).await?;
+
+    // Runs until interrupted and prints as it goes, so it isn't audited like a tool call
+    if let ToolsCmd::Watch {
+        query,
+        interval_secs,
+        max_results,
+        json,
+    } = &tool
+    {
+        let interval = Duration::from_secs(*interval_secs);
+        return watch_inbox::watch(&gmail_server, query, interval, *max_results, *json).await;
     }
 
-    let gmail_server = Arc::new(gmail::GmailServer::new(oauth_manager)?);
-    gmail_server.set_authenticated(true).await;
+    // Serialized before the match consumes it: {"tool": ..., <arguments>}
+    let mut invocation = serde_json::to_value(&tool)?;
+    if let Some(account) = account {
+        invocation["account"] = Value::String(account.to_string());
+    }
+    let tool_name = invocation["tool"].as_str().unwrap_or_default();
+    if config.read_only && server::is_mutating(tool_name) {
+        return Err(anyhow::anyhow!(tools::read_only_error(tool_name)));
+    }
+    let started = std::time::SystemTime::now();
+    let timer = std::time::Instant::now();
 
     let result = match tool {
-        ToolsCmd::SearchThreads { query, max_results } => {
-            tools::search_threads(&gmail_server, &query, max_results).await
+        ToolsCmd::SearchThreads {
+            query,
+            max_results,
+            fields,
+            last_n_days,
+            start_date,
+            end_date,
+            utc_offset,
+            category,
+            is_important,
+            body_regex,
+            header_regex,
+        } => {
+            let filter = tools::SearchFilter {
+                last_n_days,
+                start_date,
+                end_date,
+                utc_offset,
+                category,
+                is_important,
+                body_regex,
+                header_regex,
+            };
+            tools::search_threads(
+                &gmail_server,
+                &query,
+                max_results,
+                fields.as_deref(),
+                &filter,
+            )
+            .await
         }
         ToolsCmd::CreateDraft {
             to,
             subject,
             body,
             thread_id,
-        } => tools::create_draft(&gmail_server, &to, &subject, &body, thread_id.as_deref()).await,
+            exclude_own_address,
+        } => {
+            tools::create_draft(
+                &gmail_server,
+                &to,
+                &subject,
+                &body,
+                thread_id.as_deref(),
+                exclude_own_address,
+                false,
+            )
+            .await
+        }
         ToolsCmd::ExtractAttachment {
             message_id,
             filename,
-        } => tools::extract_attachment_by_filename(&gmail_server, &message_id, &filename).await,
-        ToolsCmd::FetchEmailBodies { thread_ids } => {
-            tools::fetch_email_bodies(&gmail_server, &thread_ids).await
+            preview_chars,
+        } => {
+            tools::extract_attachment_by_filename(
+                &gmail_server,
+                &message_id,
+                &filename,
+                preview_chars,
+            )
+            .await
         }
+        ToolsCmd::FetchEmailBodies {
+            thread_ids,
+            format,
+            fields,
+        } => tools::fetch_email_bodies(&gmail_server, &thread_ids, format, fields.as_deref()).await,
         ToolsCmd::DownloadAttachment {
             message_id,
             filename,
@@ -153,40 +695,205 @@ async fn run_tools(config: Config, tool: ToolsCmd) -> Result<()> {
             to,
             subject,
             body,
-        } => tools::forward_email(&gmail_server, &message_id, &to, &subject, &body).await,
-        ToolsCmd::SendDraft { draft_id } => tools::send_draft(&gmail_server, &draft_id).await,
-    }?;
+            enqueue_if_unauthenticated,
+        } => {
+            tools::forward_email(
+                &gmail_server,
+                &message_id,
+                &to,
+                &subject,
+                &body,
+                false,
+                enqueue_if_unauthenticated,
+            )
+            .await
+        }
+        ToolsCmd::SendDraft {
+            draft_id,
+            enqueue_if_unauthenticated,
+        } => tools::send_draft(&gmail_server, &draft_id, false, enqueue_if_unauthenticated).await,
+        ToolsCmd::FindBounces {
+            message_id,
+            recipient,
+            max_results,
+        } => {
+            let (message_id, recipient) = (message_id.as_deref(), recipient.as_deref());
+            bounces::find_bounces(&gmail_server, message_id, recipient, max_results).await
+        }
+        ToolsCmd::GetConversation {
+            message_id,
+            format,
+            max_threads,
+        } => conversation::get_conversation(&gmail_server, &message_id, format, max_threads).await,
+        ToolsCmd::ExportThread {
+            thread_id,
+            format,
+            download_dir,
+        } => {
+            export::export_thread(&gmail_server, &thread_id, format, download_dir.as_deref()).await
+        }
+        ToolsCmd::EmailToMarkdown { message_id } => {
+            markdown::email_to_markdown(&gmail_server, &message_id).await
+        }
+        ToolsCmd::ListThreadAttachments {
+            thread_id,
+            extract_text,
+            preview_chars,
+        } => {
+            thread_attachments::list_thread_attachments(
+                &gmail_server,
+                &thread_id,
+                extract_text,
+                preview_chars,
+            )
+            .await
+        }
+        ToolsCmd::ExtractDriveAttachments {
+            message_id,
+            preview_chars,
+        } => drive::extract_drive_attachments(&gmail_server, &message_id, preview_chars).await,
+        ToolsCmd::GetThreadChanges {
+            thread_id,
+            history_id,
+            format,
+        } => {
+            thread_changes::get_thread_changes(&gmail_server, &thread_id, &history_id, format).await
+        }
+        ToolsCmd::ClassifyMessage { message_id } => {
+            classifier::classify_message(&gmail_server, &message_id).await
+        }
+        ToolsCmd::ScorePhishingRisk { message_id } => {
+            phishing::score_phishing_risk(&gmail_server, &message_id).await
+        }
+        ToolsCmd::SnoozeThread {
+            thread_id,
+            until,
+            mark_unread,
+        } => snooze::snooze_thread(&gmail_server, &thread_id, &until, mark_unread).await,
+        ToolsCmd::ListSnoozed => snooze::list_snoozed(&gmail_server).await,
+        ToolsCmd::ListRules => rules::list_rules(&gmail_server).await,
+        ToolsCmd::AddRule {
+            name,
+            query,
+            label,
+            archive,
+            forward,
+            notify,
+            classify,
+        } => {
+            let mut actions: Vec<rules::Action> = label
+                .into_iter()
+                .map(|label| rules::Action::Label { label })
+                .collect();
+            if archive {
+                actions.push(rules::Action::Archive);
+            }
+            actions.extend(forward.map(|to| rules::Action::Forward { to }));
+            if classify {
+                actions.push(rules::Action::Classify);
+            }
+            actions.extend(notify.map(|url| rules::Action::Notify { url }));
+            let rule = rules::Rule {
+                name,
+                query,
+                account: account.map(str::to_string),
+                actions,
+            };
+            rules::add_rule(&gmail_server, rule).await
+        }
+        ToolsCmd::RemoveRule { name } => rules::remove_rule(&gmail_server, &name).await,
+        ToolsCmd::ListJobRuns { job, limit } => {
+            jobs::list_job_runs(&gmail_server, job.as_deref(), limit).await
+        }
+        ToolsCmd::ListOutbox => outbox::list_outbox(&gmail_server).await,
+        ToolsCmd::CancelOutboxMessage { outbox_id } => {
+            outbox::cancel_outbox_message(&gmail_server, outbox_id).await
+        }
+        ToolsCmd::ListSavedSearches => saved_searches::list_saved_searches(&gmail_server).await,
+        ToolsCmd::SaveSearch {
+            name,
+            query,
+            description,
+        } => {
+            let search = saved_searches::SavedSearch {
+                name,
+                query,
+                description,
+            };
+            saved_searches::save_search(&gmail_server, search).await
+        }
+        ToolsCmd::UpdateSavedSearch {
+            name,
+            query,
+            description,
+        } => saved_searches::update_saved_search(&gmail_server, &name, query, description).await,
+        ToolsCmd::DeleteSavedSearch { name } => {
+            saved_searches::delete_saved_search(&gmail_server, &name).await
+        }
+        ToolsCmd::Watch { .. } => unreachable!("watch returns before the tool calls"),
+    };
+
+    let (audit_log, webhooks) = (gmail_server.audit_log(), gmail_server.webhooks());
+    if audit_log.is_some() || webhooks.is_some() {
+        let tool_name = invocation
+            .as_object_mut()
+            .and_then(|arguments| arguments.remove("tool"))
+            .and_then(|name| name.as_str().map(str::to_string))
+            .unwrap_or_default();
+        let error = result.as_ref().err().map(|e| e.to_string());
+        let entry = audit::AuditEntry::new(
+            &tool_name,
+            "cli",
+            &invocation,
+            started,
+            timer.elapsed(),
+            error,
+        );
+        if let Some(audit_log) = audit_log {
+            audit_log.record(&entry);
+        }
+        if let Some(webhooks) = webhooks {
+            webhooks.send(webhooks::WebhookEvent::ToolInvoked, entry.event_fields());
+            // Deliveries run in the background, which ends with the process
+            webhooks.flush().await;
+        }
+    }
+    let result = result?;
 
     println!("{}", serde_json::to_string_pretty(&result)?);
 
     Ok(())
 }
 
-async fn run_http_server(config: Config, http_config: HttpConfig) -> Result<()> {
+async fn run_http_server(
+    config: Config,
+    http_config: HttpConfig,
+    log_filter: telemetry::LogFilter,
+) -> Result<()> {
     // Validate required environment variables
-    if config.gmail_client_id.is_none() {
-        return Err(anyhow::anyhow!(
-            "GMAIL_CLIENT_ID environment variable not set"
-        ));
-    }
-    if config.gmail_client_secret.is_none() {
-        return Err(anyhow::anyhow!(
-            "GMAIL_CLIENT_SECRET environment variable not set"
-        ));
+    check_config::credentials(&config)?;
+    check_config::api_key(&http_config)?;
+    // Fail with the flags involved rather than axum's panic on overlapping routes
+    check_config::routes(&http_config)?;
+    if http_config.api_key.is_some() {
+        info!(
+            "🔒 MCP and tools endpoints require the API key in the {} header",
+            http_config.api_key_header
+        );
     }
+    let tls = tls::load(&http_config.tls_config)?;
 
     let app_data_dir =
         utils::get_app_data_dir(&config).context("Failed to create app data directory")?;
-    let token_file =
-        utils::get_app_file_path(&config, "token.json").context("Failed to get token file path")?;
     info!("📁 App data directory: {}", app_data_dir.display());
-    info!("🔑 Token file: {}", token_file.display());
 
     // Create OAuth manager
-    let oauth_manager = Arc::new(oauth::OAuthManager::new(
-        config.clone(),
-        http_config.clone(),
-    )?);
+    let oauth_manager = Arc::new(if config.demo {
+        demo::oauth_manager(&config, http_config.clone()).await?
+    } else {
+        oauth::OAuthManager::new(config.clone(), http_config.clone())?
+    });
+    info!("🔑 Token store: {}", oauth_manager.token_location());
 
     // Initialize Prometheus metrics recorder (axum-prometheus uses metrics-exporter-prometheus
     // which installs a global recorder that all metrics will use)
@@ -195,32 +902,256 @@ async fn run_http_server(config: Config, http_config: HttpConfig) -> Result<()>
     // Create OAuth metrics - they will automatically use the global recorder installed by axum-prometheus
     let oauth_metrics = Arc::new(metrics::OAuthMetrics::new());
 
-    let gmail_server = Arc::new(gmail::GmailServer::new(oauth_manager.clone())?);
+    let gmail_server = Arc::new(gmail::GmailServer::new(oauth_manager.clone(), &config)?);
+    let accounts = Arc::new(accounts::Accounts::new(
+        accounts::Account {
+            oauth_manager: oauth_manager.clone(),
+            gmail_server: gmail_server.clone(),
+        },
+        &config,
+        &http_config,
+    )?);
+
+    // OAuth state values issued by the login route, checked by the callback
+    let csrf_token_ttl = Duration::from_secs(http_config.auth_config.csrf_token_ttl_secs.max(1));
+    let csrf_tokens = csrf_store::open(
+        http_config.auth_config.csrf_store.as_deref(),
+        &config.token_storage.redis_key_prefix,
+        csrf_token_ttl,
+    )?;
+    info!("🛡️ OAuth login state store: {}", csrf_tokens.location());
+
+    for (name, account) in accounts.iter() {
+        if name != accounts::DEFAULT_ACCOUNT {
+            info!(
+                "🔑 Token store for account {}: {}",
+                name,
+                account.oauth_manager.token_location()
+            );
+        }
+        let token = restore_token(name, account).await?;
+        // Initialize metrics with current token state, which describe the default account
+        if name == accounts::DEFAULT_ACCOUNT {
+            oauth_metrics.update_token_metrics(token.as_ref());
+        }
+    }
+
+    let ct = CancellationToken::new();
+    csrf_store::spawn_csrf_cleanup(csrf_tokens.clone(), csrf_token_ttl, ct.clone());
+
+    // Always in place, so the config file can turn rate limiting on while the server runs
+    let rate_limiter = Arc::new(rate_limit::RateLimiter::new(&http_config.rate_limit));
+    rate_limit::spawn_rate_limit_cleanup(rate_limiter.clone(), ct.clone());
+    if rate_limiter.enabled() {
+        let client = match http_config.rate_limit.rate_limit_by {
+            config::RateLimitKey::Ip => "client IP",
+            config::RateLimitKey::ApiKey => "API key",
+        };
+        info!(
+            "🚦 MCP and tools endpoints are limited to {} requests per minute per {}",
+            http_config.rate_limit.rate_limit_per_minute, client
+        );
+    }
 
-    // Store CSRF tokens temporarily (in production, use Redis or similar)
-    let csrf_tokens: Arc<RwLock<std::collections::HashMap<String, String>>> =
-        Arc::new(RwLock::new(std::collections::HashMap::new()));
+    // Settings the config file can change while the server runs
+    let reloadable = reload::Reloadable {
+        log_filter,
+        rate_limiter: rate_limiter.clone(),
+        cache_sync_interval: watch::Sender::new(Duration::from_secs(
+            config.cache_sync_interval_secs,
+        )),
+        token_refresh_lead: watch::Sender::new(Duration::from_secs(config.token_refresh_lead_secs)),
+        secret_refresh_interval: watch::Sender::new(Duration::from_secs(
+            config.secret_manager.secret_refresh_interval_secs,
+        )),
+    };
 
-    // Initialize metrics with current token state
-    if let Some(token) = oauth_manager.load_token().await? {
-        oauth_manager.set_token(token.clone()).await;
-        oauth_metrics.update_token_metrics(Some(&token));
-        if !token.is_expired() {
-            gmail_server.set_authenticated(true).await;
+    // Pick up client credentials rotated in the secret manager without a restart
+    if secrets::has_secret_refs(&config) && config.secret_manager.secret_refresh_interval_secs > 0 {
+        secrets::spawn_credential_rotation(
+            config.clone(),
+            reloadable.secret_refresh_interval.subscribe(),
+            ct.clone(),
+        );
+    }
+
+    // Tasks that write tokens or the cache, given the drain timeout to finish on shutdown
+    let mut persisting_tasks = Vec::new();
+
+    // Refresh the access token before it expires rather than after requests start failing
+    if config.token_refresh_lead_secs > 0 {
+        for (name, account) in accounts.iter() {
+            let metrics = (name == accounts::DEFAULT_ACCOUNT).then(|| oauth_metrics.clone());
+            persisting_tasks.push(refresh::spawn_token_refresh(
+                name.to_string(),
+                account.oauth_manager.clone(),
+                metrics,
+                gmail_server.webhooks().cloned(),
+                reloadable.token_refresh_lead.subscribe(),
+                ct.clone(),
+            ));
+        }
+    }
+
+    // Keep the message cache in step with mailbox changes
+    if gmail_server.cache().is_some() && config.cache_sync_interval_secs > 0 {
+        persisting_tasks.push(history::spawn_history_sync(
+            gmail_server.clone(),
+            reloadable.cache_sync_interval.subscribe(),
+            ct.clone(),
+        ));
+    }
+
+    // Put snoozed threads back in the inbox when they're due, including ones snoozed from the
+    // command line or while the server was down, which relabels threads a read-only server
+    // mustn't touch
+    if !config.demo {
+        if config.read_only {
+            info!("⏰ Snoozed threads aren't woken in read-only mode");
+        } else {
+            let snoozes = snooze::SnoozeStore::open(&app_data_dir.join(snooze::SNOOZE_FILE))?;
+            persisting_tasks.push(snooze::spawn_snooze_wake(
+                accounts.clone(),
+                snoozes,
+                ct.clone(),
+            ));
+        }
+    }
+
+    // Retry queued sends, including ones queued from the command line, which can't be sent from
+    // a read-only server
+    if config.outbox && !config.demo {
+        if config.read_only {
+            info!("📤 The outbox worker is off in read-only mode");
+        } else {
+            let outbox = outbox::OutboxStore::open(&app_data_dir.join(outbox::OUTBOX_FILE))?;
+            persisting_tasks.push(outbox::spawn_outbox_worker(
+                accounts.clone(),
+                outbox,
+                config.outbox_max_retries,
+                ct.clone(),
+            ));
+        }
+    }
+
+    // Act on new mail matching the triage rules, which can't change a read-only mailbox
+    if !config.demo && config.rules_interval_secs > 0 {
+        if config.read_only {
+            info!("📋 Triage rules are off in read-only mode");
+        } else {
+            persisting_tasks.push(rules::spawn_rules_watch(
+                accounts.clone(),
+                config.rules_file(),
+                Duration::from_secs(config.rules_interval_secs),
+                ct.clone(),
+            ));
         }
-    } else {
-        oauth_metrics.update_token_metrics(None);
     }
 
+    // Run the scheduled jobs, which can't change a read-only mailbox either
+    if !config.demo {
+        if config.read_only {
+            info!("🗓️ Scheduled jobs are off in read-only mode");
+        } else {
+            let runs = jobs::JobRuns::open(&app_data_dir.join(jobs::JOB_RUNS_FILE))?;
+            persisting_tasks.push(jobs::spawn_job_scheduler(
+                accounts.clone(),
+                config.jobs_file(),
+                runs,
+                ct.clone(),
+            ));
+        }
+    }
+
+    // Tell webhooks about mail arriving in each account's inbox
+    if let Some(webhooks) = gmail_server
+        .webhooks()
+        .filter(|webhooks| webhooks.wants(webhooks::WebhookEvent::NewMail))
+    {
+        if config.webhook_poll_interval_secs > 0 {
+            webhooks::spawn_new_mail_watch(
+                accounts.clone(),
+                webhooks.clone(),
+                Duration::from_secs(config.webhook_poll_interval_secs),
+                ct.clone(),
+            );
+        }
+    }
+
+    if let Some(path) = &config.config_file {
+        if http_config.config_reload_interval_secs > 0 {
+            reload::spawn_config_reload(
+                path.clone(),
+                reloadable,
+                reload::Settings::new(&config, &http_config),
+                Duration::from_secs(http_config.config_reload_interval_secs),
+                ct.clone(),
+            );
+        }
+    }
+
+    // In multi-user mode every MCP session logs in with its own Google account
+    let session_accounts = if http_config.multi_user {
+        let idle_timeout = Duration::from_secs(http_config.session_idle_timeout_secs.max(1));
+        let session_accounts = Arc::new(sessions::SessionAccounts::new(
+            gmail_server.clone(),
+            &config,
+            &http_config,
+            idle_timeout,
+        ));
+        sessions::spawn_session_cleanup(session_accounts.clone(), ct.clone());
+        info!("👥 Multi-user mode: each MCP session logs in with its own token");
+        Some(session_accounts)
+    } else {
+        None
+    };
+
+    // Behind a gateway that already handles Google auth, callers bring their own access tokens
+    let caller_tokens = if http_config.trust_caller_tokens {
+        info!("🔐 Trusting Google access tokens from Authorization: Bearer headers");
+        Some(Arc::new(caller_tokens::CallerTokens::new(
+            gmail_server.clone(),
+            &config,
+            &http_config,
+        )))
+    } else {
+        None
+    };
+
     // Create MCP server
-    let mcp_server = server::GmailMcpServer::new(gmail_server.clone());
+    let mut mcp_server = server::GmailMcpServer::new(
+        accounts.clone(),
+        session_accounts.clone(),
+        caller_tokens.clone(),
+        http_config.clone(),
+    );
+    if config.read_only {
+        mcp_server = mcp_server.read_only();
+        info!(
+            "🔒 Read-only: {} are disabled",
+            server::mutating_tools().join(", ")
+        );
+    }
+    let openapi_document = openapi::document(&mcp_server.tools(), &http_config);
 
     // Create StreamableHttpService for HTTP streaming
     let http_stream_route = http_config.http_stream_route();
+    let session_store = mcp_sessions::open(
+        http_config.session_store.as_deref(),
+        &config.token_storage.redis_key_prefix,
+        http_config.session_store_ttl_secs,
+    )?;
+    if let Some(session_store) = &session_store {
+        info!("🗄️ MCP sessions are kept in {}", session_store.location());
+    }
     let mcp_server_for_http = mcp_server.clone();
+    let mcp_sessions = Arc::new(mcp_sessions::McpSessionManager::new(
+        session_store,
+        mcp_server.clone(),
+    ));
     let mcp_service = StreamableHttpService::new(
         move || Ok(mcp_server_for_http.clone()),
-        LocalSessionManager::default().into(),
+        mcp_sessions.clone(),
         Default::default(),
     );
 
@@ -228,7 +1159,6 @@ async fn run_http_server(config: Config, http_config: HttpConfig) -> Result<()>
     let addr: SocketAddr = format!("0.0.0.0:{}", http_config.port)
         .parse()
         .context("Failed to parse bind address")?;
-    let ct = CancellationToken::new();
     // SSE routes are fixed: /sse for SSE endpoint, /message for POST endpoint
     // These are relative paths within the SSE router (nested under sse_prefix)
     // Final routes will be: {sse_prefix}/sse and {sse_prefix}/message
@@ -252,14 +1182,22 @@ async fn run_http_server(config: Config, http_config: HttpConfig) -> Result<()>
     let root_route = http_config.root_route();
     let metrics_route = http_config.metrics_route();
     let health_route = http_config.health_route();
+    let ready_route = http_config.ready_route();
+    let server_status_route = http_config.server_status_route();
     let tools_route = http_config.tools_route();
     let app_state = AppState {
         gmail_server: gmail_server.clone(),
         oauth_manager: oauth_manager.clone(),
+        accounts: accounts.clone(),
+        sessions: session_accounts.clone(),
+        caller_tokens,
+        mcp_sessions,
         csrf_tokens: csrf_tokens.clone(),
+        rate_limiter: rate_limiter.clone(),
         metrics: oauth_metrics.clone(),
         prometheus_handle: metric_handle.clone(),
         http_config: http_config.clone(),
+        started_at: std::time::SystemTime::now(),
     };
     // Build HTTP server with routes
     // SSE router has its own routes configured via SseServerConfig
@@ -269,17 +1207,27 @@ async fn run_http_server(config: Config, http_config: HttpConfig) -> Result<()>
     // Configure tracing middleware to log request headers and bodies at debug/trace level
     let trace_layer = TraceLayer::new_for_http()
         .make_span_with(|request: &axum::http::Request<_>| {
-            tracing::span!(
-                Level::DEBUG,
+            let span = tracing::span!(
+                Level::INFO,
                 "http_request",
                 method = %request.method(),
-                uri = %request.uri(),
+                uri = %redact::redact(&request.uri().to_string()),
                 version = ?request.version(),
-            )
+                request_id = tracing::field::Empty,
+                session_id = tracing::field::Empty,
+            );
+            if let Some(request_id) = telemetry::request_id(request.headers()) {
+                span.record("request_id", request_id);
+            }
+            if let Some(session_id) = telemetry::session_id(request.headers(), request.uri()) {
+                span.record("session_id", session_id);
+            }
+            telemetry::set_remote_parent(&span, request.headers());
+            span
         })
         .on_request(|request: &axum::http::Request<_>, _span: &tracing::Span| {
             // Log all request headers at debug level
-            debug!("request headers: {:?}", request.headers());
+            debug!("request headers: {:?}", redact::headers(request.headers()));
 
             // Log body metadata at trace level
             if let Some(content_type) = request.headers().get(header::CONTENT_TYPE) {
@@ -291,292 +1239,1418 @@ async fn run_http_server(config: Config, http_config: HttpConfig) -> Result<()>
         })
         .on_response(
             |response: &axum::http::Response<_>,
-            latency: std::time::Duration,
-            _span: &tracing::Span| {
+             latency: std::time::Duration,
+             _span: &tracing::Span| {
                 debug!(
                     "response status: {}, latency: {:?}",
                     response.status(),
                     latency
                 );
-                trace!("response headers: {:?}", response.headers());
+                trace!(
+                    "response headers: {:?}",
+                    redact::headers(response.headers())
+                );
             },
         );
 
-    let app = Router::new()
+    // Routes that reach Gmail through the server's tokens, behind the API key when one is set
+    let mut mcp_routes = Router::new()
+        .nest_service(sse_prefix, sse_router)
+        .nest_service(http_stream_route, mcp_service);
+    // The tools routes have no session to pick a token by, so they would act as the server's
+    // own account
+    if !http_config.multi_user {
+        mcp_routes = mcp_routes.nest(
+            tools_route,
+            tools_router(app_state.clone(), config.read_only),
+        );
+    }
+    // Innermost, so only requests that passed the other checks are waited for on shutdown
+    let in_flight = TaskTracker::new();
+    mcp_routes = mcp_routes.route_layer(axum::middleware::from_fn_with_state(
+        in_flight.clone(),
+        track_in_flight,
+    ));
+    if http_config.max_in_flight_requests > 0 {
+        mcp_routes = mcp_routes.route_layer(axum::middleware::from_fn_with_state(
+            Arc::new(Semaphore::new(http_config.max_in_flight_requests)),
+            limit_in_flight,
+        ));
+        info!(
+            "🚦 At most {} MCP and tools requests are handled at once",
+            http_config.max_in_flight_requests
+        );
+    }
+    let mcp_routes = protect(mcp_routes, &app_state);
+    // Logins replace an account's token and refreshes and status reveal its state, so they
+    // need the same credentials as the MCP routes
+    let auth_routes = protect(auth::auth_router(&http_config), &app_state);
+    let mut app = Router::new()
         .route(root_route, get(root_handler))
         .route(health_route, get(health_handler))
-        .route(metrics_route, get(metrics_handler))
-        .nest("/auth", auth::auth_router())
-        .nest(tools_route, tools_router())
-        .nest_service(sse_prefix, sse_router)
-        .nest_service(http_stream_route, mcp_service)
+        .route(ready_route, get(ready_handler))
+        .route(server_status_route, get(server_status_handler))
+        .route(metrics_route, get(metrics_handler));
+    // Describing the tools routes reveals nothing a caller needs the API key for
+    if !http_config.multi_user {
+        let docs_page = Html(openapi::docs_page(http_config.openapi_route()));
+        app = app
+            .route(
+                http_config.openapi_route(),
+                get(move || async move { Json(openapi_document) }),
+            )
+            .route(
+                http_config.docs_route(),
+                get(move || async move { docs_page }),
+            );
+    }
+    let app = app
+        .merge(auth::callback_router(&http_config))
+        .merge(auth_routes)
+        .merge(mcp_routes)
         .layer(axum::middleware::from_fn(log_request_body))
+        // Outside the body logging, so oversized bodies are refused rather than buffered
+        .layer(RequestBodyLimitLayer::new(http_config.max_body_bytes))
         .layer(ServiceBuilder::new().layer(trace_layer))
         .layer(metric_layer)
+        // Outermost, so spans and responses of every route carry the request ID
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
         .with_state(app_state);
 
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", http_config.port))
-        .await
-        .context("Failed to bind to port")?;
-
-    info!(
-        "🌐 HTTP server starting on http://localhost:{}",
-        http_config.port
-    );
+    let (listener, base_url) = match &http_config.unix_socket {
+        Some(path) => {
+            let socket = unix_socket::bind(path)?;
+            info!("🌐 HTTP server starting on unix socket {}", path.display());
+            // The host is what e.g. `curl --unix-socket` expects
+            (Listener::Unix(socket), "http://localhost".to_string())
+        }
+        None => {
+            let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", http_config.port))
+                .await
+                .context("Failed to bind to port")?;
+            let base_url = http_config.local_url();
+            info!("🌐 HTTP server starting on {base_url}");
+            (Listener::Tcp(listener), base_url)
+        }
+    };
     info!(
-        "📖 View server info: http://localhost:{}{}",
-        http_config.port,
+        "📖 View server info: {base_url}{}",
         http_config.root_route()
     );
+    info!("🔑 Auth login: {base_url}{}", http_config.login_route());
     info!(
-        "🔑 Auth login: http://localhost:{}{}",
-        http_config.port,
-        http_config.login_route()
+        "↪️ Auth callback: {base_url}{}",
+        http_config.callback_route()
     );
+    info!("🔄 Auth refresh: {base_url}{}", http_config.refresh_route());
     info!(
-        "↪️ Auth callback: http://localhost:{}{}",
-        http_config.port,
-        http_config.callback_route()
+        "🪪 Auth status: {base_url}{}",
+        http_config.auth_status_route()
     );
+    info!("🔍 Health check: {base_url}{}", http_config.health_route());
     info!(
-        "🔄 Auth refresh: http://localhost:{}{}",
-        http_config.port,
-        http_config.refresh_route()
+        "🚦 Readiness check: {base_url}{}",
+        http_config.ready_route()
     );
     info!(
-        "🔍 Health check: http://localhost:{}{}",
-        http_config.port,
-        http_config.health_route()
+        "📋 Server status: {base_url}{}",
+        http_config.server_status_route()
     );
     info!(
-        "📊 Metrics endpoint: http://localhost:{}{}",
-        http_config.port,
+        "📊 Metrics endpoint: {base_url}{}",
         http_config.metrics_route()
     );
     info!(
-        "🔌 HTTP stream endpoint: http://localhost:{}{}",
-        http_config.port,
+        "🔌 HTTP stream endpoint: {base_url}{}",
         http_config.http_stream_route()
     );
     info!(
-        "🔌 SSE endpoint: http://localhost:{}{}{}",
-        http_config.port,
+        "🔌 SSE endpoint: {base_url}{}{}",
         http_config.sse_prefix(),
         http_config.sse_route()
     );
     info!(
-        "📨 SSE POST endpoint: http://localhost:{}{}{}",
-        http_config.port,
+        "📨 SSE POST endpoint: {base_url}{}{}",
         http_config.sse_prefix(),
         http_config.sse_post_route()
     );
-    info!(
-        "🛠️ Tools endpoint: http://localhost:{}{}",
-        http_config.port,
-        http_config.tools_route()
-    );
+    if !http_config.multi_user {
+        info!("🛠️ Tools endpoint: {base_url}{}", http_config.tools_route());
+        info!(
+            "📘 Tools API docs: {base_url}{} ({base_url}{})",
+            http_config.docs_route(),
+            http_config.openapi_route()
+        );
+    }
 
     // Handle signals for graceful shutdown
     let cancel_token = ct.clone();
+    let draining = in_flight.clone();
     tokio::spawn(async move {
-        tokio::signal::ctrl_c().await.unwrap();
-        info!("Received Ctrl+C, shutting down server...");
+        match shutdown::signal().await {
+            Ok(signal) => info!("Received {signal}, shutting down server..."),
+            Err(e) => {
+                error!("Failed to listen for shutdown signals: {}", e);
+                return;
+            }
+        }
+        draining.close();
         cancel_token.cancel();
     });
 
-    // Replace axum::serve with a custom implementation that awaits shutdown
-    let server = axum::serve(listener, app);
-    let graceful = server.with_graceful_shutdown(async move {
-        ct.cancelled().await;
-        info!("Server is shutting down...");
-    });
+    let serve = async {
+        let served = match listener {
+            Listener::Tcp(listener) => tls::serve(listener, app, tls, ct.clone()).await,
+            Listener::Unix(socket) => unix_socket::serve(socket, app, ct.clone()).await,
+        };
+        if let Err(e) = served {
+            error!("Server error: {}", e);
+        }
+    };
+    // Event streams clients keep open to listen would hold the server up indefinitely, so stop
+    // once the requests in flight are done
+    let drained = async {
+        ct.cancelled().await;
+        in_flight.wait().await;
+    };
+    let shutdown = async {
+        tokio::select! {
+            _ = serve => {}
+            _ = drained => {}
+        }
+        ct.cancel();
+        // Let a token refresh or cache sync that is under way save its result
+        futures::future::join_all(persisting_tasks).await;
+    };
+    let drain_timeout = Duration::from_secs(http_config.shutdown_drain_secs);
+    let deadline = async {
+        ct.cancelled().await;
+        if !in_flight.is_empty() {
+            info!("⏳ Waiting for {} request(s) in flight", in_flight.len());
+        }
+        tokio::time::sleep(drain_timeout).await;
+    };
+    let signalled_again = async {
+        ct.cancelled().await;
+        shutdown::signal().await
+    };
+    tokio::select! {
+        _ = shutdown => {}
+        _ = deadline => warn!(
+            "Drain timeout of {}s passed with {} request(s) in flight, exiting",
+            drain_timeout.as_secs(),
+            in_flight.len()
+        ),
+        Ok(signal) = signalled_again => warn!("Received {signal} again, exiting without draining"),
+    }
+
+    Ok(())
+}
+
+/// Where the HTTP server accepts connections
+enum Listener {
+    Tcp(tokio::net::TcpListener),
+    Unix(unix_socket::UnixSocket),
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    gmail_server: Arc<gmail::GmailServer>,
+    oauth_manager: Arc<oauth::OAuthManager>,
+    accounts: Arc<accounts::Accounts>,
+    sessions: Option<Arc<sessions::SessionAccounts>>,
+    caller_tokens: Option<Arc<caller_tokens::CallerTokens>>,
+    mcp_sessions: Arc<mcp_sessions::McpSessionManager>,
+    csrf_tokens: Arc<dyn csrf_store::CsrfStore>,
+    rate_limiter: Arc<rate_limit::RateLimiter>,
+    metrics: Arc<metrics::OAuthMetrics>,
+    prometheus_handle: axum_prometheus::metrics_exporter_prometheus::PrometheusHandle,
+    http_config: HttpConfig,
+    started_at: std::time::SystemTime,
+}
+
+/// Render a template with placeholder replacements
+pub fn render_template(template: &str, replacements: &[(&str, &str)]) -> String {
+    let mut result = template.to_string();
+    for (placeholder, value) in replacements {
+        result = result.replace(placeholder, value);
+    }
+    result
+}
+
+async fn root_handler(State(state): State<AppState>) -> Html<String> {
+    let template = include_str!("../templates/index.html");
+    let sse_route_full = format!(
+        "{}{}",
+        state.http_config.sse_prefix(),
+        state.http_config.sse_route()
+    );
+    let sse_post_route_full = format!(
+        "{}{}",
+        state.http_config.sse_prefix(),
+        state.http_config.sse_post_route()
+    );
+    let html = render_template(
+        template,
+        &[
+            ("{root_route}", state.http_config.root_route()),
+            ("{login_route}", state.http_config.login_route()),
+            ("{callback_route}", state.http_config.callback_route()),
+            ("{refresh_route}", state.http_config.refresh_route()),
+            ("{auth_status_route}", state.http_config.auth_status_route()),
+            (
+                "{auth_accounts_route}",
+                state.http_config.auth_accounts_route(),
+            ),
+            ("{health_route}", state.http_config.health_route()),
+            ("{ready_route}", state.http_config.ready_route()),
+            (
+                "{server_status_route}",
+                state.http_config.server_status_route(),
+            ),
+            ("{metrics_route}", state.http_config.metrics_route()),
+            ("{http_stream_route}", state.http_config.http_stream_route()),
+            ("{tools_route}", state.http_config.tools_route()),
+            ("{openapi_route}", state.http_config.openapi_route()),
+            ("{docs_route}", state.http_config.docs_route()),
+            ("{sse_route}", &sse_route_full),
+            ("{sse_post_route}", &sse_post_route_full),
+        ],
+    );
+    Html(html)
+}
+
+/// Liveness: the process is up and serving requests
+async fn health_handler() -> impl IntoResponse {
+    (StatusCode::OK, "OK")
+}
+
+/// Readiness: tool calls can succeed, so traffic may be routed here
+async fn ready_handler(State(state): State<AppState>) -> (StatusCode, Json<Value>) {
+    // Sessions and callers bring their own tokens, so there is no server token to probe
+    let ready = if state.sessions.is_some() || state.caller_tokens.is_some() {
+        Ok(())
+    } else {
+        state.gmail_server.check_ready().await
+    };
+    match ready {
+        Ok(()) => (
+            StatusCode::OK,
+            Json(serde_json::json!({ "status": "ready" })),
+        ),
+        Err(e) => {
+            debug!("Readiness check failed: {:#}", e);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(serde_json::json!({ "status": "not_ready", "reason": format!("{e:#}") })),
+            )
+        }
+    }
+}
+
+/// A 401 for requests without the metrics credentials, if any are configured
+fn metrics_unauthorized(http_config: &HttpConfig, headers: &header::HeaderMap) -> Option<Response> {
+    if utils::is_authorized(
+        headers,
+        http_config.metrics_bearer_token.as_deref(),
+        http_config.metrics_basic_auth.as_deref(),
+    ) {
+        return None;
+    }
+    let challenge = if http_config.metrics_basic_auth.is_some() {
+        "Basic realm=\"metrics\""
+    } else {
+        "Bearer"
+    };
+    Some(
+        (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, challenge)],
+        )
+            .into_response(),
+    )
+}
+
+/// Machine-readable server state for monitoring, behind the same credentials as metrics
+async fn server_status_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+) -> Response {
+    if let Some(unauthorized) = metrics_unauthorized(&state.http_config, &headers) {
+        return unauthorized;
+    }
+    Json(server_status(&state).await).into_response()
+}
+
+/// Uptime, accounts, sessions, watchers and Gmail API errors, gathered without calling Gmail so
+/// frequent polling costs no quota
+async fn server_status(state: &AppState) -> Value {
+    let mut accounts = Vec::new();
+    for (name, account) in state.accounts.iter() {
+        let mut status = auth::token_status(account.oauth_manager.get_token().await.as_ref());
+        status["name"] = serde_json::json!(name);
+        status["authenticated"] = serde_json::json!(account.gmail_server.is_authenticated().await);
+        status["cache"] = account
+            .gmail_server
+            .cache()
+            .and_then(|cache| cache.entry_counts().ok())
+            .map_or(Value::Null, |(messages, threads)| {
+                serde_json::json!({ "messages": messages, "threads": threads })
+            });
+        accounts.push(status);
+    }
+    let logged_in_sessions = match &state.sessions {
+        Some(sessions) => Some(sessions.session_count().await),
+        None => None,
+    };
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "started_at": chrono::DateTime::<chrono::Utc>::from(state.started_at).to_rfc3339(),
+        "uptime_secs": state.started_at.elapsed().unwrap_or_default().as_secs(),
+        "accounts": accounts,
+        "mcp_sessions": state.mcp_sessions.session_count().await,
+        "logged_in_sessions": logged_in_sessions,
+        "watchers": metrics::watcher_status(),
+        "gmail_api_errors": metrics::gmail_api_error_summary(),
+    })
+}
+
+async fn metrics_handler(State(state): State<AppState>, headers: header::HeaderMap) -> Response {
+    if let Some(unauthorized) = metrics_unauthorized(&state.http_config, &headers) {
+        return unauthorized;
+    }
+
+    // Update metrics with current token state
+    let token = state.oauth_manager.get_token().await;
+    state.metrics.update_token_metrics(token.as_ref());
+
+    // Render all metrics from the global recorder
+    // Since OAuth metrics now use the metrics crate, they are automatically included
+    // in the global recorder used by axum-prometheus, so we just need to render once
+    let metrics_output = state.prometheus_handle.render();
+
+    let mut response = Response::new(metrics_output);
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+    response.into_response()
+}
+
+fn tools_router(state: AppState, read_only: bool) -> Router<AppState> {
+    let router = Router::new()
+        .route(
+            "/search_threads",
+            get(search_threads_handler).post(search_threads_handler),
+        )
+        .route(
+            "/create_draft",
+            get(create_draft_handler).post(create_draft_handler),
+        )
+        .route(
+            "/extract_attachment_by_filename",
+            get(extract_attachment_by_filename_handler)
+                .post(extract_attachment_by_filename_handler),
+        )
+        .route(
+            "/fetch_email_bodies",
+            get(fetch_email_bodies_handler).post(fetch_email_bodies_handler),
+        )
+        .route(
+            "/download_attachment",
+            get(download_attachment_handler).post(download_attachment_handler),
+        )
+        .route(
+            "/forward_email",
+            get(forward_email_handler).post(forward_email_handler),
+        )
+        .route(
+            "/send_draft",
+            get(send_draft_handler).post(send_draft_handler),
+        )
+        .route(
+            "/find_bounces",
+            get(find_bounces_handler).post(find_bounces_handler),
+        )
+        .route(
+            "/get_conversation",
+            get(get_conversation_handler).post(get_conversation_handler),
+        )
+        .route(
+            "/export_thread",
+            get(export_thread_handler).post(export_thread_handler),
+        )
+        .route(
+            "/email_to_markdown",
+            get(email_to_markdown_handler).post(email_to_markdown_handler),
+        )
+        .route(
+            "/list_thread_attachments",
+            get(list_thread_attachments_handler).post(list_thread_attachments_handler),
+        )
+        .route(
+            "/extract_drive_attachments",
+            get(extract_drive_attachments_handler).post(extract_drive_attachments_handler),
+        )
+        .route(
+            "/get_thread_changes",
+            get(get_thread_changes_handler).post(get_thread_changes_handler),
+        )
+        .route(
+            "/classify_message",
+            get(classify_message_handler).post(classify_message_handler),
+        )
+        .route(
+            "/score_phishing_risk",
+            get(score_phishing_risk_handler).post(score_phishing_risk_handler),
+        )
+        .route(
+            "/snooze_thread",
+            get(snooze_thread_handler).post(snooze_thread_handler),
+        )
+        .route(
+            "/list_snoozed",
+            get(list_snoozed_handler).post(list_snoozed_handler),
+        )
+        .route(
+            "/list_rules",
+            get(list_rules_handler).post(list_rules_handler),
+        )
+        .route("/add_rule", get(add_rule_handler).post(add_rule_handler))
+        .route(
+            "/remove_rule",
+            get(remove_rule_handler).post(remove_rule_handler),
+        )
+        .route(
+            "/list_job_runs",
+            get(list_job_runs_handler).post(list_job_runs_handler),
+        )
+        .route(
+            "/list_outbox",
+            get(list_outbox_handler).post(list_outbox_handler),
+        )
+        .route(
+            "/cancel_outbox_message",
+            get(cancel_outbox_message_handler).post(cancel_outbox_message_handler),
+        )
+        .route(
+            "/list_saved_searches",
+            get(list_saved_searches_handler).post(list_saved_searches_handler),
+        )
+        .route(
+            "/save_search",
+            get(save_search_handler).post(save_search_handler),
+        )
+        .route(
+            "/update_saved_search",
+            get(update_saved_search_handler).post(update_saved_search_handler),
+        )
+        .route(
+            "/delete_saved_search",
+            get(delete_saved_search_handler).post(delete_saved_search_handler),
+        )
+        .route("/attachment/:message_id/:filename", get(attachment_handler))
+        .route("/preview/:message_id/:filename", get(preview_handler));
+    // Inside the audit log, so refused calls are recorded too
+    let router = if read_only {
+        router.route_layer(axum::middleware::from_fn(refuse_mutating_tools))
+    } else {
+        router
+    };
+    router.route_layer(axum::middleware::from_fn_with_state(
+        state,
+        audit_tool_request,
+    ))
+}
+
+/// The tool a `/tools` request calls, the first segment of its path below the tools route
+fn tool_name(request: &Request) -> &str {
+    let path = request.uri().path().trim_start_matches('/');
+    path.split('/').next().unwrap_or_default()
+}
+
+/// Middleware answering 403 to the `/tools` endpoints that change the mailbox, with `--read-only`
+async fn refuse_mutating_tools(request: Request, next: Next) -> axum::response::Response {
+    let tool = tool_name(&request);
+    if server::is_mutating(tool) {
+        return (StatusCode::FORBIDDEN, tools::read_only_error(tool)).into_response();
+    }
+    next.run(request).await
+}
+
+/// The server for a `/tools` call, acting on the mailbox `user_id` when the call names one
+async fn tool_account(
+    state: &AppState,
+    headers: &header::HeaderMap,
+    account: Option<&str>,
+    user_id: Option<&str>,
+) -> Result<Arc<gmail::GmailServer>, (StatusCode, String)> {
+    let gmail_server = token_account(state, headers, account).await?;
+    match user_id {
+        Some(user_id) => gmail_server
+            .for_user(user_id)
+            .map(Arc::new)
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string())),
+        None => Ok(gmail_server),
+    }
+}
+
+/// The token for a `/tools` call: the caller's own when trusted, otherwise the account the call
+/// names or the default account
+async fn token_account(
+    state: &AppState,
+    headers: &header::HeaderMap,
+    account: Option<&str>,
+) -> Result<Arc<gmail::GmailServer>, (StatusCode, String)> {
+    if let (Some(caller_tokens), Some(access_token)) =
+        (&state.caller_tokens, caller_tokens::bearer_token(headers))
+    {
+        if account.is_some() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "The account argument can't be combined with a caller-supplied token".to_string(),
+            ));
+        }
+        return caller_tokens
+            .gmail_server(access_token)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+    }
+    state
+        .accounts
+        .get(account)
+        .map(|account| account.gmail_server.clone())
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+/// A `/tools` call's arguments: the query string of a GET, or the JSON body of a POST for
+/// arguments such as lists and multi-line text that don't fit a query string
+struct ToolArgs<T>(T);
+
+#[axum::async_trait]
+impl<T, S> FromRequest<S> for ToolArgs<T>
+where
+    T: serde::de::DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = axum::response::Response;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if request.method() == axum::http::Method::POST {
+            let Json(args) = Json::<T>::from_request(request, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            return Ok(Self(args));
+        }
+        let (mut parts, _) = request.into_parts();
+        let Query(args) = Query::<T>::from_request_parts(&mut parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        Ok(Self(args))
+    }
+}
+
+/// A failed `/tools` call, with the status and kind of failure the MCP tools report: `401` when
+/// a login is needed, `403` when the token lacks a scope the tool needs, `404`, `429` or `400`
+/// when Gmail answered so, otherwise `500`
+fn tool_error(error: anyhow::Error) -> (StatusCode, String) {
+    let kind = gmail::ErrorKind::of(&error);
+    let status = match kind {
+        gmail::ErrorKind::AuthRequired => StatusCode::UNAUTHORIZED,
+        gmail::ErrorKind::InsufficientScope => StatusCode::FORBIDDEN,
+        gmail::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        gmail::ErrorKind::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+        gmail::ErrorKind::InvalidArgument => StatusCode::BAD_REQUEST,
+        gmail::ErrorKind::Other => return (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()),
+    };
+    (status, format!("{}: {error}", kind.as_str()))
+}
+
+async fn search_threads_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<SearchThreadsArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(
+        &state,
+        &headers,
+        params.account.as_deref(),
+        params.user_id.as_deref(),
+    )
+    .await?;
+    tools::search_threads(
+        &gmail_server,
+        &params.query,
+        params.max_results.unwrap_or(10),
+        params.fields.as_deref(),
+        &params.filter(),
+    )
+    .await
+    .map(Json)
+    .map_err(tool_error)
+}
+
+async fn create_draft_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<CreateDraftArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(
+        &state,
+        &headers,
+        params.account.as_deref(),
+        params.user_id.as_deref(),
+    )
+    .await?;
+    tools::create_draft(
+        &gmail_server,
+        &params.to,
+        &params.subject,
+        &params.body,
+        params.thread_id.as_deref(),
+        params.exclude_own_address.unwrap_or_default(),
+        params.dry_run.unwrap_or_default(),
+    )
+    .await
+    .map(Json)
+    .map_err(tool_error)
+}
+
+async fn extract_attachment_by_filename_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<ExtractAttachmentArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(
+        &state,
+        &headers,
+        params.account.as_deref(),
+        params.user_id.as_deref(),
+    )
+    .await?;
+    tools::extract_attachment_by_filename(
+        &gmail_server,
+        &params.message_id,
+        &params.filename,
+        params.preview_chars,
+    )
+    .await
+    .map(Json)
+    .map_err(tool_error)
+}
+
+async fn fetch_email_bodies_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<FetchEmailBodiesArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(
+        &state,
+        &headers,
+        params.account.as_deref(),
+        params.user_id.as_deref(),
+    )
+    .await?;
+    tools::fetch_email_bodies(
+        &gmail_server,
+        &params.thread_ids,
+        params.format.unwrap_or_default(),
+        params.fields.as_deref(),
+    )
+    .await
+    .map(Json)
+    .map_err(tool_error)
+}
+
+async fn download_attachment_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<DownloadAttachmentArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(
+        &state,
+        &headers,
+        params.account.as_deref(),
+        params.user_id.as_deref(),
+    )
+    .await?;
+    tools::download_attachment(
+        &gmail_server,
+        &params.message_id,
+        &params.filename,
+        params.download_dir.as_deref(),
+    )
+    .await
+    .map(Json)
+    .map_err(tool_error)
+}
+
+/// The `account` query parameter of the attachment route
+#[derive(serde::Deserialize)]
+struct AccountQuery {
+    account: Option<String>,
+    user_id: Option<String>,
+}
+
+/// An attachment's bytes, served for download instead of written to the server's disk
+async fn attachment_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    Path((message_id, filename)): Path<(String, String)>,
+    Query(query): Query<AccountQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let gmail_server = tool_account(
+        &state,
+        &headers,
+        query.account.as_deref(),
+        query.user_id.as_deref(),
+    )
+    .await?;
+    let attachment = tools::fetch_attachment(&gmail_server, &message_id, &filename)
+        .await
+        .map_err(tool_error)?;
+    let content_type = header::HeaderValue::from_str(&attachment.mime_type)
+        .unwrap_or(header::HeaderValue::from_static("application/octet-stream"));
+    let mut response = (
+        [
+            (header::CONTENT_TYPE, content_type),
+            (
+                header::CONTENT_DISPOSITION,
+                utils::content_disposition(&filename),
+            ),
+            // The bytes are the sender's, so never let a browser render them as something else
+            (
+                header::X_CONTENT_TYPE_OPTIONS,
+                header::HeaderValue::from_static("nosniff"),
+            ),
+        ],
+        attachment.data,
+    )
+        .into_response();
+    // Only set when attachments are scanned; blocked attachments never get here
+    if let Some(status) = attachment
+        .scan
+        .as_ref()
+        .and_then(|scan| scan["status"].as_str())
+        .and_then(|status| header::HeaderValue::from_str(status).ok())
+    {
+        response.headers_mut().insert("x-virus-scan", status);
+    }
+    Ok(response)
+}
+
+/// Query parameters of the attachment preview route
+#[derive(serde::Deserialize)]
+struct PreviewQuery {
+    account: Option<String>,
+    user_id: Option<String>,
+    /// Characters of extracted text to return (defaults to 2000)
+    max_chars: Option<usize>,
+    /// Largest width and height of an image thumbnail in pixels, at most 1024 (defaults to 256)
+    size: Option<u32>,
+}
+
+/// Extracted text or a PNG thumbnail of an attachment, for web clients to show inline
+async fn preview_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    Path((message_id, filename)): Path<(String, String)>,
+    Query(query): Query<PreviewQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let gmail_server = tool_account(
+        &state,
+        &headers,
+        query.account.as_deref(),
+        query.user_id.as_deref(),
+    )
+    .await?;
+    let attachment = tools::fetch_attachment(&gmail_server, &message_id, &filename)
+        .await
+        .map_err(tool_error)?;
+    let max_chars = query.max_chars.unwrap_or(2000);
+    let size = query.size.unwrap_or(256).clamp(16, 1024);
+    let mime_type = attachment.mime_type.clone();
+    // Text extraction and image decoding are CPU-bound
+    let preview = tokio::task::spawn_blocking(move || {
+        preview::preview(&attachment, &filename, max_chars, size)
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, format!("{e:#}")))?;
+
+    let nosniff = (
+        header::X_CONTENT_TYPE_OPTIONS,
+        header::HeaderValue::from_static("nosniff"),
+    );
+    match preview {
+        Some(preview::Preview::Text { text, total_chars }) => Ok((
+            [
+                (
+                    header::CONTENT_TYPE,
+                    header::HeaderValue::from_static("text/plain; charset=utf-8"),
+                ),
+                nosniff,
+                (
+                    header::HeaderName::from_static("x-total-chars"),
+                    header::HeaderValue::from(total_chars),
+                ),
+            ],
+            text,
+        )
+            .into_response()),
+        Some(preview::Preview::Image(png)) => Ok((
+            [
+                (
+                    header::CONTENT_TYPE,
+                    header::HeaderValue::from_static("image/png"),
+                ),
+                nosniff,
+            ],
+            png,
+        )
+            .into_response()),
+        None => Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("No preview for {mime_type} attachments"),
+        )),
+    }
+}
+
+async fn forward_email_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<ForwardEmailArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(
+        &state,
+        &headers,
+        params.account.as_deref(),
+        params.user_id.as_deref(),
+    )
+    .await?;
+    tools::forward_email(
+        &gmail_server,
+        &params.message_id,
+        &params.to,
+        &params.subject,
+        &params.body,
+        params.dry_run.unwrap_or_default(),
+        params.enqueue_if_unauthenticated.unwrap_or_default(),
+    )
+    .await
+    .map(Json)
+    .map_err(tool_error)
+}
+
+async fn send_draft_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<SendDraftArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(
+        &state,
+        &headers,
+        params.account.as_deref(),
+        params.user_id.as_deref(),
+    )
+    .await?;
+    tools::send_draft(
+        &gmail_server,
+        &params.draft_id,
+        params.dry_run.unwrap_or_default(),
+        params.enqueue_if_unauthenticated.unwrap_or_default(),
+    )
+    .await
+    .map(Json)
+    .map_err(tool_error)
+}
+
+async fn find_bounces_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<FindBouncesArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(
+        &state,
+        &headers,
+        params.account.as_deref(),
+        params.user_id.as_deref(),
+    )
+    .await?;
+    bounces::find_bounces(
+        &gmail_server,
+        params.message_id.as_deref(),
+        params.recipient.as_deref(),
+        params.max_results.unwrap_or(10),
+    )
+    .await
+    .map(Json)
+    .map_err(tool_error)
+}
+
+async fn get_conversation_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<GetConversationArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(
+        &state,
+        &headers,
+        params.account.as_deref(),
+        params.user_id.as_deref(),
+    )
+    .await?;
+    conversation::get_conversation(
+        &gmail_server,
+        &params.message_id,
+        params.format.unwrap_or_default(),
+        params.max_threads.unwrap_or(10),
+    )
+    .await
+    .map(Json)
+    .map_err(tool_error)
+}
+
+async fn export_thread_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<ExportThreadArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(
+        &state,
+        &headers,
+        params.account.as_deref(),
+        params.user_id.as_deref(),
+    )
+    .await?;
+    export::export_thread(
+        &gmail_server,
+        &params.thread_id,
+        params.format.unwrap_or_default(),
+        params.download_dir.as_deref(),
+    )
+    .await
+    .map(Json)
+    .map_err(tool_error)
+}
+
+async fn email_to_markdown_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<EmailToMarkdownArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(
+        &state,
+        &headers,
+        params.account.as_deref(),
+        params.user_id.as_deref(),
+    )
+    .await?;
+    markdown::email_to_markdown(&gmail_server, &params.message_id)
+        .await
+        .map(Json)
+        .map_err(tool_error)
+}
 
-    if let Err(e) = graceful.await {
-        error!("Server error: {}", e);
-    }
+async fn list_thread_attachments_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<ListThreadAttachmentsArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(
+        &state,
+        &headers,
+        params.account.as_deref(),
+        params.user_id.as_deref(),
+    )
+    .await?;
+    thread_attachments::list_thread_attachments(
+        &gmail_server,
+        &params.thread_id,
+        params.extract_text.unwrap_or(false),
+        params.preview_chars,
+    )
+    .await
+    .map(Json)
+    .map_err(tool_error)
+}
 
-    Ok(())
+async fn extract_drive_attachments_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<ExtractDriveAttachmentsArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(
+        &state,
+        &headers,
+        params.account.as_deref(),
+        params.user_id.as_deref(),
+    )
+    .await?;
+    drive::extract_drive_attachments(&gmail_server, &params.message_id, params.preview_chars)
+        .await
+        .map(Json)
+        .map_err(tool_error)
 }
 
-#[derive(Clone)]
-pub struct AppState {
-    gmail_server: Arc<gmail::GmailServer>,
-    oauth_manager: Arc<oauth::OAuthManager>,
-    csrf_tokens: Arc<RwLock<std::collections::HashMap<String, String>>>,
-    metrics: Arc<metrics::OAuthMetrics>,
-    prometheus_handle: axum_prometheus::metrics_exporter_prometheus::PrometheusHandle,
-    http_config: HttpConfig,
+async fn get_thread_changes_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<GetThreadChangesArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(
+        &state,
+        &headers,
+        params.account.as_deref(),
+        params.user_id.as_deref(),
+    )
+    .await?;
+    thread_changes::get_thread_changes(
+        &gmail_server,
+        &params.thread_id,
+        &params.history_id,
+        params.format.unwrap_or_default(),
+    )
+    .await
+    .map(Json)
+    .map_err(tool_error)
 }
 
-/// Render a template with placeholder replacements
-pub fn render_template(template: &str, replacements: &[(&str, &str)]) -> String {
-    let mut result = template.to_string();
-    for (placeholder, value) in replacements {
-        result = result.replace(placeholder, value);
-    }
-    result
+async fn classify_message_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<ClassifyMessageArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(
+        &state,
+        &headers,
+        params.account.as_deref(),
+        params.user_id.as_deref(),
+    )
+    .await?;
+    classifier::classify_message(&gmail_server, &params.message_id)
+        .await
+        .map(Json)
+        .map_err(tool_error)
 }
 
-async fn root_handler(State(state): State<AppState>) -> Html<String> {
-    let template = include_str!("../templates/index.html");
-    let sse_route_full = format!(
-        "{}{}",
-        state.http_config.sse_prefix(),
-        state.http_config.sse_route()
-    );
-    let sse_post_route_full = format!(
-        "{}{}",
-        state.http_config.sse_prefix(),
-        state.http_config.sse_post_route()
-    );
-    let html = render_template(
-        template,
-        &[
-            ("{root_route}", state.http_config.root_route()),
-            ("{login_route}", state.http_config.login_route()),
-            ("{callback_route}", state.http_config.callback_route()),
-            ("{health_route}", state.http_config.health_route()),
-            ("{metrics_route}", state.http_config.metrics_route()),
-            ("{http_stream_route}", state.http_config.http_stream_route()),
-            ("{tools_route}", state.http_config.tools_route()),
-            ("{sse_route}", &sse_route_full),
-            ("{sse_post_route}", &sse_post_route_full),
-        ],
-    );
-    Html(html)
+async fn score_phishing_risk_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<ScorePhishingRiskArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(
+        &state,
+        &headers,
+        params.account.as_deref(),
+        params.user_id.as_deref(),
+    )
+    .await?;
+    phishing::score_phishing_risk(&gmail_server, &params.message_id)
+        .await
+        .map(Json)
+        .map_err(tool_error)
 }
 
-async fn health_handler() -> impl IntoResponse {
-    (StatusCode::OK, "OK")
+async fn snooze_thread_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<SnoozeThreadArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(&state, &headers, params.account.as_deref(), None).await?;
+    snooze::snooze_thread(
+        &gmail_server,
+        &params.thread_id,
+        &params.until,
+        params.mark_unread.unwrap_or_default(),
+    )
+    .await
+    .map(Json)
+    .map_err(tool_error)
 }
 
-async fn metrics_handler(State(state): State<AppState>) -> Result<Response<String>, StatusCode> {
-    // Update metrics with current token state
-    let token = state.oauth_manager.get_token().await;
-    state.metrics.update_token_metrics(token.as_ref());
+async fn list_snoozed_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<ListSnoozedArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(&state, &headers, params.account.as_deref(), None).await?;
+    snooze::list_snoozed(&gmail_server)
+        .await
+        .map(Json)
+        .map_err(tool_error)
+}
 
-    // Render all metrics from the global recorder
-    // Since OAuth metrics now use the metrics crate, they are automatically included
-    // in the global recorder used by axum-prometheus, so we just need to render once
-    let metrics_output = state.prometheus_handle.render();
+async fn list_rules_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<ListRulesArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(&state, &headers, params.account.as_deref(), None).await?;
+    rules::list_rules(&gmail_server)
+        .await
+        .map(Json)
+        .map_err(tool_error)
+}
 
-    let mut response = Response::new(metrics_output);
-    response.headers_mut().insert(
-        header::CONTENT_TYPE,
-        header::HeaderValue::from_static("text/plain; version=0.0.4"),
-    );
-    Ok(response)
+async fn add_rule_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<AddRuleArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(&state, &headers, params.account.as_deref(), None).await?;
+    rules::add_rule(&gmail_server, params.into_rule())
+        .await
+        .map(Json)
+        .map_err(tool_error)
 }
 
-fn tools_router() -> Router<AppState> {
-    Router::new()
-        .route("/search_threads", get(search_threads_handler))
-        .route("/create_draft", get(create_draft_handler))
-        .route(
-            "/extract_attachment_by_filename",
-            get(extract_attachment_by_filename_handler),
-        )
-        .route("/fetch_email_bodies", get(fetch_email_bodies_handler))
-        .route("/download_attachment", get(download_attachment_handler))
-        .route("/forward_email", get(forward_email_handler))
-        .route("/send_draft", get(send_draft_handler))
+async fn remove_rule_handler(
+    State(state): State<AppState>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<RemoveRuleArgs>,
+) -> Result<Json<Value>, (StatusCode, String)> {
+    let gmail_server = tool_account(&state, &headers, params.account.as_deref(), None).await?;
+    rules::remove_rule(&gmail_server, &params.name)
+        .await
+        .map(Json)
+        .map_err(tool_error)
 }
 
-async fn search_threads_handler(
+async fn list_job_runs_handler(
     State(state): State<AppState>,
-    Query(params): Query<SearchThreadsArgs>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<ListJobRunsArgs>,
 ) -> Result<Json<Value>, (StatusCode, String)> {
-    tools::search_threads(
-        &state.gmail_server,
-        &params.query,
-        params.max_results.unwrap_or(10),
+    let gmail_server = tool_account(&state, &headers, params.account.as_deref(), None).await?;
+    jobs::list_job_runs(
+        &gmail_server,
+        params.job.as_deref(),
+        params.limit.unwrap_or(20),
     )
     .await
     .map(Json)
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+    .map_err(tool_error)
 }
 
-async fn create_draft_handler(
+async fn list_outbox_handler(
     State(state): State<AppState>,
-    Query(params): Query<CreateDraftArgs>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<ListOutboxArgs>,
 ) -> Result<Json<Value>, (StatusCode, String)> {
-    tools::create_draft(
-        &state.gmail_server,
-        &params.to,
-        &params.subject,
-        &params.body,
-        params.thread_id.as_deref(),
-    )
-    .await
-    .map(Json)
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+    let gmail_server = tool_account(&state, &headers, params.account.as_deref(), None).await?;
+    outbox::list_outbox(&gmail_server)
+        .await
+        .map(Json)
+        .map_err(tool_error)
 }
 
-async fn extract_attachment_by_filename_handler(
+async fn cancel_outbox_message_handler(
     State(state): State<AppState>,
-    Query(params): Query<ExtractAttachmentArgs>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<CancelOutboxMessageArgs>,
 ) -> Result<Json<Value>, (StatusCode, String)> {
-    tools::extract_attachment_by_filename(&state.gmail_server, &params.message_id, &params.filename)
+    let gmail_server = tool_account(&state, &headers, params.account.as_deref(), None).await?;
+    outbox::cancel_outbox_message(&gmail_server, params.outbox_id)
         .await
         .map(Json)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        .map_err(tool_error)
 }
 
-async fn fetch_email_bodies_handler(
+async fn list_saved_searches_handler(
     State(state): State<AppState>,
-    Query(params): Query<FetchEmailBodiesArgs>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<ListSavedSearchesArgs>,
 ) -> Result<Json<Value>, (StatusCode, String)> {
-    tools::fetch_email_bodies(&state.gmail_server, &params.thread_ids)
+    let gmail_server = tool_account(&state, &headers, params.account.as_deref(), None).await?;
+    saved_searches::list_saved_searches(&gmail_server)
         .await
         .map(Json)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        .map_err(tool_error)
 }
 
-async fn download_attachment_handler(
+async fn save_search_handler(
     State(state): State<AppState>,
-    Query(params): Query<DownloadAttachmentArgs>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<SaveSearchArgs>,
 ) -> Result<Json<Value>, (StatusCode, String)> {
-    tools::download_attachment(
-        &state.gmail_server,
-        &params.message_id,
-        &params.filename,
-        params.download_dir.as_deref(),
-    )
-    .await
-    .map(Json)
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+    let gmail_server = tool_account(&state, &headers, params.account.as_deref(), None).await?;
+    saved_searches::save_search(&gmail_server, params.into_search())
+        .await
+        .map(Json)
+        .map_err(tool_error)
 }
 
-async fn forward_email_handler(
+async fn update_saved_search_handler(
     State(state): State<AppState>,
-    Query(params): Query<ForwardEmailArgs>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<UpdateSavedSearchArgs>,
 ) -> Result<Json<Value>, (StatusCode, String)> {
-    tools::forward_email(
-        &state.gmail_server,
-        &params.message_id,
-        &params.to,
-        &params.subject,
-        &params.body,
+    let gmail_server = tool_account(&state, &headers, params.account.as_deref(), None).await?;
+    saved_searches::update_saved_search(
+        &gmail_server,
+        &params.name,
+        params.query,
+        params.description,
     )
     .await
     .map(Json)
-    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+    .map_err(tool_error)
 }
 
-async fn send_draft_handler(
+async fn delete_saved_search_handler(
     State(state): State<AppState>,
-    Query(params): Query<SendDraftArgs>,
+    headers: header::HeaderMap,
+    ToolArgs(params): ToolArgs<DeleteSavedSearchArgs>,
 ) -> Result<Json<Value>, (StatusCode, String)> {
-    tools::send_draft(&state.gmail_server, &params.draft_id)
+    let gmail_server = tool_account(&state, &headers, params.account.as_deref(), None).await?;
+    saved_searches::delete_saved_search(&gmail_server, &params.name)
         .await
         .map(Json)
-        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        .map_err(tool_error)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_tool_error_status() {
+        let (status, message) = tool_error(
+            gmail::GmailError::InsufficientScope(vec![oauth::SCOPE_SEND.to_string()]).into(),
+        );
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(
+            message,
+            "insufficient_scope: Missing OAuth scope: https://www.googleapis.com/auth/gmail.send"
+        );
+        let (status, message) =
+            tool_error(gmail::GmailError::NotAuthenticated(gmail::AuthFailure::NoToken).into());
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            message,
+            "auth_required: Not authenticated: no token available"
+        );
+        let (status, _) = tool_error(anyhow::anyhow!("Gmail API error"));
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_request_body_limit() {
+        use tower::Service;
+
+        let app = Router::new()
+            .route(
+                "/echo",
+                axum::routing::post(|body: String| async move { body }),
+            )
+            .layer(axum::middleware::from_fn(log_request_body))
+            .layer(RequestBodyLimitLayer::new(8));
+        let status = |body: Body| {
+            let request = Request::post("/echo").body(body).unwrap();
+            let mut app = app.clone();
+            async move { app.call(request).await.unwrap().status() }
+        };
+        assert_eq!(status(Body::from("small")).await, StatusCode::OK);
+        assert_eq!(
+            status(Body::from("far too large")).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        // Bodies without a Content-Length are cut off once they pass the limit
+        let chunks = futures::stream::iter(["far ", "too ", "large"].map(Ok::<_, std::io::Error>));
+        assert_eq!(
+            status(Body::from_stream(chunks)).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_limit_in_flight() {
+        use bytes::Bytes;
+        use std::io;
+        use tower::Service;
+
+        // A response body that never ends
+        fn pending() -> Body {
+            Body::from_stream(futures::stream::pending::<Result<Bytes, io::Error>>())
+        }
+        let events = || async { ([(header::CONTENT_TYPE, "text/event-stream")], pending()) };
+        let app = Router::new()
+            .route("/slow", axum::routing::post(|| async { pending() }))
+            .route("/events", get(events))
+            .route("/fast", axum::routing::post(|| async { "done" }))
+            .route_layer(axum::middleware::from_fn_with_state(
+                Arc::new(Semaphore::new(1)),
+                limit_in_flight,
+            ));
+        let call = |path: &str| {
+            let method = if path == "/events" { "GET" } else { "POST" };
+            let request = Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap();
+            let mut app = app.clone();
+            async move { app.call(request).await.unwrap() }
+        };
+
+        // A finished response frees its slot once the body has been read
+        let response = call("/fast").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        response.into_body().collect().await.unwrap();
+        // Listening for server messages doesn't take a slot for the life of the stream
+        let _events = call("/events").await;
+        // A response still streaming its body keeps the slot
+        let streaming = call("/slow").await;
+        assert_eq!(streaming.status(), StatusCode::OK);
+        let shed = call("/fast").await;
+        assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(shed.headers()[header::RETRY_AFTER], "1");
+        drop(streaming);
+        assert_eq!(call("/fast").await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_track_in_flight() {
+        use tower::Service;
+
+        let tracker = TaskTracker::new();
+        let app = Router::new()
+            .route("/stream", axum::routing::post(|| async { "done" }))
+            .route_layer(axum::middleware::from_fn_with_state(
+                tracker.clone(),
+                track_in_flight,
+            ));
+        let call = |session_id: Option<&'static str>| {
+            let mut request = Request::post("/stream");
+            if let Some(session_id) = session_id {
+                request = request.header("mcp-session-id", session_id);
+            }
+            let request = request.body(Body::empty()).unwrap();
+            let mut app = app.clone();
+            async move { app.call(request).await.unwrap() }
+        };
+
+        // A request is in flight until its body has been sent
+        let response = call(None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(tracker.len(), 1);
+        response.into_body().collect().await.unwrap();
+        assert!(tracker.is_empty());
+
+        // While shutting down, only existing sessions are served
+        tracker.close();
+        assert_eq!(call(None).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let response = call(Some("s1")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let wait = tracker.wait();
+        tokio::pin!(wait);
+        assert!(futures::poll!(&mut wait).is_pending());
+        drop(response);
+        wait.await;
+    }
+
     #[test]
     fn test_render_template() {
         let template = "Hello {name}, welcome to {place}!";
@@ -630,21 +2704,29 @@ mod tests {
                 ("{root_route}", "/"),
                 ("{login_route}", "/login"),
                 ("{callback_route}", "/callback"),
-                ("{health_route}", "/health"),
+                ("{health_route}", "/healthz"),
+                ("{ready_route}", "/readyz"),
+                ("{server_status_route}", "/status"),
                 ("{metrics_route}", "/metrics"),
                 ("{http_stream_route}", "/stream"),
                 ("{tools_route}", "/tools"),
+                ("{openapi_route}", "/openapi.json"),
+                ("{docs_route}", "/docs"),
                 ("{sse_route}", "/sse"),
                 ("{sse_post_route}", "/message"),
             ],
         );
         assert!(result.contains("GET /login"));
         assert!(result.contains("GET /callback"));
-        assert!(result.contains("GET /health"));
+        assert!(result.contains("GET /healthz"));
+        assert!(result.contains("GET /readyz"));
+        assert!(result.contains("GET /status"));
         assert!(result.contains("GET /metrics"));
         assert!(result.contains("POST /stream"));
         assert!(result.contains("GET /sse"));
         assert!(result.contains("POST /message"));
+        assert!(result.contains("href=\"/docs\""));
+        assert!(result.contains("href=\"/openapi.json\""));
         assert!(result.contains("href=\"/login\""));
         assert!(result.contains("<code>/login</code>"));
     }
@@ -659,6 +2741,7 @@ mod tests {
                 ("{login_route}", "/auth/login"),
                 ("{callback_route}", "/auth/callback"),
                 ("{health_route}", "/status/health"),
+                ("{ready_route}", "/status/ready"),
                 ("{metrics_route}", "/prometheus/metrics"),
                 ("{http_stream_route}", "/api/stream"),
                 ("{tools_route}", "/api/tools"),
@@ -669,6 +2752,7 @@ mod tests {
         assert!(result.contains("GET /auth/login"));
         assert!(result.contains("GET /auth/callback"));
         assert!(result.contains("GET /status/health"));
+        assert!(result.contains("GET /status/ready"));
         assert!(result.contains("GET /prometheus/metrics"));
         assert!(result.contains("POST /api/stream"));
         assert!(result.contains("GET /api/sse"));
@@ -677,15 +2761,14 @@ mod tests {
         assert!(result.contains("<code>/auth/login</code>"));
     }
 
-    #[test]
-    fn test_app_state_uses_config_for_routes() {
-        use std::collections::HashMap;
+    #[tokio::test]
+    async fn test_app_state_uses_config_for_routes() {
         use std::sync::Arc;
-        use tokio::sync::RwLock;
 
         let config = Config {
             gmail_client_id: Some("test-client-id".to_string()),
             gmail_client_secret: Some("test-client-secret".to_string()),
+            accounts: vec!["work".to_string()],
             ..Default::default()
         };
         let http_config = HttpConfig {
@@ -698,6 +2781,8 @@ mod tests {
                 login_route: "/custom-login".to_string(),
                 callback_route: "/custom-callback".to_string(),
                 refresh_route: "/custom-refresh".to_string(),
+                status_route: "/custom-status".to_string(),
+                ..Default::default()
             },
             health_route: "/custom-health".to_string(),
             root_route: "/custom-root".to_string(),
@@ -712,16 +2797,31 @@ mod tests {
         let prometheus_handle = PrometheusBuilder::new()
             .install_recorder()
             .expect("Failed to install Prometheus recorder");
-        let oauth_manager = Arc::new(
-            oauth::OAuthManager::new(config.clone(), http_config.clone()).unwrap(),
-        );
+        let oauth_manager =
+            Arc::new(oauth::OAuthManager::new(config.clone(), http_config.clone()).unwrap());
+        let gmail_server =
+            Arc::new(gmail::GmailServer::new(oauth_manager.clone(), &config).unwrap());
+        let default_account = accounts::Account {
+            oauth_manager: oauth_manager.clone(),
+            gmail_server: gmail_server.clone(),
+        };
+        let accounts =
+            Arc::new(accounts::Accounts::new(default_account, &config, &http_config).unwrap());
+        let mcp_server =
+            server::GmailMcpServer::new(accounts.clone(), None, None, http_config.clone());
         let app_state = AppState {
-            gmail_server: Arc::new(gmail::GmailServer::new(oauth_manager.clone()).unwrap()),
+            gmail_server,
             oauth_manager,
-            csrf_tokens: Arc::new(RwLock::new(HashMap::new())),
+            accounts,
+            sessions: None,
+            caller_tokens: None,
+            mcp_sessions: Arc::new(mcp_sessions::McpSessionManager::new(None, mcp_server)),
+            csrf_tokens: Arc::new(csrf_store::CsrfTokens::new(Duration::from_secs(600))),
+            rate_limiter: Arc::new(rate_limit::RateLimiter::new(&Default::default())),
             metrics: Arc::new(metrics::OAuthMetrics::new()),
             prometheus_handle,
             http_config: http_config.clone(),
+            started_at: std::time::SystemTime::now(),
         };
 
         // Verify routes are accessible through config
@@ -729,11 +2829,418 @@ mod tests {
         assert_eq!(app_state.http_config.login_route(), "/custom-login");
         assert_eq!(app_state.http_config.callback_route(), "/custom-callback");
         assert_eq!(app_state.http_config.refresh_route(), "/custom-refresh");
+        assert_eq!(app_state.http_config.auth_status_route(), "/custom-status");
         assert_eq!(app_state.http_config.health_route(), "/custom-health");
         assert_eq!(app_state.http_config.metrics_route(), "/custom-metrics");
         assert_eq!(app_state.http_config.http_stream_route(), "/custom-stream");
         assert_eq!(app_state.http_config.sse_route(), "/sse");
         assert_eq!(app_state.http_config.sse_post_route(), "/message");
         assert_eq!(app_state.http_config.tools_route(), "/custom-tools");
+
+        // The status covers every account without calling Gmail
+        let status = server_status(&app_state).await;
+        assert_eq!(status["uptime_secs"], 0);
+        assert_eq!(status["mcp_sessions"], 0);
+        assert_eq!(status["logged_in_sessions"], Value::Null);
+        let names: Vec<_> = status["accounts"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|account| account["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["default", "work"]);
+        assert_eq!(status["accounts"][0]["token_present"], false);
+        assert!(status["gmail_api_errors"]["window_secs"].is_u64());
+
+        // Tool calls name a configured account or fall back to the default one
+        let no_headers = header::HeaderMap::new();
+        assert!(tool_account(&app_state, &no_headers, Some("work"), None)
+            .await
+            .is_ok());
+        assert!(tool_account(&app_state, &no_headers, None, None)
+            .await
+            .is_ok());
+        assert_eq!(
+            tool_account(&app_state, &no_headers, Some("personal"), None)
+                .await
+                .err()
+                .unwrap()
+                .0,
+            StatusCode::BAD_REQUEST
+        );
+
+        // A bearer token is only used once caller tokens are trusted
+        let mut bearer_headers = header::HeaderMap::new();
+        bearer_headers.insert(
+            header::AUTHORIZATION,
+            header::HeaderValue::from_static("Bearer ya29.caller"),
+        );
+        let server = tool_account(&app_state, &bearer_headers, None, None)
+            .await
+            .unwrap();
+        assert!(Arc::ptr_eq(&server, &app_state.gmail_server));
+        let trusting_state = AppState {
+            caller_tokens: Some(Arc::new(caller_tokens::CallerTokens::new(
+                app_state.gmail_server.clone(),
+                &config,
+                &http_config,
+            ))),
+            ..app_state.clone()
+        };
+        let server = tool_account(&trusting_state, &bearer_headers, None, None)
+            .await
+            .unwrap();
+        assert!(!Arc::ptr_eq(&server, &app_state.gmail_server));
+        assert!(server.is_authenticated().await);
+        assert_eq!(
+            tool_account(&trusting_state, &bearer_headers, Some("work"), None)
+                .await
+                .err()
+                .unwrap()
+                .0,
+            StatusCode::BAD_REQUEST
+        );
+
+        use tower::Service;
+
+        // With an API key, tools requests without it are turned away before reaching Gmail
+        let keyed_state = AppState {
+            http_config: HttpConfig {
+                api_key: Some("k3y".to_string()),
+                ..http_config.clone()
+            },
+            ..app_state.clone()
+        };
+        let tools = tools_router(keyed_state.clone(), false)
+            .route_layer(axum::middleware::from_fn_with_state(
+                keyed_state.clone(),
+                require_api_key,
+            ))
+            .with_state(keyed_state.clone());
+        let tools_response = |authorization: Option<&'static str>| {
+            let mut request = Request::get("/send_draft?draft_id=d1");
+            if let Some(authorization) = authorization {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+            let request = request.body(Body::empty()).unwrap();
+            let mut tools = tools.clone();
+            async move {
+                let response = tools.call(request).await.unwrap();
+                let status = response.status();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+        let (status, body) = tools_response(None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(!body.starts_with("auth_required"));
+        let (status, _) = tools_response(Some("Bearer wrong")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        // Past the key check the call fails for want of a Gmail login instead
+        let (status, body) = tools_response(Some("Bearer k3y")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body, "auth_required: Not authenticated: no token available");
+
+        // With a client CA, only connections that presented a certificate it signed get through
+        let mtls_state = AppState {
+            http_config: HttpConfig {
+                tls_config: config::TlsConfig {
+                    tls_client_ca: Some("ca.pem".into()),
+                    ..Default::default()
+                },
+                ..http_config.clone()
+            },
+            ..app_state.clone()
+        };
+        let tools = tools_router(mtls_state.clone(), false)
+            .route_layer(axum::middleware::from_fn_with_state(
+                mtls_state.clone(),
+                require_client_certificate,
+            ))
+            .with_state(mtls_state);
+        let mtls_status = |certificate: Option<tls::ClientCertificate>| {
+            let mut request = Request::get("/send_draft?draft_id=d1")
+                .body(Body::empty())
+                .unwrap();
+            if let Some(certificate) = certificate {
+                request.extensions_mut().insert(certificate);
+            }
+            let mut tools = tools.clone();
+            async move { tools.call(request).await.unwrap().status() }
+        };
+        assert_eq!(mtls_status(None).await, StatusCode::FORBIDDEN);
+        assert_eq!(
+            mtls_status(Some(tls::ClientCertificate { verified: false })).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            mtls_status(Some(tls::ClientCertificate { verified: true })).await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        // Past its burst a client is told when to retry, while other clients are unaffected
+        let limited_state = AppState {
+            rate_limiter: Arc::new(rate_limit::RateLimiter::new(&config::RateLimitConfig {
+                rate_limit_per_minute: 60,
+                rate_limit_burst: Some(1),
+                ..Default::default()
+            })),
+            ..app_state.clone()
+        };
+        let tools = tools_router(limited_state.clone(), false)
+            .route_layer(axum::middleware::from_fn_with_state(
+                limited_state.clone(),
+                rate_limit,
+            ))
+            .with_state(limited_state);
+        let limited_response = |ip: [u8; 4]| {
+            let mut request = Request::get("/send_draft?draft_id=d1")
+                .body(Body::empty())
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from((ip, 40000))));
+            let mut tools = tools.clone();
+            async move { tools.call(request).await.unwrap() }
+        };
+        assert_eq!(
+            limited_response([10, 0, 0, 1]).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let response = limited_response([10, 0, 0, 1]).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+        assert_eq!(
+            limited_response([10, 0, 0, 2]).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        // Limited by API key, a client can't dodge its limit by making up new keys
+        let key_limited_state = AppState {
+            rate_limiter: Arc::new(rate_limit::RateLimiter::new(&config::RateLimitConfig {
+                rate_limit_per_minute: 60,
+                rate_limit_burst: Some(1),
+                rate_limit_by: config::RateLimitKey::ApiKey,
+            })),
+            ..keyed_state.clone()
+        };
+        let tools = tools_router(key_limited_state.clone(), false)
+            .route_layer(axum::middleware::from_fn_with_state(
+                key_limited_state.clone(),
+                rate_limit,
+            ))
+            .with_state(key_limited_state);
+        let key_limited_status = |authorization: &'static str| {
+            let mut request = Request::get("/send_draft?draft_id=d1")
+                .header(header::AUTHORIZATION, authorization)
+                .body(Body::empty())
+                .unwrap();
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 3], 40000))));
+            let mut tools = tools.clone();
+            async move { tools.call(request).await.unwrap().status() }
+        };
+        assert_eq!(
+            key_limited_status("Bearer guess-1").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            key_limited_status("Bearer guess-2").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // The real key has a bucket of its own
+        assert_eq!(
+            key_limited_status("Bearer k3y").await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        // A read-only server refuses the tools that change the mailbox without calling Gmail
+        let tools = tools_router(app_state.clone(), true).with_state(app_state.clone());
+        let read_only_status = |uri: &str| {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            let mut tools = tools.clone();
+            async move { tools.call(request).await.unwrap().status() }
+        };
+        assert_eq!(
+            read_only_status("/send_draft?draft_id=d1").await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            read_only_status("/search_threads?query=x").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            read_only_status("/add_rule?name=r&query=x").await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            read_only_status("/cancel_outbox_message?outbox_id=1").await,
+            StatusCode::FORBIDDEN
+        );
+        // Rules, saved searches and the outbox change what the server does, so they're hidden
+        // along with the tools that change the mailbox
+        let read_only_tools: Vec<_> = server::GmailMcpServer::new(
+            app_state.accounts.clone(),
+            None,
+            None,
+            http_config.clone(),
+        )
+        .read_only()
+        .tools()
+        .into_iter()
+        .map(|tool| tool.name.to_string())
+        .collect();
+        for tool in [
+            "send_draft",
+            "add_rule",
+            "cancel_outbox_message",
+            "save_search",
+        ] {
+            assert!(!read_only_tools.iter().any(|name| name == tool), "{tool}");
+        }
+        assert!(read_only_tools.iter().any(|name| name == "list_rules"));
+        // Attachments are read whatever they're called
+        assert_eq!(
+            read_only_status("/attachment/m1/send_draft").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            read_only_status("/attachment/m1/report.pdf?account=personal").await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            read_only_status("/preview/m1/photo.png?size=64").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            read_only_status("/preview/m1/photo.png?size=large").await,
+            StatusCode::BAD_REQUEST
+        );
+
+        // Arguments that don't fit a query string, like the thread_ids list, can be POSTed as JSON
+        let tools = tools_router(app_state.clone(), false).with_state(app_state.clone());
+        let post_status = |content_type: &str, body: &str| {
+            let request = Request::post("/fetch_email_bodies")
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let mut tools = tools.clone();
+            async move { tools.call(request).await.unwrap().status() }
+        };
+        assert_eq!(
+            post_status("application/json", r#"{"thread_ids": ["t1", "t2"]}"#).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            post_status("application/json", r#"{"thread_ids": "t1"}"#).await,
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            post_status("text/plain", r#"{"thread_ids": ["t1"]}"#).await,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+
+        // With an API key, the auth routes are closed to requests without it like the tools are
+        let auth_routes = protect(auth::auth_router(&http_config), &keyed_state)
+            .merge(auth::callback_router(&http_config))
+            .with_state(keyed_state.clone());
+        let keyed_status = |request: Request| {
+            let mut auth_routes = auth_routes.clone();
+            async move { auth_routes.call(request).await.unwrap().status() }
+        };
+        for request in [
+            Request::post("/custom-refresh"),
+            Request::get("/custom-status"),
+            Request::get("/auth/accounts"),
+            Request::get("/custom-login?account=work"),
+        ] {
+            assert_eq!(
+                keyed_status(request.body(Body::empty()).unwrap()).await,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        assert_eq!(
+            keyed_status(
+                Request::get("/custom-status")
+                    .header(header::AUTHORIZATION, "Bearer k3y")
+                    .body(Body::empty())
+                    .unwrap()
+            )
+            .await,
+            StatusCode::OK
+        );
+        // Without multi-user mode there are no session logins to let through
+        assert_eq!(
+            keyed_status(
+                Request::get("/custom-login?session=s1")
+                    .body(Body::empty())
+                    .unwrap()
+            )
+            .await,
+            StatusCode::UNAUTHORIZED
+        );
+        // A session's login link is opened in a browser, so it needs no key in multi-user mode
+        let multi_user_state = AppState {
+            sessions: Some(Arc::new(sessions::SessionAccounts::new(
+                keyed_state.gmail_server.clone(),
+                &config,
+                &keyed_state.http_config,
+                Duration::from_secs(3600),
+            ))),
+            ..keyed_state.clone()
+        };
+        let mut session_routes = protect(auth::auth_router(&http_config), &multi_user_state)
+            .with_state(multi_user_state);
+        assert_eq!(
+            session_routes
+                .call(
+                    Request::get("/custom-login?session=s1")
+                        .body(Body::empty())
+                        .unwrap()
+                )
+                .await
+                .unwrap()
+                .status(),
+            StatusCode::SEE_OTHER
+        );
+        // The callback only completes logins the server started, so it stays open
+        assert_eq!(
+            keyed_status(
+                Request::get("/custom-callback?code=c&state=unknown")
+                    .body(Body::empty())
+                    .unwrap()
+            )
+            .await,
+            StatusCode::BAD_REQUEST
+        );
+
+        // Auth handlers are mounted at the configured paths, not a fixed /auth prefix
+        let app = auth::auth_router(&http_config)
+            .merge(auth::callback_router(&http_config))
+            .with_state(app_state);
+        let status = |uri: &str| {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            let mut app = app.clone();
+            async move { app.call(request).await.unwrap().status() }
+        };
+        assert_eq!(status("/custom-status").await, StatusCode::OK);
+        assert_eq!(status("/custom-login").await, StatusCode::SEE_OTHER);
+        assert_eq!(
+            status("/custom-login?account=work").await,
+            StatusCode::SEE_OTHER
+        );
+        assert_eq!(
+            status("/custom-login?account=personal").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(status("/custom-status?account=work").await, StatusCode::OK);
+        assert_eq!(status("/auth/accounts").await, StatusCode::OK);
+        assert_eq!(status("/auth/status").await, StatusCode::NOT_FOUND);
+        // Refreshing changes state, so it's POST only
+        assert_eq!(
+            status("/custom-refresh").await,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
     }
 }
@@ -0,0 +1,301 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use tracing::instrument;
+
+use crate::email::{decode_email_content, html_to_markdown, unicode_domains};
+use crate::gmail::{GmailClient, GmailServer, GMAIL_API_BASE};
+use crate::labels::label_names;
+use crate::mime::{leaf_parts, part_header};
+use crate::oauth::SCOPE_READONLY;
+use crate::pgp::decrypt_message;
+use crate::tools::{message_url, text_size, MessageFormat};
+use crate::truncate::fit_text;
+use crate::utils::format_size;
+
+/// Headers in a document's front matter, under their lowercase names
+const FRONT_MATTER_HEADERS: [&str; 5] = ["From", "To", "Cc", "Date", "Subject"];
+
+/// A front matter value: JSON strings and arrays are valid YAML, and quoting them keeps subjects
+/// with colons or leading dashes from being read as YAML syntax
+fn yaml_value(value: &Value) -> String {
+    value.to_string()
+}
+
+/// A full-format message as a markdown document: YAML front matter with its headers and labels,
+/// its subject as a heading, its body, and a list of its attachments
+fn render_markdown(
+    message_id: &str,
+    message: &Value,
+    label_names: &HashMap<String, String>,
+) -> String {
+    let payload = &message["payload"];
+    let mut markdown = String::from("---\n");
+    markdown.push_str(&format!("message_id: {}\n", yaml_value(&json!(message_id))));
+    if let Some(thread_id) = message["threadId"].as_str() {
+        markdown.push_str(&format!("thread_id: {}\n", yaml_value(&json!(thread_id))));
+    }
+    for name in FRONT_MATTER_HEADERS {
+        if let Some(value) = part_header(payload, name) {
+            let value = match name {
+                "Subject" | "Date" => value.into(),
+                _ => unicode_domains(value),
+            };
+            markdown.push_str(&format!(
+                "{}: {}\n",
+                name.to_lowercase(),
+                yaml_value(&json!(value))
+            ));
+        }
+    }
+    let labels: Vec<&str> = message["labelIds"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .map(|id| label_names.get(id).map_or(id, String::as_str))
+        .collect();
+    if !labels.is_empty() {
+        markdown.push_str(&format!("labels: {}\n", yaml_value(&json!(labels))));
+    }
+    if let Some(encryption) = message["decrypted"].as_str() {
+        markdown.push_str(&format!("decrypted: {}\n", yaml_value(&json!(encryption))));
+    }
+    markdown.push_str("---\n\n");
+
+    let subject = part_header(payload, "Subject")
+        .map(str::trim)
+        .filter(|subject| !subject.is_empty())
+        .unwrap_or("(no subject)");
+    markdown.push_str(&format!("# {subject}\n\n"));
+
+    let mut leaves = Vec::new();
+    leaf_parts(payload, &mut leaves);
+    let body_part = |mime_type: &str| {
+        leaves.iter().find_map(|part| {
+            let is_body = part["mimeType"] == mime_type
+                && part["filename"].as_str().unwrap_or_default().is_empty();
+            is_body.then(|| part["body"]["data"].as_str()).flatten()
+        })
+    };
+    // HTML keeps the links and structure the plain text alternative loses
+    let body = match (body_part("text/html"), body_part("text/plain")) {
+        (Some(html), _) => decode_email_content(html)
+            .ok()
+            .map(|html| html_to_markdown(&html)),
+        (None, Some(text)) => decode_email_content(text)
+            .ok()
+            .map(|text| text.trim().to_string()),
+        (None, None) => None,
+    };
+    let encrypted = leaves.iter().any(|part| {
+        let mime_type = part["mimeType"].as_str().unwrap_or_default();
+        mime_type == "application/pgp-encrypted" || mime_type.ends_with("pkcs7-mime")
+    });
+    match body {
+        Some(body) => markdown.push_str(&body),
+        None if encrypted => {
+            markdown.push_str("_This message is encrypted and couldn't be decrypted._")
+        }
+        None => markdown.push_str("_This message has no text body._"),
+    }
+    markdown.push('\n');
+
+    let attachments: Vec<String> = leaves
+        .iter()
+        .filter_map(|part| {
+            let filename = part["filename"].as_str().filter(|name| !name.is_empty())?;
+            Some(format!(
+                "- {filename} ({}, {})",
+                part["mimeType"]
+                    .as_str()
+                    .unwrap_or("application/octet-stream"),
+                format_size(part["body"]["size"].as_u64().unwrap_or_default())
+            ))
+        })
+        .collect();
+    if !attachments.is_empty() {
+        markdown.push_str("\n## Attachments\n\n");
+        markdown.push_str(&attachments.join("\n"));
+        markdown.push('\n');
+    }
+    markdown
+}
+
+/// Fetch one message as a markdown document, for pasting into a prompt or a notes app
+#[instrument(name = "tool", skip_all, fields(tool = "email_to_markdown", message_id = %message_id))]
+pub async fn email_to_markdown(gmail_server: &GmailServer, message_id: &str) -> Result<Value> {
+    gmail_server.check_authentication(&[SCOPE_READONLY]).await?;
+
+    let client = gmail_server.authenticated_client().await?;
+    let user_id = gmail_server.user_id();
+    let mut message = client
+        .get_json(&message_url(user_id, message_id, MessageFormat::Full, None))
+        .await
+        .context("Failed to get message")?;
+    decrypt_message(gmail_server, &client, message_id, &mut message).await;
+    let names = user_label_names(&client, user_id, &[&message]).await?;
+
+    let markdown = render_markdown(message_id, &message, &names);
+    Ok(json!({
+        "message_id": message_id,
+        "thread_id": message["threadId"],
+        "markdown_size": text_size(&markdown),
+        "markdown": markdown,
+    }))
+}
+
+/// Label names for the user labels among `messages`, without a lookup when there are none, as
+/// system labels are named by their IDs
+async fn user_label_names(
+    client: &GmailClient,
+    user_id: &str,
+    messages: &[&Value],
+) -> Result<HashMap<String, String>> {
+    let has_user_labels = messages
+        .iter()
+        .flat_map(|message| message["labelIds"].as_array().into_iter().flatten())
+        .filter_map(Value::as_str)
+        .any(|id| id.starts_with("Label_"));
+    if has_user_labels {
+        label_names(client, user_id).await
+    } else {
+        Ok(HashMap::new())
+    }
+}
+
+/// Fetch every message of a thread as markdown documents, oldest first, cut down to the server's
+/// response limit if it has one
+pub async fn thread_to_markdown(gmail_server: &GmailServer, thread_id: &str) -> Result<Value> {
+    gmail_server.check_authentication(&[SCOPE_READONLY]).await?;
+
+    let client = gmail_server.authenticated_client().await?;
+    let user_id = gmail_server.user_id();
+    let url = format!(
+        "{GMAIL_API_BASE}/users/{user_id}/threads/{}?format=full",
+        urlencoding::encode(thread_id)
+    );
+    let thread = client
+        .get_json(&url)
+        .await
+        .context("Failed to get thread")?;
+    let mut messages = Vec::new();
+    for message in thread["messages"].as_array().into_iter().flatten() {
+        let mut message = message.clone();
+        let message_id = message["id"].as_str().unwrap_or_default().to_string();
+        decrypt_message(gmail_server, &client, &message_id, &mut message).await;
+        messages.push((message_id, message));
+    }
+    if messages.is_empty() {
+        anyhow::bail!("Thread {thread_id} has no messages");
+    }
+
+    let names = user_label_names(
+        &client,
+        user_id,
+        &messages
+            .iter()
+            .map(|(_, message)| message)
+            .collect::<Vec<_>>(),
+    )
+    .await?;
+    let markdown: Vec<String> = messages
+        .iter()
+        .map(|(message_id, message)| render_markdown(message_id, message, &names))
+        .collect();
+    let result = json!({
+        "thread_id": thread_id,
+        "messages": messages.len(),
+        "markdown": markdown.join("\n"),
+    });
+    Ok(fit_text(gmail_server, "markdown", result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockGmail;
+    use base64::{engine::general_purpose::URL_SAFE, Engine};
+
+    #[test]
+    fn test_render_markdown() {
+        let message = json!({
+            "threadId": "t1",
+            "labelIds": ["INBOX", "Label_7"],
+            "payload": {
+                "mimeType": "multipart/mixed",
+                "headers": [
+                    { "name": "From", "value": "Jörg <jorg@xn--mnchen-3ya.de>" },
+                    { "name": "Subject", "value": "Re: plan: v2" },
+                ],
+                "parts": [
+                    {
+                        "mimeType": "multipart/alternative",
+                        "parts": [
+                            { "mimeType": "text/plain", "filename": "", "body": { "data": URL_SAFE.encode("Plain") } },
+                            { "mimeType": "text/html", "filename": "", "body": { "data": URL_SAFE.encode("<p>See <b>this</b></p>") } },
+                        ],
+                    },
+                    { "mimeType": "application/pdf", "filename": "plan.pdf", "body": { "attachmentId": "a1", "size": 52_000 } },
+                ],
+            },
+        });
+        let names = HashMap::from([("Label_7".to_string(), "Projects".to_string())]);
+        assert_eq!(
+            render_markdown("m1", &message, &names),
+            "---\n\
+             message_id: \"m1\"\n\
+             thread_id: \"t1\"\n\
+             from: \"Jörg <jorg@münchen.de>\"\n\
+             subject: \"Re: plan: v2\"\n\
+             labels: [\"INBOX\",\"Projects\"]\n\
+             ---\n\n\
+             # Re: plan: v2\n\n\
+             See **this**\n\n\
+             ## Attachments\n\n\
+             - plan.pdf (application/pdf, 52 KB)\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_email_to_markdown_against_mock_gmail() {
+        let gmail = MockGmail::start().await;
+        let gmail_server = gmail.gmail_server(&gmail.config()).await;
+
+        let result = email_to_markdown(&gmail_server, "msg-1").await.unwrap();
+        assert_eq!(result["thread_id"], "thread-1");
+        let markdown = result["markdown"].as_str().unwrap();
+        assert!(markdown.starts_with("---\nmessage_id: \"msg-1\"\n"));
+        assert!(markdown.contains("from: \"Alice <alice@example.com>\"\n"));
+        assert!(
+            markdown.contains("\n# Quarterly report\n\nNotes from today's meeting are attached.\n")
+        );
+        assert!(markdown.ends_with("## Attachments\n\n- notes.txt (text/plain, 31 bytes)\n"));
+        assert_eq!(result["markdown_size"]["bytes"], markdown.len());
+
+        let result = email_to_markdown(&gmail_server, "msg-2").await.unwrap();
+        assert!(result["markdown"]
+            .as_str()
+            .unwrap()
+            .ends_with("# Re: Quarterly report\n\nSounds good, see you **Friday**.\n"));
+
+        assert!(email_to_markdown(&gmail_server, "missing").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_thread_to_markdown() {
+        let gmail = MockGmail::start().await;
+        let gmail_server = gmail.gmail_server(&gmail.config()).await;
+
+        let result = thread_to_markdown(&gmail_server, "thread-1").await.unwrap();
+        assert_eq!(result["messages"], 2);
+        let markdown = result["markdown"].as_str().unwrap();
+        assert!(markdown.starts_with("---\nmessage_id: \"msg-1\"\n"));
+        // Oldest first, each with its own front matter
+        let reply = markdown.find("---\nmessage_id: \"msg-2\"\n").unwrap();
+        assert!(markdown[..reply].contains("- notes.txt (text/plain, 31 bytes)\n"));
+        assert!(markdown.ends_with("Sounds good, see you **Friday**.\n"));
+
+        assert!(thread_to_markdown(&gmail_server, "missing").await.is_err());
+    }
+}
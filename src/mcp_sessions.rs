@@ -0,0 +1,431 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::Stream;
+use rmcp::model::{ClientJsonRpcMessage, ServerJsonRpcMessage};
+use rmcp::transport::common::server_side_http::ServerSseMessage;
+use rmcp::transport::streamable_http_server::session::local::{
+    create_local_session, LocalSessionManager, LocalSessionManagerError, LocalSessionWorker,
+    SessionConfig,
+};
+use rmcp::transport::streamable_http_server::session::{SessionId, SessionManager};
+use rmcp::transport::WorkerTransport;
+use rmcp::ServiceExt;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info};
+
+use crate::server::GmailMcpServer;
+
+/// How long a restored session stays in this process without requests; the store keeps it, so
+/// a later request restores it again
+const RESTORED_SESSION_IDLE: Duration = Duration::from_secs(600);
+
+/// Where MCP sessions are recorded so any replica, or the server after a restart, can serve them
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Record a new session with the client's `initialize` request as JSON
+    async fn save(&self, id: &str, initialize: &str) -> Result<()>;
+
+    /// The session's `initialize` request, or None when it ended or expired, keeping it alive
+    async fn load(&self, id: &str) -> Result<Option<String>>;
+
+    /// Forget a session the client ended; forgetting a missing one is not an error
+    async fn delete(&self, id: &str) -> Result<()>;
+
+    /// Human-readable location of the sessions for logs, never including credentials
+    fn location(&self) -> String;
+}
+
+/// Open the `--session-store` backend, or None to keep sessions in this process only
+pub fn open(
+    url: Option<&str>,
+    key_prefix: &str,
+    ttl_secs: u64,
+) -> Result<Option<Arc<dyn SessionStore>>> {
+    let Some(url) = url else {
+        return Ok(None);
+    };
+    if !(url.starts_with("redis://") || url.starts_with("rediss://")) {
+        anyhow::bail!("--session-store must be a redis:// or rediss:// URL");
+    }
+    #[cfg(feature = "redis")]
+    return Ok(Some(Arc::new(RedisSessionStore::new(
+        url,
+        format!("{key_prefix}mcp-session:"),
+        ttl_secs,
+    )?)));
+    #[cfg(not(feature = "redis"))]
+    {
+        let _ = (key_prefix, ttl_secs);
+        anyhow::bail!("--session-store requires building with the `redis` feature")
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SessionError {
+    #[error(transparent)]
+    Local(#[from] LocalSessionManagerError),
+    #[error("Session store error: {0:#}")]
+    Store(anyhow::Error),
+}
+
+/// Serves each MCP session from this process, recording new ones in the session store when one
+/// is configured. A session this process doesn't have, because another replica started it or
+/// the server restarted since, is restored by replaying the client's `initialize` request
+pub struct McpSessionManager {
+    local: Arc<LocalSessionManager>,
+    store: Option<Arc<dyn SessionStore>>,
+    /// Serves the restored sessions
+    server: GmailMcpServer,
+}
+
+impl McpSessionManager {
+    pub fn new(store: Option<Arc<dyn SessionStore>>, server: GmailMcpServer) -> Self {
+        Self {
+            local: Arc::default(),
+            store,
+            server,
+        }
+    }
+
+    /// Number of sessions this process is serving
+    pub async fn session_count(&self) -> usize {
+        self.local.sessions.read().await.len()
+    }
+
+    /// Start serving a session recorded in the store, returning whether there was one
+    async fn restore(&self, id: &SessionId) -> Result<bool, SessionError> {
+        let Some(store) = &self.store else {
+            return Ok(false);
+        };
+        let Some(initialize) = store.load(id).await.map_err(SessionError::Store)? else {
+            return Ok(false);
+        };
+        let initialize: ClientJsonRpcMessage = serde_json::from_str(&initialize)
+            .map_err(|e| SessionError::Store(anyhow::Error::new(e)))?;
+
+        // Another request for the session may have restored it while the store was read
+        let mut sessions = self.local.sessions.write().await;
+        if sessions.contains_key(id) {
+            return Ok(true);
+        }
+        let config = SessionConfig {
+            keep_alive: Some(RESTORED_SESSION_IDLE),
+            ..self.local.session_config.clone()
+        };
+        let (handle, worker) = create_local_session(id.clone(), config);
+        self.serve(id.clone(), WorkerTransport::spawn(worker));
+        handle
+            .initialize(initialize)
+            .await
+            .map_err(LocalSessionManagerError::from)?;
+        handle
+            .push_message(initialized_notification(), None)
+            .await
+            .map_err(LocalSessionManagerError::from)?;
+        sessions.insert(id.clone(), handle);
+        info!(session_id = %id, "Restored MCP session from the session store");
+        Ok(true)
+    }
+
+    /// Run the server on a restored session until it ends, then drop it from this process only;
+    /// closing it, as the HTTP service does for the sessions it creates, would end it for every
+    /// replica
+    fn serve(&self, id: SessionId, transport: WorkerTransport<LocalSessionWorker>) {
+        let (server, local) = (self.server.clone(), self.local.clone());
+        tokio::spawn(async move {
+            match server.serve(transport).await {
+                Ok(service) => {
+                    let _ = service.waiting().await;
+                }
+                Err(e) => error!(session_id = %id, "Failed to restore MCP session: {}", e),
+            }
+            let _ = local.close_session(&id).await;
+        });
+    }
+
+    /// The session is served here, restoring it from the store first if needed
+    async fn ensure(&self, id: &SessionId) -> Result<bool, SessionError> {
+        if self.local.has_session(id).await? {
+            return Ok(true);
+        }
+        self.restore(id).await
+    }
+}
+
+/// The notification a client sends once initialized, which a restored session's server waits for
+fn initialized_notification() -> ClientJsonRpcMessage {
+    serde_json::from_value(serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": "notifications/initialized",
+    }))
+    .expect("valid initialized notification")
+}
+
+impl SessionManager for McpSessionManager {
+    type Error = SessionError;
+    type Transport = WorkerTransport<LocalSessionWorker>;
+
+    async fn create_session(&self) -> Result<(SessionId, Self::Transport), Self::Error> {
+        Ok(self.local.create_session().await?)
+    }
+
+    async fn initialize_session(
+        &self,
+        id: &SessionId,
+        message: ClientJsonRpcMessage,
+    ) -> Result<ServerJsonRpcMessage, Self::Error> {
+        if let Some(store) = &self.store {
+            let initialize = serde_json::to_string(&message)
+                .map_err(|e| SessionError::Store(anyhow::Error::new(e)))?;
+            store
+                .save(id, &initialize)
+                .await
+                .map_err(SessionError::Store)?;
+        }
+        Ok(self.local.initialize_session(id, message).await?)
+    }
+
+    async fn has_session(&self, id: &SessionId) -> Result<bool, Self::Error> {
+        self.ensure(id).await
+    }
+
+    async fn close_session(&self, id: &SessionId) -> Result<(), Self::Error> {
+        if let Some(store) = &self.store {
+            store.delete(id).await.map_err(SessionError::Store)?;
+            debug!(session_id = %id, "Removed MCP session from the session store");
+        }
+        Ok(self.local.close_session(id).await?)
+    }
+
+    async fn create_stream(
+        &self,
+        id: &SessionId,
+        message: ClientJsonRpcMessage,
+    ) -> Result<impl Stream<Item = ServerSseMessage> + Send + Sync + 'static, Self::Error> {
+        self.ensure(id).await?;
+        Ok(self.local.create_stream(id, message).await?)
+    }
+
+    async fn accept_message(
+        &self,
+        id: &SessionId,
+        message: ClientJsonRpcMessage,
+    ) -> Result<(), Self::Error> {
+        self.ensure(id).await?;
+        Ok(self.local.accept_message(id, message).await?)
+    }
+
+    async fn create_standalone_stream(
+        &self,
+        id: &SessionId,
+    ) -> Result<impl Stream<Item = ServerSseMessage> + Send + Sync + 'static, Self::Error> {
+        self.ensure(id).await?;
+        Ok(self.local.create_standalone_stream(id).await?)
+    }
+
+    async fn resume(
+        &self,
+        id: &SessionId,
+        last_event_id: String,
+    ) -> Result<impl Stream<Item = ServerSseMessage> + Send + Sync + 'static, Self::Error> {
+        self.ensure(id).await?;
+        Ok(self.local.resume(id, last_event_id).await?)
+    }
+}
+
+/// Sessions shared by every replica through Redis keys that expire once idle
+#[cfg(feature = "redis")]
+pub struct RedisSessionStore {
+    client: redis::Client,
+    prefix: String,
+    ttl_secs: u64,
+}
+
+#[cfg(feature = "redis")]
+impl RedisSessionStore {
+    pub fn new(url: &str, prefix: String, ttl_secs: u64) -> Result<Self> {
+        use anyhow::Context;
+        let client = redis::Client::open(url).context("Invalid Redis URL")?;
+        Ok(Self {
+            client,
+            prefix,
+            ttl_secs,
+        })
+    }
+
+    async fn connection(&self) -> Result<redis::aio::MultiplexedConnection> {
+        use anyhow::Context;
+        self.client
+            .get_multiplexed_async_connection()
+            .await
+            .context("Failed to connect to Redis")
+    }
+
+    fn key(&self, id: &str) -> String {
+        format!("{}{id}", self.prefix)
+    }
+}
+
+#[cfg(feature = "redis")]
+#[async_trait]
+impl SessionStore for RedisSessionStore {
+    async fn save(&self, id: &str, initialize: &str) -> Result<()> {
+        use anyhow::Context;
+        use redis::AsyncCommands;
+        self.connection()
+            .await?
+            .set_ex::<_, _, ()>(self.key(id), initialize, self.ttl_secs)
+            .await
+            .context("Failed to write session to Redis")
+    }
+
+    async fn load(&self, id: &str) -> Result<Option<String>> {
+        use anyhow::Context;
+        use redis::AsyncCommands;
+        self.connection()
+            .await?
+            .get_ex(self.key(id), redis::Expiry::EX(self.ttl_secs))
+            .await
+            .context("Failed to read session from Redis")
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        use anyhow::Context;
+        use redis::AsyncCommands;
+        self.connection()
+            .await?
+            .del::<_, ()>(self.key(id))
+            .await
+            .context("Failed to delete session from Redis")
+    }
+
+    fn location(&self) -> String {
+        format!("Redis keys {}*", self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::{Account, Accounts};
+    use crate::config::{Config, HttpConfig};
+    use crate::gmail::GmailServer;
+    use crate::oauth::OAuthManager;
+    use std::collections::HashMap;
+    use std::path::Path;
+    use tokio::sync::Mutex;
+
+    fn server(app_data_dir: &Path) -> GmailMcpServer {
+        let config = Config {
+            gmail_client_id: Some("test_client_id".to_string()),
+            gmail_client_secret: Some("test_client_secret".to_string()),
+            app_data_dir: Some(app_data_dir.to_path_buf()),
+            ..Default::default()
+        };
+        let http_config = HttpConfig::default();
+        let oauth_manager =
+            Arc::new(OAuthManager::new(config.clone(), http_config.clone()).unwrap());
+        let default = Account {
+            gmail_server: Arc::new(GmailServer::new(oauth_manager.clone(), &config).unwrap()),
+            oauth_manager,
+        };
+        let accounts = Accounts::new(default, &config, &http_config).unwrap();
+        GmailMcpServer::new(Arc::new(accounts), None, None, http_config)
+    }
+
+    /// Stands in for Redis, shared by the managers of two "replicas"
+    #[derive(Default)]
+    struct MemorySessionStore {
+        sessions: Mutex<HashMap<String, String>>,
+    }
+
+    #[async_trait]
+    impl SessionStore for MemorySessionStore {
+        async fn save(&self, id: &str, initialize: &str) -> Result<()> {
+            let mut sessions = self.sessions.lock().await;
+            sessions.insert(id.to_string(), initialize.to_string());
+            Ok(())
+        }
+
+        async fn load(&self, id: &str) -> Result<Option<String>> {
+            Ok(self.sessions.lock().await.get(id).cloned())
+        }
+
+        async fn delete(&self, id: &str) -> Result<()> {
+            self.sessions.lock().await.remove(id);
+            Ok(())
+        }
+
+        fn location(&self) -> String {
+            "memory".to_string()
+        }
+    }
+
+    #[test]
+    fn test_open() {
+        assert!(open(None, "gmail-mcp-server:", 60).unwrap().is_none());
+        assert!(open(Some("localhost:6379"), "gmail-mcp-server:", 60).is_err());
+        let redis = open(Some("redis://localhost:6379"), "gmail-mcp-server:", 60);
+        if cfg!(feature = "redis") {
+            assert_eq!(
+                redis.unwrap().unwrap().location(),
+                "Redis keys gmail-mcp-server:mcp-session:*"
+            );
+        } else {
+            assert!(redis.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sessions_move_between_replicas() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = server(dir.path());
+        let store: Arc<dyn SessionStore> = Arc::new(MemorySessionStore::default());
+        let first = McpSessionManager::new(Some(store.clone()), server.clone());
+        let second = McpSessionManager::new(Some(store.clone()), server.clone());
+
+        let (id, transport) = first.create_session().await.unwrap();
+        first.serve(id.clone(), transport);
+        let initialize = serde_json::from_value(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "initialize",
+            "params": {
+                "protocolVersion": "2025-03-26",
+                "capabilities": {},
+                "clientInfo": { "name": "test", "version": "1.0" },
+            },
+        }))
+        .unwrap();
+        first.initialize_session(&id, initialize).await.unwrap();
+        assert!(store.load(&id).await.unwrap().is_some());
+
+        // The other replica picks the session up and answers on it
+        assert!(second.has_session(&id).await.unwrap());
+        let list_tools = serde_json::from_value(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 2,
+            "method": "tools/list",
+        }))
+        .unwrap();
+        let mut stream = Box::pin(second.create_stream(&id, list_tools).await.unwrap());
+        let response = futures::StreamExt::next(&mut stream).await.unwrap();
+        assert!(serde_json::to_string(&response.message)
+            .unwrap()
+            .contains("search_threads"));
+
+        // Ending it on one replica ends it everywhere
+        second.close_session(&id).await.unwrap();
+        first.local.close_session(&id).await.unwrap();
+        assert!(!first.has_session(&id).await.unwrap());
+
+        // Sessions nobody started are still unknown
+        let unknown = SessionId::from("unknown".to_string());
+        assert!(!second.has_session(&unknown).await.unwrap());
+        assert!(!McpSessionManager::new(None, server)
+            .has_session(&unknown)
+            .await
+            .unwrap());
+    }
+}
@@ -1,10 +1,45 @@
 use crate::oauth::OAuthToken;
-use metrics::gauge;
+use metrics::{counter, gauge};
+use reqwest::Method;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, VecDeque};
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 const GAUGE_TOKEN_LAST_REFRESHED_TIMESTAMP: &str = "gmail_mcp_token_last_refreshed_timestamp";
 const GAUGE_TOKEN_EXPIRATION_TIMESTAMP: &str = "gmail_mcp_token_expiration_timestamp";
 const GAUGE_TOKEN_EXISTS: &str = "gmail_mcp_token_exists";
+const COUNTER_GMAIL_API_CALLS: &str = "gmail_mcp_gmail_api_calls_total";
+const COUNTER_GMAIL_QUOTA_UNITS: &str = "gmail_mcp_gmail_quota_units_total";
+const COUNTER_TOKEN_REFRESH_FAILURES: &str = "gmail_mcp_token_refresh_failures_total";
+const COUNTER_RATE_LIMITED_REQUESTS: &str = "gmail_mcp_rate_limited_requests_total";
+const COUNTER_SHED_REQUESTS: &str = "gmail_mcp_shed_requests_total";
+const COUNTER_GMAIL_API_ERRORS: &str = "gmail_mcp_gmail_api_errors_total";
+const GAUGE_WATCHER_LAST_POLL_TIMESTAMP: &str = "gmail_mcp_watcher_last_poll_timestamp";
+
+/// How far back the Gmail API error summary looks
+const ERROR_WINDOW: Duration = Duration::from_secs(3600);
+/// Most errors kept for the summary, so an outage can't grow it without bound
+const MAX_RECENT_ERRORS: usize = 10_000;
+
+static GMAIL_API_ERRORS: LazyLock<Mutex<GmailApiErrors>> =
+    LazyLock::new(|| Mutex::new(GmailApiErrors::default()));
+static WATCHERS: LazyLock<Mutex<BTreeMap<&'static str, Watcher>>> = LazyLock::new(Mutex::default);
+
+/// When a background watcher started and last completed a poll, as Unix timestamps
+#[derive(Debug)]
+struct Watcher {
+    started: u64,
+    last_poll: Option<u64>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
 
 /// Prometheus metrics for OAuth token status
 pub struct OAuthMetrics {
@@ -44,6 +79,202 @@ impl OAuthMetrics {
     }
 }
 
+/// Count a failed OAuth token refresh
+pub fn record_token_refresh_failure() {
+    counter!(COUNTER_TOKEN_REFRESH_FAILURES).increment(1);
+}
+
+/// Count a request turned away by the rate limiter
+pub fn record_rate_limited() {
+    counter!(COUNTER_RATE_LIMITED_REQUESTS).increment(1);
+}
+
+/// Count a request turned away because too many were already in flight
+pub fn record_shed() {
+    counter!(COUNTER_SHED_REQUESTS).increment(1);
+}
+
+/// Count a Gmail API error response by its status code and error reason, such as
+/// `rateLimitExceeded` or `notFound`
+pub fn record_gmail_api_error(status: u16, reason: &str) {
+    counter!(
+        COUNTER_GMAIL_API_ERRORS,
+        "status" => status.to_string(),
+        "reason" => reason.to_string()
+    )
+    .increment(1);
+    GMAIL_API_ERRORS
+        .lock()
+        .unwrap()
+        .record(Instant::now(), status, reason);
+}
+
+/// Gmail API errors in the last hour by status code and reason, and the most recent one
+pub fn gmail_api_error_summary() -> Value {
+    GMAIL_API_ERRORS.lock().unwrap().summary(Instant::now())
+}
+
+/// Note that a background watcher, such as the cache history sync, has started polling
+pub fn record_watcher_started(watcher: &'static str) {
+    let started = unix_now();
+    WATCHERS.lock().unwrap().insert(
+        watcher,
+        Watcher {
+            started,
+            last_poll: None,
+        },
+    );
+}
+
+/// Note that a background watcher completed a poll, so what it watches is up to date
+pub fn record_watcher_poll(watcher: &'static str) {
+    let now = unix_now();
+    gauge!(GAUGE_WATCHER_LAST_POLL_TIMESTAMP, "watcher" => watcher).set(now as f64);
+    WATCHERS
+        .lock()
+        .unwrap()
+        .entry(watcher)
+        .or_insert(Watcher {
+            started: now,
+            last_poll: None,
+        })
+        .last_poll = Some(now);
+}
+
+/// Each running watcher's last completed poll, and how many seconds behind it is: since that
+/// poll, or since it started when none has completed yet
+pub fn watcher_status() -> Value {
+    watcher_lag(&WATCHERS.lock().unwrap(), unix_now())
+}
+
+fn watcher_lag(watchers: &BTreeMap<&'static str, Watcher>, now: u64) -> Value {
+    watchers
+        .iter()
+        .map(|(name, watcher)| {
+            let since = watcher.last_poll.unwrap_or(watcher.started);
+            let status = json!({
+                "last_poll": watcher.last_poll,
+                "lag_secs": now.saturating_sub(since),
+            });
+            (name.to_string(), status)
+        })
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+/// One Gmail API error response
+#[derive(Debug)]
+struct GmailApiError {
+    at: Instant,
+    timestamp: u64,
+    status: u16,
+    reason: String,
+}
+
+/// Gmail API errors since startup, with the recent ones kept for a rolling summary
+#[derive(Debug, Default)]
+struct GmailApiErrors {
+    total: u64,
+    recent: VecDeque<GmailApiError>,
+}
+
+impl GmailApiErrors {
+    fn record(&mut self, now: Instant, status: u16, reason: &str) {
+        self.total += 1;
+        if self.recent.len() == MAX_RECENT_ERRORS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(GmailApiError {
+            at: now,
+            timestamp: unix_now(),
+            status,
+            reason: reason.to_string(),
+        });
+    }
+
+    fn summary(&self, now: Instant) -> Value {
+        let mut by_status: BTreeMap<String, u64> = BTreeMap::new();
+        let mut by_reason: BTreeMap<&str, u64> = BTreeMap::new();
+        let recent: Vec<&GmailApiError> = self
+            .recent
+            .iter()
+            .filter(|error| now.duration_since(error.at) < ERROR_WINDOW)
+            .collect();
+        for error in &recent {
+            *by_status.entry(error.status.to_string()).or_default() += 1;
+            *by_reason.entry(&error.reason).or_default() += 1;
+        }
+        json!({
+            "window_secs": ERROR_WINDOW.as_secs(),
+            "total": self.total,
+            "recent": recent.len(),
+            "by_status": by_status,
+            "by_reason": by_reason,
+            "last_error": self.recent.back().map(|error| json!({
+                "status": error.status,
+                "reason": error.reason,
+                "timestamp": error.timestamp,
+            })),
+        })
+    }
+}
+
+/// A Gmail API method and the quota units Google charges per call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GmailEndpoint {
+    pub name: &'static str,
+    pub quota_units: u64,
+}
+
+impl GmailEndpoint {
+    const fn new(name: &'static str, quota_units: u64) -> Self {
+        Self { name, quota_units }
+    }
+
+    /// Identify the Gmail API method of a request from its path, e.g. `/gmail/v1/users/me/messages/123`
+    pub fn from_request(method: &Method, path: &str) -> Option<Self> {
+        let path = path.split('?').next()?;
+        let (_, user_path) = path.split_once("/users/")?;
+        // Skip the user ID segment
+        let segments: Vec<&str> = user_path.split('/').skip(1).collect();
+        let is_get = *method == Method::GET;
+
+        let endpoint = match (segments.as_slice(), is_get) {
+            (["profile"], true) => Self::new("users.getProfile", 1),
+            (["history"], true) => Self::new("history.list", 2),
+            (["labels"], true) => Self::new("labels.list", 1),
+            (["labels", _], true) => Self::new("labels.get", 1),
+            (["messages"], true) => Self::new("messages.list", 5),
+            (["messages", "send"], false) => Self::new("messages.send", 100),
+            (["messages", "batchModify"], false) => Self::new("messages.batchModify", 50),
+            (["messages", _], true) => Self::new("messages.get", 5),
+            (["messages", _, "modify"], false) => Self::new("messages.modify", 5),
+            (["messages", _, "attachments", _], true) => Self::new("messages.attachments.get", 5),
+            (["threads"], true) => Self::new("threads.list", 10),
+            (["threads", _], true) => Self::new("threads.get", 10),
+            (["threads", _, "modify"], false) => Self::new("threads.modify", 10),
+            (["drafts"], true) => Self::new("drafts.list", 5),
+            (["drafts"], false) => Self::new("drafts.create", 10),
+            (["drafts", "send"], false) => Self::new("drafts.send", 100),
+            (["drafts", _, "send"], false) => Self::new("drafts.send", 100),
+            (["drafts", _], true) => Self::new("drafts.get", 5),
+            _ => return None,
+        };
+        Some(endpoint)
+    }
+
+    /// Whether a call sends mail, so repeating it after Gmail may have accepted it sends twice
+    pub fn sends_mail(&self) -> bool {
+        matches!(self.name, "messages.send" | "drafts.send")
+    }
+
+    /// Count one call and its quota units against this endpoint
+    pub fn record(&self) {
+        counter!(COUNTER_GMAIL_API_CALLS, "endpoint" => self.name).increment(1);
+        counter!(COUNTER_GMAIL_QUOTA_UNITS, "endpoint" => self.name).increment(self.quota_units);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -60,9 +291,7 @@ mod tests {
             0
         );
         assert_eq!(
-            metrics
-                .token_expiration_timestamp
-                .load(Ordering::Relaxed),
+            metrics.token_expiration_timestamp.load(Ordering::Relaxed),
             0
         );
         assert_eq!(metrics.token_exists.load(Ordering::Relaxed), 0);
@@ -92,9 +321,7 @@ mod tests {
             now
         );
         assert_eq!(
-            metrics
-                .token_expiration_timestamp
-                .load(Ordering::Relaxed),
+            metrics.token_expiration_timestamp.load(Ordering::Relaxed),
             now + 3600
         );
         assert_eq!(metrics.token_exists.load(Ordering::Relaxed), 1);
@@ -127,9 +354,7 @@ mod tests {
             now
         );
         assert_eq!(
-            metrics
-                .token_expiration_timestamp
-                .load(Ordering::Relaxed),
+            metrics.token_expiration_timestamp.load(Ordering::Relaxed),
             now + 3600
         );
         assert_eq!(metrics.token_exists.load(Ordering::Relaxed), 0);
@@ -160,9 +385,7 @@ mod tests {
             past_time
         );
         assert_eq!(
-            metrics
-                .token_expiration_timestamp
-                .load(Ordering::Relaxed),
+            metrics.token_expiration_timestamp.load(Ordering::Relaxed),
             past_time + 3600
         );
         assert_eq!(metrics.token_exists.load(Ordering::Relaxed), 1);
@@ -192,9 +415,7 @@ mod tests {
             now
         );
         assert_eq!(
-            metrics
-                .token_expiration_timestamp
-                .load(Ordering::Relaxed),
+            metrics.token_expiration_timestamp.load(Ordering::Relaxed),
             now + 3600
         );
         assert_eq!(metrics.token_exists.load(Ordering::Relaxed), 1);
@@ -216,11 +437,112 @@ mod tests {
             new_time
         );
         assert_eq!(
-            metrics
-                .token_expiration_timestamp
-                .load(Ordering::Relaxed),
+            metrics.token_expiration_timestamp.load(Ordering::Relaxed),
             new_time + 3600
         );
         assert_eq!(metrics.token_exists.load(Ordering::Relaxed), 1);
     }
+
+    fn endpoint(method: Method, path: &str) -> Option<(&'static str, u64)> {
+        GmailEndpoint::from_request(&method, &format!("/gmail/v1/users/me/{path}"))
+            .map(|endpoint| (endpoint.name, endpoint.quota_units))
+    }
+
+    #[test]
+    fn test_gmail_endpoint_from_request() {
+        assert_eq!(
+            endpoint(Method::GET, "profile"),
+            Some(("users.getProfile", 1))
+        );
+        assert_eq!(
+            endpoint(Method::GET, "history?startHistoryId=1"),
+            Some(("history.list", 2))
+        );
+        assert_eq!(
+            endpoint(Method::GET, "threads?q=test"),
+            Some(("threads.list", 10))
+        );
+        assert_eq!(
+            endpoint(Method::GET, "threads/t1?format=minimal"),
+            Some(("threads.get", 10))
+        );
+        assert_eq!(
+            endpoint(Method::GET, "messages/m1"),
+            Some(("messages.get", 5))
+        );
+        assert_eq!(
+            endpoint(Method::GET, "messages/m1/attachments/a1"),
+            Some(("messages.attachments.get", 5))
+        );
+        assert_eq!(
+            endpoint(Method::POST, "messages/send"),
+            Some(("messages.send", 100))
+        );
+        assert_eq!(
+            endpoint(Method::POST, "drafts"),
+            Some(("drafts.create", 10))
+        );
+        assert_eq!(
+            endpoint(Method::POST, "drafts/send"),
+            Some(("drafts.send", 100))
+        );
+        assert_eq!(
+            endpoint(Method::POST, "drafts/d1/send"),
+            Some(("drafts.send", 100))
+        );
+    }
+
+    #[test]
+    fn test_gmail_endpoint_from_request_unknown() {
+        assert_eq!(endpoint(Method::DELETE, "messages/m1"), None);
+        assert_eq!(
+            GmailEndpoint::from_request(&Method::POST, "/batch/gmail/v1"),
+            None
+        );
+        assert_eq!(GmailEndpoint::from_request(&Method::GET, "/token"), None);
+    }
+
+    #[test]
+    fn test_gmail_api_error_summary() {
+        let start = Instant::now();
+        let mut errors = GmailApiErrors::default();
+        errors.record(start, 404, "notFound");
+        errors.record(start + Duration::from_secs(1800), 429, "rateLimitExceeded");
+        errors.record(start + Duration::from_secs(3000), 429, "rateLimitExceeded");
+
+        let summary = errors.summary(start + Duration::from_secs(3000));
+        assert_eq!(summary["total"], 3);
+        assert_eq!(summary["recent"], 3);
+        assert_eq!(summary["by_status"], json!({ "404": 1, "429": 2 }));
+        assert_eq!(summary["last_error"]["reason"], "rateLimitExceeded");
+
+        // The 404 has aged out of the window, but still counts towards the total
+        let summary = errors.summary(start + Duration::from_secs(3700));
+        assert_eq!(summary["total"], 3);
+        assert_eq!(summary["recent"], 2);
+        assert_eq!(summary["by_reason"], json!({ "rateLimitExceeded": 2 }));
+
+        let summary = GmailApiErrors::default().summary(start);
+        assert_eq!(summary["recent"], 0);
+        assert_eq!(summary["last_error"], Value::Null);
+    }
+
+    #[test]
+    fn test_watcher_lag() {
+        let watcher = |last_poll| Watcher {
+            started: 1000,
+            last_poll,
+        };
+        let watchers = BTreeMap::from([
+            ("history_sync", watcher(Some(1500))),
+            ("rules", watcher(None)),
+        ]);
+        assert_eq!(
+            watcher_lag(&watchers, 1600),
+            json!({
+                "history_sync": { "last_poll": 1500, "lag_secs": 100 },
+                "rules": { "last_poll": null, "lag_secs": 600 },
+            })
+        );
+    }
 }
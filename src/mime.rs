@@ -0,0 +1,261 @@
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE},
+    Engine,
+};
+use serde_json::{json, Value};
+
+use crate::batch::boundary_from_content_type;
+
+/// A raw MIME entity, such as a decrypted PGP/MIME body, in the shape of a Gmail API message
+/// payload: `mimeType`, `filename` and `headers`, with the decoded content inline as base64url
+/// `body.data` or the entity's children as `parts`
+pub fn parse_entity(raw: &[u8]) -> Value {
+    let (head, body) = split_head(raw);
+    let headers = parse_headers(&String::from_utf8_lossy(head));
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    };
+
+    let content_type = header("Content-Type").unwrap_or("text/plain");
+    let mime_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let filename = header("Content-Disposition")
+        .and_then(|value| header_param(value, "filename"))
+        .or_else(|| header_param(content_type, "name"))
+        .unwrap_or_default();
+    let headers: Vec<Value> = headers
+        .iter()
+        .map(|(name, value)| json!({ "name": name, "value": value }))
+        .collect();
+
+    if mime_type.starts_with("multipart/") {
+        if let Some(boundary) = boundary_from_content_type(content_type) {
+            let parts: Vec<Value> = split_multipart(body, &boundary)
+                .into_iter()
+                .map(parse_entity)
+                .collect();
+            return json!({
+                "mimeType": mime_type,
+                "filename": filename,
+                "headers": headers,
+                "body": { "size": 0 },
+                "parts": parts,
+            });
+        }
+    }
+
+    let encoding = header("Content-Transfer-Encoding")
+        .unwrap_or("7bit")
+        .trim()
+        .to_ascii_lowercase();
+    let data = match encoding.as_str() {
+        "base64" => decode_base64(body),
+        "quoted-printable" => decode_quoted_printable(body),
+        _ => body.to_vec(),
+    };
+    json!({
+        "mimeType": mime_type,
+        "filename": filename,
+        "headers": headers,
+        "body": { "size": data.len(), "data": URL_SAFE.encode(&data) },
+    })
+}
+
+/// A header of a message part, by case-insensitive name
+pub fn part_header<'a>(part: &'a Value, name: &str) -> Option<&'a str> {
+    part["headers"].as_array()?.iter().find(|header| {
+        header["name"]
+            .as_str()
+            .is_some_and(|n| n.eq_ignore_ascii_case(name))
+    })?["value"]
+        .as_str()
+}
+
+/// Parts without children, depth first
+pub fn leaf_parts<'a>(part: &'a Value, leaves: &mut Vec<&'a Value>) {
+    match part["parts"].as_array() {
+        Some(parts) => parts.iter().for_each(|part| leaf_parts(part, leaves)),
+        None => leaves.push(part),
+    }
+}
+
+/// The header block and the body, split at the first blank line
+fn split_head(raw: &[u8]) -> (&[u8], &[u8]) {
+    // An entity starting with a blank line has no headers
+    if let Some(body) = raw.strip_prefix(b"\r\n").or(raw.strip_prefix(b"\n")) {
+        return (&[], body);
+    }
+    let blank_line = raw
+        .windows(2)
+        .enumerate()
+        .find_map(|(index, pair)| match pair {
+            b"\n\n" => Some((index + 1, index + 2)),
+            b"\n\r" if raw.get(index + 2) == Some(&b'\n') => Some((index + 1, index + 3)),
+            _ => None,
+        });
+    match blank_line {
+        Some((head_end, body_start)) => (&raw[..head_end], &raw[body_start..]),
+        None => (raw, &[]),
+    }
+}
+
+/// Unfolded header lines as (name, value) pairs, in order
+fn parse_headers(head: &str) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in head.lines() {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    headers
+}
+
+/// A parameter of a header value such as `attachment; filename="report.pdf"`
+fn header_param(value: &str, name: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|param| {
+        let (param_name, param_value) = param.trim().split_once('=')?;
+        param_name
+            .trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| param_value.trim().trim_matches('"').to_string())
+    })
+}
+
+/// The bodies of a multipart entity's children, between its boundary delimiter lines
+fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{boundary}");
+    let mut parts = Vec::new();
+    let mut part_start = None;
+    let mut line_start = 0;
+    while line_start < body.len() {
+        let line_end = body[line_start..]
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .map_or(body.len(), |index| line_start + index + 1);
+        let line = body[line_start..line_end].trim_ascii_end();
+        if let Some(rest) = line.strip_prefix(delimiter.as_bytes()) {
+            let closing = rest.starts_with(b"--");
+            if closing || rest.trim_ascii().is_empty() {
+                if let Some(start) = part_start {
+                    // The line break before a delimiter belongs to the delimiter
+                    let part = &body[start..line_start];
+                    let part = part
+                        .strip_suffix(b"\r\n")
+                        .or(part.strip_suffix(b"\n"))
+                        .unwrap_or(part);
+                    parts.push(part);
+                }
+                if closing {
+                    break;
+                }
+                part_start = Some(line_end);
+            }
+        }
+        line_start = line_end;
+    }
+    parts
+}
+
+/// Base64 content, ignoring line breaks; content that isn't valid base64 is kept as it is
+fn decode_base64(body: &[u8]) -> Vec<u8> {
+    let encoded: Vec<u8> = body
+        .iter()
+        .copied()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .collect();
+    STANDARD.decode(&encoded).unwrap_or_else(|_| body.to_vec())
+}
+
+fn decode_quoted_printable(body: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::with_capacity(body.len());
+    let mut index = 0;
+    while index < body.len() {
+        if body[index] != b'=' {
+            decoded.push(body[index]);
+            index += 1;
+            continue;
+        }
+        let rest = &body[index + 1..];
+        // A soft line break joins two lines
+        if let Some(after) = rest.strip_prefix(b"\r\n").or(rest.strip_prefix(b"\n")) {
+            index = body.len() - after.len();
+            continue;
+        }
+        let hex = rest
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match hex {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(b'=');
+                index += 1;
+            }
+        }
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(part: &Value) -> String {
+        let data = URL_SAFE
+            .decode(part["body"]["data"].as_str().unwrap())
+            .unwrap();
+        String::from_utf8(data).unwrap()
+    }
+
+    #[test]
+    fn test_parse_multipart_entity() {
+        let raw = concat!(
+            "Content-Type: multipart/mixed;\r\n boundary=\"outer\"\r\n",
+            "\r\n",
+            "--outer\r\n",
+            "Content-Type: text/plain; charset=utf-8\r\n",
+            "Content-Transfer-Encoding: quoted-printable\r\n",
+            "\r\n",
+            "Caf=C3=A9 at noon, see the at=\r\ntached plan.\r\n",
+            "--outer\r\n",
+            "Content-Type: application/pdf; name=\"plan.pdf\"\r\n",
+            "Content-Disposition: attachment; filename=\"plan.pdf\"\r\n",
+            "Content-Transfer-Encoding: base64\r\n",
+            "\r\n",
+            "JVBERi0x\r\nLjQ=\r\n",
+            "--outer--\r\n",
+        );
+        let payload = parse_entity(raw.as_bytes());
+        assert_eq!(payload["mimeType"], "multipart/mixed");
+        let parts = payload["parts"].as_array().unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0]["mimeType"], "text/plain");
+        assert_eq!(data(&parts[0]), "Café at noon, see the attached plan.");
+        assert_eq!(parts[1]["filename"], "plan.pdf");
+        assert_eq!(data(&parts[1]), "%PDF-1.4");
+        assert_eq!(parts[1]["body"]["size"], 8);
+    }
+
+    #[test]
+    fn test_parse_entity_defaults_to_plain_text() {
+        let payload = parse_entity(b"\nJust text\n\nand more\n");
+        assert_eq!(payload["mimeType"], "text/plain");
+        assert_eq!(payload["headers"], json!([]));
+        assert_eq!(data(&payload), "Just text\n\nand more\n");
+    }
+}
@@ -1,4 +1,6 @@
 use crate::config::{Config, HttpConfig};
+use crate::secrets::ClientCredentials;
+use crate::token_store::{MemoryTokenStore, TokenStore};
 use anyhow::{Context, Result};
 use oauth2::reqwest;
 use oauth2::{
@@ -6,11 +8,46 @@ use oauth2::{
     EndpointNotSet, EndpointSet, RedirectUrl, RefreshToken, Scope, TokenResponse, TokenUrl,
 };
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::path::{Path, PathBuf};
+use serde_json::json;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use tracing::info;
+use tracing::{info, warn};
+
+/// Google's OAuth 2.0 token revocation endpoint
+const GOOGLE_REVOKE_URL: &str = "https://oauth2.googleapis.com/revoke";
+
+pub const SCOPE_READONLY: &str = "https://www.googleapis.com/auth/gmail.readonly";
+pub const SCOPE_COMPOSE: &str = "https://www.googleapis.com/auth/gmail.compose";
+pub const SCOPE_SEND: &str = "https://www.googleapis.com/auth/gmail.send";
+pub const SCOPE_MODIFY: &str = "https://www.googleapis.com/auth/gmail.modify";
+pub const SCOPE_FULL_ACCESS: &str = "https://mail.google.com/";
+pub const SCOPE_USERINFO_EMAIL: &str = "https://www.googleapis.com/auth/userinfo.email";
+/// Exporting Google Docs, Sheets and Slides linked from messages
+pub const SCOPE_DRIVE_READONLY: &str = "https://www.googleapis.com/auth/drive.readonly";
+
+/// Scopes a login may ask for on top of the initial ones
+pub const GMAIL_SCOPES: &[&str] = &[
+    SCOPE_READONLY,
+    SCOPE_COMPOSE,
+    SCOPE_SEND,
+    SCOPE_MODIFY,
+    SCOPE_FULL_ACCESS,
+    SCOPE_DRIVE_READONLY,
+];
+
+/// Whether the granted scopes cover `required`, directly or through a broader Gmail scope
+pub fn scope_granted(granted: &[&str], required: &str) -> bool {
+    let broader: &[&str] = match required {
+        SCOPE_READONLY | SCOPE_COMPOSE => &[SCOPE_MODIFY, SCOPE_FULL_ACCESS],
+        SCOPE_SEND => &[SCOPE_COMPOSE, SCOPE_MODIFY, SCOPE_FULL_ACCESS],
+        SCOPE_MODIFY => &[SCOPE_FULL_ACCESS],
+        _ => &[],
+    };
+    granted
+        .iter()
+        .any(|scope| *scope == required || broader.contains(scope))
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct OAuthToken {
@@ -23,24 +60,86 @@ pub struct OAuthToken {
 }
 
 impl OAuthToken {
-    #[allow(dead_code)]
+    /// Unix time in seconds when the access token expires
+    pub fn expires_at(&self) -> u64 {
+        self.created_at + self.expires_in
+    }
+
     pub fn is_expired(&self) -> bool {
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs();
-        now > self.created_at + self.expires_in
+        now > self.expires_at()
+    }
+
+    /// Whether the token can still make Gmail calls: the access token hasn't expired, or the
+    /// refresh token can get a new one
+    pub fn is_usable(&self) -> bool {
+        !self.is_expired() || self.refresh_token.is_some()
+    }
+
+    /// The granted OAuth scopes
+    pub fn scopes(&self) -> Vec<&str> {
+        self.scope.split_whitespace().collect()
+    }
+
+    /// Required scopes this token wasn't granted; none when the token doesn't list its scopes
+    pub fn missing_scopes(&self, required: &[&str]) -> Vec<String> {
+        let granted = self.scopes();
+        if granted.is_empty() {
+            return Vec::new();
+        }
+        required
+            .iter()
+            .filter(|scope| !scope_granted(&granted, scope))
+            .map(|scope| scope.to_string())
+            .collect()
     }
 }
 
+type OAuthClient =
+    BasicClient<EndpointSet, EndpointNotSet, EndpointNotSet, EndpointNotSet, EndpointSet>;
+
 pub struct OAuthManager {
-    client: BasicClient<EndpointSet, EndpointNotSet, EndpointNotSet, EndpointNotSet, EndpointSet>,
+    client_id: String,
+    client_secret: String,
+    /// Credentials rotated by a secret manager, which replace the ones above once set
+    rotated_credentials: Arc<ClientCredentials>,
+    redirect_url: RedirectUrl,
     token: Arc<Mutex<Option<OAuthToken>>>,
-    token_file: PathBuf,
+    /// Where the token is persisted between runs
+    store: Arc<dyn TokenStore>,
+    http_client: reqwest::Client,
+    revoke_url: String,
+    refresh_failure_webhook_url: Option<String>,
+    refresh_failure_alert_threshold: u32,
+    consecutive_refresh_failures: AtomicU32,
+    /// Ask for read-only access first and write scopes only when a tool needs them
+    incremental_auth: bool,
 }
 
 impl OAuthManager {
     pub fn new(config: Config, http_config: HttpConfig) -> Result<Self> {
+        Self::for_account(config, http_config, crate::accounts::DEFAULT_ACCOUNT)
+    }
+
+    /// Manage the token of a named account, kept in the `--token-store` backend
+    pub fn for_account(config: Config, http_config: HttpConfig, account: &str) -> Result<Self> {
+        let store = crate::token_store::open(&config, account)?;
+        Self::with_store(config, http_config, store)
+    }
+
+    /// Manage a token that only lives in memory, whatever the configured store
+    pub fn in_memory(config: Config, http_config: HttpConfig) -> Result<Self> {
+        Self::with_store(config, http_config, Arc::new(MemoryTokenStore::default()))
+    }
+
+    fn with_store(
+        config: Config,
+        http_config: HttpConfig,
+        store: Arc<dyn TokenStore>,
+    ) -> Result<Self> {
         let client_id = config
             .gmail_client_id
             .clone()
@@ -49,9 +148,40 @@ impl OAuthManager {
             .gmail_client_secret
             .clone()
             .ok_or_else(|| anyhow::anyhow!("GMAIL_CLIENT_SECRET not set"))?;
-        let redirect_url = http_config.oauth_redirect_url();
+        let redirect_url = RedirectUrl::new(http_config.oauth_redirect_url())?;
 
-        let client = BasicClient::new(ClientId::new(client_id))
+        let http_client = crate::utils::apply_proxy(
+            reqwest::ClientBuilder::new()
+                // Following redirects opens the client up to SSRF vulnerabilities.
+                .redirect(reqwest::redirect::Policy::none()),
+            &config,
+        )?
+        .build()
+        .context("Failed to build OAuth HTTP client")?;
+
+        Ok(Self {
+            client_id,
+            client_secret,
+            rotated_credentials: config.client_credentials.clone(),
+            redirect_url,
+            token: Arc::new(Mutex::new(None)),
+            store,
+            http_client,
+            revoke_url: GOOGLE_REVOKE_URL.to_string(),
+            refresh_failure_webhook_url: config.refresh_failure_webhook_url.clone(),
+            refresh_failure_alert_threshold: config.refresh_failure_alert_threshold.max(1),
+            consecutive_refresh_failures: AtomicU32::new(0),
+            incremental_auth: config.incremental_auth,
+        })
+    }
+
+    /// The OAuth client, built from the current credentials so rotations apply straight away
+    fn client(&self) -> Result<OAuthClient> {
+        let (client_id, client_secret) = self
+            .rotated_credentials
+            .get()
+            .unwrap_or_else(|| (self.client_id.clone(), self.client_secret.clone()));
+        Ok(BasicClient::new(ClientId::new(client_id))
             .set_client_secret(ClientSecret::new(client_secret))
             .set_auth_uri(AuthUrl::new(
                 "https://accounts.google.com/o/oauth2/auth".to_string(),
@@ -59,30 +189,36 @@ impl OAuthManager {
             .set_token_uri(TokenUrl::new(
                 "https://oauth2.googleapis.com/token".to_string(),
             )?)
-            .set_redirect_uri(RedirectUrl::new(redirect_url)?);
-
-        let token_file = crate::utils::get_app_file_path(&config, "token.json")?;
+            .set_redirect_uri(self.redirect_url.clone()))
+    }
 
-        Ok(Self {
-            client,
-            token: Arc::new(Mutex::new(None)),
-            token_file,
-        })
+    /// Scopes every login asks for
+    fn initial_scopes(&self) -> &'static [&'static str] {
+        if self.incremental_auth {
+            &[SCOPE_READONLY, SCOPE_USERINFO_EMAIL]
+        } else {
+            &[SCOPE_MODIFY, SCOPE_READONLY, SCOPE_USERINFO_EMAIL]
+        }
     }
 
-    pub fn get_authorization_url(&self) -> Result<(String, String)> {
+    /// Google's consent URL and CSRF state, asking for `additional_scopes` on top of the initial
+    /// ones while keeping whatever was granted before
+    pub fn get_authorization_url(&self, additional_scopes: &[String]) -> Result<(String, String)> {
+        let mut scopes: Vec<Scope> = self
+            .initial_scopes()
+            .iter()
+            .map(|scope| Scope::new(scope.to_string()))
+            .collect();
+        for scope in additional_scopes {
+            if !self.initial_scopes().contains(&scope.as_str()) {
+                scopes.push(Scope::new(scope.clone()));
+            }
+        }
         let (auth_url, csrf_token) = self
-            .client
+            .client()?
             .authorize_url(CsrfToken::new_random)
-            .add_scope(Scope::new(
-                "https://www.googleapis.com/auth/gmail.modify".to_string(),
-            ))
-            .add_scope(Scope::new(
-                "https://www.googleapis.com/auth/gmail.readonly".to_string(),
-            ))
-            .add_scope(Scope::new(
-                "https://www.googleapis.com/auth/userinfo.email".to_string(),
-            ))
+            .add_scopes(scopes)
+            .add_extra_param("include_granted_scopes", "true")
             .add_extra_param("access_type", "offline")
             .add_extra_param("prompt", "consent")
             .url();
@@ -91,16 +227,10 @@ impl OAuthManager {
     }
 
     pub async fn exchange_code(&self, code: &str) -> Result<OAuthToken> {
-        let async_http_client = reqwest::ClientBuilder::new()
-            // Following redirects opens the client up to SSRF vulnerabilities.
-            .redirect(reqwest::redirect::Policy::none())
-            .build()
-            .expect("Client should build");
-
         let token_response = self
-            .client
+            .client()?
             .exchange_code(AuthorizationCode::new(code.to_string()))
-            .request_async(&async_http_client)
+            .request_async(&self.http_client)
             .await
             .context("Failed to exchange authorization code")?;
 
@@ -129,23 +259,81 @@ impl OAuthManager {
         Ok(oauth_token)
     }
 
-    #[allow(dead_code)]
+    /// Refresh the access token, alerting operators when refreshes keep failing
     pub async fn refresh_token(&self) -> Result<OAuthToken> {
-        let async_http_client = reqwest::ClientBuilder::new()
-            // Following redirects opens the client up to SSRF vulnerabilities.
-            .redirect(reqwest::redirect::Policy::none())
-            .build()
-            .expect("Client should build");
+        match self.request_refreshed_token().await {
+            Ok(token) => {
+                self.consecutive_refresh_failures
+                    .store(0, Ordering::Relaxed);
+                Ok(token)
+            }
+            Err(e) => {
+                self.record_refresh_failure(&e).await;
+                Err(e)
+            }
+        }
+    }
 
-        let old_token = self.get_token().await;
+    async fn record_refresh_failure(&self, error: &anyhow::Error) {
+        crate::metrics::record_token_refresh_failure();
+        // A revoked or expired refresh token, which only a new login fixes
+        if format!("{error:#}").contains("invalid_grant") {
+            crate::metrics::record_gmail_api_error(400, "invalidGrant");
+        }
+        let failures = self
+            .consecutive_refresh_failures
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        warn!("Token refresh failed ({} in a row): {:#}", failures, error);
+
+        // Alert once per run of failures rather than on every retry
+        if failures != self.refresh_failure_alert_threshold {
+            return;
+        }
+        let Some(webhook_url) = &self.refresh_failure_webhook_url else {
+            return;
+        };
+        let payload = json!({
+            "event": "token_refresh_failed",
+            "consecutive_failures": failures,
+            "error": format!("{error:#}"),
+            "timestamp": std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        });
+        match self
+            .http_client
+            .post(webhook_url)
+            .json(&payload)
+            .send()
+            .await
+        {
+            Ok(response) if response.status().is_success() => {
+                info!("🚨 Token refresh failure alert sent to webhook");
+            }
+            Ok(response) => warn!(
+                "Token refresh failure webhook returned {}",
+                response.status()
+            ),
+            Err(e) => warn!("Failed to send token refresh failure webhook: {}", e),
+        }
+    }
+
+    async fn request_refreshed_token(&self) -> Result<OAuthToken> {
+        let old_token = self
+            .get_token()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("No refresh token found"))?;
         let refresh_token_str = old_token
-            .and_then(|t| t.refresh_token)
+            .refresh_token
+            .clone()
             .ok_or_else(|| anyhow::anyhow!("No refresh token found"))?;
 
         let token_response = self
-            .client
+            .client()?
             .exchange_refresh_token(&RefreshToken::new(refresh_token_str))
-            .request_async(&async_http_client)
+            .request_async(&self.http_client)
             .await
             .context("Failed to refresh token")?;
 
@@ -153,10 +341,12 @@ impl OAuthManager {
             access_token: token_response.access_token().secret().to_string(),
             token_type: token_response.token_type().as_ref().to_string(),
             expires_in: token_response.expires_in().unwrap_or_default().as_secs(),
+            // Google usually omits the refresh token and scopes from refresh responses
             refresh_token: token_response
                 .refresh_token()
-                .map(|t| t.secret().to_string()),
-            scope: token_response.scopes().map_or("".to_string(), |s| {
+                .map(|t| t.secret().to_string())
+                .or(old_token.refresh_token),
+            scope: token_response.scopes().map_or(old_token.scope, |s| {
                 s.iter()
                     .map(|s| s.to_string())
                     .collect::<Vec<_>>()
@@ -183,29 +373,290 @@ impl OAuthManager {
     }
 
     pub async fn save_token(&self, token: &OAuthToken) -> Result<()> {
-        let token_json =
-            serde_json::to_string_pretty(token).context("Failed to serialize token")?;
-        fs::write(&self.token_file, token_json).context("Failed to write token file")?;
-        info!("🔑 Token saved to {}", self.token_file.display());
+        self.store.save(token).await?;
+        info!("🔑 Token saved to {}", self.store.location());
         Ok(())
     }
 
     pub async fn load_token(&self) -> Result<Option<OAuthToken>> {
-        if self.token_file.exists() {
-            info!("🔑 Loading token from {}", self.token_file.display());
-            let token_json =
-                fs::read_to_string(&self.token_file).context("Failed to read token file")?;
-            let token: OAuthToken =
-                serde_json::from_str(&token_json).context("Failed to deserialize token")?;
-            Ok(Some(token))
-        } else {
-            info!("🔑 Token file not found at {}", self.token_file.display());
-            Ok(None)
+        let token = self.store.load().await?;
+        match &token {
+            Some(_) => info!("🔑 Loaded token from {}", self.store.location()),
+            None => info!("🔑 No token found in {}", self.store.location()),
         }
+        Ok(token)
+    }
+
+    /// Where the token is stored, for logs and status output
+    pub fn token_location(&self) -> String {
+        self.store.location()
+    }
+
+    /// Revoke the token with Google, then forget it and remove it from the store
+    pub async fn revoke_token(&self) -> Result<()> {
+        let token = self
+            .get_token()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("No token to revoke"))?;
+        // Revoking the refresh token also revokes every access token issued from it
+        let secret = token.refresh_token.unwrap_or(token.access_token);
+
+        let response = self
+            .http_client
+            .post(&self.revoke_url)
+            .form(&[("token", secret.as_str())])
+            .send()
+            .await
+            .context("Failed to revoke token")?;
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            // An expired or already revoked token is as good as revoked
+            if !error_text.contains("invalid_token") {
+                return Err(anyhow::anyhow!(
+                    "Token revocation failed: {status} - {error_text}"
+                ));
+            }
+            warn!("Token was already invalid, removing it locally");
+        }
+
+        *self.token.lock().await = None;
+        self.store.delete().await?;
+        info!(
+            "🔑 Token revoked and removed from {}",
+            self.store.location()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_config(webhook_url: Option<String>) -> Config {
+        Config {
+            gmail_client_id: Some("test_client_id".to_string()),
+            gmail_client_secret: Some("test_client_secret".to_string()),
+            refresh_failure_webhook_url: webhook_url,
+            refresh_failure_alert_threshold: 2,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refresh_failures_fire_webhook_once_at_threshold() {
+        let mut webhook = mockito::Server::new_async().await;
+        let mock = webhook
+            .mock("POST", "/alert")
+            .match_body(mockito::Matcher::PartialJson(json!({
+                "event": "token_refresh_failed",
+                "consecutive_failures": 2
+            })))
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let config = create_test_config(Some(format!("{}/alert", webhook.url())));
+        let oauth_manager = OAuthManager::new(config, HttpConfig::default()).unwrap();
+
+        // No refresh token is loaded, so every refresh fails
+        for _ in 0..3 {
+            assert!(oauth_manager.refresh_token().await.is_err());
+        }
+        assert_eq!(
+            oauth_manager
+                .consecutive_refresh_failures
+                .load(Ordering::Relaxed),
+            3
+        );
+        mock.assert_async().await;
+    }
+
+    fn revocable_token() -> OAuthToken {
+        OAuthToken {
+            access_token: "access".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: 3600,
+            refresh_token: Some("refresh".to_string()),
+            scope: String::new(),
+            created_at: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_revoke_token_deletes_token_file() {
+        let mut google = mockito::Server::new_async().await;
+        let mock = google
+            .mock("POST", "/revoke")
+            .match_body("token=refresh")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            app_data_dir: Some(dir.path().to_path_buf()),
+            ..create_test_config(None)
+        };
+        let mut oauth_manager = OAuthManager::new(config, HttpConfig::default()).unwrap();
+        oauth_manager.revoke_url = format!("{}/revoke", google.url());
+        oauth_manager.save_token(&revocable_token()).await.unwrap();
+        oauth_manager.set_token(revocable_token()).await;
+
+        oauth_manager.revoke_token().await.unwrap();
+        mock.assert_async().await;
+        assert!(oauth_manager.get_token().await.is_none());
+        assert!(!dir.path().join("token.json").exists());
+    }
+
+    #[tokio::test]
+    async fn test_revoke_token_failures() {
+        let mut google = mockito::Server::new_async().await;
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            app_data_dir: Some(dir.path().to_path_buf()),
+            ..create_test_config(None)
+        };
+        let mut oauth_manager = OAuthManager::new(config, HttpConfig::default()).unwrap();
+        oauth_manager.revoke_url = format!("{}/revoke", google.url());
+        assert!(oauth_manager.revoke_token().await.is_err());
+
+        // A server error keeps the token so revocation can be retried
+        oauth_manager.set_token(revocable_token()).await;
+        let unavailable = google
+            .mock("POST", "/revoke")
+            .with_status(503)
+            .create_async()
+            .await;
+        assert!(oauth_manager.revoke_token().await.is_err());
+        assert!(oauth_manager.get_token().await.is_some());
+        unavailable.remove_async().await;
+
+        // An already revoked token is removed locally
+        google
+            .mock("POST", "/revoke")
+            .with_status(400)
+            .with_body(r#"{"error": "invalid_token"}"#)
+            .create_async()
+            .await;
+        oauth_manager.revoke_token().await.unwrap();
+        assert!(oauth_manager.get_token().await.is_none());
+    }
+
+    #[test]
+    fn test_rotated_client_credentials_apply_to_existing_managers() {
+        let config = create_test_config(None);
+        let oauth_manager = OAuthManager::new(config.clone(), HttpConfig::default()).unwrap();
+        let (auth_url, _) = oauth_manager.get_authorization_url(&[]).unwrap();
+        assert!(auth_url.contains("client_id=test_client_id"));
+
+        config.client_credentials.set(
+            "rotated_client_id".to_string(),
+            "rotated_secret".to_string(),
+        );
+        let (auth_url, _) = oauth_manager.get_authorization_url(&[]).unwrap();
+        assert!(auth_url.contains("client_id=rotated_client_id"));
+    }
+
+    #[test]
+    fn test_is_usable() {
+        // Issued at the epoch, so long expired
+        let expired = revocable_token();
+        assert!(expired.is_expired());
+        assert!(expired.is_usable());
+        assert!(!OAuthToken {
+            refresh_token: None,
+            ..expired.clone()
+        }
+        .is_usable());
+        let valid = OAuthToken {
+            refresh_token: None,
+            created_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            ..expired
+        };
+        assert!(valid.is_usable());
+    }
+
+    #[test]
+    fn test_missing_scopes() {
+        let token = |scope: &str| OAuthToken {
+            scope: scope.to_string(),
+            ..revocable_token()
+        };
+        let readonly = token(&format!("{SCOPE_READONLY} {SCOPE_USERINFO_EMAIL}"));
+        assert_eq!(
+            readonly.missing_scopes(&[SCOPE_READONLY]),
+            Vec::<String>::new()
+        );
+        assert_eq!(readonly.missing_scopes(&[SCOPE_SEND]), vec![SCOPE_SEND]);
+        // Broader scopes cover narrower ones
+        assert!(token(SCOPE_COMPOSE)
+            .missing_scopes(&[SCOPE_SEND])
+            .is_empty());
+        assert!(token(SCOPE_MODIFY)
+            .missing_scopes(&[SCOPE_READONLY, SCOPE_COMPOSE])
+            .is_empty());
+        assert!(token(SCOPE_FULL_ACCESS)
+            .missing_scopes(&[SCOPE_MODIFY])
+            .is_empty());
+        assert_eq!(
+            token(SCOPE_SEND).missing_scopes(&[SCOPE_COMPOSE]),
+            vec![SCOPE_COMPOSE]
+        );
+        // Tokens that don't list their scopes aren't second-guessed
+        assert!(token("").missing_scopes(&[SCOPE_SEND]).is_empty());
+    }
+
+    #[test]
+    fn test_incremental_authorization_url() {
+        let scope_param = |oauth_manager: &OAuthManager, additional: &[String]| {
+            let (auth_url, _) = oauth_manager.get_authorization_url(additional).unwrap();
+            let auth_url = url::Url::parse(&auth_url).unwrap();
+            assert!(auth_url
+                .query_pairs()
+                .any(|(name, value)| name == "include_granted_scopes" && value == "true"));
+            auth_url
+                .query_pairs()
+                .find(|(name, _)| name == "scope")
+                .map(|(_, value)| value.into_owned())
+                .unwrap()
+        };
+
+        let oauth_manager =
+            OAuthManager::new(create_test_config(None), HttpConfig::default()).unwrap();
+        assert_eq!(
+            scope_param(&oauth_manager, &[]),
+            format!("{SCOPE_MODIFY} {SCOPE_READONLY} {SCOPE_USERINFO_EMAIL}")
+        );
+
+        let config = Config {
+            incremental_auth: true,
+            ..create_test_config(None)
+        };
+        let oauth_manager = OAuthManager::new(config, HttpConfig::default()).unwrap();
+        assert_eq!(
+            scope_param(&oauth_manager, &[]),
+            format!("{SCOPE_READONLY} {SCOPE_USERINFO_EMAIL}")
+        );
+        assert_eq!(
+            scope_param(
+                &oauth_manager,
+                &[SCOPE_SEND.to_string(), SCOPE_READONLY.to_string()]
+            ),
+            format!("{SCOPE_READONLY} {SCOPE_USERINFO_EMAIL} {SCOPE_SEND}")
+        );
     }
 
-    #[allow(dead_code)]
-    pub fn token_file_path(&self) -> &Path {
-        &self.token_file
+    #[tokio::test]
+    async fn test_refresh_failure_without_webhook() {
+        let oauth_manager =
+            OAuthManager::new(create_test_config(None), HttpConfig::default()).unwrap();
+        assert!(oauth_manager.refresh_token().await.is_err());
+        assert!(oauth_manager.refresh_token().await.is_err());
     }
 }
@@ -0,0 +1,347 @@
+use rmcp::model::Tool;
+use serde_json::{json, Map, Value};
+
+use crate::config::HttpConfig;
+
+/// OpenAPI 3.0 document of the `/tools` endpoints, with each tool's arguments described by the
+/// same schema its MCP clients see; rmcp already generates those in OpenAPI's dialect
+pub fn document(tools: &[Tool], http_config: &HttpConfig) -> Value {
+    let mut tools = tools.iter().collect::<Vec<_>>();
+    tools.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut paths = Map::new();
+    let mut schemas = Map::new();
+    for tool in tools {
+        let mut schema = Value::Object(tool.input_schema.as_ref().clone());
+        hoist_definitions(&mut schema, &mut schemas);
+        let summary = tool
+            .description
+            .as_deref()
+            .and_then(|description| description.lines().next())
+            .unwrap_or_default();
+
+        let mut operations = Map::new();
+        // Lists can't be passed in a query string, so those tools are POST only
+        if let Some(parameters) = query_parameters(&schema) {
+            operations.insert(
+                "get".to_string(),
+                json!({
+                    "operationId": format!("{}_get", tool.name),
+                    "summary": summary,
+                    "description": tool.description,
+                    "tags": ["tools"],
+                    "parameters": parameters,
+                    "responses": responses(false),
+                }),
+            );
+        }
+        operations.insert(
+            "post".to_string(),
+            json!({
+                "operationId": tool.name,
+                "summary": summary,
+                "description": tool.description,
+                "tags": ["tools"],
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": schema } },
+                },
+                "responses": responses(true),
+            }),
+        );
+        paths.insert(format!("/{}", tool.name), Value::Object(operations));
+    }
+    paths.insert(
+        "/attachment/{message_id}/{filename}".to_string(),
+        attachment_path(),
+    );
+    paths.insert(
+        "/preview/{message_id}/{filename}".to_string(),
+        preview_path(),
+    );
+
+    let mut document = json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Gmail MCP Server tools",
+            "description": "The server's MCP tools, called directly over HTTP",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        // Relative to where the document is served, so it holds behind a proxy too
+        "servers": [{ "url": http_config.tools_route() }],
+        "tags": [{ "name": "tools" }],
+        "paths": paths,
+        "components": { "schemas": schemas },
+    });
+    if http_config.api_key.is_some() {
+        let scheme = if http_config
+            .api_key_header
+            .eq_ignore_ascii_case(axum::http::header::AUTHORIZATION.as_str())
+        {
+            json!({ "type": "http", "scheme": "bearer" })
+        } else {
+            json!({ "type": "apiKey", "in": "header", "name": http_config.api_key_header })
+        };
+        document["components"]["securitySchemes"] = json!({ "apiKey": scheme });
+        document["security"] = json!([{ "apiKey": [] }]);
+    }
+    document
+}
+
+/// The arguments as query parameters, or `None` when one of them is a list
+fn query_parameters(schema: &Value) -> Option<Vec<Value>> {
+    let required = schema["required"].as_array();
+    let properties = schema["properties"].as_object()?;
+    properties
+        .iter()
+        .map(|(name, property)| {
+            let is_list = match &property["type"] {
+                Value::String(kind) => kind == "array",
+                Value::Array(kinds) => kinds.iter().any(|kind| kind == "array"),
+                _ => false,
+            };
+            if is_list {
+                return None;
+            }
+            let mut schema = property.clone();
+            let description = schema
+                .as_object_mut()
+                .and_then(|schema| schema.remove("description"));
+            Some(json!({
+                "name": name,
+                "in": "query",
+                "required": required.is_some_and(|required| required.contains(&json!(name))),
+                "description": description,
+                "schema": schema,
+            }))
+        })
+        .collect()
+}
+
+/// Move a schema's `definitions` to the document's components, where OpenAPI resolves references
+fn hoist_definitions(schema: &mut Value, schemas: &mut Map<String, Value>) {
+    if let Some(object) = schema.as_object_mut() {
+        object.remove("$schema");
+        if let Some(Value::Object(definitions)) = object.remove("definitions") {
+            for (name, mut definition) in definitions {
+                rewrite_refs(&mut definition);
+                schemas.insert(name, definition);
+            }
+        }
+    }
+    rewrite_refs(schema);
+}
+
+fn rewrite_refs(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                match value {
+                    Value::String(reference) if key == "$ref" => {
+                        if let Some(name) = reference.strip_prefix("#/definitions/") {
+                            *reference = format!("#/components/schemas/{name}");
+                        }
+                    }
+                    value => rewrite_refs(value),
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(rewrite_refs),
+        _ => {}
+    }
+}
+
+/// The route serving an attachment's bytes, which isn't an MCP tool
+fn attachment_path() -> Value {
+    let mut responses = responses(false);
+    responses["200"] = json!({
+        "description": "The attachment, with the content type its message part declares",
+        "content": { "*/*": { "schema": { "type": "string", "format": "binary" } } },
+    });
+    json!({
+        "get": {
+            "operationId": "attachment",
+            "summary": "Download an attachment",
+            "tags": ["tools"],
+            "parameters": attachment_parameters(),
+            "responses": responses,
+        }
+    })
+}
+
+/// The route previewing an attachment, which isn't an MCP tool
+fn preview_path() -> Value {
+    let mut parameters = attachment_parameters();
+    parameters.push(json!({
+        "name": "max_chars",
+        "in": "query",
+        "description": "Characters of extracted text to return (default: 2000)",
+        "schema": { "type": "integer", "minimum": 0 },
+    }));
+    parameters.push(json!({
+        "name": "size",
+        "in": "query",
+        "description": "Largest width and height of an image thumbnail in pixels (default: 256)",
+        "schema": { "type": "integer", "minimum": 16, "maximum": 1024 },
+    }));
+    let mut responses = responses(false);
+    responses["200"] = json!({
+        "description": "The start of a document's text, or a PNG thumbnail of an image",
+        "headers": {
+            "X-Total-Chars": {
+                "description": "Length of the whole extracted text, for text previews",
+                "schema": { "type": "integer" },
+            },
+        },
+        "content": {
+            "text/plain": { "schema": { "type": "string" } },
+            "image/png": { "schema": { "type": "string", "format": "binary" } },
+        },
+    });
+    responses["415"] =
+        json!({ "description": "The attachment is neither a document nor an image" });
+    responses["422"] = json!({ "description": "The attachment couldn't be read" });
+    json!({
+        "get": {
+            "operationId": "preview",
+            "summary": "Preview an attachment",
+            "tags": ["tools"],
+            "parameters": parameters,
+            "responses": responses,
+        }
+    })
+}
+
+fn attachment_parameters() -> Vec<Value> {
+    let parameter = |name: &str, location: &str, required: bool, description: &str| {
+        json!({
+            "name": name,
+            "in": location,
+            "required": required,
+            "description": description,
+            "schema": { "type": "string" },
+        })
+    };
+    vec![
+        parameter("message_id", "path", true, "Gmail message ID"),
+        parameter("filename", "path", true, "Attachment filename"),
+        parameter(
+            "account",
+            "query",
+            false,
+            "Account to use, from the server's configured accounts (default: the server's default account)",
+        ),
+    ]
+}
+
+fn responses(json_body: bool) -> Value {
+    let text = |description: &str| {
+        json!({
+            "description": description,
+            "content": { "text/plain": { "schema": { "type": "string" } } },
+        })
+    };
+    let mut responses = json!({
+        "200": {
+            "description": "The tool's result",
+            "content": { "application/json": { "schema": { "type": "object" } } },
+        },
+        "400": text("Invalid arguments or an unknown account"),
+        "401": { "description": "Missing or wrong API key" },
+        "403": text("The token lacks a scope the tool needs, or the tool is read-only"),
+        "429": { "description": "Rate limited; retry after the Retry-After header's seconds" },
+        "500": text("The Gmail API call failed"),
+    });
+    if json_body {
+        responses["415"] = json!({ "description": "The body isn't application/json" });
+        responses["422"] = text("Missing or mistyped arguments");
+    }
+    responses
+}
+
+/// Swagger UI page loading the document from `openapi_route`
+pub fn docs_page(openapi_route: &str) -> String {
+    crate::render_template(
+        include_str!("../templates/docs.html"),
+        &[("{openapi_route}", openapi_route)],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{FetchEmailBodiesArgs, SendDraftArgs};
+    use rmcp::handler::server::common::schema_for_type;
+    use std::sync::Arc;
+
+    fn tools() -> Vec<Tool> {
+        vec![
+            Tool::new(
+                "send_draft",
+                "Send a draft",
+                Arc::new(schema_for_type::<SendDraftArgs>()),
+            ),
+            Tool::new(
+                "fetch_email_bodies",
+                "Fetch email bodies",
+                Arc::new(schema_for_type::<FetchEmailBodiesArgs>()),
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_document() {
+        let document = document(&tools(), &HttpConfig::default());
+        assert_eq!(document["servers"][0]["url"], "/tools");
+        assert!(document.get("security").is_none());
+
+        let send_draft = &document["paths"]["/send_draft"];
+        assert_eq!(send_draft["post"]["operationId"], "send_draft");
+        assert!(document["paths"]["/attachment/{message_id}/{filename}"]["get"].is_object());
+        assert!(document["paths"]["/preview/{message_id}/{filename}"]["get"].is_object());
+        let parameters = send_draft["get"]["parameters"].as_array().unwrap();
+        let draft_id = parameters
+            .iter()
+            .find(|parameter| parameter["name"] == "draft_id")
+            .unwrap();
+        assert_eq!(draft_id["required"], true);
+        assert_eq!(draft_id["description"], "Gmail draft ID to send");
+        assert!(parameters
+            .iter()
+            .any(|parameter| parameter["name"] == "dry_run" && parameter["required"] == false));
+
+        // The list of thread IDs only fits a JSON body
+        let fetch_email_bodies = &document["paths"]["/fetch_email_bodies"];
+        assert!(fetch_email_bodies.get("get").is_none());
+        let schema =
+            &fetch_email_bodies["post"]["requestBody"]["content"]["application/json"]["schema"];
+        assert!(schema.get("definitions").is_none());
+        assert!(schema
+            .to_string()
+            .contains("#/components/schemas/MessageFormat"));
+        assert!(document["components"]["schemas"]
+            .get("MessageFormat")
+            .is_some());
+    }
+
+    #[test]
+    fn test_document_with_api_key() {
+        let mut http_config = HttpConfig {
+            api_key: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let document = document(&tools(), &http_config);
+        assert_eq!(
+            document["components"]["securitySchemes"]["apiKey"]["scheme"],
+            "bearer"
+        );
+
+        http_config.api_key_header = "X-API-Key".to_string();
+        let document = super::document(&tools(), &http_config);
+        assert_eq!(
+            document["components"]["securitySchemes"]["apiKey"]["name"],
+            "X-API-Key"
+        );
+        assert_eq!(document["security"][0]["apiKey"], json!([]));
+    }
+}
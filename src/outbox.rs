@@ -0,0 +1,1017 @@
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use rusqlite::{params, Connection};
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, instrument, warn};
+
+use crate::accounts::Accounts;
+use crate::gmail::{is_retryable_status, ErrorKind, GmailError, GmailServer, GMAIL_API_BASE};
+use crate::oauth::{SCOPE_COMPOSE, SCOPE_READONLY, SCOPE_SEND};
+use crate::webhooks::WebhookEvent;
+
+/// The database of messages waiting to be sent, in the app data directory
+pub const OUTBOX_FILE: &str = "outbox.sqlite3";
+
+/// How often the worker looks for messages that are due another attempt
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a message being sent is kept from other attempts, in case the sender dies mid-send
+const SEND_LEASE_SECS: i64 = 10 * 60;
+
+/// Wait before the first retry, doubled for each one after it
+const RETRY_BASE_DELAY_SECS: i64 = 60;
+
+/// Longest wait between retries
+const RETRY_MAX_DELAY_SECS: i64 = 60 * 60;
+
+/// What a queued send gives Gmail
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outgoing {
+    /// An RFC 5322 message for `messages.send`
+    Message(String),
+    /// The ID of a draft for `drafts.send`
+    Draft(String),
+    /// A forward of `message_id`, built when it's sent since the original can't be read before
+    /// the account logs in
+    Forward {
+        message_id: String,
+        to: String,
+        subject: String,
+        body: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutboxStatus {
+    /// Waiting for its next attempt
+    Queued,
+    /// Given up on after a permanent error or too many retries, kept until cancelled
+    Failed,
+    /// An attempt failed after its request may have reached Gmail, so it may have been sent.
+    /// Never retried, kept until it's checked and cancelled
+    Unconfirmed,
+}
+
+impl OutboxStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Failed => "failed",
+            Self::Unconfirmed => "unconfirmed",
+        }
+    }
+}
+
+/// A message in the outbox
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutboxItem {
+    pub id: i64,
+    pub account: String,
+    /// Mailbox it's sent from
+    pub user_id: String,
+    /// Tool that sent it
+    pub tool: String,
+    pub outgoing: Outgoing,
+    pub to: Option<String>,
+    pub subject: Option<String>,
+    pub status: OutboxStatus,
+    pub attempts: u32,
+    /// Seconds since the epoch
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+    pub queued_at: i64,
+}
+
+/// Messages waiting to be sent from every account, kept in SQLite so they survive restarts and
+/// a send queued from the command line is dispatched by a running server
+pub struct OutboxStore {
+    conn: Mutex<Connection>,
+}
+
+impl OutboxStore {
+    /// Open (or create) the outbox database at the given path
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory at {}", dir.display()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open outbox database at {}", path.display()))?;
+        // It holds whole outgoing messages, so only the owner may read it, like the token file.
+        // SQLite gives its journal files the same mode
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+                .context("Failed to restrict outbox database permissions")?;
+        }
+        // Tool calls and the worker each open their own connection
+        conn.busy_timeout(Duration::from_secs(5))?;
+        Self::init(conn)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS outbox (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                account TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                tool TEXT NOT NULL,
+                raw TEXT,
+                draft_id TEXT,
+                forward_of TEXT,
+                to_address TEXT,
+                subject TEXT,
+                status TEXT NOT NULL,
+                attempts INTEGER NOT NULL,
+                next_attempt_at INTEGER NOT NULL,
+                last_error TEXT,
+                queued_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS outbox_next_attempt_at ON outbox (next_attempt_at);",
+        )
+        .context("Failed to initialize outbox database schema")?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Queue a message, returning its ID. The item's own ID is ignored
+    pub fn add(&self, item: &OutboxItem) -> Result<i64> {
+        // A forward keeps the caller's text in place of the message
+        let (raw, draft_id, forward_of) = match &item.outgoing {
+            Outgoing::Message(raw) => (Some(raw), None, None),
+            Outgoing::Draft(draft_id) => (None, Some(draft_id), None),
+            Outgoing::Forward {
+                message_id, body, ..
+            } => (Some(body), None, Some(message_id)),
+        };
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO outbox (account, user_id, tool, raw, draft_id, forward_of, to_address,
+                subject, status, attempts, next_attempt_at, last_error, queued_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            params![
+                item.account,
+                item.user_id,
+                item.tool,
+                raw,
+                draft_id,
+                forward_of,
+                item.to,
+                item.subject,
+                item.status.as_str(),
+                item.attempts,
+                item.next_attempt_at,
+                item.last_error,
+                item.queued_at
+            ],
+        )
+        .context("Failed to queue message")?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    pub fn get(&self, id: i64) -> Result<Option<OutboxItem>> {
+        Ok(self
+            .query(&format!("{SELECT} WHERE id = ?1"), params![id])?
+            .pop())
+    }
+
+    /// An account's messages, oldest first
+    pub fn list(&self, account: &str) -> Result<Vec<OutboxItem>> {
+        self.query(
+            &format!("{SELECT} WHERE account = ?1 ORDER BY id"),
+            params![account],
+        )
+    }
+
+    /// Queued messages of every account whose next attempt is due
+    pub fn due(&self, now: i64) -> Result<Vec<OutboxItem>> {
+        self.query(
+            &format!("{SELECT} WHERE status = 'queued' AND next_attempt_at <= ?1 ORDER BY id"),
+            params![now],
+        )
+    }
+
+    /// Take a due message for an attempt, keeping others off it until `lease_until`. False when
+    /// it was cancelled or taken since it was listed
+    pub fn claim(&self, id: i64, now: i64, lease_until: i64) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let claimed = conn
+            .execute(
+                "UPDATE outbox SET next_attempt_at = ?3
+                 WHERE id = ?1 AND status = 'queued' AND next_attempt_at <= ?2",
+                params![id, now, lease_until],
+            )
+            .context("Failed to claim queued message")?;
+        Ok(claimed == 1)
+    }
+
+    /// Record a failed attempt, leaving the message queued until `next_attempt_at`, or failed
+    /// when that's None
+    pub fn record_failure(
+        &self,
+        id: i64,
+        attempts: u32,
+        next_attempt_at: Option<i64>,
+        error: &str,
+    ) -> Result<()> {
+        let status = match next_attempt_at {
+            Some(_) => OutboxStatus::Queued,
+            None => OutboxStatus::Failed,
+        };
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE outbox SET status = ?2, attempts = ?3, next_attempt_at = ?4, last_error = ?5
+             WHERE id = ?1",
+            params![
+                id,
+                status.as_str(),
+                attempts,
+                next_attempt_at.unwrap_or_default(),
+                error
+            ],
+        )
+        .context("Failed to record send attempt")?;
+        Ok(())
+    }
+
+    /// Record an attempt that may have sent the message, so it isn't attempted again
+    pub fn record_unconfirmed(&self, id: i64, attempts: u32, error: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE outbox SET status = ?2, attempts = ?3, next_attempt_at = 0, last_error = ?4
+             WHERE id = ?1",
+            params![id, OutboxStatus::Unconfirmed.as_str(), attempts, error],
+        )
+        .context("Failed to record send attempt")?;
+        Ok(())
+    }
+
+    /// Remove a message, returning whether it was there
+    pub fn remove(&self, id: i64) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let removed = conn
+            .execute("DELETE FROM outbox WHERE id = ?1", params![id])
+            .context("Failed to remove queued message")?;
+        Ok(removed == 1)
+    }
+
+    fn query(&self, sql: &str, params: impl rusqlite::Params) -> Result<Vec<OutboxItem>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(sql)?;
+        let rows = statement.query_map(params, |row| {
+            let raw: Option<String> = row.get(4)?;
+            let draft_id: Option<String> = row.get(5)?;
+            let forward_of: Option<String> = row.get(6)?;
+            let to: Option<String> = row.get(7)?;
+            let subject: Option<String> = row.get(8)?;
+            let status: String = row.get(9)?;
+            Ok(OutboxItem {
+                id: row.get(0)?,
+                account: row.get(1)?,
+                user_id: row.get(2)?,
+                tool: row.get(3)?,
+                outgoing: match (raw, forward_of) {
+                    (raw, Some(message_id)) => Outgoing::Forward {
+                        message_id,
+                        to: to.clone().unwrap_or_default(),
+                        subject: subject.clone().unwrap_or_default(),
+                        body: raw.unwrap_or_default(),
+                    },
+                    (Some(raw), None) => Outgoing::Message(raw),
+                    (None, None) => Outgoing::Draft(draft_id.unwrap_or_default()),
+                },
+                to,
+                subject,
+                status: match status.as_str() {
+                    "failed" => OutboxStatus::Failed,
+                    "unconfirmed" => OutboxStatus::Unconfirmed,
+                    _ => OutboxStatus::Queued,
+                },
+                attempts: row.get(10)?,
+                next_attempt_at: row.get(11)?,
+                last_error: row.get(12)?,
+                queued_at: row.get(13)?,
+            })
+        })?;
+        rows.collect::<rusqlite::Result<_>>()
+            .context("Failed to read the outbox")
+    }
+}
+
+const SELECT: &str =
+    "SELECT id, account, user_id, tool, raw, draft_id, forward_of, to_address, subject,
+    status, attempts, next_attempt_at, last_error, queued_at FROM outbox";
+
+/// Send a message through the outbox, with `--outbox`: it's queued first, then sent straight
+/// away. When that attempt hits a transient failure the message stays queued for the worker to
+/// retry, and the result says so instead of failing. Without the outbox it's just sent
+pub async fn send(
+    gmail_server: &GmailServer,
+    tool: &str,
+    outgoing: Outgoing,
+    to: Option<&str>,
+    subject: Option<&str>,
+) -> Result<Value> {
+    let Some((store, account)) = open_store(gmail_server)? else {
+        return deliver(gmail_server, tool, &outgoing).await;
+    };
+    let item = new_item(gmail_server, account, tool, outgoing.clone(), to, subject);
+    let id = store.add(&OutboxItem {
+        // Kept from the worker while this first attempt runs
+        next_attempt_at: item.queued_at + SEND_LEASE_SECS,
+        ..item
+    })?;
+
+    match deliver(gmail_server, tool, &outgoing).await {
+        Ok(result) => {
+            store.remove(id)?;
+            Ok(result)
+        }
+        Err(e) if is_outcome_unknown(&e) => {
+            store.record_unconfirmed(id, 1, &format!("{e:#}"))?;
+            warn!(
+                "Message {} may have been sent, kept unconfirmed: {:#}",
+                id, e
+            );
+            Ok(json!({
+                "queued": false,
+                "unconfirmed": true,
+                "outbox_id": id,
+                "attempts": 1,
+                "error": format!("{e:#}"),
+            }))
+        }
+        Err(e) if is_transient(&e) => {
+            let next_attempt_at = now_secs() + retry_delay(1);
+            store.record_failure(id, 1, Some(next_attempt_at), &format!("{e:#}"))?;
+            warn!("Queued message {} to retry after: {:#}", id, e);
+            Ok(json!({
+                "queued": true,
+                "outbox_id": id,
+                "attempts": 1,
+                "next_attempt_at": rfc3339(next_attempt_at),
+                "error": format!("{e:#}"),
+            }))
+        }
+        Err(e) => {
+            store.remove(id)?;
+            Err(e)
+        }
+    }
+}
+
+/// Keep a send the account needs to log in for in the outbox, with `enqueue_if_unauthenticated`,
+/// for the worker to make once it has. Without the outbox the send fails with `error`
+pub fn park(
+    gmail_server: &GmailServer,
+    tool: &str,
+    outgoing: Outgoing,
+    error: anyhow::Error,
+) -> Result<Value> {
+    let Some((store, account)) = open_store(gmail_server)? else {
+        // Session and caller-token servers keep the outbox file but have no account to wait for
+        let reason = if gmail_server.outbox_file().is_some() {
+            "Sends can't wait in the outbox for a login in multi-user mode or with caller-supplied \
+             tokens"
+        } else {
+            "Sends are only queued until the next login with --outbox"
+        };
+        return Err(error.context(reason));
+    };
+    let (to, subject) = match &outgoing {
+        Outgoing::Forward { to, subject, .. } => (Some(to.clone()), Some(subject.clone())),
+        _ => (None, None),
+    };
+    let item = new_item(
+        gmail_server,
+        account,
+        tool,
+        outgoing,
+        to.as_deref(),
+        subject.as_deref(),
+    );
+    let id = store.add(&OutboxItem {
+        last_error: Some(format!("{error:#}")),
+        ..item
+    })?;
+    info!("📤 Queued message {} until {} logs in", id, account);
+    Ok(json!({
+        "queued": true,
+        "outbox_id": id,
+        "attempts": 0,
+        "waiting_for_login": true,
+        "error": format!("{error:#}"),
+    }))
+}
+
+/// Whether a send failed for want of a login, so it can wait in the outbox for one
+pub fn needs_login(error: &anyhow::Error) -> bool {
+    ErrorKind::of(error) == ErrorKind::AuthRequired
+}
+
+/// A send of `gmail_server`'s account due straight away
+fn new_item(
+    gmail_server: &GmailServer,
+    account: &str,
+    tool: &str,
+    outgoing: Outgoing,
+    to: Option<&str>,
+    subject: Option<&str>,
+) -> OutboxItem {
+    let now = now_secs();
+    OutboxItem {
+        id: 0,
+        account: account.to_string(),
+        user_id: gmail_server.user_id().to_string(),
+        tool: tool.to_string(),
+        outgoing,
+        to: to.map(str::to_string),
+        subject: subject.map(str::to_string),
+        status: OutboxStatus::Queued,
+        attempts: 0,
+        next_attempt_at: now,
+        last_error: None,
+        queued_at: now,
+    }
+}
+
+/// Hand a message to Gmail, telling webhooks once it's accepted
+async fn deliver(gmail_server: &GmailServer, tool: &str, outgoing: &Outgoing) -> Result<Value> {
+    let send_url = || {
+        format!(
+            "{GMAIL_API_BASE}/users/{}/messages/send",
+            gmail_server.user_id()
+        )
+    };
+    let (scope, url, payload) = match outgoing {
+        Outgoing::Message(raw) => (
+            SCOPE_SEND,
+            send_url(),
+            json!({ "raw": URL_SAFE.encode(raw.as_bytes()) }),
+        ),
+        Outgoing::Forward {
+            message_id,
+            to,
+            subject,
+            body,
+        } => {
+            gmail_server
+                .check_authentication(&[SCOPE_READONLY, SCOPE_SEND])
+                .await?;
+            let raw =
+                crate::tools::forward_message(gmail_server, message_id, to, subject, body).await?;
+            (
+                SCOPE_SEND,
+                send_url(),
+                json!({ "raw": URL_SAFE.encode(raw.as_bytes()) }),
+            )
+        }
+        Outgoing::Draft(draft_id) => (
+            SCOPE_COMPOSE,
+            format!(
+                "{GMAIL_API_BASE}/users/{}/drafts/{draft_id}/send",
+                gmail_server.user_id()
+            ),
+            json!({}),
+        ),
+    };
+    gmail_server.check_authentication(&[scope]).await?;
+    let client = gmail_server.authenticated_client().await?;
+    let response = client
+        .post(&url)
+        .json(&payload)
+        .send()
+        .await
+        .context("Failed to send message")
+        .map_err(unless_unsent)?;
+    if !response.status().is_success() {
+        return Err(GmailError::from_response(response).await.into());
+    }
+    // Gmail accepted the message, so failing to read its reply doesn't mean it wasn't sent
+    let sent: Value = response
+        .json()
+        .await
+        .context("Failed to parse response")
+        .map_err(OutcomeUnknown)?;
+
+    if let Some(webhooks) = gmail_server.webhooks() {
+        webhooks.send(
+            WebhookEvent::SendCompleted,
+            json!({
+                "tool": tool,
+                "account": gmail_server.account(),
+                "user_id": gmail_server.user_id(),
+                "message_id": sent["id"],
+                "thread_id": sent["threadId"],
+            }),
+        );
+    }
+    Ok(sent)
+}
+
+/// A send that failed after its request may have reached Gmail, such as a timeout waiting for
+/// the reply, so the message may have gone out
+#[derive(Debug, thiserror::Error)]
+#[error("The message may have been sent: {0:#}")]
+pub struct OutcomeUnknown(anyhow::Error);
+
+/// Mark a failed send as [`OutcomeUnknown`] unless its request surely never reached Gmail
+fn unless_unsent(error: anyhow::Error) -> anyhow::Error {
+    let reached_gmail = error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        .any(|cause| !cause.is_connect());
+    if reached_gmail {
+        OutcomeUnknown(error).into()
+    } else {
+        error
+    }
+}
+
+/// Whether a failed send may have gone out anyway, so it mustn't be attempted again
+pub fn is_outcome_unknown(error: &anyhow::Error) -> bool {
+    error.is::<OutcomeUnknown>()
+}
+
+/// Whether a failed send is worth retrying later: Gmail rate limits and server errors, and
+/// failures to connect to Gmail or refresh the token
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    if is_outcome_unknown(error) {
+        return false;
+    }
+    match error.downcast_ref::<GmailError>() {
+        Some(GmailError::Api { status, .. }) => {
+            is_retryable_status(*status) || ErrorKind::of(error) == ErrorKind::RateLimited
+        }
+        Some(_) => false,
+        None => error.chain().any(|cause| cause.is::<reqwest::Error>()),
+    }
+}
+
+/// Seconds to wait after the `attempts`th failed attempt
+fn retry_delay(attempts: u32) -> i64 {
+    let doublings = attempts.saturating_sub(1).min(16);
+    (RETRY_BASE_DELAY_SECS << doublings).min(RETRY_MAX_DELAY_SECS)
+}
+
+/// The account's queued and failed messages, oldest first
+#[instrument(name = "tool", skip_all, fields(tool = "list_outbox"))]
+pub async fn list_outbox(gmail_server: &GmailServer) -> Result<Value> {
+    let (store, account) = require_store(gmail_server)?;
+    let messages: Vec<Value> = store
+        .list(account)?
+        .iter()
+        .map(|item| {
+            let (draft_id, forwarded_message_id) = match &item.outgoing {
+                Outgoing::Draft(draft_id) => (Some(draft_id), None),
+                Outgoing::Forward { message_id, .. } => (None, Some(message_id)),
+                Outgoing::Message(_) => (None, None),
+            };
+            json!({
+                "outbox_id": item.id,
+                "tool": item.tool,
+                "user_id": item.user_id,
+                "to": item.to,
+                "subject": item.subject,
+                "draft_id": draft_id,
+                "forwarded_message_id": forwarded_message_id,
+                "status": item.status.as_str(),
+                "attempts": item.attempts,
+                "next_attempt_at": (item.status == OutboxStatus::Queued)
+                    .then(|| rfc3339(item.next_attempt_at)),
+                "last_error": item.last_error,
+                "queued_at": rfc3339(item.queued_at),
+            })
+        })
+        .collect();
+    Ok(json!({ "messages": messages }))
+}
+
+/// Take a queued or failed message out of the account's outbox without sending it
+#[instrument(name = "tool", skip_all, fields(tool = "cancel_outbox_message", outbox_id = id))]
+pub async fn cancel_outbox_message(gmail_server: &GmailServer, id: i64) -> Result<Value> {
+    let (store, account) = require_store(gmail_server)?;
+    let item = store
+        .get(id)?
+        .filter(|item| item.account == account)
+        .ok_or_else(|| anyhow::anyhow!("No message {id} in the outbox"))?;
+    store.remove(item.id)?;
+    Ok(json!({ "outbox_id": id, "cancelled": true }))
+}
+
+/// Retry due messages every 30 seconds, until cancelled. Messages of an account without a usable
+/// token wait for a login without using up their retries; after `max_retries` failed retries,
+/// or a failure that isn't transient, a message is marked failed
+pub fn spawn_outbox_worker(
+    accounts: Arc<Accounts>,
+    store: OutboxStore,
+    max_retries: u32,
+    ct: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(DISPATCH_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = ct.cancelled() => break,
+                _ = ticker.tick() => {}
+            }
+            let due = match store.due(now_secs()) {
+                Ok(due) => due,
+                Err(e) => {
+                    warn!("Failed to read the outbox: {:#}", e);
+                    continue;
+                }
+            };
+            for item in due {
+                if let Err(e) = dispatch(&accounts, &store, &item, max_retries).await {
+                    warn!("Failed to dispatch queued message {}: {:#}", item.id, e);
+                }
+            }
+        }
+    })
+}
+
+/// Make another attempt at a due message
+async fn dispatch(
+    accounts: &Accounts,
+    store: &OutboxStore,
+    item: &OutboxItem,
+    max_retries: u32,
+) -> Result<()> {
+    let gmail_server = accounts
+        .get(Some(&item.account))?
+        .gmail_server
+        .for_user(&item.user_id)?;
+    if !gmail_server.is_authenticated().await {
+        return Ok(());
+    }
+    let now = now_secs();
+    if !store.claim(item.id, now, now + SEND_LEASE_SECS)? {
+        return Ok(());
+    }
+
+    let attempts = item.attempts + 1;
+    match deliver(&gmail_server, &item.tool, &item.outgoing).await {
+        Ok(_) => {
+            info!(
+                "📤 Sent queued message {} after {} attempts",
+                item.id, attempts
+            );
+            store.remove(item.id)?;
+        }
+        Err(e) if is_outcome_unknown(&e) => {
+            store.record_unconfirmed(item.id, attempts, &format!("{e:#}"))?;
+            warn!(
+                "Queued message {} may have been sent, kept unconfirmed: {:#}",
+                item.id, e
+            );
+        }
+        Err(e) => {
+            // The first attempt isn't a retry
+            let retry = is_transient(&e) && attempts <= max_retries;
+            let next_attempt_at = retry.then(|| now_secs() + retry_delay(attempts));
+            store.record_failure(item.id, attempts, next_attempt_at, &format!("{e:#}"))?;
+            if retry {
+                warn!("Queued message {} failed, will retry: {:#}", item.id, e);
+            } else {
+                warn!("Giving up on queued message {}: {:#}", item.id, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The outbox database and the account a server's messages are queued under, with `--outbox`
+/// and a token the server keeps
+fn open_store(gmail_server: &GmailServer) -> Result<Option<(OutboxStore, &str)>> {
+    let (Some(path), Some(account)) = (gmail_server.outbox_file(), gmail_server.account()) else {
+        return Ok(None);
+    };
+    Ok(Some((OutboxStore::open(path)?, account)))
+}
+
+fn require_store(gmail_server: &GmailServer) -> Result<(OutboxStore, &str)> {
+    open_store(gmail_server)?.ok_or_else(|| {
+        anyhow::anyhow!(
+            "The outbox needs --outbox and a token the server keeps, so it isn't available with \
+             caller-supplied tokens, in multi-user mode or in demo mode"
+        )
+    })
+}
+
+fn rfc3339(secs: i64) -> String {
+    chrono::DateTime::from_timestamp(secs, 0)
+        .unwrap_or_default()
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+fn now_secs() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockGmail;
+    use reqwest::StatusCode;
+
+    fn item(account: &str, outgoing: Outgoing, next_attempt_at: i64) -> OutboxItem {
+        OutboxItem {
+            id: 0,
+            account: account.to_string(),
+            user_id: "me".to_string(),
+            tool: "send_draft".to_string(),
+            outgoing,
+            to: None,
+            subject: None,
+            status: OutboxStatus::Queued,
+            attempts: 0,
+            next_attempt_at,
+            last_error: None,
+            queued_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_store_queues_claims_and_fails() {
+        let store = OutboxStore::open_in_memory().unwrap();
+        let draft = store
+            .add(&item("default", Outgoing::Draft("d1".to_string()), 100))
+            .unwrap();
+        let message = store
+            .add(&item("default", Outgoing::Message("raw".to_string()), 300))
+            .unwrap();
+        store
+            .add(&item("work", Outgoing::Draft("d2".to_string()), 50))
+            .unwrap();
+
+        let due: Vec<i64> = store.due(150).unwrap().iter().map(|item| item.id).collect();
+        assert_eq!(due, vec![draft, 3]);
+        assert!(store.claim(draft, 150, 900).unwrap());
+        // Already taken until the lease runs out
+        assert!(!store.claim(draft, 150, 900).unwrap());
+
+        store.record_failure(draft, 1, Some(200), "503").unwrap();
+        store.record_failure(message, 1, None, "400").unwrap();
+        let listed = store.list("default").unwrap();
+        assert_eq!(listed[0].attempts, 1);
+        assert_eq!(listed[0].last_error.as_deref(), Some("503"));
+        assert_eq!(listed[1].status, OutboxStatus::Failed);
+        assert_eq!(listed[1].outgoing, Outgoing::Message("raw".to_string()));
+        // Failed messages aren't retried
+        assert!(store
+            .due(1000)
+            .unwrap()
+            .iter()
+            .all(|item| item.id != message));
+
+        assert!(store.remove(message).unwrap());
+        assert!(!store.remove(message).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_store_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(OUTBOX_FILE);
+        let mode = || std::fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+
+        OutboxStore::open(&path).unwrap();
+        assert_eq!(mode(), 0o600);
+        // An existing database is tightened too
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        OutboxStore::open(&path).unwrap();
+        assert_eq!(mode(), 0o600);
+    }
+
+    #[test]
+    fn test_retry_delay_doubles_up_to_an_hour() {
+        assert_eq!(retry_delay(1), 60);
+        assert_eq!(retry_delay(2), 120);
+        assert_eq!(retry_delay(4), 480);
+        assert_eq!(retry_delay(10), 3600);
+        assert_eq!(retry_delay(u32::MAX), 3600);
+    }
+
+    #[test]
+    fn test_is_transient() {
+        let api = |status: u16| -> anyhow::Error {
+            GmailError::Api {
+                status: StatusCode::from_u16(status).unwrap(),
+                reason: String::new(),
+                retry_after: None,
+                body: String::new(),
+            }
+            .into()
+        };
+        assert!(is_transient(&api(503)));
+        assert!(is_transient(&api(429)));
+        assert!(!is_transient(&api(400)));
+        assert!(!is_transient(&GmailError::InsufficientScope(vec![]).into()));
+        assert!(!is_transient(&anyhow::anyhow!("Invalid recipient")));
+        let unknown = OutcomeUnknown(anyhow::anyhow!("timed out")).into();
+        assert!(is_outcome_unknown(&unknown));
+        assert!(!is_transient(&unknown));
+    }
+
+    #[tokio::test]
+    async fn test_failed_sends_stay_queued_until_cancelled() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let unavailable = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .mount(&unavailable)
+            .await;
+        let gmail = MockGmail::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = gmail.config();
+        config.outbox = true;
+        config.app_data_dir = Some(dir.path().to_path_buf());
+        config.gmail_api.gmail_max_retries = 0;
+
+        // Sent straight away, leaving nothing queued
+        let gmail_server = gmail.gmail_server(&config).await;
+        let draft = Outgoing::Draft("draft-1".into());
+        let sent = send(&gmail_server, "send_draft", draft, None, None)
+            .await
+            .unwrap();
+        assert_eq!(sent["id"], "msg-sent");
+        assert_eq!(
+            list_outbox(&gmail_server).await.unwrap()["messages"],
+            json!([])
+        );
+
+        config.gmail_api.gmail_api_url = Some(unavailable.uri());
+        let gmail_server = gmail.gmail_server(&config).await;
+        let queued = send(
+            &gmail_server,
+            "forward_email",
+            Outgoing::Message("Subject: Hi\r\n\r\nHello".into()),
+            Some("bob@example.com"),
+            Some("Hi"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(queued["queued"], true);
+        let listed = list_outbox(&gmail_server).await.unwrap();
+        let message = &listed["messages"][0];
+        assert_eq!(message["outbox_id"], queued["outbox_id"]);
+        assert_eq!(message["to"], "bob@example.com");
+        assert_eq!(message["status"], "queued");
+        assert_eq!(message["attempts"], 1);
+
+        let id = queued["outbox_id"].as_i64().unwrap();
+        cancel_outbox_message(&gmail_server, id).await.unwrap();
+        assert!(cancel_outbox_message(&gmail_server, id).await.is_err());
+        assert_eq!(
+            list_outbox(&gmail_server).await.unwrap()["messages"],
+            json!([])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sends_that_may_have_gone_out_are_not_repeated() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        // Gmail accepts the send but the reply doesn't arrive before the timeout
+        let slow = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "id": "msg-sent" }))
+                    .set_delay(Duration::from_secs(3)),
+            )
+            .expect(1)
+            .mount(&slow)
+            .await;
+        let gmail = MockGmail::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = gmail.config();
+        config.outbox = true;
+        config.app_data_dir = Some(dir.path().to_path_buf());
+        config.gmail_api.gmail_api_url = Some(slow.uri());
+        config.gmail_api.gmail_timeout_secs = 1;
+        config.gmail_api.gmail_retry_base_delay_ms = 1;
+        let gmail_server = gmail.gmail_server(&config).await;
+
+        let draft = Outgoing::Draft("draft-1".into());
+        let result = send(&gmail_server, "send_draft", draft, None, None)
+            .await
+            .unwrap();
+        assert_eq!(result["unconfirmed"], true);
+        let listed = list_outbox(&gmail_server).await.unwrap();
+        assert_eq!(listed["messages"][0]["status"], "unconfirmed");
+        assert!(listed["messages"][0]["next_attempt_at"].is_null());
+
+        // The worker leaves it for the user to check
+        let store = OutboxStore::open(&dir.path().join(OUTBOX_FILE)).unwrap();
+        assert!(store.due(i64::MAX).unwrap().is_empty());
+        slow.verify().await;
+    }
+
+    #[tokio::test]
+    async fn test_sends_wait_in_the_outbox_for_a_login() {
+        use crate::accounts::Account;
+        use crate::config::HttpConfig;
+        use crate::oauth::{OAuthManager, OAuthToken, GMAIL_SCOPES};
+
+        let gmail = MockGmail::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = gmail.config();
+        config.outbox = true;
+        config.app_data_dir = Some(dir.path().to_path_buf());
+        let oauth_manager =
+            Arc::new(OAuthManager::in_memory(config.clone(), HttpConfig::default()).unwrap());
+        let gmail_server = Arc::new(GmailServer::new(oauth_manager.clone(), &config).unwrap());
+
+        let refused = crate::tools::send_draft(&gmail_server, "draft-1", false, false)
+            .await
+            .unwrap_err();
+        assert!(needs_login(&refused));
+        // A session's server has no account whose login the send could wait for
+        let session_server = gmail_server.for_account(oauth_manager.clone());
+        let refused = crate::tools::send_draft(&session_server, "draft-1", false, true)
+            .await
+            .unwrap_err();
+        assert!(refused
+            .to_string()
+            .starts_with("Sends can't wait in the outbox for a login in multi-user mode"));
+        let parked = crate::tools::send_draft(&gmail_server, "draft-1", false, true)
+            .await
+            .unwrap();
+        assert_eq!(parked["waiting_for_login"], true);
+        crate::tools::forward_email(
+            &gmail_server,
+            "msg-1",
+            "bob@example.com",
+            "Fwd: Hello",
+            "FYI",
+            false,
+            true,
+        )
+        .await
+        .unwrap();
+        let listed = list_outbox(&gmail_server).await.unwrap();
+        assert_eq!(listed["messages"][1]["forwarded_message_id"], "msg-1");
+        assert_eq!(listed["messages"][1]["to"], "bob@example.com");
+
+        let accounts = Accounts::new(
+            Account {
+                oauth_manager: oauth_manager.clone(),
+                gmail_server: gmail_server.clone(),
+            },
+            &config,
+            &HttpConfig::default(),
+        )
+        .unwrap();
+        let store = OutboxStore::open(&dir.path().join(OUTBOX_FILE)).unwrap();
+        let dispatch_due = || async {
+            for item in store.due(now_secs()).unwrap() {
+                dispatch(&accounts, &store, &item, 10).await.unwrap();
+            }
+        };
+
+        // Nothing is tried until the login
+        dispatch_due().await;
+        assert_eq!(store.list("default").unwrap().len(), 2);
+        assert!(gmail.requests().await.is_empty());
+
+        oauth_manager
+            .set_token(OAuthToken {
+                access_token: "mock_access_token".to_string(),
+                token_type: "Bearer".to_string(),
+                expires_in: u32::MAX.into(),
+                refresh_token: None,
+                scope: GMAIL_SCOPES.join(" "),
+                created_at: 0,
+            })
+            .await;
+        dispatch_due().await;
+        assert!(store.list("default").unwrap().is_empty());
+        let paths: Vec<String> = gmail
+            .requests()
+            .await
+            .iter()
+            .map(|request| request.url.path().to_string())
+            .collect();
+        assert!(paths.contains(&"/gmail/v1/users/me/drafts/draft-1/send".to_string()));
+        // The forward was built once the original could be read
+        assert!(paths.contains(&"/gmail/v1/users/me/messages/msg-1".to_string()));
+        assert!(paths.contains(&"/gmail/v1/users/me/messages/send".to_string()));
+    }
+}
@@ -0,0 +1,281 @@
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::gmail::{GmailClient, GmailServer, GMAIL_API_BASE};
+use crate::mime::parse_entity;
+
+/// Decrypts PGP/MIME messages with gpg and the keys in its keyring, from --pgp-decrypt.
+/// Passphrases come from gpg-agent, which has to have them cached or be able to ask
+#[derive(Debug)]
+#[cfg_attr(not(feature = "pgp"), allow(dead_code))]
+pub struct PgpDecryptor {
+    homedir: Option<PathBuf>,
+}
+
+impl PgpDecryptor {
+    /// The configured decryptor, if any
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        if !config.pgp_decrypt {
+            return Ok(None);
+        }
+        if cfg!(not(feature = "pgp")) {
+            anyhow::bail!("--pgp-decrypt requires building with the `pgp` feature");
+        }
+        Ok(Some(Self {
+            homedir: config.gpg_homedir.clone(),
+        }))
+    }
+
+    #[cfg(not(feature = "pgp"))]
+    async fn decrypt(&self, _ciphertext: &[u8]) -> Result<Vec<u8>> {
+        anyhow::bail!("Decrypting PGP messages requires building with the `pgp` feature")
+    }
+
+    /// The plaintext of an OpenPGP message, armored or binary
+    #[cfg(feature = "pgp")]
+    async fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let mut command = tokio::process::Command::new("gpg");
+        if let Some(homedir) = &self.homedir {
+            command.arg("--homedir").arg(homedir);
+        }
+        command.args(["--batch", "--quiet", "--no-tty", "--decrypt"]);
+        let output = crate::utils::run_with_input(command, ciphertext)
+            .await
+            .context("Failed to run gpg")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "gpg failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(output.stdout)
+    }
+}
+
+/// The first `multipart/encrypted` part of a message payload, itself included
+fn encrypted_part(part: &mut Value) -> Option<&mut Value> {
+    if part["mimeType"] == "multipart/encrypted" {
+        return Some(part);
+    }
+    part.get_mut("parts")?
+        .as_array_mut()?
+        .iter_mut()
+        .find_map(encrypted_part)
+}
+
+/// Decrypt a `multipart/encrypted` part into the MIME entity it wraps
+async fn decrypt_part(
+    decryptor: &PgpDecryptor,
+    client: &GmailClient,
+    user_id: &str,
+    message_id: &str,
+    part: &Value,
+) -> Result<Value> {
+    // RFC 3156: a version part, then the OpenPGP message as application/octet-stream
+    let encrypted = part["parts"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|part| part["mimeType"] == "application/octet-stream")
+        .context("Encrypted message has no OpenPGP data part")?;
+    let data = match (
+        encrypted["body"]["data"].as_str(),
+        encrypted["body"]["attachmentId"].as_str(),
+    ) {
+        (Some(data), _) => data.to_string(),
+        (None, Some(attachment_id)) => {
+            let url = format!(
+                "{GMAIL_API_BASE}/users/{user_id}/messages/{message_id}/attachments/{attachment_id}"
+            );
+            let attachment = client
+                .get_json(&url)
+                .await
+                .context("Failed to download the encrypted data")?;
+            attachment["data"]
+                .as_str()
+                .context("Invalid attachment data")?
+                .to_string()
+        }
+        (None, None) => anyhow::bail!("Encrypted data part is empty"),
+    };
+    let ciphertext = URL_SAFE
+        .decode(data)
+        .context("Failed to decode the encrypted data")?;
+    let plaintext = decryptor.decrypt(&ciphertext).await?;
+
+    // Keep the outer headers, such as From and Subject, ahead of the decrypted entity's own
+    let mut decrypted = parse_entity(&plaintext);
+    let mut headers: Vec<Value> = part["headers"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|header| {
+            !header["name"]
+                .as_str()
+                .unwrap_or_default()
+                .to_ascii_lowercase()
+                .starts_with("content-")
+        })
+        .cloned()
+        .collect();
+    headers.extend(
+        decrypted["headers"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .cloned(),
+    );
+    decrypted["headers"] = json!(headers);
+    Ok(decrypted)
+}
+
+/// Replace the encrypted part of a full-format PGP/MIME message with what it decrypts to, so its
+/// body and attachments go through the usual extraction, and mark the message `decrypted`.
+/// Messages stay as they are when decryption isn't configured or fails
+pub async fn decrypt_message(
+    gmail_server: &GmailServer,
+    client: &GmailClient,
+    message_id: &str,
+    message: &mut Value,
+) {
+    let Some(decryptor) = gmail_server.pgp_decryptor() else {
+        return;
+    };
+    let Some(part) = message.get_mut("payload").and_then(encrypted_part) else {
+        return;
+    };
+    // Only full-format messages carry the encrypted data
+    if part.get("parts").is_none() {
+        return;
+    }
+    match decrypt_part(decryptor, client, gmail_server.user_id(), message_id, part).await {
+        Ok(decrypted) => {
+            *part = decrypted;
+            message["decrypted"] = json!("pgp");
+        }
+        Err(e) => warn!("Failed to decrypt message {}: {:#}", message_id, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_config() {
+        assert!(PgpDecryptor::from_config(&Config::default())
+            .unwrap()
+            .is_none());
+        let config = Config {
+            pgp_decrypt: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            PgpDecryptor::from_config(&config).is_ok(),
+            cfg!(feature = "pgp")
+        );
+    }
+
+    #[test]
+    fn test_encrypted_part() {
+        let mut payload = json!({
+            "mimeType": "multipart/mixed",
+            "parts": [
+                { "mimeType": "text/plain" },
+                { "mimeType": "multipart/encrypted", "parts": [] },
+            ],
+        });
+        assert_eq!(
+            encrypted_part(&mut payload).unwrap()["mimeType"],
+            "multipart/encrypted"
+        );
+        assert!(encrypted_part(&mut json!({ "mimeType": "text/plain" })).is_none());
+    }
+
+    /// Encrypt a message to a throwaway key and decrypt it through the mock Gmail API
+    #[cfg(feature = "pgp")]
+    #[tokio::test]
+    async fn test_decrypt_message_with_gpg() {
+        use crate::test_support::MockGmail;
+        use std::process::Command;
+
+        let homedir = tempfile::tempdir().unwrap();
+        let gpg = |args: &[&str]| {
+            let status = Command::new("gpg")
+                .arg("--homedir")
+                .arg(homedir.path())
+                .args([
+                    "--batch",
+                    "--quiet",
+                    "--passphrase",
+                    "",
+                    "--pinentry-mode",
+                    "loopback",
+                ])
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        gpg(&[
+            "--quick-gen-key",
+            "Test <test@example.com>",
+            "future-default",
+            "default",
+            "never",
+        ]);
+        let plaintext = homedir.path().join("plain.eml");
+        std::fs::write(
+            &plaintext,
+            "Content-Type: text/plain; charset=utf-8\r\n\r\nThe launch moves to Friday.\r\n",
+        )
+        .unwrap();
+        gpg(&[
+            "--armor",
+            "--trust-model",
+            "always",
+            "--recipient",
+            "test@example.com",
+            "--encrypt",
+            plaintext.to_str().unwrap(),
+        ]);
+        let ciphertext = std::fs::read(homedir.path().join("plain.eml.asc")).unwrap();
+
+        let gmail = MockGmail::start().await;
+        let mut config = gmail.config();
+        config.pgp_decrypt = true;
+        config.gpg_homedir = Some(homedir.path().to_path_buf());
+        let gmail_server = gmail.gmail_server(&config).await;
+        let client = gmail_server.authenticated_client().await.unwrap();
+
+        let mut message = json!({
+            "payload": {
+                "mimeType": "multipart/encrypted",
+                "headers": [
+                    { "name": "Subject", "value": "Launch" },
+                    { "name": "Content-Type", "value": "multipart/encrypted; protocol=\"application/pgp-encrypted\"" },
+                ],
+                "parts": [
+                    { "mimeType": "application/pgp-encrypted", "body": { "data": URL_SAFE.encode("Version: 1") } },
+                    { "mimeType": "application/octet-stream", "body": { "data": URL_SAFE.encode(&ciphertext) } },
+                ],
+            },
+        });
+        decrypt_message(&gmail_server, &client, "msg-1", &mut message).await;
+        assert_eq!(message["decrypted"], "pgp");
+        assert_eq!(message["payload"]["mimeType"], "text/plain");
+        assert_eq!(message["payload"]["headers"][0]["value"], "Launch");
+        assert_eq!(
+            crate::email::decode_email_content(
+                message["payload"]["body"]["data"].as_str().unwrap()
+            )
+            .unwrap(),
+            "The launch moves to Friday.\r\n"
+        );
+    }
+}
@@ -0,0 +1,485 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+use tracing::instrument;
+use url::{Host, Url};
+
+use crate::email::{decode_email_content, decode_entities};
+use crate::gmail::GmailServer;
+use crate::mime::{leaf_parts, part_header};
+use crate::oauth::SCOPE_READONLY;
+use crate::pgp::decrypt_message;
+use crate::tools::{message_url, MessageFormat};
+
+/// A result of an authentication method in an Authentication-Results header, e.g. `spf=pass`
+static AUTH_RESULT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\b(spf|dkim|dmarc)\s*=\s*([a-z]+)").unwrap());
+
+/// A link in an HTML body, capturing its target and its text
+static ANCHOR: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r#"(?is)<a\b[^>]*?\bhref\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))[^>]*>(.*?)</a\s*>"#,
+    )
+    .unwrap()
+});
+
+/// A web address in a plain text body
+static PLAIN_URL: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?i)\bhttps?://[^\s<>"')\]]+"#).unwrap());
+
+/// An address written into a display name, as in `"support@bank.com" <x@elsewhere.net>`
+static NAME_ADDRESS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)[a-z0-9._%+-]+@([a-z0-9-]+(?:\.[a-z0-9-]+)+)").unwrap());
+
+/// Link text that reads as a web address or bare domain
+static DOMAIN_TEXT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)^(?:https?://)?((?:[a-z0-9-]+\.)+[a-z]{2,})(?:[/:?#]\S*)?$").unwrap()
+});
+
+static TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>").unwrap());
+
+/// Link shorteners, which hide where a link goes
+const SHORTENERS: [&str; 10] = [
+    "bit.ly",
+    "tinyurl.com",
+    "t.co",
+    "goo.gl",
+    "ow.ly",
+    "is.gd",
+    "buff.ly",
+    "rebrand.ly",
+    "cutt.ly",
+    "shorturl.at",
+];
+
+/// Scores from which a message is a medium and a high risk
+const MEDIUM_RISK: u32 = 25;
+const HIGH_RISK: u32 = 50;
+
+/// Something that makes a message look like phishing or spam, and how much it adds to the score
+#[derive(Debug, PartialEq, Serialize)]
+struct Reason {
+    check: &'static str,
+    points: u32,
+    detail: String,
+}
+
+/// A message's risk assessment
+#[derive(Debug, Serialize)]
+struct Assessment {
+    score: u32,
+    risk: &'static str,
+    /// The SPF, DKIM and DMARC results the receiving server recorded
+    authentication: BTreeMap<&'static str, Option<String>>,
+    links_checked: usize,
+    reasons: Vec<Reason>,
+}
+
+/// The address in a `Name <address>` or bare address header value, lowercased
+fn header_address(value: &str) -> Option<String> {
+    let address = match value.rsplit_once('<') {
+        Some((_, address)) => address.split('>').next().unwrap_or_default(),
+        None => value,
+    };
+    let address = address.trim().to_lowercase();
+    address.contains('@').then_some(address)
+}
+
+/// The domain of an address
+fn address_domain(address: &str) -> &str {
+    address.rsplit_once('@').map_or("", |(_, domain)| domain)
+}
+
+/// Whether two domains belong to the same site, one being the other or a subdomain of it
+fn same_site(a: &str, b: &str) -> bool {
+    let (a, b) = (a.trim_end_matches('.'), b.trim_end_matches('.'));
+    a.eq_ignore_ascii_case(b)
+        || a.to_lowercase()
+            .ends_with(&format!(".{}", b.to_lowercase()))
+        || b.to_lowercase()
+            .ends_with(&format!(".{}", a.to_lowercase()))
+}
+
+/// SPF, DKIM and DMARC results from the topmost Authentication-Results header, the one Gmail
+/// added on receipt; lower ones came with the message and could be forged. DKIM passes when any
+/// of the message's signatures did
+fn authentication_results(payload: &Value) -> Option<BTreeMap<&'static str, Option<String>>> {
+    let header = part_header(payload, "Authentication-Results")?;
+    let mut results: BTreeMap<&'static str, Option<String>> =
+        [("spf", None), ("dkim", None), ("dmarc", None)].into();
+    for captures in AUTH_RESULT.captures_iter(header) {
+        let method = match captures[1].to_lowercase().as_str() {
+            "spf" => "spf",
+            "dkim" => "dkim",
+            _ => "dmarc",
+        };
+        let result = captures[2].to_lowercase();
+        let entry = results.entry(method).or_default();
+        if entry.is_none() || result == "pass" {
+            *entry = Some(result);
+        }
+    }
+    Some(results)
+}
+
+/// Points for a failed or missing authentication result
+fn authentication_points(method: &str, result: Option<&str>) -> Option<(u32, String)> {
+    let points = match (method, result) {
+        (_, Some("pass")) => return None,
+        ("dmarc", Some("fail")) => 30,
+        ("spf", Some("fail")) | ("dkim", Some("fail")) => 20,
+        ("spf", Some("softfail")) => 10,
+        // Missing, neutral or errored results only say the sender didn't set it up
+        _ => 5,
+    };
+    let detail = match result {
+        Some(result) => format!("{} result is {result}", method.to_uppercase()),
+        None => format!("No {} result", method.to_uppercase()),
+    };
+    Some((points, detail))
+}
+
+/// The links in a message's bodies, as their targets and, for HTML links, their text
+fn links(payload: &Value) -> Vec<(String, Option<String>)> {
+    let mut leaves = Vec::new();
+    leaf_parts(payload, &mut leaves);
+    let mut links = Vec::new();
+    for part in leaves {
+        if !part["filename"].as_str().unwrap_or_default().is_empty() {
+            continue;
+        }
+        let Some(body) = part["body"]["data"]
+            .as_str()
+            .and_then(|data| decode_email_content(data).ok())
+        else {
+            continue;
+        };
+        match part["mimeType"].as_str() {
+            Some("text/html") => {
+                for captures in ANCHOR.captures_iter(&body) {
+                    let href = captures
+                        .get(1)
+                        .or(captures.get(2))
+                        .or(captures.get(3))
+                        .map_or("", |href| href.as_str());
+                    let text = TAG.replace_all(&captures[4], "");
+                    links.push((
+                        decode_entities(href.trim()).into_owned(),
+                        Some(decode_entities(text.trim()).into_owned()),
+                    ));
+                }
+            }
+            Some("text/plain") => {
+                for url in PLAIN_URL.find_iter(&body) {
+                    let url = url
+                        .as_str()
+                        .trim_end_matches(['.', ',', ';', ':', '!', '?']);
+                    links.push((url.to_string(), None));
+                }
+            }
+            _ => {}
+        }
+    }
+    links
+}
+
+/// Reasons a link looks like it leads somewhere other than it claims
+fn link_reasons(href: &str, text: Option<&str>) -> Vec<Reason> {
+    let mut reasons = Vec::new();
+    let Ok(url) = Url::parse(href) else {
+        return reasons;
+    };
+    if !matches!(url.scheme(), "http" | "https") {
+        return reasons;
+    }
+    let Some(host) = url.host() else {
+        return reasons;
+    };
+    match &host {
+        Host::Ipv4(_) | Host::Ipv6(_) => reasons.push(Reason {
+            check: "ip_address_link",
+            points: 20,
+            detail: format!("Link goes to the IP address {host}"),
+        }),
+        Host::Domain(domain) => {
+            if domain.split('.').any(|label| label.starts_with("xn--")) {
+                let (unicode, _) = idna::domain_to_unicode(domain);
+                reasons.push(Reason {
+                    check: "punycode_link",
+                    points: 15,
+                    detail: format!(
+                        "Link goes to {unicode} ({domain}), which may imitate another domain"
+                    ),
+                });
+            }
+            if SHORTENERS.contains(&domain.to_lowercase().as_str()) {
+                reasons.push(Reason {
+                    check: "shortened_link",
+                    points: 5,
+                    detail: format!("Link is shortened with {domain}, hiding where it goes"),
+                });
+            }
+            let shown = text
+                .and_then(|text| DOMAIN_TEXT.captures(text))
+                .map(|captures| captures[1].to_string());
+            if let Some(shown) = shown.filter(|shown| !same_site(shown, domain)) {
+                reasons.push(Reason {
+                    check: "mismatched_link",
+                    points: 25,
+                    detail: format!("Link text shows {shown} but the link goes to {domain}"),
+                });
+            }
+        }
+    }
+    if !url.username().is_empty() {
+        reasons.push(Reason {
+            check: "credentials_in_link",
+            points: 20,
+            detail: format!(
+                "Link starts with '{}@', hiding its real host {host}",
+                url.username()
+            ),
+        });
+    }
+    reasons
+}
+
+/// Score a full-format message on its authentication results, its sender and reply-to addresses
+/// and its links, from 0 (nothing suspicious) to 100
+fn assess(message: &Value) -> Assessment {
+    let payload = &message["payload"];
+    let mut reasons = Vec::new();
+
+    let authentication = authentication_results(payload);
+    match &authentication {
+        Some(results) => {
+            for (method, result) in results {
+                if let Some((points, detail)) = authentication_points(method, result.as_deref()) {
+                    reasons.push(Reason {
+                        check: method,
+                        points,
+                        detail,
+                    });
+                }
+            }
+        }
+        None => reasons.push(Reason {
+            check: "authentication",
+            points: 10,
+            detail: "No Authentication-Results header".into(),
+        }),
+    }
+
+    let from = part_header(payload, "From").unwrap_or_default();
+    let from_address = header_address(from);
+    let from_domain = from_address.as_deref().map(address_domain);
+    if let Some(from_domain) = from_domain {
+        let reply_to = part_header(payload, "Reply-To").and_then(header_address);
+        if let Some(reply_to) =
+            reply_to.filter(|reply_to| !same_site(address_domain(reply_to), from_domain))
+        {
+            reasons.push(Reason {
+                check: "reply_to_mismatch",
+                points: 20,
+                detail: format!("Replies go to {reply_to}, not the sender's domain {from_domain}"),
+            });
+        }
+        let display_name = from.rsplit_once('<').map_or("", |(name, _)| name);
+        if let Some(captures) = NAME_ADDRESS.captures(display_name) {
+            let shown = &captures[1];
+            if !same_site(shown, from_domain) {
+                reasons.push(Reason {
+                    check: "display_name_spoof",
+                    points: 25,
+                    detail: format!(
+                        "Sender name shows an address at {shown} but the mail is from {from_domain}"
+                    ),
+                });
+            }
+        }
+    }
+
+    let links = links(payload);
+    let mut link_findings: Vec<Reason> = Vec::new();
+    for (href, text) in &links {
+        for reason in link_reasons(href, text.as_deref()) {
+            // A link repeated in the text and HTML bodies counts once
+            if !link_findings.contains(&reason) {
+                link_findings.push(reason);
+            }
+        }
+    }
+    reasons.extend(link_findings);
+
+    let score = reasons
+        .iter()
+        .map(|reason| reason.points)
+        .sum::<u32>()
+        .min(100);
+    let risk = match score {
+        score if score >= HIGH_RISK => "high",
+        score if score >= MEDIUM_RISK => "medium",
+        _ => "low",
+    };
+    Assessment {
+        score,
+        risk,
+        authentication: authentication.unwrap_or_default(),
+        links_checked: links.len(),
+        reasons,
+    }
+}
+
+/// Score how likely a message is phishing or spam from its SPF, DKIM and DMARC results, a
+/// reply-to or display name pointing away from the sender, and deceptive links
+#[instrument(name = "tool", skip_all, fields(tool = "score_phishing_risk", message_id = %message_id))]
+pub async fn score_phishing_risk(gmail_server: &GmailServer, message_id: &str) -> Result<Value> {
+    gmail_server.check_authentication(&[SCOPE_READONLY]).await?;
+
+    let client = gmail_server.authenticated_client().await?;
+    let mut message = client
+        .get_json(&message_url(
+            gmail_server.user_id(),
+            message_id,
+            MessageFormat::Full,
+            None,
+        ))
+        .await
+        .context("Failed to get message")?;
+    decrypt_message(gmail_server, &client, message_id, &mut message).await;
+
+    let payload = &message["payload"];
+    let mut result = json!({
+        "message_id": message_id,
+        "thread_id": message["threadId"],
+        "from": part_header(payload, "From"),
+        "subject": part_header(payload, "Subject"),
+    });
+    if let Some(reply_to) = part_header(payload, "Reply-To") {
+        result["reply_to"] = json!(reply_to);
+    }
+    let assessment = serde_json::to_value(assess(&message))?;
+    if let (Some(result), Value::Object(assessment)) = (result.as_object_mut(), assessment) {
+        result.extend(assessment);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockGmail;
+    use base64::{engine::general_purpose::URL_SAFE, Engine};
+
+    fn message(headers: &[(&str, &str)], html: &str) -> Value {
+        let headers: Vec<Value> = headers
+            .iter()
+            .map(|(name, value)| json!({ "name": name, "value": value }))
+            .collect();
+        json!({
+            "payload": {
+                "mimeType": "text/html",
+                "filename": "",
+                "headers": headers,
+                "body": { "data": URL_SAFE.encode(html) },
+            },
+        })
+    }
+
+    #[test]
+    fn test_assess_phishing_message() {
+        let message = message(
+            &[
+                ("Authentication-Results", "mx.google.com; dkim=fail header.i=@paypa1.com; spf=softfail smtp.mailfrom=paypa1.com; dmarc=fail (p=REJECT) header.from=paypa1.com"),
+                ("Authentication-Results", "forged.example; spf=pass; dkim=pass; dmarc=pass"),
+                ("From", "\"service@paypal.com\" <alerts@paypa1.com>"),
+                ("Reply-To", "help@mailbox.example.net"),
+            ],
+            "<p>Verify at <a href=\"https://paypal.com.account-check.ru/login\">www.paypal.com</a>, \
+             <a href='http://192.0.2.7/x'>here</a> or <a href=\"https://xn--pypal-4ve.com/\">PayPal</a>.</p>",
+        );
+        let assessment = assess(&message);
+        let checks: Vec<&str> = assessment
+            .reasons
+            .iter()
+            .map(|reason| reason.check)
+            .collect();
+        assert_eq!(
+            checks,
+            [
+                "dkim",
+                "dmarc",
+                "spf",
+                "reply_to_mismatch",
+                "display_name_spoof",
+                "mismatched_link",
+                "ip_address_link",
+                "punycode_link",
+            ]
+        );
+        assert_eq!(assessment.authentication["dmarc"].as_deref(), Some("fail"));
+        assert_eq!(assessment.links_checked, 3);
+        assert_eq!(assessment.score, 100);
+        assert_eq!(assessment.risk, "high");
+        assert_eq!(
+            assessment.reasons[5].detail,
+            "Link text shows www.paypal.com but the link goes to paypal.com.account-check.ru"
+        );
+    }
+
+    #[test]
+    fn test_assess_legitimate_message() {
+        let message = message(
+            &[
+                ("Authentication-Results", "mx.google.com; dkim=fail header.i=@old.example.com; dkim=pass header.i=@example.com; spf=pass; dmarc=pass"),
+                ("From", "Example <news@example.com>"),
+                ("Reply-To", "support@help.example.com"),
+            ],
+            "<a href=\"https://click.example.com/r?u=1\">example.com</a> \
+             <a href=\"https://example.com/unsubscribe\">Unsubscribe</a>",
+        );
+        let assessment = assess(&message);
+        assert_eq!(assessment.reasons, []);
+        assert_eq!(assessment.score, 0);
+        assert_eq!(assessment.risk, "low");
+        assert_eq!(assessment.authentication["dkim"].as_deref(), Some("pass"));
+    }
+
+    #[test]
+    fn test_link_reasons() {
+        let checks = |href: &str, text: Option<&str>| {
+            link_reasons(href, text)
+                .iter()
+                .map(|reason| reason.check)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(checks("https://bit.ly/3abc", None), ["shortened_link"]);
+        assert_eq!(
+            checks("https://bank.com@evil.example/", Some("Log in")),
+            ["credentials_in_link"]
+        );
+        assert_eq!(
+            checks("https://docs.example.org/a", Some("https://example.org/a")),
+            Vec::<&str>::new()
+        );
+        assert!(checks("mailto:someone@example.com", Some("example.net")).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_score_phishing_risk_against_mock_gmail() {
+        let gmail = MockGmail::start().await;
+        let gmail_server = gmail.gmail_server(&gmail.config()).await;
+
+        let result = score_phishing_risk(&gmail_server, "msg-7").await.unwrap();
+        assert_eq!(result["from"], "Erin <erin@example.com>");
+        assert_eq!(result["score"], 10);
+        assert_eq!(result["risk"], "low");
+        assert_eq!(result["reasons"][0]["check"], "authentication");
+        assert_eq!(result["links_checked"], 5);
+
+        assert!(score_phishing_risk(&gmail_server, "missing").await.is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::{Context, Result};
+use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+use std::io::Cursor;
+
+use crate::extract::{extract_text_from_bytes, is_extractable_document};
+use crate::tools::Attachment;
+
+/// Largest width or height of an image decoded for a thumbnail, so a small file claiming huge
+/// dimensions can't exhaust memory
+const MAX_IMAGE_DIMENSION: u32 = 16_384;
+
+/// What an attachment looks like, small enough for a dashboard to show inline
+pub enum Preview {
+    /// The start of the extracted text, with the length of all of it in characters
+    Text { text: String, total_chars: usize },
+    /// PNG thumbnail
+    Image(Vec<u8>),
+}
+
+/// Preview an attachment: the first `max_chars` of a document's text, or an image scaled to fit
+/// in a `size` pixel square. `None` for types with neither
+pub fn preview(
+    attachment: &Attachment,
+    filename: &str,
+    max_chars: usize,
+    size: u32,
+) -> Result<Option<Preview>> {
+    if let Some(format) = image_format(&attachment.mime_type, filename) {
+        return thumbnail(&attachment.data, format, size).map(|png| Some(Preview::Image(png)));
+    }
+    if !is_extractable_document(&attachment.mime_type, filename) {
+        return Ok(None);
+    }
+    let text = extract_text_from_bytes(&attachment.data, &attachment.mime_type, filename)
+        .context("Failed to extract text from attachment")?;
+    let total_chars = text.chars().count();
+    Ok(Some(Preview::Text {
+        text: text.chars().take(max_chars).collect(),
+        total_chars,
+    }))
+}
+
+/// The image format of the attachment, by its content type or else its extension
+pub fn image_format(mime_type: &str, filename: &str) -> Option<ImageFormat> {
+    let format =
+        ImageFormat::from_mime_type(mime_type).or_else(|| ImageFormat::from_path(filename).ok())?;
+    format.reading_enabled().then_some(format)
+}
+
+/// Decode an image attachment, refusing dimensions over `MAX_IMAGE_DIMENSION`
+pub fn decode_image(data: &[u8], format: ImageFormat) -> Result<DynamicImage> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    reader.limits(limits);
+    reader.decode().context("Failed to decode image attachment")
+}
+
+fn thumbnail(data: &[u8], format: ImageFormat, size: u32) -> Result<Vec<u8>> {
+    let image = decode_image(data, format)?;
+
+    let mut png = Vec::new();
+    image
+        .thumbnail(size, size)
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .context("Failed to encode thumbnail")?;
+    Ok(png)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attachment(mime_type: &str, data: Vec<u8>) -> Attachment {
+        Attachment {
+            mime_type: mime_type.to_string(),
+            data,
+            scan: None,
+        }
+    }
+
+    #[test]
+    fn test_text_preview() {
+        let notes = attachment("text/plain", "héllo world".as_bytes().to_vec());
+        let Some(Preview::Text { text, total_chars }) =
+            preview(&notes, "notes.txt", 5, 256).unwrap()
+        else {
+            panic!("expected a text preview");
+        };
+        assert_eq!(text, "héllo");
+        assert_eq!(total_chars, 11);
+
+        let archive = attachment("application/zip", vec![0; 4]);
+        assert!(preview(&archive, "files.zip", 5, 256).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_image_preview() {
+        let mut png = Vec::new();
+        image::RgbImage::new(400, 200)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        // Recognized by extension when the content type is generic
+        let photo = attachment("application/octet-stream", png);
+        let Some(Preview::Image(thumbnail)) = preview(&photo, "photo.png", 5, 100).unwrap() else {
+            panic!("expected an image preview");
+        };
+        let thumbnail = image::load_from_memory(&thumbnail).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (100, 50));
+
+        let broken = attachment("image/jpeg", vec![0; 16]);
+        assert!(preview(&broken, "photo.jpg", 5, 100).is_err());
+    }
+}
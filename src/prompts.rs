@@ -0,0 +1,91 @@
+use anyhow::Result;
+use rmcp::model::{GetPromptResult, Prompt, PromptArgument, PromptMessage, PromptMessageRole};
+use tracing::instrument;
+
+use crate::gmail::GmailServer;
+use crate::markdown::thread_to_markdown;
+
+/// Prompt summarizing a thread the server fetches itself
+pub const SUMMARIZE_THREAD: &str = "summarize_thread";
+
+/// What a thread summary should cover
+const SUMMARIZE_INSTRUCTIONS: &str = "Summarize the email thread below. Say who is involved and \
+    what the thread is about, then what was decided and which questions are still open. List any \
+    action items with who owns them and when they're due. Keep it short, and quote the messages \
+    only where the exact wording matters.";
+
+/// The prompts served, with their arguments
+pub fn prompts() -> Vec<Prompt> {
+    vec![Prompt::new(
+        SUMMARIZE_THREAD,
+        Some(
+            "Summarize an email thread. The server fetches the thread and puts every message in \
+             the prompt, so no tool calls are needed",
+        ),
+        Some(vec![
+            argument("thread_id", "ID of the thread to summarize", true),
+            argument(
+                "account",
+                "Account to use, from the server's configured accounts (default: the server's \
+                 default account)",
+                false,
+            ),
+        ]),
+    )]
+}
+
+fn argument(name: &str, description: &str, required: bool) -> PromptArgument {
+    PromptArgument {
+        name: name.to_string(),
+        title: None,
+        description: Some(description.to_string()),
+        required: Some(required),
+    }
+}
+
+/// A prompt asking for a summary of the thread, with its messages as markdown
+#[instrument(name = "prompt", skip_all, fields(prompt = SUMMARIZE_THREAD, thread_id = %thread_id))]
+pub async fn summarize_thread(
+    gmail_server: &GmailServer,
+    thread_id: &str,
+) -> Result<GetPromptResult> {
+    let thread = thread_to_markdown(gmail_server, thread_id).await?;
+    let mut text = format!(
+        "{SUMMARIZE_INSTRUCTIONS}\n\nThread {thread_id}, {} messages, oldest first:\n\n{}",
+        thread["messages"],
+        thread["markdown"].as_str().unwrap_or_default()
+    );
+    if thread["markdown_truncated"] == true {
+        text.push_str("\n\n_The rest of the thread was cut to fit the server's size limit._\n");
+    }
+    Ok(GetPromptResult {
+        description: Some(format!("Summary of email thread {thread_id}")),
+        messages: vec![PromptMessage::new_text(PromptMessageRole::User, text)],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockGmail;
+    use rmcp::model::PromptMessageContent;
+
+    #[tokio::test]
+    async fn test_summarize_thread() {
+        let gmail = MockGmail::start().await;
+        let gmail_server = gmail.gmail_server(&gmail.config()).await;
+
+        let prompt = summarize_thread(&gmail_server, "thread-1").await.unwrap();
+        assert_eq!(prompt.messages.len(), 1);
+        assert_eq!(prompt.messages[0].role, PromptMessageRole::User);
+        let PromptMessageContent::Text { text } = &prompt.messages[0].content else {
+            panic!("expected a text message");
+        };
+        assert!(text.starts_with(SUMMARIZE_INSTRUCTIONS));
+        assert!(text.contains("Thread thread-1, 2 messages, oldest first:"));
+        assert!(text.contains("# Quarterly report\n\nNotes from today's meeting are attached."));
+        assert!(text.contains("Sounds good, see you **Friday**."));
+
+        assert!(summarize_thread(&gmail_server, "missing").await.is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+use crate::config::{RateLimitConfig, RateLimitKey};
+
+/// Token buckets per client, refilled at the configured rate up to the burst size
+pub struct RateLimiter {
+    limits: RwLock<Limits>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+#[derive(Clone, Copy)]
+struct Limits {
+    /// Requests a client regains per second, 0 when rate limiting is off
+    rate: f64,
+    burst: f64,
+    key: RateLimitKey,
+}
+
+impl Limits {
+    fn new(config: &RateLimitConfig) -> Self {
+        let burst = config
+            .rate_limit_burst
+            .unwrap_or(config.rate_limit_per_minute)
+            .max(1);
+        Self {
+            rate: f64::from(config.rate_limit_per_minute) / 60.0,
+            burst: f64::from(burst),
+            key: config.rate_limit_by,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// A limiter for `--rate-limit-per-minute`, letting every request through while it is 0
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            limits: RwLock::new(Limits::new(config)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Apply changed limits; clients keep the requests left in their buckets, up to the new burst
+    pub fn update(&self, config: &RateLimitConfig) {
+        *self.limits.write().unwrap() = Limits::new(config);
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.limits().rate > 0.0
+    }
+
+    /// What clients are told apart by
+    pub fn key(&self) -> RateLimitKey {
+        self.limits().key
+    }
+
+    fn limits(&self) -> Limits {
+        *self.limits.read().unwrap()
+    }
+
+    /// Spend one request from the client's bucket, or return how long until one is available
+    pub async fn check(&self, client: &str) -> Result<(), Duration> {
+        let limits = self.limits();
+        if limits.rate == 0.0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().await;
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: limits.burst,
+            updated: now,
+        });
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.updated).as_secs_f64() * limits.rate)
+            .min(limits.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / limits.rate))
+        }
+    }
+
+    /// Forget clients whose buckets have refilled, returning how many were removed
+    pub async fn remove_idle(&self) -> usize {
+        let limits = self.limits();
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().await;
+        let before = buckets.len();
+        if limits.rate == 0.0 {
+            buckets.clear();
+        }
+        buckets.retain(|_, bucket| {
+            bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * limits.rate
+                < limits.burst
+        });
+        before - buckets.len()
+    }
+}
+
+/// Periodically forget idle clients until cancelled
+pub fn spawn_rate_limit_cleanup(
+    rate_limiter: Arc<RateLimiter>,
+    ct: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            tokio::select! {
+                _ = ct.cancelled() => break,
+                _ = ticker.tick() => {}
+            }
+            let removed = rate_limiter.remove_idle().await;
+            if removed > 0 {
+                debug!("Forgot {} idle rate-limited client(s)", removed);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(per_minute: u32, burst: u32) -> RateLimitConfig {
+        RateLimitConfig {
+            rate_limit_per_minute: per_minute,
+            rate_limit_burst: Some(burst),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_burst_then_limited() {
+        let rate_limiter = RateLimiter::new(&config(60, 2));
+        assert!(rate_limiter.check("a").await.is_ok());
+        assert!(rate_limiter.check("a").await.is_ok());
+        let retry_after = rate_limiter.check("a").await.unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+        // Clients have separate buckets
+        assert!(rate_limiter.check("b").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_remove_idle() {
+        let rate_limiter = RateLimiter::new(&config(60, 1));
+        rate_limiter.check("a").await.unwrap();
+        assert_eq!(rate_limiter.remove_idle().await, 0);
+        let mut buckets = rate_limiter.buckets.lock().await;
+        buckets.get_mut("a").unwrap().updated -= Duration::from_secs(2);
+        drop(buckets);
+        assert_eq!(rate_limiter.remove_idle().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_update() {
+        let rate_limiter = RateLimiter::new(&RateLimitConfig::default());
+        assert!(!rate_limiter.enabled());
+        assert!(rate_limiter.check("a").await.is_ok());
+
+        // The burst defaults to the per-minute limit
+        rate_limiter.update(&RateLimitConfig {
+            rate_limit_per_minute: 30,
+            rate_limit_by: RateLimitKey::ApiKey,
+            ..Default::default()
+        });
+        assert!(rate_limiter.enabled());
+        assert_eq!(rate_limiter.key(), RateLimitKey::ApiKey);
+        assert_eq!(rate_limiter.limits().rate, 0.5);
+        assert_eq!(rate_limiter.limits().burst, 30.0);
+
+        rate_limiter.update(&config(60, 1));
+        assert!(rate_limiter.check("a").await.is_ok());
+        assert!(rate_limiter.check("a").await.is_err());
+
+        // Turning rate limiting off lets the client straight back in
+        rate_limiter.update(&RateLimitConfig::default());
+        assert!(rate_limiter.check("a").await.is_ok());
+        assert_eq!(rate_limiter.remove_idle().await, 1);
+    }
+}
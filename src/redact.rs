@@ -0,0 +1,250 @@
+use axum::http::HeaderMap;
+use regex::Regex;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::LazyLock;
+
+const REDACTED: &str = "[REDACTED]";
+const REDACTED_EMAIL: &str = "[EMAIL]";
+
+/// JSON keys and URL parameters holding credentials or message content
+const SENSITIVE_KEYS: [&str; 10] = [
+    "access_token",
+    "refresh_token",
+    "id_token",
+    "client_secret",
+    "code",
+    "state",
+    "token",
+    "password",
+    "body",
+    "raw",
+];
+
+/// Headers whose values are credentials
+const SENSITIVE_HEADERS: [&str; 5] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+];
+
+/// Set once from --log-unredacted when logging is initialized
+static UNREDACTED: AtomicBool = AtomicBool::new(false);
+
+/// Email addresses, including percent-encoded ones in query strings and internationalized ones
+/// with UTF-8 local parts or domains, in Unicode or punycode
+static EMAIL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)[\p{L}\p{N}._%+-]+(?:@|%40)[\p{L}\p{N}%-]+(?:\.[\p{L}\p{N}%-]+)*\.(?:\p{L}{2,}|xn--[a-z0-9-]+)",
+    )
+    .unwrap()
+});
+
+/// `key=value` pairs of sensitive URL or form parameters
+static SENSITIVE_PARAM: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(&format!(r"(?i)\b({})=[^&\s]*", SENSITIVE_KEYS.join("|"))).unwrap()
+});
+
+/// Credentials in Authorization-style values
+static AUTH_SCHEME: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\b(bearer|basic)\s+[a-z0-9._~+/=-]+").unwrap());
+
+/// Turn redaction off (or back on) for every subsequent log line
+pub fn set_unredacted(unredacted: bool) {
+    UNREDACTED.store(unredacted, Ordering::Relaxed);
+}
+
+fn is_unredacted() -> bool {
+    UNREDACTED.load(Ordering::Relaxed)
+}
+
+/// Mask email addresses, credentials and message content in text bound for the logs.
+///
+/// JSON documents have sensitive fields replaced wholesale; anything else is treated as
+/// free text, URL or form data.
+pub fn redact(text: &str) -> Cow<'_, str> {
+    if is_unredacted() {
+        return Cow::Borrowed(text);
+    }
+    match serde_json::from_str::<Value>(text) {
+        Ok(json @ (Value::Object(_) | Value::Array(_))) => {
+            Cow::Owned(redact_json(json).to_string())
+        }
+        _ => Cow::Owned(redact_text(text)),
+    }
+}
+
+/// Replace each email address with a stand-in at example.com, the same one wherever the address
+/// appears, so text keeps its shape and who-wrote-to-whom without naming anyone. Unlike
+/// [`redact`], this applies even with --log-unredacted
+pub fn pseudonymize_emails(text: &str) -> Cow<'_, str> {
+    EMAIL.replace_all(text, |captures: &regex::Captures| {
+        let address = captures[0].to_ascii_lowercase().replace("%40", "@");
+        let hash = address
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            });
+        format!("user-{:08x}@example.com", hash as u32)
+    })
+}
+
+/// Headers formatted for logging with credential values masked
+pub fn headers(headers: &HeaderMap) -> RedactedHeaders<'_> {
+    RedactedHeaders(headers)
+}
+
+pub struct RedactedHeaders<'a>(&'a HeaderMap);
+
+impl fmt::Debug for RedactedHeaders<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if is_unredacted() {
+            return fmt::Debug::fmt(self.0, f);
+        }
+        f.debug_map()
+            .entries(self.0.iter().map(|(name, value)| {
+                let value = if SENSITIVE_HEADERS.contains(&name.as_str()) {
+                    REDACTED.to_string()
+                } else {
+                    redact_text(&String::from_utf8_lossy(value.as_bytes()))
+                };
+                (name.as_str(), value)
+            }))
+            .finish()
+    }
+}
+
+fn redact_text(text: &str) -> String {
+    let text = SENSITIVE_PARAM.replace_all(text, format!("${{1}}={REDACTED}"));
+    let text = AUTH_SCHEME.replace_all(&text, format!("${{1}} {REDACTED}"));
+    EMAIL.replace_all(&text, REDACTED_EMAIL).into_owned()
+}
+
+fn redact_json(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| {
+                    let value = if value.is_null()
+                        || !SENSITIVE_KEYS.contains(&key.to_ascii_lowercase().as_str())
+                    {
+                        redact_json(value)
+                    } else {
+                        Value::String(REDACTED.to_string())
+                    };
+                    (key, value)
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(redact_json).collect()),
+        Value::String(text) => Value::String(redact_text(&text)),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use serde_json::json;
+
+    #[test]
+    fn test_redact_masks_query_parameters_and_emails() {
+        assert_eq!(
+            redact("/auth/callback?code=4/0AbCd&state=xyz&scope=gmail"),
+            "/auth/callback?code=[REDACTED]&state=[REDACTED]&scope=gmail"
+        );
+        assert_eq!(
+            redact("/tools/create_draft?to=alice%40example.com&subject=Hi&body=Secret%20plans"),
+            "/tools/create_draft?to=[EMAIL]&subject=Hi&body=[REDACTED]"
+        );
+        assert_eq!(
+            redact("From: Bob <bob.smith@mail.example.co.uk>"),
+            "From: Bob <[EMAIL]>"
+        );
+        assert_eq!(
+            redact("To: jörg@münchen.de, 用户@例子.广告, jörg@xn--mnchen-3ya.de"),
+            "To: [EMAIL], [EMAIL], [EMAIL]"
+        );
+    }
+
+    #[test]
+    fn test_redact_masks_json_fields() {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "method": "tools/call",
+            "params": {
+                "name": "forward_email",
+                "arguments": {
+                    "message_id": "m1",
+                    "to": "carol@example.com",
+                    "body": "Please see below",
+                    "thread_id": null
+                }
+            }
+        });
+        let redacted: Value = serde_json::from_str(&redact(&body.to_string())).unwrap();
+        assert_eq!(
+            redacted["params"]["arguments"],
+            json!({
+                "message_id": "m1",
+                "to": "[EMAIL]",
+                "body": "[REDACTED]",
+                "thread_id": null
+            })
+        );
+        assert_eq!(redacted["method"], "tools/call");
+
+        let token: Value = serde_json::from_str(&redact(
+            r#"{"access_token":"ya29.abc","refresh_token":"1//xyz","expires_in":3599}"#,
+        ))
+        .unwrap();
+        assert_eq!(
+            token,
+            json!({"access_token": "[REDACTED]", "refresh_token": "[REDACTED]", "expires_in": 3599})
+        );
+    }
+
+    #[test]
+    fn test_headers_mask_credentials() {
+        let mut map = HeaderMap::new();
+        map.insert("authorization", HeaderValue::from_static("Bearer ya29.abc"));
+        map.insert("cookie", HeaderValue::from_static("session=abc"));
+        map.insert(
+            "x-forwarded-user",
+            HeaderValue::from_static("dave@example.com"),
+        );
+        map.insert("accept", HeaderValue::from_static("application/json"));
+        assert_eq!(
+            format!("{:?}", headers(&map)),
+            r#"{"authorization": "[REDACTED]", "cookie": "[REDACTED]", "x-forwarded-user": "[EMAIL]", "accept": "application/json"}"#
+        );
+    }
+
+    #[test]
+    fn test_pseudonymize_emails() {
+        let text =
+            pseudonymize_emails("From: Bob <Bob@mail.example.co.uk>, to bob@mail.example.co.uk");
+        let (from, to) = text.split_once(", to ").unwrap();
+        let stand_in = from
+            .strip_prefix("From: Bob <")
+            .unwrap()
+            .trim_end_matches('>');
+        assert!(stand_in.starts_with("user-") && stand_in.ends_with("@example.com"));
+        assert_eq!(to, stand_in);
+        assert_ne!(pseudonymize_emails("alice@example.com"), stand_in);
+        assert_eq!(pseudonymize_emails("no addresses"), "no addresses");
+    }
+
+    #[test]
+    fn test_redact_masks_auth_schemes_in_text() {
+        assert_eq!(
+            redact("upstream rejected Bearer ya29.a0AfB_byC-xyz"),
+            "upstream rejected Bearer [REDACTED]"
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+use crate::metrics::OAuthMetrics;
+use crate::oauth::{OAuthManager, OAuthToken};
+use crate::webhooks::{WebhookEvent, Webhooks};
+
+/// How long to wait before checking again when there is no refreshable token or a refresh failed
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Refresh the access token `lead` before it expires, until cancelled. The lead can change
+/// while the task runs.
+///
+/// Token metrics are only passed for the default account, which is the one they describe.
+/// Webhooks are sent an `auth_expired` event once the account's token expires without a refresh
+/// token or its refresh token is rejected, and again only for a later token.
+pub fn spawn_token_refresh(
+    account: String,
+    oauth_manager: Arc<OAuthManager>,
+    metrics: Option<Arc<OAuthMetrics>>,
+    webhooks: Option<Webhooks>,
+    mut lead: watch::Receiver<Duration>,
+    ct: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        // Access token of the last auth_expired event, which logins and refreshes replace
+        let mut expired_token: Option<String> = None;
+        let mut auth_expired = |token: &OAuthToken, reason: &str, error: Option<String>| {
+            if expired_token.as_ref() == Some(&token.access_token) {
+                return;
+            }
+            expired_token = Some(token.access_token.clone());
+            if let Some(webhooks) = &webhooks {
+                webhooks.send(
+                    WebhookEvent::AuthExpired,
+                    json!({ "account": account, "reason": reason, "error": error }),
+                );
+            }
+        };
+        loop {
+            // Recomputed every time, since logins, on-demand refreshes and config reloads
+            // change when the next refresh is due
+            let lead_time = *lead.borrow_and_update();
+            let token = oauth_manager.get_token().await;
+            if let Some(token) = token.as_ref().filter(|token| !token.is_usable()) {
+                auth_expired(token, "token_expired", None);
+            }
+            let delay = match token {
+                Some(token) if token.refresh_token.is_some() => {
+                    refresh_delay(&token, lead_time, now_secs())
+                }
+                _ => RETRY_INTERVAL,
+            };
+            tokio::select! {
+                _ = ct.cancelled() => break,
+                _ = tokio::time::sleep(delay) => {}
+                Ok(()) = lead.changed() => continue,
+            }
+
+            let due = oauth_manager.get_token().await.is_some_and(|token| {
+                token.refresh_token.is_some()
+                    && refresh_delay(&token, lead_time, now_secs()).is_zero()
+            });
+            if !due {
+                continue;
+            }
+
+            debug!("Refreshing access token ahead of expiry");
+            match oauth_manager.refresh_token().await {
+                Ok(token) => {
+                    if let Some(metrics) = &metrics {
+                        metrics.update_token_metrics(Some(&token));
+                    }
+                    info!(
+                        "🔄 Access token refreshed in the background, expires in {}s",
+                        token.expires_in
+                    );
+                }
+                // refresh_token() already logs, counts and alerts on the failure
+                Err(e) => {
+                    // A revoked or expired refresh token, which only a new login fixes
+                    if let Some(token) = oauth_manager
+                        .get_token()
+                        .await
+                        .filter(|_| format!("{e:#}").contains("invalid_grant"))
+                    {
+                        auth_expired(&token, "refresh_token_rejected", Some(format!("{e:#}")));
+                    }
+                    tokio::select! {
+                        _ = ct.cancelled() => break,
+                        _ = tokio::time::sleep(RETRY_INTERVAL) => {}
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Time until the token is within `lead` of expiring
+fn refresh_delay(token: &OAuthToken, lead: Duration, now: u64) -> Duration {
+    let refresh_at = token.expires_at().saturating_sub(lead.as_secs());
+    Duration::from_secs(refresh_at.saturating_sub(now))
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(created_at: u64, expires_in: u64) -> OAuthToken {
+        OAuthToken {
+            access_token: "access".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in,
+            refresh_token: Some("refresh".to_string()),
+            scope: String::new(),
+            created_at,
+        }
+    }
+
+    #[test]
+    fn test_refresh_delay_leads_expiry() {
+        let lead = Duration::from_secs(300);
+        assert_eq!(
+            refresh_delay(&token(1_000, 3_600), lead, 1_000),
+            Duration::from_secs(3_300)
+        );
+        assert_eq!(
+            refresh_delay(&token(1_000, 3_600), lead, 4_000),
+            Duration::from_secs(300)
+        );
+    }
+
+    #[test]
+    fn test_refresh_delay_is_zero_once_due_or_expired() {
+        let lead = Duration::from_secs(300);
+        assert!(refresh_delay(&token(1_000, 3_600), lead, 4_300).is_zero());
+        assert!(refresh_delay(&token(1_000, 3_600), lead, 10_000).is_zero());
+        assert!(refresh_delay(&token(0, 60), lead, 0).is_zero());
+    }
+}
@@ -0,0 +1,389 @@
+use anyhow::Result;
+use clap::{FromArgMatches, ValueEnum};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use toml::{Table, Value};
+use tracing::{debug, info, warn};
+
+use crate::config::{Cli, Commands, Config, HttpConfig, RateLimitConfig};
+use crate::config_file;
+use crate::rate_limit::RateLimiter;
+use crate::telemetry::LogFilter;
+
+/// Settings applied from the config file while the server runs; changing any other needs a restart
+const RELOADABLE: &[&str] = &[
+    "log-level",
+    "rate-limit-per-minute",
+    "rate-limit-burst",
+    "rate-limit-by",
+    "cache-sync-interval-secs",
+    "token-refresh-lead-secs",
+    "secret-refresh-interval-secs",
+];
+
+/// Settings compared by [`Settings`] that always wait for a restart, reported with the changes
+/// `apply` can't make rather than from the file
+const RESTART_ONLY: &[&str] = &["read-only"];
+
+/// The reloadable settings in effect
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    log_level: Option<String>,
+    rate_limit: RateLimitConfig,
+    cache_sync_interval_secs: u64,
+    token_refresh_lead_secs: u64,
+    secret_refresh_interval_secs: u64,
+    read_only: bool,
+}
+
+impl Settings {
+    pub fn new(config: &Config, http_config: &HttpConfig) -> Self {
+        Self {
+            log_level: config.telemetry.log_level.clone(),
+            rate_limit: http_config.rate_limit.clone(),
+            cache_sync_interval_secs: config.cache_sync_interval_secs,
+            token_refresh_lead_secs: config.token_refresh_lead_secs,
+            secret_refresh_interval_secs: config.secret_manager.secret_refresh_interval_secs,
+            read_only: config.read_only,
+        }
+    }
+}
+
+/// Where reloaded settings take effect
+pub struct Reloadable {
+    pub log_filter: LogFilter,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub cache_sync_interval: watch::Sender<Duration>,
+    pub token_refresh_lead: watch::Sender<Duration>,
+    pub secret_refresh_interval: watch::Sender<Duration>,
+}
+
+/// What a reload changed, and the settings whose changes wait for a restart
+#[derive(Debug, Default)]
+struct Changes {
+    applied: Vec<String>,
+    restart: Vec<String>,
+}
+
+impl Reloadable {
+    /// Apply each setting that differs, leaving `current` as what is now in effect
+    fn apply(&self, current: &mut Settings, new: &Settings) -> Changes {
+        let mut changes = Changes::default();
+
+        if new.log_level != current.log_level {
+            match self.log_filter.set(new.log_level.as_deref()) {
+                Ok(()) => {
+                    let level =
+                        |level: &Option<String>| level.as_deref().unwrap_or("default").to_string();
+                    changes.applied.push(change(
+                        "log-level",
+                        level(&current.log_level),
+                        level(&new.log_level),
+                    ));
+                    current.log_level = new.log_level.clone();
+                }
+                Err(e) => warn!("Keeping the current log level: {:#}", e),
+            }
+        }
+
+        if new.rate_limit != current.rate_limit {
+            self.rate_limiter.update(&new.rate_limit);
+            let (old, limits) = (&current.rate_limit, &new.rate_limit);
+            if old.rate_limit_per_minute != limits.rate_limit_per_minute {
+                changes.applied.push(change(
+                    "rate-limit-per-minute",
+                    old.rate_limit_per_minute,
+                    limits.rate_limit_per_minute,
+                ));
+            }
+            if old.rate_limit_burst != limits.rate_limit_burst {
+                let burst =
+                    |burst: Option<u32>| burst.map_or("default".to_string(), |b| b.to_string());
+                changes.applied.push(change(
+                    "rate-limit-burst",
+                    burst(old.rate_limit_burst),
+                    burst(limits.rate_limit_burst),
+                ));
+            }
+            if old.rate_limit_by != limits.rate_limit_by {
+                let by = |key: &crate::config::RateLimitKey| {
+                    key.to_possible_value()
+                        .map_or(String::new(), |value| value.get_name().to_string())
+                };
+                changes.applied.push(change(
+                    "rate-limit-by",
+                    by(&old.rate_limit_by),
+                    by(&limits.rate_limit_by),
+                ));
+            }
+            current.rate_limit = new.rate_limit.clone();
+        }
+
+        for (name, sender, current, new) in [
+            (
+                "cache-sync-interval-secs",
+                &self.cache_sync_interval,
+                &mut current.cache_sync_interval_secs,
+                new.cache_sync_interval_secs,
+            ),
+            (
+                "token-refresh-lead-secs",
+                &self.token_refresh_lead,
+                &mut current.token_refresh_lead_secs,
+                new.token_refresh_lead_secs,
+            ),
+            (
+                "secret-refresh-interval-secs",
+                &self.secret_refresh_interval,
+                &mut current.secret_refresh_interval_secs,
+                new.secret_refresh_interval_secs,
+            ),
+        ] {
+            if new == *current {
+                continue;
+            }
+            // 0 means the background task isn't running, so starting or stopping one waits
+            if new == 0 || *current == 0 {
+                changes.restart.push(name.to_string());
+                continue;
+            }
+            sender.send_replace(Duration::from_secs(new));
+            changes.applied.push(change(name, *current, new));
+            *current = new;
+        }
+
+        // The tool gate decides which tools are listed and which background tasks run at
+        // startup, so it isn't switched while the server runs
+        if new.read_only != current.read_only {
+            warn!(
+                "Read-only mode stays {} until a restart",
+                if current.read_only { "on" } else { "off" }
+            );
+            changes.restart.push("read-only".to_string());
+        }
+
+        changes
+    }
+}
+
+fn change(name: &str, old: impl Display, new: impl Display) -> String {
+    format!("{} {} → {}", name, old, new)
+}
+
+/// Check `--config-file` for changes every `interval` until cancelled, applying the reloadable
+/// settings and warning about the rest
+pub fn spawn_config_reload(
+    path: PathBuf,
+    reloadable: Reloadable,
+    mut settings: Settings,
+    interval: Duration,
+    ct: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let args: Vec<OsString> = std::env::args_os().collect();
+        let mut contents = tokio::fs::read_to_string(&path).await.unwrap_or_default();
+        let mut table = contents.parse::<Table>().unwrap_or_default();
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                _ = ct.cancelled() => break,
+                _ = ticker.tick() => {}
+            }
+            // A file being replaced can briefly be missing; it is read again next time
+            let Ok(new_contents) = tokio::fs::read_to_string(&path).await else {
+                continue;
+            };
+            if new_contents == contents {
+                continue;
+            }
+            contents = new_contents;
+            let (new_table, new_settings) = match parse(&contents, &args) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    warn!(
+                        "Ignoring changes to config file {}: {:#}",
+                        path.display(),
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            let mut changes = reloadable.apply(&mut settings, &new_settings);
+            changes.restart.extend(restart_settings(&table, &new_table));
+            table = new_table;
+            if !changes.applied.is_empty() {
+                info!("🔁 Reloaded config file: {}", changes.applied.join(", "));
+            }
+            if !changes.restart.is_empty() {
+                warn!(
+                    "Config file changes to {} take effect after a restart",
+                    changes.restart.join(", ")
+                );
+            }
+            if changes.applied.is_empty() && changes.restart.is_empty() {
+                debug!(
+                    "Config file {} changed without changing settings",
+                    path.display()
+                );
+            }
+        }
+    })
+}
+
+/// The file's settings and the reloadable ones in effect with it, with flags and environment
+/// variables still taking precedence
+fn parse(contents: &str, args: &[OsString]) -> Result<(Table, Settings)> {
+    let table =
+        config_file::with_profile(contents.parse()?, config_file::profile(args).as_deref())?;
+    let matches = config_file::command(&table)?
+        .try_get_matches_from(args)
+        // clap's messages end with usage hints meant for a terminal
+        .map_err(|e| {
+            let message = e.to_string();
+            let message = message.lines().next().unwrap_or_default();
+            anyhow::anyhow!("{}", message.trim_start_matches("error: "))
+        })?;
+    let cli = Cli::from_arg_matches(&matches)?;
+    let Commands::Http(http_config) = cli.command else {
+        anyhow::bail!("Only the http command reloads its config file");
+    };
+    Ok((table, Settings::new(&cli.config, &http_config)))
+}
+
+/// Settings that changed in the file but aren't reloadable
+fn restart_settings(old: &Table, new: &Table) -> Vec<String> {
+    let settings = |table: &Table| -> BTreeMap<String, Value> {
+        table
+            .iter()
+            .map(|(key, value)| (key.replace('_', "-"), value.clone()))
+            .collect()
+    };
+    let (old, new) = (settings(old), settings(new));
+    old.keys()
+        .chain(new.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|key| {
+            !RELOADABLE.contains(&key.as_str())
+                && !RESTART_ONLY.contains(&key.as_str())
+                && old.get(*key) != new.get(*key)
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RateLimitKey;
+
+    fn args() -> Vec<OsString> {
+        vec!["gmail-mcp-server".into(), "http".into()]
+    }
+
+    fn reloadable(settings: &Settings) -> Reloadable {
+        let interval = |secs| watch::Sender::new(Duration::from_secs(secs));
+        Reloadable {
+            log_filter: LogFilter::default(),
+            rate_limiter: Arc::new(RateLimiter::new(&settings.rate_limit)),
+            cache_sync_interval: interval(settings.cache_sync_interval_secs),
+            token_refresh_lead: interval(settings.token_refresh_lead_secs),
+            secret_refresh_interval: interval(settings.secret_refresh_interval_secs),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let (table, settings) = parse(
+            "port = 9000\nrate_limit_per_minute = 60\nlog-level = \"debug\"",
+            &args(),
+        )
+        .unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(settings.rate_limit.rate_limit_per_minute, 60);
+        assert_eq!(settings.log_level.as_deref(), Some("debug"));
+        assert_eq!(settings.cache_sync_interval_secs, 300);
+
+        // Flags still win over the file
+        let mut args_with_flag = args();
+        args_with_flag.extend(["--rate-limit-per-minute".into(), "10".into()]);
+        let (_, settings) = parse("rate_limit_per_minute = 60", &args_with_flag).unwrap();
+        assert_eq!(settings.rate_limit.rate_limit_per_minute, 10);
+
+        let error = parse("port = \"eighty\"", &args()).unwrap_err();
+        assert!(!error.to_string().contains("--help"));
+        assert!(parse("prot = 9000", &args()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_apply() {
+        let (_, mut current) = parse("", &args()).unwrap();
+        let reloadable = reloadable(&current);
+        let mut cache_sync_interval = reloadable.cache_sync_interval.subscribe();
+
+        let (_, new) = parse(
+            "rate_limit_per_minute = 60\nrate_limit_by = \"api-key\"\ncache_sync_interval_secs = 60\ntoken_refresh_lead_secs = 0",
+            &args(),
+        )
+        .unwrap();
+        let changes = reloadable.apply(&mut current, &new);
+        assert_eq!(
+            changes.applied,
+            [
+                "rate-limit-per-minute 0 → 60",
+                "rate-limit-by ip → api-key",
+                "cache-sync-interval-secs 300 → 60"
+            ]
+        );
+        assert!(reloadable.rate_limiter.enabled());
+        assert_eq!(reloadable.rate_limiter.key(), RateLimitKey::ApiKey);
+        assert!(cache_sync_interval.has_changed().unwrap());
+        assert_eq!(
+            *cache_sync_interval.borrow_and_update(),
+            Duration::from_secs(60)
+        );
+
+        // Stopping the token refresh task needs a restart, so the old lead stays in effect
+        assert_eq!(changes.restart, ["token-refresh-lead-secs"]);
+        assert_eq!(current.token_refresh_lead_secs, 300);
+
+        // Read-only mode is reported as waiting for a restart and stays as it was
+        let (_, new) = parse("read_only = true", &args()).unwrap();
+        let changes = reloadable.apply(&mut current, &new);
+        assert_eq!(changes.restart, ["read-only"]);
+        assert!(!current.read_only);
+
+        // A bad log level is left out
+        let (_, new) = parse("log_level = \"gmail=loud\"", &args()).unwrap();
+        let changes = reloadable.apply(&mut current, &new);
+        assert!(changes
+            .applied
+            .iter()
+            .all(|change| !change.starts_with("log-level")));
+        assert_eq!(current.log_level, None);
+    }
+
+    #[test]
+    fn test_restart_settings() {
+        let old: Table =
+            "port = 8080\nrate_limit_per_minute = 10\naudit_log = true\nread_only = false"
+                .parse()
+                .unwrap();
+        let new: Table = "port = 9000\nrate-limit-per-minute = 20\nmessage_cache = true"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            restart_settings(&old, &new),
+            ["audit-log", "message-cache", "port"]
+        );
+        assert!(restart_settings(&old, &old).is_empty());
+    }
+}
@@ -0,0 +1,698 @@
+use anyhow::{Context, Result};
+use rmcp::schemars;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, instrument, warn};
+
+use crate::accounts::Accounts;
+use crate::classifier::Classification;
+use crate::gmail::{GmailServer, GMAIL_API_BASE};
+use crate::metrics::{record_watcher_poll, record_watcher_started};
+use crate::oauth::{SCOPE_MODIFY, SCOPE_READONLY};
+use crate::saved_searches::expand_query;
+use crate::tools::{message_url, summarize_message, MessageFormat};
+use crate::watch_inbox::{list_messages, new_messages};
+
+/// Rules file in the app data directory, unless `--rules-file` names another
+pub const RULES_FILE: &str = "rules.toml";
+
+/// Newest matching messages compared on each poll of a rule's query
+const RULE_MAX_RESULTS: u32 = 100;
+
+/// Time allowed for a notify webhook to answer
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Name the rules watch reports its polls under on `/status` and in metrics
+const WATCHER: &str = "rules";
+
+/// Held while a tool loads, changes and saves the rules file, so concurrent changes don't
+/// overwrite each other
+static RULES_LOCK: Mutex<()> = Mutex::new(());
+
+/// Actions to take on new mail matching a Gmail search
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct Rule {
+    /// Unique name of the rule
+    pub name: String,
+    /// Gmail search query new messages must match (e.g., "from:billing@example.com has:attachment")
+    pub query: String,
+    /// Account whose mail the rule watches (default: the server's default account)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Actions to take on each new matching message, in order
+    pub actions: Vec<Action>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, schemars::JsonSchema)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    /// Apply a label, creating it if the mailbox has none by that name
+    Label { label: String },
+    /// Take the message out of the inbox
+    Archive,
+    /// Forward the message to comma-separated recipients
+    Forward { to: String },
+    /// POST the rule name and a summary of the message as JSON to a URL
+    Notify { url: String },
+    /// Apply the labels the classifier hook returns for the message
+    Classify,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RulesFile {
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+/// The rules in a TOML file, or a JSON one when its name ends in `.json`. A missing file has
+/// no rules
+pub fn load(path: &Path) -> Result<Vec<Rule>> {
+    let file: RulesFile = read_file(path)?;
+    validate(&file.rules).with_context(|| format!("Invalid rules file {}", path.display()))?;
+    Ok(file.rules)
+}
+
+/// A TOML file, or a JSON one when its name ends in `.json`, read as empty when missing
+pub fn read_file<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(T::default()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    if is_json(path) {
+        serde_json::from_str(&contents).map_err(anyhow::Error::from)
+    } else {
+        toml::from_str(&contents).map_err(anyhow::Error::from)
+    }
+    .with_context(|| format!("Invalid file {}", path.display()))
+}
+
+/// Write `contents` to a temporary file next to `path` and rename it into place, so readers
+/// never see a partly written file
+pub fn write_file(path: &Path, contents: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(path.file_name().context("File path has no file name")?);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = path.with_file_name(temp_name);
+    std::fs::write(&temp_path, contents)
+        .and_then(|()| std::fs::rename(&temp_path, path))
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&temp_path);
+        })
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn save(path: &Path, rules: Vec<Rule>) -> Result<()> {
+    let file = RulesFile { rules };
+    let contents = if is_json(path) {
+        serde_json::to_string_pretty(&file)?
+    } else {
+        toml::to_string(&file)?
+    };
+    write_file(path, &contents)
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "json")
+}
+
+fn validate(rules: &[Rule]) -> Result<()> {
+    let mut names = HashSet::new();
+    for rule in rules {
+        validate_rule(rule)?;
+        if !names.insert(rule.name.as_str()) {
+            anyhow::bail!("More than one rule is named '{}'", rule.name);
+        }
+    }
+    Ok(())
+}
+
+fn validate_rule(rule: &Rule) -> Result<()> {
+    if rule.name.trim().is_empty() {
+        anyhow::bail!("Rules need a name");
+    }
+    if rule.query.trim().is_empty() {
+        anyhow::bail!("Rule '{}' has no query", rule.name);
+    }
+    if rule.actions.is_empty() {
+        anyhow::bail!("Rule '{}' has no actions", rule.name);
+    }
+    for action in &rule.actions {
+        match action {
+            Action::Label { label } if label.trim().is_empty() => {
+                anyhow::bail!("Rule '{}' has a label action without a label", rule.name)
+            }
+            Action::Forward { to } => crate::tools::validate_headers(to, "")
+                .with_context(|| format!("Rule '{}' forwards to '{to}'", rule.name))?,
+            Action::Notify { url } => {
+                reqwest::Url::parse(url)
+                    .with_context(|| format!("Rule '{}' notifies '{url}'", rule.name))?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// The server's rules
+#[instrument(name = "tool", skip_all, fields(tool = "list_rules"))]
+pub async fn list_rules(gmail_server: &GmailServer) -> Result<Value> {
+    let path = rules_file(gmail_server)?;
+    Ok(json!({ "file": path.display().to_string(), "rules": load(path)? }))
+}
+
+/// Add a rule, replacing the one of the same name if there is one
+#[instrument(name = "tool", skip_all, fields(tool = "add_rule", rule = %rule.name))]
+pub async fn add_rule(gmail_server: &GmailServer, rule: Rule) -> Result<Value> {
+    let path = rules_file(gmail_server)?;
+    validate_rule(&rule)?;
+    // Catches references to saved searches that don't exist
+    expand_query(gmail_server, &rule.query)?;
+    let _guard = RULES_LOCK.lock().unwrap();
+    let mut rules = load(path)?;
+    let replaced = match rules.iter_mut().find(|other| other.name == rule.name) {
+        Some(other) => {
+            *other = rule.clone();
+            true
+        }
+        None => {
+            rules.push(rule.clone());
+            false
+        }
+    };
+    save(path, rules)?;
+    Ok(json!({ "rule": rule, "replaced": replaced }))
+}
+
+#[instrument(name = "tool", skip_all, fields(tool = "remove_rule", rule = %name))]
+pub async fn remove_rule(gmail_server: &GmailServer, name: &str) -> Result<Value> {
+    let path = rules_file(gmail_server)?;
+    let _guard = RULES_LOCK.lock().unwrap();
+    let mut rules = load(path)?;
+    let count = rules.len();
+    rules.retain(|rule| rule.name != name);
+    if rules.len() == count {
+        anyhow::bail!("No rule named '{name}'");
+    }
+    save(path, rules)?;
+    Ok(json!({ "removed": name }))
+}
+
+/// The rules file, which only the server's own accounts can change
+fn rules_file(gmail_server: &GmailServer) -> Result<&Path> {
+    match (gmail_server.rules_file(), gmail_server.account()) {
+        (Some(path), Some(_)) => Ok(path),
+        _ => anyhow::bail!(
+            "Rules belong to the server's own accounts, so they aren't available with \
+             caller-supplied tokens, in multi-user mode or in demo mode"
+        ),
+    }
+}
+
+/// Poll each rule's query every `interval` until cancelled, and take its actions on messages
+/// that start matching it. The file is read on every poll, so edits apply without a restart.
+///
+/// Messages that match when a rule is first polled are left alone, as are those of accounts
+/// without a usable token
+pub fn spawn_rules_watch(
+    accounts: Arc<Accounts>,
+    path: PathBuf,
+    interval: Duration,
+    ct: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    record_watcher_started(WATCHER);
+    tokio::spawn(async move {
+        let notify_client = reqwest::Client::builder()
+            .timeout(NOTIFY_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        // Messages each rule matched on its last poll, keyed by what it watches
+        let mut seen: HashMap<(String, String, String), HashSet<String>> = HashMap::new();
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = ct.cancelled() => break,
+                _ = ticker.tick() => {}
+            }
+            let rules = match load(&path) {
+                Ok(rules) => rules,
+                Err(e) => {
+                    warn!("Failed to load rules: {:#}", e);
+                    continue;
+                }
+            };
+            let keys: HashSet<_> = rules
+                .iter()
+                .map(|rule| watch_key(&accounts, rule))
+                .collect();
+            seen.retain(|key, _| keys.contains(key));
+
+            // The watch is only up to date once every rule has been polled
+            let mut polled_all = true;
+            for rule in &rules {
+                let gmail_server = match accounts.get(rule.account.as_deref()) {
+                    Ok(account) => account.gmail_server.clone(),
+                    Err(e) => {
+                        warn!("Skipping rule '{}': {}", rule.name, e);
+                        continue;
+                    }
+                };
+                if !gmail_server.is_authenticated().await {
+                    debug!("Skipping rule '{}': not authenticated", rule.name);
+                    polled_all = false;
+                    continue;
+                }
+                // A failed poll is retried on the next tick
+                let listed = match list_messages(&gmail_server, &rule.query, RULE_MAX_RESULTS).await
+                {
+                    Ok(listed) => listed,
+                    Err(e) => {
+                        warn!("Failed to poll rule '{}': {:#}", rule.name, e);
+                        polled_all = false;
+                        continue;
+                    }
+                };
+                let key = watch_key(&accounts, rule);
+                if let Some(previous) = seen.get(&key) {
+                    for message_id in new_messages(previous, &listed) {
+                        info!("📬 Rule '{}' matched message {}", rule.name, message_id);
+                        if let Err(e) =
+                            apply(&gmail_server, &notify_client, rule, &message_id).await
+                        {
+                            warn!(
+                                "Rule '{}' failed on message {}: {:#}",
+                                rule.name, message_id, e
+                            );
+                        }
+                    }
+                }
+                seen.insert(key, listed.into_iter().collect());
+            }
+            if polled_all {
+                record_watcher_poll(WATCHER);
+            }
+        }
+    })
+}
+
+/// Account, query and name, so editing what a rule watches starts it afresh
+fn watch_key(accounts: &Accounts, rule: &Rule) -> (String, String, String) {
+    (
+        accounts.name(rule.account.as_deref()).to_string(),
+        rule.query.clone(),
+        rule.name.clone(),
+    )
+}
+
+/// Take a rule's actions on a message, all of them even when one fails. With `--dry-run` they
+/// are only logged
+async fn apply(
+    gmail_server: &GmailServer,
+    notify_client: &reqwest::Client,
+    rule: &Rule,
+    message_id: &str,
+) -> Result<()> {
+    if gmail_server.dry_run() {
+        info!(
+            "Dry run, not taking rule '{}' actions on {}: {:?}",
+            rule.name, message_id, rule.actions
+        );
+        return Ok(());
+    }
+    let client = gmail_server.authenticated_client().await?;
+    let user_id = gmail_server.user_id();
+    let message = client
+        .get_json(&message_url(
+            user_id,
+            message_id,
+            MessageFormat::Metadata,
+            None,
+        ))
+        .await
+        .context("Failed to get message")?;
+    let summary = summarize_message(message_id, &message, MessageFormat::Metadata)?;
+
+    let mut errors = Vec::new();
+    if let Err(e) = modify_labels(gmail_server, &rule.actions, message_id).await {
+        errors.push(format!("{e:#}"));
+    }
+    let mut classification = None;
+    for action in &rule.actions {
+        let result = match action {
+            Action::Forward { to } => {
+                let subject = format!("Fwd: {}", summary["subject"].as_str().unwrap_or_default());
+                crate::tools::forward_email(
+                    gmail_server,
+                    message_id,
+                    to,
+                    &subject,
+                    "",
+                    false,
+                    false,
+                )
+                .await
+                .map(|_| ())
+            }
+            Action::Notify { url } => {
+                let classification = classification.as_ref();
+                notify(
+                    notify_client,
+                    url,
+                    rule,
+                    gmail_server,
+                    &summary,
+                    classification,
+                )
+                .await
+            }
+            Action::Classify => classify_and_label(gmail_server, message_id)
+                .await
+                .map(|classified| classification = Some(classified)),
+            Action::Label { .. } | Action::Archive => Ok(()),
+        };
+        if let Err(e) = result {
+            errors.push(format!("{e:#}"));
+        }
+    }
+    if !errors.is_empty() {
+        anyhow::bail!(errors.join("; "));
+    }
+    Ok(())
+}
+
+/// Apply a rule's label and archive actions to a message in one request
+async fn modify_labels(
+    gmail_server: &GmailServer,
+    actions: &[Action],
+    message_id: &str,
+) -> Result<()> {
+    let labels: Vec<&str> = actions
+        .iter()
+        .filter_map(|action| match action {
+            Action::Label { label } => Some(label.as_str()),
+            _ => None,
+        })
+        .collect();
+    let archive = actions.contains(&Action::Archive);
+    if labels.is_empty() && !archive {
+        return Ok(());
+    }
+
+    gmail_server.check_authentication(&[SCOPE_MODIFY]).await?;
+    let client = gmail_server.authenticated_client().await?;
+    let user_id = gmail_server.user_id();
+    let mut add = Vec::new();
+    for label in labels {
+        add.push(crate::labels::find_or_create_label(&client, user_id, label).await?);
+    }
+    let remove: &[&str] = if archive { &["INBOX"] } else { &[] };
+    client
+        .post_json(
+            &format!("{GMAIL_API_BASE}/users/{user_id}/messages/{message_id}/modify"),
+            &json!({ "addLabelIds": add, "removeLabelIds": remove }),
+        )
+        .await
+        .context("Failed to modify message labels")?;
+    Ok(())
+}
+
+/// Run the classifier hook on a message and apply the labels it returns
+async fn classify_and_label(
+    gmail_server: &GmailServer,
+    message_id: &str,
+) -> Result<Classification> {
+    let classification = crate::classifier::classify(gmail_server, message_id).await?;
+    debug!(
+        "Classified message {}: labels {:?}, scores {:?}",
+        message_id, classification.labels, classification.scores
+    );
+    let labels: Vec<Action> = classification
+        .labels
+        .iter()
+        .map(|label| Action::Label {
+            label: label.clone(),
+        })
+        .collect();
+    modify_labels(gmail_server, &labels, message_id).await?;
+    Ok(classification)
+}
+
+async fn notify(
+    client: &reqwest::Client,
+    url: &str,
+    rule: &Rule,
+    gmail_server: &GmailServer,
+    summary: &Value,
+    classification: Option<&Classification>,
+) -> Result<()> {
+    gmail_server.check_authentication(&[SCOPE_READONLY]).await?;
+    let mut payload = json!({
+        "event": "rule_matched",
+        "rule": rule.name,
+        "account": gmail_server.account(),
+        "message": summary,
+        "timestamp": chrono::Utc::now().timestamp(),
+    });
+    // Set when an earlier classify action ran
+    if let Some(classification) = classification {
+        payload["classification"] = json!(classification);
+    }
+    let response = client
+        .post(url)
+        .json(&payload)
+        .send()
+        .await
+        .context("Failed to send rule notification")?;
+    if !response.status().is_success() {
+        anyhow::bail!("Rule notification webhook returned {}", response.status());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockGmail;
+
+    fn rule(name: &str, actions: Vec<Action>) -> Rule {
+        Rule {
+            name: name.to_string(),
+            query: "from:alice@example.com".to_string(),
+            account: None,
+            actions,
+        }
+    }
+
+    #[test]
+    fn test_load_toml_and_json_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(load(&dir.path().join("missing.toml")).unwrap().is_empty());
+
+        let toml_path = dir.path().join("rules.toml");
+        std::fs::write(
+            &toml_path,
+            r#"
+[[rules]]
+name = "receipts"
+query = "subject:receipt"
+actions = [
+    { action = "label", label = "Receipts" },
+    { action = "archive" },
+]
+
+[[rules]]
+name = "boss"
+query = "from:boss@example.com"
+account = "work"
+actions = [{ action = "notify", url = "https://hooks.example.com/mail" }]
+"#,
+        )
+        .unwrap();
+        let rules = load(&toml_path).unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(
+            rules[0].actions,
+            vec![
+                Action::Label {
+                    label: "Receipts".to_string()
+                },
+                Action::Archive
+            ]
+        );
+        assert_eq!(rules[1].account.as_deref(), Some("work"));
+
+        // Saved in the file's own format and read back unchanged
+        let json_path = dir.path().join("rules.json");
+        save(&json_path, rules.clone()).unwrap();
+        assert_eq!(load(&json_path).unwrap(), rules);
+        save(&toml_path, rules.clone()).unwrap();
+        assert_eq!(load(&toml_path).unwrap(), rules);
+    }
+
+    #[test]
+    fn test_validate_rules() {
+        assert!(validate(&[rule("a", vec![Action::Archive])]).is_ok());
+        assert!(validate(&[rule("a", vec![])]).is_err());
+        assert!(validate(&[
+            rule("a", vec![Action::Archive]),
+            rule("a", vec![Action::Archive])
+        ])
+        .is_err());
+        let forward = |to: &str| rule("a", vec![Action::Forward { to: to.to_string() }]);
+        assert!(validate_rule(&forward("bob@example.com")).is_ok());
+        assert!(validate_rule(&forward("not an address")).is_err());
+        let notify = rule(
+            "a",
+            vec![Action::Notify {
+                url: "hooks".to_string(),
+            }],
+        );
+        assert!(validate_rule(&notify).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rules_tools_and_actions_against_mock_gmail() {
+        let gmail = MockGmail::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = gmail.config();
+        config.app_data_dir = Some(dir.path().to_path_buf());
+        let gmail_server = gmail.gmail_server(&config).await;
+
+        let actions = vec![
+            Action::Label {
+                label: "Snoozed".to_string(),
+            },
+            Action::Archive,
+        ];
+        let added = add_rule(&gmail_server, rule("tidy", actions.clone()))
+            .await
+            .unwrap();
+        assert_eq!(added["replaced"], false);
+        let added = add_rule(&gmail_server, rule("tidy", actions.clone()))
+            .await
+            .unwrap();
+        assert_eq!(added["replaced"], true);
+        let listed = list_rules(&gmail_server).await.unwrap();
+        assert_eq!(listed["rules"].as_array().unwrap().len(), 1);
+        assert!(dir.path().join(RULES_FILE).exists());
+
+        let client = reqwest::Client::new();
+        apply(&gmail_server, &client, &rule("tidy", actions), "msg-1")
+            .await
+            .unwrap();
+        let requests = gmail.requests().await;
+        let modify = requests
+            .iter()
+            .find(|request| request.url.path().ends_with("/messages/msg-1/modify"))
+            .unwrap();
+        let body: Value = serde_json::from_slice(&modify.body).unwrap();
+        assert_eq!(
+            body,
+            json!({ "addLabelIds": ["Label_snoozed"], "removeLabelIds": ["INBOX"] })
+        );
+
+        remove_rule(&gmail_server, "tidy").await.unwrap();
+        assert!(remove_rule(&gmail_server, "tidy").await.is_err());
+        assert!(list_rules(&gmail_server).await.unwrap()["rules"]
+            .as_array()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_rule_changes_are_all_kept() {
+        let gmail = MockGmail::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = gmail.config();
+        config.app_data_dir = Some(dir.path().to_path_buf());
+        let gmail_server = Arc::new(gmail.gmail_server(&config).await);
+
+        let adds = (0..100).map(|index| {
+            let gmail_server = gmail_server.clone();
+            tokio::spawn(async move {
+                add_rule(
+                    &gmail_server,
+                    rule(&format!("rule-{index}"), vec![Action::Archive]),
+                )
+                .await
+            })
+        });
+        for add in futures::future::join_all(adds).await {
+            add.unwrap().unwrap();
+        }
+        let listed = list_rules(&gmail_server).await.unwrap();
+        assert_eq!(listed["rules"].as_array().unwrap().len(), 100);
+        // Only the renamed file is left behind
+        let files: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert!(!files
+            .iter()
+            .any(|file| file.to_string_lossy().ends_with(".tmp")));
+    }
+
+    #[tokio::test]
+    async fn test_classify_action_labels_and_notifies() {
+        use wiremock::matchers::{body_partial_json, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let hooks = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/classify"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "labels": ["Snoozed"],
+                "scores": { "spam": 0.1 },
+            })))
+            .mount(&hooks)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/notify"))
+            .and(body_partial_json(json!({
+                "classification": { "labels": ["Snoozed"], "scores": { "spam": 0.1 } },
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&hooks)
+            .await;
+
+        let gmail = MockGmail::start().await;
+        let mut config = gmail.config();
+        config.classifier_url = Some(format!("{}/classify", hooks.uri()));
+        let gmail_server = gmail.gmail_server(&config).await;
+        let actions = vec![
+            Action::Classify,
+            Action::Notify {
+                url: format!("{}/notify", hooks.uri()),
+            },
+        ];
+        apply(
+            &gmail_server,
+            &reqwest::Client::new(),
+            &rule("classify", actions),
+            "msg-1",
+        )
+        .await
+        .unwrap();
+
+        let requests = gmail.requests().await;
+        let modify = requests
+            .iter()
+            .find(|request| request.url.path().ends_with("/messages/msg-1/modify"))
+            .unwrap();
+        let body: Value = serde_json::from_slice(&modify.body).unwrap();
+        assert_eq!(
+            body,
+            json!({ "addLabelIds": ["Label_snoozed"], "removeLabelIds": [] })
+        );
+    }
+}
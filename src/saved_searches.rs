@@ -0,0 +1,382 @@
+use anyhow::{Context, Result};
+use rmcp::schemars;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::{debug, instrument};
+
+use crate::gmail::GmailServer;
+use crate::rules::{read_file, write_file};
+
+/// Saved searches file in the app data directory
+pub const SAVED_SEARCHES_FILE: &str = "saved_searches.toml";
+
+/// Longest accepted saved search name
+const MAX_NAME_LEN: usize = 64;
+
+/// Held while a tool loads, changes and saves the saved searches file, so concurrent changes
+/// don't overwrite each other
+static SAVED_SEARCHES_LOCK: Mutex<()> = Mutex::new(());
+
+/// A Gmail query kept under a name, so workflows can reuse it instead of rebuilding it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct SavedSearch {
+    /// Unique name of the search: letters, digits, `_` and `-`
+    pub name: String,
+    /// Gmail search query (e.g., "from:billing@example.com has:attachment newer_than:7d")
+    pub query: String,
+    /// What the search is for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedSearchesFile {
+    #[serde(default)]
+    searches: Vec<SavedSearch>,
+}
+
+/// The saved searches in a TOML file. A missing file has none
+pub fn load(path: &Path) -> Result<Vec<SavedSearch>> {
+    let file: SavedSearchesFile = read_file(path)?;
+    for search in &file.searches {
+        validate(search)
+            .with_context(|| format!("Invalid saved searches file {}", path.display()))?;
+    }
+    Ok(file.searches)
+}
+
+fn save(path: &Path, searches: Vec<SavedSearch>) -> Result<()> {
+    let contents = toml::to_string(&SavedSearchesFile { searches })?;
+    write_file(path, &contents)
+}
+
+fn validate(search: &SavedSearch) -> Result<()> {
+    validate_name(&search.name)?;
+    if search.query.trim().is_empty() {
+        anyhow::bail!("Saved search '{}' has no query", search.name);
+    }
+    Ok(())
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        anyhow::bail!(
+            "Invalid saved search name '{name}': use up to {MAX_NAME_LEN} letters, digits, '_' \
+             and '-'"
+        );
+    }
+    Ok(())
+}
+
+#[instrument(name = "tool", skip_all, fields(tool = "list_saved_searches"))]
+pub async fn list_saved_searches(gmail_server: &GmailServer) -> Result<Value> {
+    let path = saved_searches_file(gmail_server)?;
+    Ok(json!({ "file": path.display().to_string(), "searches": load(path)? }))
+}
+
+/// Save a new search, failing if one already has its name
+#[instrument(name = "tool", skip_all, fields(tool = "save_search", search = %search.name))]
+pub async fn save_search(gmail_server: &GmailServer, search: SavedSearch) -> Result<Value> {
+    let path = saved_searches_file(gmail_server)?;
+    validate(&search)?;
+    let _guard = SAVED_SEARCHES_LOCK.lock().unwrap();
+    let mut searches = load(path)?;
+    if searches.iter().any(|other| other.name == search.name) {
+        anyhow::bail!(
+            "A search named '{}' is already saved; update_saved_search changes it",
+            search.name
+        );
+    }
+    searches.push(search.clone());
+    save(path, searches)?;
+    Ok(json!({ "search": search }))
+}
+
+/// Change a saved search's query, description or both
+#[instrument(name = "tool", skip_all, fields(tool = "update_saved_search", search = %name))]
+pub async fn update_saved_search(
+    gmail_server: &GmailServer,
+    name: &str,
+    query: Option<String>,
+    description: Option<String>,
+) -> Result<Value> {
+    let path = saved_searches_file(gmail_server)?;
+    if query.is_none() && description.is_none() {
+        anyhow::bail!("Nothing to update: pass a new query, description or both");
+    }
+    let _guard = SAVED_SEARCHES_LOCK.lock().unwrap();
+    let mut searches = load(path)?;
+    let search = searches
+        .iter_mut()
+        .find(|search| search.name == name)
+        .ok_or_else(|| anyhow::anyhow!("No saved search named '{name}'"))?;
+    if let Some(query) = query {
+        search.query = query;
+    }
+    if let Some(description) = description {
+        // An empty description removes it
+        search.description = Some(description).filter(|description| !description.is_empty());
+    }
+    validate(search)?;
+    let search = search.clone();
+    save(path, searches)?;
+    Ok(json!({ "search": search }))
+}
+
+#[instrument(name = "tool", skip_all, fields(tool = "delete_saved_search", search = %name))]
+pub async fn delete_saved_search(gmail_server: &GmailServer, name: &str) -> Result<Value> {
+    let path = saved_searches_file(gmail_server)?;
+    let _guard = SAVED_SEARCHES_LOCK.lock().unwrap();
+    let mut searches = load(path)?;
+    let count = searches.len();
+    searches.retain(|search| search.name != name);
+    if searches.len() == count {
+        anyhow::bail!("No saved search named '{name}'");
+    }
+    save(path, searches)?;
+    Ok(json!({ "deleted": name }))
+}
+
+/// `query` with each `@name` in it replaced by the saved search of that name, so search, rule
+/// and job queries can share it. Unknown names are an error
+pub fn expand_query(gmail_server: &GmailServer, query: &str) -> Result<String> {
+    let mut searches = None;
+    let expanded = expand(query, |name| {
+        let searches = match &searches {
+            Some(searches) => searches,
+            None => searches.insert(load(saved_searches_file(gmail_server)?)?),
+        };
+        searches
+            .iter()
+            .find(|search| search.name == name)
+            .map(|search| search.query.clone())
+            .ok_or_else(|| anyhow::anyhow!("No saved search named '{name}' for @{name}"))
+    })?;
+    if expanded != query {
+        debug!("Expanded query '{}' to '{}'", query, expanded);
+    }
+    Ok(expanded)
+}
+
+/// `query` with the saved query `lookup` gives for each `@name`, in parentheses unless it's the
+/// whole query. References may be negated or grouped (`-@name`, `(@name OR ...)`), quoted text is
+/// left alone, and saved queries aren't expanded again
+fn expand(query: &str, mut lookup: impl FnMut(&str) -> Result<String>) -> Result<String> {
+    let mut in_quotes = false;
+    let mut tokens = Vec::new();
+    for token in query.split(' ') {
+        let quoted = in_quotes;
+        if token.matches('"').count() % 2 == 1 {
+            in_quotes = !in_quotes;
+        }
+        tokens.push(match reference(token).filter(|_| !quoted) {
+            Some((_, name, _)) if query.trim() == token => lookup(name)?,
+            Some((prefix, name, suffix)) => format!("{prefix}({}){suffix}", lookup(name)?),
+            None => token.to_string(),
+        });
+    }
+    Ok(tokens.join(" "))
+}
+
+/// The name a query token refers to as `@name`, with the `-` and `(` before it and `)` after it
+fn reference(token: &str) -> Option<(&str, &str, &str)> {
+    let core = token.trim_start_matches(['-', '(']);
+    let prefix = &token[..token.len() - core.len()];
+    let name = core.trim_end_matches(')').strip_prefix('@')?;
+    let suffix = &core[1 + name.len()..];
+    validate_name(name)
+        .is_ok()
+        .then_some((prefix, name, suffix))
+}
+
+/// The saved searches file, which only the server's own accounts can change
+fn saved_searches_file(gmail_server: &GmailServer) -> Result<&Path> {
+    match (gmail_server.saved_searches_file(), gmail_server.account()) {
+        (Some(path), Some(_)) => Ok(path),
+        _ => anyhow::bail!(
+            "Saved searches belong to the server's own accounts, so they aren't available with \
+             caller-supplied tokens, in multi-user mode or in demo mode"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockGmail;
+    use crate::tools::{search_threads, SearchFilter};
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("weekly_invoices").is_ok());
+        assert!(validate_name("q3-board").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("weekly invoices").is_err());
+        assert!(validate_name("@weekly").is_err());
+        assert!(validate_name(&"a".repeat(MAX_NAME_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_expand() {
+        let lookup = |name: &str| match name {
+            "invoices" => Ok("subject:invoice newer_than:7d".to_string()),
+            "boss" => Ok("from:boss@example.com".to_string()),
+            _ => anyhow::bail!("No saved search named '{name}'"),
+        };
+        let expanded = |query: &str| expand(query, lookup).unwrap();
+        assert_eq!(expanded("@invoices"), "subject:invoice newer_than:7d");
+        assert_eq!(expanded(" @invoices "), " subject:invoice newer_than:7d ");
+        assert_eq!(
+            expanded("@invoices is:unread"),
+            "(subject:invoice newer_than:7d) is:unread"
+        );
+        assert_eq!(
+            expanded("(@boss OR @invoices) -@boss"),
+            "((from:boss@example.com) OR (subject:invoice newer_than:7d)) -(from:boss@example.com)"
+        );
+        // Addresses, quoted text and other uses of @ are left alone
+        assert_eq!(expanded("@example.com"), "@example.com");
+        assert_eq!(expanded("to:a@example.com"), "to:a@example.com");
+        assert_eq!(expanded("\"cc @boss today\""), "\"cc @boss today\"");
+        assert!(expand("@missing is:unread", lookup).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_saved_search_tools() {
+        let gmail = MockGmail::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = gmail.config();
+        config.app_data_dir = Some(dir.path().to_path_buf());
+        let gmail_server = gmail.gmail_server(&config).await;
+        let search = |name: &str, query: &str| SavedSearch {
+            name: name.to_string(),
+            query: query.to_string(),
+            description: None,
+        };
+
+        save_search(
+            &gmail_server,
+            search("weekly_invoices", "subject:invoice newer_than:7d"),
+        )
+        .await
+        .unwrap();
+        save_search(&gmail_server, search("boss", "from:boss@example.com"))
+            .await
+            .unwrap();
+        assert!(
+            save_search(&gmail_server, search("boss", "from:ceo@example.com"))
+                .await
+                .is_err()
+        );
+        assert!(save_search(&gmail_server, search("empty", " "))
+            .await
+            .is_err());
+
+        let updated = update_saved_search(
+            &gmail_server,
+            "weekly_invoices",
+            None,
+            Some("Invoices from the last week".to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(updated["search"]["query"], "subject:invoice newer_than:7d");
+        assert_eq!(
+            updated["search"]["description"],
+            "Invoices from the last week"
+        );
+        assert!(update_saved_search(&gmail_server, "boss", None, None)
+            .await
+            .is_err());
+        assert!(
+            update_saved_search(&gmail_server, "missing", Some("x".to_string()), None)
+                .await
+                .is_err()
+        );
+
+        delete_saved_search(&gmail_server, "boss").await.unwrap();
+        assert!(delete_saved_search(&gmail_server, "boss").await.is_err());
+
+        let listed = list_saved_searches(&gmail_server).await.unwrap();
+        assert_eq!(
+            listed["searches"],
+            json!([{
+                "name": "weekly_invoices",
+                "query": "subject:invoice newer_than:7d",
+                "description": "Invoices from the last week",
+            }])
+        );
+        // Kept in the app data directory
+        let saved = load(&dir.path().join(SAVED_SEARCHES_FILE)).unwrap();
+        assert_eq!(saved.len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_saves_are_all_kept() {
+        let gmail = MockGmail::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = gmail.config();
+        config.app_data_dir = Some(dir.path().to_path_buf());
+        let gmail_server = std::sync::Arc::new(gmail.gmail_server(&config).await);
+
+        let saves = (0..100).map(|index| {
+            let gmail_server = gmail_server.clone();
+            tokio::spawn(async move {
+                let search = SavedSearch {
+                    name: format!("search-{index}"),
+                    query: "is:unread".to_string(),
+                    description: None,
+                };
+                save_search(&gmail_server, search).await
+            })
+        });
+        for save in futures::future::join_all(saves).await {
+            save.unwrap().unwrap();
+        }
+        let saved = load(&dir.path().join(SAVED_SEARCHES_FILE)).unwrap();
+        assert_eq!(saved.len(), 100);
+    }
+
+    #[tokio::test]
+    async fn test_searches_expand_saved_searches() {
+        let gmail = MockGmail::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = gmail.config();
+        config.app_data_dir = Some(dir.path().to_path_buf());
+        let gmail_server = gmail.gmail_server(&config).await;
+        save_search(
+            &gmail_server,
+            SavedSearch {
+                name: "invoices".to_string(),
+                query: "subject:invoice OR subject:receipt".to_string(),
+                description: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        let filter = SearchFilter::default();
+        search_threads(&gmail_server, "@invoices is:unread", 10, None, &filter)
+            .await
+            .unwrap();
+        let searched: Vec<String> = gmail
+            .requests()
+            .await
+            .iter()
+            .flat_map(|request| request.url.query_pairs())
+            .filter(|(key, _)| key == "q")
+            .map(|(_, query)| query.into_owned())
+            .collect();
+        assert_eq!(searched, ["(subject:invoice OR subject:receipt) is:unread"]);
+        assert!(search_threads(&gmail_server, "@missing", 10, None, &filter)
+            .await
+            .is_err());
+    }
+}
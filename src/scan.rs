@@ -0,0 +1,281 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::warn;
+
+use crate::config::{Config, ScanAction};
+use crate::utils::run_shell;
+
+/// Bytes sent to clamd per INSTREAM chunk
+const CLAMD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// What an attachment is scanned with
+#[derive(Debug)]
+enum Scanner {
+    /// clamd's INSTREAM command, over a Unix socket or TCP
+    Clamd { address: String },
+    /// A shell command reading the attachment on stdin
+    Command { command: String },
+}
+
+/// What a scan found
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    Clean,
+    /// Infected, with what the scanner found
+    Infected(String),
+}
+
+/// Scans attachments before they're saved or their content returned, with --clamd-address or
+/// --attachment-scan-command
+#[derive(Debug)]
+pub struct AttachmentScanner {
+    scanner: Scanner,
+    action: ScanAction,
+    timeout: Option<Duration>,
+}
+
+impl AttachmentScanner {
+    /// The configured scanner, if any
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let scanner = match (&config.clamd_address, &config.attachment_scan_command) {
+            (Some(address), _) => Scanner::Clamd {
+                address: address.clone(),
+            },
+            (None, Some(command)) => Scanner::Command {
+                command: command.clone(),
+            },
+            (None, None) => return None,
+        };
+        Some(Self {
+            scanner,
+            action: config.attachment_scan_action,
+            timeout: (config.attachment_scan_timeout_secs > 0)
+                .then(|| Duration::from_secs(config.attachment_scan_timeout_secs)),
+        })
+    }
+
+    /// Scan an attachment, failing when it's blocked, and otherwise returning the scan's report
+    pub async fn check(&self, filename: &str, data: &[u8]) -> Result<Value> {
+        let verdict = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.scan(data))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(anyhow::anyhow!(
+                        "Scan timed out after {}s",
+                        timeout.as_secs()
+                    ))
+                }),
+            None => self.scan(data).await,
+        };
+        match (verdict, self.action) {
+            (Ok(Verdict::Clean), _) => Ok(json!({ "status": "clean" })),
+            (Ok(Verdict::Infected(found)), ScanAction::Block) => {
+                anyhow::bail!("Attachment '{filename}' was blocked: the virus scan found {found}")
+            }
+            (Ok(Verdict::Infected(found)), ScanAction::Warn) => {
+                warn!("Virus scan found {} in attachment '{}'", found, filename);
+                Ok(json!({ "status": "infected", "found": found }))
+            }
+            (Err(e), ScanAction::Block) => Err(e.context(format!(
+                "Attachment '{filename}' was blocked: the virus scan failed"
+            ))),
+            (Err(e), ScanAction::Warn) => {
+                warn!("Virus scan of attachment '{}' failed: {:#}", filename, e);
+                Ok(json!({ "status": "error", "error": format!("{e:#}") }))
+            }
+        }
+    }
+
+    async fn scan(&self, data: &[u8]) -> Result<Verdict> {
+        match &self.scanner {
+            Scanner::Clamd { address } => scan_with_clamd(address, data).await,
+            Scanner::Command { command } => scan_with_command(command, data).await,
+        }
+    }
+}
+
+async fn scan_with_command(command: &str, data: &[u8]) -> Result<Verdict> {
+    let output = run_shell(command, data)
+        .await
+        .context("Failed to run the scan command")?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match output.status.code() {
+        Some(0) => Ok(Verdict::Clean),
+        Some(1) => {
+            let found = stdout.lines().next().unwrap_or_default().trim();
+            Ok(Verdict::Infected(if found.is_empty() {
+                "a threat".to_string()
+            } else {
+                found.to_string()
+            }))
+        }
+        _ => anyhow::bail!(
+            "Scan command failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+    }
+}
+
+/// Scan with clamd at a Unix socket path or host:port
+async fn scan_with_clamd(address: &str, data: &[u8]) -> Result<Verdict> {
+    #[cfg(unix)]
+    if address.starts_with('/') {
+        let stream = tokio::net::UnixStream::connect(address)
+            .await
+            .with_context(|| format!("Failed to connect to clamd at {address}"))?;
+        return instream(stream, data).await;
+    }
+    let stream = tokio::net::TcpStream::connect(address)
+        .await
+        .with_context(|| format!("Failed to connect to clamd at {address}"))?;
+    instream(stream, data).await
+}
+
+/// Send the data with clamd's INSTREAM command and read its verdict
+async fn instream<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    data: &[u8],
+) -> Result<Verdict> {
+    stream.write_all(b"zINSTREAM\0").await?;
+    for chunk in data.chunks(CLAMD_CHUNK_SIZE) {
+        stream
+            .write_all(&(chunk.len() as u32).to_be_bytes())
+            .await?;
+        stream.write_all(chunk).await?;
+    }
+    stream.write_all(&0u32.to_be_bytes()).await?;
+    stream.flush().await?;
+
+    let mut reply = Vec::new();
+    stream
+        .read_to_end(&mut reply)
+        .await
+        .context("Failed to read clamd's reply")?;
+    parse_clamd_reply(&String::from_utf8_lossy(&reply))
+}
+
+/// clamd answers `stream: OK`, `stream: <signature> FOUND` or `<reason> ERROR`
+fn parse_clamd_reply(reply: &str) -> Result<Verdict> {
+    let reply = reply.trim_end_matches(['\0', '\n']).trim();
+    let result = reply.strip_prefix("stream:").unwrap_or(reply).trim();
+    if result == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(found) = result.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected(found.to_string()))
+    } else {
+        anyhow::bail!("clamd couldn't scan the attachment: {reply}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scanner(scanner: Scanner, action: ScanAction) -> AttachmentScanner {
+        AttachmentScanner {
+            scanner,
+            action,
+            timeout: Some(Duration::from_secs(10)),
+        }
+    }
+
+    #[test]
+    fn test_parse_clamd_reply() {
+        assert_eq!(parse_clamd_reply("stream: OK\0").unwrap(), Verdict::Clean);
+        assert_eq!(
+            parse_clamd_reply("stream: Eicar-Test-Signature FOUND\0").unwrap(),
+            Verdict::Infected("Eicar-Test-Signature".to_string())
+        );
+        assert!(parse_clamd_reply("INSTREAM size limit exceeded. ERROR\0").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_clamd_instream() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        // A clamd that flags anything containing "EICAR"
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut command = [0u8; 10];
+                stream.read_exact(&mut command).await.unwrap();
+                assert_eq!(&command, b"zINSTREAM\0");
+                let mut data = Vec::new();
+                loop {
+                    let length = stream.read_u32().await.unwrap() as usize;
+                    if length == 0 {
+                        break;
+                    }
+                    let mut chunk = vec![0; length];
+                    stream.read_exact(&mut chunk).await.unwrap();
+                    data.extend(chunk);
+                }
+                let reply: &[u8] = if data.windows(5).any(|w| w == b"EICAR") {
+                    b"stream: Eicar-Test-Signature FOUND\0"
+                } else {
+                    b"stream: OK\0"
+                };
+                stream.write_all(reply).await.unwrap();
+            }
+        });
+
+        let clamd = || Scanner::Clamd {
+            address: address.clone(),
+        };
+        let big = vec![b'a'; CLAMD_CHUNK_SIZE * 2 + 1];
+        let report = scanner(clamd(), ScanAction::Block)
+            .check("big.bin", &big)
+            .await
+            .unwrap();
+        assert_eq!(report, json!({ "status": "clean" }));
+
+        let error = scanner(clamd(), ScanAction::Block)
+            .check("eicar.com", b"X5O!P%@AP...EICAR...")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("Eicar-Test-Signature"));
+
+        let report = scanner(clamd(), ScanAction::Warn)
+            .check("eicar.com", b"X5O!P%@AP...EICAR...")
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            json!({ "status": "infected", "found": "Eicar-Test-Signature" })
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_scanner() {
+        let command = || Scanner::Command {
+            command: "if grep -q EICAR; then echo Eicar-Test-Signature; exit 1; fi".to_string(),
+        };
+        let report = scanner(command(), ScanAction::Block)
+            .check("notes.txt", b"Meeting notes")
+            .await
+            .unwrap();
+        assert_eq!(report["status"], "clean");
+        assert!(scanner(command(), ScanAction::Block)
+            .check("eicar.com", b"EICAR")
+            .await
+            .is_err());
+
+        // A scanner that fails blocks the attachment, unless only warning
+        let broken = || Scanner::Command {
+            command: "exit 2".to_string(),
+        };
+        assert!(scanner(broken(), ScanAction::Block)
+            .check("notes.txt", b"Meeting notes")
+            .await
+            .is_err());
+        let report = scanner(broken(), ScanAction::Warn)
+            .check("notes.txt", b"Meeting notes")
+            .await
+            .unwrap();
+        assert_eq!(report["status"], "error");
+    }
+}
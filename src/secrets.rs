@@ -0,0 +1,427 @@
+use anyhow::Result;
+use std::fmt;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::config::Config;
+
+/// OAuth client credentials fetched from a secret manager, replaced in place on rotation.
+///
+/// Shared by every clone of the config, so OAuth managers created before a rotation see it too.
+#[derive(Default)]
+pub struct ClientCredentials {
+    current: RwLock<Option<(String, String)>>,
+}
+
+impl ClientCredentials {
+    /// The client ID and secret, once loaded from a secret manager
+    pub fn get(&self) -> Option<(String, String)> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Replace the credentials, returning whether they changed
+    pub fn set(&self, client_id: String, client_secret: String) -> bool {
+        let mut current = self.current.write().unwrap();
+        let changed = current
+            .as_ref()
+            .is_some_and(|(id, secret)| *id != client_id || *secret != client_secret);
+        *current = Some((client_id, client_secret));
+        changed
+    }
+}
+
+impl fmt::Debug for ClientCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientCredentials").finish_non_exhaustive()
+    }
+}
+
+/// Where a secret is stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SecretRef {
+    /// GCP Secret Manager version, `projects/<project>/secrets/<secret>/versions/<version>`
+    Gcp(String),
+    /// A key in a Vault KV v2 secret
+    Vault {
+        mount: String,
+        path: String,
+        key: String,
+    },
+}
+
+impl SecretRef {
+    /// Parse `gcp-sm://projects/<p>/secrets/<s>[/versions/<v>]` or `vault://<mount>/<path>#<key>`
+    fn parse(reference: &str) -> Result<Self> {
+        if let Some(name) = reference.strip_prefix("gcp-sm://") {
+            let segments: Vec<&str> = name.split('/').collect();
+            return match segments.as_slice() {
+                ["projects", project, "secrets", secret]
+                    if !project.is_empty() && !secret.is_empty() =>
+                {
+                    Ok(Self::Gcp(format!("{name}/versions/latest")))
+                }
+                ["projects", project, "secrets", secret, "versions", version]
+                    if !project.is_empty() && !secret.is_empty() && !version.is_empty() =>
+                {
+                    Ok(Self::Gcp(name.to_string()))
+                }
+                _ => Err(anyhow::anyhow!(
+                    "Invalid GCP secret reference '{reference}', expected \
+                     gcp-sm://projects/<project>/secrets/<secret>[/versions/<version>]"
+                )),
+            };
+        }
+        if let Some(location) = reference.strip_prefix("vault://") {
+            let parsed = location.split_once('#').and_then(|(secret, key)| {
+                let (mount, path) = secret.split_once('/')?;
+                (!mount.is_empty() && !path.is_empty() && !key.is_empty()).then(|| Self::Vault {
+                    mount: mount.to_string(),
+                    path: path.trim_end_matches('/').to_string(),
+                    key: key.to_string(),
+                })
+            });
+            return parsed.ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Invalid Vault secret reference '{reference}', expected \
+                     vault://<mount>/<path>#<key>"
+                )
+            });
+        }
+        anyhow::bail!(
+            "Unsupported secret reference '{reference}', expected a gcp-sm:// or vault:// URL"
+        )
+    }
+}
+
+/// Load the client ID and secret from their secret references, when configured.
+///
+/// The fetched values replace `--gmail-client-id` and `--gmail-client-secret`.
+pub async fn resolve_client_credentials(config: &mut Config) -> Result<()> {
+    if !has_secret_refs(config) {
+        return Ok(());
+    }
+    let (client_id, client_secret) = fetch_client_credentials(config).await?;
+    config.gmail_client_id = Some(client_id.clone());
+    config.gmail_client_secret = Some(client_secret.clone());
+    config.client_credentials.set(client_id, client_secret);
+    info!("🔐 Loaded OAuth client credentials from the secret manager");
+    Ok(())
+}
+
+/// Whether either client credential is loaded from a secret manager
+pub fn has_secret_refs(config: &Config) -> bool {
+    let secrets = &config.secret_manager;
+    secrets.gmail_client_id_from.is_some() || secrets.gmail_client_secret_from.is_some()
+}
+
+/// Re-fetch the client credentials every `interval` until cancelled, picking up rotations.
+/// The interval can change while the task runs
+pub fn spawn_credential_rotation(
+    config: Config,
+    mut interval: watch::Receiver<Duration>,
+    ct: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let period = *interval.borrow_and_update();
+            tokio::select! {
+                _ = ct.cancelled() => break,
+                _ = tokio::time::sleep(period) => {}
+                Ok(()) = interval.changed() => continue,
+            }
+            match fetch_client_credentials(&config).await {
+                Ok((client_id, client_secret)) => {
+                    if config.client_credentials.set(client_id, client_secret) {
+                        info!("🔄 OAuth client credentials rotated");
+                    }
+                }
+                Err(e) => warn!(
+                    "Failed to refresh OAuth client credentials, keeping the current ones: {:#}",
+                    e
+                ),
+            }
+        }
+    })
+}
+
+/// The client ID and secret, each from its secret reference or else the configured value
+async fn fetch_client_credentials(config: &Config) -> Result<(String, String)> {
+    let secrets = &config.secret_manager;
+    let client_id = match &secrets.gmail_client_id_from {
+        Some(reference) => fetch(config, &SecretRef::parse(reference)?).await?,
+        None => config
+            .gmail_client_id
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("GMAIL_CLIENT_ID not set"))?,
+    };
+    let client_secret = match &secrets.gmail_client_secret_from {
+        Some(reference) => fetch(config, &SecretRef::parse(reference)?).await?,
+        None => config
+            .gmail_client_secret
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("GMAIL_CLIENT_SECRET not set"))?,
+    };
+    Ok((client_id, client_secret))
+}
+
+#[cfg(not(feature = "secret-manager"))]
+async fn fetch(_config: &Config, _secret: &SecretRef) -> Result<String> {
+    anyhow::bail!("Loading secrets requires building with the `secret-manager` feature")
+}
+
+#[cfg(feature = "secret-manager")]
+async fn fetch(config: &Config, secret: &SecretRef) -> Result<String> {
+    client::SecretClient::new(config)?.fetch(secret).await
+}
+
+#[cfg(feature = "secret-manager")]
+mod client {
+    use anyhow::{Context, Result};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde_json::Value;
+
+    use super::SecretRef;
+    use crate::config::{Config, SecretManagerConfig};
+
+    const GCP_SECRET_MANAGER_URL: &str = "https://secretmanager.googleapis.com/v1";
+    /// Access tokens for the service account of the GCE, GKE or Cloud Run instance
+    const GCP_METADATA_TOKEN_URL: &str =
+        "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+    pub struct SecretClient {
+        http_client: reqwest::Client,
+        config: SecretManagerConfig,
+        gcp_secret_manager_url: String,
+        gcp_metadata_token_url: String,
+    }
+
+    impl SecretClient {
+        pub fn new(config: &Config) -> Result<Self> {
+            let http_client = crate::utils::apply_proxy(reqwest::ClientBuilder::new(), config)?
+                .build()
+                .context("Failed to build secret manager HTTP client")?;
+            Ok(Self {
+                http_client,
+                config: config.secret_manager.clone(),
+                gcp_secret_manager_url: GCP_SECRET_MANAGER_URL.to_string(),
+                gcp_metadata_token_url: GCP_METADATA_TOKEN_URL.to_string(),
+            })
+        }
+
+        /// The secret's value, without surrounding whitespace such as a trailing newline
+        pub async fn fetch(&self, secret: &SecretRef) -> Result<String> {
+            let value = match secret {
+                SecretRef::Gcp(name) => self.fetch_gcp(name).await,
+                SecretRef::Vault { mount, path, key } => self.fetch_vault(mount, path, key).await,
+            }?;
+            Ok(value.trim().to_string())
+        }
+
+        async fn fetch_gcp(&self, name: &str) -> Result<String> {
+            let access_token = match &self.config.gcp_access_token {
+                Some(access_token) => access_token.clone(),
+                None => self.gcp_metadata_token().await?,
+            };
+            let response = self
+                .http_client
+                .get(format!("{}/{name}:access", self.gcp_secret_manager_url))
+                .bearer_auth(access_token)
+                .send()
+                .await
+                .context("Failed to reach GCP Secret Manager")?;
+            let body = json_body(response, "GCP Secret Manager").await?;
+            let data = body["payload"]["data"]
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("GCP secret {name} has no payload"))?;
+            let bytes = STANDARD
+                .decode(data)
+                .context("Failed to decode GCP secret payload")?;
+            String::from_utf8(bytes).context("GCP secret is not UTF-8")
+        }
+
+        async fn gcp_metadata_token(&self) -> Result<String> {
+            let response = self
+                .http_client
+                .get(&self.gcp_metadata_token_url)
+                .header("Metadata-Flavor", "Google")
+                .send()
+                .await
+                .context(
+                    "Failed to get an access token from the GCP metadata server; \
+                     set GCP_ACCESS_TOKEN outside Google Cloud",
+                )?;
+            let body = json_body(response, "GCP metadata server").await?;
+            body["access_token"]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| anyhow::anyhow!("GCP metadata server returned no access token"))
+        }
+
+        async fn fetch_vault(&self, mount: &str, path: &str, key: &str) -> Result<String> {
+            let vault_token = self
+                .config
+                .vault_token
+                .as_deref()
+                .ok_or_else(|| anyhow::anyhow!("VAULT_TOKEN not set"))?;
+            let response = self
+                .http_client
+                .get(format!(
+                    "{}/v1/{mount}/data/{path}",
+                    self.config.vault_addr.trim_end_matches('/')
+                ))
+                .header("X-Vault-Token", vault_token)
+                .send()
+                .await
+                .context("Failed to reach Vault")?;
+            let body = json_body(response, "Vault").await?;
+            body["data"]["data"][key]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| anyhow::anyhow!("Vault secret {mount}/{path} has no key '{key}'"))
+        }
+    }
+
+    /// The JSON body of a successful response; errors carry the status but never the body,
+    /// which could echo credentials
+    async fn json_body(response: reqwest::Response, service: &str) -> Result<Value> {
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!("{service} returned {status}");
+        }
+        response
+            .json()
+            .await
+            .with_context(|| format!("Invalid response from {service}"))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn client(server: &mockito::Server, config: SecretManagerConfig) -> SecretClient {
+            SecretClient {
+                http_client: reqwest::Client::new(),
+                config: SecretManagerConfig {
+                    vault_addr: server.url(),
+                    ..config
+                },
+                gcp_secret_manager_url: format!("{}/v1", server.url()),
+                gcp_metadata_token_url: format!("{}/token", server.url()),
+            }
+        }
+
+        #[tokio::test]
+        async fn test_fetch_gcp_secret_with_metadata_token() {
+            let mut server = mockito::Server::new_async().await;
+            let metadata = server
+                .mock("GET", "/token")
+                .match_header("Metadata-Flavor", "Google")
+                .with_body(r#"{"access_token": "gcp-token"}"#)
+                .create_async()
+                .await;
+            let secret = server
+                .mock(
+                    "GET",
+                    "/v1/projects/p/secrets/client-id/versions/latest:access",
+                )
+                .match_header("authorization", "Bearer gcp-token")
+                .with_body(format!(
+                    r#"{{"payload": {{"data": "{}"}}}}"#,
+                    STANDARD.encode("id.apps.googleusercontent.com\n")
+                ))
+                .create_async()
+                .await;
+
+            let client = client(&server, SecretManagerConfig::default());
+            let value = client
+                .fetch(&SecretRef::parse("gcp-sm://projects/p/secrets/client-id").unwrap())
+                .await
+                .unwrap();
+            assert_eq!(value, "id.apps.googleusercontent.com");
+            metadata.assert_async().await;
+            secret.assert_async().await;
+        }
+
+        #[tokio::test]
+        async fn test_fetch_vault_secret() {
+            let mut server = mockito::Server::new_async().await;
+            server
+                .mock("GET", "/v1/secret/data/gmail-mcp")
+                .match_header("X-Vault-Token", "vault-token")
+                .with_body(r#"{"data": {"data": {"client_secret": "s3cret"}}}"#)
+                .create_async()
+                .await;
+            let config = SecretManagerConfig {
+                vault_token: Some("vault-token".to_string()),
+                ..Default::default()
+            };
+
+            let client = client(&server, config);
+            let secret = SecretRef::parse("vault://secret/gmail-mcp#client_secret").unwrap();
+            assert_eq!(client.fetch(&secret).await.unwrap(), "s3cret");
+            let missing = SecretRef::parse("vault://secret/gmail-mcp#client_id").unwrap();
+            assert!(client.fetch(&missing).await.is_err());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_secret_refs() {
+        assert_eq!(
+            SecretRef::parse("gcp-sm://projects/p/secrets/s").unwrap(),
+            SecretRef::Gcp("projects/p/secrets/s/versions/latest".to_string())
+        );
+        assert_eq!(
+            SecretRef::parse("gcp-sm://projects/p/secrets/s/versions/3").unwrap(),
+            SecretRef::Gcp("projects/p/secrets/s/versions/3".to_string())
+        );
+        assert_eq!(
+            SecretRef::parse("vault://secret/apps/gmail#client_id").unwrap(),
+            SecretRef::Vault {
+                mount: "secret".to_string(),
+                path: "apps/gmail".to_string(),
+                key: "client_id".to_string(),
+            }
+        );
+        assert!(SecretRef::parse("gcp-sm://projects/p").is_err());
+        assert!(SecretRef::parse("vault://secret#client_id").is_err());
+        assert!(SecretRef::parse("vault://secret/gmail").is_err());
+        assert!(SecretRef::parse("https://example.com/secret").is_err());
+    }
+
+    #[test]
+    fn test_client_credentials_report_rotation() {
+        let credentials = ClientCredentials::default();
+        assert!(credentials.get().is_none());
+        assert!(!credentials.set("id".to_string(), "secret".to_string()));
+        assert!(!credentials.set("id".to_string(), "secret".to_string()));
+        assert!(credentials.set("id".to_string(), "rotated".to_string()));
+        assert_eq!(
+            credentials.get(),
+            Some(("id".to_string(), "rotated".to_string()))
+        );
+        assert_eq!(format!("{credentials:?}"), "ClientCredentials { .. }");
+    }
+
+    #[tokio::test]
+    async fn test_configured_values_without_secret_refs() {
+        let mut config = Config {
+            gmail_client_id: Some("id".to_string()),
+            gmail_client_secret: Some("secret".to_string()),
+            ..Default::default()
+        };
+        resolve_client_credentials(&mut config).await.unwrap();
+        assert!(config.client_credentials.get().is_none());
+        assert_eq!(
+            fetch_client_credentials(&config).await.unwrap(),
+            ("id".to_string(), "secret".to_string())
+        );
+    }
+}
@@ -1,39 +1,330 @@
-use crate::gmail::GmailServer;
+use crate::accounts::Accounts;
+use crate::audit::AuditEntry;
+use crate::caller_tokens::{self, CallerTokens};
+use crate::config::HttpConfig;
+use crate::export::ExportFormat;
+use crate::gmail::{ErrorKind, GmailError, GmailServer};
+use crate::sessions::SessionAccounts;
+use crate::telemetry;
+use crate::tools::{Category, MessageFormat};
+use crate::webhooks::WebhookEvent;
 use rmcp::{
-    handler::server::{router::tool::ToolRouter, wrapper::Parameters},
+    handler::server::{
+        common::Extension, router::tool::ToolRouter, tool::ToolCallContext, wrapper::Parameters,
+    },
     model::*,
     schemars,
     service::RequestContext,
-    tool, tool_handler, tool_router, ErrorData as McpError, RoleServer, ServerHandler,
+    tool, tool_router, ErrorData as McpError, RoleServer, ServerHandler,
 };
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use tracing::error;
+use serde_json::Value;
+use std::sync::{Arc, LazyLock};
+use std::time::{Instant, SystemTime};
+use tracing::{error, info_span, Instrument};
+
+/// JSON-RPC error code of tool calls that need the user to log in (again)
+const AUTH_REQUIRED: ErrorCode = ErrorCode(-32001);
+/// JSON-RPC error code of tool calls Gmail throttled, to retry after backing off
+const RATE_LIMITED: ErrorCode = ErrorCode(-32003);
 
 #[derive(Clone)]
 pub struct GmailMcpServer {
-    gmail_server: Arc<GmailServer>,
+    accounts: Arc<Accounts>,
+    /// Per-session tokens, in multi-user mode
+    sessions: Option<Arc<SessionAccounts>>,
+    /// Trusted caller-supplied access tokens, with `--trust-caller-tokens`
+    caller_tokens: Option<Arc<CallerTokens>>,
+    /// Used to build the login URLs that grant missing scopes
+    http_config: HttpConfig,
+    /// Whether the tools that change the mailbox were removed, with `--read-only`
+    read_only: bool,
     tool_router: ToolRouter<GmailMcpServer>,
 }
 
+/// Who made a tool call, added to the call's extensions by `call_tool`
+#[derive(Clone)]
+struct Caller {
+    /// The MCP session the call arrived on
+    session_id: Option<String>,
+    /// Google access token from the request's `Authorization: Bearer` header
+    access_token: Option<String>,
+}
+
+impl Caller {
+    /// The caller of a request, from the HTTP parts streamable HTTP requests carry
+    fn of(context: &RequestContext<RoleServer>) -> Self {
+        let parts = context.extensions.get::<axum::http::request::Parts>();
+        Self {
+            session_id: parts
+                .and_then(|parts| telemetry::session_id(&parts.headers, &parts.uri))
+                .map(str::to_string),
+            access_token: parts
+                .and_then(|parts| caller_tokens::bearer_token(&parts.headers))
+                .map(str::to_string),
+        }
+    }
+}
+
+/// Tools that change the mailbox or what the server will do with it, refused with `--read-only`.
+/// Every tool not annotated `read_only_hint = true` counts, so a new tool is refused until it's
+/// marked as only reading
+pub fn mutating_tools() -> &'static [String] {
+    static TOOLS: LazyLock<Vec<String>> = LazyLock::new(|| {
+        GmailMcpServer::tool_router()
+            .list_all()
+            .into_iter()
+            .filter(|tool| {
+                tool.annotations
+                    .as_ref()
+                    .and_then(|annotations| annotations.read_only_hint)
+                    != Some(true)
+            })
+            .map(|tool| tool.name.to_string())
+            .collect()
+    });
+    &TOOLS
+}
+
+/// Whether `--read-only` refuses the tool
+pub fn is_mutating(tool: &str) -> bool {
+    mutating_tools().iter().any(|mutating| mutating == tool)
+}
+
+impl GmailMcpServer {
+    /// Remove the tools that change the mailbox, for `--read-only`
+    pub fn read_only(mut self) -> Self {
+        for tool in mutating_tools() {
+            self.tool_router.remove_route(tool);
+        }
+        self.read_only = true;
+        self
+    }
+
+    /// The tools served, with the JSON schemas of their arguments
+    pub fn tools(&self) -> Vec<Tool> {
+        self.tool_router.list_all()
+    }
+
+    /// The server holding the caller's token, acting on the mailbox `user_id` when the call names
+    /// one and otherwise on the server's --gmail-user-id
+    async fn gmail_server(
+        &self,
+        caller: &Caller,
+        account: Option<&str>,
+        user_id: Option<&str>,
+    ) -> Result<Arc<GmailServer>, McpError> {
+        let gmail_server = self.token_server(caller, account).await?;
+        match user_id {
+            Some(user_id) => gmail_server
+                .for_user(user_id)
+                .map(Arc::new)
+                .map_err(|e| McpError::invalid_params(e.to_string(), None)),
+            None => Ok(gmail_server),
+        }
+    }
+
+    /// The server holding the caller's token: the token the caller sent when trusted, the
+    /// session's own in multi-user mode, otherwise the account the call names or the default one
+    async fn token_server(
+        &self,
+        caller: &Caller,
+        account: Option<&str>,
+    ) -> Result<Arc<GmailServer>, McpError> {
+        if let (Some(caller_tokens), Some(access_token)) =
+            (&self.caller_tokens, &caller.access_token)
+        {
+            if account.is_some() {
+                return Err(McpError::invalid_params(
+                    "The account argument can't be combined with a caller-supplied token",
+                    None,
+                ));
+            }
+            return caller_tokens
+                .gmail_server(access_token)
+                .await
+                .map_err(|e| McpError::internal_error(e.to_string(), None));
+        }
+
+        let Some(sessions) = &self.sessions else {
+            return self
+                .accounts
+                .get(account)
+                .map(|account| account.gmail_server.clone())
+                .map_err(|e| McpError::invalid_params(e.to_string(), None));
+        };
+
+        if account.is_some() {
+            return Err(McpError::invalid_params(
+                "The account argument is not available in multi-user mode",
+                None,
+            ));
+        }
+        let Some(session_id) = &caller.session_id else {
+            return Err(McpError::invalid_request(
+                "Multi-user mode requires an MCP session",
+                None,
+            ));
+        };
+        match sessions.get(session_id).await {
+            Some(gmail_server) => Ok(gmail_server),
+            None => {
+                let login_url = sessions.login_url(session_id);
+                Err(McpError::new(
+                    AUTH_REQUIRED,
+                    ErrorKind::AuthRequired.as_str(),
+                    Some(serde_json::json!({
+                        "error": format!("Not logged in for this session. Open {login_url} to authorize Gmail access"),
+                        "login_url": login_url,
+                    })),
+                ))
+            }
+        }
+    }
+
+    /// A failed tool call as an MCP error. Failures the Gmail client layer recognizes carry
+    /// their kind as the message: `auth_required`, `insufficient_scope`, `not_found`,
+    /// `rate_limited` or `invalid_argument`. Anything else is an internal error named by `code`.
+    /// `auth_required` errors include the login URL, unless the caller supplied the token.
+    fn tool_error(
+        &self,
+        caller: &Caller,
+        account: Option<&str>,
+        code: &str,
+        error: anyhow::Error,
+    ) -> McpError {
+        if let Some(GmailError::InsufficientScope(missing)) = error.downcast_ref::<GmailError>() {
+            return self.scope_error(caller, account, &error, missing);
+        }
+        let kind = ErrorKind::of(&error);
+        let error_code = match kind {
+            ErrorKind::AuthRequired => AUTH_REQUIRED,
+            ErrorKind::NotFound => ErrorCode::RESOURCE_NOT_FOUND,
+            ErrorKind::RateLimited => RATE_LIMITED,
+            ErrorKind::InvalidArgument => ErrorCode::INVALID_PARAMS,
+            ErrorKind::InsufficientScope | ErrorKind::Other => {
+                return McpError::internal_error(
+                    code.to_string(),
+                    Some(serde_json::json!({ "error": error.to_string() })),
+                )
+            }
+        };
+        let mut data = serde_json::json!({ "error": error.to_string(), "code": code });
+        if kind == ErrorKind::AuthRequired {
+            if let Some(login_url) = self.login_url(caller, account, None) {
+                data["error"] = serde_json::json!(format!("{error}. Open {login_url} to log in"));
+                data["login_url"] = serde_json::json!(login_url);
+            }
+        }
+        match error.downcast_ref::<GmailError>() {
+            Some(GmailError::NotAuthenticated(failure)) => {
+                data["reason"] = serde_json::json!(failure.as_str());
+            }
+            Some(GmailError::Api {
+                status,
+                reason,
+                retry_after,
+                ..
+            }) => {
+                data["status"] = serde_json::json!(status.as_u16());
+                data["reason"] = serde_json::json!(reason);
+                if let Some(retry_after) = retry_after {
+                    data["retry_after_secs"] = serde_json::json!(retry_after.as_secs());
+                }
+            }
+            _ => {}
+        }
+        McpError::new(error_code, kind.as_str(), Some(data))
+    }
+
+    /// Where the user logs in to the token a call used, asking for `scope` on top of the ones
+    /// already granted. None for a caller-supplied token, which only the caller can replace
+    fn login_url(
+        &self,
+        caller: &Caller,
+        account: Option<&str>,
+        scope: Option<&str>,
+    ) -> Option<String> {
+        if self.caller_tokens.is_some() && caller.access_token.is_some() {
+            return None;
+        }
+        let mut params = match (&self.sessions, &caller.session_id) {
+            (Some(_), Some(session_id)) => vec![("session", session_id.as_str())],
+            _ => vec![("account", self.accounts.name(account))],
+        };
+        params.extend(scope.map(|scope| ("scope", scope)));
+        Some(crate::auth::login_url(&self.http_config, &params))
+    }
+
+    /// A call missing an OAuth scope as an `insufficient_scope` error, naming the scopes and the
+    /// login URL that grants them
+    fn scope_error(
+        &self,
+        caller: &Caller,
+        account: Option<&str>,
+        error: &anyhow::Error,
+        missing: &[String],
+    ) -> McpError {
+        // A caller-supplied token can only be replaced by the caller
+        if self.caller_tokens.is_some() && caller.access_token.is_some() {
+            return McpError::invalid_request(
+                "insufficient_scope",
+                Some(serde_json::json!({
+                    "error": format!("The caller-supplied token doesn't grant {}", missing.join(", ")),
+                    "missing_scopes": missing,
+                })),
+            );
+        }
+        let login_url = self
+            .login_url(caller, account, Some(&missing.join(" ")))
+            .expect("caller-supplied tokens are handled above");
+        McpError::invalid_request(
+            "insufficient_scope",
+            Some(serde_json::json!({
+                "error": format!("{error}. Open {login_url} to grant it"),
+                "missing_scopes": missing,
+                "login_url": login_url,
+            })),
+        )
+    }
+}
+
 #[tool_router]
 impl GmailMcpServer {
-    pub fn new(gmail_server: Arc<GmailServer>) -> Self {
+    pub fn new(
+        accounts: Arc<Accounts>,
+        sessions: Option<Arc<SessionAccounts>>,
+        caller_tokens: Option<Arc<CallerTokens>>,
+        http_config: HttpConfig,
+    ) -> Self {
         Self {
-            gmail_server,
+            accounts,
+            sessions,
+            caller_tokens,
+            http_config,
+            read_only: false,
             tool_router: Self::tool_router(),
         }
     }
 
-    #[tool(description = "Search Gmail threads using a query string")]
+    #[tool(
+        description = "Search Gmail threads using a query string",
+        annotations(read_only_hint = true)
+    )]
     async fn search_threads(
         &self,
         Parameters(args): Parameters<SearchThreadsArgs>,
+        Extension(caller): Extension<Caller>,
     ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), args.user_id.as_deref())
+            .await?;
         match crate::tools::search_threads(
-            &self.gmail_server,
+            &gmail_server,
             &args.query,
             args.max_results.unwrap_or(10),
+            args.fields.as_deref(),
+            &args.filter(),
         )
         .await
         {
@@ -42,25 +333,31 @@ impl GmailMcpServer {
             )])),
             Err(e) => {
                 error!("Failed to search threads: {}", e);
-                Err(McpError::internal_error(
-                    "search_failed",
-                    Some(serde_json::json!({ "error": e.to_string() })),
-                ))
+                Err(self.tool_error(&caller, args.account.as_deref(), "search_failed", e))
             }
         }
     }
 
-    #[tool(description = "Create a Gmail draft")]
+    #[tool(
+        description = "Create a Gmail draft",
+        annotations(read_only_hint = false)
+    )]
     async fn create_draft(
         &self,
         Parameters(args): Parameters<CreateDraftArgs>,
+        Extension(caller): Extension<Caller>,
     ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), args.user_id.as_deref())
+            .await?;
         match crate::tools::create_draft(
-            &self.gmail_server,
+            &gmail_server,
             &args.to,
             &args.subject,
             &args.body,
             args.thread_id.as_deref(),
+            args.exclude_own_address.unwrap_or_default(),
+            args.dry_run.unwrap_or_default(),
         )
         .await
         {
@@ -69,23 +366,28 @@ impl GmailMcpServer {
             )])),
             Err(e) => {
                 error!("Failed to create draft: {}", e);
-                Err(McpError::internal_error(
-                    "create_draft_failed",
-                    Some(serde_json::json!({ "error": e.to_string() })),
-                ))
+                Err(self.tool_error(&caller, args.account.as_deref(), "create_draft_failed", e))
             }
         }
     }
 
-    #[tool(description = "Extract text from an email attachment by filename")]
+    #[tool(
+        description = "Extract text from an email attachment by filename",
+        annotations(read_only_hint = true)
+    )]
     async fn extract_attachment_by_filename(
         &self,
         Parameters(args): Parameters<ExtractAttachmentArgs>,
+        Extension(caller): Extension<Caller>,
     ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), args.user_id.as_deref())
+            .await?;
         match crate::tools::extract_attachment_by_filename(
-            &self.gmail_server,
+            &gmail_server,
             &args.message_id,
             &args.filename,
+            args.preview_chars,
         )
         .await
         {
@@ -94,40 +396,65 @@ impl GmailMcpServer {
             )])),
             Err(e) => {
                 error!("Failed to extract attachment: {}", e);
-                Err(McpError::internal_error(
+                Err(self.tool_error(
+                    &caller,
+                    args.account.as_deref(),
                     "extract_attachment_failed",
-                    Some(serde_json::json!({ "error": e.to_string() })),
+                    e,
                 ))
             }
         }
     }
 
-    #[tool(description = "Fetch email bodies for thread IDs")]
+    #[tool(
+        description = "Fetch email bodies for thread IDs",
+        annotations(read_only_hint = true)
+    )]
     async fn fetch_email_bodies(
         &self,
         Parameters(args): Parameters<FetchEmailBodiesArgs>,
+        Extension(caller): Extension<Caller>,
     ) -> Result<CallToolResult, McpError> {
-        match crate::tools::fetch_email_bodies(&self.gmail_server, &args.thread_ids).await {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), args.user_id.as_deref())
+            .await?;
+        match crate::tools::fetch_email_bodies(
+            &gmail_server,
+            &args.thread_ids,
+            args.format.unwrap_or_default(),
+            args.fields.as_deref(),
+        )
+        .await
+        {
             Ok(result) => Ok(CallToolResult::success(vec![Content::text(
                 serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
             )])),
             Err(e) => {
                 error!("Failed to fetch email bodies: {}", e);
-                Err(McpError::internal_error(
+                Err(self.tool_error(
+                    &caller,
+                    args.account.as_deref(),
                     "fetch_email_bodies_failed",
-                    Some(serde_json::json!({ "error": e.to_string() })),
+                    e,
                 ))
             }
         }
     }
 
-    #[tool(description = "Download an attachment to a local file")]
+    #[tool(
+        description = "Download an attachment to a local file",
+        annotations(read_only_hint = true)
+    )]
     async fn download_attachment(
         &self,
         Parameters(args): Parameters<DownloadAttachmentArgs>,
+        Extension(caller): Extension<Caller>,
     ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), args.user_id.as_deref())
+            .await?;
         match crate::tools::download_attachment(
-            &self.gmail_server,
+            &gmail_server,
             &args.message_id,
             &args.filename,
             args.download_dir.as_deref(),
@@ -139,25 +466,33 @@ impl GmailMcpServer {
             )])),
             Err(e) => {
                 error!("Failed to download attachment: {}", e);
-                Err(McpError::internal_error(
+                Err(self.tool_error(
+                    &caller,
+                    args.account.as_deref(),
                     "download_attachment_failed",
-                    Some(serde_json::json!({ "error": e.to_string() })),
+                    e,
                 ))
             }
         }
     }
 
-    #[tool(description = "Forward an email")]
+    #[tool(description = "Forward an email", annotations(read_only_hint = false))]
     async fn forward_email(
         &self,
         Parameters(args): Parameters<ForwardEmailArgs>,
+        Extension(caller): Extension<Caller>,
     ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), args.user_id.as_deref())
+            .await?;
         match crate::tools::forward_email(
-            &self.gmail_server,
+            &gmail_server,
             &args.message_id,
             &args.to,
             &args.subject,
             &args.body,
+            args.dry_run.unwrap_or_default(),
+            args.enqueue_if_unauthenticated.unwrap_or_default(),
         )
         .await
         {
@@ -166,28 +501,657 @@ impl GmailMcpServer {
             )])),
             Err(e) => {
                 error!("Failed to forward email: {}", e);
-                Err(McpError::internal_error(
-                    "forward_email_failed",
-                    Some(serde_json::json!({ "error": e.to_string() })),
-                ))
+                Err(self.tool_error(&caller, args.account.as_deref(), "forward_email_failed", e))
             }
         }
     }
 
-    #[tool(description = "Send a draft email")]
+    #[tool(
+        description = "Send a draft email",
+        annotations(read_only_hint = false)
+    )]
     async fn send_draft(
         &self,
         Parameters(args): Parameters<SendDraftArgs>,
+        Extension(caller): Extension<Caller>,
     ) -> Result<CallToolResult, McpError> {
-        match crate::tools::send_draft(&self.gmail_server, &args.draft_id).await {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), args.user_id.as_deref())
+            .await?;
+        match crate::tools::send_draft(
+            &gmail_server,
+            &args.draft_id,
+            args.dry_run.unwrap_or_default(),
+            args.enqueue_if_unauthenticated.unwrap_or_default(),
+        )
+        .await
+        {
             Ok(result) => Ok(CallToolResult::success(vec![Content::text(
                 serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
             )])),
             Err(e) => {
                 error!("Failed to send draft: {}", e);
-                Err(McpError::internal_error(
-                    "send_draft_failed",
-                    Some(serde_json::json!({ "error": e.to_string() })),
+                Err(self.tool_error(&caller, args.account.as_deref(), "send_draft_failed", e))
+            }
+        }
+    }
+
+    #[tool(
+        description = "Find bounces and delivery status notifications for a sent message or a \
+        recipient, with each failed recipient's status code, action and reason",
+        annotations(read_only_hint = true)
+    )]
+    async fn find_bounces(
+        &self,
+        Parameters(args): Parameters<FindBouncesArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), args.user_id.as_deref())
+            .await?;
+        match crate::bounces::find_bounces(
+            &gmail_server,
+            args.message_id.as_deref(),
+            args.recipient.as_deref(),
+            args.max_results.unwrap_or(10),
+        )
+        .await
+        {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to find bounces: {}", e);
+                Err(self.tool_error(&caller, args.account.as_deref(), "find_bounces_failed", e))
+            }
+        }
+    }
+
+    #[tool(
+        description = "Get the whole conversation a message belongs to, oldest first, following \
+        its Message-ID, In-Reply-To and References headers across the threads Gmail split it into",
+        annotations(read_only_hint = true)
+    )]
+    async fn get_conversation(
+        &self,
+        Parameters(args): Parameters<GetConversationArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), args.user_id.as_deref())
+            .await?;
+        match crate::conversation::get_conversation(
+            &gmail_server,
+            &args.message_id,
+            args.format.unwrap_or_default(),
+            args.max_threads.unwrap_or(10),
+        )
+        .await
+        {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to get conversation: {}", e);
+                Err(self.tool_error(
+                    &caller,
+                    args.account.as_deref(),
+                    "get_conversation_failed",
+                    e,
+                ))
+            }
+        }
+    }
+
+    #[tool(
+        description = "Save a thread as one self-contained HTML file or PDF in the download \
+        directory: every message with its headers, body, inline images and attachment list",
+        annotations(read_only_hint = true)
+    )]
+    async fn export_thread(
+        &self,
+        Parameters(args): Parameters<ExportThreadArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), args.user_id.as_deref())
+            .await?;
+        match crate::export::export_thread(
+            &gmail_server,
+            &args.thread_id,
+            args.format.unwrap_or_default(),
+            args.download_dir.as_deref(),
+        )
+        .await
+        {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to export thread: {}", e);
+                Err(self.tool_error(&caller, args.account.as_deref(), "export_thread_failed", e))
+            }
+        }
+    }
+
+    #[tool(
+        description = "Get one message as a markdown document: front matter with its headers and \
+        labels, its body converted from HTML, and its attachments with their sizes, ready to paste \
+        into a prompt or a notes app",
+        annotations(read_only_hint = true)
+    )]
+    async fn email_to_markdown(
+        &self,
+        Parameters(args): Parameters<EmailToMarkdownArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), args.user_id.as_deref())
+            .await?;
+        match crate::markdown::email_to_markdown(&gmail_server, &args.message_id).await {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to convert email to markdown: {}", e);
+                Err(self.tool_error(
+                    &caller,
+                    args.account.as_deref(),
+                    "email_to_markdown_failed",
+                    e,
+                ))
+            }
+        }
+    }
+
+    #[tool(
+        description = "List a thread's attachments with duplicates collapsed by content hash, such \
+        as a file re-attached to every reply, and which messages carried each file. Optionally \
+        extracts the text of each unique PDF, DOCX or TXT file once",
+        annotations(read_only_hint = true)
+    )]
+    async fn list_thread_attachments(
+        &self,
+        Parameters(args): Parameters<ListThreadAttachmentsArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), args.user_id.as_deref())
+            .await?;
+        match crate::thread_attachments::list_thread_attachments(
+            &gmail_server,
+            &args.thread_id,
+            args.extract_text.unwrap_or(false),
+            args.preview_chars,
+        )
+        .await
+        {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to list thread attachments: {}", e);
+                Err(self.tool_error(
+                    &caller,
+                    args.account.as_deref(),
+                    "list_thread_attachments_failed",
+                    e,
+                ))
+            }
+        }
+    }
+
+    #[tool(
+        description = "Extract the text of the Google Docs, Sheets and Slides decks linked from a \
+        message, which Gmail sends as Drive links rather than attachments. Docs and Slides come \
+        back as plain text and Sheets as CSV. Needs the drive.readonly scope",
+        annotations(read_only_hint = true)
+    )]
+    async fn extract_drive_attachments(
+        &self,
+        Parameters(args): Parameters<ExtractDriveAttachmentsArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), args.user_id.as_deref())
+            .await?;
+        match crate::drive::extract_drive_attachments(
+            &gmail_server,
+            &args.message_id,
+            args.preview_chars,
+        )
+        .await
+        {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to extract Drive attachments: {}", e);
+                Err(self.tool_error(
+                    &caller,
+                    args.account.as_deref(),
+                    "extract_drive_attachments_failed",
+                    e,
+                ))
+            }
+        }
+    }
+
+    #[tool(
+        description = "Get what changed in a thread since a historyId seen earlier (e.g., from \
+        search_threads): new messages, deleted messages and label changes, with the historyId to \
+        pass next time, instead of refetching the whole thread",
+        annotations(read_only_hint = true)
+    )]
+    async fn get_thread_changes(
+        &self,
+        Parameters(args): Parameters<GetThreadChangesArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), args.user_id.as_deref())
+            .await?;
+        match crate::thread_changes::get_thread_changes(
+            &gmail_server,
+            &args.thread_id,
+            &args.history_id,
+            args.format.unwrap_or_default(),
+        )
+        .await
+        {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to get thread changes: {}", e);
+                Err(self.tool_error(
+                    &caller,
+                    args.account.as_deref(),
+                    "get_thread_changes_failed",
+                    e,
+                ))
+            }
+        }
+    }
+
+    #[tool(
+        description = "Run the server's classifier hook on a message and return the labels and \
+        scores it assigns, without applying them",
+        annotations(read_only_hint = true)
+    )]
+    async fn classify_message(
+        &self,
+        Parameters(args): Parameters<ClassifyMessageArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), args.user_id.as_deref())
+            .await?;
+        match crate::classifier::classify_message(&gmail_server, &args.message_id).await {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to classify message: {}", e);
+                Err(self.tool_error(
+                    &caller,
+                    args.account.as_deref(),
+                    "classify_message_failed",
+                    e,
+                ))
+            }
+        }
+    }
+
+    #[tool(
+        description = "Score how likely a message is phishing or spam, from 0 to 100 with a low, \
+        medium or high risk level, and list the reasons: failed SPF, DKIM or DMARC checks, a \
+        Reply-To or sender name pointing away from the sender's domain, and links whose text \
+        shows another domain or that go to IP addresses, lookalike or shortened domains",
+        annotations(read_only_hint = true)
+    )]
+    async fn score_phishing_risk(
+        &self,
+        Parameters(args): Parameters<ScorePhishingRiskArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), args.user_id.as_deref())
+            .await?;
+        match crate::phishing::score_phishing_risk(&gmail_server, &args.message_id).await {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to score phishing risk: {}", e);
+                Err(self.tool_error(
+                    &caller,
+                    args.account.as_deref(),
+                    "score_phishing_risk_failed",
+                    e,
+                ))
+            }
+        }
+    }
+
+    #[tool(
+        description = "Snooze a thread: take it out of the inbox under the Snoozed label and put \
+        it back at a given time, optionally marked unread",
+        annotations(read_only_hint = false)
+    )]
+    async fn snooze_thread(
+        &self,
+        Parameters(args): Parameters<SnoozeThreadArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), None)
+            .await?;
+        match crate::snooze::snooze_thread(
+            &gmail_server,
+            &args.thread_id,
+            &args.until,
+            args.mark_unread.unwrap_or_default(),
+        )
+        .await
+        {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to snooze thread: {}", e);
+                Err(self.tool_error(&caller, args.account.as_deref(), "snooze_thread_failed", e))
+            }
+        }
+    }
+
+    #[tool(
+        description = "List snoozed threads, soonest to wake first",
+        annotations(read_only_hint = true)
+    )]
+    async fn list_snoozed(
+        &self,
+        Parameters(args): Parameters<ListSnoozedArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), None)
+            .await?;
+        match crate::snooze::list_snoozed(&gmail_server).await {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to list snoozed threads: {}", e);
+                Err(self.tool_error(&caller, args.account.as_deref(), "list_snoozed_failed", e))
+            }
+        }
+    }
+
+    #[tool(
+        description = "List the triage rules the server applies to new mail",
+        annotations(read_only_hint = true)
+    )]
+    async fn list_rules(
+        &self,
+        Parameters(args): Parameters<ListRulesArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), None)
+            .await?;
+        match crate::rules::list_rules(&gmail_server).await {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to list rules: {}", e);
+                Err(self.tool_error(&caller, args.account.as_deref(), "list_rules_failed", e))
+            }
+        }
+    }
+
+    #[tool(
+        description = "Add a triage rule: when new mail matches a Gmail search, label, archive, \
+        forward it or notify a webhook. Replaces the rule of the same name",
+        annotations(read_only_hint = false)
+    )]
+    async fn add_rule(
+        &self,
+        Parameters(args): Parameters<AddRuleArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), None)
+            .await?;
+        let account = args.account.clone();
+        match crate::rules::add_rule(&gmail_server, args.into_rule()).await {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to add rule: {}", e);
+                Err(self.tool_error(&caller, account.as_deref(), "add_rule_failed", e))
+            }
+        }
+    }
+
+    #[tool(
+        description = "Remove a triage rule by name",
+        annotations(read_only_hint = false)
+    )]
+    async fn remove_rule(
+        &self,
+        Parameters(args): Parameters<RemoveRuleArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), None)
+            .await?;
+        match crate::rules::remove_rule(&gmail_server, &args.name).await {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to remove rule: {}", e);
+                Err(self.tool_error(&caller, args.account.as_deref(), "remove_rule_failed", e))
+            }
+        }
+    }
+
+    #[tool(
+        description = "List the latest runs of the scheduled label and archive jobs, newest first, \
+        with how many messages each acted on and any error",
+        annotations(read_only_hint = true)
+    )]
+    async fn list_job_runs(
+        &self,
+        Parameters(args): Parameters<ListJobRunsArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), None)
+            .await?;
+        match crate::jobs::list_job_runs(
+            &gmail_server,
+            args.job.as_deref(),
+            args.limit.unwrap_or(20),
+        )
+        .await
+        {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to list job runs: {}", e);
+                Err(self.tool_error(&caller, args.account.as_deref(), "list_job_runs_failed", e))
+            }
+        }
+    }
+
+    #[tool(
+        description = "List the sends queued in the outbox, oldest first, with their attempts and         when each is retried next",
+        annotations(read_only_hint = true)
+    )]
+    async fn list_outbox(
+        &self,
+        Parameters(args): Parameters<ListOutboxArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), None)
+            .await?;
+        match crate::outbox::list_outbox(&gmail_server).await {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to list outbox: {}", e);
+                Err(self.tool_error(&caller, args.account.as_deref(), "list_outbox_failed", e))
+            }
+        }
+    }
+
+    #[tool(
+        description = "Cancel a queued, failed or unconfirmed send in the outbox so it's never \
+        sent",
+        annotations(read_only_hint = false)
+    )]
+    async fn cancel_outbox_message(
+        &self,
+        Parameters(args): Parameters<CancelOutboxMessageArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), None)
+            .await?;
+        match crate::outbox::cancel_outbox_message(&gmail_server, args.outbox_id).await {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to cancel outbox message: {}", e);
+                Err(self.tool_error(
+                    &caller,
+                    args.account.as_deref(),
+                    "cancel_outbox_message_failed",
+                    e,
+                ))
+            }
+        }
+    }
+
+    #[tool(
+        description = "List the saved searches, named Gmail queries kept for reuse",
+        annotations(read_only_hint = true)
+    )]
+    async fn list_saved_searches(
+        &self,
+        Parameters(args): Parameters<ListSavedSearchesArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), None)
+            .await?;
+        match crate::saved_searches::list_saved_searches(&gmail_server).await {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to list saved searches: {}", e);
+                Err(self.tool_error(
+                    &caller,
+                    args.account.as_deref(),
+                    "list_saved_searches_failed",
+                    e,
+                ))
+            }
+        }
+    }
+
+    #[tool(
+        description = "Save a Gmail query under a name, so later calls can reuse it instead of \
+        rebuilding it",
+        annotations(read_only_hint = false)
+    )]
+    async fn save_search(
+        &self,
+        Parameters(args): Parameters<SaveSearchArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), None)
+            .await?;
+        let account = args.account.clone();
+        match crate::saved_searches::save_search(&gmail_server, args.into_search()).await {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to save search: {}", e);
+                Err(self.tool_error(&caller, account.as_deref(), "save_search_failed", e))
+            }
+        }
+    }
+
+    #[tool(
+        description = "Change the query or description of a saved search",
+        annotations(read_only_hint = false)
+    )]
+    async fn update_saved_search(
+        &self,
+        Parameters(args): Parameters<UpdateSavedSearchArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), None)
+            .await?;
+        match crate::saved_searches::update_saved_search(
+            &gmail_server,
+            &args.name,
+            args.query.clone(),
+            args.description.clone(),
+        )
+        .await
+        {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to update saved search: {}", e);
+                Err(self.tool_error(
+                    &caller,
+                    args.account.as_deref(),
+                    "update_saved_search_failed",
+                    e,
+                ))
+            }
+        }
+    }
+
+    #[tool(
+        description = "Delete a saved search by name",
+        annotations(read_only_hint = false)
+    )]
+    async fn delete_saved_search(
+        &self,
+        Parameters(args): Parameters<DeleteSavedSearchArgs>,
+        Extension(caller): Extension<Caller>,
+    ) -> Result<CallToolResult, McpError> {
+        let gmail_server = self
+            .gmail_server(&caller, args.account.as_deref(), None)
+            .await?;
+        match crate::saved_searches::delete_saved_search(&gmail_server, &args.name).await {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                serde_json::to_string_pretty(&result).unwrap_or_else(|e| format!("Error: {e}")),
+            )])),
+            Err(e) => {
+                error!("Failed to delete saved search: {}", e);
+                Err(self.tool_error(
+                    &caller,
+                    args.account.as_deref(),
+                    "delete_saved_search_failed",
+                    e,
                 ))
             }
         }
@@ -196,11 +1160,66 @@ impl GmailMcpServer {
 
 #[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
 pub struct SearchThreadsArgs {
-    /// Gmail search query (e.g., "from:example@gmail.com", "subject:meeting")
+    /// Gmail search query (e.g., "from:example@gmail.com", "subject:meeting"). `@name` stands
+    /// for the saved search of that name
     pub query: String,
     /// Maximum number of results to return (default: 10)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub max_results: Option<i64>,
+    /// Optional partial response selector (e.g., "threads(id,snippet),nextPageToken")
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<String>,
+    /// Only return messages from the last this many days, counted back from now
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_n_days: Option<u32>,
+    /// Only return messages from this day on, as YYYY-MM-DD (or an RFC 3339 time)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_date: Option<String>,
+    /// Only return messages up to and including this day, as YYYY-MM-DD (or an RFC 3339 time
+    /// to end before)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_date: Option<String>,
+    /// UTC offset such as "+02:00" or "-05:00" whose midnights start_date and end_date mean
+    /// (default: the server's time zone)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub utc_offset: Option<String>,
+    /// Only return messages in this inbox tab: "primary", "social", "promotions", "updates" or
+    /// "forums"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<Category>,
+    /// Only return messages Gmail marked important (true), or only those it didn't (false)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_important: Option<bool>,
+    /// Only return threads with a message whose text body matches this regular expression
+    /// (e.g., "INV-\d{4}-\d{4}"), checked by the server after fetching the hits
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_regex: Option<String>,
+    /// Only return threads with a message having a "Name: value" header line that matches this
+    /// regular expression (e.g., "^X-Mailer: .*Outlook")
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header_regex: Option<String>,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Mailbox to act on: `me`, or a user's address for a service account with domain-wide
+    /// delegation (default: the server's --gmail-user-id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+}
+
+impl SearchThreadsArgs {
+    pub fn filter(&self) -> crate::tools::SearchFilter {
+        crate::tools::SearchFilter {
+            last_n_days: self.last_n_days,
+            start_date: self.start_date.clone(),
+            end_date: self.end_date.clone(),
+            utc_offset: self.utc_offset.clone(),
+            category: self.category,
+            is_important: self.is_important,
+            body_regex: self.body_regex.clone(),
+            header_regex: self.header_regex.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
@@ -214,6 +1233,20 @@ pub struct CreateDraftArgs {
     /// Optional thread ID to reply to
     #[serde(skip_serializing_if = "Option::is_none")]
     pub thread_id: Option<String>,
+    /// Leave the mailbox's own address out of `to`, as reply-all does, unless it's the only
+    /// recipient. The result lists the removed recipients (default: false)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclude_own_address: Option<bool>,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Mailbox to act on: `me`, or a user's address for a service account with domain-wide
+    /// delegation (default: the server's --gmail-user-id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    /// Return the message that would be sent instead of calling Gmail (default: false)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dry_run: Option<bool>,
 }
 
 #[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
@@ -222,12 +1255,36 @@ pub struct ExtractAttachmentArgs {
     pub message_id: String,
     /// Attachment filename
     pub filename: String,
+    /// Return only the first this many characters of the text, with its total length
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview_chars: Option<usize>,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Mailbox to act on: `me`, or a user's address for a service account with domain-wide
+    /// delegation (default: the server's --gmail-user-id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
 pub struct FetchEmailBodiesArgs {
     /// List of thread IDs to fetch
     pub thread_ids: Vec<String>,
+    /// Message format: "full" (default) includes bodies, "metadata" only headers and snippet,
+    /// "minimal" only IDs, labels and snippet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<MessageFormat>,
+    /// Optional partial response selector applied to each message (e.g., "id,snippet,payload/headers")
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<String>,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Mailbox to act on: `me`, or a user's address for a service account with domain-wide
+    /// delegation (default: the server's --gmail-user-id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
@@ -239,6 +1296,13 @@ pub struct DownloadAttachmentArgs {
     /// Optional download directory (default: current directory)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub download_dir: Option<String>,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Mailbox to act on: `me`, or a user's address for a service account with domain-wide
+    /// delegation (default: the server's --gmail-user-id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
@@ -251,31 +1315,502 @@ pub struct ForwardEmailArgs {
     pub subject: String,
     /// Forward body text
     pub body: String,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Mailbox to act on: `me`, or a user's address for a service account with domain-wide
+    /// delegation (default: the server's --gmail-user-id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    /// Return the message that would be sent instead of calling Gmail (default: false)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dry_run: Option<bool>,
+    /// When the account needs to log in, queue the send in the outbox to go out after the next
+    /// login instead of failing; needs the server's --outbox (default: false)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enqueue_if_unauthenticated: Option<bool>,
 }
 
 #[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
 pub struct SendDraftArgs {
     /// Gmail draft ID to send
     pub draft_id: String,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Mailbox to act on: `me`, or a user's address for a service account with domain-wide
+    /// delegation (default: the server's --gmail-user-id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    /// Return the message that would be sent instead of calling Gmail (default: false)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dry_run: Option<bool>,
+    /// When the account needs to log in, queue the send in the outbox to go out after the next
+    /// login instead of failing; needs the server's --outbox (default: false)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enqueue_if_unauthenticated: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct FindBouncesArgs {
+    /// Gmail message ID of the sent message to look for bounces of
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// Recipient address to look for bounces from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recipient: Option<String>,
+    /// Maximum number of bounce messages to examine (default: 10)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_results: Option<u32>,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Mailbox to act on: `me`, or a user's address for a service account with domain-wide
+    /// delegation (default: the server's --gmail-user-id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct GetConversationArgs {
+    /// Gmail message ID of any message in the conversation
+    pub message_id: String,
+    /// Message format: "full" (default), "metadata" or "minimal"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<MessageFormat>,
+    /// Maximum number of threads to gather the conversation from (default: 10)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_threads: Option<usize>,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Mailbox to act on: `me`, or a user's address for a service account with domain-wide
+    /// delegation (default: the server's --gmail-user-id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct ExportThreadArgs {
+    /// Gmail thread ID
+    pub thread_id: String,
+    /// File type: "html" (default) or "pdf"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<ExportFormat>,
+    /// Optional download directory (default: current directory)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_dir: Option<String>,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Mailbox to act on: `me`, or a user's address for a service account with domain-wide
+    /// delegation (default: the server's --gmail-user-id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct EmailToMarkdownArgs {
+    /// Gmail message ID
+    pub message_id: String,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Mailbox to act on: `me`, or a user's address for a service account with domain-wide
+    /// delegation (default: the server's --gmail-user-id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct ListThreadAttachmentsArgs {
+    /// Gmail thread ID
+    pub thread_id: String,
+    /// Whether to extract the text of each unique PDF, DOCX or TXT file (default: false)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extract_text: Option<bool>,
+    /// Return only the first this many characters of each extracted text, with its total length
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview_chars: Option<usize>,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Mailbox to act on: `me`, or a user's address for a service account with domain-wide
+    /// delegation (default: the server's --gmail-user-id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct ExtractDriveAttachmentsArgs {
+    /// Gmail message ID
+    pub message_id: String,
+    /// Return only the first this many characters of each extracted text, with its total length
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview_chars: Option<usize>,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Mailbox to act on: `me`, or a user's address for a service account with domain-wide
+    /// delegation (default: the server's --gmail-user-id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct GetThreadChangesArgs {
+    /// Gmail thread ID to check
+    pub thread_id: String,
+    /// historyId the thread was last seen at, from search_threads or a previous call
+    pub history_id: String,
+    /// Format of new messages: "full" (default), "metadata" or "minimal"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<MessageFormat>,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Mailbox to act on: `me`, or a user's address for a service account with domain-wide
+    /// delegation (default: the server's --gmail-user-id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct ClassifyMessageArgs {
+    /// Gmail message ID to classify
+    pub message_id: String,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Mailbox to act on: `me`, or a user's address for a service account with domain-wide
+    /// delegation (default: the server's --gmail-user-id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct ScorePhishingRiskArgs {
+    /// Gmail message ID to score
+    pub message_id: String,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    /// Mailbox to act on: `me`, or a user's address for a service account with domain-wide
+    /// delegation (default: the server's --gmail-user-id)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct SnoozeThreadArgs {
+    /// Gmail thread ID to snooze
+    pub thread_id: String,
+    /// When to put the thread back in the inbox: an RFC 3339 time (e.g., "2024-10-21T09:00:00Z")
+    /// or a duration from now (e.g., "90m", "4h", "2d", "1w")
+    pub until: String,
+    /// Mark the thread unread when it comes back (default: false)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mark_unread: Option<bool>,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct ListSnoozedArgs {
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct ListRulesArgs {
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct AddRuleArgs {
+    /// Unique name of the rule
+    pub name: String,
+    /// Gmail search query new messages must match (e.g., "from:billing@example.com has:attachment").
+    /// `@name` stands for the saved search of that name
+    pub query: String,
+    /// Actions to take on each new matching message, in order: {"action": "label", "label": ...},
+    /// {"action": "archive"}, {"action": "forward", "to": ...}, {"action": "notify", "url": ...}
+    /// or {"action": "classify"}
+    pub actions: Vec<crate::rules::Action>,
+    /// Account whose mail the rule watches, from the server's configured accounts (default: the
+    /// server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+}
+
+impl AddRuleArgs {
+    pub fn into_rule(self) -> crate::rules::Rule {
+        crate::rules::Rule {
+            name: self.name,
+            query: self.query,
+            account: self.account,
+            actions: self.actions,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct ListJobRunsArgs {
+    /// Only list this job's runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job: Option<String>,
+    /// Maximum number of runs to return (default: 20)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct ListOutboxArgs {
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct CancelOutboxMessageArgs {
+    /// ID of the queued send, from list_outbox or the send's result
+    pub outbox_id: i64,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct ListSavedSearchesArgs {
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct SaveSearchArgs {
+    /// Unique name of the search: letters, digits, `_` and `-` (e.g., "weekly_invoices")
+    pub name: String,
+    /// Gmail search query to save (e.g., "subject:invoice has:attachment newer_than:7d")
+    pub query: String,
+    /// What the search is for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+}
+
+impl SaveSearchArgs {
+    pub fn into_search(self) -> crate::saved_searches::SavedSearch {
+        crate::saved_searches::SavedSearch {
+            name: self.name,
+            query: self.query,
+            description: self.description,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct UpdateSavedSearchArgs {
+    /// Name of the saved search to change
+    pub name: String,
+    /// New Gmail search query (default: unchanged)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    /// New description, or "" to remove it (default: unchanged)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct DeleteSavedSearchArgs {
+    /// Name of the saved search to delete
+    pub name: String,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, schemars::JsonSchema)]
+pub struct RemoveRuleArgs {
+    /// Name of the rule to remove
+    pub name: String,
+    /// Account to use, from the server's configured accounts (default: the server's default account)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
 }
 
-#[tool_handler]
 impl ServerHandler for GmailMcpServer {
+    /// Run a tool inside a span carrying the caller's request and session IDs
+    async fn call_tool(
+        &self,
+        request: CallToolRequestParam,
+        mut context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        // Streamable HTTP requests carry their HTTP parts; SSE calls get a fresh ID
+        let parts = context.extensions.get::<axum::http::request::Parts>();
+        let request_id = parts
+            .and_then(|parts| telemetry::request_id(&parts.headers))
+            .map(str::to_string)
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        let caller = Caller::of(&context);
+        let session_id = caller.session_id.clone();
+        let span = info_span!(
+            "mcp_tool_call",
+            tool = %request.name,
+            request_id = %request_id,
+            session_id = tracing::field::Empty,
+        );
+        if let Some(session_id) = &session_id {
+            span.record("session_id", session_id.as_str());
+        }
+
+        // The router consumes the request, so keep what the audit entry needs
+        let tool = request.name.clone();
+        let arguments = Value::Object(request.arguments.clone().unwrap_or_default());
+        let started = SystemTime::now();
+        let timer = Instant::now();
+
+        context.extensions.insert(caller);
+        let result = if self.read_only && is_mutating(&tool) {
+            // Clearer than the router's "tool not found"
+            Err(McpError::invalid_request(
+                "read_only",
+                Some(serde_json::json!({ "error": crate::tools::read_only_error(&tool) })),
+            ))
+        } else {
+            let tcc = ToolCallContext::new(self, request, context);
+            self.tool_router.call(tcc).instrument(span).await
+        };
+
+        // Accounts share one audit log and set of webhooks
+        let gmail_server = &self.accounts.default_account().gmail_server;
+        let (audit_log, webhooks) = (gmail_server.audit_log(), gmail_server.webhooks());
+        if audit_log.is_some() || webhooks.is_some() {
+            let error = result.as_ref().err().map(|e| {
+                e.data
+                    .as_ref()
+                    .and_then(|data| data["error"].as_str())
+                    .map_or_else(|| e.message.to_string(), str::to_string)
+            });
+            let mut entry =
+                AuditEntry::new(&tool, "mcp", &arguments, started, timer.elapsed(), error);
+            entry.session_id = session_id.as_deref();
+            entry.request_id = Some(&request_id);
+            if let Some(audit_log) = audit_log {
+                audit_log.record(&entry);
+            }
+            if let Some(webhooks) = webhooks {
+                webhooks.send(WebhookEvent::ToolInvoked, entry.event_fields());
+            }
+        }
+
+        result.map_err(|mut e| {
+            // Let clients quote the ID when reporting a failed call
+            if let Some(Value::Object(data)) = &mut e.data {
+                data.insert("request_id".to_string(), Value::String(request_id));
+            }
+            e
+        })
+    }
+
+    async fn list_tools(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListToolsResult, McpError> {
+        Ok(ListToolsResult::with_all_items(self.tool_router.list_all()))
+    }
+
     fn get_info(&self) -> ServerInfo {
+        // Each session has its own mailbox in multi-user mode
+        let mailbox = match &self.sessions {
+            Some(_) => None,
+            None => {
+                let default_account = self.accounts.default_account();
+                default_account.gmail_server.known_email_address()
+            }
+        };
         ServerInfo {
             protocol_version: ProtocolVersion::V_2024_11_05,
             capabilities: ServerCapabilities::builder()
                 .enable_tools()
+                .enable_prompts()
                 .build(),
             server_info: Implementation::from_build_env(),
             instructions: Some(
                 "Gmail MCP Server - Provides tools for searching, reading, and managing Gmail emails. \
                 Tools: search_threads, create_draft, extract_attachment_by_filename, fetch_email_bodies, \
-                download_attachment, forward_email, send_draft.".to_string(),
+                download_attachment, forward_email, send_draft, find_bounces, get_conversation, export_thread, email_to_markdown, list_thread_attachments, extract_drive_attachments, get_thread_changes, classify_message, score_phishing_risk, snooze_thread, list_snoozed, list_rules, add_rule, remove_rule, list_job_runs, list_outbox, cancel_outbox_message, list_saved_searches, save_search, update_saved_search, delete_saved_search. Every tool takes an optional `account` \
+                naming which configured Gmail account to use. The summarize_thread prompt fetches a \
+                thread and asks for a summary of it.".to_string()
+                    + &if self.read_only {
+                        format!(
+                            " The server is read-only, so {} are unavailable.",
+                            mutating_tools().join(", ")
+                        )
+                    } else {
+                        String::new()
+                    }
+                    + &mailbox
+                        .map(|address| {
+                            format!(" Without an `account`, tools act on the mailbox of {address}.")
+                        })
+                        .unwrap_or_default(),
             ),
         }
     }
 
+    async fn list_prompts(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListPromptsResult, McpError> {
+        Ok(ListPromptsResult::with_all_items(crate::prompts::prompts()))
+    }
+
+    /// Render a prompt, fetching the mail it's about with the caller's token
+    async fn get_prompt(
+        &self,
+        request: GetPromptRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<GetPromptResult, McpError> {
+        if request.name != crate::prompts::SUMMARIZE_THREAD {
+            return Err(McpError::invalid_params(
+                format!("Unknown prompt: {}", request.name),
+                None,
+            ));
+        }
+        let arguments = request.arguments.unwrap_or_default();
+        let argument = |name: &str| arguments.get(name).and_then(Value::as_str);
+        let Some(thread_id) = argument("thread_id") else {
+            return Err(McpError::invalid_params(
+                "summarize_thread needs a thread_id",
+                None,
+            ));
+        };
+        let account = argument("account");
+        let caller = Caller::of(&context);
+        let gmail_server = self.gmail_server(&caller, account, None).await?;
+        crate::prompts::summarize_thread(&gmail_server, thread_id)
+            .await
+            .map_err(|e| {
+                error!("Failed to summarize thread: {}", e);
+                self.tool_error(&caller, account, "summarize_thread_failed", e)
+            })
+    }
+
     async fn list_resources(
         &self,
         _request: Option<PaginatedRequestParam>,
@@ -315,8 +1850,9 @@ impl ServerHandler for GmailMcpServer {
         context: RequestContext<RoleServer>,
     ) -> Result<InitializeResult, McpError> {
         if let Some(http_request_part) = context.extensions.get::<axum::http::request::Parts>() {
-            let initialize_headers = &http_request_part.headers;
-            let initialize_uri = &http_request_part.uri;
+            let initialize_headers = crate::redact::headers(&http_request_part.headers);
+            let initialize_uri =
+                crate::redact::redact(&http_request_part.uri.to_string()).into_owned();
             tracing::info!(?initialize_headers, %initialize_uri, "initialize from http server");
         }
         Ok(self.get_info())
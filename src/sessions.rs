@@ -0,0 +1,196 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+use crate::accounts::Account;
+use crate::config::{Config, HttpConfig};
+use crate::gmail::GmailServer;
+use crate::oauth::OAuthManager;
+
+/// OAuth tokens bound to MCP sessions in multi-user mode.
+///
+/// Tokens only live in memory and are dropped once their session has been idle for the timeout.
+pub struct SessionAccounts {
+    config: Config,
+    http_config: HttpConfig,
+    /// Server whose HTTP client and audit log the session servers share
+    template: Arc<GmailServer>,
+    idle_timeout: Duration,
+    sessions: RwLock<HashMap<String, SessionAccount>>,
+}
+
+struct SessionAccount {
+    account: Account,
+    last_used: Instant,
+}
+
+impl SessionAccounts {
+    pub fn new(
+        template: Arc<GmailServer>,
+        config: &Config,
+        http_config: &HttpConfig,
+        idle_timeout: Duration,
+    ) -> Self {
+        Self {
+            config: config.clone(),
+            http_config: http_config.clone(),
+            template,
+            idle_timeout,
+            sessions: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// The server for a session whose login has completed
+    pub async fn get(&self, session_id: &str) -> Option<Arc<GmailServer>> {
+        let mut sessions = self.sessions.write().await;
+        let session = sessions.get_mut(session_id)?;
+        session.account.oauth_manager.get_token().await?;
+        session.last_used = Instant::now();
+        Some(session.account.gmail_server.clone())
+    }
+
+    /// The account a login for the session stores its token in, created on first use
+    pub async fn login_account(&self, session_id: &str) -> Result<Account> {
+        let mut sessions = self.sessions.write().await;
+        if let Some(session) = sessions.get_mut(session_id) {
+            session.last_used = Instant::now();
+            return Ok(session.account.clone());
+        }
+
+        let oauth_manager = Arc::new(OAuthManager::in_memory(
+            self.config.clone(),
+            self.http_config.clone(),
+        )?);
+        let account = Account {
+            gmail_server: Arc::new(self.template.for_account(oauth_manager.clone())),
+            oauth_manager,
+        };
+        sessions.insert(
+            session_id.to_string(),
+            SessionAccount {
+                account: account.clone(),
+                last_used: Instant::now(),
+            },
+        );
+        Ok(account)
+    }
+
+    /// Where the user of a session logs in, absolute when the redirect URL allows it
+    pub fn login_url(&self, session_id: &str) -> String {
+        crate::auth::login_url(&self.http_config, &[("session", session_id)])
+    }
+
+    /// Number of sessions with a token bound to them
+    pub async fn session_count(&self) -> usize {
+        self.sessions.read().await.len()
+    }
+
+    /// Drop sessions idle for longer than the timeout, returning how many were removed
+    pub async fn remove_idle(&self) -> usize {
+        let mut sessions = self.sessions.write().await;
+        let before = sessions.len();
+        sessions.retain(|_, session| session.last_used.elapsed() <= self.idle_timeout);
+        before - sessions.len()
+    }
+}
+
+/// Periodically drop idle session tokens until cancelled
+pub fn spawn_session_cleanup(
+    sessions: Arc<SessionAccounts>,
+    ct: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(sessions.idle_timeout);
+        loop {
+            tokio::select! {
+                _ = ct.cancelled() => break,
+                _ = ticker.tick() => {}
+            }
+            let removed = sessions.remove_idle().await;
+            if removed > 0 {
+                debug!("Removed {} idle session token(s)", removed);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oauth::OAuthToken;
+
+    fn session_accounts(idle_timeout: Duration) -> SessionAccounts {
+        let config = Config {
+            gmail_client_id: Some("test_client_id".to_string()),
+            gmail_client_secret: Some("test_client_secret".to_string()),
+            ..Default::default()
+        };
+        let http_config = HttpConfig::default();
+        let oauth_manager =
+            Arc::new(OAuthManager::in_memory(config.clone(), http_config.clone()).unwrap());
+        let template = Arc::new(GmailServer::new(oauth_manager, &config).unwrap());
+        SessionAccounts::new(template, &config, &http_config, idle_timeout)
+    }
+
+    fn token(access_token: &str) -> OAuthToken {
+        OAuthToken {
+            access_token: access_token.to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: 3600,
+            refresh_token: None,
+            scope: String::new(),
+            created_at: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sessions_have_separate_tokens() {
+        let sessions = session_accounts(Duration::from_secs(3600));
+        let alice = sessions.login_account("session-a").await.unwrap();
+        assert!(sessions.get("session-a").await.is_none());
+
+        alice.oauth_manager.set_token(token("alice")).await;
+        sessions
+            .login_account("session-b")
+            .await
+            .unwrap()
+            .oauth_manager
+            .set_token(token("bob"))
+            .await;
+
+        // A later login for the same session reuses its account
+        let again = sessions.login_account("session-a").await.unwrap();
+        assert_eq!(
+            again.oauth_manager.get_token().await.unwrap().access_token,
+            "alice"
+        );
+        assert_eq!(again.oauth_manager.token_location(), "memory");
+        assert!(sessions.get("session-a").await.is_some());
+        assert!(sessions.get("session-b").await.is_some());
+        assert!(sessions.get("session-c").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_idle_sessions_are_removed() {
+        let sessions = session_accounts(Duration::from_millis(20));
+        sessions.login_account("idle").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        sessions.login_account("active").await.unwrap();
+
+        assert_eq!(sessions.remove_idle().await, 1);
+        assert_eq!(sessions.remove_idle().await, 0);
+    }
+
+    #[test]
+    fn test_login_url() {
+        let sessions = session_accounts(Duration::from_secs(3600));
+        assert_eq!(
+            sessions.login_url("abc-123"),
+            "http://localhost:8080/auth/login?session=abc-123"
+        );
+    }
+}
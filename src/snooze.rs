@@ -0,0 +1,472 @@
+use anyhow::{Context, Result};
+use reqwest::{Response, StatusCode};
+use rusqlite::{params, Connection};
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, instrument, warn};
+
+use crate::accounts::Accounts;
+use crate::gmail::{GmailClient, GmailError, GmailServer, GMAIL_API_BASE};
+use crate::labels;
+use crate::oauth::{SCOPE_MODIFY, SCOPE_READONLY};
+
+/// The database of snoozed threads, in the app data directory
+pub const SNOOZE_FILE: &str = "snoozed.sqlite3";
+
+/// The label marking snoozed threads in Gmail, created on first use
+pub const SNOOZED_LABEL: &str = "Snoozed";
+
+/// How often the wake task looks for snoozes that are due
+const WAKE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A thread taken out of the inbox until its wake time
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snoozed {
+    pub account: String,
+    pub thread_id: String,
+    /// Seconds since the epoch
+    pub wake_at: i64,
+    pub mark_unread: bool,
+    pub snoozed_at: i64,
+}
+
+/// Snoozed threads of every account, kept in SQLite so they survive restarts and a snooze set
+/// from the command line is woken by a running server
+pub struct SnoozeStore {
+    conn: Mutex<Connection>,
+}
+
+impl SnoozeStore {
+    /// Open (or create) the snooze database at the given path
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create directory at {}", dir.display()))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open snooze database at {}", path.display()))?;
+        // Tool calls and the wake task each open their own connection
+        conn.busy_timeout(Duration::from_secs(5))?;
+        Self::init(conn)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS snoozed (
+                account TEXT NOT NULL,
+                thread_id TEXT NOT NULL,
+                wake_at INTEGER NOT NULL,
+                mark_unread INTEGER NOT NULL,
+                snoozed_at INTEGER NOT NULL,
+                PRIMARY KEY (account, thread_id)
+            );
+            CREATE INDEX IF NOT EXISTS snoozed_wake_at ON snoozed (wake_at);",
+        )
+        .context("Failed to initialize snooze database schema")?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Snooze a thread, replacing the wake time of one already snoozed
+    pub fn put(&self, snoozed: &Snoozed) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO snoozed (account, thread_id, wake_at, mark_unread, snoozed_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                snoozed.account,
+                snoozed.thread_id,
+                snoozed.wake_at,
+                snoozed.mark_unread,
+                snoozed.snoozed_at
+            ],
+        )
+        .context("Failed to store snooze")?;
+        Ok(())
+    }
+
+    /// An account's snoozed threads, soonest to wake first
+    pub fn list(&self, account: &str) -> Result<Vec<Snoozed>> {
+        self.query(
+            "SELECT account, thread_id, wake_at, mark_unread, snoozed_at FROM snoozed
+             WHERE account = ?1 ORDER BY wake_at, thread_id",
+            params![account],
+        )
+    }
+
+    /// Snoozes of every account whose wake time has come
+    pub fn due(&self, now: i64) -> Result<Vec<Snoozed>> {
+        self.query(
+            "SELECT account, thread_id, wake_at, mark_unread, snoozed_at FROM snoozed
+             WHERE wake_at <= ?1 ORDER BY wake_at, thread_id",
+            params![now],
+        )
+    }
+
+    pub fn remove(&self, account: &str, thread_id: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM snoozed WHERE account = ?1 AND thread_id = ?2",
+            params![account, thread_id],
+        )
+        .context("Failed to remove snooze")?;
+        Ok(())
+    }
+
+    fn query(&self, sql: &str, params: impl rusqlite::Params) -> Result<Vec<Snoozed>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(sql)?;
+        let rows = statement.query_map(params, |row| {
+            Ok(Snoozed {
+                account: row.get(0)?,
+                thread_id: row.get(1)?,
+                wake_at: row.get(2)?,
+                mark_unread: row.get(3)?,
+                snoozed_at: row.get(4)?,
+            })
+        })?;
+        rows.collect::<rusqlite::Result<_>>()
+            .context("Failed to read snoozes")
+    }
+}
+
+/// Take a thread out of the inbox under the Snoozed label until `until`, an RFC 3339 time or a
+/// duration from now such as `90m`, `4h`, `2d` or `1w`
+#[instrument(name = "tool", skip_all, fields(tool = "snooze_thread", thread_id = %thread_id))]
+pub async fn snooze_thread(
+    gmail_server: &GmailServer,
+    thread_id: &str,
+    until: &str,
+    mark_unread: bool,
+) -> Result<Value> {
+    let now = now_secs();
+    let wake_at = parse_wake_time(until, now)?;
+    let (store, account) = store(gmail_server)?;
+    gmail_server.check_authentication(&[SCOPE_MODIFY]).await?;
+
+    let client = gmail_server.authenticated_client().await?;
+    let user_id = gmail_server.user_id();
+    let label_id = labels::find_or_create_label(&client, user_id, SNOOZED_LABEL).await?;
+    let response = modify_thread(&client, user_id, thread_id, &[&label_id], &["INBOX"])
+        .await
+        .context("Failed to snooze thread")?;
+    json_response(response).await?;
+
+    store.put(&Snoozed {
+        account: account.to_string(),
+        thread_id: thread_id.to_string(),
+        wake_at,
+        mark_unread,
+        snoozed_at: now,
+    })?;
+    Ok(json!({
+        "thread_id": thread_id,
+        "wake_at": rfc3339(wake_at),
+        "mark_unread": mark_unread,
+        "label": SNOOZED_LABEL,
+    }))
+}
+
+/// The account's snoozed threads, soonest to wake first, with the subject and sender of each
+#[instrument(name = "tool", skip_all, fields(tool = "list_snoozed"))]
+pub async fn list_snoozed(gmail_server: &GmailServer) -> Result<Value> {
+    let (store, account) = store(gmail_server)?;
+    gmail_server.check_authentication(&[SCOPE_READONLY]).await?;
+    let snoozed = store.list(account)?;
+
+    let client = gmail_server.authenticated_client().await?;
+    let user_id = gmail_server.user_id();
+    let urls: Vec<String> = snoozed
+        .iter()
+        .map(|snoozed| {
+            format!(
+                "{GMAIL_API_BASE}/users/{user_id}/threads/{}?format=metadata&metadataHeaders=Subject&metadataHeaders=From",
+                snoozed.thread_id
+            )
+        })
+        .collect();
+    let threads = client
+        .batch_get(&urls)
+        .await
+        .context("Failed to get snoozed threads")?;
+
+    let threads: Vec<Value> = snoozed
+        .iter()
+        .zip(threads)
+        .map(|(snoozed, thread)| {
+            // A thread deleted while snoozed is still listed until its wake time clears it
+            let first = thread.ok().map(|thread| thread["messages"][0].clone());
+            let header = |name: &str| {
+                first.as_ref().and_then(|message| {
+                    message["payload"]["headers"]
+                        .as_array()?
+                        .iter()
+                        .find(|header| header["name"] == name)
+                        .and_then(|header| header["value"].as_str())
+                        .map(str::to_string)
+                })
+            };
+            json!({
+                "thread_id": snoozed.thread_id,
+                "wake_at": rfc3339(snoozed.wake_at),
+                "snoozed_at": rfc3339(snoozed.snoozed_at),
+                "mark_unread": snoozed.mark_unread,
+                "subject": header("Subject"),
+                "from": header("From"),
+            })
+        })
+        .collect();
+    Ok(json!({ "threads": threads }))
+}
+
+/// Put due snoozed threads back in the inbox every minute, until cancelled. Snoozes of an
+/// account without a usable token are retried until it has one
+pub fn spawn_snooze_wake(
+    accounts: Arc<Accounts>,
+    store: SnoozeStore,
+    ct: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(WAKE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = ct.cancelled() => break,
+                _ = ticker.tick() => {}
+            }
+            let due = match store.due(now_secs()) {
+                Ok(due) => due,
+                Err(e) => {
+                    warn!("Failed to read snoozed threads: {:#}", e);
+                    continue;
+                }
+            };
+            for snoozed in due {
+                let gmail_server = match accounts.get(Some(&snoozed.account)) {
+                    Ok(account) => account.gmail_server.clone(),
+                    Err(e) => {
+                        warn!("Can't wake snoozed thread {}: {}", snoozed.thread_id, e);
+                        continue;
+                    }
+                };
+                match wake(&gmail_server, &snoozed).await {
+                    Ok(()) => {
+                        info!(
+                            "⏰ Snoozed thread {} is back in the inbox",
+                            snoozed.thread_id
+                        );
+                        if let Err(e) = store.remove(&snoozed.account, &snoozed.thread_id) {
+                            warn!("Failed to remove snooze {}: {:#}", snoozed.thread_id, e);
+                        }
+                    }
+                    Err(e) => warn!(
+                        "Failed to wake snoozed thread {}: {:#}",
+                        snoozed.thread_id, e
+                    ),
+                }
+            }
+        }
+    })
+}
+
+/// Move a snoozed thread back to the inbox, a thread Gmail no longer has counting as woken
+async fn wake(gmail_server: &GmailServer, snoozed: &Snoozed) -> Result<()> {
+    gmail_server.check_authentication(&[SCOPE_MODIFY]).await?;
+    let client = gmail_server.authenticated_client().await?;
+    let user_id = gmail_server.user_id();
+
+    let mut add = vec!["INBOX"];
+    if snoozed.mark_unread {
+        add.push("UNREAD");
+    }
+    let label_id = labels::find_label(&client, user_id, SNOOZED_LABEL).await?;
+    let remove: Vec<&str> = label_id.iter().map(String::as_str).collect();
+    let response = modify_thread(&client, user_id, &snoozed.thread_id, &add, &remove).await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        info!("Snoozed thread {} no longer exists", snoozed.thread_id);
+        return Ok(());
+    }
+    json_response(response).await.map(|_| ())
+}
+
+/// The snooze database and the account a server's snoozes are filed under
+fn store(gmail_server: &GmailServer) -> Result<(SnoozeStore, &str)> {
+    let (Some(path), Some(account)) = (gmail_server.snooze_file(), gmail_server.account()) else {
+        anyhow::bail!(
+            "Snoozing needs a token the server keeps, so it isn't available with caller-supplied \
+             tokens, in multi-user mode or in demo mode"
+        );
+    };
+    Ok((SnoozeStore::open(path)?, account))
+}
+
+async fn modify_thread(
+    client: &GmailClient,
+    user_id: &str,
+    thread_id: &str,
+    add: &[&str],
+    remove: &[&str],
+) -> Result<Response> {
+    client
+        .post(&format!(
+            "{GMAIL_API_BASE}/users/{user_id}/threads/{thread_id}/modify"
+        ))
+        .json(&json!({ "addLabelIds": add, "removeLabelIds": remove }))
+        .send()
+        .await
+}
+
+async fn json_response(response: Response) -> Result<Value> {
+    if !response.status().is_success() {
+        return Err(GmailError::from_response(response).await.into());
+    }
+    response.json().await.context("Failed to parse response")
+}
+
+/// Seconds since the epoch to wake at: an RFC 3339 time, or a number of minutes, hours, days or
+/// weeks from `now`
+fn parse_wake_time(until: &str, now: i64) -> Result<i64> {
+    let until = until.trim();
+    let wake_at = if let Ok(time) = chrono::DateTime::parse_from_rfc3339(until) {
+        time.timestamp()
+    } else {
+        let unit = until
+            .chars()
+            .last()
+            .ok_or_else(|| anyhow::anyhow!("No wake time given"))?;
+        let seconds = match unit {
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => anyhow::bail!(
+                "Invalid wake time '{until}': use an RFC 3339 time such as 2024-10-21T09:00:00Z \
+                 or a duration such as 90m, 4h, 2d or 1w"
+            ),
+        };
+        let count: i64 = until[..until.len() - 1]
+            .parse()
+            .with_context(|| format!("Invalid wake time '{until}'"))?;
+        now.saturating_add(count.saturating_mul(seconds))
+    };
+    if wake_at <= now {
+        anyhow::bail!("Wake time '{until}' is not in the future");
+    }
+    Ok(wake_at)
+}
+
+fn rfc3339(secs: i64) -> String {
+    chrono::DateTime::from_timestamp(secs, 0)
+        .unwrap_or_default()
+        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+fn now_secs() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockGmail;
+
+    fn snoozed(account: &str, thread_id: &str, wake_at: i64) -> Snoozed {
+        Snoozed {
+            account: account.to_string(),
+            thread_id: thread_id.to_string(),
+            wake_at,
+            mark_unread: false,
+            snoozed_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_store_lists_and_wakes_by_time() {
+        let store = SnoozeStore::open_in_memory().unwrap();
+        store.put(&snoozed("default", "t2", 200)).unwrap();
+        store.put(&snoozed("default", "t1", 100)).unwrap();
+        store.put(&snoozed("work", "t3", 50)).unwrap();
+        // Snoozing again moves the wake time
+        store.put(&snoozed("default", "t2", 300)).unwrap();
+
+        let listed = store.list("default").unwrap();
+        assert_eq!(
+            listed,
+            vec![snoozed("default", "t1", 100), snoozed("default", "t2", 300)]
+        );
+
+        let due: Vec<String> = store
+            .due(150)
+            .unwrap()
+            .into_iter()
+            .map(|snoozed| snoozed.thread_id)
+            .collect();
+        assert_eq!(due, vec!["t3", "t1"]);
+
+        store.remove("work", "t3").unwrap();
+        assert!(store.list("work").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_wake_time() {
+        let now = 1_700_000_000;
+        assert_eq!(parse_wake_time("90m", now).unwrap(), now + 90 * 60);
+        assert_eq!(parse_wake_time(" 2d ", now).unwrap(), now + 2 * 86_400);
+        assert_eq!(parse_wake_time("1w", now).unwrap(), now + 7 * 86_400);
+        assert_eq!(
+            parse_wake_time("2023-11-15T00:00:00+01:00", now).unwrap(),
+            1_700_002_800
+        );
+        assert!(parse_wake_time("2020-01-01T00:00:00Z", now).is_err());
+        assert!(parse_wake_time("0h", now).is_err());
+        assert!(parse_wake_time("tomorrow", now).is_err());
+        assert!(parse_wake_time("", now).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_snooze_and_wake_against_mock_gmail() {
+        let gmail = MockGmail::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = gmail.config();
+        config.app_data_dir = Some(dir.path().to_path_buf());
+        let gmail_server = gmail.gmail_server(&config).await;
+
+        let snoozed = snooze_thread(&gmail_server, "thread-1", "4h", true)
+            .await
+            .unwrap();
+        assert_eq!(snoozed["label"], SNOOZED_LABEL);
+        let listed = list_snoozed(&gmail_server).await.unwrap();
+        assert_eq!(listed["threads"][0]["thread_id"], "thread-1");
+        assert_eq!(listed["threads"][0]["subject"], "Quarterly report");
+        assert_eq!(listed["threads"][0]["wake_at"], snoozed["wake_at"]);
+
+        let requests = gmail.requests().await;
+        let modify = requests
+            .iter()
+            .find(|request| request.url.path().ends_with("/threads/thread-1/modify"))
+            .unwrap();
+        let body: Value = serde_json::from_slice(&modify.body).unwrap();
+        assert_eq!(
+            body,
+            json!({ "addLabelIds": ["Label_snoozed"], "removeLabelIds": ["INBOX"] })
+        );
+
+        let store = SnoozeStore::open(&dir.path().join(SNOOZE_FILE)).unwrap();
+        let entry = store.list("default").unwrap().remove(0);
+        wake(&gmail_server, &entry).await.unwrap();
+        let requests = gmail.requests().await;
+        let body: Value = serde_json::from_slice(&requests.last().unwrap().body).unwrap();
+        assert_eq!(
+            body,
+            json!({ "addLabelIds": ["INBOX", "UNREAD"], "removeLabelIds": ["Label_snoozed"] })
+        );
+    }
+}
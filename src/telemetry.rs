@@ -0,0 +1,432 @@
+use anyhow::{Context, Result};
+use axum::http::{HeaderMap, Uri};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{Span, Subscriber};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer};
+
+use crate::config::{LogFormat, LogRotation, TelemetryConfig};
+
+/// Keeps log writers and trace exporters alive for the life of the process and flushes them on drop
+pub struct TelemetryGuard {
+    _log_file_writer: Option<WorkerGuard>,
+    log_filter: LogFilter,
+    #[cfg(feature = "otel")]
+    tracer_provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+/// Install the global tracing subscriber
+pub fn init(config: &TelemetryConfig) -> Result<TelemetryGuard> {
+    crate::redact::set_unredacted(config.log_unredacted);
+
+    let level = config.log_level.as_deref();
+    let mut reloads = Vec::new();
+    let (stdout_filter, reload) = reloadable_filter(level)?;
+    reloads.push(reload);
+    let stdout_layer =
+        fmt_layer(config.log_format, std::io::stdout, true).with_filter(stdout_filter);
+
+    let (file_layer, log_file_writer) = match &config.log_file {
+        Some(path) => {
+            let (writer, guard) = tracing_appender::non_blocking(log_file_writer(config, path)?);
+            let (file_filter, reload) = reloadable_filter(level)?;
+            reloads.push(reload);
+            let layer = fmt_layer(config.log_format, writer, false).with_filter(file_filter);
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    #[cfg(feature = "otel")]
+    let (otel_layer, tracer_provider) = match &config.otlp_endpoint {
+        Some(endpoint) => {
+            let (layer, provider) = otel::layer(endpoint, &config.otel_service_name)?;
+            (Some(layer), Some(provider))
+        }
+        None => (None, None),
+    };
+    #[cfg(not(feature = "otel"))]
+    let otel_layer: Option<tracing_subscriber::layer::Identity> = None;
+
+    tracing_subscriber::registry()
+        .with(stdout_layer)
+        .with(file_layer)
+        .with(otel_layer)
+        .init();
+
+    if let Some(path) = &config.log_file {
+        tracing::info!("📝 Log file: {}", path.display());
+    }
+    if config.log_unredacted {
+        tracing::warn!(
+            "⚠️ Log redaction disabled: logs may contain email addresses and credentials"
+        );
+    }
+    match &config.otlp_endpoint {
+        Some(endpoint) if cfg!(feature = "otel") => {
+            tracing::info!("📡 Exporting traces to {}", endpoint);
+        }
+        Some(_) => tracing::warn!("--otlp-endpoint ignored: built without the `otel` feature"),
+        None => {}
+    }
+
+    Ok(TelemetryGuard {
+        _log_file_writer: log_file_writer,
+        log_filter: LogFilter(Arc::new(reloads)),
+        #[cfg(feature = "otel")]
+        tracer_provider,
+    })
+}
+
+impl TelemetryGuard {
+    /// Handle for changing the log level while the process runs
+    pub fn log_filter(&self) -> LogFilter {
+        self.log_filter.clone()
+    }
+}
+
+type FilterReload = Box<dyn Fn(Option<&str>) -> Result<()> + Send + Sync>;
+
+/// Replaces the filter of every log output, e.g. when the config file changes
+#[derive(Clone, Default)]
+pub struct LogFilter(Arc<Vec<FilterReload>>);
+
+impl LogFilter {
+    /// Filter by `--log-level` directives, or by RUST_LOG when `None`
+    pub fn set(&self, level: Option<&str>) -> Result<()> {
+        // Reject a bad directive before any output has switched
+        log_filter(level)?;
+        for reload in self.0.iter() {
+            reload(level)?;
+        }
+        Ok(())
+    }
+}
+
+fn reloadable_filter<S: Subscriber>(
+    level: Option<&str>,
+) -> Result<(reload::Layer<EnvFilter, S>, FilterReload)> {
+    let (filter, handle) = reload::Layer::new(log_filter(level)?);
+    let reload = move |level: Option<&str>| {
+        handle.reload(log_filter(level)?)?;
+        Ok(())
+    };
+    Ok((filter, Box::new(reload)))
+}
+
+/// Log level filter from `--log-level`, else RUST_LOG, defaulting to info
+fn log_filter(level: Option<&str>) -> Result<EnvFilter> {
+    match level {
+        Some(level) => {
+            EnvFilter::try_new(level).with_context(|| format!("Invalid log level '{}'", level))
+        }
+        None => Ok(env_filter()),
+    }
+}
+
+/// Log level filter from RUST_LOG, defaulting to info
+fn env_filter() -> EnvFilter {
+    EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
+}
+
+/// Format log lines as text or JSON to the given writer
+fn fmt_layer<S, W>(format: LogFormat, writer: W, ansi: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match format {
+        LogFormat::Text => layer.boxed(),
+        // Event fields at the top level; span fields such as tool, message_id and
+        // session_id under "spans", outermost first
+        LogFormat::Json => layer
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
+    }
+}
+
+/// Open the log file with the configured rotation policy
+fn log_file_writer(config: &TelemetryConfig, path: &Path) -> Result<Box<dyn Write + Send>> {
+    let directory = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("Invalid log file path: {}", path.display()))?;
+    fs::create_dir_all(directory)
+        .with_context(|| format!("Failed to create log directory {}", directory.display()))?;
+
+    let rotation = match config.log_rotation {
+        LogRotation::Size => {
+            let max_bytes = config.log_max_size_mb.max(1) * 1024 * 1024;
+            let file = SizeRotatingFile::open(path, max_bytes, config.log_max_files)
+                .with_context(|| format!("Failed to open log file {}", path.display()))?;
+            return Ok(Box::new(file));
+        }
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    // Time-rotated files are named <file>.<date>
+    let appender = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(file_name)
+        .max_log_files(config.log_max_files.max(1))
+        .build(directory)
+        .with_context(|| format!("Failed to open log file {}", path.display()))?;
+    Ok(Box::new(appender))
+}
+
+/// A log file that is moved to `<file>.1` (shifting older files to `.2`, `.3`, ...)
+/// once writing to it would exceed a size limit
+struct SizeRotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    written: u64,
+}
+
+impl SizeRotatingFile {
+    fn open(path: &Path, max_bytes: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_bytes,
+            max_files: max_files.max(1),
+            file,
+            written,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        // Shift older files up, overwriting the oldest
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.tracer_provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush traces: {e}");
+            }
+        }
+    }
+}
+
+/// Header carrying the per-request correlation ID
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Correlation ID assigned to (or supplied with) a request
+pub fn request_id(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// MCP session ID of a request, from the streamable HTTP header or the SSE `sessionId` query
+pub fn session_id<'a>(headers: &'a HeaderMap, uri: &'a Uri) -> Option<&'a str> {
+    if let Some(session_id) = headers
+        .get("mcp-session-id")
+        .and_then(|value| value.to_str().ok())
+    {
+        return Some(session_id);
+    }
+    uri.query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("sessionId="))
+}
+
+/// Continue a caller's trace when the request carries a W3C `traceparent` header
+#[cfg_attr(not(feature = "otel"), allow(unused_variables))]
+pub fn set_remote_parent(span: &Span, headers: &HeaderMap) {
+    #[cfg(feature = "otel")]
+    otel::set_remote_parent(span, headers);
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use anyhow::{Context, Result};
+    use axum::http::HeaderMap;
+    use opentelemetry::propagation::Extractor;
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+    use tracing::{Level, Span, Subscriber};
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::filter::Targets;
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::Layer;
+
+    /// Build a layer exporting this crate's spans to an OTLP/HTTP collector
+    pub fn layer<S>(
+        endpoint: &str,
+        service_name: &str,
+    ) -> Result<(impl Layer<S>, SdkTracerProvider)>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+            .build()
+            .context("Failed to build OTLP span exporter")?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(service_name.to_string())
+                    .build(),
+            )
+            .build();
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+        // Export this crate's spans down to debug level regardless of RUST_LOG
+        let layer = tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer("gmail-mcp-server"))
+            .with_filter(Targets::new().with_target("gmail_mcp_server", Level::DEBUG));
+        Ok((layer, provider))
+    }
+
+    pub fn set_remote_parent(span: &Span, headers: &HeaderMap) {
+        let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(headers))
+        });
+        // Fails only when the OpenTelemetry layer is not installed
+        let _ = span.set_parent(parent);
+    }
+
+    struct HeaderExtractor<'a>(&'a HeaderMap);
+
+    impl Extractor for HeaderExtractor<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).and_then(|value| value.to_str().ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(|key| key.as_str()).collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+
+    #[test]
+    fn test_request_id() {
+        let request = Request::builder()
+            .header("X-Request-Id", "req-1")
+            .body(())
+            .unwrap();
+        assert_eq!(request_id(request.headers()), Some("req-1"));
+        assert_eq!(request_id(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_session_id_from_header() {
+        let request = Request::builder()
+            .uri("/stream")
+            .header("Mcp-Session-Id", "abc123")
+            .body(())
+            .unwrap();
+        assert_eq!(session_id(request.headers(), request.uri()), Some("abc123"));
+    }
+
+    #[test]
+    fn test_session_id_from_sse_query() {
+        let request = Request::builder()
+            .uri("/sse/message?foo=bar&sessionId=xyz")
+            .body(())
+            .unwrap();
+        assert_eq!(session_id(request.headers(), request.uri()), Some("xyz"));
+    }
+
+    #[test]
+    fn test_size_rotating_file_rotates_and_prunes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.log");
+        let mut file = SizeRotatingFile::open(&path, 10, 2).unwrap();
+
+        for line in ["first-1\n", "second\n", "third-3\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+
+        let read = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("server.log"), "fourth\n");
+        assert_eq!(read("server.log.1"), "third-3\n");
+        assert_eq!(read("server.log.2"), "second\n");
+        assert!(!dir.path().join("server.log.3").exists());
+    }
+
+    #[test]
+    fn test_size_rotating_file_appends_to_existing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.log");
+        fs::write(&path, "existing\n").unwrap();
+
+        let mut file = SizeRotatingFile::open(&path, 1024, 2).unwrap();
+        file.write_all(b"new\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "existing\nnew\n");
+    }
+
+    #[test]
+    fn test_session_id_missing() {
+        let request = Request::builder().uri("/health").body(()).unwrap();
+        assert_eq!(session_id(request.headers(), request.uri()), None);
+    }
+}
@@ -0,0 +1,499 @@
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use wiremock::{matchers::any, Mock, MockServer, Request, Respond, ResponseTemplate};
+
+use crate::batch::boundary_from_content_type;
+use crate::config::{Config, HttpConfig};
+use crate::gmail::GmailServer;
+use crate::oauth::{OAuthManager, OAuthToken, GMAIL_SCOPES};
+
+/// The mailbox's address, as its profile reports it
+pub const MOCK_EMAIL: &str = "me@example.com";
+
+/// Text of `notes.txt`, the attachment on `msg-1`
+pub const MOCK_ATTACHMENT_TEXT: &str = "Meeting notes: ship on Friday.\n";
+
+/// Text of `contract.txt` on `msg-5`, attached again to `msg-6`
+const CONTRACT_TEXT: &str = "Term: 12 months. Fee: $1,000.\n";
+
+/// Text of `changes.txt` on `msg-6`, the same size as `CONTRACT_TEXT`
+const CHANGES_TEXT: &str = "Term: 24 months. Fee: $2,000.\n";
+
+/// Text of the `Launch plan` Google Doc linked from `msg-7`, as Drive exports it
+pub const MOCK_DOC_TEXT: &str = "Launch plan\r\nShip the beta on Friday.\r\n";
+
+/// The `Budget` Google Sheet linked from `msg-7`, as Drive exports it
+pub const MOCK_SHEET_CSV: &str = "Item,Cost\r\nAds,500\r\nHosting,120\r\n";
+
+/// A fake Gmail API on a local port. Every search finds the same threads:
+///
+/// - `thread-1`: `msg-1`, plain text from Alice with the attachment `notes.txt`, and `msg-2`,
+///   Bob's reply with plain text and HTML alternatives
+/// - `thread-2`: `msg-3`, an HTML-only newsletter
+///
+/// Alice's and Bob's messages are in the Primary tab, Alice's marked important and Bob's unread,
+/// and the unread newsletter is in Promotions. Every other message is only in the inbox.
+///
+/// Each message's Message-ID is `<{id}@example.com>`, and `msg-2` is in reply to `msg-1`.
+/// `msg-4`, Carol's reply to `msg-2`, is split off in `thread-4`, found only by looking up its
+/// `rfc822msgid:`. Listing messages with any other query finds only `msg-bounce`, a delivery
+/// status notification in `thread-3` reporting that `msg-1` couldn't be delivered to
+/// `carol@example.org`.
+///
+/// `thread-5`, found only by its ID, has Dave's `msg-5` with `contract.txt`, and his `msg-6`
+/// attaching the same file again as `contract-signed.txt` along with `changes.txt`, a different
+/// file of the same size.
+///
+/// `msg-7`, also found only by its ID, is Erin's message linking the `Launch plan` Doc, the
+/// `Budget` Sheet, an uploaded PDF and a Doc the mailbox can't see, which the fake Drive API
+/// under `/drive/v3` knows as `doc-launch01`, `sheet-budget1`, `pdf-uploaded1` and nothing.
+///
+/// The mailbox has a `Snoozed` label, `Label_snoozed`. Creating a draft returns `draft-1`, and
+/// sending anything returns `msg-sent`.
+///
+/// History since `100` records `msg-2` arriving, and then `msg-1` being starred and unsnoozed, up
+/// to history `104`. Any earlier history has expired.
+pub struct MockGmail {
+    server: MockServer,
+}
+
+impl MockGmail {
+    pub async fn start() -> Self {
+        let server = MockServer::start().await;
+        Mock::given(any())
+            .respond_with(Mailbox)
+            .mount(&server)
+            .await;
+        Self { server }
+    }
+
+    /// Config sending Gmail API calls to this server
+    pub fn config(&self) -> Config {
+        let mut config = Config {
+            gmail_client_id: Some("test_client_id".to_string()),
+            gmail_client_secret: Some("test_client_secret".to_string()),
+            ..Default::default()
+        };
+        config.gmail_api.gmail_api_url = Some(self.server.uri());
+        config
+    }
+
+    /// A Gmail server for `config`, authenticated with a token for every Gmail scope
+    pub async fn gmail_server(&self, config: &Config) -> Arc<GmailServer> {
+        let oauth_manager = Arc::new(
+            OAuthManager::in_memory(config.clone(), HttpConfig::default())
+                .expect("test config has client credentials"),
+        );
+        oauth_manager
+            .set_token(OAuthToken {
+                access_token: "mock_access_token".to_string(),
+                token_type: "Bearer".to_string(),
+                expires_in: u32::MAX.into(),
+                refresh_token: None,
+                scope: GMAIL_SCOPES.join(" "),
+                created_at: 0,
+            })
+            .await;
+        Arc::new(GmailServer::new(oauth_manager, config).expect("test config is valid"))
+    }
+
+    /// Requests the server has received, oldest first
+    pub async fn requests(&self) -> Vec<Request> {
+        self.server.received_requests().await.unwrap_or_default()
+    }
+}
+
+struct Mailbox;
+
+impl Respond for Mailbox {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let method = request.method.as_str();
+        if method == "POST" && request.url.path() == "/batch/gmail/v1" {
+            return batch(request);
+        }
+        let path = match request.url.query() {
+            Some(query) => format!("{}?{query}", request.url.path()),
+            None => request.url.path().to_string(),
+        };
+        if let ["", "drive", "v3", "files", file_id, "export"] = path
+            .split('?')
+            .next()
+            .unwrap_or_default()
+            .split('/')
+            .collect::<Vec<_>>()
+            .as_slice()
+        {
+            return match *file_id {
+                "doc-launch01" => ResponseTemplate::new(200).set_body_string(MOCK_DOC_TEXT),
+                "sheet-budget1" => ResponseTemplate::new(200).set_body_string(MOCK_SHEET_CSV),
+                _ => ResponseTemplate::new(404),
+            };
+        }
+        let (status, body) = respond(method, &path);
+        ResponseTemplate::new(status).set_body_json(body)
+    }
+}
+
+/// Answer each GET in a batch request, in a multipart response like Gmail's
+fn batch(request: &Request) -> ResponseTemplate {
+    let Some(boundary) = request
+        .headers
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .and_then(boundary_from_content_type)
+    else {
+        return ResponseTemplate::new(400);
+    };
+    let body = String::from_utf8_lossy(&request.body);
+    let mut response = String::new();
+    let paths = body.lines().filter_map(|line| line.strip_prefix("GET "));
+    for (index, path) in paths.enumerate() {
+        let (status, body) = respond("GET", path.trim());
+        response.push_str(&format!(
+            "--{boundary}\r\nContent-Type: application/http\r\nContent-ID: <response-item-{index}>\r\n\r\n\
+             HTTP/1.1 {status} {}\r\nContent-Type: application/json\r\n\r\n{body}\r\n",
+            if status == 200 { "OK" } else { "Not Found" },
+        ));
+    }
+    response.push_str(&format!("--{boundary}--\r\n"));
+    ResponseTemplate::new(200)
+        .set_body_raw(response, &format!("multipart/mixed; boundary={boundary}"))
+}
+
+/// Status and JSON body of the fake API's answer to one call
+fn respond(method: &str, path: &str) -> (u16, Value) {
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    let found = match (method, segments.as_slice()) {
+        ("GET", ["gmail", "v1", "users", _, "profile"]) => Some(json!({
+            "emailAddress": MOCK_EMAIL,
+            "messagesTotal": 3,
+            "threadsTotal": 2,
+            "historyId": "100",
+        })),
+        ("GET", ["gmail", "v1", "users", _, "threads"]) => Some(search(query)),
+        ("GET", ["gmail", "v1", "users", _, "messages"]) => Some(list_messages(query)),
+        ("GET", ["gmail", "v1", "users", _, "threads", thread_id]) => thread(thread_id, query),
+        ("GET", ["gmail", "v1", "users", _, "messages", message_id]) => message(message_id),
+        (
+            "GET",
+            ["gmail", "v1", "users", _, "messages", message_id, "attachments", attachment_id],
+        ) => {
+            let data = match (*message_id, *attachment_id) {
+                ("msg-1", "att-1") => Some(MOCK_ATTACHMENT_TEXT),
+                ("msg-5", "att-5") | ("msg-6", "att-6") => Some(CONTRACT_TEXT),
+                ("msg-6", "att-7") => Some(CHANGES_TEXT),
+                _ => None,
+            };
+            data.map(|data| json!({ "size": data.len(), "data": URL_SAFE.encode(data) }))
+        }
+        ("GET", ["gmail", "v1", "users", _, "drafts", "draft-1"]) => Some(json!({
+            "id": "draft-1",
+            "message": {
+                "id": "msg-draft",
+                "threadId": "thread-1",
+                "raw": URL_SAFE.encode("To: alice@example.com\r\nSubject: Re: Quarterly report\r\n\r\nThanks!"),
+            },
+        })),
+        ("GET", ["gmail", "v1", "users", _, "history"]) => history(query),
+        ("GET", ["drive", "v3", "files", file_id]) => drive_file(file_id),
+        ("GET", ["gmail", "v1", "users", _, "labels"]) => Some(json!({
+            "labels": [
+                { "id": "INBOX", "name": "INBOX", "type": "system" },
+                { "id": "Label_snoozed", "name": "Snoozed", "type": "user" },
+            ],
+        })),
+        ("POST", ["gmail", "v1", "users", _, "threads", thread_id, "modify"]) => {
+            thread(thread_id, "format=minimal")
+        }
+        ("POST", ["gmail", "v1", "users", _, "messages", "batchModify"]) => Some(json!({})),
+        ("POST", ["gmail", "v1", "users", _, "messages", message_id, "modify"]) => {
+            message(message_id)
+        }
+        ("POST", ["gmail", "v1", "users", _, "drafts"]) => Some(json!({
+            "id": "draft-1",
+            "message": { "id": "msg-draft", "threadId": "thread-1", "labelIds": ["DRAFT"] },
+        })),
+        ("POST", ["gmail", "v1", "users", _, "drafts", "draft-1", "send"])
+        | ("POST", ["gmail", "v1", "users", _, "messages", "send"]) => Some(json!({
+            "id": "msg-sent",
+            "threadId": "thread-1",
+            "labelIds": ["SENT"],
+        })),
+        _ => None,
+    };
+    match found {
+        Some(body) => (200, body),
+        None => (
+            404,
+            json!({ "error": { "code": 404, "message": "Requested entity was not found.", "status": "NOT_FOUND" } }),
+        ),
+    }
+}
+
+/// Metadata of a Drive file linked from `msg-7`
+fn drive_file(file_id: &str) -> Option<Value> {
+    let (name, mime_type) = match file_id {
+        "doc-launch01" => ("Launch plan", "application/vnd.google-apps.document"),
+        "sheet-budget1" => ("Budget", "application/vnd.google-apps.spreadsheet"),
+        "pdf-uploaded1" => ("Invoice.pdf", "application/pdf"),
+        _ => return None,
+    };
+    Some(json!({
+        "id": file_id,
+        "name": name,
+        "mimeType": mime_type,
+        "webViewLink": format!("https://docs.google.com/d/{file_id}/view"),
+    }))
+}
+
+fn history(query: &str) -> Option<Value> {
+    let start = url::form_urlencoded::parse(query.as_bytes())
+        .find(|(name, _)| name == "startHistoryId")?
+        .1;
+    if start != "100" {
+        return None;
+    }
+    let message = |id: &str, thread_id: &str| json!({ "id": id, "threadId": thread_id });
+    Some(json!({
+        "history": [
+            {
+                "id": "101",
+                "messagesAdded": [{ "message": message("msg-2", "thread-1") }],
+            },
+            {
+                "id": "102",
+                "labelsAdded": [
+                    { "message": message("msg-1", "thread-1"), "labelIds": ["STARRED"] },
+                    { "message": message("msg-2", "thread-1"), "labelIds": ["IMPORTANT"] },
+                ],
+            },
+            {
+                "id": "103",
+                "labelsAdded": [{ "message": message("msg-3", "thread-2"), "labelIds": ["STARRED"] }],
+            },
+            {
+                "id": "104",
+                "labelsRemoved": [
+                    { "message": message("msg-1", "thread-1"), "labelIds": ["Label_snoozed"] },
+                ],
+            },
+        ],
+        "historyId": "104",
+    }))
+}
+
+fn search(query: &str) -> Value {
+    let max_results = url::form_urlencoded::parse(query.as_bytes())
+        .find(|(name, _)| name == "maxResults")
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(100);
+    let threads: Vec<Value> = [
+        ("thread-1", "Sounds good, see you Friday"),
+        ("thread-2", "This week in Rust"),
+    ]
+    .iter()
+    .take(max_results)
+    .map(|(id, snippet)| json!({ "id": id, "snippet": snippet, "historyId": "100" }))
+    .collect();
+    json!({ "threads": threads, "resultSizeEstimate": threads.len() })
+}
+
+/// The messages whose `rfc822msgid:` is searched for, otherwise the bounce
+fn list_messages(query: &str) -> Value {
+    let search = url::form_urlencoded::parse(query.as_bytes())
+        .find(|(name, _)| name == "q")
+        .map(|(_, value)| value.into_owned())
+        .unwrap_or_default();
+    let messages: Vec<Value> = match search.strip_prefix("rfc822msgid:") {
+        Some(rfc822_id) => rfc822_id
+            .strip_suffix("@example.com")
+            .and_then(message)
+            .into_iter()
+            .map(|message| json!({ "id": message["id"], "threadId": message["threadId"] }))
+            .collect(),
+        None => vec![json!({ "id": "msg-bounce", "threadId": "thread-3" })],
+    };
+    json!({ "messages": messages, "resultSizeEstimate": messages.len() })
+}
+
+/// A thread with its messages' IDs only in `format=minimal`, and whole otherwise
+fn thread(thread_id: &str, query: &str) -> Option<Value> {
+    let message_ids: &[&str] = match thread_id {
+        "thread-1" => &["msg-1", "msg-2"],
+        "thread-2" => &["msg-3"],
+        "thread-4" => &["msg-4"],
+        "thread-5" => &["msg-5", "msg-6"],
+        _ => return None,
+    };
+    let messages: Vec<Value> = message_ids
+        .iter()
+        .map(|id| {
+            if query.contains("format=minimal") {
+                json!({ "id": id, "threadId": thread_id, "historyId": "100" })
+            } else {
+                message(id).unwrap_or_default()
+            }
+        })
+        .collect();
+    Some(json!({ "id": thread_id, "historyId": "100", "messages": messages }))
+}
+
+fn message(message_id: &str) -> Option<Value> {
+    let text = |mime_type: &str, body: &str| json!({ "mimeType": mime_type, "filename": "", "body": { "data": URL_SAFE.encode(body) } });
+    let (thread_id, from, subject, date, payload) = match message_id {
+        "msg-1" => (
+            "thread-1",
+            "Alice <alice@example.com>",
+            "Quarterly report",
+            "Mon, 30 Sep 2024 09:00:00 +0000",
+            json!({
+                "mimeType": "multipart/mixed",
+                "parts": [
+                    text("text/plain", "Notes from today's meeting are attached."),
+                    {
+                        "mimeType": "text/plain",
+                        "filename": "notes.txt",
+                        "body": { "attachmentId": "att-1", "size": MOCK_ATTACHMENT_TEXT.len() },
+                    },
+                ],
+            }),
+        ),
+        "msg-2" => (
+            "thread-1",
+            "Bob <bob@example.com>",
+            "Re: Quarterly report",
+            "Mon, 30 Sep 2024 10:30:00 +0000",
+            json!({
+                "mimeType": "multipart/alternative",
+                "parts": [
+                    text("text/plain", "Sounds good, see you Friday."),
+                    text("text/html", "<p>Sounds good, see you <b>Friday</b>.</p>"),
+                ],
+            }),
+        ),
+        "msg-3" => (
+            "thread-2",
+            "Newsletter <news@example.com>",
+            "This week in Rust",
+            "Tue, 1 Oct 2024 08:00:00 +0000",
+            text(
+                "text/html",
+                "<h1>This week in Rust</h1><p>New releases.</p>",
+            ),
+        ),
+        "msg-4" => (
+            "thread-4",
+            "Carol <carol@example.org>",
+            "Re: Quarterly report",
+            "Mon, 30 Sep 2024 12:00:00 +0000",
+            text("text/plain", "Friday works for me too."),
+        ),
+        "msg-5" | "msg-6" => {
+            let attachment = |filename: &str, attachment_id: &str, size: usize| {
+                json!({
+                    "mimeType": "text/plain",
+                    "filename": filename,
+                    "body": { "attachmentId": attachment_id, "size": size },
+                })
+            };
+            let mut parts = vec![text("text/plain", "Contract attached.")];
+            if message_id == "msg-5" {
+                parts.push(attachment("contract.txt", "att-5", CONTRACT_TEXT.len()));
+            } else {
+                parts.push(attachment(
+                    "contract-signed.txt",
+                    "att-6",
+                    CONTRACT_TEXT.len(),
+                ));
+                parts.push(attachment("changes.txt", "att-7", CHANGES_TEXT.len()));
+            }
+            (
+                "thread-5",
+                "Dave <dave@example.com>",
+                "Contract",
+                "Wed, 2 Oct 2024 09:00:00 +0000",
+                json!({ "mimeType": "multipart/mixed", "parts": parts }),
+            )
+        }
+        "msg-7" => (
+            "thread-7",
+            "Erin <erin@example.com>",
+            "Launch docs",
+            "Thu, 3 Oct 2024 09:00:00 +0000",
+            json!({
+                "mimeType": "multipart/alternative",
+                "parts": [
+                    text(
+                        "text/plain",
+                        "Plan: https://docs.google.com/document/d/doc-launch01/edit?usp=drive_web\n\
+                         Budget: https://docs.google.com/spreadsheets/d/sheet-budget1/edit\n\
+                         Invoice: https://drive.google.com/open?id=pdf-uploaded1\n\
+                         Notes: https://docs.google.com/document/d/doc-private00/edit",
+                    ),
+                    text(
+                        "text/html",
+                        "<a href=\"https://docs.google.com/document/d/doc-launch01/edit?usp=drive_web\">Plan</a>",
+                    ),
+                ],
+            }),
+        ),
+        "msg-bounce" => (
+            "thread-3",
+            "Mail Delivery Subsystem <mailer-daemon@googlemail.com>",
+            "Delivery Status Notification (Failure)",
+            "Mon, 30 Sep 2024 09:01:00 +0000",
+            json!({
+                "mimeType": "multipart/report",
+                "parts": [
+                    text("text/plain", "Your message wasn't delivered to carol@example.org because the address couldn't be found."),
+                    text(
+                        "message/delivery-status",
+                        "Reporting-MTA: dns; googlemail.com\r\n\r\nFinal-Recipient: rfc822; carol@example.org\r\nAction: failed\r\nStatus: 5.1.1\r\nDiagnostic-Code: smtp; 550 5.1.1 The email account that you tried to reach does not exist.\r\n",
+                    ),
+                    text(
+                        "text/rfc822-headers",
+                        "From: me@example.com\r\nTo: carol@example.org\r\nSubject: Quarterly report\r\nMessage-ID: <msg-1@example.com>\r\n",
+                    ),
+                ],
+            }),
+        ),
+        _ => return None,
+    };
+    let mut headers = vec![
+        json!({ "name": "From", "value": from }),
+        json!({ "name": "To", "value": MOCK_EMAIL }),
+        json!({ "name": "Subject", "value": subject }),
+        json!({ "name": "Date", "value": date }),
+        json!({ "name": "Message-ID", "value": format!("<{message_id}@example.com>") }),
+    ];
+    let references = match message_id {
+        "msg-2" => Some(("<msg-1@example.com>", "<msg-1@example.com>")),
+        "msg-4" => Some((
+            "<msg-2@example.com>",
+            "<msg-1@example.com> <msg-2@example.com>",
+        )),
+        _ => None,
+    };
+    if let Some((in_reply_to, references)) = references {
+        headers.push(json!({ "name": "In-Reply-To", "value": in_reply_to }));
+        headers.push(json!({ "name": "References", "value": references }));
+    }
+    let label_ids: &[&str] = match message_id {
+        "msg-1" => &["INBOX", "IMPORTANT", "CATEGORY_PERSONAL"],
+        "msg-2" => &["INBOX", "UNREAD", "CATEGORY_PERSONAL"],
+        "msg-3" => &["INBOX", "UNREAD", "CATEGORY_PROMOTIONS"],
+        _ => &["INBOX"],
+    };
+    let mut payload = payload;
+    payload["headers"] = Value::Array(headers);
+    Some(json!({
+        "id": message_id,
+        "threadId": thread_id,
+        "historyId": "100",
+        "labelIds": label_ids,
+        "snippet": subject,
+        "payload": payload,
+    }))
+}
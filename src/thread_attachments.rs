@@ -0,0 +1,288 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tracing::instrument;
+
+use crate::extract::{extract_text_from_bytes, is_extractable_document};
+use crate::gmail::{GmailServer, GMAIL_API_BASE};
+use crate::mime::leaf_parts;
+use crate::oauth::SCOPE_READONLY;
+use crate::pgp::decrypt_message;
+use crate::tools::{part_data, preview_text, text_size, with_response_size};
+
+/// An attachment as one message carries it
+#[derive(Debug)]
+struct Occurrence {
+    message_id: String,
+    part: Value,
+}
+
+impl Occurrence {
+    fn filename(&self) -> &str {
+        self.part["filename"].as_str().unwrap_or_default()
+    }
+
+    fn mime_type(&self) -> &str {
+        self.part["mimeType"]
+            .as_str()
+            .unwrap_or("application/octet-stream")
+    }
+
+    fn size(&self) -> u64 {
+        self.part["body"]["size"].as_u64().unwrap_or_default()
+    }
+}
+
+/// A file attached once or more in a thread
+#[derive(Debug)]
+struct UniqueAttachment {
+    /// SHA-256 of the content, for files that were downloaded
+    sha256: Option<String>,
+    /// Indexes of the occurrences carrying the file, the first one first
+    occurrences: Vec<usize>,
+    /// The content, if it was downloaded to be hashed
+    data: Option<Vec<u8>>,
+}
+
+/// Collapse occurrences with the same content hash into one file each, in the order each file
+/// first appears. Occurrences without a hash are files of their own
+fn group_duplicates(hashes: Vec<Option<(String, Vec<u8>)>>) -> Vec<UniqueAttachment> {
+    let mut unique: Vec<UniqueAttachment> = Vec::new();
+    let mut by_hash: HashMap<String, usize> = HashMap::new();
+    for (index, hashed) in hashes.into_iter().enumerate() {
+        let Some((sha256, data)) = hashed else {
+            unique.push(UniqueAttachment {
+                sha256: None,
+                occurrences: vec![index],
+                data: None,
+            });
+            continue;
+        };
+        match by_hash.get(&sha256) {
+            Some(&existing) => unique[existing].occurrences.push(index),
+            None => {
+                by_hash.insert(sha256.clone(), unique.len());
+                unique.push(UniqueAttachment {
+                    sha256: Some(sha256),
+                    occurrences: vec![index],
+                    data: Some(data),
+                });
+            }
+        }
+    }
+    unique
+}
+
+/// List a thread's attachments with duplicates collapsed, such as a contract re-attached to
+/// every reply, and which messages carried each file. Optionally extracts each file's text once
+#[instrument(name = "tool", skip_all, fields(tool = "list_thread_attachments", thread_id = %thread_id))]
+pub async fn list_thread_attachments(
+    gmail_server: &GmailServer,
+    thread_id: &str,
+    extract_text: bool,
+    preview_chars: Option<usize>,
+) -> Result<Value> {
+    gmail_server.check_authentication(&[SCOPE_READONLY]).await?;
+
+    let client = gmail_server.authenticated_client().await?;
+    let user_id = gmail_server.user_id();
+    let url = format!(
+        "{GMAIL_API_BASE}/users/{user_id}/threads/{}?format=full",
+        urlencoding::encode(thread_id)
+    );
+    let thread = client
+        .get_json(&url)
+        .await
+        .context("Failed to get thread")?;
+
+    let mut occurrences = Vec::new();
+    for message in thread["messages"].as_array().into_iter().flatten() {
+        let mut message = message.clone();
+        let message_id = message["id"].as_str().unwrap_or_default().to_string();
+        decrypt_message(gmail_server, &client, &message_id, &mut message).await;
+        let mut leaves = Vec::new();
+        leaf_parts(&message["payload"], &mut leaves);
+        for part in leaves {
+            if part["filename"]
+                .as_str()
+                .is_some_and(|name| !name.is_empty())
+            {
+                occurrences.push(Occurrence {
+                    message_id: message_id.clone(),
+                    part: part.clone(),
+                });
+            }
+        }
+    }
+
+    // Files of different sizes can't be the same, so only those sharing a size are downloaded
+    // to be hashed
+    let mut sizes: HashMap<u64, usize> = HashMap::new();
+    for occurrence in &occurrences {
+        *sizes.entry(occurrence.size()).or_default() += 1;
+    }
+    let mut hashes = Vec::new();
+    for occurrence in &occurrences {
+        let hashed = if sizes[&occurrence.size()] > 1 {
+            let data = part_data(&client, user_id, &occurrence.message_id, &occurrence.part)
+                .await
+                .with_context(|| format!("Failed to download '{}'", occurrence.filename()))?;
+            Some((format!("{:x}", Sha256::digest(&data)), data))
+        } else {
+            None
+        };
+        hashes.push(hashed);
+    }
+
+    let unique = group_duplicates(hashes);
+    let mut attachments = Vec::new();
+    for file in unique {
+        let first = &occurrences[file.occurrences[0]];
+        let (filename, mime_type) = (first.filename(), first.mime_type());
+        let mut attachment = json!({
+            "filename": filename,
+            "mime_type": mime_type,
+            "size": first.size(),
+            "sha256": file.sha256,
+            "messages": file
+                .occurrences
+                .iter()
+                .map(|&index| json!({
+                    "message_id": occurrences[index].message_id,
+                    "filename": occurrences[index].filename(),
+                }))
+                .collect::<Vec<_>>(),
+        });
+        if extract_text {
+            // A file that can't be read doesn't spoil the rest of the list
+            let extracted = if is_extractable_document(mime_type, filename) {
+                extract_once(gmail_server, first, file.data)
+                    .await
+                    .map_err(|e| format!("{e:#}"))
+            } else {
+                Err("File type not supported for text extraction".to_string())
+            };
+            match extracted {
+                Ok(text) => {
+                    attachment["text_size"] = text_size(&text);
+                    attachment["extracted_text"] = json!(text);
+                    if let Some(chars) = preview_chars {
+                        preview_text(&mut attachment, chars);
+                    }
+                }
+                Err(error) => {
+                    attachment["extracted_text"] = Value::Null;
+                    attachment["error"] = json!(error);
+                }
+            }
+        }
+        attachments.push(attachment);
+    }
+
+    let duplicate_bytes: u64 = attachments
+        .iter()
+        .map(|attachment| {
+            let copies = attachment["messages"].as_array().map_or(1, Vec::len) as u64;
+            attachment["size"].as_u64().unwrap_or_default() * (copies - 1)
+        })
+        .sum();
+    Ok(with_response_size(json!({
+        "thread_id": thread_id,
+        "total_attachments": occurrences.len(),
+        "unique_attachments": attachments.len(),
+        "duplicate_bytes": duplicate_bytes,
+        "attachments": attachments,
+    })))
+}
+
+/// The text of a file, downloading it if it wasn't hashed, after the virus scan if one is set up
+async fn extract_once(
+    gmail_server: &GmailServer,
+    occurrence: &Occurrence,
+    data: Option<Vec<u8>>,
+) -> Result<String> {
+    let data = match data {
+        Some(data) => data,
+        None => {
+            let client = gmail_server.authenticated_client().await?;
+            part_data(
+                &client,
+                gmail_server.user_id(),
+                &occurrence.message_id,
+                &occurrence.part,
+            )
+            .await?
+        }
+    };
+    let filename = occurrence.filename();
+    if let Some(scanner) = gmail_server.attachment_scanner() {
+        scanner.check(filename, &data).await?;
+    }
+    extract_text_from_bytes(&data, occurrence.mime_type(), filename)
+        .context("Failed to extract text from attachment")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockGmail, MOCK_ATTACHMENT_TEXT};
+
+    #[test]
+    fn test_group_duplicates() {
+        let hashed = |hash: &str| Some((hash.to_string(), hash.as_bytes().to_vec()));
+        let unique = group_duplicates(vec![hashed("a"), None, hashed("b"), hashed("a"), None]);
+        let groups: Vec<_> = unique.iter().map(|file| file.occurrences.clone()).collect();
+        assert_eq!(groups, [vec![0, 3], vec![1], vec![2], vec![4]]);
+        assert_eq!(unique[0].sha256.as_deref(), Some("a"));
+        assert_eq!(unique[0].data.as_deref(), Some(&b"a"[..]));
+        assert!(unique[1].sha256.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_list_thread_attachments_against_mock_gmail() {
+        let gmail = MockGmail::start().await;
+        let gmail_server = gmail.gmail_server(&gmail.config()).await;
+
+        let result = list_thread_attachments(&gmail_server, "thread-5", false, None)
+            .await
+            .unwrap();
+        assert_eq!(result["total_attachments"], 3);
+        assert_eq!(result["unique_attachments"], 2);
+        assert_eq!(result["duplicate_bytes"], 30);
+        let contract = &result["attachments"][0];
+        assert_eq!(contract["filename"], "contract.txt");
+        assert_eq!(
+            contract["messages"],
+            json!([
+                { "message_id": "msg-5", "filename": "contract.txt" },
+                { "message_id": "msg-6", "filename": "contract-signed.txt" },
+            ])
+        );
+        assert_eq!(contract["sha256"].as_str().unwrap().len(), 64);
+        // Same size, different content
+        assert_eq!(result["attachments"][1]["filename"], "changes.txt");
+        assert_ne!(result["attachments"][1]["sha256"], contract["sha256"]);
+        assert!(contract.get("extracted_text").is_none());
+
+        // A file of a size no other shares isn't downloaded, unless its text is wanted
+        let result = list_thread_attachments(&gmail_server, "thread-1", false, None)
+            .await
+            .unwrap();
+        assert_eq!(result["attachments"][0]["sha256"], Value::Null);
+        let downloads = |requests: Vec<wiremock::Request>| {
+            requests
+                .iter()
+                .filter(|request| request.url.path().contains("/attachments/"))
+                .count()
+        };
+        assert_eq!(downloads(gmail.requests().await), 3);
+        let result = list_thread_attachments(&gmail_server, "thread-1", true, Some(7))
+            .await
+            .unwrap();
+        let notes = &result["attachments"][0];
+        assert_eq!(notes["extracted_text"], "Meeting");
+        assert_eq!(notes["total_chars"], MOCK_ATTACHMENT_TEXT.len());
+        assert_eq!(downloads(gmail.requests().await), 4);
+    }
+}
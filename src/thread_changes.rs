@@ -0,0 +1,293 @@
+use anyhow::{Context, Result};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use tracing::{error, instrument};
+
+use crate::gmail::{GmailError, GmailServer, GMAIL_API_BASE};
+use crate::labels::label_names;
+use crate::oauth::SCOPE_READONLY;
+use crate::pgp::decrypt_message;
+use crate::tools::{message_url, summarize_message, with_response_size, MessageFormat};
+use crate::truncate::fit_messages;
+
+/// What happened to one thread, folded from the mailbox history in order
+#[derive(Debug, Default)]
+struct ThreadChanges {
+    /// Messages added to the thread, in the order Gmail recorded them
+    added: Vec<String>,
+    /// Messages that were in the thread and have since been deleted
+    deleted: BTreeSet<String>,
+    /// Net label changes on messages that were already in the thread
+    labels: BTreeMap<String, LabelChange>,
+}
+
+/// Labels added to and removed from a message
+#[derive(Debug, Default)]
+struct LabelChange {
+    added: BTreeSet<String>,
+    removed: BTreeSet<String>,
+}
+
+impl ThreadChanges {
+    /// Fold one history record into the changes to `thread_id`
+    fn apply(&mut self, thread_id: &str, record: &Value) {
+        for message_id in changed_in_thread(record, "messagesAdded", thread_id) {
+            if !self.added.iter().any(|id| id == message_id) {
+                self.added.push(message_id.to_string());
+            }
+        }
+
+        for (field, adding) in [("labelsAdded", true), ("labelsRemoved", false)] {
+            for change in record[field].as_array().into_iter().flatten() {
+                let Some(message_id) = message_in_thread(change, thread_id) else {
+                    continue;
+                };
+                // A new message is returned whole, with the labels it has now
+                if self.added.iter().any(|id| id == message_id) {
+                    continue;
+                }
+                let entry = self.labels.entry(message_id.to_string()).or_default();
+                let (to, from) = if adding {
+                    (&mut entry.added, &mut entry.removed)
+                } else {
+                    (&mut entry.removed, &mut entry.added)
+                };
+                for label in change["labelIds"].as_array().into_iter().flatten() {
+                    if let Some(label) = label.as_str() {
+                        // Undoing an earlier change leaves the label as it was
+                        if !from.remove(label) {
+                            to.insert(label.to_string());
+                        }
+                    }
+                }
+            }
+        }
+
+        for message_id in changed_in_thread(record, "messagesDeleted", thread_id) {
+            self.labels.remove(message_id);
+            match self.added.iter().position(|id| id == message_id) {
+                Some(index) => {
+                    self.added.remove(index);
+                }
+                None => {
+                    self.deleted.insert(message_id.to_string());
+                }
+            }
+        }
+    }
+}
+
+/// IDs of the messages in `thread_id` that a history record's `field` lists
+fn changed_in_thread<'a>(record: &'a Value, field: &str, thread_id: &str) -> Vec<&'a str> {
+    record[field]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|change| message_in_thread(change, thread_id))
+        .collect()
+}
+
+/// The ID of the message a history change wraps, if it belongs to `thread_id`
+fn message_in_thread<'a>(change: &'a Value, thread_id: &str) -> Option<&'a str> {
+    let message = &change["message"];
+    if message["threadId"] != thread_id {
+        return None;
+    }
+    message["id"].as_str()
+}
+
+/// New messages and label changes in a thread since a previously seen historyId, with the
+/// historyId to pass next time
+#[instrument(name = "tool", skip_all, fields(tool = "get_thread_changes", thread_id = %thread_id))]
+pub async fn get_thread_changes(
+    gmail_server: &GmailServer,
+    thread_id: &str,
+    history_id: &str,
+    format: MessageFormat,
+) -> Result<Value> {
+    gmail_server.check_authentication(&[SCOPE_READONLY]).await?;
+
+    let client = gmail_server.authenticated_client().await?;
+    let user_id = gmail_server.user_id();
+
+    let mut changes = ThreadChanges::default();
+    let mut latest_history_id = history_id.to_string();
+    let mut page_token: Option<String> = None;
+    loop {
+        let mut url = format!(
+            "{GMAIL_API_BASE}/users/{user_id}/history?startHistoryId={}",
+            urlencoding::encode(history_id)
+        );
+        if let Some(token) = &page_token {
+            url.push_str(&format!("&pageToken={}", urlencoding::encode(token)));
+        }
+
+        let response = client
+            .get(&url)
+            .send()
+            .await
+            .context("Failed to list history")?;
+
+        // Gmail only keeps about a week of history
+        if response.status() == StatusCode::NOT_FOUND {
+            anyhow::bail!(
+                "History {history_id} has expired; fetch the whole thread again and track its \
+                 current historyId"
+            );
+        }
+
+        if !response.status().is_success() {
+            return Err(GmailError::from_response(response).await.into());
+        }
+
+        let page: Value = response.json().await.context("Failed to parse history")?;
+        for record in page["history"].as_array().into_iter().flatten() {
+            changes.apply(thread_id, record);
+        }
+        if let Some(history_id) = page["historyId"].as_str() {
+            latest_history_id = history_id.to_string();
+        }
+
+        match page["nextPageToken"].as_str() {
+            Some(token) => page_token = Some(token.to_string()),
+            None => break,
+        }
+    }
+
+    let urls: Vec<String> = changes
+        .added
+        .iter()
+        .map(|message_id| message_url(user_id, message_id, format, None))
+        .collect();
+    let fetched = client
+        .batch_get(&urls)
+        .await
+        .context("Failed to get messages")?;
+    let mut messages_added = Vec::new();
+    for (message_id, message) in changes.added.iter().zip(fetched) {
+        let mut message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Error fetching message {}: {}", message_id, e);
+                continue;
+            }
+        };
+        decrypt_message(gmail_server, &client, message_id, &mut message).await;
+        let mut summary = summarize_message(message_id, &message, format)?;
+        summary["label_ids"] = message["labelIds"].clone();
+        messages_added.push(summary);
+    }
+
+    let names = if changes.labels.is_empty() {
+        HashMap::new()
+    } else {
+        label_names(&client, user_id).await?
+    };
+    let name = |label_id: &String| names.get(label_id).unwrap_or(label_id).clone();
+    let label_changes: Vec<Value> = changes
+        .labels
+        .iter()
+        .filter(|(_, change)| !change.added.is_empty() || !change.removed.is_empty())
+        .map(|(message_id, change)| {
+            json!({
+                "message_id": message_id,
+                "labels_added": change.added.iter().map(name).collect::<Vec<_>>(),
+                "labels_removed": change.removed.iter().map(name).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+
+    let result = with_response_size(json!({
+        "thread_id": thread_id,
+        "history_id": latest_history_id,
+        "messages_added": messages_added,
+        "messages_deleted": changes.deleted,
+        "label_changes": label_changes,
+    }));
+    Ok(fit_messages(gmail_server, "get_thread_changes", result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockGmail;
+
+    fn change(message_id: &str, thread_id: &str, labels: &[&str]) -> Value {
+        json!({
+            "message": { "id": message_id, "threadId": thread_id },
+            "labelIds": labels,
+        })
+    }
+
+    #[test]
+    fn test_thread_changes_fold_history_in_order() {
+        let mut changes = ThreadChanges::default();
+        let records = [
+            json!({ "messagesAdded": [change("m3", "t1", &[]), change("m9", "t2", &[])] }),
+            json!({ "labelsAdded": [change("m1", "t1", &["STARRED", "IMPORTANT"])] }),
+            json!({ "labelsRemoved": [change("m1", "t1", &["STARRED", "UNREAD"])] }),
+            json!({ "labelsAdded": [change("m3", "t1", &["STARRED"])] }),
+            json!({ "messagesAdded": [change("m4", "t1", &[])] }),
+            json!({ "messagesDeleted": [change("m4", "t1", &[]), change("m2", "t1", &[])] }),
+        ];
+        for record in &records {
+            changes.apply("t1", record);
+        }
+
+        assert_eq!(changes.added, vec!["m3"]);
+        assert_eq!(changes.deleted, BTreeSet::from(["m2".to_string()]));
+        assert_eq!(changes.labels.len(), 1);
+        let m1 = &changes.labels["m1"];
+        assert_eq!(m1.added, BTreeSet::from(["IMPORTANT".to_string()]));
+        assert_eq!(m1.removed, BTreeSet::from(["UNREAD".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn test_get_thread_changes_against_mock_gmail() {
+        let gmail = MockGmail::start().await;
+        let config = gmail.config();
+        let gmail_server = gmail.gmail_server(&config).await;
+
+        let changes = get_thread_changes(&gmail_server, "thread-1", "100", MessageFormat::Full)
+            .await
+            .unwrap();
+        assert_eq!(changes["history_id"], "104");
+        let bytes = changes["response_size"]["bytes"].as_u64().unwrap();
+        assert!(bytes > 0);
+        assert_eq!(
+            changes["response_size"]["estimated_tokens"],
+            bytes.div_ceil(4)
+        );
+        let added = changes["messages_added"].as_array().unwrap();
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0]["message_id"], "msg-2");
+        assert_eq!(changes["messages_deleted"], json!([]));
+        assert_eq!(
+            changes["label_changes"],
+            json!([{
+                "message_id": "msg-1",
+                "labels_added": ["STARRED"],
+                "labels_removed": ["Snoozed"],
+            }])
+        );
+
+        let changes = get_thread_changes(&gmail_server, "thread-2", "100", MessageFormat::Minimal)
+            .await
+            .unwrap();
+        assert_eq!(changes["messages_added"], json!([]));
+        assert_eq!(
+            changes["label_changes"],
+            json!([{
+                "message_id": "msg-3",
+                "labels_added": ["STARRED"],
+                "labels_removed": [],
+            }])
+        );
+
+        let error = get_thread_changes(&gmail_server, "thread-1", "50", MessageFormat::Full)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("has expired"));
+    }
+}
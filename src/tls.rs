@@ -0,0 +1,207 @@
+use anyhow::Result;
+use axum::Router;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::config::TlsConfig;
+
+/// Certificates for serving HTTPS, loaded before the listener starts so bad files fail startup
+#[cfg(feature = "tls")]
+pub struct Tls(std::sync::Arc<rustls::ServerConfig>);
+
+#[cfg(not(feature = "tls"))]
+pub enum Tls {}
+
+/// Whether a request's connection presented a client certificate signed by `--tls-client-ca`,
+/// added to the extensions of every request served over HTTPS
+#[derive(Clone, Copy, Debug)]
+pub struct ClientCertificate {
+    pub verified: bool,
+}
+
+/// Load `--tls-cert` and `--tls-key`, or `None` to serve plain HTTP
+#[cfg(feature = "tls")]
+pub fn load(config: &TlsConfig) -> Result<Option<Tls>> {
+    use anyhow::Context;
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::PrivateKeyDer;
+
+    let (Some(cert_path), Some(key_path)) = (&config.tls_cert, &config.tls_key) else {
+        return Ok(None);
+    };
+    let certs = server::read_certs(cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .with_context(|| format!("Failed to read TLS key {}", key_path.display()))?;
+
+    let provider = std::sync::Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &config.tls_client_ca {
+        Some(ca_path) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in server::read_certs(ca_path)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("Invalid CA certificate in {}", ca_path.display()))?;
+            }
+            // Browsers completing the OAuth login have no certificate, so the handshake lets
+            // them through and only the MCP and tools routes insist on one
+            let verifier =
+                rustls::server::WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                    .allow_unauthenticated()
+                    .build()
+                    .context("Failed to set up client certificate verification")?;
+            info!(
+                "🔐 MCP and tools endpoints require a client certificate signed by {}",
+                ca_path.display()
+            );
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder
+        .with_single_cert(certs, key)
+        .context("TLS certificate and key don't match")?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    info!("🔒 Serving HTTPS with {}", cert_path.display());
+    Ok(Some(Tls(server_config.into())))
+}
+
+#[cfg(not(feature = "tls"))]
+pub fn load(config: &TlsConfig) -> Result<Option<Tls>> {
+    if config.enabled() {
+        anyhow::bail!("--tls-cert requires building with the `tls` feature");
+    }
+    Ok(None)
+}
+
+/// Serve the app until cancelled, over HTTPS when certificates were loaded. Requests carry
+/// the peer address as `ConnectInfo<SocketAddr>`
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    tls: Option<Tls>,
+    ct: CancellationToken,
+) -> Result<()> {
+    let Some(tls) = tls else {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(async move {
+            ct.cancelled().await;
+            info!("Server is shutting down...");
+        })
+        .await?;
+        return Ok(());
+    };
+    #[cfg(feature = "tls")]
+    return server::serve(listener, app, tls, ct).await;
+    #[cfg(not(feature = "tls"))]
+    match tls {}
+}
+
+#[cfg(feature = "tls")]
+mod server {
+    use anyhow::{Context, Result};
+    use axum::Router;
+    use axum_server::accept::Accept;
+    use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+    use futures::future::BoxFuture;
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::CertificateDer;
+    use std::net::SocketAddr;
+    use std::path::Path;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::sync::CancellationToken;
+    use tower::Layer;
+    use tracing::info;
+
+    use super::{ClientCertificate, Tls};
+
+    /// Every certificate in a PEM file
+    pub fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+        let certs = CertificateDer::pem_file_iter(path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .with_context(|| format!("Failed to read certificates from {}", path.display()))?;
+        if certs.is_empty() {
+            anyhow::bail!("No certificates found in {}", path.display());
+        }
+        Ok(certs)
+    }
+
+    pub async fn serve(
+        listener: TcpListener,
+        app: Router,
+        tls: Tls,
+        ct: CancellationToken,
+    ) -> Result<()> {
+        let handle = axum_server::Handle::new();
+        let shutdown = handle.clone();
+        tokio::spawn(async move {
+            ct.cancelled().await;
+            info!("Server is shutting down...");
+            shutdown.graceful_shutdown(None);
+        });
+        let acceptor = ClientCertAcceptor(RustlsAcceptor::new(RustlsConfig::from_config(tls.0)));
+        axum_server::from_tcp(listener.into_std()?)
+            .acceptor(acceptor)
+            .handle(handle)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await?;
+        Ok(())
+    }
+
+    /// Completes the TLS handshake and tells the connection's requests whether the client
+    /// presented a certificate; the verifier has already rejected any it couldn't validate
+    #[derive(Clone)]
+    struct ClientCertAcceptor(RustlsAcceptor);
+
+    impl<S: Send + 'static> Accept<TcpStream, S> for ClientCertAcceptor {
+        type Stream = <RustlsAcceptor as Accept<TcpStream, S>>::Stream;
+        type Service = <axum::Extension<ClientCertificate> as Layer<S>>::Service;
+        type Future = BoxFuture<'static, std::io::Result<(Self::Stream, Self::Service)>>;
+
+        fn accept(&self, stream: TcpStream, service: S) -> Self::Future {
+            let handshake = self.0.accept(stream, service);
+            Box::pin(async move {
+                let (stream, service) = handshake.await?;
+                let verified = stream.get_ref().1.peer_certificates().is_some();
+                let service = axum::Extension(ClientCertificate { verified }).layer(service);
+                Ok((stream, service))
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_load_without_certificate() {
+        assert!(load(&TlsConfig::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_load_rejects_bad_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = dir.path().join("empty.pem");
+        std::fs::write(&empty, "").unwrap();
+        let config = |cert: PathBuf| TlsConfig {
+            tls_cert: Some(cert),
+            tls_key: Some(empty.clone()),
+            ..Default::default()
+        };
+        assert!(load(&config(dir.path().join("missing.pem"))).is_err());
+        assert!(load(&config(empty.clone())).is_err());
+        let with_client_ca = TlsConfig {
+            tls_client_ca: Some(dir.path().join("missing-ca.pem")),
+            ..config(empty.clone())
+        };
+        assert!(load(&with_client_ca).is_err());
+    }
+}
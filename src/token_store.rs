@@ -0,0 +1,498 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::accounts::DEFAULT_ACCOUNT;
+use crate::config::{Config, TokenStoreKind};
+use crate::oauth::OAuthToken;
+
+/// Where an account's OAuth token is persisted between runs
+#[async_trait]
+pub trait TokenStore: Send + Sync {
+    /// The stored token, or None when nothing has been stored yet
+    async fn load(&self) -> Result<Option<OAuthToken>>;
+
+    async fn save(&self, token: &OAuthToken) -> Result<()>;
+
+    /// Remove the stored token; removing a missing one is not an error
+    async fn delete(&self) -> Result<()>;
+
+    /// Human-readable location of the token for logs, never including credentials
+    fn location(&self) -> String;
+}
+
+/// Open the `--token-store` backend for an account
+pub fn open(config: &Config, account: &str) -> Result<Arc<dyn TokenStore>> {
+    Ok(match config.token_storage.token_store {
+        TokenStoreKind::File => Arc::new(FileTokenStore::new(
+            crate::utils::get_app_file_path(config, &crate::accounts::token_file_name(account))?,
+            config.token_storage.allow_insecure_token_file,
+        )),
+        TokenStoreKind::Memory => Arc::new(MemoryTokenStore::default()),
+        TokenStoreKind::Env => Arc::new(EnvTokenStore::new(env_var_name(
+            &config.token_storage.token_env_var,
+            account,
+        ))),
+        #[cfg(feature = "redis")]
+        TokenStoreKind::Redis => {
+            let url = config
+                .token_storage
+                .redis_url
+                .as_deref()
+                .ok_or_else(|| anyhow::anyhow!("--token-store redis requires --redis-url"))?;
+            Arc::new(RedisTokenStore::new(
+                url,
+                format!("{}token:{account}", config.token_storage.redis_key_prefix),
+            )?)
+        }
+        #[cfg(not(feature = "redis"))]
+        TokenStoreKind::Redis => {
+            anyhow::bail!("--token-store redis requires building with the `redis` feature")
+        }
+        #[cfg(feature = "keyring")]
+        TokenStoreKind::Keyring => Arc::new(KeyringTokenStore::new(
+            config.token_storage.keyring_service.clone(),
+            account.to_string(),
+        )),
+        #[cfg(not(feature = "keyring"))]
+        TokenStoreKind::Keyring => {
+            anyhow::bail!("--token-store keyring requires building with the `keyring` feature")
+        }
+    })
+}
+
+/// Environment variable holding an account's token: the base name, suffixed for named accounts
+fn env_var_name(base: &str, account: &str) -> String {
+    if account == DEFAULT_ACCOUNT {
+        base.to_string()
+    } else {
+        format!("{base}_{}", account.to_ascii_uppercase().replace('-', "_"))
+    }
+}
+
+/// A JSON token file in the app data directory, only readable by its owner on Unix
+pub struct FileTokenStore {
+    path: PathBuf,
+    /// Load world-readable files instead of refusing them
+    allow_insecure: bool,
+}
+
+impl FileTokenStore {
+    pub fn new(path: PathBuf, allow_insecure: bool) -> Self {
+        Self {
+            path,
+            allow_insecure,
+        }
+    }
+
+    /// Restrict the file to its owner, refusing to trust a world-readable one unless allowed
+    #[cfg(unix)]
+    fn check_permissions(&self) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&self.path)
+            .context("Failed to read token file metadata")?
+            .permissions()
+            .mode()
+            & 0o777;
+        if mode & 0o077 == 0 {
+            return Ok(());
+        }
+        if mode & 0o004 != 0 && !self.allow_insecure {
+            anyhow::bail!(
+                "Token file {} is readable by other users (mode {mode:o}); run `chmod 600 {}` \
+                 or pass --allow-insecure-token-file",
+                self.path.display(),
+                self.path.display()
+            );
+        }
+        warn!(
+            "Token file {} had mode {:o}, restricting it to 600",
+            self.path.display(),
+            mode
+        );
+        fs::set_permissions(&self.path, fs::Permissions::from_mode(0o600))
+            .context("Failed to restrict token file permissions")
+    }
+
+    #[cfg(not(unix))]
+    fn check_permissions(&self) -> Result<()> {
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn write(&self, contents: &str) -> std::io::Result<()> {
+        use std::io::Write;
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        // The mode only applies to new files, so also tighten an existing one before writing
+        if self.path.exists() {
+            fs::set_permissions(&self.path, fs::Permissions::from_mode(0o600))?;
+        }
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&self.path)?
+            .write_all(contents.as_bytes())
+    }
+
+    #[cfg(not(unix))]
+    fn write(&self, contents: &str) -> std::io::Result<()> {
+        fs::write(&self.path, contents)
+    }
+}
+
+#[async_trait]
+impl TokenStore for FileTokenStore {
+    async fn load(&self) -> Result<Option<OAuthToken>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        self.check_permissions()?;
+        let token_json = fs::read_to_string(&self.path).context("Failed to read token file")?;
+        let token = serde_json::from_str(&token_json).context("Failed to deserialize token")?;
+        Ok(Some(token))
+    }
+
+    async fn save(&self, token: &OAuthToken) -> Result<()> {
+        let token_json =
+            serde_json::to_string_pretty(token).context("Failed to serialize token")?;
+        self.write(&token_json)
+            .context("Failed to write token file")
+    }
+
+    async fn delete(&self) -> Result<()> {
+        if self.path.exists() {
+            fs::remove_file(&self.path).context("Failed to delete token file")?;
+        }
+        Ok(())
+    }
+
+    fn location(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+/// Keeps the token for the life of the process only
+#[derive(Default)]
+pub struct MemoryTokenStore {
+    token: Mutex<Option<OAuthToken>>,
+}
+
+#[async_trait]
+impl TokenStore for MemoryTokenStore {
+    async fn load(&self) -> Result<Option<OAuthToken>> {
+        Ok(self.token.lock().await.clone())
+    }
+
+    async fn save(&self, token: &OAuthToken) -> Result<()> {
+        *self.token.lock().await = Some(token.clone());
+        Ok(())
+    }
+
+    async fn delete(&self) -> Result<()> {
+        *self.token.lock().await = None;
+        Ok(())
+    }
+
+    fn location(&self) -> String {
+        "memory".to_string()
+    }
+}
+
+/// A token JSON document provided through an environment variable, e.g. from a secret mount.
+///
+/// The variable can't be written back, so refreshed tokens only live in memory.
+pub struct EnvTokenStore {
+    var: String,
+}
+
+impl EnvTokenStore {
+    pub fn new(var: String) -> Self {
+        Self { var }
+    }
+}
+
+#[async_trait]
+impl TokenStore for EnvTokenStore {
+    async fn load(&self) -> Result<Option<OAuthToken>> {
+        match std::env::var(&self.var) {
+            Ok(token_json) if !token_json.trim().is_empty() => serde_json::from_str(&token_json)
+                .with_context(|| format!("Failed to deserialize token from {}", self.var))
+                .map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    async fn save(&self, _token: &OAuthToken) -> Result<()> {
+        debug!("Not persisting token: {} is read-only", self.var);
+        Ok(())
+    }
+
+    async fn delete(&self) -> Result<()> {
+        debug!("Not deleting token: {} is read-only", self.var);
+        Ok(())
+    }
+
+    fn location(&self) -> String {
+        format!("environment variable {}", self.var)
+    }
+}
+
+/// A token shared by every replica through a Redis key
+#[cfg(feature = "redis")]
+pub struct RedisTokenStore {
+    client: redis::Client,
+    key: String,
+}
+
+#[cfg(feature = "redis")]
+impl RedisTokenStore {
+    pub fn new(url: &str, key: String) -> Result<Self> {
+        let client = redis::Client::open(url).context("Invalid Redis URL")?;
+        Ok(Self { client, key })
+    }
+
+    async fn connection(&self) -> Result<redis::aio::MultiplexedConnection> {
+        self.client
+            .get_multiplexed_async_connection()
+            .await
+            .context("Failed to connect to Redis")
+    }
+}
+
+#[cfg(feature = "redis")]
+#[async_trait]
+impl TokenStore for RedisTokenStore {
+    async fn load(&self) -> Result<Option<OAuthToken>> {
+        use redis::AsyncCommands;
+        let token_json: Option<String> = self
+            .connection()
+            .await?
+            .get(&self.key)
+            .await
+            .context("Failed to read token from Redis")?;
+        token_json
+            .map(|token_json| {
+                serde_json::from_str(&token_json).context("Failed to deserialize token")
+            })
+            .transpose()
+    }
+
+    async fn save(&self, token: &OAuthToken) -> Result<()> {
+        use redis::AsyncCommands;
+        let token_json = serde_json::to_string(token).context("Failed to serialize token")?;
+        self.connection()
+            .await?
+            .set::<_, _, ()>(&self.key, token_json)
+            .await
+            .context("Failed to write token to Redis")
+    }
+
+    async fn delete(&self) -> Result<()> {
+        use redis::AsyncCommands;
+        self.connection()
+            .await?
+            .del::<_, ()>(&self.key)
+            .await
+            .context("Failed to delete token from Redis")
+    }
+
+    fn location(&self) -> String {
+        format!("Redis key {}", self.key)
+    }
+}
+
+/// A token in the OS keychain, under the account's name
+#[cfg(feature = "keyring")]
+pub struct KeyringTokenStore {
+    service: String,
+    account: String,
+}
+
+#[cfg(feature = "keyring")]
+impl KeyringTokenStore {
+    pub fn new(service: String, account: String) -> Self {
+        Self { service, account }
+    }
+
+    /// Run a keychain operation off the async runtime, since the platform APIs block
+    async fn with_entry<T: Send + 'static>(
+        &self,
+        operation: impl FnOnce(keyring::Entry) -> keyring::Result<T> + Send + 'static,
+    ) -> keyring::Result<T> {
+        let (service, account) = (self.service.clone(), self.account.clone());
+        tokio::task::spawn_blocking(move || operation(keyring::Entry::new(&service, &account)?))
+            .await
+            .map_err(|e| keyring::Error::PlatformFailure(Box::new(e)))?
+    }
+}
+
+#[cfg(feature = "keyring")]
+#[async_trait]
+impl TokenStore for KeyringTokenStore {
+    async fn load(&self) -> Result<Option<OAuthToken>> {
+        match self.with_entry(|entry| entry.get_password()).await {
+            Ok(token_json) => serde_json::from_str(&token_json)
+                .context("Failed to deserialize token")
+                .map(Some),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e).context("Failed to read token from the keychain"),
+        }
+    }
+
+    async fn save(&self, token: &OAuthToken) -> Result<()> {
+        // Compact JSON, as Windows Credential Manager limits the size of a secret
+        let token_json = serde_json::to_string(token).context("Failed to serialize token")?;
+        self.with_entry(move |entry| entry.set_password(&token_json))
+            .await
+            .context("Failed to write token to the keychain")
+    }
+
+    async fn delete(&self) -> Result<()> {
+        match self.with_entry(|entry| entry.delete_credential()).await {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(e).context("Failed to delete token from the keychain"),
+        }
+    }
+
+    fn location(&self) -> String {
+        format!("OS keychain entry {}/{}", self.service, self.account)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(access_token: &str) -> OAuthToken {
+        OAuthToken {
+            access_token: access_token.to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: 3600,
+            refresh_token: Some("refresh".to_string()),
+            scope: String::new(),
+            created_at: 0,
+        }
+    }
+
+    async fn assert_round_trip(store: &dyn TokenStore) {
+        assert!(store.load().await.unwrap().is_none());
+        store.save(&token("first")).await.unwrap();
+        store.save(&token("second")).await.unwrap();
+        assert_eq!(store.load().await.unwrap().unwrap().access_token, "second");
+        store.delete().await.unwrap();
+        assert!(store.load().await.unwrap().is_none());
+        store.delete().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_file_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token.json");
+        assert_round_trip(&FileTokenStore::new(path.clone(), false)).await;
+        assert_eq!(
+            FileTokenStore::new(path.clone(), false).location(),
+            path.display().to_string()
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_file_store_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token.json");
+        let mode = || fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        let set_mode = |mode| fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        let store = FileTokenStore::new(path.clone(), false);
+
+        store.save(&token("new")).await.unwrap();
+        assert_eq!(mode(), 0o600);
+        set_mode(0o640);
+        store.save(&token("rewritten")).await.unwrap();
+        assert_eq!(mode(), 0o600);
+
+        // Group access is repaired on load
+        set_mode(0o640);
+        assert!(store.load().await.unwrap().is_some());
+        assert_eq!(mode(), 0o600);
+
+        // World-readable files are refused unless allowed, then repaired
+        set_mode(0o644);
+        assert!(store.load().await.is_err());
+        assert_eq!(mode(), 0o644);
+        let insecure = FileTokenStore::new(path.clone(), true);
+        assert!(insecure.load().await.unwrap().is_some());
+        assert_eq!(mode(), 0o600);
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        assert_round_trip(&MemoryTokenStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn test_env_store_is_read_only() {
+        let var = "GMAIL_MCP_TEST_TOKEN_STORE";
+        let store = EnvTokenStore::new(var.to_string());
+        assert!(store.load().await.unwrap().is_none());
+
+        std::env::set_var(var, serde_json::to_string(&token("from-env")).unwrap());
+        store.save(&token("refreshed")).await.unwrap();
+        store.delete().await.unwrap();
+        assert_eq!(
+            store.load().await.unwrap().unwrap().access_token,
+            "from-env"
+        );
+
+        std::env::set_var(var, "not json");
+        assert!(store.load().await.is_err());
+        std::env::remove_var(var);
+    }
+
+    #[test]
+    fn test_env_var_name() {
+        assert_eq!(env_var_name("GMAIL_TOKEN", DEFAULT_ACCOUNT), "GMAIL_TOKEN");
+        assert_eq!(env_var_name("GMAIL_TOKEN", "work-2"), "GMAIL_TOKEN_WORK_2");
+    }
+
+    #[test]
+    fn test_open_selects_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            app_data_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        assert_eq!(
+            open(&config, "work").unwrap().location(),
+            dir.path().join("token-work.json").display().to_string()
+        );
+
+        config.token_storage.token_store = TokenStoreKind::Memory;
+        assert_eq!(open(&config, "work").unwrap().location(), "memory");
+
+        config.token_storage.token_store = TokenStoreKind::Env;
+        assert_eq!(
+            open(&config, "work").unwrap().location(),
+            "environment variable GMAIL_TOKEN_WORK"
+        );
+
+        config.token_storage.token_store = TokenStoreKind::Redis;
+        assert!(open(&config, "work").is_err());
+
+        config.token_storage.token_store = TokenStoreKind::Keyring;
+        if cfg!(feature = "keyring") {
+            assert_eq!(
+                open(&config, "work").unwrap().location(),
+                "OS keychain entry gmail-mcp-server/work"
+            );
+        } else {
+            assert!(open(&config, "work").is_err());
+        }
+    }
+}
@@ -1,30 +1,301 @@
 use anyhow::{Context, Result};
 use base64::{engine::general_purpose::URL_SAFE, Engine};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveTime, TimeDelta, TimeZone, Utc};
+use mail_builder::headers::address::Address;
+use mail_builder::MessageBuilder;
+use rmcp::schemars;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::path::PathBuf;
-use tracing::error;
+use tracing::{error, instrument, warn};
 
-use crate::email::decode_email_content;
+use crate::cache::MessageCache;
+use crate::email::{ascii_domain_address, decode_email_content, unicode_domains};
 use crate::extract::{extract_text_from_bytes, is_extractable_document};
-use crate::gmail::{GmailServer, GMAIL_API_BASE};
+use crate::gmail::{GmailClient, GmailError, GmailServer, GMAIL_API_BASE};
+use crate::oauth::{SCOPE_COMPOSE, SCOPE_READONLY, SCOPE_SEND};
+use crate::outbox::{self, Outgoing};
+use crate::pgp::decrypt_message;
+use crate::saved_searches::expand_query;
+use crate::truncate::{fit_messages, fit_text};
+
+/// How much of each message to request from Gmail
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    schemars::JsonSchema,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageFormat {
+    /// Full payload including decoded bodies
+    #[default]
+    Full,
+    /// Headers and snippet only
+    Metadata,
+    /// IDs, labels and snippet only
+    Minimal,
+}
+
+impl MessageFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageFormat::Full => "full",
+            MessageFormat::Metadata => "metadata",
+            MessageFormat::Minimal => "minimal",
+        }
+    }
+}
+
+/// One of Gmail's inbox tabs
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, schemars::JsonSchema, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+    Primary,
+    Social,
+    Promotions,
+    Updates,
+    Forums,
+}
+
+impl Category {
+    const ALL: [Category; 5] = [
+        Category::Primary,
+        Category::Social,
+        Category::Promotions,
+        Category::Updates,
+        Category::Forums,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Primary => "primary",
+            Category::Social => "social",
+            Category::Promotions => "promotions",
+            Category::Updates => "updates",
+            Category::Forums => "forums",
+        }
+    }
+
+    /// The system label that files a message under the tab
+    fn label(&self) -> &'static str {
+        match self {
+            Category::Primary => "CATEGORY_PERSONAL",
+            Category::Social => "CATEGORY_SOCIAL",
+            Category::Promotions => "CATEGORY_PROMOTIONS",
+            Category::Updates => "CATEGORY_UPDATES",
+            Category::Forums => "CATEGORY_FORUMS",
+        }
+    }
+}
+
+/// Most threads a regex-filtered search fetches looking for matches
+const MAX_FILTERED_THREADS: usize = 500;
+
+/// Threads listed per page by a regex-filtered search
+const FILTERED_PAGE_SIZE: usize = 100;
+
+/// Distinct matched strings returned for each thread of a regex-filtered search
+const MAX_MATCHES_PER_THREAD: usize = 10;
+
+/// Compiled size limit for filter patterns, so a pathological one can't exhaust memory
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// Bytes of text per model token, roughly, for English prose
+const BYTES_PER_TOKEN: usize = 4;
+
+/// Narrowing of a search beyond its query: a date range added to it as `after:` and `before:`
+/// epoch seconds, an inbox tab and importance added as their operators, and regular expressions
+/// checked by the server after fetching the hits
+#[derive(Debug, Default)]
+pub struct SearchFilter {
+    /// Only messages from the last this many days, counted back from now
+    pub last_n_days: Option<u32>,
+    /// First day of the range as YYYY-MM-DD, or an RFC 3339 time
+    pub start_date: Option<String>,
+    /// Last day of the range, included, as YYYY-MM-DD, or an RFC 3339 time it ends before
+    pub end_date: Option<String>,
+    /// Offset such as +02:00 that dates are midnights in (default: the server's time zone)
+    pub utc_offset: Option<String>,
+    /// Only messages in this inbox tab
+    pub category: Option<Category>,
+    /// Only messages Gmail marked important, or only those it didn't
+    pub is_important: Option<bool>,
+    /// Matched against the text body of each message
+    pub body_regex: Option<String>,
+    /// Matched against each `Name: value` header line of each message
+    pub header_regex: Option<String>,
+}
+
+impl SearchFilter {
+    fn has_regex(&self) -> bool {
+        self.body_regex.is_some() || self.header_regex.is_some()
+    }
+
+    /// The query with the date range, tab and importance added, so callers don't need Gmail's
+    /// operators for them
+    fn query(&self, query: &str, now: DateTime<Utc>) -> Result<String> {
+        let offset = match self.utc_offset.as_deref().map(str::trim) {
+            None => None,
+            Some("UTC" | "Z") => FixedOffset::east_opt(0),
+            Some(offset) => Some(offset.parse::<FixedOffset>().map_err(|_| {
+                anyhow::anyhow!(
+                    "Invalid utc_offset '{offset}': use an offset such as +02:00 or -05:00"
+                )
+            })?),
+        };
+        let after = match (self.last_n_days, &self.start_date) {
+            (Some(_), Some(_)) => anyhow::bail!("last_n_days can't be combined with start_date"),
+            (Some(0), None) => anyhow::bail!("last_n_days must be at least 1"),
+            (Some(days), None) => Some((now - TimeDelta::days(days.into())).timestamp()),
+            (None, Some(start)) => {
+                Some(date_bound(start, offset, false).context("Invalid start_date")?)
+            }
+            (None, None) => None,
+        };
+        let before = self
+            .end_date
+            .as_deref()
+            .map(|end| date_bound(end, offset, true).context("Invalid end_date"))
+            .transpose()?;
+        if let (Some(after), Some(before)) = (after, before) {
+            if after >= before {
+                anyhow::bail!("The date range ends before it starts");
+            }
+        }
+        let mut filters = Vec::new();
+        filters.extend(after.map(|after| format!("after:{after}")));
+        filters.extend(before.map(|before| format!("before:{before}")));
+        filters.extend(
+            self.category
+                .map(|category| format!("category:{}", category.as_str())),
+        );
+        filters.extend(self.is_important.map(|important| {
+            if important {
+                "is:important"
+            } else {
+                "-is:important"
+            }
+            .to_string()
+        }));
+        if filters.is_empty() {
+            return Ok(query.to_string());
+        }
+
+        let mut terms = Vec::new();
+        if !query.trim().is_empty() {
+            terms.push(format!("({query})"));
+        }
+        terms.extend(filters);
+        Ok(terms.join(" "))
+    }
+}
+
+/// Epoch seconds of an RFC 3339 time, or of the midnight starting a YYYY-MM-DD date (the one
+/// after it, for the end of a range) at `offset` or in the server's time zone
+fn date_bound(text: &str, offset: Option<FixedOffset>, end: bool) -> Result<i64> {
+    let text = text.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        return Ok(time.timestamp());
+    }
+    let date = NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .with_context(|| format!("'{text}' is neither a YYYY-MM-DD date nor an RFC 3339 time"))?;
+    let date = if end {
+        date.succ_opt()
+            .ok_or_else(|| anyhow::anyhow!("'{text}' is out of range"))?
+    } else {
+        date
+    };
+    let midnight = date.and_time(NaiveTime::MIN);
+    let time = match offset {
+        Some(offset) => offset
+            .from_local_datetime(&midnight)
+            .single()
+            .map(|time| time.timestamp()),
+        // A midnight skipped by a daylight saving change starts the day at its first instant
+        None => Local
+            .from_local_datetime(&midnight)
+            .earliest()
+            .or_else(|| {
+                Local
+                    .from_local_datetime(&(midnight + TimeDelta::hours(1)))
+                    .earliest()
+            })
+            .map(|time| time.timestamp()),
+    };
+    time.ok_or_else(|| anyhow::anyhow!("'{text}' has no midnight in the time zone"))
+}
+
+/// Why a mutating tool was refused
+pub fn read_only_error(tool: &str) -> String {
+    format!("{tool} changes the mailbox, and the server is running with --read-only")
+}
+
+/// Headers requested when fetching messages in metadata format
+const METADATA_HEADERS: [&str; 3] = ["From", "Subject", "Date"];
+
+/// Build a messages.get URL for the given format and optional partial response fields
+pub fn message_url(
+    user_id: &str,
+    message_id: &str,
+    format: MessageFormat,
+    fields: Option<&str>,
+) -> String {
+    let mut url = format!(
+        "{GMAIL_API_BASE}/users/{user_id}/messages/{message_id}?format={}",
+        format.as_str()
+    );
+    if format == MessageFormat::Metadata {
+        for header in METADATA_HEADERS {
+            url.push_str(&format!("&metadataHeaders={header}"));
+        }
+    }
+    if let Some(fields) = fields {
+        url.push_str(&format!("&fields={}", urlencoding::encode(fields)));
+    }
+    url
+}
 
 /// Search Gmail threads
+#[instrument(name = "tool", skip_all, fields(tool = "search_threads"))]
 pub async fn search_threads(
     gmail_server: &GmailServer,
     query: &str,
     max_results: i64,
+    fields: Option<&str>,
+    filter: &SearchFilter,
 ) -> Result<Value> {
-    gmail_server.check_authentication().await?;
+    gmail_server.check_authentication(&[SCOPE_READONLY]).await?;
+    let query = &expand_query(gmail_server, query)?;
+    let query = &filter.query(query, Utc::now())?;
+    if filter.has_regex() {
+        if fields.is_some() {
+            anyhow::bail!("fields can't be combined with body_regex or header_regex");
+        }
+        return search_threads_matching(gmail_server, query, max_results, filter).await;
+    }
 
     let client = gmail_server.authenticated_client().await?;
     let user_id = gmail_server.user_id();
-    let url = format!(
+    let mut url = format!(
         "{}/users/{}/threads?q={}&maxResults={}",
         GMAIL_API_BASE,
         user_id,
         urlencoding::encode(query),
         max_results
     );
+    if let Some(fields) = fields {
+        url.push_str(&format!("&fields={}", urlencoding::encode(fields)));
+    }
 
     let response = client
         .get(&url)
@@ -32,36 +303,195 @@ pub async fn search_threads(
         .await
         .context("Failed to search threads")?;
 
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        return Err(anyhow::anyhow!("Gmail API error: {status} - {error_text}"));
+    if !response.status().is_success() {
+        return Err(GmailError::from_response(response).await.into());
     }
 
     let result: Value = response.json().await.context("Failed to parse response")?;
     Ok(result)
 }
 
+/// Page through a search until `max_results` threads have a message matching every regex of
+/// the filter, or `MAX_FILTERED_THREADS` have been fetched. Each thread lists what its regexes
+/// matched
+async fn search_threads_matching(
+    gmail_server: &GmailServer,
+    query: &str,
+    max_results: i64,
+    filter: &SearchFilter,
+) -> Result<Value> {
+    let compile = |pattern: &Option<String>, name: &str| {
+        pattern
+            .as_deref()
+            .map(|pattern| {
+                regex::RegexBuilder::new(pattern)
+                    .size_limit(REGEX_SIZE_LIMIT)
+                    .build()
+                    .with_context(|| format!("Invalid {name}"))
+            })
+            .transpose()
+    };
+    let body_regex = compile(&filter.body_regex, "body_regex")?;
+    let header_regex = compile(&filter.header_regex, "header_regex")?;
+    // Headers alone come with the metadata format, without downloading bodies
+    let format = match body_regex {
+        Some(_) => MessageFormat::Full,
+        None => MessageFormat::Metadata,
+    };
+
+    let client = gmail_server.authenticated_client().await?;
+    let user_id = gmail_server.user_id();
+    let wanted = usize::try_from(max_results).unwrap_or_default();
+    let mut matched = Vec::new();
+    let mut scanned = 0;
+    let mut page_token: Option<String> = None;
+    while matched.len() < wanted && scanned < MAX_FILTERED_THREADS {
+        let mut url = format!(
+            "{GMAIL_API_BASE}/users/{user_id}/threads?q={}&maxResults={}",
+            urlencoding::encode(query),
+            FILTERED_PAGE_SIZE.min(MAX_FILTERED_THREADS - scanned),
+        );
+        if let Some(token) = &page_token {
+            url.push_str(&format!("&pageToken={}", urlencoding::encode(token)));
+        }
+        let page = client
+            .get_json(&url)
+            .await
+            .context("Failed to search threads")?;
+        let listed: Vec<&Value> = page["threads"].as_array().into_iter().flatten().collect();
+        let thread_urls: Vec<String> = listed
+            .iter()
+            .filter_map(|thread| thread["id"].as_str())
+            .map(|thread_id| {
+                format!(
+                    "{GMAIL_API_BASE}/users/{user_id}/threads/{thread_id}?format={}",
+                    format.as_str()
+                )
+            })
+            .collect();
+        let threads = client
+            .batch_get(&thread_urls)
+            .await
+            .context("Failed to get threads")?;
+        scanned += listed.len();
+        for (listed, thread) in listed.into_iter().zip(threads) {
+            let thread = match thread {
+                Ok(thread) => thread,
+                Err(e) => {
+                    error!("Error fetching thread {}: {}", listed["id"], e);
+                    continue;
+                }
+            };
+            if let Some(matches) =
+                thread_matches(&thread, body_regex.as_ref(), header_regex.as_ref())
+            {
+                let mut listed = listed.clone();
+                listed["matches"] = json!(matches);
+                matched.push(listed);
+                if matched.len() == wanted {
+                    break;
+                }
+            }
+        }
+        match page["nextPageToken"].as_str() {
+            Some(token) => page_token = Some(token.to_string()),
+            None => break,
+        }
+    }
+
+    Ok(json!({
+        "threads": matched,
+        "resultSizeEstimate": matched.len(),
+        "scanned_threads": scanned,
+    }))
+}
+
+/// What the regexes matched in the first of the thread's messages that every given one matches
+fn thread_matches(
+    thread: &Value,
+    body_regex: Option<&regex::Regex>,
+    header_regex: Option<&regex::Regex>,
+) -> Option<Vec<String>> {
+    thread["messages"].as_array()?.iter().find_map(|message| {
+        let mut matches = Vec::new();
+        if let Some(regex) = header_regex {
+            let lines = message["payload"]["headers"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|header| {
+                    format!(
+                        "{}: {}",
+                        header["name"].as_str().unwrap_or_default(),
+                        header["value"].as_str().unwrap_or_default()
+                    )
+                });
+            let found: Vec<String> = lines
+                .flat_map(|line| {
+                    regex
+                        .find_iter(&line)
+                        .map(|found| found.as_str().to_string())
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+            if found.is_empty() {
+                return None;
+            }
+            matches.extend(found);
+        }
+        if let Some(regex) = body_regex {
+            let body = extract_message_body(message).unwrap_or_default();
+            let found: Vec<String> = regex
+                .find_iter(&body)
+                .map(|found| found.as_str().to_string())
+                .collect();
+            if found.is_empty() {
+                return None;
+            }
+            matches.extend(found);
+        }
+        let mut distinct = Vec::new();
+        for found in matches {
+            if !distinct.contains(&found) && distinct.len() < MAX_MATCHES_PER_THREAD {
+                distinct.push(found);
+            }
+        }
+        Some(distinct)
+    })
+}
+
 /// Create a Gmail draft
+#[instrument(name = "tool", skip_all, fields(tool = "create_draft"))]
 pub async fn create_draft(
     gmail_server: &GmailServer,
     to: &str,
     subject: &str,
     body: &str,
     thread_id: Option<&str>,
+    exclude_own_address: bool,
+    dry_run: bool,
 ) -> Result<Value> {
-    gmail_server.check_authentication().await?;
+    let (to, removed) = if exclude_own_address {
+        without_own_address(to, &gmail_server.email_address().await?)
+    } else {
+        (to.to_string(), Vec::new())
+    };
+    // Reported so the caller sees which of its recipients were left out
+    let removed = exclude_own_address.then(|| json!(removed));
+    let message = plain_text_message(&to, subject, body)?;
+    if dry_run || gmail_server.dry_run() {
+        let mut result = dry_run_result("create_draft", &message, thread_id);
+        if let Some(removed) = removed {
+            result["removed_recipients"] = removed;
+        }
+        return Ok(result);
+    }
+
+    gmail_server.check_authentication(&[SCOPE_COMPOSE]).await?;
 
     let client = gmail_server.authenticated_client().await?;
     let user_id = gmail_server.user_id();
 
-    // Build email message in RFC 2822 format
-    let mut message = format!("To: {to}\r\n");
-    message.push_str(&format!("Subject: {subject}\r\n"));
-    message.push_str("Content-Type: text/plain; charset=utf-8\r\n");
-    message.push_str("\r\n");
-    message.push_str(body);
-
     // Encode message in base64url
     let encoded_message = URL_SAFE.encode(message.as_bytes());
 
@@ -85,23 +515,32 @@ pub async fn create_draft(
         .await
         .context("Failed to create draft")?;
 
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        return Err(anyhow::anyhow!("Gmail API error: {status} - {error_text}"));
+    if !response.status().is_success() {
+        return Err(GmailError::from_response(response).await.into());
     }
 
-    let result: Value = response.json().await.context("Failed to parse response")?;
+    let mut result: Value = response.json().await.context("Failed to parse response")?;
+    if let Some(removed) = removed {
+        result["removed_recipients"] = removed;
+    }
     Ok(result)
 }
 
-/// Extract attachment text by filename
-pub async fn extract_attachment_by_filename(
+/// An attachment's decoded bytes with the content type its message part declares
+pub struct Attachment {
+    pub mime_type: String,
+    pub data: Vec<u8>,
+    /// The virus scan's report, when attachments are scanned
+    pub scan: Option<Value>,
+}
+
+/// Download the attachment named `filename` from a message, failing if the virus scan blocks it
+pub async fn fetch_attachment(
     gmail_server: &GmailServer,
     message_id: &str,
     filename: &str,
-) -> Result<Value> {
-    gmail_server.check_authentication().await?;
+) -> Result<Attachment> {
+    gmail_server.check_authentication(&[SCOPE_READONLY]).await?;
 
     let client = gmail_server.authenticated_client().await?;
     let user_id = gmail_server.user_id();
@@ -114,28 +553,42 @@ pub async fn extract_attachment_by_filename(
         .await
         .context("Failed to get message")?;
 
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        return Err(anyhow::anyhow!("Gmail API error: {status} - {error_text}"));
+    if !response.status().is_success() {
+        return Err(GmailError::from_response(response).await.into());
     }
 
-    let message: Value = response.json().await.context("Failed to parse message")?;
+    let mut message: Value = response.json().await.context("Failed to parse message")?;
+    // Attachments of a PGP/MIME message are inside its encrypted part
+    decrypt_message(gmail_server, &client, message_id, &mut message).await;
 
     // Find the attachment by filename
     let parts = message["payload"]["parts"]
         .as_array()
         .ok_or_else(|| anyhow::anyhow!("Invalid message structure"))?;
 
-    fn find_attachment(parts: &[Value], filename: &str) -> Option<(String, String)> {
+    /// Where an attachment's data is: an attachment ID to download, or inline in a decrypted part
+    enum Source {
+        AttachmentId(String),
+        Inline(String),
+    }
+
+    fn find_attachment(parts: &[Value], filename: &str) -> Option<(Source, String)> {
         for part in parts {
             if let Some(part_filename) = part["filename"].as_str() {
                 if part_filename == filename {
-                    if let Some(att_id) = part["body"]["attachmentId"].as_str() {
+                    let source = match (
+                        part["body"]["attachmentId"].as_str(),
+                        part["body"]["data"].as_str(),
+                    ) {
+                        (Some(att_id), _) => Some(Source::AttachmentId(att_id.to_string())),
+                        (None, Some(data)) => Some(Source::Inline(data.to_string())),
+                        (None, None) => None,
+                    };
+                    if let Some(source) = source {
                         let mime = part["mimeType"]
                             .as_str()
                             .unwrap_or("application/octet-stream");
-                        return Some((att_id.to_string(), mime.to_string()));
+                        return Some((source, mime.to_string()));
                     }
                 }
             }
@@ -149,253 +602,533 @@ pub async fn extract_attachment_by_filename(
         None
     }
 
-    let (att_id, mime) = find_attachment(parts, filename)
+    let (source, mime) = find_attachment(parts, filename)
         .ok_or_else(|| anyhow::anyhow!("Attachment '{filename}' not found in message"))?;
 
-    // Download the attachment
-    let att_url =
-        format!("{GMAIL_API_BASE}/users/{user_id}/messages/{message_id}/attachments/{att_id}");
+    let encoded_data = match source {
+        Source::Inline(data) => data,
+        Source::AttachmentId(att_id) => {
+            // Download the attachment
+            let att_url = format!(
+                "{GMAIL_API_BASE}/users/{user_id}/messages/{message_id}/attachments/{att_id}"
+            );
 
-    let att_response = client
-        .get(&att_url)
-        .send()
-        .await
-        .context("Failed to download attachment")?;
+            let att_response = client
+                .get(&att_url)
+                .send()
+                .await
+                .context("Failed to download attachment")?;
 
-    let att_status = att_response.status();
-    if !att_status.is_success() {
-        let error_text = att_response.text().await.unwrap_or_default();
-        return Err(anyhow::anyhow!(
-            "Gmail API error: {att_status} - {error_text}"
-        ));
-    }
+            if !att_response.status().is_success() {
+                return Err(GmailError::from_response(att_response).await.into());
+            }
 
-    let att_data: Value = att_response
-        .json()
-        .await
-        .context("Failed to parse attachment")?;
-    let encoded_data = att_data["data"]
-        .as_str()
-        .ok_or_else(|| anyhow::anyhow!("Invalid attachment data"))?;
+            let att_data: Value = att_response
+                .json()
+                .await
+                .context("Failed to parse attachment")?;
+            att_data["data"]
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("Invalid attachment data"))?
+                .to_string()
+        }
+    };
 
     // Decode base64url
-    let decoded_data = URL_SAFE
+    let data = URL_SAFE
         .decode(encoded_data)
         .context("Failed to decode attachment data")?;
+    let scan = match gmail_server.attachment_scanner() {
+        Some(scanner) => Some(scanner.check(filename, &data).await?),
+        None => None,
+    };
+    Ok(Attachment {
+        mime_type: mime,
+        data,
+        scan,
+    })
+}
+
+/// A message part's content, inline or downloaded by its attachment ID
+pub async fn part_data(
+    client: &GmailClient,
+    user_id: &str,
+    message_id: &str,
+    part: &Value,
+) -> Result<Vec<u8>> {
+    let data = match (
+        part["body"]["data"].as_str(),
+        part["body"]["attachmentId"].as_str(),
+    ) {
+        (Some(data), _) => data.to_string(),
+        (None, Some(attachment_id)) => {
+            let url = format!(
+                "{GMAIL_API_BASE}/users/{user_id}/messages/{message_id}/attachments/{attachment_id}"
+            );
+            let attachment = client
+                .get_json(&url)
+                .await
+                .context("Failed to download attachment")?;
+            attachment["data"]
+                .as_str()
+                .context("Invalid attachment data")?
+                .to_string()
+        }
+        (None, None) => anyhow::bail!("Message part has no data"),
+    };
+    URL_SAFE
+        .decode(data)
+        .context("Failed to decode attachment data")
+}
+
+/// Extract attachment text by filename
+#[instrument(
+    name = "tool",
+    skip_all,
+    fields(tool = "extract_attachment_by_filename", message_id = %message_id)
+)]
+pub async fn extract_attachment_by_filename(
+    gmail_server: &GmailServer,
+    message_id: &str,
+    filename: &str,
+    preview_chars: Option<usize>,
+) -> Result<Value> {
+    let attachment = fetch_attachment(gmail_server, message_id, filename).await?;
+    let (mime, decoded_data) = (attachment.mime_type, attachment.data);
 
     // Extract text if possible
-    if is_extractable_document(&mime, filename) {
+    let mut result = if is_extractable_document(&mime, filename) {
         let extracted_text = extract_text_from_bytes(&decoded_data, &mime, filename)
             .context("Failed to extract text from attachment")?;
 
-        Ok(json!({
+        json!({
             "filename": filename,
             "mime_type": mime,
             "size": decoded_data.len(),
+            "text_size": text_size(&extracted_text),
             "extracted_text": extracted_text
-        }))
+        })
     } else {
-        Ok(json!({
+        json!({
             "filename": filename,
             "mime_type": mime,
             "size": decoded_data.len(),
             "extracted_text": null,
             "error": "File type not supported for text extraction"
-        }))
+        })
+    };
+    if let Some(chars) = preview_chars {
+        preview_text(&mut result, chars);
+    }
+    if let Some(scan) = attachment.scan {
+        result["scan"] = scan;
     }
+    Ok(fit_text(gmail_server, "extracted_text", result))
+}
+
+/// Cut a result's `extracted_text` to its first `chars` characters, adding the whole text's
+/// `total_chars` and whether it was `preview_truncated`, so a client can decide whether the
+/// rest is worth fetching. `text_size` stays the size of the whole text
+pub fn preview_text(result: &mut Value, chars: usize) {
+    let Some(text) = result["extracted_text"].as_str() else {
+        return;
+    };
+    let total_chars = text.chars().count();
+    let preview = match text.char_indices().nth(chars) {
+        Some((end, _)) => text[..end].to_string(),
+        None => text.to_string(),
+    };
+    result["extracted_text"] = json!(preview);
+    result["total_chars"] = json!(total_chars);
+    result["preview_truncated"] = json!(total_chars > chars);
 }
 
 /// Fetch email bodies for threads
+#[instrument(name = "tool", skip_all, fields(tool = "fetch_email_bodies", threads = thread_ids.len()))]
 pub async fn fetch_email_bodies(
     gmail_server: &GmailServer,
     thread_ids: &[String],
+    format: MessageFormat,
+    fields: Option<&str>,
 ) -> Result<Value> {
-    gmail_server.check_authentication().await?;
+    gmail_server.check_authentication(&[SCOPE_READONLY]).await?;
 
     let client = gmail_server.authenticated_client().await?;
     let user_id = gmail_server.user_id();
 
-    let mut results = Vec::new();
+    // Partial responses are never cached
+    let cache = gmail_server
+        .cache()
+        .filter(|_| format == MessageFormat::Full && fields.is_none());
 
-    for thread_id in thread_ids {
-        let url = format!("{GMAIL_API_BASE}/users/{user_id}/threads/{thread_id}");
-        let response = client
-            .get(&url)
-            .send()
-            .await
-            .context(format!("Failed to get thread {thread_id}"))?;
-
-        if !response.status().is_success() {
-            let error_text = response.text().await.unwrap_or_default();
-            error!("Error fetching thread {}: {}", thread_id, error_text);
-            continue;
-        }
+    // Threads still at their cached historyId are served without listing their messages
+    let cached_threads = match cache {
+        Some(cache) => cached_threads(&client, cache, user_id, thread_ids).await,
+        None => HashMap::new(),
+    };
 
-        let thread: Value = response.json().await.context("Failed to parse thread")?;
+    // List the message IDs of every other thread in one batched round trip
+    let to_list: Vec<&String> = thread_ids
+        .iter()
+        .filter(|thread_id| !cached_threads.contains_key(*thread_id))
+        .collect();
+    let thread_urls: Vec<String> = to_list
+        .iter()
+        .map(|thread_id| {
+            format!("{GMAIL_API_BASE}/users/{user_id}/threads/{thread_id}?format=minimal")
+        })
+        .collect();
+    let threads = client
+        .batch_get(&thread_urls)
+        .await
+        .context("Failed to get threads")?;
+
+    // Each listed thread's historyId and its messages as (message ID, historyId) pairs
+    let mut thread_messages = Vec::new();
+    for (thread_id, thread) in to_list.into_iter().zip(threads) {
+        let thread = match thread {
+            Ok(thread) => thread,
+            Err(e) => {
+                error!("Error fetching thread {}: {}", thread_id, e);
+                continue;
+            }
+        };
 
         let messages = thread["messages"]
             .as_array()
-            .ok_or_else(|| anyhow::anyhow!("Invalid thread structure"))?;
-
-        let mut thread_messages = Vec::new();
+            .ok_or_else(|| anyhow::anyhow!("Invalid thread structure"))?
+            .iter()
+            .map(|message| {
+                let message_id = message["id"]
+                    .as_str()
+                    .ok_or_else(|| anyhow::anyhow!("Message missing ID"))?;
+                let history_id = message["historyId"].as_str().map(str::to_string);
+                Ok((message_id.to_string(), history_id))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let history_id = thread["historyId"].as_str().map(str::to_string);
+        thread_messages.push((thread_id, history_id, messages));
+    }
 
-        for message in messages {
-            let message_id = message["id"]
-                .as_str()
-                .ok_or_else(|| anyhow::anyhow!("Message missing ID"))?;
+    // Serve unchanged messages from the cache
+    let mut summaries: HashMap<String, Value> = HashMap::new();
+    let mut to_fetch = Vec::new();
+    for (thread_id, _, messages) in &thread_messages {
+        for (message_id, history_id) in messages {
+            if let (Some(cache), Some(history_id)) = (cache, history_id) {
+                match cache.get(message_id, history_id) {
+                    Ok(Some(summary)) => {
+                        summaries.insert(message_id.clone(), summary);
+                        continue;
+                    }
+                    Ok(None) => {}
+                    Err(e) => warn!("Message cache lookup failed for {}: {}", message_id, e),
+                }
+            }
+            to_fetch.push((*thread_id, message_id, history_id));
+        }
+    }
 
-            // Get full message details
-            let msg_url = format!("{GMAIL_API_BASE}/users/{user_id}/messages/{message_id}");
-            let msg_response = client
-                .get(&msg_url)
-                .send()
-                .await
-                .context(format!("Failed to get message {message_id}"))?;
+    // Fetch message details for the remaining messages in batched round trips
+    let message_urls: Vec<String> = to_fetch
+        .iter()
+        .map(|(_, message_id, _)| message_url(user_id, message_id, format, fields))
+        .collect();
+    let messages = client
+        .batch_get(&message_urls)
+        .await
+        .context("Failed to get messages")?;
 
-            if !msg_response.status().is_success() {
+    for ((thread_id, message_id, history_id), message) in to_fetch.into_iter().zip(messages) {
+        let mut msg = match message {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!("Error fetching message {}: {}", message_id, e);
                 continue;
             }
+        };
+        decrypt_message(gmail_server, &client, message_id, &mut msg).await;
+        let summary = summarize_message(message_id, &msg, format)?;
+        // Decrypted bodies never go to disk, and encrypted ones are fetched again in case a key
+        // has been added since
+        let cache = cache.filter(|_| summary.get("encrypted").is_none());
+        if let (Some(cache), Some(history_id)) = (cache, history_id) {
+            if let Err(e) = cache.put(message_id, thread_id, history_id, &summary) {
+                warn!("Failed to cache message {}: {}", message_id, e);
+            }
+        }
+        summaries.insert(message_id.clone(), summary);
+    }
 
-            let msg: Value = msg_response
-                .json()
-                .await
-                .context("Failed to parse message")?;
-
-            // Extract body text
-            let body_text = extract_message_body(&msg)?;
-
-            // Extract headers
-            let empty_vec = Vec::new();
-            let headers = msg["payload"]["headers"].as_array().unwrap_or(&empty_vec);
-
-            let mut from = None;
-            let mut subject = None;
-            let mut date = None;
-
-            for header in headers {
-                let name = header["name"].as_str().unwrap_or("");
-                let value = header["value"].as_str().unwrap_or("");
-                match name {
-                    "From" => from = Some(value.to_string()),
-                    "Subject" => subject = Some(value.to_string()),
-                    "Date" => date = Some(value.to_string()),
-                    _ => {}
+    let mut listed_threads: HashMap<&String, Vec<Value>> = HashMap::new();
+    for (thread_id, history_id, messages) in &thread_messages {
+        let message_ids: Vec<String> = messages
+            .iter()
+            .map(|(message_id, _)| message_id.clone())
+            .collect();
+
+        // Only remember threads whose every message made it into the cache
+        if let (Some(cache), Some(history_id)) = (cache, history_id) {
+            if message_ids.iter().all(|id| summaries.contains_key(id)) {
+                if let Err(e) = cache.put_thread(thread_id, history_id, &message_ids) {
+                    warn!("Failed to cache thread {}: {}", thread_id, e);
                 }
             }
-
-            thread_messages.push(json!({
-                "message_id": message_id,
-                "from": from,
-                "subject": subject,
-                "date": date,
-                "body": body_text
-            }));
         }
 
+        let thread_messages = message_ids
+            .iter()
+            .filter_map(|message_id| summaries.get(message_id).cloned())
+            .collect();
+        listed_threads.insert(thread_id, thread_messages);
+    }
+
+    let mut results = Vec::new();
+    for thread_id in thread_ids {
+        let Some(messages) = cached_threads
+            .get(thread_id)
+            .or_else(|| listed_threads.get(thread_id))
+        else {
+            continue;
+        };
+
         results.push(json!({
             "thread_id": thread_id,
-            "messages": thread_messages
+            "messages": messages
         }));
     }
 
-    Ok(json!({ "threads": results }))
+    let result = with_response_size(json!({ "threads": results }));
+    Ok(fit_messages(gmail_server, "fetch_email_bodies", result))
 }
 
-/// Download attachment
-pub async fn download_attachment(
-    gmail_server: &GmailServer,
+/// Cached message summaries of threads whose historyId is unchanged on Gmail
+async fn cached_threads(
+    client: &GmailClient,
+    cache: &MessageCache,
+    user_id: &str,
+    thread_ids: &[String],
+) -> HashMap<String, Vec<Value>> {
+    let known: Vec<&String> = thread_ids
+        .iter()
+        .filter(|thread_id| cache.contains_thread(thread_id).unwrap_or(false))
+        .collect();
+    if known.is_empty() {
+        return HashMap::new();
+    }
+
+    // Asking for only the historyId keeps each validation response tiny
+    let probe_urls: Vec<String> = known
+        .iter()
+        .map(|thread_id| {
+            format!(
+                "{GMAIL_API_BASE}/users/{user_id}/threads/{thread_id}?format=minimal&fields=id%2ChistoryId"
+            )
+        })
+        .collect();
+    let probes = match client.batch_get(&probe_urls).await {
+        Ok(probes) => probes,
+        Err(e) => {
+            warn!("Thread cache validation failed: {}", e);
+            return HashMap::new();
+        }
+    };
+
+    let mut cached = HashMap::new();
+    for (thread_id, probe) in known.into_iter().zip(probes) {
+        let Some(history_id) = probe
+            .ok()
+            .and_then(|probe| probe["historyId"].as_str().map(str::to_string))
+        else {
+            continue;
+        };
+        match cache.get_thread(thread_id, &history_id) {
+            Ok(Some(messages)) => {
+                cached.insert(thread_id.clone(), messages);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Thread cache lookup failed for {}: {}", thread_id, e),
+        }
+    }
+    cached
+}
+
+/// Summarize a Gmail message into its body, key headers and where Gmail filed it
+pub fn summarize_message(message_id: &str, msg: &Value, format: MessageFormat) -> Result<Value> {
+    let mut summary = summarize_message_content(message_id, msg, format)?;
+    add_filing(&mut summary, msg);
+    Ok(summary)
+}
+
+/// Add the inbox tab a message is in as its `category`, or null for mail outside the tabs such
+/// as sent mail, and whether it's `important` and `unread`. Left out when the message's labels
+/// are, as in a partial response without them
+fn add_filing(summary: &mut Value, msg: &Value) {
+    let Some(label_ids) = msg["labelIds"].as_array() else {
+        return;
+    };
+    let has = |label: &str| label_ids.iter().any(|id| id == label);
+    let category = Category::ALL
+        .iter()
+        .find(|category| has(category.label()))
+        .map(Category::as_str);
+    summary["category"] = json!(category);
+    summary["important"] = json!(has("IMPORTANT"));
+    summary["unread"] = json!(has("UNREAD"));
+}
+
+fn summarize_message_content(
     message_id: &str,
-    filename: &str,
-    download_dir: Option<&str>,
+    msg: &Value,
+    format: MessageFormat,
 ) -> Result<Value> {
-    gmail_server.check_authentication().await?;
+    // Only full messages carry a body; partial formats fall back to the snippet
+    if format != MessageFormat::Full {
+        let headers = summarize_headers(msg);
+        return Ok(json!({
+            "message_id": message_id,
+            "from": headers.from,
+            "subject": headers.subject,
+            "date": headers.date,
+            "snippet": msg["snippet"],
+            "body": null
+        }));
+    }
 
-    let client = gmail_server.authenticated_client().await?;
-    let user_id = gmail_server.user_id();
+    // Encrypted bodies can't be read, so say so instead of failing to find a text part
+    if let Some(encryption) = encryption_type(&msg["payload"]) {
+        let headers = summarize_headers(msg);
+        return Ok(json!({
+            "message_id": message_id,
+            "from": headers.from,
+            "subject": headers.subject,
+            "date": headers.date,
+            "encrypted": true,
+            "encryption": encryption,
+            "body": null
+        }));
+    }
 
-    // Get the message
-    let url = format!("{GMAIL_API_BASE}/users/{user_id}/messages/{message_id}");
-    let response = client
-        .get(&url)
-        .send()
-        .await
-        .context("Failed to get message")?;
+    // Extract body text
+    let (body_text, body_mime_type) = extract_message_body_with_type(msg)?;
+    let headers = summarize_headers(msg);
+
+    let mut summary = json!({
+        "message_id": message_id,
+        "from": headers.from,
+        "subject": headers.subject,
+        "date": headers.date,
+        "body_mime_type": body_mime_type,
+        "body_size": text_size(&body_text),
+        "body": body_text
+    });
+    // Set by `decrypt_message` on messages it decrypted
+    if let Some(encryption) = msg["decrypted"].as_str() {
+        summary["encrypted"] = json!(true);
+        summary["encryption"] = json!(encryption);
+        summary["decrypted"] = json!(true);
+    }
+    Ok(summary)
+}
 
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        return Err(anyhow::anyhow!("Gmail API error: {status} - {error_text}"));
+/// How a message part or any part nested in it is encrypted: `pgp` for PGP/MIME
+/// (`multipart/encrypted`) or `smime` for S/MIME (`application/pkcs7-mime`)
+fn encryption_type(part: &Value) -> Option<&'static str> {
+    let mime_type = part["mimeType"].as_str().unwrap_or("").to_ascii_lowercase();
+    match mime_type.as_str() {
+        "multipart/encrypted" => return Some("pgp"),
+        "application/pkcs7-mime" | "application/x-pkcs7-mime" => return Some("smime"),
+        _ => {}
     }
+    part["parts"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find_map(encryption_type)
+}
 
-    let message: Value = response.json().await.context("Failed to parse message")?;
+/// Approximate number of model tokens in `text`
+pub fn estimate_tokens(text: &str) -> usize {
+    text.len().div_ceil(BYTES_PER_TOKEN)
+}
 
-    // Find the attachment by filename
-    let parts = message["payload"]["parts"]
-        .as_array()
-        .ok_or_else(|| anyhow::anyhow!("Invalid message structure"))?;
+/// Size of `text` in bytes and approximate model tokens, for clients deciding whether to
+/// summarize or paginate before passing it to a model
+pub fn text_size(text: &str) -> Value {
+    json!({
+        "bytes": text.len(),
+        "estimated_tokens": estimate_tokens(text),
+    })
+}
 
-    fn find_attachment(parts: &[Value], filename: &str) -> Option<(String, String)> {
-        for part in parts {
-            if let Some(part_filename) = part["filename"].as_str() {
-                if part_filename == filename {
-                    if let Some(att_id) = part["body"]["attachmentId"].as_str() {
-                        let mime = part["mimeType"]
-                            .as_str()
-                            .unwrap_or("application/octet-stream");
-                        return Some((att_id.to_string(), mime.to_string()));
-                    }
-                }
-            }
-            if let Some(nested_parts) = part["parts"].as_array() {
-                if let Some(result) = find_attachment(nested_parts, filename) {
-                    return Some(result);
-                }
-            }
-        }
-        None
+/// Annotate a tool result with the size of its JSON as `response_size`
+pub fn with_response_size(mut result: Value) -> Value {
+    let size = text_size(&result.to_string());
+    if let Some(object) = result.as_object_mut() {
+        object.insert("response_size".to_string(), size);
     }
+    result
+}
 
-    let (attachment_id, mime_type) = find_attachment(parts, filename)
-        .ok_or_else(|| anyhow::anyhow!("Attachment '{filename}' not found in message"))?;
+/// From, Subject and Date headers of a Gmail message
+struct MessageHeaders {
+    from: Option<String>,
+    subject: Option<String>,
+    date: Option<String>,
+}
 
-    // Download the attachment
-    let att_url = format!(
-        "{GMAIL_API_BASE}/users/{user_id}/messages/{message_id}/attachments/{attachment_id}"
-    );
+/// Extract the From, Subject and Date headers from a Gmail message
+fn summarize_headers(msg: &Value) -> MessageHeaders {
+    // Extract headers
+    let empty_vec = Vec::new();
+    let headers = msg["payload"]["headers"].as_array().unwrap_or(&empty_vec);
 
-    let att_response = client
-        .get(&att_url)
-        .send()
-        .await
-        .context("Failed to download attachment")?;
+    let mut from = None;
+    let mut subject = None;
+    let mut date = None;
 
-    let att_status = att_response.status();
-    if !att_status.is_success() {
-        let error_text = att_response.text().await.unwrap_or_default();
-        return Err(anyhow::anyhow!(
-            "Gmail API error: {att_status} - {error_text}"
-        ));
+    for header in headers {
+        let name = header["name"].as_str().unwrap_or("");
+        let value = header["value"].as_str().unwrap_or("");
+        match name {
+            "From" => from = Some(unicode_domains(value).into_owned()),
+            "Subject" => subject = Some(value.to_string()),
+            "Date" => date = Some(value.to_string()),
+            _ => {}
+        }
     }
 
-    let att_data: Value = att_response
-        .json()
-        .await
-        .context("Failed to parse attachment")?;
-    let encoded_data = att_data["data"]
-        .as_str()
-        .ok_or_else(|| anyhow::anyhow!("Invalid attachment data"))?;
+    MessageHeaders {
+        from,
+        subject,
+        date,
+    }
+}
 
-    // Decode base64url
-    let decoded_data = URL_SAFE
-        .decode(encoded_data)
-        .context("Failed to decode attachment data")?;
+/// Where downloads go: the given directory, or the working directory
+pub fn download_path(download_dir: Option<&str>) -> PathBuf {
+    match download_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
+    }
+}
 
-    // Determine download directory
-    let download_path = if let Some(dir) = download_dir {
-        PathBuf::from(dir)
-    } else {
-        std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."))
-    };
+/// Download attachment
+#[instrument(
+    name = "tool",
+    skip_all,
+    fields(tool = "download_attachment", message_id = %message_id)
+)]
+pub async fn download_attachment(
+    gmail_server: &GmailServer,
+    message_id: &str,
+    filename: &str,
+    download_dir: Option<&str>,
+) -> Result<Value> {
+    let attachment = fetch_attachment(gmail_server, message_id, filename).await?;
+    let (mime_type, decoded_data) = (attachment.mime_type, attachment.data);
+
+    let download_path = download_path(download_dir);
 
     // Ensure directory exists
     std::fs::create_dir_all(&download_path).context("Failed to create download directory")?;
@@ -405,24 +1138,77 @@ pub async fn download_attachment(
     // Write file
     std::fs::write(&file_path, &decoded_data).context("Failed to write attachment file")?;
 
-    Ok(json!({
+    let mut result = json!({
         "filename": filename,
         "mime_type": mime_type,
         "size": decoded_data.len(),
         "path": file_path.to_string_lossy().to_string()
-    }))
+    });
+    if let Some(scan) = attachment.scan {
+        result["scan"] = scan;
+    }
+    Ok(result)
 }
 
 /// Forward email
+#[instrument(
+    name = "tool",
+    skip_all,
+    fields(tool = "forward_email", message_id = %message_id)
+)]
 pub async fn forward_email(
     gmail_server: &GmailServer,
     message_id: &str,
     to: &str,
     subject: &str,
     body: &str,
+    dry_run: bool,
+    enqueue_if_unauthenticated: bool,
 ) -> Result<Value> {
-    gmail_server.check_authentication().await?;
+    validate_headers(to, subject)?;
+    let dry_run = dry_run || gmail_server.dry_run();
+    // A dry run only reads the original message
+    let scopes: &[&str] = if dry_run {
+        &[SCOPE_READONLY]
+    } else {
+        &[SCOPE_READONLY, SCOPE_SEND]
+    };
+    if let Err(e) = gmail_server.check_authentication(scopes).await {
+        if enqueue_if_unauthenticated && !dry_run && outbox::needs_login(&e) {
+            // The original is read when it's sent, after the login
+            let forward = Outgoing::Forward {
+                message_id: message_id.to_string(),
+                to: to.to_string(),
+                subject: subject.to_string(),
+                body: body.to_string(),
+            };
+            return outbox::park(gmail_server, "forward_email", forward, e);
+        }
+        return Err(e);
+    }
+
+    let message = forward_message(gmail_server, message_id, to, subject, body).await?;
+    if dry_run {
+        return Ok(dry_run_result("send", &message, None));
+    }
+    outbox::send(
+        gmail_server,
+        "forward_email",
+        Outgoing::Message(message),
+        Some(to),
+        Some(subject),
+    )
+    .await
+}
 
+/// The message forwarding `message_id`, with `body` above the quoted original
+pub async fn forward_message(
+    gmail_server: &GmailServer,
+    message_id: &str,
+    to: &str,
+    subject: &str,
+    body: &str,
+) -> Result<String> {
     let client = gmail_server.authenticated_client().await?;
     let user_id = gmail_server.user_id();
 
@@ -434,10 +1220,8 @@ pub async fn forward_email(
         .await
         .context("Failed to get original message")?;
 
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        return Err(anyhow::anyhow!("Gmail API error: {status} - {error_text}"));
+    if !response.status().is_success() {
+        return Err(GmailError::from_response(response).await.into());
     }
 
     let original_message: Value = response.json().await.context("Failed to parse message")?;
@@ -456,122 +1240,213 @@ pub async fn forward_email(
         let name = header["name"].as_str().unwrap_or("");
         let value = header["value"].as_str().unwrap_or("");
         match name {
-            "From" => original_from = Some(value.to_string()),
+            "From" => original_from = Some(unicode_domains(value).into_owned()),
             "Subject" => original_subject = Some(value.to_string()),
             "Date" => original_date = Some(value.to_string()),
             _ => {}
         }
     }
 
-    // Build forwarded message
-    let mut message = format!("To: {to}\r\n");
-    message.push_str(&format!("Subject: {subject}\r\n"));
-    message.push_str("Content-Type: text/plain; charset=utf-8\r\n");
-    message.push_str("\r\n");
-    message.push_str(body);
-    message.push_str("\r\n\r\n");
-    message.push_str("---------- Forwarded message ----------\r\n");
+    // Build forwarded message, quoting the original below the caller's text
+    let mut text = body.to_string();
+    text.push_str("\r\n\r\n");
+    text.push_str("---------- Forwarded message ----------\r\n");
     if let Some(from) = original_from {
-        message.push_str(&format!("From: {from}\r\n"));
+        text.push_str(&format!("From: {from}\r\n"));
     }
     if let Some(date) = original_date {
-        message.push_str(&format!("Date: {date}\r\n"));
+        text.push_str(&format!("Date: {date}\r\n"));
     }
     if let Some(subj) = original_subject {
-        message.push_str(&format!("Subject: {subj}\r\n"));
+        text.push_str(&format!("Subject: {subj}\r\n"));
     }
-    message.push_str("\r\n");
+    text.push_str("\r\n");
 
     // Get original body
     let original_body = extract_message_body(&original_message)?;
-    message.push_str(&original_body);
+    text.push_str(&original_body);
+    plain_text_message(to, subject, &text)
+}
 
-    // Encode message in base64url
-    let encoded_message = URL_SAFE.encode(message.as_bytes());
+/// Send draft
+#[instrument(name = "tool", skip_all, fields(tool = "send_draft"))]
+pub async fn send_draft(
+    gmail_server: &GmailServer,
+    draft_id: &str,
+    dry_run: bool,
+    enqueue_if_unauthenticated: bool,
+) -> Result<Value> {
+    if dry_run || gmail_server.dry_run() {
+        return draft_dry_run(gmail_server, draft_id).await;
+    }
+    let outgoing = Outgoing::Draft(draft_id.to_string());
+    if let Err(e) = gmail_server.check_authentication(&[SCOPE_COMPOSE]).await {
+        if enqueue_if_unauthenticated && outbox::needs_login(&e) {
+            return outbox::park(gmail_server, "send_draft", outgoing, e);
+        }
+        return Err(e);
+    }
+    outbox::send(gmail_server, "send_draft", outgoing, None, None).await
+}
 
-    // Send the message
-    let send_url = format!("{GMAIL_API_BASE}/users/{user_id}/messages/send");
-    let send_payload = json!({
-        "raw": encoded_message
-    });
+/// The draft `send_draft` would send, fetched in raw form instead of sending it
+async fn draft_dry_run(gmail_server: &GmailServer, draft_id: &str) -> Result<Value> {
+    gmail_server.check_authentication(&[SCOPE_READONLY]).await?;
 
-    let send_response = client
-        .post(&send_url)
-        .json(&send_payload)
+    let client = gmail_server.authenticated_client().await?;
+    let user_id = gmail_server.user_id();
+    let url = format!("{GMAIL_API_BASE}/users/{user_id}/drafts/{draft_id}?format=raw");
+    let response = client
+        .get(&url)
         .send()
         .await
-        .context("Failed to send forwarded message")?;
+        .context("Failed to get draft")?;
 
-    let send_status = send_response.status();
-    if !send_status.is_success() {
-        let error_text = send_response.text().await.unwrap_or_default();
-        return Err(anyhow::anyhow!(
-            "Gmail API error: {send_status} - {error_text}"
-        ));
+    if !response.status().is_success() {
+        return Err(GmailError::from_response(response).await.into());
     }
 
-    let result: Value = send_response
-        .json()
-        .await
-        .context("Failed to parse response")?;
-    Ok(result)
+    let draft: Value = response.json().await.context("Failed to parse draft")?;
+    let raw = draft["message"]["raw"].as_str().unwrap_or_default();
+    let message = decode_email_content(raw).context("Failed to decode draft")?;
+    Ok(dry_run_result(
+        "send",
+        &message,
+        draft["message"]["threadId"].as_str(),
+    ))
 }
 
-/// Send draft
-pub async fn send_draft(gmail_server: &GmailServer, draft_id: &str) -> Result<Value> {
-    gmail_server.check_authentication().await?;
-
-    let client = gmail_server.authenticated_client().await?;
-    let user_id = gmail_server.user_id();
+/// What a tool would have sent to Gmail, returned in place of calling it in a dry run
+fn dry_run_result(action: &str, message: &str, thread_id: Option<&str>) -> Value {
+    json!({
+        "dry_run": true,
+        "action": action,
+        "thread_id": thread_id,
+        "size_bytes": message.len(),
+        "message": message,
+    })
+}
 
-    let url = format!("{GMAIL_API_BASE}/users/{user_id}/drafts/{draft_id}/send");
+/// A plain-text RFC 5322 message, after checking its headers. Non-ASCII subjects and display
+/// names are encoded as RFC 2047 words, long headers are folded and the body gets a transfer
+/// encoding that survives 7-bit transport
+fn plain_text_message(to: &str, subject: &str, body: &str) -> Result<String> {
+    validate_headers(to, subject)?;
+    MessageBuilder::new()
+        .to(Address::new_list(recipients(to)?))
+        .subject(subject)
+        .text_body(body)
+        .write_to_string()
+        .context("Failed to build message")
+}
 
-    let payload = json!({});
+/// Reject recipients and subjects that would produce a malformed message or inject headers
+pub fn validate_headers(to: &str, subject: &str) -> Result<()> {
+    if to.contains(['\r', '\n']) || subject.contains(['\r', '\n']) {
+        anyhow::bail!("Recipients and subject can't contain line breaks");
+    }
+    recipients(to).map(|_| ())
+}
 
-    let response = client
-        .post(&url)
-        .json(&payload)
-        .send()
-        .await
-        .context("Failed to send draft")?;
+/// The addresses in a `To` value, each either a bare address or `Name <address>`
+fn recipients(to: &str) -> Result<Vec<Address<'_>>> {
+    let recipients = split_recipients(to);
+    if recipients.is_empty() {
+        anyhow::bail!("No recipients given");
+    }
+    recipients
+        .into_iter()
+        .map(|recipient| {
+            let (name, address) = match recipient.rsplit_once('<') {
+                Some((name, address)) => (name.trim(), address.strip_suffix('>').unwrap_or("")),
+                None => ("", recipient),
+            };
+            let Some(address) = ascii_domain_address(address) else {
+                anyhow::bail!("Invalid recipient address: {recipient}");
+            };
+            // The builder quotes names again where they need it
+            let name = name
+                .strip_prefix('"')
+                .and_then(|name| name.strip_suffix('"'))
+                .map_or_else(|| name.to_string(), |name| name.replace("\\\"", "\""));
+            Ok(Address::new_address(
+                (!name.is_empty()).then_some(name),
+                address,
+            ))
+        })
+        .collect()
+}
 
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response.text().await.unwrap_or_default();
-        return Err(anyhow::anyhow!("Gmail API error: {status} - {error_text}"));
+/// Comma-separated recipients, leaving commas inside quoted display names alone
+fn split_recipients(to: &str) -> Vec<&str> {
+    let mut recipients = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in to.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                recipients.push(&to[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
     }
+    recipients.push(&to[start..]);
+    recipients
+        .into_iter()
+        .map(str::trim)
+        .filter(|recipient| !recipient.is_empty())
+        .collect()
+}
 
-    let result: Value = response.json().await.context("Failed to parse response")?;
-    Ok(result)
+/// `to` without the mailbox's own address, as reply-all leaves it out, and the recipients that
+/// were removed. A note to self keeps its only recipient
+fn without_own_address(to: &str, own_address: &str) -> (String, Vec<String>) {
+    let (own, others): (Vec<&str>, Vec<&str>) =
+        split_recipients(to).into_iter().partition(|recipient| {
+            let address = match recipient.rsplit_once('<') {
+                Some((_, address)) => address.strip_suffix('>').unwrap_or(address),
+                None => recipient,
+            };
+            address.trim().eq_ignore_ascii_case(own_address)
+        });
+    if others.is_empty() {
+        (to.to_string(), Vec::new())
+    } else {
+        (
+            others.join(", "),
+            own.into_iter().map(str::to_string).collect(),
+        )
+    }
 }
 
 /// Helper function to extract message body from Gmail API response
 fn extract_message_body(message: &Value) -> Result<String> {
+    extract_message_body_with_type(message).map(|(body, _)| body)
+}
+
+/// Decode the body text of a message, preferring text/plain over text/html, with the MIME type
+/// of the part it came from
+fn extract_message_body_with_type(message: &Value) -> Result<(String, &str)> {
     let payload = &message["payload"];
 
     // Check if body is directly in payload
     if let Some(body_data) = payload["body"]["data"].as_str() {
-        return decode_email_content(body_data);
+        let mime_type = payload["mimeType"].as_str().unwrap_or("text/plain");
+        return Ok((decode_email_content(body_data)?, mime_type));
     }
 
     // Check parts for body
     if let Some(parts) = payload["parts"].as_array() {
         // Look for text/plain first, then text/html
-        for part in parts {
-            let mime_type = part["mimeType"].as_str().unwrap_or("");
-            if mime_type == "text/plain" {
-                if let Some(body_data) = part["body"]["data"].as_str() {
-                    return decode_email_content(body_data);
-                }
-            }
-        }
-
-        // If no plain text, try HTML
-        for part in parts {
-            let mime_type = part["mimeType"].as_str().unwrap_or("");
-            if mime_type == "text/html" {
-                if let Some(body_data) = part["body"]["data"].as_str() {
-                    return decode_email_content(body_data);
+        for wanted in ["text/plain", "text/html"] {
+            for part in parts {
+                let mime_type = part["mimeType"].as_str().unwrap_or("");
+                if mime_type == wanted {
+                    if let Some(body_data) = part["body"]["data"].as_str() {
+                        return Ok((decode_email_content(body_data)?, wanted));
+                    }
                 }
             }
         }
@@ -579,19 +1454,13 @@ fn extract_message_body(message: &Value) -> Result<String> {
         // Recursively search nested parts
         for part in parts {
             if let Some(nested_parts) = part["parts"].as_array() {
-                for nested_part in nested_parts {
-                    let mime_type = nested_part["mimeType"].as_str().unwrap_or("");
-                    if mime_type == "text/plain" {
-                        if let Some(body_data) = nested_part["body"]["data"].as_str() {
-                            return decode_email_content(body_data);
-                        }
-                    }
-                }
-                for nested_part in nested_parts {
-                    let mime_type = nested_part["mimeType"].as_str().unwrap_or("");
-                    if mime_type == "text/html" {
-                        if let Some(body_data) = nested_part["body"]["data"].as_str() {
-                            return decode_email_content(body_data);
+                for wanted in ["text/plain", "text/html"] {
+                    for nested_part in nested_parts {
+                        let mime_type = nested_part["mimeType"].as_str().unwrap_or("");
+                        if mime_type == wanted {
+                            if let Some(body_data) = nested_part["body"]["data"].as_str() {
+                                return Ok((decode_email_content(body_data)?, wanted));
+                            }
                         }
                     }
                 }
@@ -601,3 +1470,576 @@ fn extract_message_body(message: &Value) -> Result<String> {
 
     Err(anyhow::anyhow!("Could not extract message body"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockGmail, MOCK_ATTACHMENT_TEXT};
+
+    #[test]
+    fn test_message_url_full() {
+        assert_eq!(
+            message_url("me", "abc", MessageFormat::Full, None),
+            format!("{GMAIL_API_BASE}/users/me/messages/abc?format=full")
+        );
+    }
+
+    #[test]
+    fn test_message_url_metadata_requests_summary_headers() {
+        let url = message_url("me", "abc", MessageFormat::Metadata, None);
+        assert!(url.contains("format=metadata"));
+        assert!(url.contains("&metadataHeaders=From&metadataHeaders=Subject&metadataHeaders=Date"));
+    }
+
+    #[test]
+    fn test_message_url_encodes_fields() {
+        let url = message_url("me", "abc", MessageFormat::Minimal, Some("id,snippet"));
+        assert!(url.ends_with("?format=minimal&fields=id%2Csnippet"));
+    }
+
+    #[test]
+    fn test_summarize_message_metadata_uses_snippet() {
+        let msg = json!({
+            "snippet": "Hello there",
+            "payload": {
+                "headers": [
+                    {"name": "From", "value": "alice@example.com"},
+                    {"name": "Subject", "value": "Hi"}
+                ]
+            }
+        });
+        let summary = summarize_message("abc", &msg, MessageFormat::Metadata).unwrap();
+        assert_eq!(summary["from"], "alice@example.com");
+        assert_eq!(summary["subject"], "Hi");
+        assert_eq!(summary["snippet"], "Hello there");
+        assert!(summary["body"].is_null());
+        assert!(summary.get("category").is_none());
+    }
+
+    #[test]
+    fn test_summarize_message_reports_filing() {
+        let msg = json!({
+            "labelIds": ["INBOX", "UNREAD", "CATEGORY_PROMOTIONS"],
+            "payload": { "headers": [] },
+        });
+        let summary = summarize_message("abc", &msg, MessageFormat::Minimal).unwrap();
+        assert_eq!(summary["category"], "promotions");
+        assert_eq!(summary["important"], false);
+        assert_eq!(summary["unread"], true);
+
+        let sent = json!({ "labelIds": ["SENT", "IMPORTANT"], "payload": { "headers": [] } });
+        let summary = summarize_message("abc", &sent, MessageFormat::Minimal).unwrap();
+        assert_eq!(summary["category"], Value::Null);
+        assert_eq!(summary["important"], true);
+    }
+
+    #[test]
+    fn test_summarize_message_full_decodes_body() {
+        let msg = json!({
+            "payload": {
+                "headers": [{"name": "Date", "value": "Mon, 1 Jan 2024 00:00:00 +0000"}],
+                "body": {"data": URL_SAFE.encode("Body text")}
+            }
+        });
+        let summary = summarize_message("abc", &msg, MessageFormat::Full).unwrap();
+        assert_eq!(summary["body"], "Body text");
+        assert_eq!(
+            summary["body_size"],
+            json!({ "bytes": 9, "estimated_tokens": 3 })
+        );
+        assert_eq!(summary["date"], "Mon, 1 Jan 2024 00:00:00 +0000");
+        assert!(summary.get("snippet").is_none());
+    }
+
+    #[test]
+    fn test_summarize_message_marks_encrypted_bodies() {
+        let pgp = json!({
+            "payload": {
+                "mimeType": "multipart/encrypted",
+                "headers": [{"name": "Subject", "value": "Secret"}],
+                "parts": [
+                    {"mimeType": "application/pgp-encrypted", "body": {"data": URL_SAFE.encode("Version: 1")}},
+                    {"mimeType": "application/octet-stream", "body": {"attachmentId": "att-1"}},
+                ]
+            }
+        });
+        let summary = summarize_message("abc", &pgp, MessageFormat::Full).unwrap();
+        assert_eq!(summary["encrypted"], true);
+        assert_eq!(summary["encryption"], "pgp");
+        assert_eq!(summary["subject"], "Secret");
+        assert!(summary["body"].is_null());
+
+        // S/MIME bodies can be small enough to come inline, and must not be decoded as text
+        let smime = json!({
+            "payload": {
+                "mimeType": "multipart/mixed",
+                "parts": [{
+                    "mimeType": "application/pkcs7-mime",
+                    "body": {"data": URL_SAFE.encode([0x30, 0x82, 0x01])}
+                }]
+            }
+        });
+        let summary = summarize_message("abc", &smime, MessageFormat::Full).unwrap();
+        assert_eq!(summary["encryption"], "smime");
+
+        let plain = json!({ "payload": { "body": {"data": URL_SAFE.encode("Body text")} } });
+        let summary = summarize_message("abc", &plain, MessageFormat::Full).unwrap();
+        assert!(summary.get("encrypted").is_none());
+    }
+
+    #[test]
+    fn test_plain_text_message() {
+        let message = plain_text_message(
+            "\"Doe, Jane\" <jane@example.com>, bob@example.com",
+            "Hi",
+            "Body",
+        )
+        .unwrap();
+        assert!(message.starts_with(
+            "To: \"Doe, Jane\" <jane@example.com>, <bob@example.com>\r\nSubject: Hi\r\n"
+        ));
+        assert!(message.contains("MIME-Version: 1.0\r\n"));
+        assert!(message.contains("Content-Type: text/plain; charset=\"utf-8\"\r\n"));
+        assert!(message.ends_with("\r\n\r\nBody"));
+        let result = dry_run_result("create_draft", &message, Some("t1"));
+        assert_eq!(result["dry_run"], true);
+        assert_eq!(result["thread_id"], "t1");
+        assert_eq!(result["size_bytes"], message.len());
+    }
+
+    #[test]
+    fn test_plain_text_message_encodes_non_ascii() {
+        let message = plain_text_message(
+            "José Müller <jose@example.com>",
+            "Café meeting – 10:00 ☕",
+            "Grüße aus München",
+        )
+        .unwrap();
+        assert!(message.is_ascii());
+        let header = |name: &str| {
+            message
+                .split("\r\n\r\n")
+                .next()
+                .unwrap()
+                .replace("\r\n ", " ")
+                .replace("\r\n\t", " ")
+                .lines()
+                .find_map(|line| line.strip_prefix(&format!("{name}: ")).map(str::to_string))
+                .unwrap()
+        };
+        assert!(header("Subject").starts_with("=?utf-8?"));
+        assert!(header("To").contains("=?utf-8?") && header("To").ends_with("<jose@example.com>"));
+        assert!(!header("Content-Transfer-Encoding").contains("8bit"));
+    }
+
+    #[test]
+    fn test_plain_text_message_internationalized_addresses() {
+        let message =
+            plain_text_message("Jörg <jörg@münchen.de>, 用户@例子.广告", "Hi", "Body").unwrap();
+        // Domains go out as punycode, while UTF-8 local parts are kept
+        let to = message.split("\r\nSubject:").next().unwrap();
+        assert_eq!(
+            to.replace("\r\n\t", ""),
+            "To: \"=?utf-8?B?SsO2cmc=?=\" <jörg@xn--mnchen-3ya.de>, <用户@xn--fsqu00a.xn--4rr70v>"
+        );
+    }
+
+    #[test]
+    fn test_plain_text_message_folds_long_subjects() {
+        let subject = "word ".repeat(40);
+        let message = plain_text_message("jane@example.com", subject.trim(), "Body").unwrap();
+        let headers = message.split("\r\n\r\n").next().unwrap();
+        assert!(headers.lines().all(|line| line.len() <= 78), "{headers}");
+    }
+
+    #[test]
+    fn test_validate_headers() {
+        assert!(validate_headers("Jane <jane@example.com>", "Hi").is_ok());
+        assert!(validate_headers("", "Hi").is_err());
+        assert!(validate_headers("jane", "Hi").is_err());
+        assert!(validate_headers("Jane <jane@example.com", "Hi").is_err());
+        assert!(validate_headers("jane@example.com, @example.com", "Hi").is_err());
+        // Line breaks would let a caller add headers such as Bcc
+        assert!(validate_headers("jane@example.com\r\nBcc: eve@example.com", "Hi").is_err());
+        assert!(validate_headers("jane@example.com", "Hi\nBcc: eve@example.com").is_err());
+    }
+
+    #[test]
+    fn test_without_own_address() {
+        let to = "Me <ME@example.com>, \"Doe, Jane\" <jane@example.com>, me@example.com, bob@example.com";
+        assert_eq!(
+            without_own_address(to, "me@example.com"),
+            (
+                "\"Doe, Jane\" <jane@example.com>, bob@example.com".to_string(),
+                vec![
+                    "Me <ME@example.com>".to_string(),
+                    "me@example.com".to_string()
+                ]
+            )
+        );
+        // A note to self keeps its only recipient
+        assert_eq!(
+            without_own_address("me@example.com", "me@example.com"),
+            ("me@example.com".to_string(), Vec::new())
+        );
+    }
+
+    #[test]
+    fn test_search_filter_date_range() {
+        let now: DateTime<Utc> = "2024-10-21T12:00:00Z".parse().unwrap();
+        let filter = |last_n_days, start: Option<&str>, end: Option<&str>, offset: Option<&str>| {
+            SearchFilter {
+                last_n_days,
+                start_date: start.map(str::to_string),
+                end_date: end.map(str::to_string),
+                utc_offset: offset.map(str::to_string),
+                ..Default::default()
+            }
+        };
+
+        assert_eq!(
+            filter(None, None, None, None)
+                .query("is:unread", now)
+                .unwrap(),
+            "is:unread"
+        );
+        // 2024-10-14T12:00:00Z
+        assert_eq!(
+            filter(Some(7), None, None, None)
+                .query("from:a OR from:b", now)
+                .unwrap(),
+            "(from:a OR from:b) after:1728907200"
+        );
+        // The whole of October 1st to 3rd in UTC+2, from 2024-09-30T22:00:00Z to 2024-10-03T22:00:00Z
+        assert_eq!(
+            filter(None, Some("2024-10-01"), Some("2024-10-03"), Some("+02:00"))
+                .query("", now)
+                .unwrap(),
+            "after:1727733600 before:1727992800"
+        );
+        assert_eq!(
+            filter(None, Some("2024-10-01T09:30:00-04:00"), None, Some("UTC"))
+                .query("invoice", now)
+                .unwrap(),
+            "(invoice) after:1727789400"
+        );
+
+        assert!(filter(Some(7), Some("2024-10-01"), None, None)
+            .query("", now)
+            .is_err());
+        assert!(filter(Some(0), None, None, None).query("", now).is_err());
+        assert!(filter(None, Some("10/01/2024"), None, None)
+            .query("", now)
+            .is_err());
+        assert!(
+            filter(None, Some("2024-10-03"), Some("2024-10-01"), Some("Z"))
+                .query("", now)
+                .is_err()
+        );
+        assert!(filter(None, Some("2024-10-01"), None, Some("CEST"))
+            .query("", now)
+            .is_err());
+    }
+
+    #[test]
+    fn test_search_filter_category_and_importance() {
+        let now = Utc::now();
+        let filter = |category, is_important| SearchFilter {
+            category,
+            is_important,
+            ..Default::default()
+        };
+        assert_eq!(
+            filter(Some(Category::Promotions), None)
+                .query("older_than:1y", now)
+                .unwrap(),
+            "(older_than:1y) category:promotions"
+        );
+        assert_eq!(
+            filter(Some(Category::Primary), Some(true))
+                .query("", now)
+                .unwrap(),
+            "category:primary is:important"
+        );
+        assert_eq!(
+            filter(None, Some(false)).query("is:unread", now).unwrap(),
+            "(is:unread) -is:important"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_with_regex_filters_against_mock_gmail() {
+        let gmail = MockGmail::start().await;
+        let gmail_server = gmail.gmail_server(&gmail.config()).await;
+        let search = |body_regex: Option<&str>, header_regex: Option<&str>| {
+            let filter = SearchFilter {
+                body_regex: body_regex.map(str::to_string),
+                header_regex: header_regex.map(str::to_string),
+                ..Default::default()
+            };
+            let gmail_server = gmail_server.clone();
+            async move { search_threads(&gmail_server, "in:inbox", 10, None, &filter).await }
+        };
+
+        let threads = search(Some(r"see you \w+"), None).await.unwrap();
+        assert_eq!(threads["threads"].as_array().unwrap().len(), 1);
+        assert_eq!(threads["threads"][0]["id"], "thread-1");
+        assert_eq!(threads["threads"][0]["matches"], json!(["see you Friday"]));
+        assert_eq!(threads["scanned_threads"], 2);
+
+        let threads = search(None, Some("^From: News")).await.unwrap();
+        assert_eq!(threads["threads"][0]["id"], "thread-2");
+        assert_eq!(threads["threads"][0]["matches"], json!(["From: News"]));
+
+        // Both must match the same message: Alice's has no "see you", Bob's isn't from Alice
+        let threads = search(Some("see you"), Some("^From: Alice")).await.unwrap();
+        assert!(threads["threads"].as_array().unwrap().is_empty());
+
+        assert!(search(Some("("), None).await.is_err());
+        let filter = SearchFilter {
+            body_regex: Some("x".to_string()),
+            ..Default::default()
+        };
+        assert!(
+            search_threads(&gmail_server, "in:inbox", 10, Some("threads(id)"), &filter)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_and_fetch_against_mock_gmail() {
+        let gmail = MockGmail::start().await;
+        let gmail_server = gmail.gmail_server(&gmail.config()).await;
+
+        let threads = search_threads(&gmail_server, "in:inbox", 1, None, &SearchFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(threads["threads"][0]["id"], "thread-1");
+        assert_eq!(threads["threads"].as_array().unwrap().len(), 1);
+
+        let thread_ids = ["thread-1".to_string(), "thread-2".to_string()];
+        let bodies = fetch_email_bodies(&gmail_server, &thread_ids, MessageFormat::Full, None)
+            .await
+            .unwrap();
+        let thread = &bodies["threads"][0];
+        assert_eq!(thread["thread_id"], "thread-1");
+        assert_eq!(
+            thread["messages"][0]["body"],
+            "Notes from today's meeting are attached."
+        );
+        // The plain text alternative is preferred over HTML
+        assert_eq!(
+            thread["messages"][1]["body"],
+            "Sounds good, see you Friday."
+        );
+        assert_eq!(thread["messages"][1]["from"], "Bob <bob@example.com>");
+        assert_eq!(thread["messages"][0]["important"], true);
+        assert_eq!(thread["messages"][1]["unread"], true);
+        let newsletter = &bodies["threads"][1]["messages"][0];
+        assert_eq!(newsletter["subject"], "This week in Rust");
+        assert_eq!(newsletter["category"], "promotions");
+
+        // The two threads, then their three messages, are each fetched in one batch request
+        let batches = gmail
+            .requests()
+            .await
+            .into_iter()
+            .filter(|request| request.url.path() == "/batch/gmail/v1")
+            .count();
+        assert_eq!(batches, 2);
+
+        let missing = fetch_email_bodies(
+            &gmail_server,
+            &["nope".to_string()],
+            MessageFormat::Full,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(missing["threads"], json!([]));
+    }
+
+    #[test]
+    fn test_preview_text() {
+        let mut result = json!({ "extracted_text": "Café menu" });
+        preview_text(&mut result, 4);
+        assert_eq!(
+            result,
+            json!({ "extracted_text": "Café", "total_chars": 9, "preview_truncated": true })
+        );
+        let mut unsupported = json!({ "extracted_text": null });
+        preview_text(&mut unsupported, 4);
+        assert_eq!(unsupported, json!({ "extracted_text": null }));
+    }
+
+    #[tokio::test]
+    async fn test_attachments_against_mock_gmail() {
+        let gmail = MockGmail::start().await;
+        let gmail_server = gmail.gmail_server(&gmail.config()).await;
+
+        let extracted = extract_attachment_by_filename(&gmail_server, "msg-1", "notes.txt", None)
+            .await
+            .unwrap();
+        assert_eq!(extracted["extracted_text"], MOCK_ATTACHMENT_TEXT);
+        assert_eq!(extracted["mime_type"], "text/plain");
+        assert!(extracted.get("total_chars").is_none());
+
+        let preview = extract_attachment_by_filename(&gmail_server, "msg-1", "notes.txt", Some(7))
+            .await
+            .unwrap();
+        assert_eq!(preview["extracted_text"], "Meeting");
+        assert_eq!(preview["total_chars"], MOCK_ATTACHMENT_TEXT.len());
+        assert_eq!(preview["preview_truncated"], true);
+        assert_eq!(preview["text_size"], text_size(MOCK_ATTACHMENT_TEXT));
+        let whole = extract_attachment_by_filename(&gmail_server, "msg-1", "notes.txt", Some(1000))
+            .await
+            .unwrap();
+        assert_eq!(whole["extracted_text"], MOCK_ATTACHMENT_TEXT);
+        assert_eq!(whole["preview_truncated"], false);
+
+        let dir = tempfile::tempdir().unwrap();
+        let download_dir = dir.path().to_str().unwrap();
+        let downloaded =
+            download_attachment(&gmail_server, "msg-1", "notes.txt", Some(download_dir))
+                .await
+                .unwrap();
+        assert_eq!(downloaded["size"], MOCK_ATTACHMENT_TEXT.len());
+        let saved = std::fs::read_to_string(dir.path().join("notes.txt")).unwrap();
+        assert_eq!(saved, MOCK_ATTACHMENT_TEXT);
+
+        let error = extract_attachment_by_filename(&gmail_server, "msg-2", "notes.txt", None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Attachment 'notes.txt' not found in message"
+        );
+        let error = extract_attachment_by_filename(&gmail_server, "missing", "notes.txt", None)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().starts_with("Gmail API error: 404"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_scanned_attachments_against_mock_gmail() {
+        let gmail = MockGmail::start().await;
+        let mut config = gmail.config();
+        config.attachment_scan_command =
+            Some("grep -q Friday && echo Test-Signature && exit 1; exit 0".to_string());
+        let gmail_server = gmail.gmail_server(&config).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let download_dir = dir.path().to_str().unwrap();
+        let error = download_attachment(&gmail_server, "msg-1", "notes.txt", Some(download_dir))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Attachment 'notes.txt' was blocked: the virus scan found Test-Signature"
+        );
+        assert!(!dir.path().join("notes.txt").exists());
+
+        config.attachment_scan_action = crate::config::ScanAction::Warn;
+        let gmail_server = gmail.gmail_server(&config).await;
+        let extracted = extract_attachment_by_filename(&gmail_server, "msg-1", "notes.txt", None)
+            .await
+            .unwrap();
+        assert_eq!(extracted["extracted_text"], MOCK_ATTACHMENT_TEXT);
+        assert_eq!(
+            extracted["scan"],
+            json!({ "status": "infected", "found": "Test-Signature" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_drafts_and_sending_against_mock_gmail() {
+        let gmail = MockGmail::start().await;
+        let gmail_server = gmail.gmail_server(&gmail.config()).await;
+
+        // Recipients are kept as given unless the mailbox's own address is to be left out
+        let to = "alice@example.com, me@example.com";
+        let reply = |exclude_own_address| {
+            create_draft(
+                &gmail_server,
+                to,
+                "Re: Quarterly report",
+                "Thanks!",
+                Some("thread-1"),
+                exclude_own_address,
+                true,
+            )
+        };
+        let kept = reply(false).await.unwrap();
+        assert!(kept["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("To: <alice@example.com>, <me@example.com>\r\n"));
+        assert!(kept.get("removed_recipients").is_none());
+        let reply_all = reply(true).await.unwrap();
+        assert!(reply_all["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("To: <alice@example.com>\r\n"));
+        assert_eq!(reply_all["removed_recipients"], json!(["me@example.com"]));
+
+        let draft = create_draft(
+            &gmail_server,
+            "alice@example.com",
+            "Re: Quarterly report",
+            "Thanks!",
+            Some("thread-1"),
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(draft["id"], "draft-1");
+        let sent = send_draft(&gmail_server, "draft-1", false, false)
+            .await
+            .unwrap();
+        assert_eq!(sent["labelIds"], json!(["SENT"]));
+
+        let dry_run = send_draft(&gmail_server, "draft-1", true, false)
+            .await
+            .unwrap();
+        assert_eq!(dry_run["dry_run"], true);
+        assert_eq!(dry_run["thread_id"], "thread-1");
+
+        let forwarded = forward_email(
+            &gmail_server,
+            "msg-2",
+            "carol@example.com",
+            "Fwd: Quarterly report",
+            "FYI",
+            false,
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(forwarded["id"], "msg-sent");
+
+        // What reached Gmail: the draft in its thread, and the forward quoting the original
+        let requests = gmail.requests().await;
+        let body = |path: &str| {
+            let request = requests
+                .iter()
+                .find(|request| request.url.path() == path)
+                .unwrap();
+            request.body_json::<Value>().unwrap()
+        };
+        let draft = body("/gmail/v1/users/me/drafts");
+        assert_eq!(draft["message"]["threadId"], "thread-1");
+        let raw = body("/gmail/v1/users/me/messages/send")["raw"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let forward = String::from_utf8(URL_SAFE.decode(raw).unwrap()).unwrap();
+        assert!(
+            forward.starts_with("To: <carol@example.com>\r\nSubject: Fwd: Quarterly report\r\n")
+        );
+        assert!(forward.contains("From: Bob <bob@example.com>\r\n"));
+        assert!(forward.ends_with("Sounds good, see you Friday."));
+    }
+}
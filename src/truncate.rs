@@ -0,0 +1,360 @@
+use anyhow::Result;
+use chrono::DateTime;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use crate::config::Config;
+use crate::gmail::GmailServer;
+use crate::tools::with_response_size;
+
+/// Tools returning message bodies, which can each have their own truncation strategy
+pub const TRUNCATED_TOOLS: [&str; 3] = [
+    "fetch_email_bodies",
+    "get_conversation",
+    "get_thread_changes",
+];
+
+/// Room kept under the limit for the `truncated` and `response_size` notes
+const NOTE_BYTES: usize = 160;
+
+/// How a response over the size limit is cut down
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TruncationStrategy {
+    /// Drop the bodies of the oldest messages first
+    #[default]
+    OldestFirst,
+    /// Drop bodies that came from HTML parts first, then the oldest
+    DropHtml,
+    /// Drop every body, keeping the headers
+    HeadersOnly,
+}
+
+impl TruncationStrategy {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::OldestFirst => "oldest-first",
+            Self::DropHtml => "drop-html",
+            Self::HeadersOnly => "headers-only",
+        }
+    }
+}
+
+impl FromStr for TruncationStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.trim() {
+            "oldest-first" => Ok(Self::OldestFirst),
+            "drop-html" => Ok(Self::DropHtml),
+            "headers-only" => Ok(Self::HeadersOnly),
+            other => anyhow::bail!(
+                "Unknown truncation strategy '{other}': expected oldest-first, drop-html or \
+                 headers-only"
+            ),
+        }
+    }
+}
+
+/// The largest tool response, from --max-tool-response-bytes, and how each tool keeps under it
+#[derive(Debug, Clone)]
+pub struct ResponseLimit {
+    max_bytes: usize,
+    default: TruncationStrategy,
+    tools: HashMap<String, TruncationStrategy>,
+}
+
+impl ResponseLimit {
+    /// The configured limit, if any, with its strategies from --truncation-strategy
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        let Some(max_bytes) = config.max_tool_response_bytes else {
+            return Ok(None);
+        };
+        let mut limit = Self {
+            max_bytes,
+            default: TruncationStrategy::default(),
+            tools: HashMap::new(),
+        };
+        for setting in &config.truncation_strategy {
+            match setting.split_once('=') {
+                Some((tool, strategy)) => {
+                    let tool = tool.trim();
+                    if !TRUNCATED_TOOLS.contains(&tool) {
+                        anyhow::bail!(
+                            "No truncation strategy for '{tool}': only {} have one",
+                            TRUNCATED_TOOLS.join(", ")
+                        );
+                    }
+                    limit.tools.insert(tool.to_string(), strategy.parse()?);
+                }
+                None => limit.default = setting.parse()?,
+            }
+        }
+        Ok(Some(limit))
+    }
+
+    fn strategy(&self, tool: &str) -> TruncationStrategy {
+        self.tools.get(tool).copied().unwrap_or(self.default)
+    }
+
+    /// Cut the message bodies, and then the messages, out of a tool's result until it fits,
+    /// noting what was left out under `truncated`
+    pub fn fit_messages(&self, tool: &str, mut result: Value) -> Value {
+        let mut size = result.to_string().len();
+        if size <= self.max_bytes {
+            return result;
+        }
+        let strategy = self.strategy(tool);
+        let target = self.max_bytes.saturating_sub(NOTE_BYTES);
+
+        let mut messages = Vec::new();
+        find_messages(&result, String::new(), &mut messages);
+        // Oldest first; messages without a readable date count as oldest, in the order listed
+        messages.sort_by_key(|pointer| {
+            result
+                .pointer(pointer)
+                .and_then(|message| message["date"].as_str())
+                .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+        });
+
+        let mut bodies = messages.clone();
+        if strategy == TruncationStrategy::DropHtml {
+            // A stable sort keeps each group oldest first
+            bodies.sort_by_key(|pointer| {
+                result
+                    .pointer(pointer)
+                    .is_none_or(|message| message["body_mime_type"] != "text/html")
+            });
+        }
+        let mut bodies_dropped = 0;
+        for pointer in &bodies {
+            if size <= target && strategy != TruncationStrategy::HeadersOnly {
+                break;
+            }
+            let Some(message) = result.pointer_mut(pointer) else {
+                continue;
+            };
+            if !message["body"].is_string() {
+                continue;
+            }
+            let body = message["body"].take();
+            message["body_truncated"] = json!(true);
+            size = size + r#","body_truncated":true"#.len() + "null".len() - body.to_string().len();
+            bodies_dropped += 1;
+        }
+
+        // Still too big with headers only; leave out whole messages, oldest first
+        let mut dropped: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+        for pointer in &messages {
+            if size <= target {
+                break;
+            }
+            let Some((parent, index)) = pointer.rsplit_once('/') else {
+                continue;
+            };
+            let (Some(message), Ok(index)) = (result.pointer(pointer), index.parse()) else {
+                continue;
+            };
+            size = size.saturating_sub(message.to_string().len() + 1);
+            dropped.entry(parent.to_string()).or_default().push(index);
+        }
+        let mut messages_dropped = 0;
+        for (parent, mut indexes) in dropped {
+            let Some(array) = result.pointer_mut(&parent).and_then(Value::as_array_mut) else {
+                continue;
+            };
+            indexes.sort_unstable_by(|a, b| b.cmp(a));
+            for index in indexes {
+                array.remove(index);
+                messages_dropped += 1;
+            }
+        }
+
+        if let Some(object) = result.as_object_mut() {
+            object.insert(
+                "truncated".to_string(),
+                json!({
+                    "max_bytes": self.max_bytes,
+                    "strategy": strategy.as_str(),
+                    "bodies_dropped": bodies_dropped,
+                    "messages_dropped": messages_dropped,
+                }),
+            );
+        }
+        with_response_size(result)
+    }
+
+    /// Shorten a text field of a tool's result until it fits, marking it `<field>_truncated`
+    pub fn fit_text(&self, field: &str, mut result: Value) -> Value {
+        let size = result.to_string().len();
+        let Some(text) = result[field].as_str() else {
+            return result;
+        };
+        if size <= self.max_bytes {
+            return result;
+        }
+        // Escaping makes the JSON string longer than the text, so cutting the excess from the
+        // text is enough
+        let note = format!(r#","{field}_truncated":true"#).len();
+        let mut end = text.len().saturating_sub(size - self.max_bytes + note);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        let text = text[..end].to_string();
+        result[field] = json!(text);
+        result[format!("{field}_truncated")] = json!(true);
+        result
+    }
+}
+
+/// JSON pointers to every message summary in a tool result
+fn find_messages(value: &Value, pointer: String, found: &mut Vec<String>) {
+    match value {
+        Value::Object(object) if object.contains_key("message_id") => found.push(pointer),
+        Value::Object(object) => {
+            for (key, value) in object {
+                let key = key.replace('~', "~0").replace('/', "~1");
+                find_messages(value, format!("{pointer}/{key}"), found);
+            }
+        }
+        Value::Array(array) => {
+            for (index, value) in array.iter().enumerate() {
+                find_messages(value, format!("{pointer}/{index}"), found);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// A message-returning tool's result, cut down to the server's response limit if it has one
+pub fn fit_messages(gmail_server: &GmailServer, tool: &str, result: Value) -> Value {
+    match gmail_server.response_limit() {
+        Some(limit) => limit.fit_messages(tool, result),
+        None => result,
+    }
+}
+
+/// A tool's result with its text `field` cut down to the server's response limit if it has one
+pub fn fit_text(gmail_server: &GmailServer, field: &str, result: Value) -> Value {
+    match gmail_server.response_limit() {
+        Some(limit) => limit.fit_text(field, result),
+        None => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(max_bytes: usize, strategies: &[&str]) -> ResponseLimit {
+        let config = Config {
+            max_tool_response_bytes: Some(max_bytes),
+            truncation_strategy: strategies.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+        ResponseLimit::from_config(&config).unwrap().unwrap()
+    }
+
+    fn message(id: &str, date: &str, mime_type: &str, body: &str) -> Value {
+        json!({
+            "message_id": id,
+            "date": date,
+            "body_mime_type": mime_type,
+            "body": body,
+        })
+    }
+
+    fn thread() -> Value {
+        let body = "x".repeat(300);
+        json!({
+            "threads": [{
+                "thread_id": "t1",
+                "messages": [
+                    message("m2", "Tue, 2 Jan 2024 00:00:00 +0000", "text/html", &body),
+                    message("m1", "Mon, 1 Jan 2024 00:00:00 +0000", "text/plain", &body),
+                    message("m3", "Wed, 3 Jan 2024 00:00:00 +0000", "text/plain", &body),
+                ],
+            }],
+        })
+    }
+
+    fn bodies(result: &Value) -> Vec<(&str, bool)> {
+        result["threads"][0]["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| (m["message_id"].as_str().unwrap(), m["body"].is_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_from_config() {
+        let config = Config::default();
+        assert!(ResponseLimit::from_config(&config).unwrap().is_none());
+
+        let limit = limit(1000, &["headers-only", "get_conversation=drop-html"]);
+        assert_eq!(
+            limit.strategy("fetch_email_bodies"),
+            TruncationStrategy::HeadersOnly
+        );
+        assert_eq!(
+            limit.strategy("get_conversation"),
+            TruncationStrategy::DropHtml
+        );
+
+        for invalid in [&["shortest"][..], &["search_threads=headers-only"]] {
+            let config = Config {
+                max_tool_response_bytes: Some(1000),
+                truncation_strategy: invalid.iter().map(|s| s.to_string()).collect(),
+                ..Default::default()
+            };
+            assert!(ResponseLimit::from_config(&config).is_err());
+        }
+    }
+
+    #[test]
+    fn test_fit_messages_under_limit_is_unchanged() {
+        let result = limit(10_000, &[]).fit_messages("fetch_email_bodies", thread());
+        assert_eq!(result, thread());
+    }
+
+    #[test]
+    fn test_fit_messages_strategies() {
+        let size = thread().to_string().len();
+
+        let result = limit(size - 50, &[]).fit_messages("fetch_email_bodies", thread());
+        assert_eq!(bodies(&result), [("m2", true), ("m1", false), ("m3", true)]);
+        assert_eq!(result["truncated"]["bodies_dropped"], 1);
+        assert!(result["response_size"]["bytes"].as_u64().unwrap() as usize <= size - 50);
+
+        let result = limit(size - 50, &["drop-html"]).fit_messages("fetch_email_bodies", thread());
+        assert_eq!(bodies(&result), [("m2", false), ("m1", true), ("m3", true)]);
+        assert_eq!(result["truncated"]["strategy"], "drop-html");
+
+        let result =
+            limit(size - 50, &["headers-only"]).fit_messages("fetch_email_bodies", thread());
+        assert_eq!(
+            bodies(&result),
+            [("m2", false), ("m1", false), ("m3", false)]
+        );
+        assert_eq!(result["threads"][0]["messages"][0]["body_truncated"], true);
+
+        // Without bodies it is still too big, so the oldest messages go too
+        let result = limit(400, &[]).fit_messages("fetch_email_bodies", thread());
+        assert_eq!(bodies(&result), [("m3", false)]);
+        assert_eq!(result["truncated"]["messages_dropped"], 2);
+    }
+
+    #[test]
+    fn test_fit_text() {
+        let result = json!({ "filename": "a.txt", "extracted_text": "é".repeat(100) });
+        let size = result.to_string().len();
+        let fitted = limit(size - 21, &[]).fit_text("extracted_text", result.clone());
+        assert!(fitted.to_string().len() <= size - 21);
+        assert_eq!(fitted["extracted_text_truncated"], true);
+        assert!(fitted["extracted_text"].as_str().unwrap().starts_with('é'));
+
+        let fitted = limit(size, &[]).fit_text("extracted_text", result.clone());
+        assert_eq!(fitted, result);
+    }
+}
@@ -0,0 +1,135 @@
+use anyhow::Result;
+use axum::Router;
+use std::path::Path;
+use tokio_util::sync::CancellationToken;
+
+/// A bound `--unix-socket`, whose file is removed again when the server stops
+#[cfg(unix)]
+pub struct UnixSocket {
+    listener: tokio::net::UnixListener,
+    path: std::path::PathBuf,
+}
+
+#[cfg(not(unix))]
+pub enum UnixSocket {}
+
+/// Bind the socket, replacing one left behind by a server that didn't shut down cleanly
+#[cfg(unix)]
+pub fn bind(path: &Path) -> Result<UnixSocket> {
+    use anyhow::Context;
+    use std::os::unix::fs::FileTypeExt;
+
+    let stale = std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+    if stale {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            anyhow::bail!("{} is in use by another server", path.display());
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind to {}", path.display()))?;
+    Ok(UnixSocket {
+        listener,
+        path: path.to_path_buf(),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn bind(_path: &Path) -> Result<UnixSocket> {
+    anyhow::bail!("--unix-socket is only supported on Unix")
+}
+
+/// Serve the app on the socket until cancelled, letting open connections finish their requests
+#[cfg(unix)]
+pub async fn serve(socket: UnixSocket, app: Router, ct: CancellationToken) -> Result<()> {
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use hyper_util::server::conn::auto::Builder;
+    use hyper_util::server::graceful::GracefulShutdown;
+    use hyper_util::service::TowerToHyperService;
+    use std::time::Duration;
+    use tracing::{debug, info, warn};
+
+    let UnixSocket { listener, path } = socket;
+    let builder = Builder::new(TokioExecutor::new());
+    let graceful = GracefulShutdown::new();
+    loop {
+        let stream = tokio::select! {
+            _ = ct.cancelled() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    // Usually out of file descriptors, which takes a moment to resolve
+                    warn!("Failed to accept connection on {}: {}", path.display(), e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+        };
+        let connection = builder
+            .serve_connection(TokioIo::new(stream), TowerToHyperService::new(app.clone()))
+            .into_owned();
+        let connection = graceful.watch(connection);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("Connection error: {}", e);
+            }
+        });
+    }
+    info!("Server is shutting down...");
+    drop(listener);
+    graceful.shutdown().await;
+    let _ = std::fs::remove_file(&path);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn serve(socket: UnixSocket, _app: Router, _ct: CancellationToken) -> Result<()> {
+    match socket {}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_serve_on_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gmail-mcp.sock");
+        let app = Router::new().route("/healthz", axum::routing::get(|| async { "OK" }));
+        let ct = CancellationToken::new();
+        let server = tokio::spawn(serve(bind(&path).unwrap(), app, ct.clone()));
+
+        // A second server can't take over a socket that is still being served
+        assert!(bind(&path).is_err());
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("OK"));
+
+        ct.cancel();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_bind_replaces_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gmail-mcp.sock");
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        assert!(bind(&path).is_ok());
+
+        // Anything other than a socket is left alone
+        let file = dir.path().join("not-a-socket");
+        std::fs::write(&file, "").unwrap();
+        assert!(bind(&file).is_err());
+    }
+}
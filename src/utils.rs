@@ -1,7 +1,12 @@
 use crate::config::Config;
 use anyhow::{Context, Result};
+use axum::http::{header, HeaderMap, HeaderValue};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::{ClientBuilder, NoProxy, Proxy};
 use std::fs;
 use std::path::PathBuf;
+use std::process::{Output, Stdio};
+use tokio::io::AsyncWriteExt;
 
 pub fn get_app_data_dir(config: &Config) -> Result<PathBuf> {
     let app_data_dir = config.app_data_dir();
@@ -15,6 +20,150 @@ pub fn get_app_file_path(config: &Config, filename: &str) -> Result<PathBuf> {
     Ok(app_data_dir.join(filename))
 }
 
+/// Run a command through the platform's shell with `input` on stdin, collecting its output. The
+/// command is killed if the returned future is dropped, e.g. on a timeout
+pub async fn run_shell(command: &str, input: &[u8]) -> Result<Output> {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut shell_command = tokio::process::Command::new(shell);
+    shell_command.arg(flag).arg(command);
+    run_with_input(shell_command, input)
+        .await
+        .with_context(|| format!("Failed to run '{command}'"))
+}
+
+/// Run a command with `input` on stdin, collecting its output. Input is written while the
+/// output is read, so neither pipe fills up and stalls the command
+pub async fn run_with_input(mut command: tokio::process::Command, input: &[u8]) -> Result<Output> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdin = child.stdin.take();
+    let write = async move {
+        if let Some(mut stdin) = stdin {
+            // A command may exit without reading all of its input
+            if let Err(e) = stdin.write_all(input).await {
+                if e.kind() != std::io::ErrorKind::BrokenPipe {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    };
+    let (written, output) = tokio::join!(write, child.wait_with_output());
+    written?;
+    Ok(output?)
+}
+
+/// Route a client through the configured proxy
+///
+/// Without `--proxy`, reqwest already honors HTTP_PROXY, HTTPS_PROXY and NO_PROXY.
+pub fn apply_proxy(builder: ClientBuilder, config: &Config) -> Result<ClientBuilder> {
+    let Some(proxy_url) = &config.proxy else {
+        return Ok(builder);
+    };
+    let proxy = Proxy::all(proxy_url)
+        .with_context(|| format!("Invalid proxy URL: {proxy_url}"))?
+        .no_proxy(NoProxy::from_env());
+    Ok(builder.proxy(proxy))
+}
+
+/// Check a request's Authorization header against the configured credentials
+///
+/// Accepts either `Bearer <bearer_token>` or `Basic` with `basic_auth` as `user:password`.
+/// With neither configured every request is authorized.
+pub fn is_authorized(
+    headers: &HeaderMap,
+    bearer_token: Option<&str>,
+    basic_auth: Option<&str>,
+) -> bool {
+    if bearer_token.is_none() && basic_auth.is_none() {
+        return true;
+    }
+    let Some(authorization) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    let Some((scheme, credentials)) = authorization.split_once(' ') else {
+        return false;
+    };
+
+    if scheme.eq_ignore_ascii_case("bearer") {
+        bearer_token.is_some_and(|token| constant_time_eq(credentials.trim(), token))
+    } else if scheme.eq_ignore_ascii_case("basic") {
+        let decoded = STANDARD
+            .decode(credentials.trim())
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+        match (decoded, basic_auth) {
+            (Some(decoded), Some(expected)) => constant_time_eq(&decoded, expected),
+            _ => false,
+        }
+    } else {
+        false
+    }
+}
+
+/// Check a request for the API key, sent as `Bearer <key>` when the header is `Authorization`
+/// and as the bare key in any other header
+pub fn has_api_key(headers: &HeaderMap, header_name: &str, api_key: &str) -> bool {
+    if header_name.eq_ignore_ascii_case(header::AUTHORIZATION.as_str()) {
+        return is_authorized(headers, Some(api_key), None);
+    }
+    headers
+        .get(header_name.to_ascii_lowercase().as_str())
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| constant_time_eq(value.trim(), api_key))
+}
+
+/// A byte count for people, e.g. `1.2 MB`
+pub fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..1_000 => format!("{bytes} bytes"),
+        1_000..1_000_000 => format!("{:.0} KB", bytes as f64 / 1e3),
+        _ => format!("{:.1} MB", bytes as f64 / 1e6),
+    }
+}
+
+/// `Content-Disposition` downloading a file as `filename`, spelled out for clients that only
+/// read the ASCII `filename` parameter and percent-encoded for those that read `filename*`
+pub fn content_disposition(filename: &str) -> HeaderValue {
+    let ascii: String = filename
+        .chars()
+        .map(|c| {
+            if matches!(c, ' '..='~') && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let value = format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        ascii,
+        urlencoding::encode(filename)
+    );
+    // Everything outside visible ASCII was replaced or encoded above
+    HeaderValue::from_str(&value).expect("content disposition is visible ASCII")
+}
+
+/// Compare secrets without leaking the position of the first mismatch through timing
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -25,6 +174,7 @@ mod tests {
             gmail_client_id: None,
             gmail_client_secret: None,
             app_data_dir,
+            ..Default::default()
         }
     }
 
@@ -45,6 +195,60 @@ mod tests {
         // We will test the directory creation logic in `test_get_app_data_dir_creates_dir` instead.
     }
 
+    #[test]
+    fn test_apply_proxy() {
+        let mut config = create_test_config(None);
+        assert!(apply_proxy(reqwest::Client::builder(), &config).is_ok());
+
+        config.proxy = Some("http://proxy.example.com:3128".to_string());
+        let client = apply_proxy(reqwest::Client::builder(), &config)
+            .unwrap()
+            .build();
+        assert!(client.is_ok());
+
+        config.proxy = Some("not a url".to_string());
+        assert!(apply_proxy(reqwest::Client::builder(), &config).is_err());
+    }
+
+    #[test]
+    fn test_is_authorized() {
+        fn authorization(value: &str) -> HeaderMap {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::AUTHORIZATION, value.parse().unwrap());
+            headers
+        }
+        let bearer = authorization("Bearer t0ken");
+        let wrong_bearer = authorization("Bearer wrong");
+        let basic = authorization(&format!("Basic {}", STANDARD.encode("prom:s3cret")));
+        let malformed_basic = authorization("Basic !!!");
+
+        assert!(is_authorized(&HeaderMap::new(), None, None));
+        assert!(!is_authorized(&HeaderMap::new(), Some("t0ken"), None));
+
+        assert!(is_authorized(&bearer, Some("t0ken"), None));
+        assert!(!is_authorized(&wrong_bearer, Some("t0ken"), None));
+        assert!(!is_authorized(&basic, Some("t0ken"), None));
+
+        assert!(is_authorized(&basic, None, Some("prom:s3cret")));
+        assert!(is_authorized(&basic, Some("t0ken"), Some("prom:s3cret")));
+        assert!(!is_authorized(&basic, None, Some("prom:other")));
+        assert!(!is_authorized(&malformed_basic, None, Some("prom:s3cret")));
+    }
+
+    #[test]
+    fn test_has_api_key() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer k3y".parse().unwrap());
+        headers.insert("x-api-key", " k3y ".parse().unwrap());
+
+        assert!(has_api_key(&headers, "Authorization", "k3y"));
+        assert!(!has_api_key(&headers, "authorization", "other"));
+        assert!(has_api_key(&headers, "X-API-Key", "k3y"));
+        assert!(!has_api_key(&headers, "X-API-Key", "other"));
+        assert!(!has_api_key(&headers, "X-Other-Key", "k3y"));
+        assert!(!has_api_key(&HeaderMap::new(), "Authorization", "k3y"));
+    }
+
     #[test]
     fn test_get_app_file_path() {
         let dir = tempdir().unwrap();
@@ -75,5 +279,22 @@ mod tests {
         get_app_file_path(&config, "another_test_file.txt").unwrap();
         assert!(custom_path.exists());
     }
-}
 
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("report 2024.pdf"),
+            "attachment; filename=\"report 2024.pdf\"; filename*=UTF-8''report%202024.pdf"
+        );
+        assert_eq!(
+            content_disposition("résumé \"final\".pdf"),
+            "attachment; filename=\"r_sum_ _final_.pdf\"; \
+             filename*=UTF-8''r%C3%A9sum%C3%A9%20%22final%22.pdf"
+        );
+        // A name can't end the header early
+        assert!(!content_disposition("a\r\nSet-Cookie: x")
+            .to_str()
+            .unwrap()
+            .contains('\n'));
+    }
+}
@@ -0,0 +1,169 @@
+use anyhow::Result;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::io::Write;
+use std::time::Duration;
+
+use crate::gmail::{GmailServer, GMAIL_API_BASE};
+use crate::oauth::SCOPE_READONLY;
+use crate::saved_searches::expand_query;
+use crate::tools::{message_url, summarize_message, MessageFormat};
+
+/// Poll a search every `interval` and print a line for each message that starts matching it,
+/// until Ctrl+C or SIGTERM. Messages that match when the watch starts are not printed.
+///
+/// Progress and errors go to stderr rather than the log, keeping stdout to one line per message
+pub async fn watch(
+    gmail_server: &GmailServer,
+    query: &str,
+    interval: Duration,
+    max_results: u32,
+    json: bool,
+) -> Result<()> {
+    gmail_server.check_authentication(&[SCOPE_READONLY]).await?;
+
+    let mut seen: HashSet<String> = list_messages(gmail_server, query, max_results)
+        .await?
+        .into_iter()
+        .collect();
+    eprintln!("Watching for new messages matching '{query}', Ctrl+C to stop");
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        tokio::select! {
+            signal = crate::shutdown::signal() => {
+                if let Ok(signal) = signal {
+                    eprintln!("Received {signal}, stopping the watch");
+                }
+                return Ok(());
+            }
+            _ = ticker.tick() => {}
+        }
+
+        // A failed poll is retried on the next tick, so a network blip doesn't end the watch
+        let listed = match list_messages(gmail_server, query, max_results).await {
+            Ok(listed) => listed,
+            Err(e) => {
+                eprintln!("Failed to poll for new messages: {e:#}");
+                continue;
+            }
+        };
+        let arrived = new_messages(&seen, &listed);
+        if !arrived.is_empty() {
+            print_messages(gmail_server, &arrived, json).await?;
+        }
+        seen = listed.into_iter().collect();
+    }
+}
+
+/// IDs of the newest messages matching the query, newest first. `@name` saved searches in it are
+/// expanded on each call, so edits to them apply from the next poll
+pub async fn list_messages(
+    gmail_server: &GmailServer,
+    query: &str,
+    max_results: u32,
+) -> Result<Vec<String>> {
+    let query = &expand_query(gmail_server, query)?;
+    let client = gmail_server.authenticated_client().await?;
+    let url = format!(
+        "{GMAIL_API_BASE}/users/{}/messages?q={}&maxResults={max_results}",
+        gmail_server.user_id(),
+        urlencoding::encode(query),
+    );
+    let response = client.get_json(&url).await?;
+    Ok(response["messages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|message| message["id"].as_str().map(str::to_string))
+        .collect())
+}
+
+/// Listed messages that weren't in the previous listing, oldest first
+pub fn new_messages(seen: &HashSet<String>, listed: &[String]) -> Vec<String> {
+    listed
+        .iter()
+        .rev()
+        .filter(|id| !seen.contains(*id))
+        .cloned()
+        .collect()
+}
+
+async fn print_messages(
+    gmail_server: &GmailServer,
+    message_ids: &[String],
+    json: bool,
+) -> Result<()> {
+    let client = gmail_server.authenticated_client().await?;
+    let urls: Vec<String> = message_ids
+        .iter()
+        .map(|id| message_url(gmail_server.user_id(), id, MessageFormat::Metadata, None))
+        .collect();
+    let messages = client.batch_get(&urls).await?;
+
+    let mut stdout = std::io::stdout().lock();
+    for (message_id, message) in message_ids.iter().zip(messages) {
+        let summary = match message {
+            Ok(message) => summarize_message(message_id, &message, MessageFormat::Metadata)?,
+            Err(e) => {
+                eprintln!("Error fetching message {message_id}: {e:#}");
+                continue;
+            }
+        };
+        let line = if json {
+            summary.to_string()
+        } else {
+            summary_line(&summary)
+        };
+        writeln!(stdout, "{line}")?;
+    }
+    // Flushed per poll, so a pipe sees each message as it arrives
+    stdout.flush()?;
+    Ok(())
+}
+
+/// Tab-separated message ID, date, sender and subject, for `cut` and `awk`
+fn summary_line(summary: &Value) -> String {
+    ["message_id", "date", "from", "subject"]
+        .iter()
+        .map(|field| {
+            summary[field]
+                .as_str()
+                .unwrap_or_default()
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join("\t")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_new_messages() {
+        let seen = HashSet::from(["m2".to_string(), "m1".to_string()]);
+        let listed = ["m4", "m3", "m2"].map(str::to_string);
+        assert_eq!(new_messages(&seen, &listed), ["m3", "m4"]);
+        assert!(new_messages(&seen, &listed[2..]).is_empty());
+    }
+
+    #[test]
+    fn test_summary_line() {
+        let summary = json!({
+            "message_id": "m1",
+            "from": "Ann <ann@example.com>",
+            "subject": "Quarterly\r\n\treport",
+            "date": "Tue, 1 Oct 2024 09:00:00 +0000",
+            "snippet": "Numbers attached",
+            "body": null,
+        });
+        assert_eq!(
+            summary_line(&summary),
+            "m1\tTue, 1 Oct 2024 09:00:00 +0000\tAnn <ann@example.com>\tQuarterly report"
+        );
+        assert_eq!(summary_line(&json!({ "message_id": "m2" })), "m2\t\t\t");
+    }
+}
@@ -0,0 +1,318 @@
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, warn};
+
+use crate::accounts::Accounts;
+use crate::config::Config;
+use crate::gmail::GmailServer;
+use crate::metrics::{record_watcher_poll, record_watcher_started};
+use crate::tools::{message_url, summarize_message, MessageFormat};
+use crate::watch_inbox::{list_messages, new_messages};
+
+/// Header naming the event a webhook payload describes
+pub const EVENT_HEADER: &str = "x-webhook-event";
+
+/// Header carrying `sha256=<hex>`, the HMAC-SHA256 of the payload keyed with --webhook-secret
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+
+/// Time allowed for a webhook to answer
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Search polled for `new_mail` events
+const NEW_MAIL_QUERY: &str = "in:inbox";
+
+/// Newest inbox messages compared on each poll
+const NEW_MAIL_MAX_RESULTS: u32 = 100;
+
+/// Name the new mail watch reports its polls under on `/status` and in metrics
+const WATCHER: &str = "new_mail_webhooks";
+
+/// Something the server did or saw that webhooks are told about
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[value(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// A tool call finished, successfully or not
+    ToolInvoked,
+    /// Gmail accepted a message from `send_draft` or `forward_email`
+    SendCompleted,
+    /// An account's token can no longer be used or refreshed, so it needs a new login
+    AuthExpired,
+    /// A message arrived in an account's inbox
+    NewMail,
+}
+
+impl WebhookEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ToolInvoked => "tool_invoked",
+            Self::SendCompleted => "send_completed",
+            Self::AuthExpired => "auth_expired",
+            Self::NewMail => "new_mail",
+        }
+    }
+}
+
+/// The URLs POSTed JSON events, from --webhook-urls
+#[derive(Clone)]
+pub struct Webhooks {
+    urls: Arc<[String]>,
+    secret: Option<Arc<str>>,
+    /// Events sent, all of them when empty
+    events: Arc<[WebhookEvent]>,
+    client: reqwest::Client,
+    /// Deliveries still in flight, for the command line to wait on before exiting
+    deliveries: TaskTracker,
+}
+
+impl Webhooks {
+    /// The configured webhooks, if any
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        if config.webhook_urls.is_empty() {
+            return Ok(None);
+        }
+        for url in &config.webhook_urls {
+            reqwest::Url::parse(url).with_context(|| format!("Invalid webhook URL: {url}"))?;
+        }
+        let client = reqwest::Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .build()
+            .context("Failed to build webhook HTTP client")?;
+        Ok(Some(Self {
+            urls: config.webhook_urls.clone().into(),
+            secret: config.webhook_secret.as_deref().map(Arc::from),
+            events: config.webhook_events.clone().into(),
+            client,
+            deliveries: TaskTracker::new(),
+        }))
+    }
+
+    /// Whether `event` is sent
+    pub fn wants(&self, event: WebhookEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+
+    /// POST `event` to every URL in the background, with the event name and Unix timestamp added
+    /// to `fields`. Failed deliveries are logged, not retried
+    pub fn send(&self, event: WebhookEvent, fields: Value) {
+        if !self.wants(event) {
+            return;
+        }
+        let mut payload = json!({
+            "event": event.as_str(),
+            "timestamp": chrono::Utc::now().timestamp(),
+        });
+        if let (Value::Object(payload), Value::Object(fields)) = (&mut payload, fields) {
+            payload.extend(fields);
+        }
+        // Signed as sent, so receivers can check the exact bytes
+        let body = payload.to_string();
+        let signature = self
+            .secret
+            .as_deref()
+            .map(|secret| signature(secret, &body));
+
+        for url in self.urls.iter() {
+            let mut request = self
+                .client
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(EVENT_HEADER, event.as_str())
+                .body(body.clone());
+            if let Some(signature) = &signature {
+                request = request.header(SIGNATURE_HEADER, signature);
+            }
+            let url = url.clone();
+            self.deliveries.spawn(async move {
+                match request.send().await {
+                    Ok(response) if response.status().is_success() => {
+                        debug!("Sent {} webhook to {}", event.as_str(), url);
+                    }
+                    Ok(response) => warn!(
+                        "{} webhook to {} returned {}",
+                        event.as_str(),
+                        url,
+                        response.status()
+                    ),
+                    Err(e) => warn!(
+                        "Failed to send {} webhook to {}: {}",
+                        event.as_str(),
+                        url,
+                        e
+                    ),
+                }
+            });
+        }
+    }
+
+    /// Wait for deliveries in flight, so a command-line tool call doesn't exit before they're made
+    pub async fn flush(&self) {
+        self.deliveries.close();
+        self.deliveries.wait().await;
+    }
+}
+
+/// `sha256=` and the hex HMAC-SHA256 of `body` keyed with `secret`
+fn signature(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body.as_bytes());
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+/// Poll every account's inbox every `interval` until cancelled, and send a `new_mail` event for
+/// each message that arrives. Messages in the inbox when an account is first polled are not
+/// sent, and accounts without a usable token are skipped
+pub fn spawn_new_mail_watch(
+    accounts: Arc<Accounts>,
+    webhooks: Webhooks,
+    interval: Duration,
+    ct: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    record_watcher_started(WATCHER);
+    tokio::spawn(async move {
+        // Inbox messages of each account on its last poll
+        let mut seen: HashMap<String, HashSet<String>> = HashMap::new();
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                _ = ct.cancelled() => break,
+                _ = ticker.tick() => {}
+            }
+
+            let mut polled_all = true;
+            for (name, account) in accounts.iter() {
+                let gmail_server = &account.gmail_server;
+                if !gmail_server.is_authenticated().await {
+                    polled_all = false;
+                    continue;
+                }
+                // A failed poll is retried on the next tick
+                let listed =
+                    match list_messages(gmail_server, NEW_MAIL_QUERY, NEW_MAIL_MAX_RESULTS).await {
+                        Ok(listed) => listed,
+                        Err(e) => {
+                            warn!("Failed to poll the {} inbox for webhooks: {:#}", name, e);
+                            polled_all = false;
+                            continue;
+                        }
+                    };
+                if let Some(previous) = seen.get(name) {
+                    let arrived = new_messages(previous, &listed);
+                    if !arrived.is_empty() {
+                        if let Err(e) = send_new_mail(gmail_server, &webhooks, &arrived).await {
+                            warn!("Failed to send new mail webhooks for {}: {:#}", name, e);
+                        }
+                    }
+                }
+                seen.insert(name.to_string(), listed.into_iter().collect());
+            }
+            if polled_all {
+                record_watcher_poll(WATCHER);
+            }
+        }
+    })
+}
+
+/// Send a `new_mail` event with the summary of each message
+async fn send_new_mail(
+    gmail_server: &GmailServer,
+    webhooks: &Webhooks,
+    message_ids: &[String],
+) -> Result<()> {
+    let client = gmail_server.authenticated_client().await?;
+    let urls: Vec<String> = message_ids
+        .iter()
+        .map(|id| message_url(gmail_server.user_id(), id, MessageFormat::Metadata, None))
+        .collect();
+    let messages = client.batch_get(&urls).await?;
+    for (message_id, message) in message_ids.iter().zip(messages) {
+        let summary = match message
+            .and_then(|message| summarize_message(message_id, &message, MessageFormat::Metadata))
+        {
+            Ok(summary) => summary,
+            Err(e) => {
+                warn!("Error fetching new message {}: {:#}", message_id, e);
+                continue;
+            }
+        };
+        webhooks.send(
+            WebhookEvent::NewMail,
+            json!({ "account": gmail_server.account(), "message": summary }),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockGmail;
+    use wiremock::matchers::{header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_signature() {
+        assert_eq!(
+            signature("key", "The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_events_are_signed_and_filtered() {
+        let hooks = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header(EVENT_HEADER, "new_mail"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&hooks)
+            .await;
+
+        let gmail = MockGmail::start().await;
+        let mut config = gmail.config();
+        config.webhook_urls = vec![format!("{}/hook", hooks.uri())];
+        config.webhook_secret = Some("s3cret".to_string());
+        config.webhook_events = vec![WebhookEvent::NewMail];
+        let gmail_server = gmail.gmail_server(&config).await;
+        let webhooks = gmail_server.webhooks().unwrap();
+        assert!(!webhooks.wants(WebhookEvent::ToolInvoked));
+
+        webhooks.send(
+            WebhookEvent::ToolInvoked,
+            json!({ "tool": "search_threads" }),
+        );
+        send_new_mail(&gmail_server, webhooks, &["msg-1".to_string()])
+            .await
+            .unwrap();
+        webhooks.flush().await;
+
+        let requests = hooks.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        let body = String::from_utf8(requests[0].body.clone()).unwrap();
+        assert_eq!(
+            requests[0].headers[SIGNATURE_HEADER].to_str().unwrap(),
+            signature("s3cret", &body)
+        );
+        let payload: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(payload["event"], "new_mail");
+        assert_eq!(payload["account"], "default");
+        assert_eq!(payload["message"]["message_id"], "msg-1");
+        assert!(payload["timestamp"].is_i64());
+    }
+
+    #[test]
+    fn test_invalid_webhook_url() {
+        let config = Config {
+            webhook_urls: vec!["not a url".to_string()],
+            ..Default::default()
+        };
+        assert!(Webhooks::from_config(&config).is_err());
+        assert!(Webhooks::from_config(&Config::default()).unwrap().is_none());
+    }
+}